@@ -3,10 +3,17 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 //! Concatenates the upstream `sunset` SSH ident with the `ssh-stamp`
-//! version, e.g. `SSH-2.0-Sunset-0.5.0-ssh-stamp-0.3.0`.
+//! version, e.g. `SSH-2.0-Sunset-0.5.0-ssh-stamp-0.3.0`, and records the
+//! build metadata reported by `show version` (see `src/version.rs`).
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
     let lock_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.lock");
     let lock = std::fs::read_to_string(&lock_path).unwrap();
     let sunset_ver = lock
@@ -25,4 +32,69 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
     println!("cargo::rustc-env=SSH_STAMP_IDENT={ident}");
+    println!("cargo::rustc-env=SSH_STAMP_SUNSET_VERSION={sunset_ver}");
+    println!("cargo::rustc-env=SSH_STAMP_GIT_HASH={}", git_hash());
+    println!("cargo::rustc-env=SSH_STAMP_BUILD_DATE={}", build_date());
+    println!("cargo::rustc-env=SSH_STAMP_FEATURES={}", features());
+}
+
+/// Abbreviated commit hash, with `-dirty` for uncommitted changes.
+fn git_hash() -> String {
+    Command::new("git")
+        .args([
+            "describe",
+            "--always",
+            "--dirty",
+            "--abbrev=12",
+            "--exclude=*",
+        ])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map_or_else(|| "unknown".into(), |s| s.trim().into())
+}
+
+/// Build time in UTC, from `SOURCE_DATE_EPOCH` for reproducible builds.
+fn build_date() -> String {
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+/// Enabled cargo features of this crate, comma separated.
+fn features() -> String {
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(k, _)| {
+            k.strip_prefix("CARGO_FEATURE_")
+                .map(|f| f.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    if features.is_empty() {
+        "none".into()
+    } else {
+        features.join(",")
+    }
 }
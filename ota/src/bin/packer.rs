@@ -2,13 +2,14 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use ota::{OtaHeader, tlv};
+use ota::{OtaHeader, manifest, tlv};
+use ssh_stamp_hal::ChipTarget;
 
 use clap::{ArgAction, Command};
 use sha2::{Digest, Sha256};
 use std::{
     io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 const OTA_PACKER_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -39,6 +40,31 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .conflicts_with("unpack"),
         )
+        .arg(
+            clap::arg!(--"no-crc32" "Same as --format 1, for devices running firmware that predates the CRC-32 checksums")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("unpack"),
+        )
+        .arg(
+            clap::arg!(--notes <TEXT> "Release notes the device logs and keeps in its OTA history when it accepts the image, up to 255 bytes")
+                .conflicts_with_all(["unpack", "no-crc32"]),
+        )
+        .arg(
+            clap::arg!(--signature <SIG_FILE> "Raw 64 byte Ed25519 signature of the firmware's binary SHA-256, for devices built with SSH_STAMP_OTA_KEY")
+                .conflicts_with_all(["unpack", "no-crc32"]),
+        )
+        .arg(
+            clap::arg!(--format <VERSION> "OTA file format version to write, for devices running older firmware")
+                .value_parser(clap::value_parser!(u8).range(1..=i64::from(tlv::OTA_FORMAT_VERSION)))
+                .conflicts_with_all(["unpack", "no-crc32"]),
+        )
+        .arg(
+            clap::arg!(--manifest "Also write <file>.manifest recording the image, the files below and the firmware's build record")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("unpack"),
+        )
+        .arg(clap::arg!(--bootloader <FILE> "Bootloader image flashed with the firmware, recorded in the manifest").requires("manifest"))
+        .arg(clap::arg!(--"partition-table" <FILE> "Partition table image flashed with the firmware, recorded in the manifest").requires("manifest"))
         .get_matches();
     let Some(file_path) = matches.get_one::<String>("FILE") else {
         eprintln!("Error: No file provided");
@@ -62,7 +88,61 @@ fn main() {
         std::process::exit(unpack_ota(file_path));
     }
 
-    std::process::exit(pack_bin(file_path));
+    let format = if matches.get_flag("no-crc32") {
+        1
+    } else {
+        matches
+            .get_one::<u8>("format")
+            .copied()
+            .unwrap_or(tlv::OTA_FORMAT_VERSION)
+    };
+    let notes = match matches.get_one::<String>("notes") {
+        None => None,
+        Some(_) if format < 4 => {
+            eprintln!("Error: Release notes need OTA format version 4 or later");
+            std::process::exit(USAGE);
+        }
+        Some(text) => {
+            let Some(notes) = tlv::ReleaseNotes::new(text) else {
+                eprintln!(
+                    "Error: Release notes are longer than {} bytes",
+                    tlv::MAX_RELEASE_NOTES_LEN
+                );
+                std::process::exit(USAGE);
+            };
+            Some(notes)
+        }
+    };
+    let signature = match matches.get_one::<String>("signature") {
+        None => None,
+        Some(_) if format < 6 => {
+            eprintln!("Error: Signatures need OTA format version 6 or later");
+            std::process::exit(USAGE);
+        }
+        Some(sig_path) => {
+            let Ok(bytes) = std::fs::read(sig_path) else {
+                eprintln!("Error: Could not read signature file '{sig_path}'");
+                std::process::exit(READ_FAILED);
+            };
+            let Ok(signature) = <[u8; tlv::SIGNATURE_LEN as usize]>::try_from(bytes) else {
+                eprintln!(
+                    "Error: Signature file '{sig_path}' is not {} bytes long",
+                    tlv::SIGNATURE_LEN
+                );
+                std::process::exit(USAGE);
+            };
+            Some(signature)
+        }
+    };
+    let packed = pack_bin(file_path.clone(), format, notes, signature);
+    if packed != OK || !matches.get_flag("manifest") {
+        std::process::exit(packed);
+    }
+    std::process::exit(write_manifest(
+        &file_path,
+        matches.get_one::<String>("bootloader"),
+        matches.get_one::<String>("partition-table"),
+    ));
 }
 
 fn unpack_ota(file_path: PathBuf) -> i32 {
@@ -72,7 +152,7 @@ fn unpack_ota(file_path: PathBuf) -> i32 {
         return OPEN_FAILED;
     };
     let mut reader = std::io::BufReader::new(file);
-    let mut buffer = [0u8; 512];
+    let mut buffer = [0u8; 1024];
     let Ok(_) = reader.read(&mut buffer) else {
         eprintln!("Error: Could not read from file '{}'", file_path.display(),);
         return READ_FAILED;
@@ -141,8 +221,17 @@ fn unpack_ota(file_path: PathBuf) -> i32 {
 }
 
 // TODO: Optimize memory usage by streaming the file instead of reading it all at once
-fn pack_bin(file_path: PathBuf) -> i32 {
-    println!("Packing {} as OTA...", file_path.display());
+fn pack_bin(
+    file_path: PathBuf,
+    format: u8,
+    notes: Option<tlv::ReleaseNotes>,
+    signature: Option<[u8; tlv::SIGNATURE_LEN as usize]>,
+) -> i32 {
+    println!(
+        "Packing {} as OTA format version {}...",
+        file_path.display(),
+        format
+    );
 
     let firmware_size = match file_path.metadata() {
         Ok(metadata) => match u32::try_from(metadata.len()) {
@@ -176,6 +265,29 @@ fn pack_bin(file_path: PathBuf) -> i32 {
     let firmware_sha256 = hasher.finalize();
     println!("Firmware SHA-256: {:x}", firmware_sha256);
 
+    let target_ic = if format >= 5 {
+        let target = image_target(&read);
+        match target {
+            Some(target) => println!(
+                "Target IC: chip ID {}, revision {} or later",
+                target.family, target.revision
+            ),
+            None => println!("Target IC: not an ESP-IDF app image, devices will not check it"),
+        }
+        target
+    } else {
+        None
+    };
+
+    let crc32_segments = (format >= 2).then(|| tlv::SegmentCrcs::compute(&read));
+    if let Some(segments) = &crc32_segments {
+        println!(
+            "Firmware CRC-32: {} segments of {} bytes",
+            segments.crcs().len(),
+            segments.segment_size
+        );
+    }
+
     // We could read an u32 from an argument if we want to support multiple OTA types...
     let ota_type = tlv::OTA_TYPE_VALUE_SSH_STAMP;
     println!("OTA Type Number: {} (SSH-Stamp)", ota_type);
@@ -194,10 +306,26 @@ fn pack_bin(file_path: PathBuf) -> i32 {
     };
 
     // More than enough for the header
-    let mut buf = [0u8; 512];
-
-    let header_len =
-        OtaHeader::new(ota_type, firmware_sha256.as_slice(), firmware_size).serialize(&mut buf);
+    let mut buf = [0u8; 1024];
+
+    let mut header = OtaHeader::new(
+        ota_type,
+        firmware_sha256.as_slice(),
+        crc32_segments,
+        firmware_size,
+    );
+    if let Some(notes) = notes {
+        println!("Release notes: {}", notes.as_str());
+        header = header.with_release_notes(notes);
+    }
+    if let Some(target) = target_ic {
+        header = header.with_target_ic(target);
+    }
+    if let Some(signature) = signature {
+        println!("Signature: {} bytes", signature.len());
+        header = header.with_signature(signature);
+    }
+    let header_len = header.for_format(format).serialize(&mut buf);
 
     println!("OTA header length: {} bytes", header_len);
 
@@ -221,3 +349,72 @@ fn pack_bin(file_path: PathBuf) -> i32 {
 
     OK
 }
+
+/// Writes `<file>.manifest` next to the OTA file, see [`ota::manifest`]
+///
+/// Only the contents of the files go in, never their paths or the time, so rebuilding the
+/// same source with the same `SOURCE_DATE_EPOCH` gives the same manifest.
+fn write_manifest(
+    file_path: &Path,
+    bootloader: Option<&String>,
+    partition_table: Option<&String>,
+) -> i32 {
+    let Ok(image) = std::fs::read(file_path) else {
+        eprintln!("Error: Could not read file '{}'", file_path.display());
+        return READ_FAILED;
+    };
+    let mut text = format!("{}\n", manifest::HEADER);
+    text.push_str(&format!("app sha256: {:x}\n", Sha256::digest(&image)));
+    text.push_str(&format!("app size: {}\n", image.len()));
+    if let Some(target) = image_target(&image) {
+        text.push_str(&format!(
+            "target: chip ID {} revision {}\n",
+            target.family, target.revision
+        ));
+    }
+    for (key, path) in [
+        ("bootloader sha256", bootloader),
+        ("partition table sha256", partition_table),
+    ] {
+        let Some(path) = path else {
+            continue;
+        };
+        let Ok(bytes) = std::fs::read(path) else {
+            eprintln!("Error: Could not read file '{path}'");
+            return READ_FAILED;
+        };
+        text.push_str(&format!("{key}: {:x}\n", Sha256::digest(&bytes)));
+    }
+    match manifest::build_record(&image) {
+        Some(record) => text.push_str(record),
+        None => println!("No build record in the image, the manifest only describes the files"),
+    }
+
+    let mut manifest_path = file_path.to_path_buf();
+    manifest_path.set_extension("manifest");
+    println!("Saving manifest to: {}", manifest_path.display());
+    if std::fs::write(&manifest_path, text).is_err() {
+        eprintln!(
+            "Error: Could not write manifest file '{}'",
+            manifest_path.display()
+        );
+        return WRITE_FAILED;
+    }
+    OK
+}
+
+/// Reads the chip ID and minimum chip revision from the header of an ESP-IDF app image,
+/// as written by `espflash save-image`
+///
+/// `None` if `image` does not start with such a header.
+fn image_target(image: &[u8]) -> Option<ChipTarget> {
+    // esp_image_header_t: magic byte 0xE9, chip ID at 12, minimum full revision at 15,
+    // both little endian
+    if image.len() < 24 || image[0] != 0xE9 {
+        return None;
+    }
+    Some(ChipTarget {
+        family: u16::from_le_bytes([image[12], image[13]]),
+        revision: u16::from_le_bytes([image[15], image[16]]),
+    })
+}
@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText: 2026 Julio Beltran Ortega <jubeormk1@gmail.com>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Failure injection for the OTA error paths
+//!
+//! Builds with the `fault-inject` feature read one fault from `SSH_STAMP_FAULT` at
+//! build time and make every upload hit it, so the error handling, the OTA history and
+//! the rollback to the running image can be exercised on a test rig without a bad
+//! flash chip or a mangled upload:
+//!
+//! - `write@<offset>`: writing the blob byte at `<offset>` to flash fails.
+//! - `erase@<offset>`: erasing the sector holding blob byte `<offset>` fails.
+//! - `corrupt@<offset>`: blob byte `<offset>` arrives with its lowest bit flipped, so
+//!   the segment CRC-32 or the SHA256 checksum does not match.
+//! - `partition-table`: the partition table cannot be read, so the OTA partition size
+//!   is unknown.
+//!
+//! Offsets are decimal or `0x` hexadecimal. Never ship such a build.
+
+use core::fmt;
+use core::str::FromStr;
+
+/// A failure to inject, see the module documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Write(u32),
+    Erase(u32),
+    Corrupt(u32),
+    PartitionTable,
+}
+
+impl Fault {
+    /// The fault this firmware was built with, if any
+    #[cfg(target_os = "none")]
+    pub fn armed() -> Option<Self> {
+        let spec = option_env!("SSH_STAMP_FAULT")?;
+        let fault = spec.parse().ok();
+        if fault.is_none() {
+            log::error!("Ignoring SSH_STAMP_FAULT={spec}: not a fault");
+        }
+        fault
+    }
+
+    /// Whether an operation on `len` blob bytes from `offset` hits this fault's offset
+    fn covers(at: u32, offset: u32, len: usize) -> bool {
+        at >= offset && u64::from(at - offset) < len as u64
+    }
+
+    /// Whether writing `len` bytes at `offset` fails
+    pub fn fails_write(self, offset: u32, len: usize) -> bool {
+        matches!(self, Fault::Write(at) if Self::covers(at, offset, len))
+    }
+
+    /// Whether erasing `len` bytes at `offset` fails
+    pub fn fails_erase(self, offset: u32, len: u32) -> bool {
+        matches!(self, Fault::Erase(at) if Self::covers(at, offset, len as usize))
+    }
+
+    /// Index of the byte to flip in a chunk of `len` blob bytes from `offset`
+    pub fn corrupts(self, offset: u32, len: usize) -> Option<usize> {
+        match self {
+            Fault::Corrupt(at) if Self::covers(at, offset, len) => Some((at - offset) as usize),
+            _ => None,
+        }
+    }
+}
+
+/// `SSH_STAMP_FAULT` is not one of the forms in the module documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadFault;
+
+impl FromStr for Fault {
+    type Err = BadFault;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "partition-table" {
+            return Ok(Fault::PartitionTable);
+        }
+        let (kind, offset) = s.split_once('@').ok_or(BadFault)?;
+        let offset = match offset.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => offset.parse(),
+        }
+        .map_err(|_| BadFault)?;
+        match kind {
+            "write" => Ok(Fault::Write(offset)),
+            "erase" => Ok(Fault::Erase(offset)),
+            "corrupt" => Ok(Fault::Corrupt(offset)),
+            _ => Err(BadFault),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Write(at) => write!(f, "write@{at:#x}"),
+            Fault::Erase(at) => write!(f, "erase@{at:#x}"),
+            Fault::Corrupt(at) => write!(f, "corrupt@{at:#x}"),
+            Fault::PartitionTable => f.write_str("partition-table"),
+        }
+    }
+}
@@ -2,14 +2,65 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use ed25519_dalek::VerifyingKey;
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Instant};
 use sunset::sshwire::{SSHDecode, SSHSource, WireError};
 
+use crate::crc32::Crc32;
+#[cfg(feature = "fault-inject")]
+use crate::fault::Fault;
 use crate::{OtaHeader, tlv};
-use ssh_stamp_hal::OtaActions;
+use ssh_stamp_hal::{OtaActions, OtaFailure, OtaUpload};
 
 use log::{debug, error, info, warn};
 use sha2::{Digest, Sha256};
 
+/// Flash is erased up to this many bytes past the write cursor
+///
+/// The target partition still holds an older image, so every sector must be erased
+/// before it is written. A single SPI flash cannot erase and program at the same
+/// time, so the erase runs ahead of the writes instead: when a chunk reaches the
+/// erased boundary, the rest of its sector and the following `ERASE_AHEAD` bytes are
+/// erased, and the next chunks go straight to programming. The erase and program
+/// times are logged when the update finalizes.
+///
+/// Each sector is erased in its own call, which takes the flash mutex only for that
+/// sector, and the task yields between sectors, so config saves and the other tasks
+/// sharing the executor wait at most one sector erase.
+const ERASE_AHEAD: u32 = 64 * 1024;
+
+/// Number of out of order writes held back until the data before them arrives
+///
+/// SFTP clients may keep several writes in flight, and nothing forces them to send
+/// those in file order. The image is hashed and written strictly in order, so a write
+/// past the next expected offset waits in the reorder window. Once the window is full,
+/// or a write does not fit in a slot, the upload fails.
+pub(crate) const REORDER_SLOTS: usize = 4;
+/// Largest out of order write the reorder window can hold
+pub(crate) const REORDER_CHUNK_LEN: usize = 512;
+
+/// A write held back in the reorder window
+struct ReorderSlot {
+    /// File offset of the first byte
+    offset: u64,
+    /// Bytes in use, 0 for a free slot
+    len: usize,
+    data: [u8; REORDER_CHUNK_LEN],
+}
+
+impl ReorderSlot {
+    const EMPTY: Self = Self {
+        offset: 0,
+        len: 0,
+        data: [0; REORDER_CHUNK_LEN],
+    };
+
+    fn is_free(&self) -> bool {
+        self.len == 0
+    }
+}
+
 /// `UpdateProcessorState` for OTA update processing
 ///
 /// This enum defines the various states of the OTA update processing state machine and will control the flow of the update process.
@@ -48,28 +99,70 @@ pub(crate) struct UpdateProcessor<W: OtaActions> {
     state: UpdateProcessorState,
     /// Hasher computing the checksum of the downloaded firmware on the fly
     hasher: Sha256,
+    /// CRC-32 of the current segment so far, when the header carries segment checksums
+    segment_crc: Crc32,
     header: OtaHeader,
     ota_writer: W,
+    /// Key images must be signed with, `None` to accept unsigned images
+    firmware_key: Option<VerifyingKey>,
     tlv_holder: [u8; tlv::MAX_TLV_SIZE as usize],
     current_len: usize,
+    /// The target partition is erased up to here
+    erased_until: u32,
+    /// Time spent erasing flash during this update
+    erase_time: Duration,
+    /// Time spent programming flash during this update
+    write_time: Duration,
+    /// File offset of the next byte to process
+    next_offset: u64,
+    /// Writes that arrived ahead of `next_offset`
+    reorder_window: [ReorderSlot; REORDER_SLOTS],
+    /// The outcome of this update was passed to the platform already
+    outcome_recorded: bool,
+    /// Failure to inject into every update, see [`crate::fault`]
+    #[cfg(feature = "fault-inject")]
+    fault: Option<Fault>,
 }
 
 impl<W: OtaActions> UpdateProcessor<W> {
     /// Creates a new `UpdateProcessor` instance with the given `OtaActions` implementation
     ///
-    /// Use this `ota_writer` to perform platform-specific OTA actions
-    pub fn new(ota_writer: W) -> Self {
+    /// Use this `ota_writer` to perform platform-specific OTA actions. With a
+    /// `firmware_key`, only images whose header carries a signature made with it are
+    /// written.
+    pub fn new(ota_writer: W, firmware_key: Option<VerifyingKey>) -> Self {
+        #[cfg(feature = "fault-inject")]
+        let fault = Fault::armed();
+        #[cfg(feature = "fault-inject")]
+        if let Some(fault) = fault {
+            warn!("UpdateProcessor: Fault injection armed: {fault}");
+        }
         Self {
             state: UpdateProcessorState::default(),
             hasher: Sha256::new(),
+            segment_crc: Crc32::new(),
             header: OtaHeader {
                 ota_type: None,
+                format_version: None,
+                target_ic: None,
                 firmware_blob_size: None,
                 sha256_checksum: None,
+                signature: None,
+                crc32_segments: None,
+                release_notes: None,
             },
             ota_writer,
+            firmware_key,
             tlv_holder: [0; tlv::MAX_TLV_SIZE as usize],
             current_len: 0,
+            erased_until: 0,
+            erase_time: Duration::from_ticks(0),
+            write_time: Duration::from_ticks(0),
+            next_offset: 0,
+            reorder_window: [ReorderSlot::EMPTY; REORDER_SLOTS],
+            outcome_recorded: false,
+            #[cfg(feature = "fault-inject")]
+            fault,
         }
     }
 
@@ -77,7 +170,9 @@ impl<W: OtaActions> UpdateProcessor<W> {
     ///
     /// It processes data based on the current state of the update processor [[`UpdateProcessorState`]]. To first, read most metadata parameters, after that, write the data to the appropriate location. as it is received.
     ///
-    /// It will try to consume as much data as possible from the provided buffer and return the number of bytes used.
+    /// Data arriving ahead of the next expected file `offset` is held back in the reorder
+    /// window, see [`REORDER_SLOTS`], and processed once the gap before it is filled.
+    /// Data already processed, such as a repeated write, is skipped.
     pub async fn process_data(&mut self, offset: u64, data: &[u8]) -> Result<(), OtaError> {
         debug!(
             "UpdateProcessor: Processing data chunk at offset {}, length {} in state {:?}",
@@ -85,6 +180,76 @@ impl<W: OtaActions> UpdateProcessor<W> {
             data.len(),
             self.state
         );
+        if offset > self.next_offset {
+            return self.hold_back(offset, data);
+        }
+        self.process_in_order(offset, data).await?;
+
+        while let Some(index) = self
+            .reorder_window
+            .iter()
+            .position(|slot| !slot.is_free() && slot.offset <= self.next_offset)
+        {
+            let slot = core::mem::replace(&mut self.reorder_window[index], ReorderSlot::EMPTY);
+            debug!(
+                "UpdateProcessor: Processing held back chunk at offset {}, length {}",
+                slot.offset, slot.len
+            );
+            self.process_in_order(slot.offset, &slot.data[..slot.len])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Keeps a chunk that arrived ahead of `next_offset` in the reorder window
+    fn hold_back(&mut self, offset: u64, data: &[u8]) -> Result<(), OtaError> {
+        if let UpdateProcessorState::Error(ota_error) = self.state {
+            warn!(
+                "UpdateProcessor: Received data in Error state: {ota_error:?}, ignoring additional data"
+            );
+            return Ok(());
+        }
+        if self
+            .reorder_window
+            .iter()
+            .any(|slot| !slot.is_free() && slot.offset == offset && slot.len >= data.len())
+        {
+            debug!("UpdateProcessor: Chunk at offset {offset} already held back");
+            return Ok(());
+        }
+        let free = self.reorder_window.iter_mut().find(|slot| slot.is_free());
+        let Some(slot) = free.filter(|_| !data.is_empty() && data.len() <= REORDER_CHUNK_LEN)
+        else {
+            error!(
+                "UpdateProcessor: Chunk at offset {offset}, length {} does not fit the reorder window, expected offset {}",
+                data.len(),
+                self.next_offset
+            );
+            self.state = UpdateProcessorState::Error(OtaError::ReorderWindowExceeded);
+            return Err(OtaError::ReorderWindowExceeded);
+        };
+        debug!(
+            "UpdateProcessor: Holding back chunk at offset {offset}, expected offset {}",
+            self.next_offset
+        );
+        slot.offset = offset;
+        slot.len = data.len();
+        slot.data[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Processes a chunk starting at or before `next_offset`, skipping the bytes
+    /// already processed
+    async fn process_in_order(&mut self, offset: u64, data: &[u8]) -> Result<(), OtaError> {
+        let Ok(skip) = usize::try_from(self.next_offset - offset) else {
+            return Ok(());
+        };
+        let Some(data) = data.get(skip..).filter(|data| !data.is_empty()) else {
+            debug!("UpdateProcessor: Chunk at offset {offset} was already processed");
+            return Ok(());
+        };
+        self.next_offset += data.len() as u64;
+
         let mut source = tlv::TlvsSource::new(data);
         while source.remaining() > 0 {
             debug!("processor state : {:?}", self.state);
@@ -175,6 +340,37 @@ impl<W: OtaActions> UpdateProcessor<W> {
                 self.tlv_holder.fill(0);
                 self.current_len = 0;
             }
+            tlv::Tlv::FormatVersion { version } => {
+                debug!("Received format version: {version}");
+                if self.header.ota_type.is_none() {
+                    error!("UpdateProcessor: Received Format Version TLV before OTA Type TLV");
+                    self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
+                    return Err(OtaError::IllegalOperation);
+                }
+                if OtaHeader::check_format_version(version).is_err() {
+                    self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
+                    return Err(OtaError::IllegalOperation);
+                }
+                self.header.format_version = Some(version);
+                self.tlv_holder.fill(0);
+                self.current_len = 0;
+            }
+            tlv::Tlv::TargetIc { target } => {
+                debug!("Received target IC: {target:?}");
+                if self.header.ota_type.is_none() {
+                    error!("UpdateProcessor: Received Target IC TLV before OTA Type TLV");
+                    self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
+                    return Err(OtaError::IllegalOperation);
+                }
+                // Refused here, before the partition is erased
+                if OtaHeader::check_target_ic(target, W::running_chip()).is_err() {
+                    self.state = UpdateProcessorState::Error(OtaError::WrongTarget);
+                    return Err(OtaError::WrongTarget);
+                }
+                self.header.target_ic = Some(target);
+                self.tlv_holder.fill(0);
+                self.current_len = 0;
+            }
             tlv::Tlv::Sha256Checksum { checksum } => {
                 debug!("Received Checksum: {checksum:?}");
                 if self.header.ota_type.is_none() {
@@ -186,6 +382,43 @@ impl<W: OtaActions> UpdateProcessor<W> {
                 self.tlv_holder.fill(0);
                 self.current_len = 0;
             }
+            tlv::Tlv::Signature { signature } => {
+                debug!("Received signature: {signature:x?}");
+                if self.header.ota_type.is_none() {
+                    error!("UpdateProcessor: Received Signature TLV before OTA Type TLV");
+                    self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
+                    return Err(OtaError::IllegalOperation);
+                }
+                self.header.signature = Some(signature);
+                self.tlv_holder.fill(0);
+                self.current_len = 0;
+            }
+            tlv::Tlv::Crc32Segments { segments } => {
+                debug!(
+                    "Received {} CRC32 checksums of {} byte segments",
+                    segments.crcs().len(),
+                    segments.segment_size
+                );
+                if self.header.ota_type.is_none() {
+                    error!("UpdateProcessor: Received CRC32 Segments TLV before OTA Type TLV");
+                    self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
+                    return Err(OtaError::IllegalOperation);
+                }
+                self.header.crc32_segments = Some(segments);
+                self.tlv_holder.fill(0);
+                self.current_len = 0;
+            }
+            tlv::Tlv::ReleaseNotes { notes } => {
+                debug!("Received release notes: {}", notes.as_str());
+                if self.header.ota_type.is_none() {
+                    error!("UpdateProcessor: Received Release Notes TLV before OTA Type TLV");
+                    self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
+                    return Err(OtaError::IllegalOperation);
+                }
+                self.header.release_notes = Some(notes);
+                self.tlv_holder.fill(0);
+                self.current_len = 0;
+            }
             tlv::Tlv::FirmwareBlob { size } => {
                 self.handle_firmware_blob(size).await?;
             }
@@ -206,6 +439,18 @@ impl<W: OtaActions> UpdateProcessor<W> {
             self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
             return Err(OtaError::IllegalOperation);
         }
+        // The header is complete here, so this is before the partition is erased
+        if let Some(key) = &self.firmware_key
+            && self.header.check_signature(key).is_err()
+        {
+            self.state = UpdateProcessorState::Error(OtaError::BadSignature);
+            return Err(OtaError::BadSignature);
+        }
+        #[cfg(feature = "fault-inject")]
+        if self.fault == Some(Fault::PartitionTable) {
+            error!("UpdateProcessor: Fault injection: partition table unreadable");
+            return Err(OtaError::InternalError);
+        }
         let max_size = W::get_ota_partition_size()
             .await
             .map_err(|_| OtaError::InternalError)?;
@@ -216,6 +461,17 @@ impl<W: OtaActions> UpdateProcessor<W> {
             self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
             return Err(OtaError::IllegalOperation);
         }
+        if let Some(segments) = &self.header.crc32_segments
+            && !segments.covers(size)
+        {
+            error!(
+                "UpdateProcessor: {} CRC32 checksums of {} byte segments do not cover a {size} byte blob",
+                segments.crcs().len(),
+                segments.segment_size
+            );
+            self.state = UpdateProcessorState::Error(OtaError::IllegalOperation);
+            return Err(OtaError::IllegalOperation);
+        }
         self.header.firmware_blob_size = Some(size);
 
         debug!("Starting OTA update");
@@ -253,13 +509,32 @@ impl<W: OtaActions> UpdateProcessor<W> {
             OtaError::InternalError
         })?;
 
-        self.hasher.update(data_chunk);
+        self.digest(*total_received_size, data_chunk, total_blob_size)?;
+
+        let chunk_end = *total_received_size
+            + u32::try_from(to_take).map_err(|_| {
+                error!("UpdateProcessor: Data chunk size overflow");
+                OtaError::InternalError
+            })?;
+        self.erase_ahead(chunk_end, total_blob_size).await?;
 
         debug!(
             "Writing {} bytes to flash at offset {}",
             data_chunk.len(),
             *total_received_size
         );
+        #[cfg(feature = "fault-inject")]
+        if self
+            .fault
+            .is_some_and(|f| f.fails_write(*total_received_size, data_chunk.len()))
+        {
+            error!(
+                "UpdateProcessor: Fault injection: write at offset {} fails",
+                *total_received_size
+            );
+            return Err(OtaError::WriteError);
+        }
+        let write_start = Instant::now();
         self.ota_writer
             .write_ota_data(*total_received_size, data_chunk)
             .await
@@ -270,11 +545,9 @@ impl<W: OtaActions> UpdateProcessor<W> {
                 );
                 OtaError::WriteError
             })?;
+        self.write_time += write_start.elapsed();
 
-        *total_received_size += u32::try_from(to_take).map_err(|_| {
-            error!("UpdateProcessor: Data chunk size overflow");
-            OtaError::InternalError
-        })?;
+        *total_received_size = chunk_end;
 
         if *total_received_size >= total_blob_size {
             self.verify_checksum()?;
@@ -288,6 +561,127 @@ impl<W: OtaActions> UpdateProcessor<W> {
         Ok(())
     }
 
+    /// Feeds a chunk starting at blob offset `start` to the segment CRC-32 checks and
+    /// the SHA256 hasher
+    ///
+    /// With a `corrupt` fault armed, see [`crate::fault`], the byte at its offset is fed
+    /// with its lowest bit flipped.
+    fn digest(&mut self, start: u32, chunk: &[u8], blob_size: u32) -> Result<(), OtaError> {
+        #[cfg(feature = "fault-inject")]
+        if let Some(i) = self.fault.and_then(|f| f.corrupts(start, chunk.len())) {
+            let at = start + u32::try_from(i).map_err(|_| OtaError::InternalError)?;
+            warn!("UpdateProcessor: Fault injection: flipping blob byte {at}");
+            let flipped = [chunk[i] ^ 1];
+            for (position, part) in [
+                (start, &chunk[..i]),
+                (at, &flipped[..]),
+                (at + 1, &chunk[i + 1..]),
+            ] {
+                self.check_segment_crcs(position, part, blob_size)?;
+                self.hasher.update(part);
+            }
+            return Ok(());
+        }
+        self.check_segment_crcs(start, chunk, blob_size)?;
+        self.hasher.update(chunk);
+        Ok(())
+    }
+
+    /// Feeds a chunk starting at blob offset `start` to the segment CRC-32 and checks
+    /// every segment the chunk completes
+    ///
+    /// Runs before the chunk is hashed and written, so a corrupted upload is rejected at
+    /// the end of the segment instead of after the whole blob. Does nothing when the
+    /// header carries no segment checksums.
+    fn check_segment_crcs(
+        &mut self,
+        start: u32,
+        mut chunk: &[u8],
+        blob_size: u32,
+    ) -> Result<(), OtaError> {
+        let Some(segments) = &self.header.crc32_segments else {
+            return Ok(());
+        };
+        let segment_size = segments.segment_size;
+        let mut position = start;
+        while !chunk.is_empty() {
+            let segment = position / segment_size;
+            let segment_end = (segment + 1).saturating_mul(segment_size).min(blob_size);
+            let (head, rest) = chunk.split_at(chunk.len().min((segment_end - position) as usize));
+            self.segment_crc.update(head);
+            position += u32::try_from(head.len()).map_err(|_| {
+                error!("UpdateProcessor: Data chunk size overflow");
+                OtaError::InternalError
+            })?;
+            chunk = rest;
+
+            if position == segment_end {
+                let computed = core::mem::take(&mut self.segment_crc).finalize();
+                let expected = segments.crcs().get(segment as usize).copied();
+                if expected != Some(computed) {
+                    error!(
+                        "UpdateProcessor: CRC32 mismatch in segment {segment}: expected {expected:x?}, computed {computed:08x}"
+                    );
+                    self.state = UpdateProcessorState::Error(OtaError::VerificationFailed);
+                    return Err(OtaError::VerificationFailed);
+                }
+                debug!("UpdateProcessor: CRC32 of segment {segment} verified");
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure the target partition is erased up to `end`
+    ///
+    /// When `end` crosses the erased boundary, erases up to [`ERASE_AHEAD`] bytes past
+    /// it one sector at a time, never beyond the sector holding the last byte of the
+    /// blob.
+    async fn erase_ahead(&mut self, end: u32, blob_size: u32) -> Result<(), OtaError> {
+        if end <= self.erased_until {
+            return Ok(());
+        }
+        let erase_size = W::ERASE_SIZE;
+        let until = end
+            .saturating_add(ERASE_AHEAD)
+            .min(blob_size)
+            .next_multiple_of(erase_size);
+        let len = until - self.erased_until;
+
+        debug!(
+            "Erasing {len} bytes of flash at offset {}",
+            self.erased_until
+        );
+        #[cfg(feature = "fault-inject")]
+        if self
+            .fault
+            .is_some_and(|f| f.fails_erase(self.erased_until, len))
+        {
+            error!(
+                "UpdateProcessor: Fault injection: erase at offset {} fails",
+                self.erased_until
+            );
+            return Err(OtaError::WriteError);
+        }
+        while self.erased_until < until {
+            let erase_start = Instant::now();
+            self.ota_writer
+                .erase_ota_data(self.erased_until, erase_size)
+                .await
+                .map_err(|e| {
+                    error!(
+                        "UpdateProcessor: Error erasing flash at offset {}: {e:?}",
+                        self.erased_until
+                    );
+                    OtaError::WriteError
+                })?;
+            self.erase_time += erase_start.elapsed();
+            self.erased_until += erase_size;
+            // Let the tasks waiting on the flash or the executor run
+            yield_now().await;
+        }
+        Ok(())
+    }
+
     fn verify_checksum(&mut self) -> Result<(), OtaError> {
         let Some(original_hash) = self.header.sha256_checksum else {
             error!("UpdateProcessor: No original checksum to verify against after download");
@@ -315,11 +709,23 @@ impl<W: OtaActions> UpdateProcessor<W> {
         let ret_val = match self.state {
             UpdateProcessorState::Finished {} => {
                 info!("Finalizing OTA update process successfully.");
+                info!(
+                    "OTA flash time: erase {} ms, write {} ms",
+                    self.erase_time.as_millis(),
+                    self.write_time.as_millis()
+                );
+                if let Some(notes) = &self.header.release_notes {
+                    info!("OTA image accepted, release notes: {}", notes.as_str());
+                }
 
-                self.ota_writer.finalize_ota_update().await.map_err(|e| {
-                    error!("Error finalizing OTA update: {e:?}");
-                    OtaError::InternalError
-                })
+                let upload = self.upload();
+                self.ota_writer
+                    .finalize_ota_update(&upload)
+                    .await
+                    .map_err(|e| {
+                        error!("Error finalizing OTA update: {e:?}");
+                        OtaError::InternalError
+                    })
             }
             UpdateProcessorState::Error(e) => {
                 error!("Cannot finalize OTA update due to error state: {e:?}");
@@ -334,6 +740,21 @@ impl<W: OtaActions> UpdateProcessor<W> {
             }
         };
 
+        match ret_val {
+            Ok(()) => self.outcome_recorded = true,
+            Err(e) => {
+                // In any other state the image did not arrive in full
+                let e = if matches!(
+                    self.state,
+                    UpdateProcessorState::Finished {} | UpdateProcessorState::Error(_)
+                ) {
+                    e
+                } else {
+                    OtaError::MoreDataRequired
+                };
+                self.record_failure(e).await;
+            }
+        }
         self.reset_ota_state();
         ret_val
     }
@@ -346,14 +767,54 @@ impl<W: OtaActions> UpdateProcessor<W> {
         info!("Resetting OTA processor state.");
         self.state = UpdateProcessorState::default();
         self.hasher = Sha256::new();
+        self.segment_crc = Crc32::new();
         self.header = OtaHeader {
             ota_type: None,
+            format_version: None,
+            target_ic: None,
             firmware_blob_size: None,
             sha256_checksum: None,
+            signature: None,
+            crc32_segments: None,
+            release_notes: None,
         };
+        self.erased_until = 0;
+        self.erase_time = Duration::from_ticks(0);
+        self.write_time = Duration::from_ticks(0);
+        self.next_offset = 0;
+        self.reorder_window = [ReorderSlot::EMPTY; REORDER_SLOTS];
+        self.outcome_recorded = false;
     }
 
-    // Add other parameters, such as verify, apply, check signature, etc.
+    /// What the header told about the image so far
+    fn upload(&self) -> OtaUpload {
+        OtaUpload {
+            size: self.header.firmware_blob_size,
+            sha256: self.header.sha256_checksum,
+            release_notes: self
+                .header
+                .release_notes
+                .and_then(|notes| notes.as_str().try_into().ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Passes a failed update to the platform's OTA history, once per update
+    ///
+    /// A failed write is recorded right away, since the client may drop the
+    /// connection instead of closing the file.
+    pub async fn record_failure(&mut self, error: OtaError) {
+        if self.outcome_recorded {
+            return;
+        }
+        self.outcome_recorded = true;
+        let upload = self.upload();
+        self.ota_writer
+            .record_failed_upload(&upload, error.failure())
+            .await;
+    }
+
+    // Add other parameters, such as verify, apply, etc.
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -371,4 +832,26 @@ pub(crate) enum OtaError {
     VerificationFailed,
     /// Unknown TLV Type encountered during processing
     UnknownTlvType,
+    /// A write arrived too far ahead of the data before it
+    ReorderWindowExceeded,
+    /// The image is for another chip
+    WrongTarget,
+    /// The image is unsigned or its signature does not match the firmware key
+    BadSignature,
+}
+
+impl OtaError {
+    /// How the failure is told in the platform's OTA history
+    fn failure(self) -> OtaFailure {
+        match self {
+            OtaError::IllegalOperation | OtaError::UnknownTlvType | OtaError::WrongTarget => {
+                OtaFailure::BadHeader
+            }
+            OtaError::VerificationFailed => OtaFailure::Verification,
+            OtaError::BadSignature => OtaFailure::Signature,
+            OtaError::WriteError => OtaFailure::Write,
+            OtaError::MoreDataRequired | OtaError::ReorderWindowExceeded => OtaFailure::Incomplete,
+            OtaError::InternalError => OtaFailure::Internal,
+        }
+    }
 }
@@ -11,7 +11,9 @@
 //! The [`tlv`] module defines the TLV record format used by the `packer`
 //! host utility and the on-device parser. The `packer` binary
 //! (`ota/src/bin/packer.rs`) wraps a raw app binary into an `.otap` blob
-//! with the required TLV header (OTA type, SHA-256 checksum, firmware size).
+//! with the required TLV header (OTA type, format version, SHA-256
+//! checksum, optional per-segment CRC-32 checksums, firmware size). The
+//! format is specified, per version, in `ota/README.md`.
 //!
 //! This crate is `no_std` on embedded targets. The `std` feature gate and
 //! `cfg(target_os = "none")` keep the SFTP server and handler modules
@@ -26,15 +28,56 @@
 /// Entry point for this crate when used as an OTA server on the device.
 #[cfg(target_os = "none")]
 pub use sftpserver::run_ota_server;
+/// Size of the request buffer [`run_ota_server`] takes, so callers can
+/// place it wherever suits their memory layout.
+#[cfg(target_os = "none")]
+pub use sunset_sftp::server::MAX_REQUEST_LEN as SFTP_REQUEST_LEN;
+/// Failure injection for exercising the OTA error paths, see `SSH_STAMP_FAULT`
+#[cfg(any(feature = "fault-inject", test))]
+pub mod fault;
 /// Module handling OTA update metadata and header parsing
 ///
-/// It will be called from the sftpserver module to handle the OTA update process
-#[cfg(target_os = "none")]
+/// It will be called from the sftpserver module to handle the OTA update process.
+/// Host tests drive it directly.
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
 mod handler;
 /// Module implementing the OTA SFTP server
 #[cfg(target_os = "none")]
 mod sftpserver;
 
+/// Path of the read-only file holding the version report, one of the
+/// [`InfoFile`]s passed to `run_ota_server`.
+pub const VERSION_PATH: &str = "/info/version";
+
+/// Path of the read-only file describing the upload target: a
+/// `capacity: <bytes>` line giving the size of the OTA partition.
+pub const OTA_INFO_PATH: &str = "/info/ota";
+
+/// A read-only file served next to the OTA upload target, e.g. the version
+/// report at [`VERSION_PATH`]. Every path that is not one of these, or a
+/// directory holding one, is the upload target.
+#[derive(Debug, Clone, Copy)]
+pub struct InfoFile<'a> {
+    /// Absolute path, e.g. `/info/version`.
+    pub path: &'a str,
+    pub contents: &'a [u8],
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib and `crc32` tools)
+///
+/// Far cheaper than SHA256, it lets the device reject a corrupted segment of an upload
+/// before writing it to flash. SHA256 remains the final integrity check.
+pub mod crc32;
+
+/// Build manifests written by the packer next to `.ota` files, and the build record they
+/// copy from the firmware
+pub mod manifest;
+
+/// The operator's own files, kept in the scratch partition and served over SFTP next to
+/// the OTA upload target
+pub mod scratch;
+
 /// Module defining TLV types and constants for OTA updates
 ///
 /// Re-exporting this module for easier access from outside the crate: packer
@@ -258,5 +301,743 @@ mod ota_tlv_tests {
         assert_eq!(header.sha256_checksum, None);
     }
 
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crate::crc32::Crc32::checksum(b"123456789"), 0xCBF4_3926);
+
+        let mut crc = crate::crc32::Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finalize(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc32_segments_cover_blob() {
+        let blob = [0xA5u8; 3 * CRC32_SEGMENT_ALIGN as usize + 10];
+        let segments = SegmentCrcs::compute(&blob);
+
+        assert_eq!(segments.segment_size, CRC32_SEGMENT_ALIGN);
+        assert_eq!(segments.crcs().len(), 4);
+        assert!(segments.covers(u32::try_from(blob.len()).unwrap()));
+        assert!(!segments.covers(u32::try_from(blob.len()).unwrap() + CRC32_SEGMENT_ALIGN));
+        assert_eq!(
+            segments.crcs()[3],
+            crate::crc32::Crc32::checksum(&blob[3 * CRC32_SEGMENT_ALIGN as usize..])
+        );
+
+        // Large blobs get larger segments rather than more checksums
+        let blob = vec![0u8; 2 * 1024 * 1024];
+        let segments = SegmentCrcs::compute(&blob);
+        assert_eq!(segments.segment_size % CRC32_SEGMENT_ALIGN, 0);
+        assert!(segments.crcs().len() <= MAX_CRC32_SEGMENTS);
+        assert!(segments.covers(u32::try_from(blob.len()).unwrap()));
+    }
+
+    #[test]
+    fn crc32_segments_round_trip() {
+        let blob = vec![0x5Au8; 1024 * 1024];
+        let segments = SegmentCrcs::compute(&blob);
+        let tlv = Tlv::Crc32Segments { segments };
+
+        let mut buffer = [0u8; MAX_TLV_SIZE as usize];
+        let used = sshwire::write_ssh(&mut buffer, &tlv).expect("Failed to encode TLV");
+        assert!(used <= MAX_TLV_SIZE as usize);
+
+        match sshwire::read_ssh::<Tlv>(&buffer[..used], None).expect("Failed to decode TLV") {
+            Tlv::Crc32Segments { segments: decoded } => assert_eq!(decoded, segments),
+            _ => panic!("Decoded variant does not match original"),
+        }
+    }
+
+    #[test]
+    fn serialized_header_with_crc32_round_trip() {
+        let blob = vec![0x11u8; 10_000];
+        let segments = SegmentCrcs::compute(&blob);
+        let header = OtaHeader::new(
+            OTA_TYPE_VALUE_SSH_STAMP,
+            &[7u8; CHECKSUM_LEN as usize],
+            Some(segments),
+            u32::try_from(blob.len()).unwrap(),
+        );
+
+        let mut buffer = [0u8; 512];
+        let len = header.serialize(&mut buffer);
+        let (decoded, used) =
+            OtaHeader::deserialize(&buffer[..len]).expect("Failed to deserialize header");
+
+        assert_eq!(used, len);
+        assert_eq!(decoded.crc32_segments, Some(segments));
+        assert_eq!(decoded.firmware_blob_size, Some(10_000));
+    }
+
+    #[test]
+    fn crc32_segments_with_bad_length_rejected() {
+        // Type, length 6: a segment size and half a checksum
+        let buffer = [CRC32_SEGMENTS, 6, 0, 0, 16, 0, 1, 2];
+        assert!(sshwire::read_ssh::<Tlv>(&buffer, None).is_err());
+    }
+
+    /// Headers as written by the packer for each OTA format version, for a 5000 byte blob
+    /// of 0x11 bytes, a SHA256 checksum of 0x07 bytes, the release notes "v1.2 fix" and
+    /// an ESP32-C3 revision 0.3 target.
+    /// These bytes must never change:
+    /// fielded devices parse them, and new parsers must keep accepting them.
+    mod format_compat {
+        use crate::OtaHeader;
+        use crate::tlv::*;
+        use ssh_stamp_hal::ChipTarget;
+
+        const TARGET: ChipTarget = ChipTarget {
+            family: 5,
+            revision: 3,
+        };
+        const OTA_TYPE_BYTES: [u8; 6] = [OTA_TYPE, 4, 0x73, 0x73, 0x68, 0x73];
+        const SHA256_BYTES: [u8; 34] = {
+            let mut tlv = [7u8; 34];
+            tlv[0] = SHA256_CHECKSUM;
+            tlv[1] = 32;
+            tlv
+        };
+        const CRC32_BYTES: [u8; 14] = [
+            CRC32_SEGMENTS,
+            12,
+            0,
+            0,
+            0x10,
+            0x00,
+            0xE6,
+            0x7E,
+            0x93,
+            0x1F,
+            0xC6,
+            0x18,
+            0xBD,
+            0x35,
+        ];
+        const RELEASE_NOTES_BYTES: [u8; 10] = [
+            RELEASE_NOTES,
+            8,
+            b'v',
+            b'1',
+            b'.',
+            b'2',
+            b' ',
+            b'f',
+            b'i',
+            b'x',
+        ];
+        const TARGET_IC_BYTES: [u8; 6] = [TARGET_IC, 4, 0, 5, 0, 3];
+        const SIGNATURE_BYTES: [u8; 2 + SIGNATURE_LEN as usize] = {
+            let mut tlv = [9u8; 2 + SIGNATURE_LEN as usize];
+            tlv[0] = SIGNATURE;
+            tlv[1] = 64;
+            tlv
+        };
+        const FIRMWARE_BLOB_BYTES: [u8; 6] = [FIRMWARE_BLOB, 4, 0, 0, 0x13, 0x88];
+
+        fn golden(format: u8) -> Vec<u8> {
+            let mut header = OTA_TYPE_BYTES.to_vec();
+            if format >= 3 {
+                header.extend_from_slice(&[FORMAT_VERSION, 1, format]);
+            }
+            if format >= 5 {
+                header.extend_from_slice(&TARGET_IC_BYTES);
+            }
+            header.extend_from_slice(&SHA256_BYTES);
+            if format >= 6 {
+                header.extend_from_slice(&SIGNATURE_BYTES);
+            }
+            if format >= 2 {
+                header.extend_from_slice(&CRC32_BYTES);
+            }
+            if format >= 4 {
+                header.extend_from_slice(&RELEASE_NOTES_BYTES);
+            }
+            header.extend_from_slice(&FIRMWARE_BLOB_BYTES);
+            header
+        }
+
+        fn packed(format: u8) -> Vec<u8> {
+            let blob = [0x11u8; 5000];
+            let mut buffer = [0u8; 512];
+            let len = OtaHeader::new(
+                OTA_TYPE_VALUE_SSH_STAMP,
+                &[7u8; CHECKSUM_LEN as usize],
+                Some(SegmentCrcs::compute(&blob)),
+                5000,
+            )
+            .with_release_notes(ReleaseNotes::new("v1.2 fix").unwrap())
+            .with_target_ic(TARGET)
+            .with_signature([9u8; SIGNATURE_LEN as usize])
+            .for_format(format)
+            .serialize(&mut buffer);
+            buffer[..len].to_vec()
+        }
+
+        #[test]
+        fn packer_writes_every_format_unchanged() {
+            // Devices running firmware of an older format parse these bytes
+            for format in 1..=OTA_FORMAT_VERSION {
+                assert_eq!(packed(format), golden(format), "format {format}");
+            }
+        }
+
+        #[test]
+        fn parser_reads_every_format() {
+            for format in 1..=OTA_FORMAT_VERSION {
+                let header = golden(format);
+                let (decoded, used) =
+                    OtaHeader::deserialize(&header).expect("Failed to deserialize header");
+
+                assert_eq!(used, header.len(), "format {format}");
+                assert_eq!(decoded.ota_type, Some(OTA_TYPE_VALUE_SSH_STAMP));
+                assert_eq!(decoded.firmware_blob_size, Some(5000));
+                assert_eq!(decoded.sha256_checksum, Some([7u8; CHECKSUM_LEN as usize]));
+                assert_eq!(
+                    decoded.crc32_segments.map(|s| s.crcs().to_vec()),
+                    (format >= 2).then(|| vec![0xE67E_931F, 0xC618_BD35])
+                );
+                assert_eq!(decoded.format_version, (format >= 3).then_some(format));
+                assert_eq!(
+                    decoded.release_notes.as_ref().map(ReleaseNotes::as_str),
+                    (format >= 4).then_some("v1.2 fix")
+                );
+                assert_eq!(decoded.target_ic, (format >= 5).then_some(TARGET));
+                assert_eq!(
+                    decoded.signature,
+                    (format >= 6).then_some([9u8; SIGNATURE_LEN as usize])
+                );
+            }
+        }
+
+        #[test]
+        fn signature_checked_against_firmware_key() {
+            use ed25519_dalek::{Signer, SigningKey};
+
+            let signer = SigningKey::from_bytes(&[1u8; 32]);
+            let key = signer.verifying_key();
+            let checksum = [7u8; CHECKSUM_LEN as usize];
+            let header = |signature| {
+                let header = OtaHeader::new(OTA_TYPE_VALUE_SSH_STAMP, &checksum, None, 5000);
+                match signature {
+                    Some(signature) => header.with_signature(signature),
+                    None => header,
+                }
+            };
+            let signed = header(Some(signer.sign(&checksum).to_bytes()));
+            assert!(signed.check_signature(&key).is_ok());
+            // Unsigned, signed with another key, or over another checksum
+            assert!(header(None).check_signature(&key).is_err());
+            let other = SigningKey::from_bytes(&[2u8; 32]);
+            assert!(
+                header(Some(other.sign(&checksum).to_bytes()))
+                    .check_signature(&key)
+                    .is_err()
+            );
+            assert!(
+                header(Some(signer.sign(&[8u8; 32]).to_bytes()))
+                    .check_signature(&key)
+                    .is_err()
+            );
+        }
+
+        #[test]
+        fn target_ic_checked_against_running_chip() {
+            let check = |family, revision| {
+                OtaHeader::check_target_ic(TARGET, Some(ChipTarget { family, revision }))
+            };
+            assert!(check(5, 3).is_ok());
+            assert!(check(5, 101).is_ok());
+            // An older revision, or an ESP32-S3
+            assert!(check(5, 2).is_err());
+            assert!(check(9, 3).is_err());
+            // Platforms that do not know their chip accept any image
+            assert!(OtaHeader::check_target_ic(TARGET, None).is_ok());
+        }
+
+        #[test]
+        fn newer_format_version_rejected() {
+            let mut header = golden(OTA_FORMAT_VERSION);
+            header[OTA_TYPE_BYTES.len() + 2] = OTA_FORMAT_VERSION + 1;
+            assert!(OtaHeader::deserialize(&header).is_err());
+        }
+
+        #[test]
+        fn release_notes_must_be_utf8() {
+            let mut header = golden(OTA_FORMAT_VERSION);
+            let notes = header.len() - FIRMWARE_BLOB_BYTES.len() - RELEASE_NOTES_BYTES.len();
+            header[notes + 2] = 0xFF;
+            assert!(OtaHeader::deserialize(&header).is_err());
+        }
+
+        #[test]
+        fn format_version_must_follow_ota_type() {
+            let mut header = vec![FORMAT_VERSION, 1, OTA_FORMAT_VERSION];
+            header.extend_from_slice(&OTA_TYPE_BYTES);
+            header.extend_from_slice(&FIRMWARE_BLOB_BYTES);
+            assert!(OtaHeader::deserialize(&header).is_err());
+        }
+    }
+
+    #[test]
+    fn fault_specs_parsed() {
+        use crate::fault::Fault;
+
+        assert_eq!("write@4096".parse(), Ok(Fault::Write(4096)));
+        assert_eq!("erase@0x10000".parse(), Ok(Fault::Erase(0x10000)));
+        assert_eq!("corrupt@0".parse(), Ok(Fault::Corrupt(0)));
+        assert_eq!("partition-table".parse(), Ok(Fault::PartitionTable));
+        for bad in [
+            "write",
+            "write@",
+            "flip@12",
+            "erase@0xZZ",
+            "partition-table@0",
+        ] {
+            assert!(bad.parse::<Fault>().is_err(), "{bad}");
+        }
+        for fault in [Fault::Write(0x1f000), Fault::PartitionTable] {
+            assert_eq!(fault.to_string().parse(), Ok(fault));
+        }
+    }
+
+    #[test]
+    fn faults_hit_only_their_offset() {
+        use crate::fault::Fault;
+
+        let write = Fault::Write(1000);
+        assert!(!write.fails_write(0, 1000));
+        assert!(write.fails_write(0, 1001));
+        assert!(write.fails_write(1000, 1));
+        assert!(!write.fails_write(1001, 512));
+        assert!(!write.fails_erase(0, 4096));
+
+        assert!(Fault::Erase(4096).fails_erase(4096, 4096));
+        assert!(!Fault::Erase(4096).fails_erase(0, 4096));
+
+        let corrupt = Fault::Corrupt(600);
+        assert_eq!(corrupt.corrupts(512, 512), Some(88));
+        assert_eq!(corrupt.corrupts(0, 512), None);
+        assert_eq!(Fault::PartitionTable.corrupts(0, usize::MAX), None);
+    }
+
+    #[test]
+    fn build_record_is_found_in_an_image() {
+        use crate::manifest::{self, BUILD_RECORD_MARKER};
+
+        let record = "ssh-stamp build record\nfirmware: 0.3.0\ngit: 0123456789ab\n\0";
+        assert!(manifest::is_build_record(record));
+        assert!(!manifest::is_build_record(BUILD_RECORD_MARKER));
+        assert!(!manifest::is_build_record(
+            "ssh-stamp build\nfirmware: 0.3.0\n\0"
+        ));
+
+        let mut image = vec![0xE9, 0, 0, 0];
+        image.extend_from_slice(record.as_bytes());
+        image.extend_from_slice(&[0x5a; 16]);
+        let lines = manifest::build_record(&image).unwrap();
+        assert_eq!(lines, "firmware: 0.3.0\ngit: 0123456789ab\n");
+        assert_eq!(
+            manifest::entries(lines).collect::<Vec<_>>(),
+            [("firmware", "0.3.0"), ("git", "0123456789ab")]
+        );
+        assert_eq!(manifest::build_record(&image[..20]), None);
+    }
+
+    #[test]
+    fn manifest_entries_skip_other_lines() {
+        let text = "ssh-stamp manifest: 1\r\n\nno separator\r\napp size: 1024\r\n";
+        assert_eq!(
+            crate::manifest::entries(text).collect::<Vec<_>>(),
+            [("ssh-stamp manifest", "1"), ("app size", "1024")]
+        );
+    }
+
+    /// Scratch partition in RAM, counting the erases of each sector
+    struct RamScratch(std::sync::Mutex<(Vec<u8>, Vec<u32>)>);
+
+    impl RamScratch {
+        const SECTOR: u32 = 4096;
+
+        fn new(sectors: usize) -> Self {
+            let flash = vec![0xff; sectors * Self::SECTOR as usize];
+            Self(std::sync::Mutex::new((flash, vec![0; sectors])))
+        }
+    }
+
+    impl ssh_stamp_hal::ScratchActions for &RamScratch {
+        const ERASE_SIZE: u32 = RamScratch::SECTOR;
+
+        async fn scratch_size(&self) -> Result<u32, ssh_stamp_hal::HalError> {
+            Ok(u32::try_from(self.0.lock().unwrap().0.len()).unwrap())
+        }
+
+        async fn read_scratch(
+            &self,
+            offset: u32,
+            buf: &mut [u8],
+        ) -> Result<(), ssh_stamp_hal::HalError> {
+            assert!(offset.is_multiple_of(4) && buf.len().is_multiple_of(4));
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.0.lock().unwrap().0[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        async fn erase_scratch(
+            &self,
+            offset: u32,
+            len: u32,
+        ) -> Result<(), ssh_stamp_hal::HalError> {
+            assert!(offset.is_multiple_of(Self::ERASE_SIZE) && len == Self::ERASE_SIZE);
+            let (flash, erases) = &mut *self.0.lock().unwrap();
+            flash[offset as usize..(offset + len) as usize].fill(0xff);
+            erases[(offset / Self::ERASE_SIZE) as usize] += 1;
+            Ok(())
+        }
+
+        async fn write_scratch(
+            &self,
+            offset: u32,
+            data: &[u8],
+        ) -> Result<(), ssh_stamp_hal::HalError> {
+            assert!(offset.is_multiple_of(4) && data.len().is_multiple_of(4));
+            let flash = &mut self.0.lock().unwrap().0;
+            for (byte, new) in flash[offset as usize..].iter_mut().zip(data) {
+                *byte &= new;
+            }
+            Ok(())
+        }
+    }
+
+    /// Polls `future` to completion; the RAM flash never makes it wait
+    fn block_on<F: core::future::Future>(future: F) -> F::Output {
+        let mut future = core::pin::pin!(future);
+        let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+        loop {
+            if let core::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    fn read_all(scratch: &crate::scratch::Scratch<&RamScratch>, name: &str) -> Option<Vec<u8>> {
+        let file = scratch.find(&crate::scratch::Name::new(name).unwrap())?;
+        let mut buf = [0; crate::scratch::READ_CHUNK];
+        let mut contents = Vec::new();
+        loop {
+            let offset = u32::try_from(contents.len()).unwrap();
+            let data = block_on(scratch.read(&file, offset, &mut buf)).unwrap();
+            if data.is_empty() {
+                return Some(contents);
+            }
+            contents.extend_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn scratch_names_come_from_paths() {
+        use crate::scratch::{NAME_LEN, Name};
+
+        assert_eq!(
+            Name::from_path("/scratch/notes.txt").map(|n| n.to_string()),
+            Some("notes.txt".into())
+        );
+        assert!(Name::from_path("scratch/notes.txt").is_some());
+        for bad in [
+            "/scratch/",
+            "/scratch/a/b",
+            "/scratch/.hidden",
+            "/scratch/two words",
+            "/scratchy/notes",
+            "/notes",
+        ] {
+            assert!(Name::from_path(bad).is_none(), "{bad}");
+        }
+        assert!(Name::new(&"x".repeat(NAME_LEN)).is_some());
+        assert!(Name::new(&"x".repeat(NAME_LEN + 1)).is_none());
+    }
+
+    #[test]
+    fn scratch_files_are_replaced_whole_within_the_quota() {
+        use crate::scratch::{HEADER_LEN, Name, Scratch, ScratchError};
+
+        let ram = RamScratch::new(16);
+        let load = || block_on(Scratch::load(&ram)).unwrap();
+        let notes = Name::new("notes.txt").unwrap();
+        let mut scratch = load();
+        assert_eq!(scratch.capacity(), 16 * (RamScratch::SECTOR - HEADER_LEN));
+        assert_eq!(scratch.files().count(), 0);
+
+        let mut upload = scratch.begin(notes).unwrap();
+        for piece in [&b"hel"[..], b"lo ", b"world"] {
+            block_on(scratch.append(&mut upload, piece)).unwrap();
+        }
+        block_on(scratch.commit(upload)).unwrap();
+        assert_eq!(read_all(&load(), "notes.txt").unwrap(), b"hello world");
+
+        // Spans two sectors, written in unaligned pieces
+        let text: Vec<u8> = (0..5000u32)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect();
+        let mut scratch = load();
+        let mut upload = scratch.begin(notes).unwrap();
+        assert!(matches!(scratch.begin(notes), Err(ScratchError::Busy)));
+        for piece in [&text[..1001], &text[1001..4002], &text[4002..]] {
+            block_on(scratch.append(&mut upload, piece)).unwrap();
+        }
+        block_on(scratch.commit(upload)).unwrap();
+        let scratch = load();
+        assert_eq!(
+            scratch
+                .files()
+                .map(|(name, len)| (name.to_string(), len))
+                .collect::<Vec<_>>(),
+            [("notes.txt".to_string(), 5000)]
+        );
+        assert_eq!(read_all(&scratch, "notes.txt").unwrap(), text);
+
+        // An upload not completed leaves the file as it was
+        let mut scratch = load();
+        let mut upload = scratch.begin(notes).unwrap();
+        block_on(scratch.append(&mut upload, b"overwritten")).unwrap();
+        drop(upload);
+        assert_eq!(read_all(&load(), "notes.txt").unwrap(), text);
+
+        // Fails once the sectors left are used up, the least erased first
+        let mut scratch = load();
+        let free = scratch.free();
+        assert_eq!(free, 14 * (RamScratch::SECTOR - HEADER_LEN));
+        let mut upload = scratch.begin(Name::new("image.bin").unwrap()).unwrap();
+        let chunk = [0x5a; 1000];
+        let full = loop {
+            if let Err(e) = block_on(scratch.append(&mut upload, &chunk)) {
+                break e;
+            }
+        };
+        assert!(matches!(full, ScratchError::Full));
+        assert_eq!(upload.len(), free);
+        drop(upload);
+        let mut scratch = load();
+        assert_eq!(
+            scratch.most_erases(),
+            *ram.0.lock().unwrap().1.iter().max().unwrap()
+        );
+        assert_eq!(scratch.most_erases(), 2);
+        assert_eq!(read_all(&scratch, "notes.txt").unwrap(), text);
+
+        assert!(block_on(scratch.delete(&notes)).unwrap());
+        assert!(!block_on(scratch.delete(&notes)).unwrap());
+        let scratch = load();
+        assert!(read_all(&scratch, "notes.txt").is_none());
+        assert_eq!(scratch.free(), scratch.capacity());
+    }
+
+    /// OTA partition in RAM, still holding an older image, that refuses writes to bytes
+    /// not erased
+    struct RamOta(std::sync::Mutex<Vec<u8>>);
+
+    impl RamOta {
+        const SECTOR: u32 = 4096;
+        const SIZE: u32 = 16 * Self::SECTOR;
+
+        fn new() -> Self {
+            Self(std::sync::Mutex::new(vec![0; Self::SIZE as usize]))
+        }
+    }
+
+    impl ssh_stamp_hal::OtaActions for &RamOta {
+        const ERASE_SIZE: u32 = RamOta::SECTOR;
+
+        async fn try_validating_current_ota_partition() -> Result<(), ssh_stamp_hal::HalError> {
+            Ok(())
+        }
+
+        async fn is_current_ota_partition_pending() -> Result<bool, ssh_stamp_hal::HalError> {
+            Ok(false)
+        }
+
+        async fn roll_back_current_ota_partition() -> Result<(), ssh_stamp_hal::HalError> {
+            Ok(())
+        }
+
+        async fn get_ota_partition_size() -> Result<u32, ssh_stamp_hal::HalError> {
+            Ok(RamOta::SIZE)
+        }
+
+        async fn erase_ota_data(
+            &self,
+            offset: u32,
+            len: u32,
+        ) -> Result<(), ssh_stamp_hal::HalError> {
+            assert!(offset.is_multiple_of(Self::ERASE_SIZE) && len == Self::ERASE_SIZE);
+            self.0.lock().unwrap()[offset as usize..(offset + len) as usize].fill(0xff);
+            Ok(())
+        }
+
+        async fn write_ota_data(
+            &self,
+            offset: u32,
+            data: &[u8],
+        ) -> Result<(), ssh_stamp_hal::HalError> {
+            let flash = &mut self.0.lock().unwrap();
+            let target = &mut flash[offset as usize..offset as usize + data.len()];
+            assert!(
+                target.iter().all(|&b| b == 0xff),
+                "write at {offset} not erased"
+            );
+            target.copy_from_slice(data);
+            Ok(())
+        }
+
+        async fn finalize_ota_update(
+            &mut self,
+            _upload: &ssh_stamp_hal::OtaUpload,
+        ) -> Result<(), ssh_stamp_hal::HalError> {
+            Ok(())
+        }
+
+        fn reset_device(&self) -> ! {
+            unreachable!("the tests never reset")
+        }
+    }
+
+    /// Unsigned image of a `len` byte blob, and the blob
+    fn ota_image(len: usize) -> (Vec<u8>, Vec<u8>) {
+        use sha2::Digest;
+
+        let blob: Vec<u8> = (0..len).map(|i| u8::try_from(i % 251).unwrap()).collect();
+        let mut image = vec![0; MAX_TLV_SIZE as usize];
+        let header_len = OtaHeader::new(
+            OTA_TYPE_VALUE_SSH_STAMP,
+            &sha2::Sha256::digest(&blob),
+            None,
+            u32::try_from(len).unwrap(),
+        )
+        .serialize(&mut image);
+        image.truncate(header_len);
+        image.extend_from_slice(&blob);
+        (image, blob)
+    }
+
+    /// `len` bytes split into writes of `size` bytes
+    fn writes(len: usize, size: usize) -> Vec<core::ops::Range<usize>> {
+        (0..len)
+            .step_by(size)
+            .map(|start| start..len.min(start + size))
+            .collect()
+    }
+
+    /// Writes `image[range]` at its offset, as an SFTP write would
+    fn send(
+        processor: &mut crate::handler::UpdateProcessor<&RamOta>,
+        image: &[u8],
+        range: core::ops::Range<usize>,
+    ) -> Result<(), crate::handler::OtaError> {
+        let offset = u64::try_from(range.start).unwrap();
+        block_on(processor.process_data(offset, &image[range]))
+    }
+
+    #[test]
+    fn reorder_window_puts_writes_back_in_order() {
+        use crate::handler::{REORDER_SLOTS, UpdateProcessor};
+
+        let (image, blob) = ota_image(5000);
+        let mut ranges = writes(image.len(), 256);
+        // Each run fills the window before the write it waits for arrives
+        for run in ranges.chunks_mut(REORDER_SLOTS + 1) {
+            run.reverse();
+        }
+        let ota = RamOta::new();
+        let mut processor = UpdateProcessor::new(&ota, None);
+        for range in ranges {
+            send(&mut processor, &image, range).unwrap();
+        }
+        block_on(processor.finalize()).unwrap();
+        assert_eq!(ota.0.lock().unwrap()[..blob.len()], blob);
+    }
+
+    #[test]
+    fn reorder_window_skips_overlaps_and_repeats() {
+        use crate::handler::UpdateProcessor;
+
+        let (image, blob) = ota_image(5000);
+        let ota = RamOta::new();
+        let mut processor = UpdateProcessor::new(&ota, None);
+        for range in [
+            0..300,
+            // Overlaps the data processed already
+            200..600,
+            0..300,
+            // Held back twice, then once more overlapping itself
+            800..1000,
+            800..1000,
+            900..1100,
+            // Fills the gap, the held back writes follow
+            600..800,
+            1000..1100,
+        ] {
+            send(&mut processor, &image, range).unwrap();
+        }
+        for range in writes(image.len() - 1100, 400) {
+            send(&mut processor, &image, range.start + 1100..range.end + 1100).unwrap();
+        }
+        block_on(processor.finalize()).unwrap();
+        assert_eq!(ota.0.lock().unwrap()[..blob.len()], blob);
+    }
+
+    #[test]
+    fn reorder_window_fails_once_full() {
+        use crate::handler::{OtaError, REORDER_SLOTS, UpdateProcessor};
+
+        let (image, _) = ota_image(5000);
+        let ota = RamOta::new();
+        let mut processor = UpdateProcessor::new(&ota, None);
+        send(&mut processor, &image, 0..100).unwrap();
+        for i in 0..REORDER_SLOTS {
+            let start = 200 + i * 100;
+            send(&mut processor, &image, start..start + 100).unwrap();
+        }
+        let start = 200 + REORDER_SLOTS * 100;
+        assert_eq!(
+            send(&mut processor, &image, start..start + 100),
+            Err(OtaError::ReorderWindowExceeded)
+        );
+        // Filling the gap does not bring the upload back
+        send(&mut processor, &image, 100..200).unwrap();
+        assert_eq!(
+            block_on(processor.finalize()),
+            Err(OtaError::ReorderWindowExceeded)
+        );
+    }
+
+    #[test]
+    fn reorder_window_holds_only_short_writes() {
+        use crate::handler::{OtaError, REORDER_CHUNK_LEN, UpdateProcessor};
+
+        let (image, blob) = ota_image(5000);
+        // In order, writes of any length go straight through
+        let ota = RamOta::new();
+        let mut processor = UpdateProcessor::new(&ota, None);
+        for range in writes(image.len(), 4 * REORDER_CHUNK_LEN) {
+            send(&mut processor, &image, range).unwrap();
+        }
+        block_on(processor.finalize()).unwrap();
+        assert_eq!(ota.0.lock().unwrap()[..blob.len()], blob);
+
+        let ota = RamOta::new();
+        let mut processor = UpdateProcessor::new(&ota, None);
+        send(&mut processor, &image, 0..100).unwrap();
+        send(&mut processor, &image, 200..200 + REORDER_CHUNK_LEN).unwrap();
+        let start = 200 + REORDER_CHUNK_LEN;
+        assert_eq!(
+            send(&mut processor, &image, start..start + REORDER_CHUNK_LEN + 1),
+            Err(OtaError::ReorderWindowExceeded)
+        );
+        assert_eq!(
+            block_on(processor.finalize()),
+            Err(OtaError::ReorderWindowExceeded)
+        );
+    }
+
     // TODO: Test more error cases, such as incomplete TLVs
 }
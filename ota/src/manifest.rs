@@ -0,0 +1,57 @@
+// SPDX-FileCopyrightText: 2026 Julio Beltran Ortega <jubeormk1@gmail.com>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Build manifests
+//!
+//! `packer --manifest` writes a `.manifest` file next to the `.ota` file: a [`HEADER`] line,
+//! then one `key: value` line per fact about the build:
+//!
+//! - `app sha256`, `app size`: the packed app binary.
+//! - `target`: chip ID and minimum revision from the app image header, if it has one.
+//! - `bootloader sha256`, `partition table sha256`: the files given to the packer, if any.
+//! - `firmware`, `git`, `built`, `features`: copied from the build record the firmware
+//!   carries, see [`BUILD_RECORD_MARKER`].
+//!
+//! Two builds of the same source and settings give the same manifest, and a device checks
+//! the entries it can vouch for with the `manifest check` admin command.
+
+/// First line of a manifest, carrying the manifest format version
+pub const HEADER: &str = "ssh-stamp manifest: 1";
+
+/// Start of the build record in the firmware image: `key: value` lines up to a NUL byte
+pub const BUILD_RECORD_MARKER: &str = "ssh-stamp build record\n";
+
+/// Whether `record` starts with [`BUILD_RECORD_MARKER`] and ends with a NUL byte, so
+/// [`build_record`] finds it in an image
+pub const fn is_build_record(record: &str) -> bool {
+    let record = record.as_bytes();
+    let marker = BUILD_RECORD_MARKER.as_bytes();
+    if record.len() <= marker.len() || record[record.len() - 1] != 0 {
+        return false;
+    }
+    let mut i = 0;
+    while i < marker.len() {
+        if record[i] != marker[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// The `key: value` lines of `text`, skipping blank lines and others without a `: `
+pub fn entries(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.lines()
+        .filter_map(|line| line.trim_end_matches('\r').split_once(": "))
+}
+
+/// The lines of the build record in a firmware `image`, without the marker
+///
+/// `None` if `image` holds no build record, or a broken one.
+pub fn build_record(image: &[u8]) -> Option<&str> {
+    let marker = BUILD_RECORD_MARKER.as_bytes();
+    let start = image.windows(marker.len()).position(|w| w == marker)? + marker.len();
+    let len = image[start..].iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&image[start..start + len]).ok()
+}
@@ -0,0 +1,631 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The scratch area
+//!
+//! A small flash partition (64 KiB in the shipped partition tables) where operators keep
+//! their own files: site notes, target configs, a recovery image for the target. The SFTP
+//! server serves it as the [`DIR`] directory next to the OTA upload target, and the
+//! `scratch list` and `scratch delete` admin commands show and remove what it holds.
+//!
+//! Each erase sector holds a [`HEADER_LEN`] byte header, then part of one file. A new file
+//! goes to free sectors and only takes the place of an older one by the same name once it
+//! is complete, so an interrupted upload leaves the old one as it was. Files are replaced
+//! whole: there is no appending and no writing in place.
+//!
+//! Wear is spread over the partition: each header counts the erases of its sector, a file
+//! always gets the least erased free sectors, and sectors are only erased right before they
+//! are reused. Deleting a file just marks its sectors.
+//!
+//! The partition is the quota, shared by all files: an upload fails once no free sector is
+//! left for it.
+
+use core::fmt;
+
+use portable_atomic::{AtomicBool, Ordering};
+use ssh_stamp_hal::{HalError, SCRATCH_ALIGN, ScratchActions};
+
+/// Directory the scratch files are served in over SFTP
+pub const DIR: &str = "/scratch";
+
+/// Longest file name
+pub const NAME_LEN: usize = 32;
+
+/// Sectors used at most; a larger partition is only used that far
+pub const MAX_SECTORS: usize = 16;
+
+/// Length of the header at the start of each sector, little endian:
+///
+/// | offset | field                                                               |
+/// |--------|---------------------------------------------------------------------|
+/// | 0      | `SSCR`, written with the erase count when the sector is erased      |
+/// | 4      | erases of this sector                                               |
+/// | 8      | `0xffffffff` while the file is current, cleared when it is not      |
+/// | 12     | version of the file, `0xffffffff` until the file is complete        |
+/// | 16     | length of the file                                                  |
+/// | 20     | index of this part of the file, `u16`, then 2 reserved bytes        |
+/// | 24     | name, NUL padded                                                    |
+pub const HEADER_LEN: u32 = 64;
+
+const MAGIC: [u8; 4] = *b"SSCR";
+const ERASES_AT: u32 = 4;
+const LIVE_AT: u32 = 8;
+const SEQ_AT: u32 = 12;
+const LEN_AT: u32 = 16;
+const INDEX_AT: u32 = 20;
+const NAME_AT: u32 = 24;
+/// Fields written when a file is complete
+const COMMIT_LEN: usize = (NAME_AT - SEQ_AT) as usize + NAME_LEN;
+
+/// Bytes returned by one [`Scratch::read`] at most
+pub const READ_CHUNK: usize = 512;
+
+/// Set while an [`Upload`] exists
+static UPLOADING: AtomicBool = AtomicBool::new(false);
+
+/// Why a scratch operation failed
+#[derive(Debug)]
+pub enum ScratchError {
+    /// No free sector is left.
+    Full,
+    /// Another upload is in progress.
+    Busy,
+    /// Reading, erasing or writing the partition failed.
+    Flash(HalError),
+}
+
+impl From<HalError> for ScratchError {
+    fn from(e: HalError) -> Self {
+        ScratchError::Flash(e)
+    }
+}
+
+impl fmt::Display for ScratchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScratchError::Full => write!(f, "scratch area full"),
+            ScratchError::Busy => write!(f, "another upload to the scratch area is in progress"),
+            ScratchError::Flash(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// A file name: printable ASCII but `/`, not starting with a `.`, at most [`NAME_LEN`]
+/// bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Name {
+    bytes: [u8; NAME_LEN],
+    len: usize,
+}
+
+impl Name {
+    /// `name` if it is a valid file name
+    #[must_use]
+    pub fn new(name: &str) -> Option<Self> {
+        let valid = !name.is_empty()
+            && name.len() <= NAME_LEN
+            && !name.starts_with('.')
+            && name.bytes().all(|b| b.is_ascii_graphic() && b != b'/');
+        if !valid {
+            return None;
+        }
+        let mut bytes = [0; NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Name {
+            bytes,
+            len: name.len(),
+        })
+    }
+
+    /// The name of the file at SFTP `path`, with or without the leading `/`
+    #[must_use]
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        Self::new(path.strip_prefix(&DIR[1..])?.strip_prefix('/')?)
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a checked `&str`.
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    fn from_header(field: &[u8]) -> Option<Self> {
+        let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+        Self::new(core::str::from_utf8(&field[..len]).ok()?)
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Content {
+    /// Erased, never used, or left by an upload that was not completed.
+    Free,
+    /// Taken by the upload in progress.
+    Upload,
+    /// Held a file since deleted or replaced.
+    Dead,
+    /// Part `index` of version `seq` of file `name`, `len` bytes long.
+    File {
+        name: Name,
+        seq: u32,
+        len: u32,
+        index: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sector {
+    erases: u32,
+    content: Content,
+}
+
+impl Sector {
+    const UNUSED: Sector = Sector {
+        erases: 0,
+        content: Content::Free,
+    };
+
+    fn decode(header: &[u8; HEADER_LEN as usize]) -> Self {
+        if header[..MAGIC.len()] != MAGIC {
+            return Sector::UNUSED;
+        }
+        let word = |at: u32| {
+            let at = at as usize;
+            u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+        };
+        let seq = word(SEQ_AT);
+        let index = word(INDEX_AT).to_le_bytes();
+        let name_at = NAME_AT as usize;
+        let content = if word(LIVE_AT) != u32::MAX {
+            Content::Dead
+        } else if seq == u32::MAX {
+            Content::Free
+        } else if let Some(name) = Name::from_header(&header[name_at..name_at + NAME_LEN]) {
+            Content::File {
+                name,
+                seq,
+                len: word(LEN_AT),
+                index: u16::from_le_bytes([index[0], index[1]]),
+            }
+        } else {
+            Content::Dead
+        };
+        Sector {
+            erases: word(ERASES_AT),
+            content,
+        }
+    }
+
+    fn is_file(&self, name: &Name, seq: u32) -> bool {
+        matches!(self.content, Content::File { name: n, seq: s, .. } if n == *name && s == seq)
+    }
+}
+
+/// The first two header fields, written when a sector is erased
+fn erased_header(erases: u32) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&erases.to_le_bytes());
+    header
+}
+
+/// The header fields from [`SEQ_AT`] on, written when a file is complete
+fn commit_fields(name: &Name, seq: u32, len: u32, index: u16) -> [u8; COMMIT_LEN] {
+    let mut fields = [0; COMMIT_LEN];
+    fields[..4].copy_from_slice(&seq.to_le_bytes());
+    fields[4..8].copy_from_slice(&len.to_le_bytes());
+    fields[8..10].copy_from_slice(&index.to_le_bytes());
+    fields[10..12].copy_from_slice(&[0xff, 0xff]);
+    fields[12..].copy_from_slice(&name.bytes);
+    fields
+}
+
+/// Sectors a file of `len` bytes takes, at least one
+fn parts(len: u32, data_len: u32) -> usize {
+    len.div_ceil(data_len).max(1) as usize
+}
+
+/// The free sector erased the least often, if any
+fn least_worn(sectors: &[Sector]) -> Option<usize> {
+    sectors
+        .iter()
+        .enumerate()
+        .filter(|(_, sector)| matches!(sector.content, Content::Free | Content::Dead))
+        .min_by_key(|(_, sector)| sector.erases)
+        .map(|(i, _)| i)
+}
+
+/// Marks every file sector not part of the newest complete version of its file dead: left
+/// from a replaced version not marked yet, or by a reset while a file was being completed
+fn settle(sectors: &mut [Sector], data_len: u32) {
+    let complete = |sectors: &[Sector], name: &Name, seq: u32, len: u32| {
+        sectors.iter().filter(|s| s.is_file(name, seq)).count() == parts(len, data_len)
+    };
+    let mut keep = [false; MAX_SECTORS];
+    for (i, sector) in sectors.iter().enumerate() {
+        if let Content::File { name, seq, len, .. } = sector.content {
+            keep[i] = complete(sectors, &name, seq, len)
+                && !sectors.iter().any(|other| match other.content {
+                    Content::File {
+                        name: n,
+                        seq: s,
+                        len: l,
+                        ..
+                    } => n == name && s > seq && complete(sectors, &n, s, l),
+                    _ => false,
+                });
+        }
+    }
+    for (sector, keep) in sectors.iter_mut().zip(keep) {
+        if matches!(sector.content, Content::File { .. }) && !keep {
+            sector.content = Content::Dead;
+        }
+    }
+}
+
+/// A file found by [`Scratch::find`]
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    pub len: u32,
+    /// Sector of each part
+    sectors: [usize; MAX_SECTORS],
+}
+
+/// A file being written, see [`Scratch::begin`]. Dropping it without
+/// [`Scratch::commit`] abandons it, its sectors are free again once the headers are
+/// read again.
+#[derive(Debug)]
+pub struct Upload {
+    name: Name,
+    /// Sector of each part written so far
+    sectors: [usize; MAX_SECTORS],
+    parts: usize,
+    /// Bytes received
+    len: u32,
+    /// The last `len % SCRATCH_ALIGN` bytes received, not written yet
+    tail: [u8; SCRATCH_ALIGN as usize],
+}
+
+impl Upload {
+    #[must_use]
+    pub fn name(&self) -> &Name {
+        &self.name
+    }
+
+    /// Bytes received so far
+    #[must_use]
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        UPLOADING.store(false, Ordering::Release);
+    }
+}
+
+/// The scratch partition as last read by [`Scratch::load`]
+pub struct Scratch<S: ScratchActions> {
+    flash: S,
+    sectors: [Sector; MAX_SECTORS],
+    count: usize,
+}
+
+impl<S: ScratchActions> Scratch<S> {
+    /// Reads the sector headers.
+    ///
+    /// # Errors
+    /// Returns an error if the partition cannot be found or read.
+    pub async fn load(flash: S) -> Result<Self, HalError> {
+        let size = flash.scratch_size().await?;
+        let count = ((size / S::ERASE_SIZE) as usize).min(MAX_SECTORS);
+        let mut scratch = Scratch {
+            flash,
+            sectors: [Sector::UNUSED; MAX_SECTORS],
+            count,
+        };
+        scratch.refresh().await?;
+        Ok(scratch)
+    }
+
+    /// Reads the sector headers again, for changes made since, e.g. by another SFTP
+    /// session. Not while an [`Upload`] begun here is in progress: its sectors would be
+    /// taken for free.
+    ///
+    /// # Errors
+    /// Returns an error if the partition cannot be read.
+    pub async fn refresh(&mut self) -> Result<(), HalError> {
+        let mut header = [0; HEADER_LEN as usize];
+        for i in 0..self.count {
+            self.flash
+                .read_scratch(Self::offset(i), &mut header)
+                .await?;
+            self.sectors[i] = Sector::decode(&header);
+        }
+        settle(&mut self.sectors[..self.count], Self::data_len());
+        Ok(())
+    }
+
+    /// File bytes a sector holds
+    fn data_len() -> u32 {
+        S::ERASE_SIZE - HEADER_LEN
+    }
+
+    fn offset(sector: usize) -> u32 {
+        u32::try_from(sector)
+            .unwrap_or(u32::MAX)
+            .saturating_mul(S::ERASE_SIZE)
+    }
+
+    fn sectors(&self) -> &[Sector] {
+        &self.sectors[..self.count]
+    }
+
+    /// Bytes all files together can take
+    pub fn capacity(&self) -> u32 {
+        u32::try_from(self.count).unwrap_or(0) * Self::data_len()
+    }
+
+    /// Bytes left for new files
+    pub fn free(&self) -> u32 {
+        let free = self
+            .sectors()
+            .iter()
+            .filter(|sector| matches!(sector.content, Content::Free | Content::Dead))
+            .count();
+        u32::try_from(free).unwrap_or(0) * Self::data_len()
+    }
+
+    /// Erases of the most erased sector
+    pub fn most_erases(&self) -> u32 {
+        self.sectors()
+            .iter()
+            .map(|sector| sector.erases)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The files, as name and length
+    pub fn files(&self) -> impl Iterator<Item = (&Name, u32)> {
+        self.sectors()
+            .iter()
+            .filter_map(|sector| match &sector.content {
+                Content::File {
+                    name,
+                    len,
+                    index: 0,
+                    ..
+                } => Some((name, *len)),
+                _ => None,
+            })
+    }
+
+    /// The file called `name`, if there is one
+    pub fn find(&self, name: &Name) -> Option<File> {
+        let mut file = None;
+        for (i, sector) in self.sectors().iter().enumerate() {
+            if let Content::File {
+                name: n,
+                len,
+                index,
+                ..
+            } = sector.content
+                && n == *name
+            {
+                let found = file.get_or_insert(File {
+                    len,
+                    sectors: [0; MAX_SECTORS],
+                });
+                if let Some(part) = found.sectors.get_mut(usize::from(index)) {
+                    *part = i;
+                }
+            }
+        }
+        file
+    }
+
+    /// Reads `file` from `offset` into `buf`, up to the end of the sector holding
+    /// `offset`, and returns what was read: nothing at the end of the file.
+    ///
+    /// # Errors
+    /// Returns an error if the partition cannot be read.
+    pub async fn read<'b>(
+        &self,
+        file: &File,
+        offset: u32,
+        buf: &'b mut [u8; READ_CHUNK],
+    ) -> Result<&'b [u8], HalError> {
+        if offset >= file.len {
+            return Ok(&[]);
+        }
+        let data_len = Self::data_len();
+        let Some(&sector) = file.sectors.get((offset / data_len) as usize) else {
+            return Ok(&[]);
+        };
+        let within = offset % data_len;
+        let start = within - within % SCRATCH_ALIGN;
+        let skip = (within - start) as usize;
+        let len = ((data_len - within).min(file.len - offset) as usize).min(READ_CHUNK - skip);
+        let aligned = (skip + len).next_multiple_of(SCRATCH_ALIGN as usize);
+        self.flash
+            .read_scratch(
+                Self::offset(sector) + HEADER_LEN + start,
+                &mut buf[..aligned],
+            )
+            .await?;
+        Ok(&buf[skip..skip + len])
+    }
+
+    /// Starts writing the file called `name`, which replaces the one by that name, if
+    /// any, once [`Scratch::commit`]ted. Only one upload can be in progress at a time.
+    ///
+    /// # Errors
+    /// Returns [`ScratchError::Busy`] while another upload is in progress.
+    pub fn begin(&self, name: Name) -> Result<Upload, ScratchError> {
+        if UPLOADING.swap(true, Ordering::Acquire) {
+            return Err(ScratchError::Busy);
+        }
+        Ok(Upload {
+            name,
+            sectors: [0; MAX_SECTORS],
+            parts: 0,
+            len: 0,
+            tail: [0; SCRATCH_ALIGN as usize],
+        })
+    }
+
+    /// Adds `data` to the end of `upload`.
+    ///
+    /// # Errors
+    /// Returns [`ScratchError::Full`] if no free sector is left for it.
+    pub async fn append(
+        &mut self,
+        upload: &mut Upload,
+        mut data: &[u8],
+    ) -> Result<(), ScratchError> {
+        let align = SCRATCH_ALIGN as usize;
+        while !data.is_empty() {
+            let pending = (upload.len % SCRATCH_ALIGN) as usize;
+            if pending > 0 || data.len() < align {
+                let take = data.len().min(align - pending);
+                upload.tail[pending..pending + take].copy_from_slice(&data[..take]);
+                data = &data[take..];
+                upload.len += u32::try_from(take).unwrap_or(0);
+                if pending + take == align {
+                    let (word, at) = (upload.tail, upload.len - SCRATCH_ALIGN);
+                    self.put(upload, at, &word).await?;
+                }
+                continue;
+            }
+            let room = Self::data_len() - upload.len % Self::data_len();
+            let (at, len) = (
+                upload.len,
+                (data.len() - data.len() % align).min(room as usize),
+            );
+            self.put(upload, at, &data[..len]).await?;
+            upload.len += u32::try_from(len).unwrap_or(0);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Writes the aligned `chunk` of `upload` at `at`, taking a sector for it if it is
+    /// the first in one.
+    async fn put(
+        &mut self,
+        upload: &mut Upload,
+        at: u32,
+        chunk: &[u8],
+    ) -> Result<(), ScratchError> {
+        let part = (at / Self::data_len()) as usize;
+        if part == upload.parts {
+            self.take_sector(upload).await?;
+        }
+        let offset = Self::offset(upload.sectors[part]) + HEADER_LEN + at % Self::data_len();
+        self.flash.write_scratch(offset, chunk).await?;
+        Ok(())
+    }
+
+    /// Erases the least worn free sector for the next part of `upload`.
+    async fn take_sector(&mut self, upload: &mut Upload) -> Result<(), ScratchError> {
+        let i = least_worn(self.sectors())
+            .filter(|_| upload.parts < MAX_SECTORS)
+            .ok_or(ScratchError::Full)?;
+        let erases = self.sectors[i].erases.saturating_add(1);
+        // Not offered again should erasing fail.
+        self.sectors[i] = Sector {
+            erases,
+            content: Content::Upload,
+        };
+        let offset = Self::offset(i);
+        self.flash.erase_scratch(offset, S::ERASE_SIZE).await?;
+        self.flash
+            .write_scratch(offset, &erased_header(erases))
+            .await?;
+        upload.sectors[upload.parts] = i;
+        upload.parts += 1;
+        Ok(())
+    }
+
+    /// Completes `upload`, which then replaces the file by its name, if any.
+    ///
+    /// # Errors
+    /// Returns an error if the partition cannot be written, or no sector is left for
+    /// an empty file.
+    pub async fn commit(&mut self, mut upload: Upload) -> Result<(), ScratchError> {
+        let pending = (upload.len % SCRATCH_ALIGN) as usize;
+        if pending > 0 {
+            let mut word = [0xff; SCRATCH_ALIGN as usize];
+            word[..pending].copy_from_slice(&upload.tail[..pending]);
+            let at = upload.len - upload.len % SCRATCH_ALIGN;
+            self.put(&mut upload, at, &word).await?;
+        }
+        if upload.parts == 0 {
+            self.take_sector(&mut upload).await?;
+        }
+        let seq = self
+            .sectors()
+            .iter()
+            .filter_map(|sector| match sector.content {
+                Content::File { seq, .. } => Some(seq.saturating_add(1)),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+            .min(u32::MAX - 1);
+        for (part, &i) in upload.sectors[..upload.parts].iter().enumerate() {
+            let index = u16::try_from(part).unwrap_or(u16::MAX);
+            let fields = commit_fields(&upload.name, seq, upload.len, index);
+            self.flash
+                .write_scratch(Self::offset(i) + SEQ_AT, &fields)
+                .await?;
+            self.sectors[i].content = Content::File {
+                name: upload.name,
+                seq,
+                len: upload.len,
+                index,
+            };
+        }
+        self.retire(&upload.name, Some(seq)).await?;
+        Ok(())
+    }
+
+    /// Deletes the file called `name`; false if there is none.
+    ///
+    /// # Errors
+    /// Returns an error if the partition cannot be written.
+    pub async fn delete(&mut self, name: &Name) -> Result<bool, HalError> {
+        self.retire(name, None).await
+    }
+
+    /// Marks the sectors of file `name` dead, but those of version `keep`.
+    async fn retire(&mut self, name: &Name, keep: Option<u32>) -> Result<bool, HalError> {
+        let mut found = false;
+        for i in 0..self.count {
+            if let Content::File { name: n, seq, .. } = self.sectors[i].content
+                && n == *name
+                && Some(seq) != keep
+            {
+                self.flash
+                    .write_scratch(Self::offset(i) + LIVE_AT, &[0; 4])
+                    .await?;
+                self.sectors[i].content = Content::Dead;
+                found = true;
+            }
+        }
+        Ok(found)
+    }
+}
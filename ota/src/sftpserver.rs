@@ -5,16 +5,22 @@
 
 use core::hash::Hasher;
 
+use crate::InfoFile;
 use crate::handler::{OtaError, UpdateProcessor};
-use ssh_stamp_hal::OtaActions;
+use crate::scratch::{self, Name, Scratch};
+use ssh_stamp_hal::{OtaActions, ScratchActions};
 
+use ed25519_dalek::VerifyingKey;
 use sunset::sshwire::{BinString, WireError};
 use sunset_async::ChanInOut;
 use sunset_sftp::{
     SftpHandler,
     handles::OpaqueFileHandle,
-    protocol::{FileHandle, Filename, NameEntry, PFlags, StatusCode},
-    server::{DirReadHeaderReply, DirReadReplyFinished, MAX_REQUEST_LEN, SftpServer},
+    protocol::{Attrs, FileHandle, Filename, NameEntry, PFlags, StatusCode},
+    server::{
+        DirReadHeaderReply, DirReadReplyFinished, MAX_REQUEST_LEN, ReadHeaderReply,
+        ReadReplyFinished, SftpServer,
+    },
 };
 
 use log::{debug, error, info, warn};
@@ -22,21 +28,41 @@ use rustc_hash::FxHasher;
 
 /// Runs the OTA SFTP server
 ///
+/// With a `firmware_key`, only images signed with it are accepted, see
+/// [`crate::tlv::Tlv::Signature`]. `files` are served read-only, see
+/// [`InfoFile`], and the files in the `scratch` partition under
+/// [`scratch::DIR`]. `request_buffer` holds incoming SFTP requests; it is
+/// passed in so it need not live in the caller's future. Replies are built in
+/// a `REPLY_LEN` byte buffer, which does.
+///
 /// # Errors
 /// Returns an error if the SFTP server loop encounters an error
-pub async fn run_ota_server<W: OtaActions>(
+pub async fn run_ota_server<W: OtaActions, S: ScratchActions, const REPLY_LEN: usize>(
     stdio: ChanInOut<'_>,
     ota_writer: W,
+    firmware_key: Option<VerifyingKey>,
+    files: &[InfoFile<'_>],
+    scratch: S,
+    request_buffer: &mut [u8; MAX_REQUEST_LEN],
 ) -> Result<(), sunset::Error> {
-    let mut request_buffer = [0u8; MAX_REQUEST_LEN];
-
-    let mut file_server = SftpOtaServer::new(ota_writer);
+    let scratch = match Scratch::load(scratch).await {
+        Ok(scratch) => Some(scratch),
+        Err(e) => {
+            warn!("No scratch area: {e}");
+            None
+        }
+    };
+    let mut file_server = SftpOtaServer::new(ota_writer, firmware_key, files, scratch);
 
     let (chan_in, chan_out) = stdio.split();
 
-    match SftpHandler::<OtaOpaqueFileHandle, SftpOtaServer<OtaOpaqueFileHandle, W>, 512>::new(
+    match SftpHandler::<
+        OtaOpaqueFileHandle,
+        SftpOtaServer<'_, OtaOpaqueFileHandle, W, S>,
+        REPLY_LEN,
+    >::new(
         &mut file_server,
-        &mut request_buffer,
+        request_buffer,
     )
     .process_loop(chan_in, chan_out)
     .await
@@ -87,6 +113,37 @@ impl OpaqueFileHandle for OtaOpaqueFileHandle {
     }
 }
 
+/// `st_mode` file type bits, as in POSIX `<sys/stat.h>`
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+
+/// `path` without its leading `/`, if any.
+fn relative(path: &str) -> &str {
+    path.strip_prefix('/').unwrap_or(path)
+}
+
+/// True for [`scratch::DIR`] and any path in it
+fn in_scratch(path: &str) -> bool {
+    relative(path)
+        .strip_prefix(relative(scratch::DIR))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Sends `data` as the reply to a read
+async fn send_data<const N: usize>(
+    reply: ReadHeaderReply<'_, N>,
+    data: &[u8],
+) -> sunset_sftp::server::SftpOpResult<ReadReplyFinished> {
+    let len = u32::try_from(data.len()).map_err(|_| StatusCode::SSH_FX_FAILURE)?;
+    reply
+        .send_header(len)
+        .await
+        .map_err(|_| StatusCode::SSH_FX_FAILURE)?
+        .send_data(data)
+        .await
+        .map_err(|_| StatusCode::SSH_FX_FAILURE)
+}
+
 /// Derive the file handle from a path seed.
 trait InitFromSeed: Sized {
     type Err;
@@ -107,30 +164,247 @@ impl InitFromSeed for OtaOpaqueFileHandle {
     }
 }
 
+/// A file open in the scratch area
+enum ScratchOpen {
+    Read(scratch::File),
+    Write(scratch::Upload),
+    /// A write failed; closing discards the upload.
+    Failed,
+}
+
 /// SFTP server implementation for OTA updates
 ///
 /// This struct implements the `SftpServer` trait for handling OTA updates over SFTP
-/// For now, all methods log an error and return unsupported operation as this is a placeholder
-struct SftpOtaServer<T, W: OtaActions> {
+/// Attribute changes (`SETSTAT`/`FSETSTAT`) are accepted and ignored, see
+/// [`SftpOtaServer::check_setstat`]
+struct SftpOtaServer<'v, T, W: OtaActions, S: ScratchActions> {
     // Add fields as necessary for OTA server state
     file_handle: Option<T>,
+    /// Open handle on one of `files`, by index, independent of the OTA file
+    info_handle: Option<(T, usize)>,
+    files: &'v [InfoFile<'v>],
+    /// `None` if the partition table has no scratch partition
+    scratch: Option<Scratch<S>>,
+    /// Open handle on a scratch file, independent of the others
+    scratch_handle: Option<(T, ScratchOpen)>,
     write_permission: bool,
+    /// End of the furthest write into the current file
+    bytes_received: u64,
     processor: UpdateProcessor<W>,
 }
 
-impl<T, W: OtaActions> SftpOtaServer<T, W> {
-    pub fn new(ota_writer: W) -> Self {
+impl<'v, T: PartialEq, W: OtaActions, S: ScratchActions> SftpOtaServer<'v, T, W, S> {
+    pub fn new(
+        ota_writer: W,
+        firmware_key: Option<VerifyingKey>,
+        files: &'v [InfoFile<'v>],
+        scratch: Option<Scratch<S>>,
+    ) -> Self {
         Self {
             // Initialize fields as necessary
             file_handle: None,
+            info_handle: None,
+            files,
+            scratch,
+            scratch_handle: None,
             write_permission: false,
-            processor: UpdateProcessor::new(ota_writer),
+            bytes_received: 0,
+            processor: UpdateProcessor::new(ota_writer, firmware_key),
+        }
+    }
+
+    /// The open scratch file, if `handle` is on it
+    fn scratch_open(&mut self, handle: &T) -> Option<&mut ScratchOpen> {
+        self.scratch_handle
+            .as_mut()
+            .filter(|(open, _)| open == handle)
+            .map(|(_, open)| open)
+    }
+
+    /// Bytes received into the scratch file being written, if `scratch`, or else into
+    /// the OTA file
+    fn received(&self, scratch: bool) -> u64 {
+        match &self.scratch_handle {
+            Some((_, ScratchOpen::Write(upload))) if scratch => u64::from(upload.len()),
+            _ if scratch => 0,
+            _ => self.bytes_received,
+        }
+    }
+
+    /// Index of the read-only file at `path`, with or without the leading `/`
+    fn info_file(&self, path: &str) -> Option<usize> {
+        self.files
+            .iter()
+            .position(|file| relative(file.path) == relative(path))
+    }
+
+    /// True for the root, the scratch directory and the directories holding read-only
+    /// files
+    fn is_dir(&self, path: &str) -> bool {
+        let dir = relative(path).trim_end_matches('/');
+        matches!(dir, "" | ".")
+            || (self.scratch.is_some() && dir == relative(scratch::DIR))
+            || self.files.iter().any(|file| {
+                relative(file.path)
+                    .strip_prefix(dir)
+                    .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    /// Decides whether an attribute change on the virtual OTA file, or a scratch file,
+    /// can be ignored
+    ///
+    /// Many clients set permissions and times after an upload and treat a refusal as
+    /// fatal. None of that means anything for a firmware image, so it is accepted and
+    /// dropped. A size change would alter the image and is refused, unless it matches
+    /// what was already `received` (e.g. a truncate to 0 before the first write).
+    fn check_setstat(&self, attrs: &Attrs, received: u64) -> sunset_sftp::server::SftpOpResult<()> {
+        match attrs.size {
+            Some(size) if size != received => {
+                warn!("SftpServer SetStat refused: size {size} != {received} bytes received");
+                Err(StatusCode::SSH_FX_OP_UNSUPPORTED)
+            }
+            _ => {
+                debug!("SftpServer SetStat ignored: attrs = {attrs:?}");
+                Ok(())
+            }
         }
     }
 }
 
-impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOtaServer<T, W> {
+impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions, S: ScratchActions>
+    SftpOtaServer<'_, T, W, S>
+{
+    /// Opens the scratch file at `path`: any write, create or truncate flag starts
+    /// replacing it, one file at a time
+    async fn open_scratch(
+        &mut self,
+        path: &str,
+        mode: &PFlags,
+    ) -> sunset_sftp::server::SftpOpResult<T> {
+        let Some(scratch) = self.scratch.as_mut() else {
+            warn!("SftpServer Open operation failed: no scratch partition for {path:?}");
+            return Err(StatusCode::SSH_FX_NO_SUCH_FILE);
+        };
+        let Some(name) = Name::from_path(path) else {
+            warn!("SftpServer Open operation failed: {path:?} is not a scratch file name");
+            return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
+        };
+        if self.scratch_handle.is_some() {
+            warn!(
+                "SftpServer Open operation failed: a scratch file is already open, path = {path:?}"
+            );
+            return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
+        }
+        let num_mode = u32::from(mode);
+        if num_mode & u32::from(&PFlags::SSH_FXF_APPEND) > 0 {
+            warn!(
+                "SftpServer Open operation failed: scratch files are replaced whole, path = {path:?}"
+            );
+            return Err(StatusCode::SSH_FX_OP_UNSUPPORTED);
+        }
+        scratch.refresh().await.map_err(|e| {
+            error!("SftpServer Open failed to read the scratch area: {e}");
+            StatusCode::SSH_FX_FAILURE
+        })?;
+        let write = num_mode
+            & (u32::from(&PFlags::SSH_FXF_WRITE)
+                | u32::from(&PFlags::SSH_FXF_CREAT)
+                | u32::from(&PFlags::SSH_FXF_TRUNC))
+            > 0;
+        let open = if write {
+            ScratchOpen::Write(scratch.begin(name).map_err(|e| {
+                warn!("SftpServer Open operation failed: {e}");
+                StatusCode::SSH_FX_FAILURE
+            })?)
+        } else {
+            ScratchOpen::Read(scratch.find(&name).ok_or(StatusCode::SSH_FX_NO_SUCH_FILE)?)
+        };
+        let handle = T::init_from_seed(path).map_err(|_| StatusCode::SSH_FX_FAILURE)?;
+        info!("SftpServer Open: scratch file {path:?}, write = {write}, handle = {handle:?}");
+        self.scratch_handle = Some((handle.clone(), open));
+        Ok(handle)
+    }
+
+    /// Stores the scratch file written through the closed handle, if any
+    async fn close_scratch(&mut self, open: ScratchOpen) -> sunset_sftp::server::SftpOpResult<()> {
+        match open {
+            ScratchOpen::Read(_) => Ok(()),
+            ScratchOpen::Failed => Err(StatusCode::SSH_FX_FAILURE),
+            ScratchOpen::Write(upload) => {
+                let Some(scratch) = self.scratch.as_mut() else {
+                    return Err(StatusCode::SSH_FX_FAILURE);
+                };
+                let (name, len) = (*upload.name(), upload.len());
+                match scratch.commit(upload).await {
+                    Ok(()) => {
+                        info!("Scratch file {name} stored, {len} bytes");
+                        Ok(())
+                    }
+                    Err(e) => {
+                        error!("Scratch file {name} not stored: {e}");
+                        Err(StatusCode::SSH_FX_FAILURE)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds `buf` to the scratch file being written through `handle`
+    async fn write_scratch(
+        &mut self,
+        handle: &T,
+        offset: u64,
+        buf: &[u8],
+    ) -> sunset_sftp::server::SftpOpResult<()> {
+        let (Some(scratch), Some((_, open))) =
+            (self.scratch.as_mut(), self.scratch_handle.as_mut())
+        else {
+            return Err(StatusCode::SSH_FX_FAILURE);
+        };
+        let ScratchOpen::Write(upload) = open else {
+            warn!(
+                "SftpServer Write operation denied: scratch file not open for writing, handle = {handle:?}"
+            );
+            return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
+        };
+        if offset != u64::from(upload.len()) {
+            warn!(
+                "SftpServer Write operation refused: scratch files are written in order, offset = {offset}, expected {}",
+                upload.len()
+            );
+            *open = ScratchOpen::Failed;
+            return Err(StatusCode::SSH_FX_OP_UNSUPPORTED);
+        }
+        if let Err(e) = scratch.append(upload, buf).await {
+            error!(
+                "SftpServer Write operation failed for scratch file {}: {e}",
+                upload.name()
+            );
+            *open = ScratchOpen::Failed;
+            return Err(StatusCode::SSH_FX_FAILURE);
+        }
+        Ok(())
+    }
+}
+
+impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions, S: ScratchActions> SftpServer<T>
+    for SftpOtaServer<'_, T, W, S>
+{
     async fn open(&'_ mut self, path: &str, mode: &PFlags) -> sunset_sftp::server::SftpOpResult<T> {
+        if in_scratch(path) {
+            return self.open_scratch(path, mode).await;
+        }
+        if let Some(index) = self.info_file(path) {
+            if u32::from(mode) & u32::from(&PFlags::SSH_FXF_READ) == u32::from(mode) {
+                let handle = T::init_from_seed(path).map_err(|_| StatusCode::SSH_FX_FAILURE)?;
+                info!("SftpServer Open: read-only file {path:?}, handle = {handle:?}");
+                self.info_handle = Some((handle.clone(), index));
+                return Ok(handle);
+            }
+            warn!("SftpServer Open operation failed: {path:?} is read-only");
+            return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
+        }
         if self.file_handle.is_none() {
             let num_mode = u32::from(mode);
 
@@ -157,6 +431,20 @@ impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOt
         // TODO: At this point I need to reset the target if all is ok or reset the processor if not so we are
         // either loading a new firmware or ready to receive a correct one.
         info!("Close called for handle {handle:?}");
+        if self
+            .info_handle
+            .as_ref()
+            .is_some_and(|(open, _)| open == handle)
+        {
+            self.info_handle = None;
+            return Ok(());
+        }
+        if self.scratch_open(handle).is_some() {
+            let Some((_, open)) = self.scratch_handle.take() else {
+                return Err(StatusCode::SSH_FX_FAILURE);
+            };
+            return self.close_scratch(open).await;
+        }
         if let Some(current_handle) = &self.file_handle {
             if current_handle == handle {
                 let ret_val = match self.processor.finalize().await {
@@ -173,6 +461,7 @@ impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOt
                 info!("SftpServer Close operation for OTA completed: handle = {handle:?}");
                 self.file_handle = None;
                 self.write_permission = false;
+                self.bytes_received = 0;
 
                 ret_val
             } else {
@@ -185,12 +474,68 @@ impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOt
         }
     }
 
+    /// Only the read-only and scratch files can be read; the OTA file is write-only
+    async fn read<const N: usize>(
+        &mut self,
+        opaque_file_handle: &T,
+        offset: u64,
+        len: u32,
+        reply: ReadHeaderReply<'_, N>,
+    ) -> sunset_sftp::server::SftpOpResult<ReadReplyFinished> {
+        if let Some(open) = self.scratch_open(opaque_file_handle) {
+            let ScratchOpen::Read(file) = *open else {
+                warn!("SftpServer Read operation denied: scratch file open for writing");
+                return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
+            };
+            let Some(scratch) = self.scratch.as_ref() else {
+                return Err(StatusCode::SSH_FX_FAILURE);
+            };
+            let mut buf = [0; scratch::READ_CHUNK];
+            let offset = u32::try_from(offset).unwrap_or(u32::MAX);
+            let data = scratch.read(&file, offset, &mut buf).await.map_err(|e| {
+                error!("SftpServer Read failed on a scratch file: {e}");
+                StatusCode::SSH_FX_FAILURE
+            })?;
+            if data.is_empty() {
+                return Err(StatusCode::SSH_FX_EOF);
+            }
+            let data = &data[..data.len().min(usize::try_from(len).unwrap_or(usize::MAX))];
+            debug!(
+                "SftpServer Read: scratch file, offset = {offset}, len = {}",
+                data.len()
+            );
+            return send_data(reply, data).await;
+        }
+        let Some((_, index)) = self
+            .info_handle
+            .as_ref()
+            .filter(|(open, _)| open == opaque_file_handle)
+        else {
+            warn!("SftpServer Read operation denied: handle = {opaque_file_handle:?}");
+            return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
+        };
+        let text = self.files[*index].contents;
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let Some(rest) = text.get(start..).filter(|rest| !rest.is_empty()) else {
+            return Err(StatusCode::SSH_FX_EOF);
+        };
+        let data = &rest[..rest.len().min(usize::try_from(len).unwrap_or(usize::MAX))];
+        debug!(
+            "SftpServer Read: read-only file, offset = {offset}, len = {}",
+            data.len()
+        );
+        send_data(reply, data).await
+    }
+
     async fn write(
         &mut self,
         opaque_file_handle: &T,
         offset: u64,
         buf: &[u8],
     ) -> sunset_sftp::server::SftpOpResult<()> {
+        if self.scratch_open(opaque_file_handle).is_some() {
+            return self.write_scratch(opaque_file_handle, offset, buf).await;
+        }
         if let Some(current_handle) = &self.file_handle
             && current_handle == opaque_file_handle
         {
@@ -206,6 +551,7 @@ impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOt
             );
 
             if let Err(e) = self.processor.process_data(offset, buf).await {
+                self.processor.record_failure(e).await;
                 match e {
                     OtaError::IllegalOperation => {
                         error!(
@@ -213,6 +559,18 @@ impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOt
                         );
                         return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
                     }
+                    OtaError::BadSignature => {
+                        error!(
+                            "SftpServer Write operation failed during OTA processing: image not signed with the firmware key"
+                        );
+                        return Err(StatusCode::SSH_FX_PERMISSION_DENIED);
+                    }
+                    OtaError::WrongTarget => {
+                        error!(
+                            "SftpServer Write operation failed during OTA processing: image is for another chip"
+                        );
+                        return Err(StatusCode::SSH_FX_OP_UNSUPPORTED);
+                    }
                     OtaError::UnknownTlvType => {
                         error!(
                             "SftpServer Write operation failed during OTA processing: Unknown TLV Type - {e:?}"
@@ -225,6 +583,7 @@ impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOt
                     }
                 }
             }
+            self.bytes_received = self.bytes_received.max(offset + buf.len() as u64);
             debug!(
                 "SftpServer Write operation for OTA processed successfully: handle = {opaque_file_handle:?}, offset = {offset:?}, buf_len = {:?}",
                 buf.len()
@@ -236,6 +595,81 @@ impl<T: OpaqueFileHandle + InitFromSeed, W: OtaActions> SftpServer<T> for SftpOt
         Err(StatusCode::SSH_FX_FAILURE)
     }
 
+    async fn setstat(
+        &mut self,
+        file_path: &str,
+        attrs: &Attrs,
+    ) -> sunset_sftp::server::SftpOpResult<()> {
+        info!("SftpServer SetStat: file_path = {file_path:?}");
+        self.check_setstat(attrs, self.received(in_scratch(file_path)))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        opaque_file_handle: &T,
+        attrs: &Attrs,
+    ) -> sunset_sftp::server::SftpOpResult<()> {
+        info!("SftpServer FSetStat: handle = {opaque_file_handle:?}");
+        let scratch = self.scratch_open(opaque_file_handle).is_some();
+        self.check_setstat(attrs, self.received(scratch))
+    }
+
+    /// Stat on the root directory, the scratch directory or one holding read-only
+    /// files describes a directory, on a read-only or scratch file that file; any
+    /// other path is the virtual OTA file
+    ///
+    /// Its size is what was received so far while an upload is in progress and 0
+    /// otherwise, which keeps client progress reporting and resume logic sensible.
+    /// The OTA partition size is served in [`crate::OTA_INFO_PATH`] instead.
+    async fn stats(
+        &mut self,
+        follow_links: bool,
+        file_path: &str,
+    ) -> sunset_sftp::server::SftpOpResult<Attrs> {
+        info!("SftpServer Stat: file_path = {file_path:?}, follow_links = {follow_links:?}");
+        if self.is_dir(file_path) {
+            return Ok(Attrs {
+                permissions: Some(S_IFDIR | 0o755),
+                ..Attrs::default()
+            });
+        }
+        if let Some(index) = self.info_file(file_path) {
+            return Ok(Attrs {
+                size: Some(self.files[index].contents.len() as u64),
+                permissions: Some(S_IFREG | 0o444),
+                ..Attrs::default()
+            });
+        }
+        if in_scratch(file_path) {
+            let (Some(scratch), Some(name)) = (self.scratch.as_mut(), Name::from_path(file_path))
+            else {
+                return Err(StatusCode::SSH_FX_NO_SUCH_FILE);
+            };
+            if self.scratch_handle.is_none() {
+                scratch.refresh().await.map_err(|e| {
+                    error!("SftpServer Stat failed to read the scratch area: {e}");
+                    StatusCode::SSH_FX_FAILURE
+                })?;
+            }
+            let file = scratch.find(&name).ok_or(StatusCode::SSH_FX_NO_SUCH_FILE)?;
+            return Ok(Attrs {
+                size: Some(u64::from(file.len)),
+                permissions: Some(S_IFREG | 0o644),
+                ..Attrs::default()
+            });
+        }
+        let size = if self.file_handle.is_some() {
+            self.bytes_received
+        } else {
+            0
+        };
+        Ok(Attrs {
+            size: Some(size),
+            permissions: Some(S_IFREG | 0o644),
+            ..Attrs::default()
+        })
+    }
+
     async fn opendir(&mut self, dir: &str) -> sunset_sftp::server::SftpOpResult<T> {
         let handle = T::init_from_seed(dir).map_err(|_| StatusCode::SSH_FX_FAILURE)?;
         info!("SftpServer OpenDir: dir = {dir:?}. Returning {handle:?}");
@@ -8,7 +8,9 @@
 ///
 /// If you are looking into improving this, consider looking into [proto.rs](https://github.com/mkj/sunset/blob/8e5d20916cf7b29111b90e4d3b7bb7827c9be8e5/sftp/src/proto.rs)
 /// for an example on how to automate the generation of protocols with macros
+use ed25519_dalek::{Signature, VerifyingKey};
 use log::{debug, error, info, warn};
+use ssh_stamp_hal::ChipTarget;
 use sunset::packets::ParseContext;
 use sunset::sshwire::{SSHDecode, SSHEncode, SSHSource, WireError};
 
@@ -22,11 +24,29 @@ pub type OtaTlvLen = u8;
 // TODO: We could provide a new type for better debugging information
 pub const OTA_TYPE_VALUE_SSH_STAMP: u32 = 0x7373_6873; // 'sshs' big endian in ASCII
 
+/// Version of the OTA file format this crate writes and reads
+///
+/// See the format specification in `ota/README.md`. A header that carries no
+/// [`Tlv::FormatVersion`] is of version 1 or 2, which differ only in the optional
+/// [`Tlv::Crc32Segments`]. Parsers refuse headers of a newer version.
+pub const OTA_FORMAT_VERSION: u8 = 6;
+
 pub const CHECKSUM_LEN: u32 = 32;
 /// Maximum size for LTV (Length-Type-Value) entries in OTA metadata. Used during the reading of OTA parameters.
 ///
 /// Calculated as: `size_of::<OtaTlvType>() + size_of::<OtaTlvLen>() + u8::MAX = 1 + 1 + 255 = 257`
 pub const MAX_TLV_SIZE: u32 = 257;
+/// Maximum number of segment checksums in a [`Tlv::Crc32Segments`]
+///
+/// The segment size and the checksums must fit in a single TLV value: `(255 - 4) / 4 = 62`
+pub const MAX_CRC32_SEGMENTS: usize = (OtaTlvLen::MAX as usize - 4) / 4;
+/// Segments are a multiple of this size, the flash sector size, so a segment never ends
+/// in the middle of a sector the device is about to erase
+pub const CRC32_SEGMENT_ALIGN: u32 = 4096;
+/// Maximum length in bytes of [`ReleaseNotes`], a single TLV value
+pub const MAX_RELEASE_NOTES_LEN: usize = OtaTlvLen::MAX as usize;
+/// Length of an Ed25519 signature, the value of a [`Tlv::Signature`]
+pub const SIGNATURE_LEN: u32 = 64;
 
 /// Encodes the length and value of a sized values
 fn enc_len_val<SE>(
@@ -64,6 +84,101 @@ where
 pub const OTA_TYPE: OtaTlvType = 0;
 pub const FIRMWARE_BLOB: OtaTlvType = 1;
 pub const SHA256_CHECKSUM: OtaTlvType = 2;
+pub const CRC32_SEGMENTS: OtaTlvType = 3;
+pub const FORMAT_VERSION: OtaTlvType = 4;
+pub const RELEASE_NOTES: OtaTlvType = 5;
+pub const TARGET_IC: OtaTlvType = 6;
+pub const SIGNATURE: OtaTlvType = 7;
+
+/// Length of a [`Tlv::TargetIc`] value: family and revision
+const TARGET_IC_LEN: OtaTlvLen = 4;
+
+/// CRC-32 checksums of the firmware blob split in consecutive segments of `segment_size`
+/// bytes, the last one possibly shorter
+///
+/// Lets the device reject a corrupted upload as soon as a segment is complete instead of
+/// after the whole blob has been written. The SHA256 checksum is still verified at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentCrcs {
+    pub segment_size: u32,
+    count: u8,
+    crcs: [u32; MAX_CRC32_SEGMENTS],
+}
+
+impl SegmentCrcs {
+    /// Computes the checksums of `blob` split in at most [`MAX_CRC32_SEGMENTS`] segments
+    /// of a multiple of [`CRC32_SEGMENT_ALIGN`] bytes
+    ///
+    /// Used during packing of OTA files. Therefore, not needed in the embedded side.
+    /// # Panics
+    /// Panics if the blob is larger than 4GB
+    #[cfg(not(target_os = "none"))]
+    #[must_use]
+    pub fn compute(blob: &[u8]) -> Self {
+        let size = u32::try_from(blob.len()).expect("Firmware blob larger than 4GB");
+        #[allow(clippy::cast_possible_truncation)] // 62
+        let segment_size = size
+            .div_ceil(MAX_CRC32_SEGMENTS as u32)
+            .next_multiple_of(CRC32_SEGMENT_ALIGN)
+            .max(CRC32_SEGMENT_ALIGN);
+        let mut segments = Self {
+            segment_size,
+            count: 0,
+            crcs: [0; MAX_CRC32_SEGMENTS],
+        };
+        for (crc, segment) in segments
+            .crcs
+            .iter_mut()
+            .zip(blob.chunks(segment_size as usize))
+        {
+            *crc = crate::crc32::Crc32::checksum(segment);
+            segments.count += 1;
+        }
+        segments
+    }
+
+    /// The checksums, one per segment in blob order
+    #[must_use]
+    pub fn crcs(&self) -> &[u32] {
+        &self.crcs[..usize::from(self.count)]
+    }
+
+    /// Whether there is exactly one checksum per segment of a blob of `size` bytes
+    #[must_use]
+    pub fn covers(&self, size: u32) -> bool {
+        self.segment_size > 0 && u32::from(self.count) == size.div_ceil(self.segment_size)
+    }
+}
+
+/// Free UTF-8 text describing the release, up to [`MAX_RELEASE_NOTES_LEN`] bytes
+///
+/// The device logs it and keeps the start of it in its OTA history when it accepts the
+/// image, so the uploader can tell the intended release was flashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReleaseNotes {
+    len: u8,
+    text: [u8; MAX_RELEASE_NOTES_LEN],
+}
+
+impl ReleaseNotes {
+    /// `None` if `text` is longer than [`MAX_RELEASE_NOTES_LEN`] bytes
+    #[must_use]
+    pub fn new(text: &str) -> Option<Self> {
+        let len = u8::try_from(text.len()).ok()?;
+        let mut notes = Self {
+            len,
+            text: [0; MAX_RELEASE_NOTES_LEN],
+        };
+        notes.text[..text.len()].copy_from_slice(text.as_bytes());
+        Some(notes)
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // Only built from a str or checked on decoding
+        core::str::from_utf8(&self.text[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
 
 /// `OTA_TLV` enum for OTA metadata LTV entries
 /// This TLV does not capture length as it will be captured during parsing
@@ -74,10 +189,28 @@ pub enum Tlv {
     /// Type of OTA update. This **MUST be the first Tlv**.
     /// For SSH Stamp, this must be `OTA_FIRMWARE_BLOB_TYPE`
     OtaType { ota_type: u32 },
+    /// Version of the format the header follows, since version 3. Follows [`Tlv::OtaType`]
+    FormatVersion { version: u8 },
+    /// Optional chip the image is built for, since version 5
+    ///
+    /// The device refuses the image before writing anything if its own chip is of another
+    /// family, or of a revision older than `target.revision`.
+    TargetIc { target: ChipTarget },
     /// Expected SHA256 checksum of the firmware blob
     Sha256Checksum {
         checksum: [u8; CHECKSUM_LEN as usize],
     },
+    /// Optional Ed25519 signature of the [`Tlv::Sha256Checksum`] value, since version 6
+    ///
+    /// Devices built with a firmware key refuse images without a valid one. The checksum
+    /// is verified against the blob at the end, so the signature covers the image too.
+    Signature {
+        signature: [u8; SIGNATURE_LEN as usize],
+    },
+    /// Optional CRC-32 checksums of the firmware blob segments
+    Crc32Segments { segments: SegmentCrcs },
+    /// Optional release notes, since version 4
+    ReleaseNotes { notes: ReleaseNotes },
     /// Contains the length in bytes of the firmware blob.
     /// The firmware blob follows immediately after this TLV.
     ///
@@ -92,6 +225,21 @@ impl SSHEncode for Tlv {
                 OTA_TYPE.enc(s)?;
                 enc_len_val(ota_type, s)
             }
+            Tlv::FormatVersion { version } => {
+                FORMAT_VERSION.enc(s)?;
+                enc_len_val(version, s)
+            }
+            Tlv::TargetIc { target } => {
+                TARGET_IC.enc(s)?;
+                TARGET_IC_LEN.enc(s)?;
+                for b in target.family.to_be_bytes() {
+                    b.enc(s)?;
+                }
+                for b in target.revision.to_be_bytes() {
+                    b.enc(s)?;
+                }
+                Ok(())
+            }
             Tlv::FirmwareBlob { size } => {
                 FIRMWARE_BLOB.enc(s)?;
                 enc_len_val(size, s)
@@ -100,6 +248,32 @@ impl SSHEncode for Tlv {
                 SHA256_CHECKSUM.enc(s)?;
                 enc_len_val(checksum, s)
             }
+            Tlv::Signature { signature } => {
+                SIGNATURE.enc(s)?;
+                enc_len_val(signature, s)
+            }
+            Tlv::Crc32Segments { segments } => {
+                CRC32_SEGMENTS.enc(s)?;
+                let crcs = segments.crcs();
+                OtaTlvLen::try_from(
+                    core::mem::size_of_val(&segments.segment_size) + core::mem::size_of_val(crcs),
+                )
+                .map_err(|_| sunset::sshwire::WireError::PacketWrong)?
+                .enc(s)?;
+                segments.segment_size.enc(s)?;
+                for crc in crcs {
+                    crc.enc(s)?;
+                }
+                Ok(())
+            }
+            Tlv::ReleaseNotes { notes } => {
+                RELEASE_NOTES.enc(s)?;
+                notes.len.enc(s)?;
+                for b in notes.as_str().as_bytes() {
+                    b.enc(s)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -126,11 +300,69 @@ impl<'de> SSHDecode<'de> for Tlv {
                 }
                 Ok(Tlv::Sha256Checksum { checksum })
             }
+            SIGNATURE => {
+                let expected_len = OtaTlvLen::try_from(tlv::SIGNATURE_LEN)
+                    .map_err(|_| sunset::sshwire::WireError::PacketWrong)?;
+                if OtaTlvLen::dec(s)? != expected_len {
+                    return Err(sunset::sshwire::WireError::PacketWrong);
+                }
+                let mut signature = [0u8; tlv::SIGNATURE_LEN as usize];
+                signature.copy_from_slice(s.take(tlv::SIGNATURE_LEN as usize)?);
+                Ok(Tlv::Signature { signature })
+            }
+            CRC32_SEGMENTS => {
+                let val_len = usize::from(OtaTlvLen::dec(s)?);
+                let size_len = core::mem::size_of::<u32>();
+                if val_len < size_len || (val_len - size_len) % size_len != 0 {
+                    return Err(sunset::sshwire::WireError::PacketWrong);
+                }
+                let count = (val_len - size_len) / size_len;
+                let mut segments = SegmentCrcs {
+                    segment_size: u32::dec(s)?,
+                    count: u8::try_from(count)
+                        .map_err(|_| sunset::sshwire::WireError::PacketWrong)?,
+                    crcs: [0; MAX_CRC32_SEGMENTS],
+                };
+                for crc in &mut segments.crcs[..count] {
+                    *crc = u32::dec(s)?;
+                }
+                Ok(Tlv::Crc32Segments { segments })
+            }
+            RELEASE_NOTES => {
+                let len = OtaTlvLen::dec(s)?;
+                let mut notes = ReleaseNotes {
+                    len,
+                    text: [0; MAX_RELEASE_NOTES_LEN],
+                };
+                notes.text[..usize::from(len)].copy_from_slice(s.take(usize::from(len))?);
+                if core::str::from_utf8(&notes.text[..usize::from(len)]).is_err() {
+                    return Err(sunset::sshwire::WireError::BadString);
+                }
+                Ok(Tlv::ReleaseNotes { notes })
+            }
             OTA_TYPE => {
                 dec_check_val_len::<S, u32>(s)?;
                 let ota_type = u32::dec(s)?;
                 Ok(Tlv::OtaType { ota_type })
             }
+            FORMAT_VERSION => {
+                dec_check_val_len::<S, u8>(s)?;
+                Ok(Tlv::FormatVersion {
+                    version: u8::dec(s)?,
+                })
+            }
+            TARGET_IC => {
+                if OtaTlvLen::dec(s)? != TARGET_IC_LEN {
+                    return Err(sunset::sshwire::WireError::PacketWrong);
+                }
+                let value = s.take(usize::from(TARGET_IC_LEN))?;
+                Ok(Tlv::TargetIc {
+                    target: ChipTarget {
+                        family: u16::from_be_bytes([value[0], value[1]]),
+                        revision: u16::from_be_bytes([value[2], value[3]]),
+                    },
+                })
+            }
             _ => {
                 error!("Unknown TLV type encountered: {tlv_type}");
                 let len = OtaTlvLen::dec(s)?;
@@ -252,10 +484,20 @@ pub struct OtaHeader {
     // hasher: sha2::Sha256,
     /// Type of OTA update being processed. Used for screening incorrect ota blobs quickly
     pub(crate) ota_type: Option<u32>,
+    /// Format version from the header, `None` for versions 1 and 2 which do not carry it
+    pub format_version: Option<u8>,
+    /// Chip the image is built for, if provided
+    pub target_ic: Option<ChipTarget>,
     /// Total size of the firmware being downloaded, if known
     pub(crate) firmware_blob_size: Option<u32>,
     /// Expected sha256 checksum of the firmware, if provided
     pub sha256_checksum: Option<[u8; tlv::CHECKSUM_LEN as usize]>,
+    /// Signature of the sha256 checksum, if provided
+    pub signature: Option<[u8; tlv::SIGNATURE_LEN as usize]>,
+    /// Expected CRC-32 checksums of the firmware segments, if provided
+    pub crc32_segments: Option<tlv::SegmentCrcs>,
+    /// Release notes, if provided
+    pub release_notes: Option<tlv::ReleaseNotes>,
 }
 
 impl OtaHeader {
@@ -263,17 +505,86 @@ impl OtaHeader {
     ///
     /// Used during packing of OTA files. Therefore, not needed in the embedded side.
     #[cfg(not(target_os = "none"))]
-    pub fn new(ota_type: u32, sha256_checksum: &[u8], firmware_blob_size: u32) -> Self {
+    pub fn new(
+        ota_type: u32,
+        sha256_checksum: &[u8],
+        crc32_segments: Option<tlv::SegmentCrcs>,
+        firmware_blob_size: u32,
+    ) -> Self {
         // TODO: Check that the sha256_checksum length is correct: 32 bytes
         let mut checksum_array = [0u8; tlv::CHECKSUM_LEN as usize];
         checksum_array.copy_from_slice(sha256_checksum);
         Self {
             ota_type: Some(ota_type),
+            format_version: Some(OTA_FORMAT_VERSION),
+            target_ic: None,
             firmware_blob_size: Some(firmware_blob_size),
             sha256_checksum: Some(checksum_array),
+            signature: None,
+            crc32_segments,
+            release_notes: None,
         }
     }
 
+    /// Adds release notes to the header
+    ///
+    /// Used during packing of OTA files. Therefore, not needed in the embedded side.
+    #[cfg(not(target_os = "none"))]
+    #[must_use]
+    pub fn with_release_notes(mut self, notes: tlv::ReleaseNotes) -> Self {
+        self.release_notes = Some(notes);
+        self
+    }
+
+    /// Adds the signature of the firmware checksum to the header
+    ///
+    /// Used during packing of OTA files. Therefore, not needed in the embedded side.
+    #[cfg(not(target_os = "none"))]
+    #[must_use]
+    pub fn with_signature(mut self, signature: [u8; tlv::SIGNATURE_LEN as usize]) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+
+    /// Names the chip the image is built for in the header
+    ///
+    /// Used during packing of OTA files. Therefore, not needed in the embedded side.
+    #[cfg(not(target_os = "none"))]
+    #[must_use]
+    pub fn with_target_ic(mut self, target: ChipTarget) -> Self {
+        self.target_ic = Some(target);
+        self
+    }
+
+    /// Drops what an older format `version` does not have, so devices running firmware
+    /// that only knows that version accept the header
+    ///
+    /// Used during packing of OTA files. Therefore, not needed in the embedded side.
+    /// # Panics
+    /// Panics if `version` is not between 1 and [`OTA_FORMAT_VERSION`]
+    #[cfg(not(target_os = "none"))]
+    #[must_use]
+    pub fn for_format(mut self, version: u8) -> Self {
+        assert!(
+            (1..=OTA_FORMAT_VERSION).contains(&version),
+            "Unknown OTA format version {version}"
+        );
+        self.format_version = (version >= 3).then_some(version);
+        if version < 6 {
+            self.signature = None;
+        }
+        if version < 5 {
+            self.target_ic = None;
+        }
+        if version < 4 {
+            self.release_notes = None;
+        }
+        if version < 2 {
+            self.crc32_segments = None;
+        }
+        self
+    }
+
     /// Serializes the OTA header into the provided buffer
     ///
     /// Returns the number of bytes written to the buffer
@@ -287,6 +598,18 @@ impl OtaHeader {
                 .expect("Failed to serialize OTA Type TLV");
             offset += used;
         }
+        if let Some(version) = self.format_version {
+            let tlv = tlv::Tlv::FormatVersion { version };
+            let used = sunset::sshwire::write_ssh(&mut buf[offset..], &tlv)
+                .expect("Failed to serialize Format Version TLV");
+            offset += used;
+        }
+        if let Some(target) = self.target_ic {
+            let tlv = tlv::Tlv::TargetIc { target };
+            let used = sunset::sshwire::write_ssh(&mut buf[offset..], &tlv)
+                .expect("Failed to serialize Target IC TLV");
+            offset += used;
+        }
         if let Some(checksum) = &self.sha256_checksum {
             let tlv = tlv::Tlv::Sha256Checksum {
                 checksum: *checksum,
@@ -295,6 +618,24 @@ impl OtaHeader {
                 .expect("Failed to serialize SHA256 Checksum TLV");
             offset += used;
         }
+        if let Some(signature) = self.signature {
+            let tlv = tlv::Tlv::Signature { signature };
+            let used = sunset::sshwire::write_ssh(&mut buf[offset..], &tlv)
+                .expect("Failed to serialize Signature TLV");
+            offset += used;
+        }
+        if let Some(segments) = self.crc32_segments {
+            let tlv = tlv::Tlv::Crc32Segments { segments };
+            let used = sunset::sshwire::write_ssh(&mut buf[offset..], &tlv)
+                .expect("Failed to serialize CRC32 Segments TLV");
+            offset += used;
+        }
+        if let Some(notes) = self.release_notes {
+            let tlv = tlv::Tlv::ReleaseNotes { notes };
+            let used = sunset::sshwire::write_ssh(&mut buf[offset..], &tlv)
+                .expect("Failed to serialize Release Notes TLV");
+            offset += used;
+        }
         if let Some(size) = self.firmware_blob_size {
             let tlv = tlv::Tlv::FirmwareBlob { size };
             let used = sunset::sshwire::write_ssh(&mut buf[offset..], &tlv)
@@ -313,8 +654,13 @@ impl OtaHeader {
     pub fn deserialize(buf: &[u8]) -> Result<(Self, usize), sunset::sshwire::WireError> {
         let mut source = tlv::TlvsSource::new(buf);
         let mut ota_type = None;
+        let mut format_version = None;
+        let mut target_ic = None;
         let mut firmware_blob_size = None;
         let mut sha256_checksum = None;
+        let mut signature = None;
+        let mut crc32_segments = None;
+        let mut release_notes = None;
 
         while source.remaining() > 0 {
             match tlv::Tlv::dec(&mut source) {
@@ -331,10 +677,31 @@ impl OtaHeader {
                         tlv::Tlv::OtaType { ota_type: ot } => {
                             ota_type = Some(ot);
                         }
+                        tlv::Tlv::FormatVersion { version } => {
+                            Self::check_ota_is_first_tlv(ota_type)?;
+                            Self::check_format_version(version)?;
+                            format_version = Some(version);
+                        }
+                        tlv::Tlv::TargetIc { target } => {
+                            Self::check_ota_is_first_tlv(ota_type)?;
+                            target_ic = Some(target);
+                        }
                         tlv::Tlv::Sha256Checksum { checksum } => {
                             Self::check_ota_is_first_tlv(ota_type)?;
                             sha256_checksum = Some(checksum);
                         }
+                        tlv::Tlv::Signature { signature: sig } => {
+                            Self::check_ota_is_first_tlv(ota_type)?;
+                            signature = Some(sig);
+                        }
+                        tlv::Tlv::Crc32Segments { segments } => {
+                            Self::check_ota_is_first_tlv(ota_type)?;
+                            crc32_segments = Some(segments);
+                        }
+                        tlv::Tlv::ReleaseNotes { notes } => {
+                            Self::check_ota_is_first_tlv(ota_type)?;
+                            release_notes = Some(notes);
+                        }
                         tlv::Tlv::FirmwareBlob { size } => {
                             Self::check_ota_is_first_tlv(ota_type)?;
                             firmware_blob_size = Some(size);
@@ -350,16 +717,81 @@ impl OtaHeader {
         Ok((
             Self {
                 ota_type,
+                format_version,
+                target_ic,
                 firmware_blob_size,
                 sha256_checksum,
+                signature,
+                crc32_segments,
+                release_notes,
             },
             source.used(),
         ))
     }
 
+    /// Checks that a header of format `version` can be understood by this parser
+    ///
+    /// # Errors
+    /// Returns `WireError::PacketWrong` for a version newer than [`OTA_FORMAT_VERSION`]
+    pub fn check_format_version(version: u8) -> Result<(), WireError> {
+        if version > OTA_FORMAT_VERSION {
+            error!("OTA format version {version} is newer than the supported {OTA_FORMAT_VERSION}");
+            Err(sunset::sshwire::WireError::PacketWrong)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that an image built for `target` runs on the `running` chip, if known
+    ///
+    /// # Errors
+    /// Returns `WireError::PacketWrong` if the chip family differs or the running chip is
+    /// of an older revision than the image needs
+    pub fn check_target_ic(
+        target: ChipTarget,
+        running: Option<ChipTarget>,
+    ) -> Result<(), WireError> {
+        let Some(running) = running else {
+            return Ok(());
+        };
+        if target.family != running.family {
+            error!(
+                "OTA image is for chip family {}, this chip is of family {}",
+                target.family, running.family
+            );
+            Err(sunset::sshwire::WireError::PacketWrong)
+        } else if target.revision > running.revision {
+            error!(
+                "OTA image needs chip revision {} or later, this chip is revision {}",
+                target.revision, running.revision
+            );
+            Err(sunset::sshwire::WireError::PacketWrong)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that the header carries a signature of its SHA256 checksum made with the
+    /// private half of `key`
+    ///
+    /// # Errors
+    /// Returns `WireError::PacketWrong` if the signature or the checksum is missing, or
+    /// the signature does not match
+    pub fn check_signature(&self, key: &VerifyingKey) -> Result<(), WireError> {
+        let (Some(checksum), Some(signature)) = (&self.sha256_checksum, &self.signature) else {
+            error!("OTA image is not signed, this device only accepts signed images");
+            return Err(sunset::sshwire::WireError::PacketWrong);
+        };
+        key.verify_strict(checksum, &Signature::from_bytes(signature))
+            .map_err(|_| {
+                error!("OTA image signature does not match the firmware key");
+                sunset::sshwire::WireError::PacketWrong
+            })
+    }
+
     fn check_ota_is_first_tlv(ota_type: Option<u32>) -> Result<(), WireError> {
         if ota_type.is_none() {
-            error!("SHA256 Checksum TLV encountered before OTA Type TLV. Ignoring it");
+            error!("TLV encountered before OTA Type TLV. Ignoring it");
             Err(sunset::sshwire::WireError::PacketWrong)
         } else {
             Ok(())
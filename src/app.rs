@@ -6,28 +6,54 @@
 //!
 //! Once a platform crate has brought up its peripherals, loaded the
 //! [`SSHStampConfig`] from flash, and raised an `embassy_net::Stack` via a
-//! [`ssh_stamp_hal::NetworkProviderHal`] implementation, it hands control
-//! here. Everything from "accept a TCP connection" downward is the same on
-//! every MCU.
+//! [`ssh_stamp_hal::NetworkProviderHal`] implementation (two of them in
+//! AP+STA mode, see [`NetStacks`]), it hands control here. Everything from
+//! "accept a TCP connection" downward is the same on every MCU.
+//!
+//! Firmware that embeds the SSH console next to its own tasks, rather than
+//! running the standalone binary, builds it with [`SshConsole`] instead of
+//! calling [`run_app`].
 
+use core::net::IpAddr;
 use core::result::Result;
 
-use embassy_futures::select::{Either3, select3};
+use embassy_futures::select::{Either, Either4, select, select4};
 use embassy_net::{IpListenEndpoint, Stack, tcp::TcpSocket};
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
 use heapless::String;
 use log::{debug, error, info, warn};
 use ssh_key::HashAlg;
 use ssh_stamp_hal::WifiApConfigStatic;
+use static_cell::ConstStaticCell;
 use sunset::SignKey;
 use sunset_async::SunsetMutex;
 
-use crate::config::SSHStampConfig;
+use crate::baud_ladder;
+use crate::clients;
+use crate::config::{NetInterface, SSHStampConfig};
+use crate::events::{self, Event};
 use crate::handle::{self, SessionType};
+use crate::handshake::Handshake;
+use crate::lockout;
+use crate::logging::Redacted;
+#[cfg(feature = "observers")]
+use crate::observers;
+use crate::ota_window;
+use crate::outbox;
+#[cfg(feature = "peers")]
+use crate::peers;
 use crate::platform::PlatformServices;
-use crate::serial::BufferedSerial;
+#[cfg(feature = "plugins")]
+use crate::plugins::{self, Plugin};
+#[cfg(feature = "raw-tcp")]
+use crate::raw_tcp;
+use crate::serial::{self, BufferedSerial};
 use crate::serve;
-use crate::settings::{SSH_STAMP_IDENT, UART_BUFFER_SIZE, WIFI_PASSWORD_CHARS};
+use crate::sessions;
+use crate::settings::{
+    SSH_PORT, SSH_STAMP_IDENT, TCP_BUFFER_SIZE, UART_BUFFER_SIZE, WIFI_PASSWORD_CHARS,
+};
+use crate::staging;
 
 /// Ensures a `WiFi` password exists, persists a freshly-generated one if not,
 /// prints the SSH hostkey fingerprint, and returns a ready-to-use
@@ -62,7 +88,7 @@ pub async fn prepare_ap_config<P: PlatformServices>(
             .await
             .map_err(|_| sunset::error::BadUsage.build())?;
     }
-    info!("WIFI PSK: {}", guard.wifi_ap_pw);
+    info!("WIFI PSK: {}", Redacted(&guard.wifi_ap_pw));
 
     let mac = guard
         .resolve_mac()
@@ -81,17 +107,92 @@ pub async fn prepare_ap_config<P: PlatformServices>(
         sta_password: guard.wifi_sta_pw.clone(),
         channel: 1,
         mac,
+        concurrent: guard.wifi_concurrent,
+        ap_address: guard.ap_address,
+        dhcp_server: guard.dhcp_server,
     })
 }
 
+/// Network stacks the SSH server listens on.
+#[derive(Clone, Copy)]
+pub struct NetStacks {
+    /// The station stack, or the only stack outside AP+STA mode.
+    pub primary: Stack<'static>,
+    /// The access-point stack in AP+STA mode, see
+    /// [`ssh_stamp_hal::WifiHal::concurrent_ap_stack`].
+    pub access_point: Option<Stack<'static>>,
+}
+
+/// Socket and SSH buffers of the server loop.
+///
+/// Kept in a static rather than in the [`run_app`] future: a task's future
+/// is built on the stack before it is moved into place, and 14 KiB of
+/// buffers in it is more than the ESP32-S2's main stack can spare. An
+/// embedding firmware may place its own, see [`SshConsole::buffers`].
+pub struct ConnectionBuffers {
+    tcp_rx: [u8; TCP_BUFFER_SIZE],
+    tcp_tx: [u8; TCP_BUFFER_SIZE],
+    // Second listener for the access point in AP+STA mode.
+    ap_tcp_rx: [u8; TCP_BUFFER_SIZE],
+    ap_tcp_tx: [u8; TCP_BUFFER_SIZE],
+    ssh_in: [u8; UART_BUFFER_SIZE],
+    ssh_out: [u8; UART_BUFFER_SIZE],
+}
+
+impl ConnectionBuffers {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            tcp_rx: [0; TCP_BUFFER_SIZE],
+            tcp_tx: [0; TCP_BUFFER_SIZE],
+            ap_tcp_rx: [0; TCP_BUFFER_SIZE],
+            ap_tcp_tx: [0; TCP_BUFFER_SIZE],
+            ssh_in: [0; UART_BUFFER_SIZE],
+            ssh_out: [0; UART_BUFFER_SIZE],
+        }
+    }
+}
+
+impl Default for ConnectionBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static CONNECTION_BUFFERS: ConstStaticCell<ConnectionBuffers> =
+    ConstStaticCell::new(ConnectionBuffers::new());
+
 /// Runs the SSH server loop forever: accept TCP, run SSH, bridge to UART,
 /// then go round again. Does not return under normal operation.
 ///
+/// In AP+STA mode connections are accepted on whichever of the two stacks
+/// is enabled by `ssh_on_ap` / `ssh_on_sta`; the first one to connect wins
+/// and the other listener is dropped until the session ends. The session
+/// remembers its interface, so SFTP OTA can be limited to some with
+/// `ota_on_ap` / `ota_on_sta`.
+///
+/// Alongside, [`staging::rollback_watchdog`] undoes unconfirmed network
+/// changes and [`ota_window::watchdog`] unconfirmed firmware, while
+/// [`serial::report_uart_alerts`] logs what the UART task noticed and
+/// [`baud_ladder::run`] looks for a working baud rate if one is configured.
+/// Builds with the `peers` feature also answer and browse mDNS on the
+/// primary stack, and builds with the `observers` feature let further
+/// clients watch the bridge there while a connection is up, see
+/// `observers`, and builds with the `raw-tcp` feature bridge plain TCP
+/// clients on the configured port while no connection is up, see
+/// `raw_tcp`.
+///
+/// Same as [`SshConsole::run`] with the defaults.
+///
 /// # Errors
 ///
 /// Returns an error only on unrecoverable TCP socket initialisation failure.
+///
+/// # Panics
+///
+/// Panics if called more than once, see [`ConnectionBuffers`].
 pub async fn run_app<U, P>(
-    stack: Stack<'static>,
+    stacks: NetStacks,
     uart: &U,
     config: &'static SunsetMutex<SSHStampConfig>,
     platform: &P,
@@ -100,43 +201,322 @@ where
     U: BufferedSerial,
     P: PlatformServices,
 {
-    // TODO: Are the size of those buffers reasonable?
-    let mut rx_buffer = [0u8; 1536];
-    let mut tx_buffer = [0u8; 1536];
+    let mut console = SshConsole::new(stacks.primary, uart, config, platform);
+    if let Some(ap_stack) = stacks.access_point {
+        console = console.access_point(ap_stack);
+    }
+    console.run().await
+}
 
-    loop {
-        debug!("HSM: accepting TCP on port 22");
-        let mut tcp_socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
-        if let Err(e) = tcp_socket
-            .accept(IpListenEndpoint {
-                addr: None,
-                port: 22,
-            })
-            .await
+/// Builder for the SSH console, for firmware that embeds it in its own
+/// embassy application.
+///
+/// The embedding firmware brings up its network stack, loads or builds a
+/// [`SSHStampConfig`], provides a [`BufferedSerial`] for the bridged UART
+/// and a [`PlatformServices`] impl, then runs the console as one of its
+/// tasks:
+///
+/// ```ignore
+/// #[embassy_executor::task]
+/// async fn ssh_console(
+///     stack: Stack<'static>,
+///     uart: &'static MyUart,
+///     config: &'static SunsetMutex<SSHStampConfig>,
+/// ) {
+///     let platform = MyPlatform::new();
+///     let result = SshConsole::new(stack, uart, config, &platform)
+///         .port(2222)
+///         .rollback_watchdog(false)
+///         .run()
+///         .await;
+///     log::error!("SSH console stopped: {result:?}");
+/// }
+/// ```
+///
+/// Admin commands, env var provisioning and, with the `sftp-ota` feature,
+/// the OTA subsystem come along; they use `platform` to persist the config,
+/// reset and write images.
+pub struct SshConsole<'a, U, P> {
+    stacks: NetStacks,
+    uart: &'a U,
+    config: &'static SunsetMutex<SSHStampConfig>,
+    platform: &'a P,
+    port: u16,
+    rollback_watchdog: bool,
+    buffers: Option<&'static mut ConnectionBuffers>,
+}
+
+impl<'a, U, P> SshConsole<'a, U, P>
+where
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    /// Listens on `stack`, port [`SSH_PORT`], and bridges sessions to `uart`.
+    #[must_use]
+    pub fn new(
+        stack: Stack<'static>,
+        uart: &'a U,
+        config: &'static SunsetMutex<SSHStampConfig>,
+        platform: &'a P,
+    ) -> Self {
+        Self {
+            stacks: NetStacks {
+                primary: stack,
+                access_point: None,
+            },
+            uart,
+            config,
+            platform,
+            port: SSH_PORT,
+            rollback_watchdog: true,
+            buffers: None,
+        }
+    }
+
+    /// Also listens on the access-point stack in AP+STA mode, subject to
+    /// `ssh_on_ap` / `ssh_on_sta`.
+    #[must_use]
+    pub fn access_point(mut self, stack: Stack<'static>) -> Self {
+        self.stacks.access_point = Some(stack);
+        self
+    }
+
+    /// Listens on `port` instead of [`SSH_PORT`].
+    #[must_use]
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Whether to run [`staging::rollback_watchdog`] alongside, on by
+    /// default. Without it, network changes committed over SSH are never
+    /// rolled back.
+    #[must_use]
+    pub fn rollback_watchdog(mut self, enabled: bool) -> Self {
+        self.rollback_watchdog = enabled;
+        self
+    }
+
+    /// Uses `buffers` rather than the crate's own static ones, e.g. to place
+    /// them in a particular RAM region.
+    #[must_use]
+    pub fn buffers(mut self, buffers: &'static mut ConnectionBuffers) -> Self {
+        self.buffers = Some(buffers);
+        self
+    }
+
+    /// Registers `plugin`, see [`plugins`]. Plugins beyond
+    /// [`PLUGIN_SLOTS`](crate::settings::PLUGIN_SLOTS) are left out with a
+    /// warning.
+    #[cfg(feature = "plugins")]
+    #[must_use]
+    pub fn plugin(self, plugin: &'static dyn Plugin) -> Self {
+        plugins::register_or_warn(plugin);
+        self
+    }
+
+    /// Runs the server loop, see [`run_app`]. Does not return under normal
+    /// operation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error only on unrecoverable TCP socket initialisation
+    /// failure.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the crate's static buffers are taken twice: run one console
+    /// without [`Self::buffers`] at most.
+    pub async fn run(self) -> Result<(), sunset::Error> {
+        let buffers = self.buffers.unwrap_or_else(|| CONNECTION_BUFFERS.take());
+        let server = serve_forever(
+            self.stacks,
+            self.port,
+            buffers,
+            self.uart,
+            self.config,
+            self.platform,
+        );
+        let server = async {
+            let watchdog = ota_window::watchdog(self.platform);
+            let ladder = baud_ladder::run(self.uart, self.config, self.platform);
+            match select4(server, watchdog, serial::report_uart_alerts(), ladder).await {
+                Either4::First(r) => r,
+                Either4::Second(never) | Either4::Third(never) | Either4::Fourth(never) => {
+                    match never {}
+                }
+            }
+        };
+        #[cfg(feature = "peers")]
+        let server = async {
+            let name = self.config.lock().await.wifi_ap_ssid.clone();
+            match select(server, peers::discover(self.stacks.primary, &name)).await {
+                Either::First(r) => r,
+                Either::Second(never) => match never {},
+            }
+        };
+        #[cfg(feature = "observers")]
+        let server = async {
+            let observers = observers::serve(self.stacks.primary, self.port, self.config);
+            match select(server, observers).await {
+                Either::First(r) => r,
+                Either::Second(never) => match never {},
+            }
+        };
+        #[cfg(feature = "raw-tcp")]
+        let server = async {
+            let raw = raw_tcp::serve(self.stacks.primary, self.port, self.uart, self.config);
+            match select(server, raw).await {
+                Either::First(r) => r,
+                Either::Second(never) => match never {},
+            }
+        };
+        if !self.rollback_watchdog {
+            return server.await;
+        }
+        match select(
+            server,
+            staging::rollback_watchdog(self.config, self.platform),
+        )
+        .await
         {
-            error!("TCP accept error: {e:?}");
-            continue;
+            Either::First(r) => r,
+            Either::Second(never) => match never {},
         }
-        debug!("HSM: TCP connected on port 22");
+    }
+}
 
-        let mut inbuf = [0u8; UART_BUFFER_SIZE];
-        let mut outbuf = [0u8; UART_BUFFER_SIZE];
-        let ssh_server = serve::ssh_wait_for_initialisation(&mut inbuf, &mut outbuf);
+async fn serve_forever<U, P>(
+    stacks: NetStacks,
+    port: u16,
+    buffers: &mut ConnectionBuffers,
+    uart: &U,
+    config: &'static SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), sunset::Error>
+where
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    let endpoint = IpListenEndpoint { addr: None, port };
+
+    loop {
+        debug!("HSM: accepting TCP on port {port}");
+        let (on_ap, on_sta, nodelay) = {
+            let guard = config.lock().await;
+            (guard.ssh_on_ap, guard.ssh_on_sta, guard.tcp_nodelay)
+        };
+        let mut sta_socket =
+            TcpSocket::new(stacks.primary, &mut buffers.tcp_rx, &mut buffers.tcp_tx);
+        let accepted = match stacks.access_point {
+            Some(ap_stack) if on_ap => {
+                let mut ap_socket =
+                    TcpSocket::new(ap_stack, &mut buffers.ap_tcp_rx, &mut buffers.ap_tcp_tx);
+                if on_sta {
+                    let res = select(sta_socket.accept(endpoint), ap_socket.accept(endpoint)).await;
+                    match res {
+                        Either::First(r) => r.map(|()| (sta_socket, Some(NetInterface::Station))),
+                        Either::Second(r) => {
+                            r.map(|()| (ap_socket, Some(NetInterface::AccessPoint)))
+                        }
+                    }
+                } else {
+                    let res = ap_socket.accept(endpoint).await;
+                    res.map(|()| (ap_socket, Some(NetInterface::AccessPoint)))
+                }
+            }
+            Some(_) => {
+                let res = sta_socket.accept(endpoint).await;
+                res.map(|()| (sta_socket, Some(NetInterface::Station)))
+            }
+            // Outside AP+STA mode the per-interface flags do not apply.
+            None => {
+                let res = sta_socket.accept(endpoint).await;
+                res.map(|()| (sta_socket, None))
+            }
+        };
+        let (mut tcp_socket, interface) = match accepted {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("TCP accept error: {e:?}");
+                continue;
+            }
+        };
+        debug!("HSM: TCP connected on port {port}");
+        // Set for the whole connection: the SSH server holds the socket's
+        // halves until it ends, so the session type cannot be waited for.
+        tcp_socket.set_nagle_enabled(!nodelay);
+
+        let peer = tcp_socket.remote_endpoint().map(|e| IpAddr::from(e.addr));
+        if let Some(peer) = peer {
+            if !config.lock().await.access_lists.permits(peer) {
+                warn!("Rejecting connection from {peer}: not permitted by access lists");
+                events::emit(Event::ConnectionRejected { peer });
+                tcp_socket.abort();
+                let _ = tcp_socket.flush().await;
+                continue;
+            }
+            if lockout::is_blocked(peer) {
+                debug!("Rejecting connection from {peer}: too many failures");
+                tcp_socket.abort();
+                let _ = tcp_socket.flush().await;
+                continue;
+            }
+        }
+
+        let ssh_server =
+            serve::ssh_wait_for_initialisation(&mut buffers.ssh_in, &mut buffers.ssh_out);
 
         let chan_pipe = Channel::<NoopRawMutex, SessionType, 1>::new();
         let connection = serve::connection_loop(&ssh_server, &chan_pipe, config, platform);
-        let bridge = handle::ssh_client(uart, &ssh_server, &chan_pipe, platform);
+        let bridge = handle::ssh_client(uart, &ssh_server, &chan_pipe, config, platform);
 
-        let (mut rsock, mut wsock) = tcp_socket.split();
+        sessions::begin(peer, interface);
+        #[cfg(feature = "observers")]
+        observers::main_connection(true);
+        #[cfg(feature = "raw-tcp")]
+        raw_tcp::main_connection(true);
+        let handshake = Handshake::new();
+        let (rsock, wsock) = tcp_socket.split();
+        let mut rsock = handshake.client_tap(rsock);
+        let mut wsock = handshake.server_tap(wsock);
         let server = ssh_server.run(&mut rsock, &mut wsock);
 
-        match select3(server, connection, bridge).await {
-            Either3::First(r) | Either3::Second(r) | Either3::Third(r) => {
+        let stalled = sessions::stalled();
+
+        let timed_out = match select4(server, connection, bridge, stalled).await {
+            Either4::First(r) | Either4::Second(r) | Either4::Third(r) => {
                 if let Err(e) = r {
                     debug!("Session ended: {e}");
                 }
+                false
+            }
+            Either4::Fourth(()) => true,
+        };
+        if timed_out {
+            tcp_socket.abort();
+            let _ = tcp_socket.flush().await;
+        }
+        // Whichever future finished, the others were dropped wherever they
+        // were waiting. Per-connection state (channels, SFTP and OTA state)
+        // is rebuilt for the next connection; what outlives it is put back
+        // in order here.
+        #[cfg(feature = "observers")]
+        observers::main_connection(false);
+        #[cfg(feature = "raw-tcp")]
+        raw_tcp::main_connection(false);
+        serial::end_connection(uart).await;
+        let authenticated = sessions::end();
+        lockout::record(peer, authenticated);
+        if clients::needs_saving() || outbox::needs_saving() {
+            let config = config.lock().await;
+            if let Err(e) = platform.save_config(&config).await {
+                warn!("Cannot save the known clients and outbox: {e}");
             }
         }
+        // Drop any session UART overrides before the next client.
+        let uart_config = config.lock().await.uart_config();
+        uart.reconfigure(uart_config).await;
     }
 }
 
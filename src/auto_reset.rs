@@ -0,0 +1,106 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Arduino-style target resets through the pulse output (`rmt` feature).
+//!
+//! Arduino and similar dev boards reset when the host toggles DTR or RTS,
+//! which their USB serial chip wires to the reset pin through a capacitor;
+//! `avrdude` relies on that to reach the boot loader. SSH carries no modem
+//! lines, so the bridge offers two stand-ins driving the pulse output
+//! (ESP32: GPIO4) wired the same way:
+//!
+//! - `SSH_STAMP_SESSION_RESET=1` resets the target as the bridge session
+//!   starts, like opening the port does on such a board.
+//! - With `SSH_STAMP_AUTO_RESET=1`, input starting with the STK500
+//!   `GET_SYNC` command after a quiet spell resets the target before it is
+//!   passed on, so `avrdude -c arduino` works unchanged through a local pty.
+//!   The programmer retries the command until the boot loader answers.
+//!
+//! The pulse is [`RESET_PULSE`]: the pin, low while idle, goes high and
+//! back, a falling edge for a capacitor-coupled reset pin or an active-high
+//! pulse for a transistor pulling it down. ESP targets also need their boot
+//! pin held, which a single line cannot do; use `pulse` for those.
+
+use embassy_time::{Duration, Instant};
+use embedded_io_async::{ErrorType, Read};
+use log::{info, warn};
+use ssh_stamp_hal::Pulse;
+
+use crate::platform::PlatformServices;
+use crate::settings::{AUTO_RESET_HOLDOFF_SECS, AUTO_RESET_PULSE_MICROS, AUTO_RESET_QUIET_MS};
+
+/// The reset pulse sent to the target.
+pub const RESET_PULSE: [Pulse; 1] = [Pulse {
+    high: true,
+    micros: AUTO_RESET_PULSE_MICROS,
+}];
+
+/// STK500 `Cmnd_STK_GET_SYNC`, `Sync_CRC_EOP`: what `avrdude` sends first.
+const STK500_GET_SYNC: [u8; 2] = [0x30, 0x20];
+
+/// Sends [`RESET_PULSE`], logging a failure rather than returning it: the
+/// session goes on either way.
+pub async fn reset_target<P: PlatformServices>(platform: &P, why: &str) {
+    info!("Resetting the target: {why}");
+    if let Err(e) = platform.send_pulses(&RESET_PULSE).await {
+        warn!("Cannot reset the target: {e}");
+    }
+}
+
+/// Passes the client's input through, resetting the target first when it
+/// looks like a programmer starting up, see the module docs. Does nothing
+/// unless enabled.
+pub struct SyncWatch<'a, R, P> {
+    inner: R,
+    platform: &'a P,
+    enabled: bool,
+    last_input: Option<Instant>,
+    last_reset: Option<Instant>,
+}
+
+impl<'a, R, P> SyncWatch<'a, R, P> {
+    pub fn new(inner: R, platform: &'a P, enabled: bool) -> Self {
+        Self {
+            inner,
+            platform,
+            enabled,
+            last_input: None,
+            last_reset: None,
+        }
+    }
+}
+
+impl<R, P> ErrorType for SyncWatch<'_, R, P>
+where
+    R: Read<Error = sunset::Error>,
+{
+    type Error = sunset::Error;
+}
+
+impl<R, P> Read for SyncWatch<'_, R, P>
+where
+    R: Read<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, sunset::Error> {
+        let n = self.inner.read(buf).await?;
+        if !self.enabled {
+            return Ok(n);
+        }
+        let quiet = self
+            .last_input
+            .is_none_or(|at| at.elapsed() >= Duration::from_millis(AUTO_RESET_QUIET_MS));
+        // The programmer's retries must reach the boot loader, not reset it
+        // again.
+        let held_off = self
+            .last_reset
+            .is_some_and(|at| at.elapsed() < Duration::from_secs(AUTO_RESET_HOLDOFF_SECS));
+        self.last_input = Some(Instant::now());
+        if quiet && !held_off && buf[..n].starts_with(&STK500_GET_SYNC) {
+            reset_target(self.platform, "STK500 sync in the input").await;
+            self.last_reset = Some(Instant::now());
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Baud rate fallback for targets whose console rate is not known.
+//!
+//! With `SSH_STAMP_UART_BAUD_LADDER` set, e.g. to `115200,57600,38400,9600`,
+//! [`run`] watches the UART's receive errors. Once a window of
+//! [`BAUD_LADDER_WINDOW_SECS`] brings [`BAUD_LADDER_ERRORS`] or more line
+//! noise errors (glitch, framing, parity), the UART moves to the next rate
+//! of the ladder, skipping the configured one and any the UART rejects.
+//! The first window at a new rate with target output and no errors ends the
+//! search: the rate is saved as the device-wide baud rate
+//! (`SSH_STAMP_UART_BAUD`) and an [`Event::UartBaudDetected`] reports it.
+//!
+//! If every rate fails, the configured one is restored and the ladder is
+//! not tried again until a window passes without errors. This is no
+//! autobaud: only the listed rates are tried, and only while the target
+//! talks, and not while the line is stuck ([`serial::line_stuck`]). A
+//! selected mux target has its own rate and is left alone; a
+//! session's `SSH_STAMP_SESSION_BAUD` is replaced like the configured rate
+//! if the line fails at it.
+
+use core::convert::Infallible;
+
+use embassy_time::Timer;
+use log::{info, warn};
+use sunset_async::SunsetMutex;
+
+use crate::config::SSHStampConfig;
+use crate::events::{self, Event};
+use crate::platform::PlatformServices;
+use crate::serial::{self, BufferedSerial, LineStats};
+use crate::settings::{BAUD_LADDER_ERRORS, BAUD_LADDER_WINDOW_SECS};
+
+/// Line noise errors counted so far.
+fn noise(stats: &LineStats) -> u32 {
+    stats
+        .glitches
+        .wrapping_add(stats.framing)
+        .wrapping_add(stats.parity)
+}
+
+/// Switches `uart` to `baud_rate`, keeping the other configured settings.
+/// Returns `false` if the UART rejected it and stayed at its rate.
+async fn switch<U: BufferedSerial>(uart: &U, config: &SSHStampConfig, baud_rate: u32) -> bool {
+    let mut uart_config = config.uart_config();
+    uart_config.baud_rate = baud_rate;
+    let applied = uart.reconfigure(uart_config).await;
+    if !applied {
+        warn!("The UART cannot run at {baud_rate} baud");
+    }
+    applied
+}
+
+/// Steps through the configured baud ladder while the UART keeps failing,
+/// see the module docs. Runs alongside the SSH server.
+pub async fn run<U, P>(uart: &U, config: &SunsetMutex<SSHStampConfig>, platform: &P) -> Infallible
+where
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    let mut errors = noise(&serial::line_stats());
+    let mut received = serial::received_bytes();
+    // The rate being tried, if a search is under way.
+    let mut trying: Option<u32> = None;
+    // Ladder rates tried since the configured one failed.
+    let mut tried = 0;
+    loop {
+        Timer::after_secs(BAUD_LADDER_WINDOW_SECS).await;
+        let (now_errors, now_received) = (noise(&serial::line_stats()), serial::received_bytes());
+        let new_errors = now_errors.wrapping_sub(errors);
+        let new_bytes = now_received.wrapping_sub(received);
+        (errors, received) = (now_errors, now_received);
+
+        let mut config = config.lock().await;
+        // A stuck line fails at every rate.
+        if config.baud_ladder.is_empty()
+            || config.selected_mux_target().is_some()
+            || serial::line_stuck()
+        {
+            (trying, tried) = (None, 0);
+            continue;
+        }
+        let failing = new_errors >= BAUD_LADDER_ERRORS;
+        if !failing {
+            match trying {
+                Some(baud_rate) if new_errors == 0 && new_bytes > 0 => {
+                    (trying, tried) = (None, 0);
+                    config.uart_baud = baud_rate;
+                    if let Err(e) = platform.save_config(&config).await {
+                        warn!("Cannot save the detected baud rate: {e}");
+                    }
+                    drop(config);
+                    events::emit(Event::UartBaudDetected { baud_rate });
+                }
+                Some(_) => (),
+                None => tried = 0,
+            }
+            continue;
+        }
+        let configured = config.uart_baud;
+        let next = config
+            .baud_ladder
+            .iter()
+            .filter(|&&rate| rate != configured)
+            .nth(tried)
+            .copied();
+        match next {
+            Some(baud_rate) => {
+                info!(
+                    "{new_errors} UART receive errors in {BAUD_LADDER_WINDOW_SECS} s, trying {baud_rate} baud"
+                );
+                tried += 1;
+                // A rejected rate is passed over: the UART is still at the
+                // one tried before, which the next window judges again.
+                if switch(uart, &config, baud_rate).await {
+                    trying = Some(baud_rate);
+                }
+            }
+            None if trying.is_some() => {
+                warn!("No baud rate on the ladder receives cleanly, back to {configured}");
+                trying = None;
+                let _ = switch(uart, &config, configured).await;
+            }
+            None => (),
+        }
+    }
+}
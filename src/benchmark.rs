@@ -0,0 +1,311 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Throughput benchmarks for the `benchmark` admin command.
+//!
+//! `benchmark uart` pushes a counting pattern through the same buffered UART
+//! the bridge uses and checks what comes back, so it needs the UART's TX and
+//! RX wired together, or a target that echoes. Bytes that never come back
+//! are lost; bytes out of sequence point at drops or corruption on the way
+//! that the byte count does not show. `benchmark ssh` sends lines of
+//! test pattern to the client while counting whatever the client sends,
+//! which measures the radio link and the SSH encryption without the UART.
+//!
+//! With the `test-services` feature, [`echo`] and [`chargen`] back the
+//! `test` commands: plain data sources and sinks for client-side tools such
+//! as `pv` or `cmp`, with nothing but the data on the channel.
+
+use core::fmt::Write as _;
+
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, with_deadline, with_timeout};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+
+use crate::serial::{self, BufferedSerial};
+use crate::settings::{BRIDGE_TO_SSH_CHUNK, BRIDGE_TO_UART_CHUNK};
+
+/// The UART pattern counts from 0 to 250 and starts over. A prime period
+/// keeps a loss of a multiple of 256 bytes from going unnoticed.
+const PATTERN_PERIOD: u8 = 251;
+
+/// After sending stops, input is read until the UART has been quiet this
+/// long...
+const DRAIN_QUIET_MS: u64 = 200;
+/// ...or for at most this long.
+const DRAIN_MAX_MS: u64 = 2000;
+
+/// Characters following the line number in each line of the SSH pattern.
+const LINE_CHARS: &[u8; 51] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxy";
+/// Ten digit line number, a space, [`LINE_CHARS`] and CR LF.
+const LINE_LEN: usize = 11 + LINE_CHARS.len() + 2;
+
+const _: () = assert!(BRIDGE_TO_SSH_CHUNK % LINE_LEN == 0);
+
+/// Result of [`uart`].
+#[derive(Debug, Clone)]
+pub struct UartReport {
+    pub sent: u64,
+    pub received: u64,
+    /// Received bytes that did not follow the one before them.
+    pub out_of_sequence: u64,
+    /// Bytes dropped because the receive buffer was full.
+    pub overflowed: usize,
+    /// Receive errors reported by the hardware during the run.
+    pub line_errors: u32,
+    pub elapsed: Duration,
+}
+
+impl UartReport {
+    /// Sent bytes that did not come back.
+    #[must_use]
+    pub fn lost(&self) -> u64 {
+        self.sent.saturating_sub(self.received)
+    }
+}
+
+/// Result of [`ssh`].
+#[derive(Debug, Clone)]
+pub struct SshReport {
+    pub sent: u64,
+    pub received: u64,
+    /// Longest wait for the channel to take more output: the client not
+    /// reading, or the link stalling.
+    pub longest_stall: Duration,
+    pub elapsed: Duration,
+}
+
+/// Bytes per second over `elapsed`.
+#[must_use]
+pub fn per_sec(bytes: u64, elapsed: Duration) -> u64 {
+    bytes * 1000 / elapsed.as_millis().max(1)
+}
+
+/// Sends the UART pattern for `secs` seconds, reading it back at the same
+/// time, then waits for the bytes still in flight.
+pub async fn uart<U: BufferedSerial>(uart: &U, secs: u64) -> UartReport {
+    // Start both counters from zero.
+    uart.check_dropped_bytes();
+    let errors_before = line_errors();
+
+    let mut sent = 0;
+    let mut check = PatternCheck::default();
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(secs);
+    select(
+        send_pattern(uart, deadline, &mut sent),
+        receive_pattern(uart, &mut check, None),
+    )
+    .await;
+    let elapsed = start.elapsed();
+    let _ = with_timeout(
+        Duration::from_millis(DRAIN_MAX_MS),
+        receive_pattern(
+            uart,
+            &mut check,
+            Some(Duration::from_millis(DRAIN_QUIET_MS)),
+        ),
+    )
+    .await;
+
+    UartReport {
+        sent,
+        received: check.received,
+        out_of_sequence: check.out_of_sequence,
+        overflowed: uart.check_dropped_bytes(),
+        line_errors: line_errors().saturating_sub(errors_before),
+        elapsed,
+    }
+}
+
+/// Receive errors of any kind since boot.
+fn line_errors() -> u32 {
+    let stats = serial::line_stats();
+    stats
+        .glitches
+        .saturating_add(stats.framing)
+        .saturating_add(stats.parity)
+        .saturating_add(stats.overflows)
+}
+
+const fn next_in_pattern(byte: u8) -> u8 {
+    if byte >= PATTERN_PERIOD - 1 {
+        0
+    } else {
+        byte + 1
+    }
+}
+
+async fn send_pattern<U: BufferedSerial>(uart: &U, deadline: Instant, sent: &mut u64) {
+    let mut buf = [0u8; BRIDGE_TO_UART_CHUNK];
+    let mut next = 0;
+    loop {
+        for byte in &mut buf {
+            *byte = next;
+            next = next_in_pattern(next);
+        }
+        let Ok(written) = with_deadline(deadline, uart.write_some(&buf)).await else {
+            return;
+        };
+        *sent += written as u64;
+        // Resume the pattern at the first byte not taken.
+        if let Some(&byte) = buf.get(written) {
+            next = byte;
+        }
+    }
+}
+
+/// Checks received bytes against the pattern.
+#[derive(Default)]
+struct PatternCheck {
+    expected: u8,
+    received: u64,
+    out_of_sequence: u64,
+}
+
+/// Reads and checks the pattern; with `quiet`, returns once nothing arrived
+/// for that long.
+async fn receive_pattern<U: BufferedSerial>(
+    uart: &U,
+    check: &mut PatternCheck,
+    quiet: Option<Duration>,
+) {
+    let mut buf = [0u8; BRIDGE_TO_SSH_CHUNK];
+    loop {
+        let n = match quiet {
+            Some(quiet) => match with_timeout(quiet, uart.read(&mut buf)).await {
+                Ok(n) => n,
+                Err(_) => return,
+            },
+            None => uart.read(&mut buf).await,
+        };
+        for &byte in &buf[..n] {
+            if byte != check.expected {
+                check.out_of_sequence += 1;
+            }
+            check.expected = next_in_pattern(byte);
+        }
+        check.received += n as u64;
+    }
+}
+
+/// Writes lines of test pattern to `out` for `secs` seconds while counting
+/// the bytes arriving on `input`.
+///
+/// # Errors
+/// Returns an error if writing to the channel fails.
+pub async fn ssh<R, W>(mut input: R, out: &mut W, secs: u64) -> Result<SshReport, sunset::Error>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+{
+    let mut sent = 0;
+    let mut received = 0;
+    let mut longest_stall = Duration::from_ticks(0);
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(secs);
+
+    let send = async {
+        let mut buf = [0u8; BRIDGE_TO_SSH_CHUNK];
+        let mut line = 0u32;
+        loop {
+            for chunk in buf.chunks_exact_mut(LINE_LEN) {
+                write_line(chunk, line);
+                line = line.wrapping_add(1);
+            }
+            let mut rest = &buf[..];
+            while !rest.is_empty() {
+                let waiting = Instant::now();
+                let written = match with_deadline(deadline, out.write(rest)).await {
+                    Ok(res) => res?,
+                    Err(_) => return Ok::<(), sunset::Error>(()),
+                };
+                longest_stall = longest_stall.max(waiting.elapsed());
+                sent += written as u64;
+                rest = &rest[written..];
+            }
+        }
+    };
+    if let Either::First(res) = select(send, sink(&mut input, &mut received)).await {
+        res?;
+    }
+
+    Ok(SshReport {
+        sent,
+        received,
+        longest_stall,
+        elapsed: start.elapsed(),
+    })
+}
+
+fn write_line(chunk: &mut [u8], line: u32) {
+    let mut number = String::<11>::new();
+    let _ = write!(number, "{line:010} ");
+    let (head, tail) = chunk.split_at_mut(number.len());
+    head.copy_from_slice(number.as_bytes());
+    let (body, end) = tail.split_at_mut(LINE_CHARS.len());
+    body.copy_from_slice(LINE_CHARS);
+    end.copy_from_slice(b"\r\n");
+}
+
+/// Counts the bytes arriving on `input`; never returns.
+async fn sink<R: Read<Error = sunset::Error>>(input: &mut R, received: &mut u64) {
+    let mut buf = [0u8; BRIDGE_TO_UART_CHUNK];
+    loop {
+        match input.read(&mut buf).await {
+            Ok(n) if n > 0 => *received += n as u64,
+            // Nothing more will come; keep the output going.
+            _ => core::future::pending::<()>().await,
+        }
+    }
+}
+
+/// Writes whatever arrives on `input` back to `out` until the client ends its
+/// input. Returns the bytes echoed.
+///
+/// # Errors
+/// Returns an error if writing to the channel fails.
+#[cfg(feature = "test-services")]
+pub async fn echo<R, W>(mut input: R, out: &mut W) -> Result<u64, sunset::Error>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+{
+    let mut buf = [0u8; BRIDGE_TO_SSH_CHUNK];
+    let mut echoed = 0;
+    loop {
+        match input.read(&mut buf).await {
+            Ok(n) if n > 0 => {
+                out.write_all(&buf[..n]).await?;
+                echoed += n as u64;
+            }
+            _ => return Ok(echoed),
+        }
+    }
+}
+
+/// Writes `bytes` of the `benchmark ssh` line pattern to `out`, the last
+/// line cut short where the count ends.
+///
+/// # Errors
+/// Returns an error if writing to the channel fails.
+#[cfg(feature = "test-services")]
+pub async fn chargen<W>(out: &mut W, bytes: u64) -> Result<(), sunset::Error>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut buf = [0u8; BRIDGE_TO_SSH_CHUNK];
+    let mut line = 0u32;
+    let mut left = bytes;
+    while left > 0 {
+        for chunk in buf.chunks_exact_mut(LINE_LEN) {
+            write_line(chunk, line);
+            line = line.wrapping_add(1);
+        }
+        let n = usize::try_from(left).map_or(buf.len(), |left| left.min(buf.len()));
+        out.write_all(&buf[..n]).await?;
+        left -= n as u64;
+    }
+    Ok(())
+}
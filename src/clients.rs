@@ -0,0 +1,245 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Clients that logged in, for auditing who reached a stamp and when.
+//!
+//! Each username and key pair that passes public key authentication gets a
+//! [`KnownClient`] with its last source address and the time it was last
+//! seen. The device has no wall clock, so times are a boot number (counted
+//! like the recorded boots of [`crate::resets`]) and seconds since that
+//! boot. The table is kept in flash next to the config; once
+//! [`KNOWN_CLIENT_SLOTS`] are in use the least recently seen entry makes
+//! room.
+//!
+//! To spare the flash, a login only triggers a save when it adds a client or
+//! a new source address; otherwise last-seen times are saved at most every
+//! [`CLIENT_SAVE_INTERVAL_SECS`], or with the next config save.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use ssh_key::HashAlg;
+use sunset::packets::{Ed25519PubKey, PubKey};
+use sunset::sshwire::{Blob, SSHDecode, SSHEncode, SSHSink, SSHSource, WireError, WireResult};
+
+use crate::config::wire_str;
+use crate::resets;
+use crate::sessions::truncated;
+use crate::settings::{CLIENT_SAVE_INTERVAL_SECS, KNOWN_CLIENT_SLOTS, USER_NAME_LEN};
+
+/// A username and key that logged in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownClient {
+    pub user: String<USER_NAME_LEN>,
+    /// Ed25519 public key.
+    pub key: [u8; 32],
+    /// Source address of the last login.
+    pub peer: Option<IpAddr>,
+    /// Boot number of the last login, 0 if unknown.
+    pub boot: u32,
+    /// Seconds after that boot.
+    pub uptime_secs: u64,
+    pub logins: u32,
+}
+
+impl KnownClient {
+    /// The key's `SHA256:...` fingerprint, as printed by `ssh-keygen -l`.
+    pub fn write_fingerprint(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        let key = PubKey::Ed25519(Ed25519PubKey {
+            key: Blob(self.key),
+        });
+        match key.fingerprint(HashAlg::Sha256) {
+            Ok(fp) => write!(f, "{fp}"),
+            Err(_) => f.write_str("(no fingerprint)"),
+        }
+    }
+}
+
+/// The persisted table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KnownClients(pub Vec<KnownClient, KNOWN_CLIENT_SLOTS>);
+
+impl KnownClients {
+    /// Largest encoding of a full table.
+    pub const MAX_ENCODED_LEN: usize =
+        1 + KNOWN_CLIENT_SLOTS * (wire_str(USER_NAME_LEN) + 32 + PEER_MAX_LEN + 4 + 8 + 4);
+}
+
+struct Registry {
+    clients: KnownClients,
+    /// A client or source address was added since the last save.
+    changed: bool,
+    /// Seconds since boot of the first last-seen update not saved yet.
+    unsaved_since: Option<u64>,
+}
+
+static REGISTRY: Mutex<CriticalSectionRawMutex, RefCell<Registry>> =
+    Mutex::new(RefCell::new(Registry {
+        clients: KnownClients(Vec::new()),
+        changed: false,
+        unsaved_since: None,
+    }));
+
+/// Records a login by `user` with `key` from `peer`.
+pub fn seen(user: &str, key: [u8; 32], peer: Option<IpAddr>) {
+    let now = Instant::now().as_secs();
+    let boot = resets::boot_number();
+    let user: String<USER_NAME_LEN> = truncated(user);
+    REGISTRY.lock(|registry| {
+        let mut guard = registry.borrow_mut();
+        let registry = &mut *guard;
+        let clients = &mut registry.clients.0;
+        let index = match clients.iter().position(|c| c.key == key && c.user == user) {
+            Some(index) => index,
+            None => {
+                if clients.is_full()
+                    && let Some((oldest, _)) = clients
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, c)| (c.boot, c.uptime_secs))
+                {
+                    clients.swap_remove(oldest);
+                }
+                let _ = clients.push(KnownClient {
+                    user,
+                    key,
+                    peer,
+                    boot,
+                    uptime_secs: now,
+                    logins: 0,
+                });
+                registry.changed = true;
+                clients.len() - 1
+            }
+        };
+        let client = &mut clients[index];
+        if client.peer != peer {
+            client.peer = peer;
+            registry.changed = true;
+        }
+        client.boot = boot;
+        client.uptime_secs = now;
+        client.logins = client.logins.saturating_add(1);
+        registry.unsaved_since.get_or_insert(now);
+    });
+}
+
+/// Whether the table should be written to flash now, see the module docs.
+#[must_use]
+pub fn needs_saving() -> bool {
+    let now = Instant::now().as_secs();
+    REGISTRY.lock(|registry| {
+        let registry = registry.borrow();
+        registry.changed
+            || registry
+                .unsaved_since
+                .is_some_and(|since| now.saturating_sub(since) >= CLIENT_SAVE_INTERVAL_SECS)
+    })
+}
+
+/// A copy of the table.
+#[must_use]
+pub fn snapshot() -> KnownClients {
+    REGISTRY.lock(|registry| registry.borrow().clients.clone())
+}
+
+/// Replaces the table with the one loaded from flash.
+pub(crate) fn restore(clients: KnownClients) {
+    REGISTRY.lock(|registry| {
+        let mut registry = registry.borrow_mut();
+        registry.clients = clients;
+        registry.changed = false;
+        registry.unsaved_since = None;
+    });
+}
+
+/// Notes that `saved` was written to flash.
+pub(crate) fn saved(saved: &KnownClients) {
+    REGISTRY.lock(|registry| {
+        let mut registry = registry.borrow_mut();
+        // A login may have slipped in while flash was written.
+        if registry.clients == *saved {
+            registry.changed = false;
+            registry.unsaved_since = None;
+        }
+    });
+}
+
+/// Largest encoding by [`enc_peer`].
+pub(crate) const PEER_MAX_LEN: usize = 1 + 16;
+
+// A private encoding, like the config's.
+pub(crate) fn enc_peer(v: Option<&IpAddr>, s: &mut dyn SSHSink) -> WireResult<()> {
+    match v {
+        None => 0u8.enc(s),
+        Some(IpAddr::V4(a)) => {
+            4u8.enc(s)?;
+            a.to_bits().enc(s)
+        }
+        Some(IpAddr::V6(a)) => {
+            6u8.enc(s)?;
+            a.octets().enc(s)
+        }
+    }
+}
+
+pub(crate) fn dec_peer<'de, S>(s: &mut S) -> WireResult<Option<IpAddr>>
+where
+    S: SSHSource<'de>,
+{
+    match u8::dec(s)? {
+        0 => Ok(None),
+        4 => Ok(Some(IpAddr::V4(Ipv4Addr::from_bits(SSHDecode::dec(s)?)))),
+        6 => {
+            let octets: [u8; 16] = SSHDecode::dec(s)?;
+            Ok(Some(IpAddr::V6(Ipv6Addr::from(octets))))
+        }
+        _ => Err(WireError::UnknownVariant),
+    }
+}
+
+impl SSHEncode for KnownClients {
+    fn enc(&self, s: &mut dyn SSHSink) -> WireResult<()> {
+        u8::try_from(self.0.len())
+            .map_err(|_| WireError::PacketWrong)?
+            .enc(s)?;
+        for c in &self.0 {
+            c.user.as_str().enc(s)?;
+            c.key.enc(s)?;
+            enc_peer(c.peer.as_ref(), s)?;
+            c.boot.enc(s)?;
+            c.uptime_secs.enc(s)?;
+            c.logins.enc(s)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> SSHDecode<'de> for KnownClients {
+    fn dec<S>(s: &mut S) -> WireResult<Self>
+    where
+        S: SSHSource<'de>,
+    {
+        let count: u8 = SSHDecode::dec(s)?;
+        let mut v = Vec::new();
+        for _ in 0..count {
+            let user: &str = SSHDecode::dec(s)?;
+            let client = KnownClient {
+                user: String::try_from(user).map_err(|_| WireError::BadString)?,
+                key: SSHDecode::dec(s)?,
+                peer: dec_peer(s)?,
+                boot: SSHDecode::dec(s)?,
+                uptime_secs: SSHDecode::dec(s)?,
+                logins: SSHDecode::dec(s)?,
+            };
+            v.push(client).map_err(|_| WireError::PacketWrong)?;
+        }
+        Ok(Self(v))
+    }
+}
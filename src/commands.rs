@@ -0,0 +1,2114 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Admin commands.
+//!
+//! An authenticated SSH `exec` request (`ssh root@192.168.4.1 <command>`)
+//! runs one of the commands below instead of opening the serial bridge. The
+//! output is written to the channel's stdout and the channel is closed once
+//! the command returns. Failures are reported as an `error: ...` line.
+
+use core::convert::Infallible;
+use core::fmt::{self, Write as _};
+use core::future::{self, Future};
+
+use base64ct::{Base64, Encoding};
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer, with_deadline, with_timeout};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+#[cfg(feature = "test-services")]
+use log::info;
+#[cfg(feature = "sftp-ota")]
+use ota::scratch::{self, Scratch};
+use portable_atomic::{AtomicU32, Ordering};
+use ssh_key::HashAlg;
+use ssh_stamp_hal::HalError;
+#[cfg(feature = "rmt")]
+use ssh_stamp_hal::{MAX_PULSE_MICROS, MAX_PULSES, Pulse};
+use sunset::SignKey;
+use sunset_async::SunsetMutex;
+
+use crate::benchmark;
+use crate::clients;
+use crate::config::{Role, SSHStampConfig};
+use crate::console;
+use crate::events;
+use crate::logging;
+use crate::ota_history;
+use crate::ota_window;
+use crate::outbox;
+#[cfg(feature = "peers")]
+use crate::peers;
+use crate::platform::PlatformServices;
+use crate::provisioning::{self, ProvisioningError};
+use crate::qr;
+use crate::registry::{self, Args, COMMANDS, Handler, ParseError, Parsed};
+use crate::resets::{self, RESET_RECORD_DELAY_SECS};
+#[cfg(feature = "scripts")]
+use crate::scripts::{self, ScriptError};
+use crate::serial::{self, BufferedSerial};
+use crate::sessions::{self, Algorithms, Negotiated};
+#[cfg(feature = "scripts")]
+use crate::settings::SCRIPT_LEN;
+use crate::settings::{
+    BENCHMARK_MAX_SECS, BENCHMARK_SECS, COMMAND_LINE_LEN, COMMAND_OUTPUT_LEN,
+    FLASH_RATED_ERASE_CYCLES, INTERLOCK_MAX_MINUTES, INTERLOCK_MINUTES, LOG_VERBOSE_MAX_MINUTES,
+    LOG_VERBOSE_MINUTES, MANIFEST_LEN, PROGRESS_INTERVAL_SECS, PROVISION_BUNDLE_LEN,
+    ROLLBACK_MINUTES, SEND_MAX_MS, UART_ERROR_ALERT_PER_MINUTE, UART_PARK_TIMEOUT_MS,
+};
+use crate::staging::{self, StagingError};
+use crate::stats;
+use crate::store;
+use crate::version;
+
+/// A command line as received in the `exec` request.
+pub type CommandLine = String<COMMAND_LINE_LEN>;
+
+/// Why a command did not run to completion.
+#[derive(Debug)]
+pub enum CommandError {
+    /// No command with that name in this build.
+    Unknown,
+    /// Wrong arguments; carries the command family, see
+    /// [`registry::Command::family`].
+    Usage(&'static str),
+    /// The named item is not configured.
+    NotFound,
+    /// The session's role does not allow this command; carries the least
+    /// role that does.
+    Forbidden(Role),
+    /// The platform refused or failed the operation.
+    Platform(HalError),
+    /// A staged configuration operation was refused.
+    Staging(StagingError),
+    /// A config backup could not be made or restored.
+    Backup(&'static str),
+    /// A provisioning bundle was refused.
+    Provisioning(ProvisioningError),
+    /// A script was refused.
+    #[cfg(feature = "scripts")]
+    Script(ScriptError),
+    /// A build manifest was refused or does not match this firmware.
+    Manifest(&'static str),
+    /// The UART bridge is out of service; carries the reason, see
+    /// [`serial::disable_bridge`].
+    BridgeDisabled(&'static str),
+    /// Writing the output to the channel failed.
+    Channel(sunset::Error),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown => write!(f, "unknown command"),
+            CommandError::Usage(family) => write!(f, "usage: see `help {family}`"),
+            CommandError::NotFound => write!(f, "not found"),
+            CommandError::Forbidden(role) => write!(f, "needs the {} role", role.name()),
+            CommandError::Platform(e) => write!(f, "{e}"),
+            CommandError::Staging(e) => write!(f, "{e}"),
+            CommandError::Backup(why) => write!(f, "{why}"),
+            CommandError::Provisioning(e) => write!(f, "{e}"),
+            #[cfg(feature = "scripts")]
+            CommandError::Script(e) => write!(f, "{e}"),
+            CommandError::Manifest(why) => write!(f, "{why}"),
+            CommandError::BridgeDisabled(why) => write!(f, "bridge disabled, {why}"),
+            CommandError::Channel(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<HalError> for CommandError {
+    fn from(e: HalError) -> Self {
+        CommandError::Platform(e)
+    }
+}
+
+impl From<StagingError> for CommandError {
+    fn from(e: StagingError) -> Self {
+        CommandError::Staging(e)
+    }
+}
+
+impl From<ProvisioningError> for CommandError {
+    fn from(e: ProvisioningError) -> Self {
+        CommandError::Provisioning(e)
+    }
+}
+
+#[cfg(feature = "scripts")]
+impl From<ScriptError> for CommandError {
+    fn from(e: ScriptError) -> Self {
+        CommandError::Script(e)
+    }
+}
+
+impl From<ParseError> for CommandError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Unknown => CommandError::Unknown,
+            ParseError::Usage(family) => CommandError::Usage(family),
+        }
+    }
+}
+
+impl From<sunset::Error> for CommandError {
+    fn from(e: sunset::Error) -> Self {
+        CommandError::Channel(e)
+    }
+}
+
+/// Runs `line` and writes its output to `out`. Only `benchmark`,
+/// `config restore` and `provision` read `input`; `benchmark`, `reboot` and `shutdown` use
+/// the bridge `uart`.
+///
+/// Commands are looked up in the [`registry`], which also gives the role
+/// each needs: `help` and `show` are open to every [`Role`], except
+/// `show qr`, which reveals the access point PSK; the other commands need
+/// [`Role::Admin`].
+///
+/// Returns whether the command succeeded, for the exit status of an `exec`
+/// request.
+///
+/// # Errors
+///
+/// Returns an error only if writing to the channel fails; command failures
+/// are reported to the client.
+pub async fn run<R, W, U, P>(
+    line: &str,
+    input: R,
+    out: &mut W,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    role: Role,
+) -> Result<bool, sunset::Error>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    let res = match registry::parse(line) {
+        Ok(parsed) if role < parsed.command.role => {
+            Err(CommandError::Forbidden(parsed.command.role))
+        }
+        Ok(parsed) => dispatch(parsed, input, out, uart, config, platform, role).await,
+        Err(e) => Err(e.into()),
+    };
+    let succeeded = res.is_ok();
+    match res {
+        Ok(()) => (),
+        Err(CommandError::Channel(e)) => return Err(e),
+        Err(CommandError::Usage(family)) => {
+            for command in COMMANDS.iter().filter(|c| c.family() == family) {
+                print(out, format_args!("usage: {}\r\n", command.usage())).await?;
+            }
+        }
+        Err(e) => print(out, format_args!("error: {e}\r\n")).await?,
+    }
+    out.flush().await?;
+    Ok(succeeded)
+}
+
+/// Prompt of [`admin_shell`].
+const PROMPT: &[u8] = b"ssh-stamp> ";
+
+/// An interactive admin console in place of the bridge, for shell sessions
+/// while [`serial::bridge_fault`] is set. Warns why the bridge is out, then
+/// reads lines from `input` with a [`LineReader`] and runs each like an
+/// `exec` request with [`run`], until `exit` or Ctrl-D.
+///
+/// # Errors
+///
+/// Returns an error only if the channel fails.
+pub async fn admin_shell<R, W, U, P>(
+    input: R,
+    out: &mut W,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    role: Role,
+    fault: &str,
+) -> Result<(), sunset::Error>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    print(
+        out,
+        format_args!("\r\n*** ssh-stamp: serial bridge disabled: {fault} ***\r\n"),
+    )
+    .await?;
+    print(
+        out,
+        format_args!("Fix the settings with `config set` and `config commit`, then `reboot`.\r\n"),
+    )
+    .await?;
+    print(
+        out,
+        format_args!("Admin console: `help` lists the commands, `exit` or Ctrl-D leaves.\r\n"),
+    )
+    .await?;
+    let mut lines = LineReader::new(input);
+    let mut line = CommandLine::new();
+    loop {
+        out.write_all(PROMPT).await?;
+        if !lines.read_line(out, &mut line).await? {
+            return Ok(());
+        }
+        match line.trim() {
+            "" => {}
+            "exit" | "quit" => return Ok(()),
+            command => {
+                run(command, lines.input(), out, uart, config, platform, role).await?;
+            }
+        }
+    }
+}
+
+/// Reads lines typed at an interactive prompt, for [`admin_shell`] and
+/// the `config` menu: echoes what is typed, erases with Backspace, and
+/// ends a line at CR, LF or CR LF.
+pub(crate) struct LineReader<R> {
+    input: R,
+    after_cr: bool,
+    buf: [u8; 32],
+    pos: usize,
+    len: usize,
+}
+
+impl<R: Read<Error = sunset::Error>> LineReader<R> {
+    pub(crate) fn new(input: R) -> Self {
+        Self {
+            input,
+            after_cr: false,
+            buf: [0; 32],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// The channel, for commands that read from it themselves.
+    pub(crate) fn input(&mut self) -> &mut R {
+        &mut self.input
+    }
+
+    /// Reads the next line into `line`, echoing it to `out`. Ctrl-C gives
+    /// an empty line; Ctrl-D on an empty line and the end of input give
+    /// `false`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error only if the channel fails.
+    pub(crate) async fn read_line<W>(
+        &mut self,
+        out: &mut W,
+        line: &mut CommandLine,
+    ) -> Result<bool, sunset::Error>
+    where
+        W: Write<Error = sunset::Error>,
+    {
+        line.clear();
+        loop {
+            if self.pos == self.len {
+                out.flush().await?;
+                self.len = self.input.read(&mut self.buf).await?;
+                self.pos = 0;
+                if self.len == 0 {
+                    return Ok(false);
+                }
+            }
+            let b = self.buf[self.pos];
+            self.pos += 1;
+            let cr = core::mem::replace(&mut self.after_cr, b == b'\r');
+            match b {
+                b'\n' if cr => {}
+                b'\r' | b'\n' => {
+                    out.write_all(b"\r\n").await?;
+                    return Ok(true);
+                }
+                // Ctrl-D on an empty line
+                0x04 if line.is_empty() => {
+                    out.write_all(b"\r\n").await?;
+                    return Ok(false);
+                }
+                // Ctrl-C
+                0x03 => {
+                    line.clear();
+                    out.write_all(b"^C\r\n").await?;
+                    return Ok(true);
+                }
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        out.write_all(b"\x08 \x08").await?;
+                    }
+                }
+                b' '..=b'~' => {
+                    if line.push(char::from(b)).is_ok() {
+                        out.write_all(&[b]).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Runs the [`Handler`] of a parsed command line.
+async fn dispatch<R, W, U, P>(
+    parsed: Parsed<'_>,
+    input: R,
+    out: &mut W,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    role: Role,
+) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    let args = &parsed.args;
+    match parsed.command.handler {
+        Handler::BenchmarkSsh => benchmark_ssh(input, out, benchmark_secs(args)?).await,
+        Handler::BenchmarkUart => benchmark_uart(out, uart, config, benchmark_secs(args)?).await,
+        handler @ (Handler::ConfigAbort
+        | Handler::ConfigBackup
+        | Handler::ConfigCommit
+        | Handler::ConfigConfirm
+        | Handler::ConfigList
+        | Handler::ConfigRestore
+        | Handler::ConfigSet
+        | Handler::ConfigValidate) => {
+            config_command(handler, args, input, out, config, platform).await
+        }
+        Handler::Console => show_console(out).await,
+        Handler::ConsoleBreak => console_break(out, uart).await,
+        Handler::Help => help(args.get(0), out, role).await,
+        Handler::Interlock => show_interlock(out).await,
+        Handler::InterlockOff => interlock_off(out).await,
+        Handler::InterlockOn => interlock_on(args, out).await,
+        Handler::LogVerbose => log_verbose(args, out, config).await,
+        Handler::ManifestCheck => manifest_check(input, out).await,
+        Handler::OtaConfirm => ota_confirm(out, platform).await,
+        Handler::OutboxClear => outbox_clear(out, config, platform).await,
+        Handler::Ping => ping(out).await,
+        Handler::Provision => provision(input, out, config, platform).await,
+        #[cfg(feature = "rmt")]
+        Handler::Pulse => pulse(args, out, platform).await,
+        Handler::Reboot => halt(out, uart, config, platform, Halt::Reboot).await,
+        #[cfg(feature = "sftp-ota")]
+        Handler::ScratchDelete => {
+            scratch_delete(args.get(0).ok_or(args.usage())?, out, platform).await
+        }
+        #[cfg(feature = "sftp-ota")]
+        Handler::ScratchList => scratch_list(out, platform).await,
+        #[cfg(feature = "scripts")]
+        Handler::ScriptDelete => {
+            script_delete(args.get(0).ok_or(args.usage())?, out, config, platform).await
+        }
+        #[cfg(feature = "scripts")]
+        Handler::ScriptList => script_list(out, config).await,
+        #[cfg(feature = "scripts")]
+        Handler::ScriptResult => script_result(out).await,
+        #[cfg(feature = "scripts")]
+        Handler::ScriptRun => script_run(args.get(0).ok_or(args.usage())?, out, uart, config).await,
+        #[cfg(feature = "scripts")]
+        Handler::ScriptSet => {
+            script_set(
+                args.get(0).ok_or(args.usage())?,
+                input,
+                out,
+                config,
+                platform,
+            )
+            .await
+        }
+        Handler::Send => send(args, out, uart).await,
+        Handler::ShowBuild => show_build(out, platform).await,
+        Handler::ShowClients => show_clients(out).await,
+        Handler::ShowEvents => show_events(out).await,
+        Handler::ShowFlash => show_flash(out).await,
+        Handler::ShowLog => show_log(out).await,
+        Handler::ShowOta => show_ota(out, config).await,
+        Handler::ShowOtaHistory => show_ota_history(out).await,
+        Handler::ShowOutbox => show_outbox(out).await,
+        #[cfg(feature = "peers")]
+        Handler::ShowPeers => show_peers(out).await,
+        Handler::ShowQr => show_qr(out, config).await,
+        Handler::ShowResets => show_resets(out).await,
+        Handler::ShowSessions => show_sessions(out).await,
+        Handler::ShowStats => show_stats(out).await,
+        Handler::ShowUart => show_uart(out).await,
+        Handler::ShowVersion => show_version(out, platform).await,
+        Handler::Shutdown => halt(out, uart, config, platform, Halt::PowerOff).await,
+        #[cfg(feature = "mux")]
+        Handler::Target => target(args.get(0), out, config, platform).await,
+        #[cfg(feature = "test-services")]
+        Handler::TestChargen => {
+            let kib: u32 = args.number(0)?.ok_or(args.usage())?;
+            test_chargen(out, kib).await
+        }
+        #[cfg(feature = "test-services")]
+        Handler::TestEcho => test_echo(input, out).await,
+    }
+}
+
+/// Formats `args` into a bounded buffer and writes it to `out`.
+pub(crate) async fn print<W>(out: &mut W, args: fmt::Arguments<'_>) -> Result<(), sunset::Error>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut line = String::<COMMAND_OUTPUT_LEN>::new();
+    // Truncated output is better than none.
+    let _ = line.write_fmt(args);
+    out.write_all(line.as_bytes()).await
+}
+
+/// Runs `op`, printing a `<what>: <n>s` line every
+/// [`PROGRESS_INTERVAL_SECS`] until it is done, or `<what>: <n>%` if it is
+/// known to take `total_secs`. A client watching a slow save or a long run
+/// then sees the device is still busy; quick operations print nothing.
+///
+/// Whole lines rather than a spinner, so the output reads the same in a
+/// log or a pipe. A flash erase stalls the whole executor, so no line
+/// appears during one, only during the waits around it. `op` runs to
+/// completion even if the client stops reading.
+async fn with_progress<W, T>(
+    out: &mut W,
+    what: &str,
+    total_secs: Option<u64>,
+    op: impl Future<Output = T>,
+) -> T
+where
+    W: Write<Error = sunset::Error>,
+{
+    match select(op, progress(out, what, total_secs)).await {
+        Either::First(done) => done,
+        Either::Second(never) => match never {},
+    }
+}
+
+/// The progress lines of [`with_progress`].
+async fn progress<W>(out: &mut W, what: &str, total_secs: Option<u64>) -> Infallible
+where
+    W: Write<Error = sunset::Error>,
+{
+    let started = Instant::now();
+    loop {
+        Timer::after_secs(PROGRESS_INTERVAL_SECS).await;
+        let secs = started.elapsed().as_secs();
+        let shown = match total_secs {
+            Some(total) => {
+                let percent = (secs * 100 / total.max(1)).min(99);
+                print(out, format_args!("{what}: {percent}%\r\n")).await
+            }
+            None => print(out, format_args!("{what}: {secs}s\r\n")).await,
+        };
+        if shown.is_err() || out.flush().await.is_err() {
+            // Nobody is listening; let the operation finish regardless.
+            return future::pending().await;
+        }
+    }
+}
+
+/// Width of the usage column of `help`.
+const HELP_USAGE_WIDTH: usize = 32;
+
+/// `help [<command>]`: list the commands `role` may run, or those of the
+/// `command` family.
+async fn help<W>(wanted: Option<&str>, out: &mut W, role: Role) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut listed = false;
+    for command in COMMANDS {
+        if wanted.is_some_and(|w| command.family() != w) || role < command.role {
+            continue;
+        }
+        let mut usage = String::<COMMAND_LINE_LEN>::new();
+        let _ = write!(usage, "{}", command.usage());
+        print(
+            out,
+            format_args!("{usage:<HELP_USAGE_WIDTH$} {}\r\n", command.summary),
+        )
+        .await?;
+        listed = true;
+    }
+    if !listed {
+        return Err(CommandError::NotFound);
+    }
+    Ok(())
+}
+
+/// Pings answered since boot, see [`ping`].
+static PINGS: AtomicU32 = AtomicU32::new(0);
+
+/// `ping`: one line with a counter, the boot number and the uptime. Touches
+/// neither the UART nor the flash, so a fleet monitor can time `ssh stamp
+/// ping` to check the whole SSH path cheaply; a counter that starts over or
+/// a boot number that moved tells it the stamp reset in between.
+async fn ping<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let seq = PINGS.fetch_add(1, Ordering::Relaxed) + 1;
+    print(
+        out,
+        format_args!(
+            "pong seq {seq} boot {} uptime {}s\r\n",
+            resets::boot_number(),
+            Instant::now().as_secs()
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `show version`: firmware, library and build details, see [`version`].
+async fn show_version<W, P>(out: &mut W, platform: &P) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    for line in version::text(platform).lines() {
+        print(out, format_args!("{line}\r\n")).await?;
+    }
+    Ok(())
+}
+
+/// `show build`: build details for host tooling as JSON, see
+/// [`version::write_json`].
+async fn show_build<W, P>(out: &mut W, platform: &P) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    out.write_all(version::build_info(platform).as_bytes())
+        .await?;
+    out.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// `show clients`: clients that logged in, most recent first, see
+/// [`clients`].
+async fn show_clients<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut known = clients::snapshot().0;
+    if known.is_empty() {
+        print(out, format_args!("no logins recorded\r\n")).await?;
+        return Ok(());
+    }
+    known.sort_unstable_by_key(|c| core::cmp::Reverse((c.boot, c.uptime_secs)));
+    for client in &known {
+        let mut fingerprint = String::<64>::new();
+        let _ = client.write_fingerprint(&mut fingerprint);
+        print(out, format_args!("{} {fingerprint}\r\n", client.user)).await?;
+        match client.peer {
+            Some(peer) => print(out, format_args!("  from {peer}")).await?,
+            None => print(out, format_args!("  from unknown")).await?,
+        }
+        print(
+            out,
+            format_args!(
+                ", boot {} at {}s, {} logins\r\n",
+                client.boot, client.uptime_secs, client.logins
+            ),
+        )
+        .await?;
+    }
+    print(
+        out,
+        format_args!("this is boot {}\r\n", resets::boot_number()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `show peers`: the other stamps that answered, by name, see [`peers`].
+#[cfg(feature = "peers")]
+async fn show_peers<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut found = peers::snapshot();
+    if found.is_empty() {
+        print(out, format_args!("no peers found\r\n")).await?;
+        return Ok(());
+    }
+    found.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    let now = Instant::now().as_secs();
+    for peer in &found {
+        print(
+            out,
+            format_args!(
+                "{} {}, seen {}s ago\r\n",
+                peer.name,
+                peer.addr,
+                now.saturating_sub(peer.seen_secs)
+            ),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// `show ota history`: the recorded uploads, newest first, see
+/// [`ota_history`].
+async fn show_ota_history<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let history = ota_history::snapshot().0;
+    if history.is_empty() {
+        print(out, format_args!("no uploads recorded\r\n")).await?;
+        return Ok(());
+    }
+    for attempt in history.iter().rev() {
+        print(out, format_args!("{attempt}\r\n")).await?;
+    }
+    print(
+        out,
+        format_args!("this is boot {}\r\n", resets::boot_number()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `show outbox`: the events kept in flash until taken, oldest first, see
+/// [`outbox`].
+async fn show_outbox<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let outbox = outbox::snapshot();
+    if outbox.pending.is_empty() {
+        print(out, format_args!("no events kept\r\n")).await?;
+    }
+    for pending in &outbox.pending {
+        print(out, format_args!("{pending}\r\n")).await?;
+    }
+    if outbox.dropped > 0 {
+        print(
+            out,
+            format_args!("{} more dropped, the outbox was full\r\n", outbox.dropped),
+        )
+        .await?;
+    }
+    print(
+        out,
+        format_args!("this is boot {}\r\n", resets::boot_number()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `show qr`: a QR code joining the access point, and what to check on
+/// first connect, see [`qr`].
+async fn show_qr<W>(out: &mut W, config: &SunsetMutex<SSHStampConfig>) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    // Copied out so the config is not locked while writing to the channel.
+    let (ssid, payload, address, fingerprint, ap_on) = {
+        let config = config.lock().await;
+        let fingerprint = match config.hostkey {
+            SignKey::Ed25519(_) => config.hostkey.pubkey().fingerprint(HashAlg::Sha256).ok(),
+            SignKey::AgentEd25519(_) => None,
+        };
+        (
+            config.wifi_ap_ssid.clone(),
+            qr::wifi_payload(&config.wifi_ap_ssid, &config.wifi_ap_pw),
+            config.ap_address,
+            fingerprint,
+            config.wifi_sta_ssid.is_empty() || config.wifi_concurrent,
+        )
+    };
+    let mut temp = [0u8; qr::QR_BUFFER_LEN];
+    let mut buf = [0u8; qr::QR_BUFFER_LEN];
+    let Some(code) = qr::encode(&payload, &mut temp, &mut buf) else {
+        // Cannot happen with a valid SSID and PSK.
+        return Err(CommandError::NotFound);
+    };
+    for line in qr::lines(&code) {
+        out.write_all(line.as_bytes()).await?;
+        out.write_all(b"\r\n").await?;
+    }
+    print(
+        out,
+        format_args!("joins {ssid}, then: ssh root@{address}\r\n"),
+    )
+    .await?;
+    if let Some(fingerprint) = fingerprint {
+        print(out, format_args!("host key {fingerprint}\r\n")).await?;
+    }
+    if !ap_on {
+        print(
+            out,
+            format_args!("the access point is off while the station is configured\r\n"),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// `show flash`: flash wear counters, see [`store::FlashStats`].
+async fn show_flash<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let usage = store::usage();
+    let erases = usage.stats.config_erases;
+    // Hundredths of a percent of the rated endurance.
+    let worn = u64::from(erases) * 10_000 / u64::from(FLASH_RATED_ERASE_CYCLES);
+    print(
+        out,
+        format_args!(
+            "config sector erases: {erases} ({}.{:02}% of {FLASH_RATED_ERASE_CYCLES} rated)\r\n",
+            worn / 100,
+            worn % 100
+        ),
+    )
+    .await?;
+    if usage.on_reserve {
+        print(
+            out,
+            format_args!(
+                "config sector:        reserve at {:#x}, the first one failed\r\n",
+                store::CONFIG_RESERVE_OFFSET
+            ),
+        )
+        .await?;
+    }
+    print(
+        out,
+        format_args!("OTA images written:   {}\r\n", usage.stats.ota_writes),
+    )
+    .await?;
+    print(
+        out,
+        format_args!("saves since boot:     {}", usage.saves_since_boot),
+    )
+    .await?;
+    match usage.last_save_secs {
+        Some(secs) => print(out, format_args!(", last at {secs}s\r\n")).await?,
+        None => print(out, format_args!("\r\n")).await?,
+    }
+    Ok(())
+}
+
+/// `show ota`: whether the running firmware awaits confirmation and how
+/// long it has left, see [`ota_window`].
+async fn show_ota<W>(out: &mut W, config: &SunsetMutex<SSHStampConfig>) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    match ota_window::pending() {
+        None => print(out, format_args!("firmware: confirmed\r\n")).await?,
+        Some(pending) => {
+            print(
+                out,
+                format_args!("firmware: unconfirmed, boot {}", pending.boot),
+            )
+            .await?;
+            if pending.window.boots > 0 {
+                print(out, format_args!(" of {}", pending.window.boots)).await?;
+            }
+            print(out, format_args!("\r\n")).await?;
+            if let Some(left) = pending.remaining() {
+                let secs = left.as_secs();
+                print(
+                    out,
+                    format_args!(
+                        "rolls back in {}m{:02}s unless `ota confirm`\r\n",
+                        secs / 60,
+                        secs % 60
+                    ),
+                )
+                .await?;
+            } else {
+                print(
+                    out,
+                    format_args!("rolls back after the last boot unless `ota confirm`\r\n"),
+                )
+                .await?;
+            }
+        }
+    }
+    let window = config.lock().await.ota_confirm;
+    match (window.minutes, window.boots) {
+        (0, 0) => print(out, format_args!("window: off\r\n")).await?,
+        (0, boots) => print(out, format_args!("window: {boots} boots\r\n")).await?,
+        (minutes, 0) => print(out, format_args!("window: {minutes} min per boot\r\n")).await?,
+        (minutes, boots) => {
+            print(
+                out,
+                format_args!("window: {minutes} min per boot, {boots} boots\r\n"),
+            )
+            .await?;
+        }
+    }
+    print(
+        out,
+        format_args!(
+            "OTA images written: {}\r\n",
+            store::usage().stats.ota_writes
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `show stats`: what the bridge lost, see [`stats`].
+async fn show_stats<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let snapshot = stats::snapshot();
+    print(
+        out,
+        format_args!("sessions:   {} since boot\r\n", snapshot.sessions),
+    )
+    .await?;
+    if let Some(session) = snapshot.session {
+        print(
+            out,
+            format_args!(
+                "session:    {session} (started at {}s)\r\n",
+                snapshot.session_start_secs
+            ),
+        )
+        .await?;
+    }
+    print(out, format_args!("since boot: {}\r\n", snapshot.since_boot)).await?;
+    match snapshot.last_loss_secs {
+        Some(secs) => {
+            print(
+                out,
+                format_args!(
+                    "last loss:  at {secs}s, uptime now {}s\r\n",
+                    Instant::now().as_secs()
+                ),
+            )
+            .await?;
+        }
+        None => print(out, format_args!("last loss:  none\r\n")).await?,
+    }
+    Ok(())
+}
+
+/// `show uart`: receive error counters, see [`serial::record_line_error`].
+async fn show_uart<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    if let Some(fault) = serial::bridge_fault() {
+        print(out, format_args!("bridge:         disabled, {fault}\r\n")).await?;
+    }
+    #[cfg(feature = "tx-sense")]
+    if let Some(swapped) = serial::tx_contention() {
+        let hint = if swapped {
+            ", RX and TX look swapped"
+        } else {
+            ""
+        };
+        print(
+            out,
+            format_args!("TX pin:         driven by the target at boot{hint}\r\n"),
+        )
+        .await?;
+    }
+    if serial::line_stuck() {
+        print(
+            out,
+            format_args!("RX line:        stuck, target off or miswired? Polling for recovery\r\n"),
+        )
+        .await?;
+    }
+    let stats = serial::line_stats();
+    print(out, format_args!("receive errors: {stats}\r\n")).await?;
+    print(
+        out,
+        format_args!(
+            "last minute:    {} (alert above {UART_ERROR_ALERT_PER_MINUTE})\r\n",
+            stats.last_minute
+        ),
+    )
+    .await?;
+    print(
+        out,
+        format_args!(
+            "discarded:      {} bytes while resyncing\r\n",
+            stats.discarded_bytes
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `log verbose [<minutes>]`: lift log redaction for a while, see
+/// [`logging::Redacted`].
+async fn log_verbose<W>(
+    args: &Args<'_>,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let minutes = match args.number(0)? {
+        Some(minutes) if (1..=LOG_VERBOSE_MAX_MINUTES).contains(&minutes) => minutes,
+        Some(_) => return Err(args.usage().into()),
+        None => LOG_VERBOSE_MINUTES,
+    };
+    if !config.lock().await.log_redact {
+        print(out, format_args!("log redaction is off\r\n")).await?;
+        return Ok(());
+    }
+    logging::verbose_for(Duration::from_secs(minutes * 60));
+    log::warn!(
+        "Log redaction lifted for {minutes} minutes by {}",
+        sessions::current_user().unwrap_or_default()
+    );
+    print(
+        out,
+        format_args!("secrets shown in the log for {minutes} minutes\r\n"),
+    )
+    .await?;
+    Ok(())
+}
+
+const NOT_A_MANIFEST: CommandError = CommandError::Manifest("not an ssh-stamp build manifest");
+
+/// `manifest check`: read a build manifest from `input` until end of file
+/// and compare the entries this firmware records, see
+/// [`version::build_fact`]; the image, bootloader and partition table
+/// hashes are left to the host. Fails if an entry differs.
+async fn manifest_check<R, W>(mut input: R, out: &mut W) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+{
+    let mut text = [0u8; MANIFEST_LEN];
+    let mut len = 0;
+    loop {
+        if len == text.len() {
+            return Err(CommandError::Manifest("manifest too long"));
+        }
+        let n = match input.read(&mut text[len..]).await {
+            Ok(0) | Err(sunset::Error::ChannelEOF) => break,
+            Ok(n) => n,
+            Err(e) => return Err(e.into()),
+        };
+        len += n;
+    }
+    let text = core::str::from_utf8(&text[..len]).map_err(|_| NOT_A_MANIFEST)?;
+    if text.lines().next().map(|l| l.trim_end_matches('\r')) != Some(ota::manifest::HEADER) {
+        return Err(NOT_A_MANIFEST);
+    }
+    let mut differ = false;
+    for (key, value) in ota::manifest::entries(text).skip(1) {
+        match version::build_fact(key) {
+            Some(fact) if fact == value => print(out, format_args!("{key}: ok\r\n")).await?,
+            Some(fact) => {
+                differ = true;
+                print(
+                    out,
+                    format_args!("{key}: differs, this firmware has {fact}\r\n"),
+                )
+                .await?;
+            }
+            None => print(out, format_args!("{key}: not checked\r\n")).await?,
+        }
+    }
+    if differ {
+        return Err(CommandError::Manifest(
+            "manifest does not match this firmware",
+        ));
+    }
+    Ok(())
+}
+
+/// `interlock`: whether bridge input is held, see [`serial::hold_input`].
+async fn show_interlock<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    match serial::input_held_until() {
+        Some(until) => {
+            let secs = (until - Instant::now()).as_secs();
+            print(out, format_args!("input held for another {secs}s\r\n")).await?;
+        }
+        None => print(out, format_args!("input not held\r\n")).await?,
+    }
+    Ok(())
+}
+
+/// `interlock on [<minutes>]`: hold the bridge's input while the target is
+/// in a critical section, see [`serial::hold_input`].
+async fn interlock_on<W>(args: &Args<'_>, out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let minutes = match args.number(0)? {
+        Some(minutes) if (1..=INTERLOCK_MAX_MINUTES).contains(&minutes) => minutes,
+        Some(_) => return Err(args.usage().into()),
+        None => INTERLOCK_MINUTES,
+    };
+    serial::hold_input(Duration::from_secs(minutes * 60));
+    log::warn!(
+        "Bridge input held for {minutes} minutes by {}",
+        sessions::current_user().unwrap_or_default()
+    );
+    print(
+        out,
+        format_args!("bridge input held for {minutes} minutes, `interlock off` releases it\r\n"),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `interlock off`: deliver bridge input again.
+async fn interlock_off<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    if serial::input_held_until().is_none() {
+        print(out, format_args!("input not held\r\n")).await?;
+        return Ok(());
+    }
+    serial::release_input();
+    log::info!(
+        "Bridge input released by {}",
+        sessions::current_user().unwrap_or_default()
+    );
+    print(out, format_args!("bridge input released\r\n")).await?;
+    Ok(())
+}
+
+/// `console`: the target console guessed from its output, see [`console`].
+async fn show_console<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    match console::detected() {
+        Some(kind) => {
+            print(
+                out,
+                format_args!(
+                    "{kind}, break sequence \"{}\"\r\n",
+                    kind.break_sequence().escape_ascii()
+                ),
+            )
+            .await?;
+        }
+        None => {
+            print(
+                out,
+                format_args!("unknown, not recognised in the output yet\r\n"),
+            )
+            .await?
+        }
+    }
+    Ok(())
+}
+
+/// `console break`: send the guessed console's break sequence to the UART.
+async fn console_break<W, U>(out: &mut W, uart: &U) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+{
+    let kind = console::detected().ok_or(CommandError::NotFound)?;
+    let Ok(()) = uart.handle().write_all(kind.break_sequence()).await;
+    print(out, format_args!("sent the {kind} break sequence\r\n")).await?;
+    Ok(())
+}
+
+/// `send <ms> <text>`: write `text`, escapes decoded (see
+/// [`serial::unescape`]), to the UART and copy what the target prints to
+/// `out` for `ms` milliseconds, for one-shot scripted exchanges such as
+/// `ssh stamp send 500 'AT+RST\r'`. Words of `text` are joined by single
+/// spaces. Output that arrived before is discarded, so only the answer is
+/// printed, unmasked as on the bridge.
+async fn send<W, U>(args: &Args<'_>, out: &mut W, uart: &U) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+{
+    let ms: u64 = args.number(0)?.ok_or(args.usage())?;
+    if ms > SEND_MAX_MS {
+        return Err(args.usage().into());
+    }
+    let mut line = String::<COMMAND_LINE_LEN>::new();
+    for (i, word) in args.rest(1).enumerate() {
+        if i > 0 {
+            line.push(' ').map_err(|_| args.usage())?;
+        }
+        line.push_str(word).map_err(|_| args.usage())?;
+    }
+    let text: heapless::Vec<u8, COMMAND_LINE_LEN> =
+        serial::unescape(&line).map_err(|_| args.usage())?;
+    if let Some(fault) = serial::bridge_fault() {
+        return Err(CommandError::BridgeDisabled(fault));
+    }
+
+    let mut buf = [0u8; COMMAND_OUTPUT_LEN];
+    let mut uart_io = uart.handle();
+    while let Ok(Ok(_)) = with_timeout(Duration::from_ticks(0), uart_io.read(&mut buf)).await {}
+    let Ok(()) = uart_io.write_all(&text).await;
+    let end = Instant::now() + Duration::from_millis(ms);
+    while let Ok(Ok(n)) = with_deadline(end, uart_io.read(&mut buf)).await {
+        out.write_all(&buf[..n]).await?;
+    }
+    Ok(())
+}
+
+/// `show resets`: this boot's reset reason and the recorded count per
+/// reason, see [`resets`].
+async fn show_resets<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    match resets::boot_reason() {
+        Some(reason) => print(out, format_args!("last reset: {reason}\r\n")).await?,
+        None => print(out, format_args!("last reset: unknown\r\n")).await?,
+    }
+    print(
+        out,
+        format_args!("recorded boots (after {RESET_RECORD_DELAY_SECS}s up):\r\n"),
+    )
+    .await?;
+    for (reason, count) in store::usage().stats.resets.iter() {
+        print(out, format_args!("  {:<10} {count}\r\n", reason.name())).await?;
+    }
+    Ok(())
+}
+
+/// `show log`: dump the in-memory log ring, oldest first.
+async fn show_log<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    // Stop at the current end; writing to the channel may itself log.
+    let end = logging::end();
+    let mut pos = 0;
+    let mut buf = [0u8; COMMAND_OUTPUT_LEN];
+    while pos < end {
+        let want = usize::try_from(end - pos).map_or(buf.len(), |left| left.min(buf.len()));
+        let (start, n) = logging::read(pos, &mut buf[..want]);
+        if n == 0 {
+            break;
+        }
+        out.write_all(&buf[..n]).await?;
+        pos = start + n as u64;
+    }
+    Ok(())
+}
+
+/// `show events`: list the in-memory event log, oldest first.
+async fn show_events<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut index = 0;
+    while let Some(record) = events::get(index) {
+        index += 1;
+        print(
+            out,
+            format_args!("[{:>6}s] {}\r\n", record.uptime_secs, record.event),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// `show sessions`: the current and recent SSH sessions with the client
+/// version and negotiated algorithms.
+async fn show_sessions<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut index = 0;
+    while let Some(session) = sessions::get(index) {
+        index += 1;
+        print(out, format_args!("#{} ", session.id)).await?;
+        match session.peer {
+            Some(peer) => print(out, format_args!("{peer}")).await?,
+            None => print(out, format_args!("(unknown peer)")).await?,
+        }
+        if let Some(interface) = session.interface {
+            print(out, format_args!(" via {interface}")).await?;
+        }
+        let started = session.started_secs;
+        match session.ended_secs {
+            Some(ended) => print(out, format_args!(" {started}s-{ended}s\r\n")).await?,
+            None => print(out, format_args!(" since {started}s, active\r\n")).await?,
+        }
+        if !session.client_version.is_empty() {
+            print(
+                out,
+                format_args!("  client:  {}\r\n", session.client_version),
+            )
+            .await?;
+        }
+        if !session.user.is_empty() {
+            print(out, format_args!("  user:    {}\r\n", session.user)).await?;
+        }
+        let Some(a) = session.algorithms else {
+            continue;
+        };
+        print(out, format_args!("  kex:     {}\r\n", Negotiated(&a.kex))).await?;
+        print(
+            out,
+            format_args!("  hostkey: {}\r\n", Negotiated(&a.hostkey)),
+        )
+        .await?;
+        if a.cipher_c2s == a.cipher_s2c {
+            print(
+                out,
+                format_args!("  cipher:  {}\r\n", Negotiated(&a.cipher_c2s)),
+            )
+            .await?;
+        } else {
+            print(
+                out,
+                format_args!(
+                    "  cipher:  {} (in) {} (out)\r\n",
+                    Negotiated(&a.cipher_c2s),
+                    Negotiated(&a.cipher_s2c)
+                ),
+            )
+            .await?;
+        }
+        let aead = Algorithms::is_aead(a.cipher_c2s.as_ref());
+        if aead && Algorithms::is_aead(a.cipher_s2c.as_ref()) {
+            print(out, format_args!("  mac:     (implicit)\r\n")).await?;
+        } else if a.mac_c2s == a.mac_s2c {
+            print(
+                out,
+                format_args!("  mac:     {}\r\n", Negotiated(&a.mac_c2s)),
+            )
+            .await?;
+        } else {
+            print(
+                out,
+                format_args!(
+                    "  mac:     {} (in) {} (out)\r\n",
+                    Negotiated(&a.mac_c2s),
+                    Negotiated(&a.mac_s2c)
+                ),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// The `<seconds>` of `benchmark <uart|ssh> [<seconds>]`, see [`benchmark`].
+fn benchmark_secs(args: &Args<'_>) -> Result<u64, CommandError> {
+    match args.number(0)? {
+        Some(secs) if (1..=BENCHMARK_MAX_SECS).contains(&secs) => Ok(secs),
+        Some(_) => Err(args.usage().into()),
+        None => Ok(BENCHMARK_SECS),
+    }
+}
+
+async fn benchmark_uart<W, U>(
+    out: &mut W,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+    secs: u64,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+{
+    // The device-wide settings, not those a bridge session left behind.
+    let uart_config = config.lock().await.uart_config();
+    let baud_rate = uart_config.baud_rate;
+    uart.reconfigure(uart_config).await;
+    print(
+        out,
+        format_args!("uart: sending a test pattern at {baud_rate} baud for {secs}s, TX must be wired to RX\r\n"),
+    )
+    .await?;
+    out.flush().await?;
+
+    let report = with_progress(out, "uart", Some(secs), benchmark::uart(uart, secs)).await;
+    let rate = benchmark::per_sec(report.sent, report.elapsed);
+    // 8N1: ten bits on the wire per byte.
+    let line_rate = u64::from(baud_rate / 10).max(1);
+    print(
+        out,
+        format_args!(
+            "sent:     {} bytes in {} ms, {rate} B/s ({}% of line rate)\r\n",
+            report.sent,
+            report.elapsed.as_millis(),
+            rate * 100 / line_rate
+        ),
+    )
+    .await?;
+    if report.received == 0 {
+        print(
+            out,
+            format_args!("received: nothing, is TX wired to RX?\r\n"),
+        )
+        .await?;
+        return Ok(());
+    }
+    let lost = report.lost();
+    // Hundredths of a percent of the bytes sent.
+    let lost_share = lost * 10_000 / report.sent.max(1);
+    print(
+        out,
+        format_args!(
+            "received: {} bytes, {lost} lost ({}.{:02}%), {} out of sequence\r\n",
+            report.received,
+            lost_share / 100,
+            lost_share % 100,
+            report.out_of_sequence
+        ),
+    )
+    .await?;
+    print(
+        out,
+        format_args!(
+            "receive buffer overflowed by {} bytes, {} line errors\r\n",
+            report.overflowed, report.line_errors
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn benchmark_ssh<R, W>(input: R, out: &mut W, secs: u64) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+{
+    let report = benchmark::ssh(input, out, secs).await?;
+    print(
+        out,
+        format_args!(
+            "\r\nssh: sent {} bytes in {} ms, {} B/s, longest stall {} ms\r\n",
+            report.sent,
+            report.elapsed.as_millis(),
+            benchmark::per_sec(report.sent, report.elapsed),
+            report.longest_stall.as_millis()
+        ),
+    )
+    .await?;
+    print(
+        out,
+        format_args!(
+            "ssh: received {} bytes, {} B/s\r\n",
+            report.received,
+            benchmark::per_sec(report.received, report.elapsed)
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `test chargen <kib>`: `kib` KiB of test pattern, see
+/// [`benchmark::chargen`]. The rate only goes to the log, so the channel
+/// carries the data alone.
+#[cfg(feature = "test-services")]
+async fn test_chargen<W>(out: &mut W, kib: u32) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let bytes = u64::from(kib) * 1024;
+    let start = Instant::now();
+    benchmark::chargen(out, bytes).await?;
+    let elapsed = start.elapsed();
+    info!(
+        "test chargen: sent {bytes} bytes in {} ms, {} B/s",
+        elapsed.as_millis(),
+        benchmark::per_sec(bytes, elapsed)
+    );
+    Ok(())
+}
+
+/// `test echo`: echo the client's input, see [`benchmark::echo`].
+#[cfg(feature = "test-services")]
+async fn test_echo<R, W>(input: R, out: &mut W) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+{
+    let start = Instant::now();
+    let echoed = benchmark::echo(input, out).await?;
+    let elapsed = start.elapsed();
+    info!(
+        "test echo: echoed {echoed} bytes in {} ms, {} B/s",
+        elapsed.as_millis(),
+        benchmark::per_sec(echoed, elapsed)
+    );
+    Ok(())
+}
+
+/// `config ...`: stage configuration changes and apply them together, see
+/// [`staging`]. Without arguments, list the staged keys and whether a commit
+/// awaits confirmation. `backup` and `restore` save and load the whole
+/// config, see [`config_backup`].
+async fn config_command<R, W, P>(
+    handler: Handler,
+    args: &Args<'_>,
+    input: R,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    match handler {
+        Handler::ConfigList => {
+            let keys = staging::keys().await;
+            if keys.is_empty() {
+                print(out, format_args!("nothing staged\r\n")).await?;
+            }
+            for key in keys {
+                print(out, format_args!("staged {key}\r\n")).await?;
+            }
+            let pending = config.lock().await.rollback.as_ref().map(|r| r.minutes);
+            if let Some(minutes) = pending {
+                print(
+                    out,
+                    format_args!("unconfirmed commit, rolls back {minutes} min after boot\r\n"),
+                )
+                .await?;
+            }
+            Ok(())
+        }
+        Handler::ConfigSet => {
+            let key = args.get(0).ok_or(args.usage())?;
+            staging::set(config, key, args.get(1).unwrap_or("")).await?;
+            print(out, format_args!("staged {key}\r\n")).await?;
+            Ok(())
+        }
+        Handler::ConfigValidate => {
+            let problems = staging::validate(sessions::current_peer()).await?;
+            report_problems(out, &problems).await?;
+            if problems.is_empty() {
+                print(out, format_args!("ok\r\n")).await?;
+            }
+            Ok(())
+        }
+        Handler::ConfigCommit => {
+            let minutes = args.number(0)?.unwrap_or(ROLLBACK_MINUTES);
+            let peer = sessions::current_peer();
+            let commit = staging::commit(config, platform, peer, minutes);
+            let committed = match with_progress(out, "committing", None, commit).await {
+                Err(StagingError::Invalid) => {
+                    if let Ok(problems) = staging::validate(peer).await {
+                        report_problems(out, &problems).await?;
+                    }
+                    return Err(StagingError::Invalid.into());
+                }
+                res => res?,
+            };
+            if let Some(minutes) = committed.rollback_minutes {
+                print(
+                    out,
+                    format_args!(
+                        "reconnect and run `config confirm` within {minutes} min, or the network settings are rolled back\r\n"
+                    ),
+                )
+                .await?;
+            }
+            if committed.needs_reset {
+                print(out, format_args!("committed, resetting\r\n")).await?;
+                out.flush().await?;
+                // Give the reply a moment to leave before the radio goes.
+                Timer::after_millis(500).await;
+                platform.reset();
+            }
+            print(out, format_args!("committed\r\n")).await?;
+            Ok(())
+        }
+        Handler::ConfigConfirm => {
+            with_progress(out, "confirming", None, staging::confirm(config, platform)).await?;
+            print(out, format_args!("confirmed\r\n")).await?;
+            Ok(())
+        }
+        Handler::ConfigAbort => {
+            staging::abort().await?;
+            print(out, format_args!("aborted\r\n")).await?;
+            Ok(())
+        }
+        Handler::ConfigBackup => config_backup(out, config).await,
+        Handler::ConfigRestore => config_restore(input, out, config, platform).await,
+        _ => Err(args.usage().into()),
+    }
+}
+
+/// Bytes per line of `config backup` output, 76 base64 characters.
+const BACKUP_LINE_BYTES: usize = 57;
+
+const BAD_BACKUP: CommandError =
+    CommandError::Backup("not a backup from this firmware version, or damaged");
+
+/// `config backup`: print the config, admin keys and known clients as
+/// stored in flash, base64 encoded, for `config restore`. The output holds
+/// the host key and every password: keep it as safe as the device.
+async fn config_backup<W>(
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut backup = [0u8; store::BACKUP_SIZE];
+    let len = store::write_backup(&*config.lock().await, &mut backup)
+        .map_err(|_| CommandError::Backup("cannot encode the config"))?;
+    for chunk in backup[..len].chunks(BACKUP_LINE_BYTES) {
+        let mut line = [0u8; BACKUP_LINE_BYTES / 3 * 4];
+        let text = Base64::encode(chunk, &mut line)
+            .map_err(|_| CommandError::Backup("cannot encode the config"))?;
+        print(out, format_args!("{text}\r\n")).await?;
+    }
+    Ok(())
+}
+
+/// `config restore`: read a `config backup` from `input` until end of
+/// file, check it, save it and reset into it. Line breaks and other white
+/// space are ignored. The flash wear counters are this device's own and are
+/// kept.
+async fn config_restore<R, W, P>(
+    mut input: R,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    let mut backup = [0u8; store::BACKUP_SIZE];
+    let mut len = 0;
+    // Base64 is decoded four characters at a time; padding ends it.
+    let mut quad = [0u8; 4];
+    let mut have = 0;
+    let mut padded = false;
+    let mut buf = [0u8; 64];
+    loop {
+        let n = match input.read(&mut buf).await {
+            Ok(0) | Err(sunset::Error::ChannelEOF) => break,
+            Ok(n) => n,
+            Err(e) => return Err(e.into()),
+        };
+        for &c in buf[..n].iter().filter(|c| !c.is_ascii_whitespace()) {
+            if padded {
+                return Err(BAD_BACKUP);
+            }
+            quad[have] = c;
+            have += 1;
+            if have == quad.len() {
+                have = 0;
+                let bytes = Base64::decode(quad, &mut backup[len..]).map_err(|_| BAD_BACKUP)?;
+                padded = bytes.len() < 3;
+                len += bytes.len();
+            }
+        }
+    }
+    if have != 0 || len == 0 {
+        return Err(BAD_BACKUP);
+    }
+    let (restored, known) = store::read_backup(&backup[..len]).map_err(|_| BAD_BACKUP)?;
+
+    let mut current = config.lock().await;
+    clients::restore(known);
+    platform.save_config(&restored).await?;
+    *current = restored;
+    drop(current);
+    print(out, format_args!("restored, resetting\r\n")).await?;
+    out.flush().await?;
+    // Give the reply a moment to leave before the radio goes.
+    Timer::after_millis(500).await;
+    platform.reset();
+}
+
+/// `provision`: read a signed bundle from `input` until end of file, apply
+/// it as described in [`provisioning`] and reset into it.
+async fn provision<R, W, P>(
+    mut input: R,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    let mut bundle = [0u8; PROVISION_BUNDLE_LEN];
+    let mut len = 0;
+    loop {
+        if len == bundle.len() {
+            return Err(ProvisioningError::Malformed.into());
+        }
+        match input.read(&mut bundle[len..]).await {
+            Ok(0) | Err(sunset::Error::ChannelEOF) => break,
+            Ok(n) => len += n,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let apply = provisioning::apply_bundle(config, platform, &bundle[..len]);
+    with_progress(out, "provisioning", None, apply).await?;
+    print(out, format_args!("provisioned, resetting\r\n")).await?;
+    out.flush().await?;
+    // Give the reply a moment to leave before the radio goes.
+    Timer::after_millis(500).await;
+    platform.reset();
+}
+
+/// `ota confirm`: keep the running firmware, see [`ota_window`].
+async fn ota_confirm<W, P>(out: &mut W, _platform: &P) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    if ota_window::pending().is_none() {
+        print(out, format_args!("firmware already confirmed\r\n")).await?;
+        return Ok(());
+    }
+    let confirm = ota_window::confirm::<P::OtaWriter>();
+    with_progress(out, "confirming", None, confirm).await?;
+    print(out, format_args!("firmware confirmed\r\n")).await?;
+    Ok(())
+}
+
+/// `outbox clear`: drop the events kept in flash, e.g. after reading them
+/// with `show outbox`.
+async fn outbox_clear<W, P>(
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    let taken = outbox::take();
+    if taken.pending.is_empty() && taken.dropped == 0 {
+        print(out, format_args!("outbox already empty\r\n")).await?;
+        return Ok(());
+    }
+    let config = config.lock().await;
+    platform.save_config(&config).await?;
+    print(
+        out,
+        format_args!("dropped {} events\r\n", taken.pending.len()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// What [`halt`] ends with.
+#[derive(Clone, Copy)]
+enum Halt {
+    Reboot,
+    PowerOff,
+}
+
+/// `reboot` and `shutdown`: park the UART so the target does not get a
+/// partial character, save the known clients and the outbox if they changed,
+/// wait for flash writes in progress, then reset or power off. No other
+/// connection is accepted meanwhile, as the server serves one at a time.
+async fn halt<W, U, P>(
+    out: &mut W,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    action: Halt,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    print(out, format_args!("parking uart\r\n")).await?;
+    if with_timeout(Duration::from_millis(UART_PARK_TIMEOUT_MS), uart.park())
+        .await
+        .is_err()
+    {
+        print(
+            out,
+            format_args!("uart did not drain in time, going ahead\r\n"),
+        )
+        .await?;
+    }
+    if clients::needs_saving() || outbox::needs_saving() {
+        print(out, format_args!("saving known clients and outbox\r\n")).await?;
+        let save = async { platform.save_config(&*config.lock().await).await };
+        with_progress(out, "saving", None, save).await?;
+    }
+    match action {
+        Halt::Reboot => print(out, format_args!("rebooting\r\n")).await?,
+        Halt::PowerOff => {
+            print(
+                out,
+                format_args!("powering off, power cycle or reset to start again\r\n"),
+            )
+            .await?;
+        }
+    }
+    out.flush().await?;
+    // Give the reply a moment to leave before the radio goes.
+    Timer::after_millis(500).await;
+    platform.sync_flash().await;
+    match action {
+        Halt::Reboot => platform.reset(),
+        Halt::PowerOff => platform.power_off(),
+    }
+}
+
+async fn report_problems<W>(out: &mut W, problems: &staging::Problems) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    for problem in problems {
+        let severity = if problem.is_fatal() {
+            "error"
+        } else {
+            "warning"
+        };
+        print(out, format_args!("{severity}: {problem}\r\n")).await?;
+    }
+    Ok(())
+}
+
+/// `pulse <high|low> <us> [<us> ...]`: drive the pulse output pin starting
+/// at the given level, toggling after each duration.
+#[cfg(feature = "rmt")]
+async fn pulse<W, P>(args: &Args<'_>, out: &mut W, platform: &P) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    let pulses = parse_pulses(args).ok_or(args.usage())?;
+    platform.send_pulses(&pulses).await?;
+    print(out, format_args!("sent {} pulses\r\n", pulses.len())).await?;
+    Ok(())
+}
+
+#[cfg(feature = "rmt")]
+fn parse_pulses(args: &Args<'_>) -> Option<heapless::Vec<Pulse, MAX_PULSES>> {
+    let mut high = match args.get(0)? {
+        "high" | "1" => true,
+        "low" | "0" => false,
+        _ => return None,
+    };
+    let mut pulses = heapless::Vec::new();
+    for arg in args.rest(1) {
+        let micros: u16 = arg.parse().ok()?;
+        if micros == 0 || micros > MAX_PULSE_MICROS {
+            return None;
+        }
+        pulses.push(Pulse { high, micros }).ok()?;
+        high = !high;
+    }
+    (!pulses.is_empty()).then_some(pulses)
+}
+
+/// `target [<name>]`: list the mux targets, or route the bridge UART to
+/// `name` and remember it across reboots.
+#[cfg(feature = "mux")]
+async fn target<W, P>(
+    name: Option<&str>,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    match name {
+        None => list_targets(out, config).await,
+        Some(name) => select_target(name, out, config, platform).await,
+    }
+}
+
+#[cfg(feature = "mux")]
+async fn list_targets<W>(
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    // Copied out so the config is not locked while writing to the channel.
+    let (targets, selected) = {
+        let config = config.lock().await;
+        (config.mux_targets.clone(), config.mux_selected)
+    };
+    if targets.is_empty() {
+        print(
+            out,
+            format_args!("no targets, set SSH_STAMP_MUX_TARGETS\r\n"),
+        )
+        .await?;
+    }
+    for (i, t) in targets.iter().enumerate() {
+        let mark = if selected.is_some_and(|s| usize::from(s) == i) {
+            '*'
+        } else {
+            ' '
+        };
+        let mut escape: String<24> = String::new();
+        if let Some(e) = t.escape {
+            let _ = write!(escape, " escape {e}/{}", e.timeout_ms);
+        }
+        print(
+            out,
+            format_args!(
+                "{mark} {:<16} select {:#05b} {:>7} baud rx {}/{}{escape}\r\n",
+                t.name, t.select, t.baud_rate, t.profile.rx_fifo_threshold, t.profile.rx_timeout
+            ),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mux")]
+async fn select_target<W, P>(
+    name: &str,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    {
+        let mut config = config.lock().await;
+        let index = config
+            .mux_targets
+            .iter()
+            .position(|t| t.name == name)
+            .and_then(|i| u8::try_from(i).ok())
+            .ok_or(CommandError::NotFound)?;
+        platform
+            .select_mux(config.mux_targets[usize::from(index)].select)
+            .await?;
+        if config.mux_selected != Some(index) {
+            console::forget();
+            config.mux_selected = Some(index);
+            platform.save_config(&config).await?;
+        }
+    }
+    print(out, format_args!("target {name} selected\r\n")).await?;
+    Ok(())
+}
+
+/// `scratch list`: the files in the scratch partition, see [`scratch`].
+#[cfg(feature = "sftp-ota")]
+async fn scratch_list<W, P>(out: &mut W, platform: &P) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    let scratch = Scratch::load(platform.scratch()).await?;
+    let mut files = 0;
+    for (name, len) in scratch.files() {
+        print(out, format_args!("{name:<32} {len} bytes\r\n")).await?;
+        files += 1;
+    }
+    if files == 0 {
+        print(
+            out,
+            format_args!("no files, upload one over SFTP to {}/\r\n", scratch::DIR),
+        )
+        .await?;
+    }
+    print(
+        out,
+        format_args!(
+            "{} of {} bytes free, most erased sector: {} erases\r\n",
+            scratch.free(),
+            scratch.capacity(),
+            scratch.most_erases()
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `scratch delete <name>`: remove a file from the scratch partition.
+#[cfg(feature = "sftp-ota")]
+async fn scratch_delete<W, P>(name: &str, out: &mut W, platform: &P) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    let name = scratch::Name::new(name).ok_or(CommandError::NotFound)?;
+    let mut scratch = Scratch::load(platform.scratch()).await?;
+    if !scratch.delete(&name).await? {
+        return Err(CommandError::NotFound);
+    }
+    print(out, format_args!("scratch file {name} deleted\r\n")).await?;
+    Ok(())
+}
+
+/// `script list`: the stored scripts, see [`scripts`].
+#[cfg(feature = "scripts")]
+async fn script_list<W>(
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    // Copied out so the config is not locked while writing to the channel.
+    let stored = config.lock().await.scripts.clone();
+    if stored.is_empty() {
+        print(out, format_args!("no scripts, add one with script set\r\n")).await?;
+    }
+    for script in &stored {
+        print(
+            out,
+            format_args!("{:<16} {} bytes\r\n", script.name, script.text.len()),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// `script set <name>`: read a script from `input` until end of file,
+/// check it and store it, replacing one by the same name.
+#[cfg(feature = "scripts")]
+async fn script_set<R, W, P>(
+    name: &str,
+    mut input: R,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    let mut text = [0u8; SCRIPT_LEN];
+    let mut len = 0;
+    loop {
+        if len == text.len() {
+            // One more byte tells a full script from a long one.
+            match input.read(&mut [0u8; 1]).await {
+                Ok(0) | Err(sunset::Error::ChannelEOF) => break,
+                Ok(_) => return Err(ScriptError::BadText.into()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match input.read(&mut text[len..]).await {
+            Ok(0) | Err(sunset::Error::ChannelEOF) => break,
+            Ok(n) => len += n,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let script = scripts::parse(name, &text[..len])?;
+    {
+        let mut config = config.lock().await;
+        match config.scripts.iter_mut().find(|s| s.name == name) {
+            Some(stored) => *stored = script,
+            None => config
+                .scripts
+                .push(script)
+                .map_err(|_| ScriptError::NoSlot)?,
+        }
+        platform.save_config(&config).await?;
+    }
+    print(out, format_args!("script {name} stored, {len} bytes\r\n")).await?;
+    Ok(())
+}
+
+/// `script delete <name>`.
+#[cfg(feature = "scripts")]
+async fn script_delete<W, P>(
+    name: &str,
+    out: &mut W,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    P: PlatformServices,
+{
+    {
+        let mut config = config.lock().await;
+        let index = config
+            .scripts
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or(CommandError::NotFound)?;
+        config.scripts.remove(index);
+        platform.save_config(&config).await?;
+    }
+    print(out, format_args!("script {name} deleted\r\n")).await?;
+    Ok(())
+}
+
+/// `script run <name>`: run a stored script with the device-wide UART
+/// settings, showing the target output as it comes.
+#[cfg(feature = "scripts")]
+async fn script_run<W, U>(
+    name: &str,
+    out: &mut W,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+{
+    let (script, uart_config) = {
+        let config = config.lock().await;
+        let script = config.scripts.iter().find(|s| s.name == name).cloned();
+        (script.ok_or(CommandError::NotFound)?, config.uart_config())
+    };
+    uart.reconfigure(uart_config).await;
+    print(out, format_args!("script {name}: running\r\n")).await?;
+    let outcome = scripts::run(&script, uart, out).await?;
+    print(out, format_args!("\r\nscript {name}: {outcome}\r\n")).await?;
+    Ok(())
+}
+
+/// `script result`: the outcome and recorded output of the last run.
+#[cfg(feature = "scripts")]
+async fn script_result<W>(out: &mut W) -> Result<(), CommandError>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let Some(header) = scripts::result_header() else {
+        print(out, format_args!("no script run since boot\r\n")).await?;
+        return Ok(());
+    };
+    print(out, format_args!("{header}\r\n")).await?;
+    let mut buf = [0u8; COMMAND_OUTPUT_LEN];
+    let mut from = 0;
+    loop {
+        let n = scripts::read_output(from, &mut buf);
+        if n == 0 {
+            return Ok(());
+        }
+        out.write_all(&buf[..n]).await?;
+        from += n;
+    }
+}
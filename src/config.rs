@@ -16,9 +16,10 @@
 
 use log::{debug, warn};
 
-use core::net::Ipv4Addr;
+use core::fmt;
 #[cfg(feature = "ipv6")]
 use core::net::Ipv6Addr;
+use core::net::{IpAddr, Ipv4Addr};
 use core::str::FromStr;
 use embassy_net::{Ipv4Cidr, StaticConfigV4};
 #[cfg(feature = "ipv6")]
@@ -26,6 +27,7 @@ use embassy_net::{Ipv6Cidr, StaticConfigV6};
 use heapless::String;
 use ssh_key::PublicKey;
 use ssh_key::public::KeyData;
+use ssh_stamp_hal::{Parity, UartConfig, UartFrame};
 
 use sunset::packets::Ed25519PubKey;
 use sunset::{KeyType, Result};
@@ -35,7 +37,11 @@ use sunset::{
 };
 
 use crate::errors::Error;
-use crate::settings::{KEY_SLOTS, WIFI_PASSWORD_CHARS};
+use crate::settings::{
+    ACCESS_LIST_SLOTS, AP_FALLBACK_ADDRESSES, BAUD_LADDER_SLOTS, DEFAULT_IP, KEY_SLOTS,
+    MUX_TARGET_NAME_LEN, MUX_TARGET_SLOTS, SCRIPT_LEN, SCRIPT_NAME_LEN, SCRIPT_SLOTS,
+    UART_REDACT_LEN, UART_REDACT_SLOTS, USER_NAME_LEN, USER_ROUTE_SLOTS, WIFI_PASSWORD_CHARS,
+};
 
 #[derive(Debug, PartialEq)]
 pub struct SSHStampConfig {
@@ -64,6 +70,76 @@ pub struct SSHStampConfig {
     pub uart_pins: UartPins,
     /// True until a pubkey is provisioned. Further changes require authentication.
     pub first_login: bool,
+    /// Subnets allowed (or denied) to open a TCP connection to the SSH port.
+    pub access_lists: AccessLists,
+    /// Keep the access point up alongside Station Mode (AP+STA).
+    pub wifi_concurrent: bool,
+    /// Accept SSH on the access point interface while in AP+STA mode.
+    pub ssh_on_ap: bool,
+    /// Accept SSH on the station interface while in AP+STA mode.
+    pub ssh_on_sta: bool,
+    /// Accept SFTP OTA uploads from sessions on the access point interface
+    /// while in AP+STA mode.
+    pub ota_on_ap: bool,
+    /// Accept SFTP OTA uploads from sessions on the station interface while
+    /// in AP+STA mode.
+    pub ota_on_sta: bool,
+    /// UART line settings applied when the bridge starts.
+    pub uart_profile: UartProfile,
+    /// Device address on the access point network (a `/24`).
+    pub ap_address: Ipv4Addr,
+    /// Serve DHCP on the access point network.
+    pub dhcp_server: bool,
+    /// Copy log output to the platform console (it always goes to RAM).
+    pub log_console: bool,
+    /// Targets reachable through an external UART mux (`mux` feature).
+    pub mux_targets: heapless::Vec<MuxTarget, MUX_TARGET_SLOTS>,
+    /// Index into `mux_targets` of the target the bridge is routed to.
+    pub mux_selected: Option<u8>,
+    /// Network settings to restore unless the last commit is confirmed,
+    /// see [`crate::staging`].
+    pub rollback: Option<Rollback>,
+    /// SSH usernames and what their sessions do. Empty: any username gets
+    /// [`Route::DEFAULT`].
+    pub user_routes: heapless::Vec<UserRoute, USER_ROUTE_SLOTS>,
+    /// What the bridge does when the target stops taking UART input.
+    pub uart_tx_full: TxFullPolicy,
+    /// How long new firmware may run unconfirmed before it is rolled back.
+    pub ota_confirm: OtaConfirm,
+    /// Minutes without input after which a bridge session's input is locked
+    /// until the escape command unlocks it, 0 for never.
+    pub uart_idle_lock: u16,
+    /// The bridge escape, unless the selected mux target has its own.
+    pub uart_escape: BridgeEscape,
+    /// Hide secrets and config dumps from log output, see
+    /// [`crate::logging::Redacted`].
+    pub log_redact: bool,
+    /// Send small segments without waiting for outstanding ones to be
+    /// acknowledged (Nagle's algorithm off), for snappier keystroke echo.
+    pub tcp_nodelay: bool,
+    /// Cap on bridge, monitor and admin shell session length.
+    pub session_limit: SessionLimit,
+    /// Reset the target when a programmer's sync command comes through the
+    /// bridge (`rmt` feature), see `auto_reset`.
+    pub auto_reset: bool,
+    /// UART baud rate, unless the selected mux target or the session sets
+    /// its own.
+    pub uart_baud: u32,
+    /// UART data bits, parity and stop bits, for mux targets too.
+    pub uart_frame: UartFrame,
+    /// Rates tried when the line keeps failing at `uart_baud`.
+    pub baud_ladder: BaudLadder,
+    /// Byte patterns masked in the copies of target output the firmware
+    /// passes on, see [`crate::redact`].
+    pub uart_redact: RedactPatterns,
+    /// Copy bridged target output to the platform console, see
+    /// [`crate::logging::mirror`].
+    pub console_mirror: bool,
+    /// Expect scripts run against the UART on command (`scripts` feature).
+    pub scripts: heapless::Vec<Script, SCRIPT_SLOTS>,
+    /// Port of the unauthenticated TCP bridge, 0 for off (`raw-tcp`
+    /// feature), see `raw_tcp`.
+    pub raw_tcp_port: u16,
 }
 
 /// UART pin assignment.
@@ -71,18 +147,619 @@ pub struct SSHStampConfig {
 /// UART TX and RX pin numbers are target-specific and must be provided
 /// by the port binary (e.g. `ssh-stamp-esp32`). There is no sensible
 /// cross-platform default; `UartPins` is constructed explicitly by the
-/// binary and passed to [`SSHStampConfig::new`].
-#[derive(Debug, PartialEq)]
+/// binary and passed to [`SSHStampConfig::new`]. `SSH_STAMP_UART_PINS`
+/// changes them afterwards; the port checks them against the chip at boot
+/// and keeps its own pins if they cannot be used.
+#[derive(Debug, Clone, PartialEq)]
 pub struct UartPins {
     pub rx: u8,
     pub tx: u8,
 }
 
+/// Persisted UART settings.
+///
+/// The RX pair trades latency against throughput: interactive shells want
+/// every keystroke echoed at once, log streaming wants fewer, larger reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UartProfile {
+    /// RX FIFO fill level at which bytes are handed to SSH, `1..=127`.
+    pub rx_fifo_threshold: u8,
+    /// Idle symbol times before a partial FIFO is handed over, `1..=100`.
+    pub rx_timeout: u8,
+}
+
+impl UartProfile {
+    /// Lowest latency: every byte is forwarded as soon as it arrives.
+    pub const INTERACTIVE: Self = Self {
+        rx_fifo_threshold: 1,
+        rx_timeout: 1,
+    };
+    /// The long-standing default, a middle ground.
+    pub const BALANCED: Self = Self {
+        rx_fifo_threshold: 16,
+        rx_timeout: 1,
+    };
+    /// Fewer wake-ups for bulk output such as boot logs.
+    pub const THROUGHPUT: Self = Self {
+        rx_fifo_threshold: 100,
+        rx_timeout: 10,
+    };
+
+    /// True if both RX settings are within the ranges documented above.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        (1..=127).contains(&self.rx_fifo_threshold) && (1..=100).contains(&self.rx_timeout)
+    }
+}
+
+impl Default for UartProfile {
+    fn default() -> Self {
+        Self::BALANCED
+    }
+}
+
+/// UART settings a single SSH session applies on top of the persisted ones.
+///
+/// Set through the `SSH_STAMP_SESSION_*` environment variables, never saved,
+/// and dropped when the session ends, so an experiment does not leave the
+/// device misconfigured for the next user.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UartOverrides {
+    pub baud_rate: Option<u32>,
+    pub profile: Option<UartProfile>,
+    /// Strict binary bridge: nothing but target bytes in either direction,
+    /// see [`crate::serial::serial_bridge`].
+    pub binary: bool,
+    /// Reset the target as the bridge starts (`rmt` feature), see
+    /// `auto_reset`.
+    pub reset: bool,
+}
+
+/// The interface a connection came in on, in AP+STA mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetInterface {
+    AccessPoint,
+    Station,
+}
+
+impl NetInterface {
+    /// Name as used in `SSH_STAMP_SSH_INTERFACES`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            NetInterface::AccessPoint => "ap",
+            NetInterface::Station => "sta",
+        }
+    }
+}
+
+impl fmt::Display for NetInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The window in which firmware installed over OTA must be confirmed with
+/// `ota confirm`, see [`crate::ota_window`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OtaConfirm {
+    /// Minutes after each boot, 0 for no time limit.
+    pub minutes: u16,
+    /// Boots the image gets, 0 for no limit.
+    pub boots: u8,
+}
+
+impl OtaConfirm {
+    /// No window: new firmware is confirmed as soon as it boots.
+    pub const OFF: Self = Self {
+        minutes: 0,
+        boots: 0,
+    };
+
+    #[must_use]
+    pub fn is_off(self) -> bool {
+        self == Self::OFF
+    }
+}
+
+/// Baud rates to try in turn when the UART keeps reporting receive errors,
+/// see [`crate::baud_ladder`]. Empty: off.
+pub type BaudLadder = heapless::Vec<u32, BAUD_LADDER_SLOTS>;
+
+/// Byte patterns masked by [`crate::redact`]. Empty: off.
+pub type RedactPatterns = heapless::Vec<String<UART_REDACT_LEN>, UART_REDACT_SLOTS>;
+
+/// How long an interactive session may last before it is disconnected, see
+/// [`SESSION_LIMIT_WARNING_SECS`](crate::settings::SESSION_LIMIT_WARNING_SECS).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionLimit {
+    /// Minutes for any role without its own limit, 0 for no limit.
+    pub minutes: u16,
+    /// Minutes for viewers, operators and admins, in that order, overriding
+    /// `minutes`. `Some(0)` exempts the role.
+    pub per_role: [Option<u16>; 3],
+}
+
+impl SessionLimit {
+    /// No limit for anyone.
+    pub const OFF: Self = Self {
+        minutes: 0,
+        per_role: [None; 3],
+    };
+
+    /// Minutes a session with `role` may last, `None` for no limit.
+    #[must_use]
+    pub fn minutes_for(self, role: Role) -> Option<u16> {
+        let minutes = self.per_role[role as usize].unwrap_or(self.minutes);
+        (minutes != 0).then_some(minutes)
+    }
+}
+
+/// What the bridge does once UART output to the target has been stuck for
+/// [`UART_TX_STALL_MS`](crate::settings::UART_TX_STALL_MS), e.g. because the
+/// target is held in reset with flow control asserted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TxFullPolicy {
+    /// Keep waiting; the SSH session stalls along with the target.
+    #[default]
+    Block,
+    /// Drop input while the target is stuck and tell the client how much.
+    Drop,
+    /// End the session.
+    Disconnect,
+}
+
+impl TxFullPolicy {
+    /// Name as used in `SSH_STAMP_UART_TX_FULL`.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            TxFullPolicy::Block => "block",
+            TxFullPolicy::Drop => "drop",
+            TxFullPolicy::Disconnect => "disconnect",
+        }
+    }
+
+    /// Parses [`name`](Self::name).
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Block, Self::Drop, Self::Disconnect]
+            .into_iter()
+            .find(|p| p.name() == name)
+    }
+}
+
+/// One device behind an external UART mux.
+///
+/// Selecting it drives the mux select lines to `select` and bridges with
+/// its own baud rate and RX tuning instead of the device-wide ones, and its
+/// own escape if it has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuxTarget {
+    pub name: String<MUX_TARGET_NAME_LEN>,
+    /// Select line levels, bit `n` drives line `n`.
+    pub select: u8,
+    pub baud_rate: u32,
+    pub profile: UartProfile,
+    pub escape: Option<BridgeEscape>,
+}
+
+/// An expect script kept in the config, see the `scripts` module for its
+/// steps. Only checked scripts are stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    pub name: String<SCRIPT_NAME_LEN>,
+    pub text: String<SCRIPT_LEN>,
+}
+
+/// The bridge's escape: [`character`](Self::character) typed at the start
+/// of a line, followed within [`timeout_ms`](Self::timeout_ms) by a command
+/// character, e.g. `.` to disconnect or `u` to unlock input locked by
+/// `SSH_STAMP_UART_IDLE_LOCK`; see [`crate::serial::serial_bridge`].
+///
+/// Targets whose users type the default `~` at the start of a line need
+/// another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeEscape {
+    /// An ASCII character other than NUL, CR and LF.
+    pub character: u8,
+    pub timeout_ms: u16,
+}
+
+impl BridgeEscape {
+    pub const DEFAULT: Self = Self {
+        character: b'~',
+        timeout_ms: 1000,
+    };
+
+    /// True if the character can start a command and the timeout is not 0.
+    #[must_use]
+    pub fn is_valid(self) -> bool {
+        self.character.is_ascii()
+            && !matches!(self.character, 0 | b'\r' | b'\n')
+            && self.timeout_ms > 0
+    }
+}
+
+impl Default for BridgeEscape {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The character, control characters in caret notation (`^]`).
+impl fmt::Display for BridgeEscape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.character.is_ascii_control() {
+            write!(f, "^{}", char::from(self.character ^ 0x40))
+        } else {
+            write!(f, "{}", char::from(self.character))
+        }
+    }
+}
+
+/// What an authenticated session is for, chosen by its username.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionBehavior {
+    /// Interactive serial bridge.
+    Bridge,
+    /// Serial bridge that forwards UART output only; input is discarded.
+    Monitor,
+    /// Admin commands and environment variables only, no bridge.
+    Commands,
+    /// Firmware uploads over SFTP only, e.g. for an update robot: no
+    /// bridge, admin commands or settings.
+    Sftp,
+}
+
+impl SessionBehavior {
+    /// Name as used in `SSH_STAMP_USER_ROUTES`.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            SessionBehavior::Bridge => "bridge",
+            SessionBehavior::Monitor => "monitor",
+            SessionBehavior::Commands => "commands",
+            SessionBehavior::Sftp => "sftp",
+        }
+    }
+
+    /// Parses [`name`](Self::name).
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Bridge, Self::Monitor, Self::Commands, Self::Sftp]
+            .into_iter()
+            .find(|b| b.name() == name)
+    }
+
+    /// Whether the user gets the serial bridge, read-only or not.
+    #[must_use]
+    pub fn bridges(self) -> bool {
+        matches!(self, Self::Bridge | Self::Monitor)
+    }
+}
+
+/// What a session may do, in increasing order of privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Read-only: `show` commands and a monitoring bridge.
+    Viewer,
+    /// Also writes to the UART.
+    Operator,
+    /// Also changes settings, runs any admin command and updates firmware.
+    Admin,
+}
+
+impl Role {
+    /// Name as used in `SSH_STAMP_USER_ROUTES`.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Parses [`name`](Self::name).
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Viewer, Self::Operator, Self::Admin]
+            .into_iter()
+            .find(|r| r.name() == name)
+    }
+}
+
+/// The behavior and role a username maps to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub behavior: SessionBehavior,
+    pub role: Role,
+}
+
+impl Route {
+    /// For every username while no routes are configured, and during
+    /// first-boot provisioning.
+    pub const DEFAULT: Route = Route {
+        behavior: SessionBehavior::Bridge,
+        role: Role::Admin,
+    };
+
+    /// Whether `role` suffices for `behavior`; a bridge that writes to the
+    /// UART needs at least [`Role::Operator`], firmware uploads
+    /// [`Role::Admin`].
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        match self.behavior {
+            SessionBehavior::Bridge => self.role >= Role::Operator,
+            SessionBehavior::Sftp => self.role == Role::Admin,
+            SessionBehavior::Monitor | SessionBehavior::Commands => true,
+        }
+    }
+}
+
+/// A username and its [`Route`], see `SSH_STAMP_USER_ROUTES`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserRoute {
+    pub name: String<USER_NAME_LEN>,
+    pub route: Route,
+}
+
+/// An IPv4 network in CIDR notation, e.g. `192.168.1.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ipv4Subnet {
+    pub addr: Ipv4Addr,
+    /// Prefix length, `0..=32`.
+    pub prefix: u8,
+}
+
+impl Ipv4Subnet {
+    /// True if `ip` lies within this subnet.
+    #[must_use]
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix.min(32)))
+            .unwrap_or(0);
+        ip.to_bits() & mask == self.addr.to_bits() & mask
+    }
+
+    /// True if the two subnets share any address.
+    #[must_use]
+    pub fn overlaps(&self, other: &Ipv4Subnet) -> bool {
+        self.contains(other.addr) || other.contains(self.addr)
+    }
+}
+
+/// The access point address to use while the station is on `station`: the
+/// `configured` one, or else the first of [`AP_FALLBACK_ADDRESSES`] whose
+/// `/24` does not overlap the station's network. `None` if all of them do.
+///
+/// Two interfaces on overlapping networks cannot tell where to send a reply,
+/// so clients on one of them silently lose the device.
+#[must_use]
+pub fn ap_address_beside(configured: Ipv4Addr, station: Ipv4Subnet) -> Option<Ipv4Addr> {
+    core::iter::once(configured)
+        .chain(AP_FALLBACK_ADDRESSES)
+        .find(|&addr| !station.overlaps(&Ipv4Subnet { addr, prefix: 24 }))
+}
+
+/// Source-address filtering applied when a TCP connection is accepted.
+///
+/// The deny list always wins. If the allow list is non-empty, a peer must
+/// also match one of its entries. With both lists empty (the default) every
+/// peer is accepted.
+///
+/// Only IPv4 subnets can be listed; IPv6 peers are refused as soon as an
+/// allow list is configured.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessLists {
+    pub allow: heapless::Vec<Ipv4Subnet, ACCESS_LIST_SLOTS>,
+    pub deny: heapless::Vec<Ipv4Subnet, ACCESS_LIST_SLOTS>,
+}
+
+impl AccessLists {
+    /// Returns whether a connection from `peer` may proceed to the SSH handshake.
+    #[must_use]
+    pub fn permits(&self, peer: IpAddr) -> bool {
+        match peer {
+            IpAddr::V4(ip) => {
+                !self.deny.iter().any(|n| n.contains(ip))
+                    && (self.allow.is_empty() || self.allow.iter().any(|n| n.contains(ip)))
+            }
+            IpAddr::V6(_) => self.allow.is_empty(),
+        }
+    }
+}
+
+/// The settings that decide whether the device can be reached over the
+/// network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkSettings {
+    pub wifi_ap_ssid: String<32>,
+    pub wifi_ap_pw: String<63>,
+    pub wifi_sta_ssid: String<32>,
+    pub wifi_sta_pw: String<63>,
+    pub mac: [u8; 6],
+    pub ipv4_static: Option<StaticConfigV4>,
+    pub access_lists: AccessLists,
+    pub wifi_concurrent: bool,
+    pub ssh_on_ap: bool,
+    pub ssh_on_sta: bool,
+    pub ap_address: Ipv4Addr,
+    pub dhcp_server: bool,
+}
+
+impl NetworkSettings {
+    /// A copy of the network settings of `config`.
+    #[must_use]
+    pub fn of(config: &SSHStampConfig) -> Self {
+        Self {
+            wifi_ap_ssid: config.wifi_ap_ssid.clone(),
+            wifi_ap_pw: config.wifi_ap_pw.clone(),
+            wifi_sta_ssid: config.wifi_sta_ssid.clone(),
+            wifi_sta_pw: config.wifi_sta_pw.clone(),
+            mac: config.mac,
+            ipv4_static: config.ipv4_static.clone(),
+            access_lists: config.access_lists.clone(),
+            wifi_concurrent: config.wifi_concurrent,
+            ssh_on_ap: config.ssh_on_ap,
+            ssh_on_sta: config.ssh_on_sta,
+            ap_address: config.ap_address,
+            dhcp_server: config.dhcp_server,
+        }
+    }
+
+    /// Writes these settings back into `config`.
+    pub fn restore(self, config: &mut SSHStampConfig) {
+        config.wifi_ap_ssid = self.wifi_ap_ssid;
+        config.wifi_ap_pw = self.wifi_ap_pw;
+        config.wifi_sta_ssid = self.wifi_sta_ssid;
+        config.wifi_sta_pw = self.wifi_sta_pw;
+        config.mac = self.mac;
+        config.ipv4_static = self.ipv4_static;
+        config.access_lists = self.access_lists;
+        config.wifi_concurrent = self.wifi_concurrent;
+        config.ssh_on_ap = self.ssh_on_ap;
+        config.ssh_on_sta = self.ssh_on_sta;
+        config.ap_address = self.ap_address;
+        config.dhcp_server = self.dhcp_server;
+    }
+}
+
+/// A commit awaiting confirmation.
+///
+/// Persisted with the config, so the window restarts at every boot until
+/// the change is confirmed or rolled back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollback {
+    /// Minutes after boot (or the commit) before `previous` is restored.
+    pub minutes: u16,
+    /// The last confirmed network settings.
+    pub previous: NetworkSettings,
+}
+
+/// A reason not to apply a configuration, see [`SSHStampConfig::validate`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigProblem {
+    /// UART RX and TX are assigned the same pin.
+    UartPinConflict,
+    /// The access point SSID is empty.
+    ApSsidEmpty,
+    /// The access point PSK is not 8-63 characters.
+    ApPskLength,
+    /// The station PSK is neither empty (open network) nor 8-63 characters.
+    StaPskLength,
+    /// The static IPv4 prefix leaves no room for hosts.
+    StaticPrefixLength(u8),
+    /// The static station address lies in the access point's `/24`.
+    StaticOverlapsAp,
+    /// The access lists would refuse the given peer.
+    LocksOut(IpAddr),
+    /// AP+STA mode with SSH disabled on both interfaces.
+    SshNowhere,
+    /// Access point only, without DHCP: clients need a static address.
+    ApWithoutDhcp,
+    /// AP+STA mode with OTA allowed only on interfaces without SSH.
+    OtaUnreachable,
+}
+
+impl ConfigProblem {
+    /// Whether the configuration must not be applied; otherwise this is
+    /// only a warning.
+    #[must_use]
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            ConfigProblem::ApWithoutDhcp | ConfigProblem::OtaUnreachable
+        )
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigProblem::UartPinConflict => write!(f, "UART RX and TX use the same pin"),
+            ConfigProblem::ApSsidEmpty => write!(f, "access point SSID is empty"),
+            ConfigProblem::ApPskLength => write!(f, "access point PSK must be 8-63 characters"),
+            ConfigProblem::StaPskLength => {
+                write!(f, "station PSK must be empty or 8-63 characters")
+            }
+            ConfigProblem::StaticPrefixLength(prefix) => {
+                write!(f, "static IPv4 prefix /{prefix} must be within /1-/30")
+            }
+            ConfigProblem::StaticOverlapsAp => {
+                write!(f, "static IPv4 address overlaps the access point network")
+            }
+            ConfigProblem::LocksOut(peer) => {
+                write!(f, "access lists would refuse this client ({peer})")
+            }
+            ConfigProblem::SshNowhere => write!(f, "SSH disabled on both interfaces"),
+            ConfigProblem::ApWithoutDhcp => {
+                write!(
+                    f,
+                    "access point without DHCP, clients need a static address"
+                )
+            }
+            ConfigProblem::OtaUnreachable => {
+                write!(f, "OTA only allowed on interfaces without SSH")
+            }
+        }
+    }
+}
+
+/// Most problems [`SSHStampConfig::validate`] reports at once.
+pub const MAX_CONFIG_PROBLEMS: usize = 8;
+
 const MAC_RANDOM_SENTINEL: [u8; 6] = [0xFF; 6];
 
 impl SSHStampConfig {
     /// Bump this when the format changes
-    pub const CURRENT_VERSION: u8 = 10;
+    pub const CURRENT_VERSION: u8 = 40;
+
+    /// Largest encoding of a config, with every list full and every string
+    /// at its capacity. Keep in step with the `SSHEncode` impl below.
+    pub const MAX_ENCODED_LEN: usize = {
+        // An `Ed25519PubKey` is a blob: a length, then the key.
+        let pubkey = 4 + 32;
+        let ipv4 = 1 + 4 + 1 + (1 + 4);
+        #[cfg(feature = "ipv6")]
+        let ipv6 = 1 + 16 + 1 + (1 + 16);
+        #[cfg(not(feature = "ipv6"))]
+        let ipv6 = 0;
+        let subnets = 1 + ACCESS_LIST_SLOTS * (4 + 1);
+        let escape = 1 + 4;
+        let network = 2 * wire_str(32) + 2 * wire_str(63) + 6 + ipv4 + 2 * subnets + 3 + 4 + 1;
+        let mux_target = wire_str(MUX_TARGET_NAME_LEN) + 1 + 4 + 2 + 1 + escape;
+        let user_route = wire_str(USER_NAME_LEN)
+            + wire_str(SessionBehavior::Commands.name().len())
+            + wire_str(Role::Operator.name().len());
+        let script = wire_str(SCRIPT_NAME_LEN) + wire_str(SCRIPT_LEN);
+        32 + KEY_SLOTS * (1 + pubkey)
+            + network
+            + ipv6
+            + 2 // uart pins
+            + 1 // first login
+            + 2 // ota on ap, sta
+            + 2 // uart profile
+            + 1 // log console
+            + 1 + MUX_TARGET_SLOTS * mux_target
+            + (1 + 1)
+            + (1 + 4 + network)
+            + 1 + USER_ROUTE_SLOTS * user_route
+            + wire_str(TxFullPolicy::Disconnect.name().len())
+            + (4 + 1) // ota confirm
+            + 4 // uart idle lock
+            + escape
+            + 2 // log redact, tcp nodelay
+            + 4 + 3 * (1 + 4) // session limit
+            + 1 // auto reset
+            + 4 + 3 // uart baud and frame
+            + 1 + BAUD_LADDER_SLOTS * 4
+            + 1 + UART_REDACT_SLOTS * wire_str(UART_REDACT_LEN)
+            + 1 // console mirror
+            + 1 + SCRIPT_SLOTS * script
+            + 4 // raw tcp port
+    };
 
     /// Check if configured for random MAC on each boot
     #[must_use]
@@ -90,6 +767,198 @@ impl SSHStampConfig {
         self.mac == MAC_RANDOM_SENTINEL
     }
 
+    /// Whether a session on `interface` may upload firmware over SFTP.
+    /// `None`, a connection outside AP+STA mode, always may.
+    #[must_use]
+    pub fn ota_permitted(&self, interface: Option<NetInterface>) -> bool {
+        match interface {
+            None => true,
+            Some(NetInterface::AccessPoint) => self.ota_on_ap,
+            Some(NetInterface::Station) => self.ota_on_sta,
+        }
+    }
+
+    /// UART settings for the platform's bridge UART.
+    #[must_use]
+    pub fn uart_config(&self) -> UartConfig {
+        self.uart_config_with(&UartOverrides::default())
+    }
+
+    /// UART settings for the platform's bridge UART with a session's
+    /// `overrides` applied.
+    ///
+    /// A selected mux target replaces the device-wide baud rate and RX
+    /// tuning; session overrides still win over both.
+    #[must_use]
+    pub fn uart_config_with(&self, overrides: &UartOverrides) -> UartConfig {
+        let defaults = UartConfig::default();
+        let (baud_rate, profile) = match self.selected_mux_target() {
+            Some(target) => (target.baud_rate, target.profile),
+            None => (self.uart_baud, self.uart_profile),
+        };
+        let profile = overrides.profile.unwrap_or(profile);
+        UartConfig {
+            tx_pin: self.uart_pins.tx,
+            rx_pin: self.uart_pins.rx,
+            baud_rate: overrides.baud_rate.unwrap_or(baud_rate),
+            rx_fifo_threshold: u16::from(profile.rx_fifo_threshold),
+            rx_timeout: profile.rx_timeout,
+            frame: self.uart_frame,
+            ..defaults
+        }
+    }
+
+    /// The bridge escape: the selected mux target's, if it has one.
+    #[must_use]
+    pub fn bridge_escape(&self) -> BridgeEscape {
+        self.selected_mux_target()
+            .and_then(|target| target.escape)
+            .unwrap_or(self.uart_escape)
+    }
+
+    /// Whether `presented` is one of the installed admin keys.
+    #[must_use]
+    pub fn knows_key(&self, presented: &Ed25519PubKey) -> bool {
+        self.pubkeys
+            .iter()
+            .any(|slot| slot.as_ref().is_some_and(|stored| stored == presented))
+    }
+
+    /// The route for an SSH login as `user`, `None` if it is not allowed.
+    #[must_use]
+    pub fn route_for(&self, user: &str) -> Option<Route> {
+        if self.user_routes.is_empty() {
+            return Some(Route::DEFAULT);
+        }
+        self.user_routes
+            .iter()
+            .find(|r| r.name == user)
+            .map(|r| r.route)
+    }
+
+    /// The mux target the bridge is currently routed to, if any.
+    #[must_use]
+    pub fn selected_mux_target(&self) -> Option<&MuxTarget> {
+        self.mux_targets.get(usize::from(self.mux_selected?))
+    }
+
+    /// A copy of this config, e.g. to stage changes on.
+    ///
+    /// Returns `None` if the host key cannot be copied (agent-held keys).
+    #[must_use]
+    pub fn try_clone(&self) -> Option<Self> {
+        let hostkey = match &self.hostkey {
+            SignKey::Ed25519(k) => SignKey::Ed25519(k.clone()),
+            SignKey::AgentEd25519(_) => return None,
+        };
+        Some(Self {
+            hostkey,
+            pubkeys: self.pubkeys,
+            wifi_ap_ssid: self.wifi_ap_ssid.clone(),
+            wifi_ap_pw: self.wifi_ap_pw.clone(),
+            wifi_sta_ssid: self.wifi_sta_ssid.clone(),
+            wifi_sta_pw: self.wifi_sta_pw.clone(),
+            mac: self.mac,
+            ipv4_static: self.ipv4_static.clone(),
+            #[cfg(feature = "ipv6")]
+            ipv6_static: self.ipv6_static.clone(),
+            uart_pins: self.uart_pins.clone(),
+            first_login: self.first_login,
+            access_lists: self.access_lists.clone(),
+            wifi_concurrent: self.wifi_concurrent,
+            ssh_on_ap: self.ssh_on_ap,
+            ssh_on_sta: self.ssh_on_sta,
+            ota_on_ap: self.ota_on_ap,
+            ota_on_sta: self.ota_on_sta,
+            uart_profile: self.uart_profile,
+            ap_address: self.ap_address,
+            dhcp_server: self.dhcp_server,
+            log_console: self.log_console,
+            mux_targets: self.mux_targets.clone(),
+            mux_selected: self.mux_selected,
+            rollback: self.rollback.clone(),
+            user_routes: self.user_routes.clone(),
+            uart_tx_full: self.uart_tx_full,
+            ota_confirm: self.ota_confirm,
+            uart_idle_lock: self.uart_idle_lock,
+            uart_escape: self.uart_escape,
+            log_redact: self.log_redact,
+            tcp_nodelay: self.tcp_nodelay,
+            session_limit: self.session_limit,
+            auto_reset: self.auto_reset,
+            uart_baud: self.uart_baud,
+            uart_frame: self.uart_frame,
+            baud_ladder: self.baud_ladder.clone(),
+            uart_redact: self.uart_redact.clone(),
+            console_mirror: self.console_mirror,
+            scripts: self.scripts.clone(),
+            raw_tcp_port: self.raw_tcp_port,
+        })
+    }
+
+    /// Cross-field checks run before a staged configuration is applied.
+    ///
+    /// `peer` is the admin's own address; a configuration whose access
+    /// lists would refuse it is reported, since applying it would end
+    /// remote access. Problems beyond [`MAX_CONFIG_PROBLEMS`] are dropped.
+    #[must_use]
+    pub fn validate(
+        &self,
+        peer: Option<IpAddr>,
+    ) -> heapless::Vec<ConfigProblem, MAX_CONFIG_PROBLEMS> {
+        let mut problems = heapless::Vec::new();
+        let mut report = |p| {
+            let _ = problems.push(p);
+        };
+        let station = !self.wifi_sta_ssid.is_empty();
+        let ap = !station || self.wifi_concurrent;
+
+        if self.uart_pins.rx == self.uart_pins.tx {
+            report(ConfigProblem::UartPinConflict);
+        }
+        if self.wifi_ap_ssid.is_empty() {
+            report(ConfigProblem::ApSsidEmpty);
+        }
+        if !(8..=63).contains(&self.wifi_ap_pw.len()) {
+            report(ConfigProblem::ApPskLength);
+        }
+        if station && !self.wifi_sta_pw.is_empty() && !(8..=63).contains(&self.wifi_sta_pw.len()) {
+            report(ConfigProblem::StaPskLength);
+        }
+        if let Some(v4) = &self.ipv4_static {
+            let prefix = v4.address.prefix_len();
+            if !(1..=30).contains(&prefix) {
+                report(ConfigProblem::StaticPrefixLength(prefix));
+            }
+            let ap_net = Ipv4Subnet {
+                addr: self.ap_address,
+                prefix: 24,
+            };
+            if ap && ap_net.contains(v4.address.address()) {
+                report(ConfigProblem::StaticOverlapsAp);
+            }
+        }
+        if let Some(peer) = peer
+            && !self.access_lists.permits(peer)
+        {
+            report(ConfigProblem::LocksOut(peer));
+        }
+        if station && self.wifi_concurrent && !self.ssh_on_ap && !self.ssh_on_sta {
+            report(ConfigProblem::SshNowhere);
+        }
+        if station
+            && self.wifi_concurrent
+            && !(self.ota_on_ap && self.ssh_on_ap)
+            && !(self.ota_on_sta && self.ssh_on_sta)
+        {
+            report(ConfigProblem::OtaUnreachable);
+        }
+        if !station && !self.dhcp_server {
+            report(ConfigProblem::ApWithoutDhcp);
+        }
+        problems
+    }
+
     /// Get the MAC address to use (resolves random sentinel)
     /// # Errors
     /// Returns an error if the RNG fails
@@ -141,6 +1010,35 @@ impl SSHStampConfig {
             ipv6_static: None,
             uart_pins,
             first_login: true,
+            access_lists: AccessLists::default(),
+            wifi_concurrent: false,
+            ssh_on_ap: true,
+            ssh_on_sta: true,
+            ota_on_ap: true,
+            ota_on_sta: true,
+            uart_profile: UartProfile::default(),
+            ap_address: DEFAULT_IP,
+            dhcp_server: true,
+            log_console: true,
+            mux_targets: heapless::Vec::new(),
+            mux_selected: None,
+            rollback: None,
+            user_routes: heapless::Vec::new(),
+            uart_tx_full: TxFullPolicy::default(),
+            ota_confirm: OtaConfirm::OFF,
+            uart_idle_lock: 0,
+            uart_escape: BridgeEscape::DEFAULT,
+            log_redact: false,
+            tcp_nodelay: false,
+            session_limit: SessionLimit::OFF,
+            auto_reset: false,
+            uart_baud: UartConfig::default().baud_rate,
+            uart_frame: UartFrame::DEFAULT,
+            baud_ladder: BaudLadder::new(),
+            uart_redact: RedactPatterns::new(),
+            console_mirror: false,
+            scripts: heapless::Vec::new(),
+            raw_tcp_port: 0,
         })
     }
 
@@ -238,6 +1136,12 @@ pub(crate) fn enc_option<T: SSHEncode>(v: Option<&T>, s: &mut dyn SSHSink) -> Wi
     Ok(())
 }
 
+/// Encoded length of a string of up to `cap` bytes: a length, then the
+/// bytes.
+pub(crate) const fn wire_str(cap: usize) -> usize {
+    4 + cap
+}
+
 pub(crate) fn dec_option<'de, S, T: SSHDecode<'de>>(s: &mut S) -> WireResult<Option<T>>
 where
     S: SSHSource<'de>,
@@ -321,6 +1225,249 @@ where
     .transpose()
 }
 
+fn enc_subnets(v: &[Ipv4Subnet], s: &mut dyn SSHSink) -> WireResult<()> {
+    u8::try_from(v.len())
+        .map_err(|_| WireError::PacketWrong)?
+        .enc(s)?;
+    for n in v {
+        n.addr.to_bits().enc(s)?;
+        n.prefix.enc(s)?;
+    }
+    Ok(())
+}
+
+fn dec_subnets<'de, S>(s: &mut S) -> WireResult<heapless::Vec<Ipv4Subnet, ACCESS_LIST_SLOTS>>
+where
+    S: SSHSource<'de>,
+{
+    let count: u8 = SSHDecode::dec(s)?;
+    let mut v = heapless::Vec::new();
+    for _ in 0..count {
+        let addr: u32 = SSHDecode::dec(s)?;
+        let prefix: u8 = SSHDecode::dec(s)?;
+        if prefix > 32 {
+            return Err(WireError::PacketWrong);
+        }
+        v.push(Ipv4Subnet {
+            addr: Ipv4Addr::from_bits(addr),
+            prefix,
+        })
+        .map_err(|_| WireError::PacketWrong)?;
+    }
+    Ok(v)
+}
+
+fn enc_mux_targets(v: &[MuxTarget], s: &mut dyn SSHSink) -> WireResult<()> {
+    u8::try_from(v.len())
+        .map_err(|_| WireError::PacketWrong)?
+        .enc(s)?;
+    for t in v {
+        t.name.as_str().enc(s)?;
+        t.select.enc(s)?;
+        t.baud_rate.enc(s)?;
+        t.profile.rx_fifo_threshold.enc(s)?;
+        t.profile.rx_timeout.enc(s)?;
+        t.escape.is_some().enc(s)?;
+        if let Some(escape) = t.escape {
+            enc_escape(escape, s)?;
+        }
+    }
+    Ok(())
+}
+
+fn dec_mux_targets<'de, S>(s: &mut S) -> WireResult<heapless::Vec<MuxTarget, MUX_TARGET_SLOTS>>
+where
+    S: SSHSource<'de>,
+{
+    let count: u8 = SSHDecode::dec(s)?;
+    let mut v = heapless::Vec::new();
+    for _ in 0..count {
+        let name: &str = SSHDecode::dec(s)?;
+        let name = String::try_from(name).map_err(|_| WireError::BadString)?;
+        let select = SSHDecode::dec(s)?;
+        let baud_rate = SSHDecode::dec(s)?;
+        let profile = UartProfile {
+            rx_fifo_threshold: SSHDecode::dec(s)?,
+            rx_timeout: SSHDecode::dec(s)?,
+        };
+        if !profile.is_valid() {
+            return Err(WireError::PacketWrong);
+        }
+        let has_escape: bool = SSHDecode::dec(s)?;
+        let escape = if has_escape {
+            Some(dec_escape(s)?)
+        } else {
+            None
+        };
+        v.push(MuxTarget {
+            name,
+            select,
+            baud_rate,
+            profile,
+            escape,
+        })
+        .map_err(|_| WireError::PacketWrong)?;
+    }
+    Ok(v)
+}
+
+fn enc_escape(escape: BridgeEscape, s: &mut dyn SSHSink) -> WireResult<()> {
+    escape.character.enc(s)?;
+    u32::from(escape.timeout_ms).enc(s)
+}
+
+fn dec_escape<'de, S>(s: &mut S) -> WireResult<BridgeEscape>
+where
+    S: SSHSource<'de>,
+{
+    let character = SSHDecode::dec(s)?;
+    let timeout_ms: u32 = SSHDecode::dec(s)?;
+    let escape = BridgeEscape {
+        character,
+        timeout_ms: u16::try_from(timeout_ms).map_err(|_| WireError::PacketWrong)?,
+    };
+    if !escape.is_valid() {
+        return Err(WireError::PacketWrong);
+    }
+    Ok(escape)
+}
+
+fn enc_frame(frame: UartFrame, s: &mut dyn SSHSink) -> WireResult<()> {
+    frame.data_bits.enc(s)?;
+    let parity: u8 = match frame.parity {
+        Parity::None => 0,
+        Parity::Even => 1,
+        Parity::Odd => 2,
+    };
+    parity.enc(s)?;
+    frame.stop_bits.enc(s)
+}
+
+fn dec_frame<'de, S>(s: &mut S) -> WireResult<UartFrame>
+where
+    S: SSHSource<'de>,
+{
+    let data_bits: u8 = SSHDecode::dec(s)?;
+    let parity = match u8::dec(s)? {
+        0 => Parity::None,
+        1 => Parity::Even,
+        2 => Parity::Odd,
+        _ => return Err(WireError::UnknownVariant),
+    };
+    let stop_bits: u8 = SSHDecode::dec(s)?;
+    if !(5..=8).contains(&data_bits) || !(1..=2).contains(&stop_bits) {
+        return Err(WireError::PacketWrong);
+    }
+    Ok(UartFrame {
+        data_bits,
+        parity,
+        stop_bits,
+    })
+}
+
+fn enc_user_routes(v: &[UserRoute], s: &mut dyn SSHSink) -> WireResult<()> {
+    u8::try_from(v.len())
+        .map_err(|_| WireError::PacketWrong)?
+        .enc(s)?;
+    for r in v {
+        r.name.as_str().enc(s)?;
+        r.route.behavior.name().enc(s)?;
+        r.route.role.name().enc(s)?;
+    }
+    Ok(())
+}
+
+fn dec_user_routes<'de, S>(s: &mut S) -> WireResult<heapless::Vec<UserRoute, USER_ROUTE_SLOTS>>
+where
+    S: SSHSource<'de>,
+{
+    let count: u8 = SSHDecode::dec(s)?;
+    let mut v = heapless::Vec::new();
+    for _ in 0..count {
+        let name = dec_string(s)?;
+        let behavior: &str = SSHDecode::dec(s)?;
+        let role: &str = SSHDecode::dec(s)?;
+        let route = Route {
+            behavior: SessionBehavior::from_name(behavior).ok_or(WireError::UnknownVariant)?,
+            role: Role::from_name(role).ok_or(WireError::UnknownVariant)?,
+        };
+        v.push(UserRoute { name, route })
+            .map_err(|_| WireError::PacketWrong)?;
+    }
+    Ok(v)
+}
+
+fn enc_network_settings(v: &NetworkSettings, s: &mut dyn SSHSink) -> WireResult<()> {
+    v.wifi_ap_ssid.as_str().enc(s)?;
+    v.wifi_ap_pw.as_str().enc(s)?;
+    v.wifi_sta_ssid.as_str().enc(s)?;
+    v.wifi_sta_pw.as_str().enc(s)?;
+    v.mac.enc(s)?;
+    enc_ipv4_config(v.ipv4_static.as_ref(), s)?;
+    enc_subnets(&v.access_lists.allow, s)?;
+    enc_subnets(&v.access_lists.deny, s)?;
+    v.wifi_concurrent.enc(s)?;
+    v.ssh_on_ap.enc(s)?;
+    v.ssh_on_sta.enc(s)?;
+    v.ap_address.to_bits().enc(s)?;
+    v.dhcp_server.enc(s)
+}
+
+fn dec_network_settings<'de, S>(s: &mut S) -> WireResult<NetworkSettings>
+where
+    S: SSHSource<'de>,
+{
+    Ok(NetworkSettings {
+        wifi_ap_ssid: dec_string(s)?,
+        wifi_ap_pw: dec_string(s)?,
+        wifi_sta_ssid: dec_string(s)?,
+        wifi_sta_pw: dec_string(s)?,
+        mac: SSHDecode::dec(s)?,
+        ipv4_static: dec_ipv4_config(s)?,
+        access_lists: AccessLists {
+            allow: dec_subnets(s)?,
+            deny: dec_subnets(s)?,
+        },
+        wifi_concurrent: SSHDecode::dec(s)?,
+        ssh_on_ap: SSHDecode::dec(s)?,
+        ssh_on_sta: SSHDecode::dec(s)?,
+        ap_address: Ipv4Addr::from_bits(SSHDecode::dec(s)?),
+        dhcp_server: SSHDecode::dec(s)?,
+    })
+}
+
+fn dec_string<'de, S, const N: usize>(s: &mut S) -> WireResult<String<N>>
+where
+    S: SSHSource<'de>,
+{
+    let v: &str = SSHDecode::dec(s)?;
+    String::try_from(v).map_err(|_| WireError::BadString)
+}
+
+fn enc_rollback(v: Option<&Rollback>, s: &mut dyn SSHSink) -> WireResult<()> {
+    v.is_some().enc(s)?;
+    if let Some(v) = v {
+        u32::from(v.minutes).enc(s)?;
+        enc_network_settings(&v.previous, s)?;
+    }
+    Ok(())
+}
+
+fn dec_rollback<'de, S>(s: &mut S) -> WireResult<Option<Rollback>>
+where
+    S: SSHSource<'de>,
+{
+    let opt = bool::dec(s)?;
+    opt.then(|| {
+        let minutes: u32 = SSHDecode::dec(s)?;
+        Ok(Rollback {
+            minutes: u16::try_from(minutes).map_err(|_| WireError::PacketWrong)?,
+            previous: dec_network_settings(s)?,
+        })
+    })
+    .transpose()
+}
+
 impl SSHEncode for SSHStampConfig {
     fn enc(&self, s: &mut dyn SSHSink) -> WireResult<()> {
         enc_signkey(&self.hostkey, s)?;
@@ -348,6 +1495,67 @@ impl SSHEncode for SSHStampConfig {
         // Persist first-login marker
         self.first_login.enc(s)?;
 
+        enc_subnets(&self.access_lists.allow, s)?;
+        enc_subnets(&self.access_lists.deny, s)?;
+
+        self.wifi_concurrent.enc(s)?;
+        self.ssh_on_ap.enc(s)?;
+        self.ssh_on_sta.enc(s)?;
+        self.ota_on_ap.enc(s)?;
+        self.ota_on_sta.enc(s)?;
+
+        self.uart_profile.rx_fifo_threshold.enc(s)?;
+        self.uart_profile.rx_timeout.enc(s)?;
+
+        self.ap_address.to_bits().enc(s)?;
+        self.dhcp_server.enc(s)?;
+        self.log_console.enc(s)?;
+
+        enc_mux_targets(&self.mux_targets, s)?;
+        enc_option(self.mux_selected.as_ref(), s)?;
+
+        enc_rollback(self.rollback.as_ref(), s)?;
+
+        enc_user_routes(&self.user_routes, s)?;
+
+        self.uart_tx_full.name().enc(s)?;
+
+        u32::from(self.ota_confirm.minutes).enc(s)?;
+        self.ota_confirm.boots.enc(s)?;
+
+        u32::from(self.uart_idle_lock).enc(s)?;
+        enc_escape(self.uart_escape, s)?;
+        self.log_redact.enc(s)?;
+        self.tcp_nodelay.enc(s)?;
+        u32::from(self.session_limit.minutes).enc(s)?;
+        for minutes in self.session_limit.per_role {
+            enc_option(minutes.map(u32::from).as_ref(), s)?;
+        }
+        self.auto_reset.enc(s)?;
+        self.uart_baud.enc(s)?;
+        enc_frame(self.uart_frame, s)?;
+        u8::try_from(self.baud_ladder.len())
+            .map_err(|_| WireError::PacketWrong)?
+            .enc(s)?;
+        for baud_rate in &self.baud_ladder {
+            baud_rate.enc(s)?;
+        }
+        u8::try_from(self.uart_redact.len())
+            .map_err(|_| WireError::PacketWrong)?
+            .enc(s)?;
+        for pattern in &self.uart_redact {
+            pattern.as_str().enc(s)?;
+        }
+        self.console_mirror.enc(s)?;
+        u8::try_from(self.scripts.len())
+            .map_err(|_| WireError::PacketWrong)?
+            .enc(s)?;
+        for script in &self.scripts {
+            script.name.as_str().enc(s)?;
+            script.text.as_str().enc(s)?;
+        }
+        u32::from(self.raw_tcp_port).enc(s)?;
+
         Ok(())
     }
 }
@@ -390,6 +1598,98 @@ impl<'de> SSHDecode<'de> for SSHStampConfig {
 
         let first_login = SSHDecode::dec(s)?;
 
+        let access_lists = AccessLists {
+            allow: dec_subnets(s)?,
+            deny: dec_subnets(s)?,
+        };
+
+        let wifi_concurrent = SSHDecode::dec(s)?;
+        let ssh_on_ap = SSHDecode::dec(s)?;
+        let ssh_on_sta = SSHDecode::dec(s)?;
+        let ota_on_ap = SSHDecode::dec(s)?;
+        let ota_on_sta = SSHDecode::dec(s)?;
+
+        let uart_profile = UartProfile {
+            rx_fifo_threshold: SSHDecode::dec(s)?,
+            rx_timeout: SSHDecode::dec(s)?,
+        };
+        if !uart_profile.is_valid() {
+            return Err(WireError::PacketWrong);
+        }
+
+        let ap_address: u32 = SSHDecode::dec(s)?;
+        let ap_address = Ipv4Addr::from_bits(ap_address);
+        let dhcp_server = SSHDecode::dec(s)?;
+        let log_console = SSHDecode::dec(s)?;
+
+        let mux_targets = dec_mux_targets(s)?;
+        let mux_selected: Option<u8> = dec_option(s)?;
+        if mux_selected.is_some_and(|i| usize::from(i) >= mux_targets.len()) {
+            return Err(WireError::PacketWrong);
+        }
+
+        let rollback = dec_rollback(s)?;
+
+        let user_routes = dec_user_routes(s)?;
+
+        let uart_tx_full: &str = SSHDecode::dec(s)?;
+        let uart_tx_full =
+            TxFullPolicy::from_name(uart_tx_full).ok_or(WireError::UnknownVariant)?;
+
+        let minutes: u32 = SSHDecode::dec(s)?;
+        let ota_confirm = OtaConfirm {
+            minutes: u16::try_from(minutes).map_err(|_| WireError::PacketWrong)?,
+            boots: SSHDecode::dec(s)?,
+        };
+
+        let uart_idle_lock: u32 = SSHDecode::dec(s)?;
+        let uart_idle_lock = u16::try_from(uart_idle_lock).map_err(|_| WireError::PacketWrong)?;
+        let uart_escape = dec_escape(s)?;
+        let log_redact = SSHDecode::dec(s)?;
+        let tcp_nodelay = SSHDecode::dec(s)?;
+
+        let minutes: u32 = SSHDecode::dec(s)?;
+        let mut session_limit = SessionLimit {
+            minutes: u16::try_from(minutes).map_err(|_| WireError::PacketWrong)?,
+            per_role: [None; 3],
+        };
+        for slot in &mut session_limit.per_role {
+            let minutes: Option<u32> = dec_option(s)?;
+            *slot = minutes
+                .map(u16::try_from)
+                .transpose()
+                .map_err(|_| WireError::PacketWrong)?;
+        }
+        let auto_reset = SSHDecode::dec(s)?;
+        let uart_baud = SSHDecode::dec(s)?;
+        let uart_frame = dec_frame(s)?;
+        let count: u8 = SSHDecode::dec(s)?;
+        let mut baud_ladder = BaudLadder::new();
+        for _ in 0..count {
+            baud_ladder
+                .push(SSHDecode::dec(s)?)
+                .map_err(|_| WireError::PacketWrong)?;
+        }
+        let count: u8 = SSHDecode::dec(s)?;
+        let mut uart_redact = RedactPatterns::new();
+        for _ in 0..count {
+            uart_redact
+                .push(dec_string(s)?)
+                .map_err(|_| WireError::PacketWrong)?;
+        }
+        let console_mirror = SSHDecode::dec(s)?;
+        let count: u8 = SSHDecode::dec(s)?;
+        let mut scripts = heapless::Vec::new();
+        for _ in 0..count {
+            let script = Script {
+                name: dec_string(s)?,
+                text: dec_string(s)?,
+            };
+            scripts.push(script).map_err(|_| WireError::PacketWrong)?;
+        }
+        let raw_tcp_port: u32 = SSHDecode::dec(s)?;
+        let raw_tcp_port = u16::try_from(raw_tcp_port).map_err(|_| WireError::PacketWrong)?;
+
         Ok(Self {
             hostkey,
             pubkeys,
@@ -403,6 +1703,77 @@ impl<'de> SSHDecode<'de> for SSHStampConfig {
             ipv6_static,
             uart_pins,
             first_login,
+            access_lists,
+            wifi_concurrent,
+            ssh_on_ap,
+            ssh_on_sta,
+            ota_on_ap,
+            ota_on_sta,
+            uart_profile,
+            ap_address,
+            dhcp_server,
+            log_console,
+            mux_targets,
+            mux_selected,
+            rollback,
+            user_routes,
+            uart_tx_full,
+            ota_confirm,
+            uart_idle_lock,
+            uart_escape,
+            log_redact,
+            tcp_nodelay,
+            session_limit,
+            auto_reset,
+            uart_baud,
+            uart_frame,
+            baud_ladder,
+            uart_redact,
+            console_mirror,
+            scripts,
+            raw_tcp_port,
         })
     }
 }
+
+#[cfg(test)]
+mod config_tests {
+    use core::net::Ipv4Addr;
+
+    use super::Ipv4Subnet;
+
+    fn subnet(addr: Ipv4Addr, prefix: u8) -> Ipv4Subnet {
+        Ipv4Subnet { addr, prefix }
+    }
+
+    #[test]
+    fn subnet_contains() {
+        let any = subnet(Ipv4Addr::new(10, 1, 2, 3), 0);
+        assert!(any.contains(Ipv4Addr::UNSPECIFIED));
+        assert!(any.contains(Ipv4Addr::BROADCAST));
+
+        let host = subnet(Ipv4Addr::new(10, 0, 0, 7), 32);
+        assert!(host.contains(Ipv4Addr::new(10, 0, 0, 7)));
+        assert!(!host.contains(Ipv4Addr::new(10, 0, 0, 6)));
+        assert!(!host.contains(Ipv4Addr::new(10, 0, 0, 8)));
+
+        // The host bits of the subnet address do not matter.
+        let lan = subnet(Ipv4Addr::new(192, 168, 1, 77), 24);
+        assert!(lan.contains(Ipv4Addr::new(192, 168, 1, 0)));
+        assert!(lan.contains(Ipv4Addr::new(192, 168, 1, 255)));
+        assert!(!lan.contains(Ipv4Addr::new(192, 168, 0, 255)));
+        assert!(!lan.contains(Ipv4Addr::new(192, 168, 2, 0)));
+
+        let private = subnet(Ipv4Addr::new(172, 16, 0, 0), 12);
+        assert!(private.contains(Ipv4Addr::new(172, 31, 255, 255)));
+        assert!(!private.contains(Ipv4Addr::new(172, 15, 255, 255)));
+        assert!(!private.contains(Ipv4Addr::new(172, 32, 0, 0)));
+    }
+
+    #[test]
+    fn subnet_prefixes_past_32_match_one_address() {
+        let host = subnet(Ipv4Addr::new(10, 0, 0, 7), 40);
+        assert!(host.contains(Ipv4Addr::new(10, 0, 0, 7)));
+        assert!(!host.contains(Ipv4Addr::new(10, 0, 0, 6)));
+    }
+}
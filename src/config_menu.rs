@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The `config` SSH subsystem: a small menu for the bridge UART settings.
+//!
+//! `ssh -t -s root@192.168.4.1 config` shows the baud rate, framing and
+//! pins and lets an admin change them without knowing the `SSH_STAMP_*`
+//! names. Each answer is run as `config set` (see [`staging`]), so values
+//! are checked as they are entered, and `s` saves them together with
+//! `config commit`; `q` leaves, discarding them like `config abort`. The
+//! change set is the one `config set` uses, so changes staged before the
+//! menu was opened are saved or discarded with it.
+//!
+//! Baud rate and framing apply when the next bridge session starts, which
+//! rebuilds the UART driver with them; pins after a reset, which saving
+//! them does.
+
+use core::fmt::Write as _;
+
+use embedded_io_async::{Read, Write};
+use ssh_stamp_hal::UartFrame;
+use sunset_async::SunsetMutex;
+
+use crate::commands::{self, CommandLine, LineReader, print};
+use crate::config::{Role, SSHStampConfig, UartPins};
+use crate::platform::PlatformServices;
+use crate::serial::BufferedSerial;
+use crate::staging;
+
+/// Prompt for a menu choice.
+const PROMPT: &[u8] = b"config> ";
+
+/// Runs the menu, see the module docs, until `s` saves, `q`, Ctrl-D on an
+/// empty line or the end of input.
+///
+/// # Errors
+///
+/// Returns an error only if the channel fails.
+pub async fn config_menu<R, W, U, P>(
+    input: R,
+    out: &mut W,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    role: Role,
+) -> Result<(), sunset::Error>
+where
+    R: Read<Error = sunset::Error>,
+    W: Write<Error = sunset::Error>,
+    U: BufferedSerial,
+    P: PlatformServices,
+{
+    let mut lines = LineReader::new(input);
+    let mut line = CommandLine::new();
+    let staged = staging::keys().await;
+    if !staged.is_empty() {
+        print(
+            out,
+            format_args!(
+                "{} changes already staged with `config set`: saving commits them too, quitting discards them\r\n",
+                staged.len()
+            ),
+        )
+        .await?;
+    }
+    loop {
+        show(out, config).await?;
+        out.write_all(PROMPT).await?;
+        if !lines.read_line(out, &mut line).await? {
+            return Ok(());
+        }
+        let (key, question) = match line.trim() {
+            "b" => ("UART_BAUD", "baud rate, 1200 to 5000000"),
+            "f" => ("UART_FRAME", "framing, e.g. 8N1 or 7E1"),
+            "p" => ("UART_PINS", "pins as <rx>:<tx>, used after a reset"),
+            "s" => {
+                commands::run(
+                    "config commit",
+                    lines.input(),
+                    out,
+                    uart,
+                    config,
+                    platform,
+                    role,
+                )
+                .await?;
+                if staging::keys().await.is_empty() {
+                    return Ok(());
+                }
+                continue;
+            }
+            "q" => {
+                if staging::abort().await.is_ok() {
+                    print(out, format_args!("changes discarded\r\n")).await?;
+                }
+                return Ok(());
+            }
+            "" => continue,
+            _ => {
+                print(out, format_args!("choose b, f, p, s or q\r\n")).await?;
+                continue;
+            }
+        };
+        print(out, format_args!("{question}, empty to keep: ")).await?;
+        if !lines.read_line(out, &mut line).await? {
+            return Ok(());
+        }
+        let value = line.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let mut command = CommandLine::new();
+        if write!(command, "config set {key} {value}").is_err() {
+            print(out, format_args!("error: value too long\r\n")).await?;
+            continue;
+        }
+        commands::run(&command, lines.input(), out, uart, config, platform, role).await?;
+    }
+}
+
+/// Prints the settings, staged values where there are any, and the choices.
+async fn show<W>(out: &mut W, config: &SunsetMutex<SSHStampConfig>) -> Result<(), sunset::Error>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let (baud, frame, pins) = match staging::with_staged(uart_settings).await {
+        Some(staged) => staged,
+        None => uart_settings(&config.lock().await),
+    };
+    let mux = config
+        .lock()
+        .await
+        .selected_mux_target()
+        .map(|target| target.name.clone());
+    print(out, format_args!("\r\nbridge UART\r\n")).await?;
+    match mux {
+        Some(name) => {
+            print(
+                out,
+                format_args!("  b  baud rate  {baud}, mux target {name} sets its own\r\n"),
+            )
+            .await?;
+        }
+        None => print(out, format_args!("  b  baud rate  {baud}\r\n")).await?,
+    }
+    print(out, format_args!("  f  framing    {frame}\r\n")).await?;
+    print(
+        out,
+        format_args!("  p  pins       RX {}, TX {}\r\n", pins.rx, pins.tx),
+    )
+    .await?;
+    print(
+        out,
+        format_args!("  s  save and apply\r\n  q  quit without saving\r\n"),
+    )
+    .await
+}
+
+fn uart_settings(config: &SSHStampConfig) -> (u32, UartFrame, UartPins) {
+    (
+        config.uart_baud,
+        config.uart_frame,
+        config.uart_pins.clone(),
+    )
+}
@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Guessing the target's console from its output.
+//!
+//! While a bridge or monitor session runs, the UART output is matched
+//! against a few strings common boot loaders and login prompts print. The
+//! last one seen decides the [`ConsoleKind`], which is announced when the
+//! next session starts, shown by the `console` admin command and picks the
+//! bytes `console break` sends to get the target's attention.
+//!
+//! This is a heuristic: a target that never printed one of the strings while
+//! someone was connected stays unknown. Selecting another mux target
+//! forgets the guess.
+
+use core::cell::RefCell;
+use core::fmt;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use log::info;
+
+/// A kind of console recognised in the target's output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleKind {
+    /// The U-Boot boot loader.
+    UBoot,
+    /// Broadcom's CFE boot loader.
+    Cfe,
+    /// A Linux `getty` login prompt.
+    LinuxLogin,
+    /// `MikroTik` `RouterOS`.
+    RouterOs,
+}
+
+impl ConsoleKind {
+    /// Name shown to the operator.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            ConsoleKind::UBoot => "U-Boot",
+            ConsoleKind::Cfe => "CFE",
+            ConsoleKind::LinuxLogin => "Linux login",
+            ConsoleKind::RouterOs => "RouterOS",
+        }
+    }
+
+    /// Bytes that get the console's attention: Ctrl-C stops a boot loader's
+    /// autoboot countdown, a carriage return brings up a fresh login prompt.
+    #[must_use]
+    pub fn break_sequence(self) -> &'static [u8] {
+        match self {
+            ConsoleKind::UBoot | ConsoleKind::Cfe => b"\x03",
+            ConsoleKind::LinuxLogin | ConsoleKind::RouterOs => b"\r",
+        }
+    }
+}
+
+impl fmt::Display for ConsoleKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Strings that identify a console. The first byte of each does not recur
+/// before its last, so matching restarts on a mismatch without backtracking.
+const MARKERS: &[(&[u8], ConsoleKind)] = &[
+    (b"U-Boot ", ConsoleKind::UBoot),
+    (b"Hit any key to stop autoboot", ConsoleKind::UBoot),
+    (b"CFE version", ConsoleKind::Cfe),
+    (b"CFE> ", ConsoleKind::Cfe),
+    (b" login: ", ConsoleKind::LinuxLogin),
+    (b"MikroTik", ConsoleKind::RouterOs),
+    (b"RouterOS", ConsoleKind::RouterOs),
+];
+
+struct Detector {
+    /// Bytes of each marker matched so far.
+    progress: [usize; MARKERS.len()],
+    kind: Option<ConsoleKind>,
+}
+
+static DETECTOR: Mutex<CriticalSectionRawMutex, RefCell<Detector>> =
+    Mutex::new(RefCell::new(Detector {
+        progress: [0; MARKERS.len()],
+        kind: None,
+    }));
+
+/// Matches target output against the known consoles. Called by the bridge
+/// with every chunk read from the UART.
+pub fn observe(bytes: &[u8]) {
+    let changed = DETECTOR.lock(|detector| {
+        let mut detector = detector.borrow_mut();
+        let mut found = None;
+        for &b in bytes {
+            for (progress, (marker, kind)) in detector.progress.iter_mut().zip(MARKERS) {
+                if marker[*progress] == b {
+                    *progress += 1;
+                    if *progress == marker.len() {
+                        *progress = 0;
+                        found = Some(*kind);
+                    }
+                } else {
+                    *progress = usize::from(marker[0] == b);
+                }
+            }
+        }
+        let found = found.filter(|kind| detector.kind != Some(*kind))?;
+        detector.kind = Some(found);
+        Some(found)
+    });
+    if let Some(kind) = changed {
+        info!("Target console looks like {kind}");
+    }
+}
+
+/// The console last recognised, if any.
+#[must_use]
+pub fn detected() -> Option<ConsoleKind> {
+    DETECTOR.lock(|detector| detector.borrow().kind)
+}
+
+/// Drops the guess, e.g. when another target is connected.
+pub fn forget() {
+    DETECTOR.lock(|detector| {
+        let mut detector = detector.borrow_mut();
+        detector.progress = [0; MARKERS.len()];
+        detector.kind = None;
+    });
+}
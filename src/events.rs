@@ -0,0 +1,219 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! In-memory event log.
+//!
+//! Noteworthy occurrences (rejected connections, failures the device
+//! recovered from, ...) are recorded here in addition to the `log` output,
+//! so they can still be inspected after the console has scrolled away.
+//!
+//! The log is a fixed-size ring: once [`EVENT_LOG_LEN`] entries are stored,
+//! the oldest one is dropped to make room for the next.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::net::{IpAddr, Ipv4Addr};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+use heapless::{Deque, String};
+use log::info;
+
+use crate::ota_history::FirmwareUpdate;
+use crate::sessions::{AlgorithmName, LoginPhase, Negotiated};
+
+/// Number of events kept in RAM.
+pub const EVENT_LOG_LEN: usize = 16;
+
+/// Something worth telling the operator about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A TCP connection was refused by the configured access lists.
+    ConnectionRejected { peer: IpAddr },
+    /// Key exchange proposals were seen for a session, see
+    /// [`crate::sessions`].
+    SessionNegotiated {
+        session: u32,
+        /// Client software version, e.g. `OpenSSH_9.6`.
+        client: String<32>,
+        kex: Option<AlgorithmName>,
+        cipher: Option<AlgorithmName>,
+    },
+    /// More UART receive errors within a minute than
+    /// [`crate::settings::UART_ERROR_ALERT_PER_MINUTE`], see
+    /// [`crate::serial::record_line_error`].
+    UartLineErrors { per_minute: u32 },
+    /// An address kept failing to log in and is refused for a while, see
+    /// [`crate::lockout`].
+    ClientBlocked { peer: IpAddr, secs: u64 },
+    /// Failures from all over made the access point go off the air.
+    AccessPointPaused { secs: u64 },
+    /// A connection took too long to log in and was closed, see
+    /// [`crate::sessions::stalled`].
+    LoginStalled { session: u32, phase: LoginPhase },
+    /// The station's network in AP+STA mode overlapped the access point's,
+    /// which was moved, see [`crate::config::ap_address_beside`].
+    ApAddressMoved { from: Ipv4Addr, to: Ipv4Addr },
+    /// The target drove the UART TX pin before the bridge took it (`tx-sense`
+    /// feature), see `serial::record_pin_sense`.
+    UartTxContention { swapped: bool },
+    /// The baud ladder found a rate the target's output arrives cleanly at
+    /// and saved it, see [`crate::baud_ladder`].
+    UartBaudDetected { baud_rate: u32 },
+    /// The UART RX line kept reporting errors and nothing arrived cleanly,
+    /// see [`crate::serial::line_stuck`].
+    UartLineStuck { errors: u32 },
+    /// Data arrives cleanly again on the stuck RX line.
+    UartLineRecovered,
+    /// The config sector kept failing to erase or write; the config moved
+    /// to the reserve sector, see [`crate::store`].
+    ConfigFailover { from: usize, to: usize },
+    /// This boot is the first of firmware installed over OTA, see
+    /// [`crate::ota_history::booted_update`].
+    FirmwareUpdated(FirmwareUpdate),
+}
+
+impl Event {
+    /// Stable name of the kind of event, for machine-readable output.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ConnectionRejected { .. } => "connection_rejected",
+            Event::SessionNegotiated { .. } => "session_negotiated",
+            Event::UartLineErrors { .. } => "uart_line_errors",
+            Event::ClientBlocked { .. } => "client_blocked",
+            Event::AccessPointPaused { .. } => "access_point_paused",
+            Event::LoginStalled { .. } => "login_stalled",
+            Event::ApAddressMoved { .. } => "ap_address_moved",
+            Event::UartTxContention { .. } => "uart_tx_contention",
+            Event::UartBaudDetected { .. } => "uart_baud_detected",
+            Event::UartLineStuck { .. } => "uart_line_stuck",
+            Event::UartLineRecovered => "uart_line_recovered",
+            Event::ConfigFailover { .. } => "config_failover",
+            Event::FirmwareUpdated(_) => "firmware_updated",
+        }
+    }
+
+    /// Whether the event is also kept in flash until taken, see
+    /// [`crate::outbox`]: it points at trouble with the device or its
+    /// surroundings rather than with one client.
+    #[must_use]
+    pub fn is_durable(&self) -> bool {
+        matches!(
+            self,
+            Event::UartLineErrors { .. }
+                | Event::AccessPointPaused { .. }
+                | Event::ApAddressMoved { .. }
+                | Event::UartTxContention { .. }
+                | Event::UartBaudDetected { .. }
+                | Event::UartLineStuck { .. }
+                | Event::ConfigFailover { .. }
+        )
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::ConnectionRejected { peer } => {
+                write!(f, "connection from {peer} rejected by access lists")
+            }
+            Event::SessionNegotiated {
+                session,
+                client,
+                kex,
+                cipher,
+            } => write!(
+                f,
+                "session {session}: {client}, kex {}, cipher {}",
+                Negotiated(kex),
+                Negotiated(cipher)
+            ),
+            Event::UartLineErrors { per_minute } => write!(
+                f,
+                "{per_minute} UART receive errors within a minute, check the baud rate and wiring"
+            ),
+            Event::ClientBlocked { peer, secs } => {
+                write!(
+                    f,
+                    "{peer} refused for {secs} s after repeated failed logins"
+                )
+            }
+            Event::AccessPointPaused { secs } => write!(
+                f,
+                "access point off the air for {secs} s after repeated failed logins"
+            ),
+            Event::LoginStalled { session, phase } => {
+                write!(f, "session {session}: closed, stalled in {phase}")
+            }
+            Event::ApAddressMoved { from, to } => write!(
+                f,
+                "access point moved from {from} to {to}, the station network overlaps it"
+            ),
+            Event::ConfigFailover { from, to } => write!(
+                f,
+                "config sector at {from:#x} keeps failing, moved the config to {to:#x}"
+            ),
+            Event::UartBaudDetected { baud_rate } => write!(
+                f,
+                "UART switched to {baud_rate} baud, the target's output arrives cleanly there"
+            ),
+            Event::UartLineStuck { errors } => write!(
+                f,
+                "UART RX line stuck, {errors} receive errors and no data; target off or miswired?"
+            ),
+            Event::UartLineRecovered => f.write_str("UART RX line receives data again"),
+            Event::FirmwareUpdated(update) => write!(f, "{update}"),
+            Event::UartTxContention { swapped } => {
+                f.write_str("UART TX contention suspected, the target drives the TX pin")?;
+                if *swapped {
+                    f.write_str("; RX and TX look swapped")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An [`Event`] together with the time it was emitted.
+#[derive(Debug, Clone)]
+pub struct EventRecord {
+    /// Seconds since boot.
+    pub uptime_secs: u64,
+    pub event: Event,
+}
+
+static EVENTS: Mutex<CriticalSectionRawMutex, RefCell<Deque<EventRecord, EVENT_LOG_LEN>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Records `event` in the log, evicting the oldest entry if it is full.
+pub fn emit(event: Event) {
+    info!("Event: {event}");
+    crate::outbox::event(&event);
+    #[cfg(feature = "plugins")]
+    crate::plugins::event(&event);
+    let record = EventRecord {
+        uptime_secs: Instant::now().as_secs(),
+        event,
+    };
+    EVENTS.lock(|events| {
+        let mut events = events.borrow_mut();
+        if events.is_full() {
+            events.pop_front();
+        }
+        let _ = events.push_back(record);
+    });
+}
+
+/// Returns a copy of the `index`th stored event, oldest first.
+#[must_use]
+pub fn get(index: usize) -> Option<EventRecord> {
+    EVENTS.lock(|events| events.borrow().iter().nth(index).cloned())
+}
+
+/// Calls `f` on every stored event, oldest first.
+pub fn for_each(mut f: impl FnMut(&EventRecord)) {
+    EVENTS.lock(|events| events.borrow().iter().for_each(&mut f));
+}
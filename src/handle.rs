@@ -6,8 +6,8 @@
 //!
 //! Every incoming SSH event is dispatched here by the connection loop in
 //! [`serve`](crate::serve). The main entry point is [`session_env`], which
-//! routes environment variable requests to handlers like [`pubkey_env`] and
-//! [`wifi_ssid_env`].
+//! routes environment variable requests to handlers like [`pubkey_env`];
+//! saved settings go through the key table of [`staging`](crate::staging).
 //!
 //! First-boot provisioning also flows through here: when `first_login` is true,
 //! the device accepts any SSH connection (empty password) and allows the
@@ -16,21 +16,67 @@
 use heapless::String;
 use log::{debug, info, warn};
 
-use crate::config::SSHStampConfig;
+#[cfg(feature = "rmt")]
+use crate::auto_reset;
+use crate::clients;
+use crate::commands::{self, CommandLine};
+use crate::config::{
+    MuxTarget, Role, Route, SSHStampConfig, SessionBehavior, TxFullPolicy, UartOverrides,
+};
+use crate::config_menu;
+use crate::console;
+use crate::ota_history;
 use crate::platform::PlatformServices;
-use crate::serial::{BufferedSerial, serial_bridge};
+#[cfg(feature = "plugins")]
+use crate::plugins;
+use crate::resets;
+#[cfg(all(feature = "sftp-ota", feature = "scripts"))]
+use crate::scripts;
+use crate::serial::{self, BufferedSerial, serial_bridge, serial_monitor};
+use crate::sessions;
+use crate::settings::SESSION_LIMIT_WARNING_SECS;
+#[cfg(feature = "sftp-ota")]
+use crate::settings::{SFTP_REPLY_LEN, SFTP_SESSION_SLOTS};
+use crate::staging;
+use crate::stats;
+#[cfg(feature = "sftp-ota")]
+use crate::{telemetry, version};
 
+use embassy_futures::select::{Either, select};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write;
 
 use core::result::Result;
 
+#[cfg(feature = "sftp-ota")]
+use core::str::FromStr;
+#[cfg(feature = "sftp-ota")]
+use ed25519_dalek::VerifyingKey;
+#[cfg(feature = "sftp-ota")]
+use ota::InfoFile;
+#[cfg(feature = "sftp-ota")]
+use ssh_stamp_hal::OtaActions;
 use sunset::packets::PubKey;
 use sunset::{ChanFail, ChanHandle, ServEvent};
-use sunset_async::{ChanInOut, SSHServer, SunsetMutex};
+use sunset_async::{ChanInOut, ChanOut, SSHServer, SunsetMutex};
 
 pub mod env_parser {
+    use core::net::Ipv4Addr;
+    use core::str::FromStr;
+
+    use ssh_stamp_hal::{MUX_SELECT_LINES, Parity, UartConfig, UartFrame};
+
     use super::String;
+    use crate::config::{
+        BaudLadder, BridgeEscape, Ipv4Subnet, MuxTarget, OtaConfirm, RedactPatterns, Role, Route,
+        SessionBehavior, SessionLimit, UartPins, UartProfile, UserRoute,
+    };
+    use crate::settings::{
+        ACCESS_LIST_SLOTS, MUX_TARGET_SLOTS, OTA_CONFIRM_BOOTS, UART_REDACT_MIN_LEN,
+        USER_ROUTE_SLOTS,
+    };
 
     /// Limit the maximum length accepted for an SSH key, Ed25519 lines
     /// should be less than this.
@@ -129,13 +175,393 @@ pub mod env_parser {
         }
         Some([parts[0], parts[1], parts[2], parts[3], parts[4], parts[5]])
     }
+
+    /// Parses a comma-separated list of IPv4 subnets, e.g.
+    /// `192.168.1.0/24,10.0.0.7`. A bare address means a `/32`.
+    ///
+    /// An empty value yields an empty list. Returns `None` if any entry is
+    /// malformed or there are more than `ACCESS_LIST_SLOTS` entries.
+    #[must_use]
+    pub fn parse_subnet_list(value: &str) -> Option<heapless::Vec<Ipv4Subnet, ACCESS_LIST_SLOTS>> {
+        let mut list = heapless::Vec::new();
+        if value.is_empty() {
+            return Some(list);
+        }
+        if !env_sanitize(value) {
+            return None;
+        }
+        for entry in value.split(',') {
+            let (addr, prefix) = match entry.split_once('/') {
+                Some((addr, prefix)) => (addr, prefix.parse::<u8>().ok()?),
+                None => (entry, 32),
+            };
+            if prefix > 32 {
+                return None;
+            }
+            let addr = Ipv4Addr::from_str(addr).ok()?;
+            list.push(Ipv4Subnet { addr, prefix }).ok()?;
+        }
+        Some(list)
+    }
+
+    /// Parses an on/off flag: `1`/`0`, `on`/`off` or `true`/`false`.
+    #[must_use]
+    pub fn parse_flag(value: &str) -> Option<bool> {
+        match value {
+            "1" | "on" | "true" => Some(true),
+            "0" | "off" | "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Parses a comma-separated set of interface names (`ap`, `sta`) into
+    /// `(ap, sta)` enable flags.
+    ///
+    /// Returns `None` on unknown names or when no interface is listed, so
+    /// SSH cannot be switched off on every interface at once.
+    #[must_use]
+    pub fn parse_interfaces(value: &str) -> Option<(bool, bool)> {
+        let (mut ap, mut sta) = (false, false);
+        for name in value.split(',') {
+            match name {
+                "ap" => ap = true,
+                "sta" => sta = true,
+                _ => return None,
+            }
+        }
+        (ap || sta).then_some((ap, sta))
+    }
+
+    /// Parses the device address for the access point network.
+    ///
+    /// The network is always a `/24`, so the host part must not be the
+    /// network (`.0`) or broadcast (`.255`) address. Loopback, multicast and
+    /// unspecified addresses are refused.
+    #[must_use]
+    pub fn parse_ap_address(value: &str) -> Option<Ipv4Addr> {
+        let addr = Ipv4Addr::from_str(value).ok()?;
+        let host = addr.octets()[3];
+        let usable = !addr.is_loopback()
+            && !addr.is_multicast()
+            && !addr.is_unspecified()
+            && !addr.is_broadcast()
+            && host != 0
+            && host != 255;
+        usable.then_some(addr)
+    }
+
+    /// Parses a UART baud rate, `1200..=5_000_000`.
+    #[must_use]
+    pub fn parse_baud_rate(value: &str) -> Option<u32> {
+        let baud_rate = value.parse().ok()?;
+        (1200..=5_000_000).contains(&baud_rate).then_some(baud_rate)
+    }
+
+    /// Parses UART framing such as `8N1` or `7E2`: 5 to 8 data bits, `N`,
+    /// `E` or `O` parity (either case) and 1 or 2 stop bits.
+    #[must_use]
+    pub fn parse_uart_frame(value: &str) -> Option<UartFrame> {
+        let &[data_bits, parity, stop_bits] = value.as_bytes() else {
+            return None;
+        };
+        let frame = UartFrame {
+            data_bits: char::from(data_bits).to_digit(10)?.try_into().ok()?,
+            parity: match parity.to_ascii_uppercase() {
+                b'N' => Parity::None,
+                b'E' => Parity::Even,
+                b'O' => Parity::Odd,
+                _ => return None,
+            },
+            stop_bits: char::from(stop_bits).to_digit(10)?.try_into().ok()?,
+        };
+        ((5..=8).contains(&frame.data_bits) && (1..=2).contains(&frame.stop_bits)).then_some(frame)
+    }
+
+    /// Parses a baud ladder: comma separated baud rates as in
+    /// [`parse_baud_rate`], tried in that order, e.g. `115200,57600,9600`.
+    ///
+    /// An empty value or `off` yields an empty ladder. Returns `None` if a
+    /// rate is malformed or repeated, or there are more than
+    /// `BAUD_LADDER_SLOTS`.
+    #[must_use]
+    pub fn parse_baud_ladder(value: &str) -> Option<BaudLadder> {
+        let mut ladder = BaudLadder::new();
+        if value.is_empty() || value == "off" {
+            return Some(ladder);
+        }
+        for rate in value.split(',') {
+            let baud_rate = parse_baud_rate(rate)?;
+            if ladder.contains(&baud_rate) {
+                return None;
+            }
+            ladder.push(baud_rate).ok()?;
+        }
+        Some(ladder)
+    }
+
+    /// Parses UART redaction patterns: comma separated byte strings masked
+    /// in copies of target output, e.g. `hunter22,s3cr3tPW`, see
+    /// [`crate::redact`].
+    ///
+    /// An empty value or `off` yields no patterns. Returns `None` if a
+    /// pattern is shorter than `UART_REDACT_MIN_LEN` or longer than
+    /// `UART_REDACT_LEN`, or there are more than `UART_REDACT_SLOTS`.
+    #[must_use]
+    pub fn parse_redact_patterns(value: &str) -> Option<RedactPatterns> {
+        let mut patterns = RedactPatterns::new();
+        if value.is_empty() || value == "off" {
+            return Some(patterns);
+        }
+        for pattern in value.split(',') {
+            if pattern.len() < UART_REDACT_MIN_LEN {
+                return None;
+            }
+            patterns.push(String::try_from(pattern).ok()?).ok()?;
+        }
+        Some(patterns)
+    }
+
+    /// Parses UART RX tuning: a preset (`interactive`, `balanced`,
+    /// `throughput`) or `<fifo threshold>/<timeout>`, e.g. `32/4`.
+    ///
+    /// Returns `None` if the value is malformed or out of range.
+    #[must_use]
+    pub fn parse_uart_rx(value: &str) -> Option<UartProfile> {
+        let profile = match value {
+            "interactive" => UartProfile::INTERACTIVE,
+            "balanced" => UartProfile::BALANCED,
+            "throughput" => UartProfile::THROUGHPUT,
+            _ => {
+                let (threshold, timeout) = value.split_once('/')?;
+                UartProfile {
+                    rx_fifo_threshold: threshold.parse().ok()?,
+                    rx_timeout: timeout.parse().ok()?,
+                }
+            }
+        };
+        profile.is_valid().then_some(profile)
+    }
+
+    /// Parses the bridge UART pins, `<rx>:<tx>` GPIO numbers, e.g. `18:17`.
+    ///
+    /// Only the numbers are checked here; whether the chip has such GPIOs
+    /// and they are free is up to the port, which falls back to its default
+    /// pins at boot otherwise.
+    ///
+    /// Returns `None` if the value is malformed or both pins are the same.
+    #[must_use]
+    pub fn parse_uart_pins(value: &str) -> Option<UartPins> {
+        let (rx, tx) = value.split_once(':')?;
+        let pins = UartPins {
+            rx: rx.parse().ok()?,
+            tx: tx.parse().ok()?,
+        };
+        (pins.rx != pins.tx).then_some(pins)
+    }
+
+    /// Parses the raw TCP bridge port, `off` or `0` to turn it off.
+    ///
+    /// Returns `None` if the value is not a port number.
+    #[cfg(feature = "raw-tcp")]
+    #[must_use]
+    pub fn parse_raw_tcp(value: &str) -> Option<u16> {
+        match value {
+            "off" => Some(0),
+            _ => value.parse().ok(),
+        }
+    }
+
+    /// Parses the OTA confirmation window, `<minutes>[,<boots>]`, e.g. `5`
+    /// or `5,3`. `0` turns it off; without `<boots>` the image gets
+    /// [`OTA_CONFIRM_BOOTS`] boots. A zero in either place means no limit
+    /// of that kind.
+    ///
+    /// Returns `None` if the value is malformed or out of range.
+    #[must_use]
+    pub fn parse_ota_confirm(value: &str) -> Option<OtaConfirm> {
+        let (minutes, boots) = match value.split_once(',') {
+            Some((minutes, boots)) => (minutes.parse().ok()?, boots.parse().ok()?),
+            None => {
+                let minutes = value.parse().ok()?;
+                let boots = if minutes == 0 { 0 } else { OTA_CONFIRM_BOOTS };
+                (minutes, boots)
+            }
+        };
+        Some(OtaConfirm { minutes, boots })
+    }
+
+    /// Parses the session length limit, `<minutes>[,<role>=<minutes>...]`,
+    /// e.g. `480` or `480,viewer=60,admin=0`. A zero means no limit, for
+    /// everyone or for that role.
+    ///
+    /// Returns `None` if the value is malformed, out of range or names a
+    /// role twice.
+    #[must_use]
+    pub fn parse_session_limit(value: &str) -> Option<SessionLimit> {
+        let mut fields = value.split(',');
+        let mut limit = SessionLimit {
+            minutes: fields.next()?.parse().ok()?,
+            per_role: [None; 3],
+        };
+        for field in fields {
+            let (role, minutes) = field.split_once('=')?;
+            let slot = &mut limit.per_role[Role::from_name(role)? as usize];
+            if slot.replace(minutes.parse().ok()?).is_some() {
+                return None;
+            }
+        }
+        Some(limit)
+    }
+
+    /// Parses the bridge escape, `<char>[/<timeout ms>]`, e.g. `~` or
+    /// `^]/500`.
+    ///
+    /// `<char>` is a printable ASCII character other than `,`, `:` and `/`,
+    /// or `^` and a letter or one of `@[\]^_` for a control character other
+    /// than NUL, CR and LF. The timeout defaults to 1000 ms.
+    ///
+    /// Returns `None` if the value is malformed or out of range.
+    #[must_use]
+    pub fn parse_escape(value: &str) -> Option<BridgeEscape> {
+        let (key, timeout_ms) = match value.split_once('/') {
+            Some((key, timeout)) => (key, timeout.parse().ok()?),
+            None => (value, BridgeEscape::DEFAULT.timeout_ms),
+        };
+        let character = match key.as_bytes() {
+            [c] if c.is_ascii_graphic() && !b",:/".contains(c) => *c,
+            [b'^', c] if (b'@'..=b'_').contains(&c.to_ascii_uppercase()) => {
+                c.to_ascii_uppercase() - b'@'
+            }
+            _ => return None,
+        };
+        let escape = BridgeEscape {
+            character,
+            timeout_ms,
+        };
+        escape.is_valid().then_some(escape)
+    }
+
+    /// Parses a comma-separated list of mux targets, each
+    /// `<name>:<select>[:<baud>[:<rx>[:<escape>]]]`, e.g.
+    /// `router:0,switch:1:9600:interactive:^]`.
+    ///
+    /// `<select>` is the select line value, `<rx>` is as in
+    /// [`parse_uart_rx`] and `<escape>` as in [`parse_escape`]; the baud rate
+    /// defaults to the UART default, the RX tuning to `balanced` and the
+    /// escape to `SSH_STAMP_UART_ESCAPE`. Names are letters, digits, `-` and
+    /// `_`, and must be unique.
+    ///
+    /// An empty value yields an empty list. Returns `None` if any entry is
+    /// malformed or there are more than `MUX_TARGET_SLOTS` entries.
+    #[must_use]
+    pub fn parse_mux_targets(value: &str) -> Option<heapless::Vec<MuxTarget, MUX_TARGET_SLOTS>> {
+        let mut list: heapless::Vec<MuxTarget, MUX_TARGET_SLOTS> = heapless::Vec::new();
+        if value.is_empty() {
+            return Some(list);
+        }
+        for entry in value.split(',') {
+            let mut fields = entry.split(':');
+            let name = fields.next()?;
+            let valid_name = !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+            if !valid_name || list.iter().any(|t| t.name == name) {
+                return None;
+            }
+            let select: u8 = fields.next()?.parse().ok()?;
+            if select >> MUX_SELECT_LINES != 0 {
+                return None;
+            }
+            let baud_rate = match fields.next() {
+                Some(baud) => parse_baud_rate(baud)?,
+                None => UartConfig::default().baud_rate,
+            };
+            let profile = match fields.next() {
+                Some(rx) => parse_uart_rx(rx)?,
+                None => UartProfile::default(),
+            };
+            let escape = match fields.next() {
+                Some(escape) => Some(parse_escape(escape)?),
+                None => None,
+            };
+            if fields.next().is_some() {
+                return None;
+            }
+            list.push(MuxTarget {
+                name: String::try_from(name).ok()?,
+                select,
+                baud_rate,
+                profile,
+                escape,
+            })
+            .ok()?;
+        }
+        Some(list)
+    }
+
+    /// Parses a comma-separated username routing table, each entry
+    /// `<user>:<behavior>:<role>`, e.g. `config:commands:admin,monitor:monitor:viewer`.
+    ///
+    /// Behaviors are `bridge`, `monitor` and `commands`, roles `viewer`,
+    /// `operator` and `admin`; a `bridge` needs at least `operator`. Names are
+    /// letters, digits, `-`, `_` and `.`, and must be unique.
+    ///
+    /// An empty value yields an empty table (any username bridges as admin).
+    /// Returns `None` if any entry is malformed, there are more than
+    /// `USER_ROUTE_SLOTS` entries, or no username keeps the admin role, which
+    /// would leave the device without a way to change settings.
+    #[must_use]
+    pub fn parse_user_routes(value: &str) -> Option<heapless::Vec<UserRoute, USER_ROUTE_SLOTS>> {
+        let mut list: heapless::Vec<UserRoute, USER_ROUTE_SLOTS> = heapless::Vec::new();
+        if value.is_empty() {
+            return Some(list);
+        }
+        for entry in value.split(',') {
+            let mut fields = entry.split(':');
+            let name = fields.next()?;
+            let valid_name = !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+            if !valid_name || list.iter().any(|r| r.name == name) {
+                return None;
+            }
+            let route = Route {
+                behavior: SessionBehavior::from_name(fields.next()?)?,
+                role: Role::from_name(fields.next()?)?,
+            };
+            if !route.is_valid() || fields.next().is_some() {
+                return None;
+            }
+            list.push(UserRoute {
+                name: String::try_from(name).ok()?,
+                route,
+            })
+            .ok()?;
+        }
+        // Someone must still be able to change the table.
+        list.iter()
+            .any(|r| r.route.role == Role::Admin && r.route.behavior != SessionBehavior::Sftp)
+            .then_some(list)
+    }
 }
 
 #[derive(Debug)]
 pub enum SessionType {
-    Bridge(ChanHandle),
+    /// Serial bridge, with the session's temporary UART settings and its
+    /// route; read-only for [`SessionBehavior::Monitor`].
+    Bridge(ChanHandle, UartOverrides, Route),
     #[cfg(feature = "sftp-ota")]
     Sftp(ChanHandle),
+    /// Admin command from an `exec` request, see [`crate::commands`], run
+    /// with the session's role.
+    Exec(ChanHandle, CommandLine, Role),
+    /// Shell while the bridge is out of service, see
+    /// [`commands::admin_shell`].
+    AdminShell(ChanHandle, Role),
+    /// The `config` subsystem, see [`config_menu`].
+    ConfigMenu(ChanHandle, Role),
 }
 
 pub struct EventContext<'a> {
@@ -143,35 +569,129 @@ pub struct EventContext<'a> {
     pub auth_checked: &'a mut bool,
     pub config_changed: &'a mut bool,
     pub needs_reset: &'a mut bool,
+    /// Set by `SSH_STAMP_BOOT_RECOVERY`, acted upon when the shell is requested.
+    pub boot_recovery: &'a mut bool,
+    /// Set by `SSH_STAMP_SESSION_*`, applied when the bridge starts.
+    pub uart_overrides: &'a mut UartOverrides,
+    /// Chosen by the username at authentication, see `SSH_STAMP_USER_ROUTES`.
+    pub route: &'a mut Route,
 }
 
-/// Handles SSH session subsystem requests (e.g., SFTP).
+/// Handles SSH session subsystem requests: `sftp`, `serial` for the
+/// bridge without a shell (`ssh -s host serial`), for scripted clients,
+/// `stats`, running `show stats` (see [`crate::stats`]), and `config`, a
+/// menu for the UART settings (see [`config_menu`]).
+///
+/// In AP+STA mode SFTP is refused on interfaces excluded by
+/// `SSH_STAMP_OTA_INTERFACES`, and with `SSH_STAMP_USER_ROUTES` set to
+/// users not routed to [`SessionBehavior::Sftp`].
 ///
 /// # Errors
 ///
 /// Returns an error if SSH protocol operations fail.
-pub fn session_subsystem(
+pub async fn session_subsystem<P: PlatformServices>(
     ev: ServEvent<'_, '_>,
     ctx: &mut EventContext<'_>,
-    #[cfg(feature = "sftp-ota")] chan_pipe: &Channel<NoopRawMutex, SessionType, 1>,
+    config: &SunsetMutex<SSHStampConfig>,
+    chan_pipe: &Channel<NoopRawMutex, SessionType, 1>,
+    platform: &P,
 ) -> Result<(), sunset::Error> {
     if let ServEvent::SessionSubsystem(a) = ev {
         debug!("ServEvent::SessionSubsystem");
+        let name = a.command()?.to_lowercase();
 
         if !*ctx.auth_checked {
             warn!("Unauthenticated SessionSubsystem rejected");
             a.fail()?;
-        } else if a.command()?.to_lowercase().as_str() == "sftp" {
-            if let Some(ch) = ctx.session.take() {
+        } else if name.as_str() == "serial" {
+            if !ctx.route.behavior.bridges() {
+                warn!(
+                    "Serial subsystem rejected, this user's sessions are for {}",
+                    ctx.route.behavior.name()
+                );
+                a.fail()?;
+            } else if let Some(fault) = serial::bridge_fault() {
+                warn!("Serial subsystem rejected, the bridge is disabled: {fault}");
+                a.fail()?;
+            } else if let Some(ch) = ctx.session.take() {
+                if prepare_bridge(ctx, config, platform).await {
+                    debug_assert!(ch.num() == a.channel());
+                    a.succeed()?;
+                    debug!("We got serial subsystem");
+                    start_bridge(ch, ctx, chan_pipe, platform);
+                } else {
+                    a.fail()?;
+                }
+            } else {
+                a.fail()?;
+            }
+        } else if name.as_str() == "stats" {
+            if ctx.route.behavior == SessionBehavior::Sftp {
+                warn!("Stats subsystem rejected, this user only uploads firmware");
+                a.fail()?;
+            } else if let (Ok(line), Some(ch)) =
+                (CommandLine::try_from("show stats"), ctx.session.take())
+            {
+                debug_assert!(ch.num() == a.channel());
+                a.succeed()?;
+                match chan_pipe.try_send(SessionType::Exec(ch, line, ctx.route.role)) {
+                    Ok(()) => *ctx.auth_checked = false,
+                    Err(e) => log::error!("Could not send the channel: {e:?}"),
+                }
+            } else {
+                a.fail()?;
+            }
+        } else if ctx.route.role < Role::Admin {
+            warn!("SessionSubsystem needs the admin role, rejected");
+            a.fail()?;
+        } else if name.as_str() == "config" {
+            if ctx.route.behavior == SessionBehavior::Sftp {
+                warn!("Config subsystem rejected, this user only uploads firmware");
+                a.fail()?;
+            } else if !save_changes(ctx, config, platform).await {
+                a.fail()?;
+            } else if let Some(ch) = ctx.session.take() {
+                debug_assert!(ch.num() == a.channel());
+                a.succeed()?;
+                debug!("We got config subsystem");
+                match chan_pipe.try_send(SessionType::ConfigMenu(ch, ctx.route.role)) {
+                    Ok(()) => *ctx.auth_checked = false,
+                    Err(e) => log::error!("Could not send the channel: {e:?}"),
+                }
+            } else {
+                a.fail()?;
+            }
+        } else if name.as_str() == "sftp" {
+            let interface = sessions::current_interface();
+            let (permitted, routed) = {
+                let config = config.lock().await;
+                (
+                    config.ota_permitted(interface),
+                    !config.user_routes.is_empty(),
+                )
+            };
+            if !permitted {
+                if let Some(interface) = interface {
+                    warn!("SFTP not allowed on the {interface} interface, rejected");
+                }
+                a.fail()?;
+            } else if routed && ctx.route.behavior != SessionBehavior::Sftp {
+                warn!("SFTP rejected, firmware uploads need a user routed to sftp");
+                a.fail()?;
+            } else if let Some(ch) = ctx.session.take() {
                 debug_assert!(ch.num() == a.channel());
                 #[cfg(feature = "sftp-ota")]
-                {
+                if sftp_slot_free() {
                     a.succeed()?;
                     debug!("We got SFTP subsystem");
                     match chan_pipe.try_send(SessionType::Sftp(ch)) {
                         Ok(_) => *ctx.auth_checked = false,
                         Err(e) => log::error!("Could not send the channel: {e:?}"),
                     };
+                } else {
+                    warn!("All {SFTP_SESSION_SLOTS} SFTP buffers in use, refusing the session");
+                    *ctx.session = Some(ch);
+                    a.fail()?;
                 }
                 #[cfg(not(feature = "sftp-ota"))]
                 {
@@ -206,28 +726,24 @@ pub async fn session_shell<P: PlatformServices>(
         if !*ctx.auth_checked {
             warn!("Unauthenticated SessionShell rejected");
             a.fail()?;
+        } else if !ctx.route.behavior.bridges() {
+            warn!(
+                "SessionShell rejected, this user's sessions are for {}",
+                ctx.route.behavior.name()
+            );
+            a.fail()?;
         } else if let Some(ch) = ctx.session.take() {
-            if *ctx.config_changed {
-                *ctx.config_changed = false;
-                let config_guard = config.lock().await;
-                platform
-                    .save_config(&config_guard)
-                    .await
-                    .map_err(|_| sunset::error::BadUsage.build())?;
-                drop(config_guard);
-                if *ctx.needs_reset {
-                    info!("Configuration saved. Rebooting to apply WiFi changes...");
-                    platform.reset();
+            if prepare_bridge(ctx, config, platform).await {
+                debug_assert!(ch.num() == a.channel());
+                a.succeed()?;
+                debug!("We got shell");
+                if serial::bridge_fault().is_some() {
+                    start_admin_shell(ch, ctx, chan_pipe);
+                } else {
+                    start_bridge(ch, ctx, chan_pipe, platform);
                 }
-            }
-            debug_assert!(ch.num() == a.channel());
-            a.succeed()?;
-            debug!("We got shell");
-            platform.activate_uart();
-            debug!("Connection loop: UART activated");
-            match chan_pipe.try_send(SessionType::Bridge(ch)) {
-                Ok(()) => *ctx.auth_checked = false,
-                Err(e) => log::error!("Could not send the channel: {e:?}"),
+            } else {
+                a.fail()?;
             }
         } else {
             a.fail()?;
@@ -236,6 +752,88 @@ pub async fn session_shell<P: PlatformServices>(
     Ok(())
 }
 
+/// Saves settings changed through env vars (resetting if they need it) and
+/// enters recovery if requested, before a shell or `serial` subsystem is
+/// answered. Returns `false` if the request is to be refused: the settings
+/// could not be saved, or this build has no bridge.
+async fn prepare_bridge<P: PlatformServices>(
+    ctx: &mut EventContext<'_>,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> bool {
+    if !save_changes(ctx, config, platform).await {
+        return false;
+    }
+    if *ctx.boot_recovery {
+        info!("Rebooting into the recovery image...");
+        if let Err(e) = platform.boot_recovery().await {
+            warn!("Cannot boot into recovery: {e}");
+        }
+        *ctx.boot_recovery = false;
+    }
+    if cfg!(feature = "recovery") {
+        warn!("Recovery image: serial bridge disabled, upload firmware over SFTP");
+        return false;
+    }
+    true
+}
+
+/// Saves settings changed through env vars, resetting if they need it.
+/// Returns `false` if they could not be saved.
+///
+/// Runs while the request's event holds the connection, so nothing is
+/// started on settings that were never stored.
+async fn save_changes<P: PlatformServices>(
+    ctx: &mut EventContext<'_>,
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> bool {
+    if !*ctx.config_changed {
+        return true;
+    }
+    let config_guard = config.lock().await;
+    if let Err(e) = platform.save_config(&config_guard).await {
+        warn!("Cannot save the settings, refusing the session: {e}");
+        return false;
+    }
+    drop(config_guard);
+    *ctx.config_changed = false;
+    if *ctx.needs_reset {
+        info!("Configuration saved. Rebooting to apply WiFi changes...");
+        platform.reset();
+    }
+    true
+}
+
+/// Hands `ch` to [`ssh_client`] as a bridge session.
+fn start_bridge<P: PlatformServices>(
+    ch: ChanHandle,
+    ctx: &mut EventContext<'_>,
+    chan_pipe: &Channel<NoopRawMutex, SessionType, 1>,
+    platform: &P,
+) {
+    platform.activate_uart();
+    debug!("Connection loop: UART activated");
+    let bridge = SessionType::Bridge(ch, *ctx.uart_overrides, *ctx.route);
+    match chan_pipe.try_send(bridge) {
+        Ok(()) => *ctx.auth_checked = false,
+        Err(e) => log::error!("Could not send the channel: {e:?}"),
+    }
+}
+
+/// Hands `ch` to [`ssh_client`] as an admin console, for a shell while the
+/// bridge is out of service.
+fn start_admin_shell(
+    ch: ChanHandle,
+    ctx: &mut EventContext<'_>,
+    chan_pipe: &Channel<NoopRawMutex, SessionType, 1>,
+) {
+    match chan_pipe.try_send(SessionType::AdminShell(ch, ctx.route.role)) {
+        Ok(()) => *ctx.auth_checked = false,
+        Err(e) => log::error!("Could not send the channel: {e:?}"),
+    }
+}
+
 /// Handles the first authentication request.
 ///
 /// # Errors
@@ -281,6 +879,11 @@ pub async fn hostkeys(
 
 /// Rejects password authentication requests.
 ///
+/// The password is not looked at, so no hash is computed while the event
+/// holds the connection. Should password auth return, its bcrypt check
+/// takes hundreds of milliseconds and belongs outside [`crate::serve`]'s
+/// event handling.
+///
 /// # Errors
 ///
 /// Returns an error if SSH protocol operations fail.
@@ -306,16 +909,19 @@ pub async fn pubkey_auth(
         debug!("ServEvent::PubkeyAuth");
         let config_guard = config.lock().await;
         let client_pubkey = a.pubkey()?;
+        let Some(route) = config_guard.route_for(a.username()?) else {
+            warn!("No route for user {}, rejecting", a.username()?);
+            a.reject()?;
+            return Ok(());
+        };
 
         match client_pubkey {
             PubKey::Ed25519(presented) => {
-                let matched = config_guard
-                    .pubkeys
-                    .iter()
-                    .any(|slot| slot.as_ref().is_some_and(|stored| *stored == presented));
-
-                if matched {
+                if config_guard.knows_key(&presented) {
                     *ctx.auth_checked = true;
+                    sessions::authenticated(Some(a.username()?));
+                    clients::seen(a.username()?, presented.key.0, sessions::current_peer());
+                    *ctx.route = route;
                     a.allow()?;
                 } else {
                     debug!("No matching pubkey slot found");
@@ -369,30 +975,60 @@ pub async fn session_env(
         debug!("ENV name: {}", a.name()?);
         debug!("ENV value: {}", a.value()?);
 
-        match a.name()? {
+        let name = a.name()?;
+        if *ctx.auth_checked
+            && ctx.route.behavior == SessionBehavior::Sftp
+            && name.starts_with("SSH_STAMP_")
+        {
+            warn!("{name} rejected, this user only uploads firmware");
+            a.fail()?;
+            return Ok(());
+        }
+        if *ctx.auth_checked
+            && ctx.route.role < Role::Admin
+            && name.starts_with("SSH_STAMP_")
+            && !name.starts_with("SSH_STAMP_SESSION_")
+        {
+            warn!("{name} needs the admin role; rejecting");
+            a.fail()?;
+            return Ok(());
+        }
+
+        match name {
             "LANG" => {
                 a.succeed()?;
             }
             "SSH_STAMP_PUBKEY" => {
                 pubkey_env(a, config, ctx).await?;
             }
-            "SSH_STAMP_WIFI_AP_SSID" => {
-                wifi_ap_ssid_env(a, config, ctx).await?;
+            "SSH_STAMP_SESSION_BAUD" => {
+                session_baud_env(a, config, ctx).await?;
             }
-            "SSH_STAMP_WIFI_AP_PSK" => {
-                wifi_ap_psk_env(a, config, ctx).await?;
+            "SSH_STAMP_SESSION_BINARY" => {
+                session_binary_env(a, config, ctx).await?;
             }
-            "SSH_STAMP_WIFI_STA_SSID" => {
-                wifi_sta_ssid_env(a, config, ctx).await?;
+            "SSH_STAMP_SESSION_UART_RX" => {
+                session_uart_rx_env(a, config, ctx).await?;
             }
-            "SSH_STAMP_WIFI_STA_PW" => {
-                wifi_sta_psk_env(a, config, ctx).await?;
+            #[cfg(feature = "rmt")]
+            "SSH_STAMP_SESSION_RESET" => {
+                session_reset_env(a, config, ctx).await?;
             }
-            "SSH_STAMP_WIFI_MAC_ADDRESS" => {
-                wifi_mac_address_env(a, config, ctx).await?;
+            "SSH_STAMP_BOOT_RECOVERY" => {
+                if !*ctx.auth_checked {
+                    warn!("SSH_STAMP_BOOT_RECOVERY env received but not authenticated; rejecting");
+                    a.fail()?;
+                } else if let Some(enabled) = env_parser::parse_flag(a.value()?) {
+                    // `0` asks for the normal session.
+                    *ctx.boot_recovery = enabled;
+                    a.succeed()?;
+                } else {
+                    warn!("SSH_STAMP_BOOT_RECOVERY must be 1 or 0");
+                    a.fail()?;
+                }
             }
-            "SSH_STAMP_WIFI_MAC_RANDOM" => {
-                wifi_mac_random_env(a, config, ctx).await?;
+            name if name.starts_with("SSH_STAMP_") && staging::is_key(name) => {
+                setting_env(a, config, ctx).await?;
             }
             _ => {
                 debug!("Ignoring unknown environment variable: {}", a.name()?);
@@ -429,6 +1065,7 @@ pub async fn pubkey_env(
                         config_guard.first_login = false;
                         *ctx.config_changed = true;
                         *ctx.auth_checked = true;
+                        sessions::authenticated(None);
                     }
                 } else {
                     warn!("Failed to add new pubkey from ENV");
@@ -444,164 +1081,153 @@ pub async fn pubkey_env(
     Ok(())
 }
 
-/// Handles `SSH_STAMP_WIFI_AP_SSID` environment variable requests.
+/// Handles the `SSH_STAMP_*` environment variables that change saved
+/// settings, as listed in [`staging`].
+///
+/// Settings that apply without a reset (logging, UART redaction) take effect
+/// at once; the rest apply from the next bridge session or the reset after
+/// this one.
 ///
 /// # Errors
-/// Returns an error if SSH protocol operations fail or if the SSID is invalid.
-pub async fn wifi_ap_ssid_env(
+/// Returns an error if SSH protocol operations fail.
+async fn setting_env(
     a: sunset::event::ServEnvironmentRequest<'_, '_>,
     config: &SunsetMutex<SSHStampConfig>,
     ctx: &mut EventContext<'_>,
 ) -> Result<(), sunset::Error> {
+    let name = a.name()?;
     let mut config_guard = config.lock().await;
-    if *ctx.auth_checked || config_guard.first_login {
-        if let Some(s) = env_parser::parse_wifi_ap_ssid(a.value()?) {
-            config_guard.wifi_ap_ssid = s;
-            debug!("Set wifi Access Point SSID from ENV");
-            a.succeed()?;
-            *ctx.config_changed = true;
-            *ctx.needs_reset = true;
-        } else {
-            warn!("SSH_STAMP_WIFI_AP_SSID invalid and/or too long");
-            a.fail()?;
-        }
+    if !(*ctx.auth_checked || config_guard.first_login) {
+        warn!("{name} env received but not authenticated; rejecting");
+        a.fail()?;
+        return Ok(());
+    }
+    if let Some(needs_reset) = staging::apply_key(&mut config_guard, name, a.value()?) {
+        // Logged before applying so the console shows why it went quiet.
+        debug!("Set {name} from ENV");
+        staging::apply_live(&config_guard);
+        a.succeed()?;
+        *ctx.config_changed = true;
+        *ctx.needs_reset |= needs_reset;
     } else {
-        warn!("SSH_STAMP_WIFI_AP_SSID env received but not authenticated; rejecting");
+        warn!("{name} has an invalid value; rejecting");
         a.fail()?;
     }
     Ok(())
 }
 
-/// Handles `SSH_STAMP_WIFI_AP_PSK` environment variable requests.
+/// Handles `SSH_STAMP_SESSION_BAUD` environment variable requests.
+///
+/// The baud rate only applies to this session's bridge and is not saved.
+/// If the UART rejects it, the bridge says so and runs at the saved rate.
 ///
 /// # Errors
-/// Returns an error if SSH protocol operations fail or if the PSK is invalid.
-pub async fn wifi_ap_psk_env(
+/// Returns an error if SSH protocol operations fail.
+pub async fn session_baud_env(
     a: sunset::event::ServEnvironmentRequest<'_, '_>,
     config: &SunsetMutex<SSHStampConfig>,
     ctx: &mut EventContext<'_>,
 ) -> Result<(), sunset::Error> {
-    let mut config_guard = config.lock().await;
-    if *ctx.auth_checked || config_guard.first_login {
-        if let Some(s) = env_parser::parse_wifi_psk(a.value()?) {
-            config_guard.wifi_ap_pw = s;
-            debug!("Set WIFI AP PSK from ENV");
+    if *ctx.auth_checked || config.lock().await.first_login {
+        if let Some(baud_rate) = env_parser::parse_baud_rate(a.value()?) {
+            debug!("Set session UART baud rate to {baud_rate}");
+            ctx.uart_overrides.baud_rate = Some(baud_rate);
             a.succeed()?;
-            *ctx.config_changed = true;
-            *ctx.needs_reset = true;
         } else {
-            warn!("SSH_STAMP_WIFI_AP_PSK invalid and/or not within 8-63 characters");
+            warn!("SSH_STAMP_SESSION_BAUD must be within 1200-5000000");
             a.fail()?;
         }
     } else {
-        warn!("SSH_STAMP_WIFI_AP_PSK env received but not authenticated; rejecting");
+        warn!("SSH_STAMP_SESSION_BAUD env received but not authenticated; rejecting");
         a.fail()?;
     }
     Ok(())
 }
 
-/// Handles `SSH_STAMP_WIFI_STA_SSID` environment variable requests.
+/// Handles `SSH_STAMP_SESSION_BINARY` environment variable requests.
+///
+/// Makes this session's bridge strictly binary; not saved.
 ///
 /// # Errors
-/// Returns an error if SSH protocol operations fail or if the SSID is invalid.
-pub async fn wifi_sta_ssid_env(
+/// Returns an error if SSH protocol operations fail.
+pub async fn session_binary_env(
     a: sunset::event::ServEnvironmentRequest<'_, '_>,
     config: &SunsetMutex<SSHStampConfig>,
     ctx: &mut EventContext<'_>,
 ) -> Result<(), sunset::Error> {
-    let mut config_guard = config.lock().await;
-    if *ctx.auth_checked || config_guard.first_login {
-        if let Some(s) = env_parser::parse_wifi_station_ssid(a.value()?) {
-            config_guard.wifi_sta_ssid = s;
-            debug!("Set wifi STATION SSID from ENV");
+    if *ctx.auth_checked || config.lock().await.first_login {
+        if let Some(binary) = env_parser::parse_flag(a.value()?) {
+            debug!("Set session binary bridge to {binary}");
+            ctx.uart_overrides.binary = binary;
             a.succeed()?;
-            *ctx.config_changed = true;
-            *ctx.needs_reset = true;
         } else {
-            warn!("SSH_STAMP_WIFI_STA_SSID invalid and/or too long");
+            warn!("SSH_STAMP_SESSION_BINARY must be 1 or 0");
             a.fail()?;
         }
     } else {
-        warn!("SSH_STAMP_WIFI_STA_SSID env received but not authenticated; rejecting");
+        warn!("SSH_STAMP_SESSION_BINARY env received but not authenticated; rejecting");
         a.fail()?;
     }
     Ok(())
 }
 
-/// Handles `SSH_STAMP_WIFI_STA_PSK` environment variable requests.
+/// Handles `SSH_STAMP_SESSION_RESET` environment variable requests.
+///
+/// Resets the target as this session's bridge starts, see
+/// [`crate::auto_reset`]. Needs the operator role, like writing to the
+/// UART.
 ///
 /// # Errors
-/// Returns an error if SSH protocol operations fail or if the SSID is invalid.
-pub async fn wifi_sta_psk_env(
+/// Returns an error if SSH protocol operations fail.
+#[cfg(feature = "rmt")]
+pub async fn session_reset_env(
     a: sunset::event::ServEnvironmentRequest<'_, '_>,
     config: &SunsetMutex<SSHStampConfig>,
     ctx: &mut EventContext<'_>,
 ) -> Result<(), sunset::Error> {
-    let mut config_guard = config.lock().await;
-    if *ctx.auth_checked || config_guard.first_login {
-        if let Some(s) = env_parser::parse_wifi_psk(a.value()?) {
-            config_guard.wifi_sta_pw = s;
-            debug!("Set wifi STATION PSK from ENV");
+    if *ctx.auth_checked || config.lock().await.first_login {
+        if ctx.route.role < Role::Operator {
+            warn!("SSH_STAMP_SESSION_RESET needs the operator role; rejecting");
+            a.fail()?;
+        } else if let Some(reset) = env_parser::parse_flag(a.value()?) {
+            debug!("Set session target reset to {reset}");
+            ctx.uart_overrides.reset = reset;
             a.succeed()?;
-            *ctx.config_changed = true;
-            *ctx.needs_reset = true;
         } else {
-            warn!("SSH_STAMP_WIFI_STA_PSK invalid and/or not within 8-63 characters");
+            warn!("SSH_STAMP_SESSION_RESET must be 1 or 0");
             a.fail()?;
         }
     } else {
-        warn!("SSH_STAMP_WIFI_STA_PSK env received but not authenticated; rejecting");
+        warn!("SSH_STAMP_SESSION_RESET env received but not authenticated; rejecting");
         a.fail()?;
     }
     Ok(())
 }
 
-/// Handles `SSH_STAMP_WIFI_MAC_ADDRESS` environment variable requests.
+/// Handles `SSH_STAMP_SESSION_UART_RX` environment variable requests.
+///
+/// Like `SSH_STAMP_UART_RX`, but only for this session's bridge.
 ///
 /// # Errors
-/// Returns an error if SSH protocol operations fail or if the MAC address is invalid.
-pub async fn wifi_mac_address_env(
+/// Returns an error if SSH protocol operations fail.
+pub async fn session_uart_rx_env(
     a: sunset::event::ServEnvironmentRequest<'_, '_>,
     config: &SunsetMutex<SSHStampConfig>,
     ctx: &mut EventContext<'_>,
 ) -> Result<(), sunset::Error> {
-    let mut config_guard = config.lock().await;
-    if *ctx.auth_checked || config_guard.first_login {
-        if let Some(mac) = env_parser::parse_mac_address(a.value()?) {
-            config_guard.mac = mac;
-            debug!("Set MAC address from ENV: {mac:02X?}");
+    if *ctx.auth_checked || config.lock().await.first_login {
+        if let Some(profile) = env_parser::parse_uart_rx(a.value()?) {
+            debug!("Set session UART RX tuning to {profile:?}");
+            ctx.uart_overrides.profile = Some(profile);
             a.succeed()?;
-            *ctx.config_changed = true;
-            *ctx.needs_reset = true;
         } else {
-            warn!("SSH_STAMP_WIFI_MAC_ADDRESS must be XX:XX:XX:XX:XX:XX format");
+            warn!(
+                "SSH_STAMP_SESSION_UART_RX must be interactive, balanced, throughput or <1-127>/<1-100>"
+            );
             a.fail()?;
         }
     } else {
-        warn!("SSH_STAMP_WIFI_MAC_ADDRESS env received but not authenticated; rejecting");
-        a.fail()?;
-    }
-    Ok(())
-}
-
-/// Handles `SSH_STAMP_WIFI_MAC_RANDOM` environment variable requests.
-///
-/// # Errors
-/// Returns an error if SSH protocol operations fail or if authentication is missing.
-pub async fn wifi_mac_random_env(
-    a: sunset::event::ServEnvironmentRequest<'_, '_>,
-    config: &SunsetMutex<SSHStampConfig>,
-    ctx: &mut EventContext<'_>,
-) -> Result<(), sunset::Error> {
-    let mut config_guard = config.lock().await;
-    if *ctx.auth_checked || config_guard.first_login {
-        config_guard.mac = [0xFF; 6];
-        debug!("Set MAC address to random mode");
-        a.succeed()?;
-        *ctx.config_changed = true;
-        *ctx.needs_reset = true;
-    } else {
-        warn!("SSH_STAMP_WIFI_MAC_RANDOM env received but not authenticated; rejecting");
+        warn!("SSH_STAMP_SESSION_UART_RX env received but not authenticated; rejecting");
         a.fail()?;
     }
     Ok(())
@@ -631,14 +1257,48 @@ pub async fn session_pty(
     Ok(())
 }
 
-/// Rejects SSH exec requests.
+/// Handles SSH exec requests by handing the command line to
+/// [`ssh_client`], which runs it as an admin command.
+///
+/// Before any admin key is installed, `provision` is the one command
+/// accepted without one: the bundle it reads carries its own signature,
+/// see [`crate::provisioning`].
 ///
 /// # Errors
 ///
 /// Returns an error if SSH protocol operations fail.
-pub fn session_exec(ev: ServEvent<'_, '_>) -> Result<(), sunset::Error> {
+pub async fn session_exec(
+    ev: ServEvent<'_, '_>,
+    ctx: &mut EventContext<'_>,
+    config: &SunsetMutex<SSHStampConfig>,
+    chan_pipe: &Channel<NoopRawMutex, SessionType, 1>,
+) -> Result<(), sunset::Error> {
     if let ServEvent::SessionExec(a) = ev {
-        a.fail()?;
+        debug!("ServEvent::SessionExec");
+
+        let provisioning =
+            a.command()?.trim() == "provision" && { config.lock().await.first_login };
+        if !*ctx.auth_checked && !provisioning {
+            warn!("Unauthenticated SessionExec rejected");
+            a.fail()?;
+        } else if *ctx.auth_checked && ctx.route.behavior == SessionBehavior::Sftp {
+            warn!("SessionExec rejected, this user only uploads firmware");
+            a.fail()?;
+        } else if let Ok(line) = CommandLine::try_from(a.command()?) {
+            if let Some(ch) = ctx.session.take() {
+                debug_assert!(ch.num() == a.channel());
+                a.succeed()?;
+                match chan_pipe.try_send(SessionType::Exec(ch, line, ctx.route.role)) {
+                    Ok(()) => *ctx.auth_checked = false,
+                    Err(e) => log::error!("Could not send the channel: {e:?}"),
+                }
+            } else {
+                a.fail()?;
+            }
+        } else {
+            warn!("Exec command line too long");
+            a.fail()?;
+        }
     }
     Ok(())
 }
@@ -653,6 +1313,181 @@ pub fn defunct() -> Result<(), sunset::Error> {
     sunset::error::BadUsage.fail()
 }
 
+/// Writes the notices a bridge session starts with: the mux target, and
+/// anything about the target or the device the user should know. The first
+/// session after an OTA update also tells which firmware now runs.
+async fn announce<W>(
+    out: &mut W,
+    target: Option<&MuxTarget>,
+    baud_rate: u32,
+) -> Result<(), sunset::Error>
+where
+    W: Write<Error = sunset::Error>,
+{
+    if let Some(target) = target {
+        commands::print(
+            out,
+            format_args!("ssh-stamp: target {} at {baud_rate} baud\r\n", target.name),
+        )
+        .await?;
+    }
+    #[cfg(feature = "tx-sense")]
+    if serial::tx_contention().is_some() {
+        commands::print(
+            out,
+            format_args!("ssh-stamp: UART TX contention suspected, see `show uart`\r\n"),
+        )
+        .await?;
+    }
+    if let Some(kind) = console::detected() {
+        commands::print(
+            out,
+            format_args!("ssh-stamp: target console looks like {kind}\r\n"),
+        )
+        .await?;
+    }
+    if let Some(update) = ota_history::take_update() {
+        commands::print(out, format_args!("ssh-stamp: {update}\r\n")).await?;
+    }
+    if let Some(reason) = resets::boot_reason()
+        && reason.is_fault()
+    {
+        commands::print(
+            out,
+            format_args!("ssh-stamp: last reset: {reason}, see `show resets`\r\n"),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Tells the plugins about the session [`ssh_client`] is starting.
+#[cfg(feature = "plugins")]
+fn session_started(behavior: SessionBehavior, role: Role) {
+    let user = sessions::current_user().unwrap_or_default();
+    plugins::session_started(&plugins::SessionStart {
+        user: &user,
+        peer: sessions::current_peer(),
+        behavior,
+        role,
+    });
+}
+
+/// Runs `session` for at most `limit` minutes, if given. A warning goes to
+/// `stderr` [`SESSION_LIMIT_WARNING_SECS`] before the limit, and a notice
+/// when it is reached; the session then ends like a disconnect.
+async fn time_limited<F, W>(
+    session: F,
+    limit: Option<u16>,
+    stderr: &mut W,
+) -> Result<(), sunset::Error>
+where
+    F: Future<Output = Result<(), sunset::Error>>,
+    W: Write<Error = sunset::Error>,
+{
+    let Some(minutes) = limit else {
+        return session.await;
+    };
+    let length = Duration::from_secs(u64::from(minutes) * 60);
+    let deadline = Instant::now() + length;
+    let clock = async {
+        let warning = Duration::from_secs(SESSION_LIMIT_WARNING_SECS).min(length);
+        Timer::at(deadline - warning).await;
+        commands::print(
+            stderr,
+            format_args!(
+                "\r\nssh-stamp: session time limit, disconnecting in {}s\r\n",
+                warning.as_secs()
+            ),
+        )
+        .await?;
+        Timer::at(deadline).await;
+        commands::print(
+            stderr,
+            format_args!("\r\nssh-stamp: session time limit of {minutes} minutes reached\r\n"),
+        )
+        .await
+    };
+    match select(session, clock).await {
+        Either::First(result) => result,
+        Either::Second(result) => {
+            info!("Session ended by its {minutes} minute time limit");
+            result
+        }
+    }
+}
+
+/// SFTP request buffers, only linked into `sftp-ota` builds and kept out of
+/// the connection future like [`crate::app::ConnectionBuffers`]. A session
+/// holds one of the [`SFTP_SESSION_SLOTS`] while it runs.
+#[cfg(feature = "sftp-ota")]
+static SFTP_REQUEST_BUFFERS: [SunsetMutex<[u8; ota::SFTP_REQUEST_LEN]>; SFTP_SESSION_SLOTS] =
+    [const { SunsetMutex::new([0; ota::SFTP_REQUEST_LEN]) }; SFTP_SESSION_SLOTS];
+
+/// Whether an SFTP session could get a request buffer now.
+#[cfg(feature = "sftp-ota")]
+fn sftp_slot_free() -> bool {
+    SFTP_REQUEST_BUFFERS
+        .iter()
+        .any(|buffer| buffer.try_lock().is_ok())
+}
+
+/// Text of the event log and counters files, see [`telemetry`], kept out of
+/// the connection future likewise.
+#[cfg(feature = "sftp-ota")]
+static SFTP_TELEMETRY: SunsetMutex<telemetry::Snapshot> =
+    SunsetMutex::new(telemetry::Snapshot::new());
+
+/// The last script run, see [`scripts`], kept out of the connection future
+/// likewise.
+#[cfg(all(feature = "sftp-ota", feature = "scripts"))]
+static SFTP_SCRIPT_RESULT: SunsetMutex<scripts::ResultFile> =
+    SunsetMutex::new(scripts::ResultFile::new());
+
+/// OpenSSH public key OTA images must be signed with, from
+/// `SSH_STAMP_OTA_KEY` at build time. Without it unsigned images are
+/// accepted, guarded against corruption by their checksums only.
+#[cfg(feature = "sftp-ota")]
+pub const OTA_KEY: Option<&str> = option_env!("SSH_STAMP_OTA_KEY");
+
+/// The key from [`OTA_KEY`], `None` if the build has none.
+///
+/// # Errors
+/// Returns an error if [`OTA_KEY`] is not an Ed25519 key, so a build with a
+/// mistyped key refuses every image rather than accepting unsigned ones.
+#[cfg(feature = "sftp-ota")]
+fn firmware_key() -> Result<Option<VerifyingKey>, sunset::Error> {
+    let Some(key) = OTA_KEY else {
+        return Ok(None);
+    };
+    if let Ok(key) = ssh_key::PublicKey::from_str(key.trim())
+        && let ssh_key::public::KeyData::Ed25519(k) = key.key_data()
+        && let Ok(key) = VerifyingKey::from_bytes(&k.0)
+    {
+        return Ok(Some(key));
+    }
+    warn!("SSH_STAMP_OTA_KEY is not an Ed25519 public key, refusing OTA uploads");
+    Err(sunset::Error::msg("invalid SSH_STAMP_OTA_KEY"))
+}
+
+/// Longest [`ota::OTA_INFO_PATH`] text.
+#[cfg(feature = "sftp-ota")]
+const OTA_INFO_LEN: usize = 32;
+
+/// The text of [`ota::OTA_INFO_PATH`], empty if the platform cannot tell
+/// the OTA partition size.
+#[cfg(feature = "sftp-ota")]
+async fn ota_info<P: PlatformServices>() -> String<OTA_INFO_LEN> {
+    let mut text = String::new();
+    match P::OtaWriter::get_ota_partition_size().await {
+        Ok(capacity) => {
+            let _ = core::fmt::Write::write_fmt(&mut text, format_args!("capacity: {capacity}\n"));
+        }
+        Err(e) => warn!("Cannot get the OTA partition size: {e:?}"),
+    }
+    text
+}
+
 /// Handles an SSH client connection, bridging UART and SSH.
 ///
 /// # Errors
@@ -661,7 +1496,8 @@ pub async fn ssh_client<'a, 'b, U, P>(
     uart_buff: &'a U,
     ssh_server: &'b SSHServer<'a>,
     chan_pipe: &'b Channel<NoopRawMutex, SessionType, 1>,
-    _platform: &'b P,
+    config: &'b SunsetMutex<SSHStampConfig>,
+    platform: &'b P,
 ) -> Result<(), sunset::Error>
 where
     U: BufferedSerial,
@@ -671,19 +1507,184 @@ where
     let session_type = chan_pipe.receive().await;
     debug!("Checking bridge session type");
     match session_type {
-        SessionType::Bridge(ch) => {
-            info!("Handling bridge session");
-            let chan_io: ChanInOut<'_> = ssh_server.stdio(ch).await?;
-            let (stdin, stdout) = chan_io.split();
+        SessionType::Bridge(ch, overrides, route) => {
+            info!("Handling {} session", route.behavior.name());
+            #[cfg(feature = "plugins")]
+            session_started(route.behavior, route.role);
+            if overrides != UartOverrides::default() {
+                info!("Session UART overrides until disconnect: {overrides:?}");
+            }
+            let (mut uart_config, target, mut tx_full, mut idle_lock, mut escape, limit) = {
+                let config = config.lock().await;
+                (
+                    config.uart_config_with(&overrides),
+                    config.selected_mux_target().cloned(),
+                    config.uart_tx_full,
+                    config.uart_idle_lock,
+                    Some(config.bridge_escape()),
+                    config.session_limit.minutes_for(route.role),
+                )
+            };
+            if overrides.binary {
+                // Neither drops input, takes escape commands out of it nor
+                // writes notices into the output.
+                tx_full = TxFullPolicy::Block;
+                idle_lock = 0;
+                escape = None;
+            }
+            // Routed on every bridge start, the target list may have changed.
+            #[cfg(feature = "mux")]
+            if let Some(target) = &target
+                && let Err(e) = platform.select_mux(target.select).await
+            {
+                warn!("Cannot route the UART mux to {}: {e}", target.name);
+            }
+            let mut rejected = None;
+            if !uart_buff.reconfigure(uart_config.clone()).await {
+                // The UART kept whatever it ran at before; make that the
+                // device-wide settings rather than guess.
+                warn!(
+                    "UART rejected {} baud {}",
+                    uart_config.baud_rate, uart_config.frame
+                );
+                rejected = Some((uart_config.baud_rate, uart_config.frame));
+                uart_config = config.lock().await.uart_config();
+                uart_buff.reconfigure(uart_config.clone()).await;
+            }
+            let baud_rate = uart_config.baud_rate;
+            let (chan_io, mut stderr): (ChanInOut<'_>, ChanOut<'_>) =
+                ssh_server.stdio_stderr(ch).await?;
+            let (stdin, mut stdout) = chan_io.split();
+            if !overrides.binary {
+                if let Some((rate, frame)) = rejected {
+                    commands::print(
+                        &mut stdout,
+                        format_args!(
+                            "ssh-stamp: the UART cannot run at {rate} baud {frame}, using {baud_rate} baud {}\r\n",
+                            uart_config.frame
+                        ),
+                    )
+                    .await?;
+                }
+                announce(&mut stdout, target.as_ref(), baud_rate).await?;
+            }
             info!("Starting bridge");
-            serial_bridge(stdin, stdout, uart_buff).await?;
+            stats::session_started();
+            if route.behavior == SessionBehavior::Monitor {
+                let session = serial_monitor(stdin, stdout, uart_buff);
+                time_limited(session, limit, &mut stderr).await?;
+            } else {
+                #[cfg(feature = "rmt")]
+                let stdin = {
+                    if overrides.reset {
+                        auto_reset::reset_target(platform, "requested by the session").await;
+                    }
+                    let enabled = config.lock().await.auto_reset;
+                    auto_reset::SyncWatch::new(stdin, platform, enabled)
+                };
+                let session = serial_bridge(
+                    stdin,
+                    stdout,
+                    uart_buff,
+                    uart_config,
+                    tx_full,
+                    idle_lock,
+                    escape,
+                );
+                time_limited(session, limit, &mut stderr).await?;
+            }
         }
         #[cfg(feature = "sftp-ota")]
         SessionType::Sftp(ch) => {
             debug!("Handling SFTP session");
+            let Some(mut request_buffer) = SFTP_REQUEST_BUFFERS
+                .iter()
+                .find_map(|buffer| buffer.try_lock().ok())
+            else {
+                // Taken since the subsystem was accepted: close the channel
+                // rather than leave the client waiting on it.
+                warn!("All {SFTP_SESSION_SLOTS} SFTP buffers in use, closing the session");
+                drop(ssh_server.stdio(ch).await?);
+                return Ok(());
+            };
             let stdio = ssh_server.stdio(ch).await?;
-            let ota_writer = _platform.ota_writer();
-            ota::run_ota_server::<P::OtaWriter>(stdio, ota_writer).await?;
+            let firmware_key = firmware_key()?;
+            let ota_writer = platform.ota_writer();
+            let version = version::text(platform);
+            let ota_info = ota_info::<P>().await;
+            let mut snapshot = SFTP_TELEMETRY.lock().await;
+            snapshot.refresh();
+            #[cfg(feature = "scripts")]
+            let mut script_result = SFTP_SCRIPT_RESULT.lock().await;
+            #[cfg(feature = "scripts")]
+            script_result.refresh();
+            let files = [
+                InfoFile {
+                    path: ota::VERSION_PATH,
+                    contents: version.as_bytes(),
+                },
+                InfoFile {
+                    path: ota::OTA_INFO_PATH,
+                    contents: ota_info.as_bytes(),
+                },
+                InfoFile {
+                    path: telemetry::EVENTS_PATH,
+                    contents: snapshot.events.as_bytes(),
+                },
+                InfoFile {
+                    path: telemetry::METRICS_PATH,
+                    contents: snapshot.metrics.as_bytes(),
+                },
+                #[cfg(feature = "scripts")]
+                InfoFile {
+                    path: scripts::RESULT_PATH,
+                    contents: script_result.as_bytes(),
+                },
+            ];
+            ota::run_ota_server::<P::OtaWriter, P::Scratch, SFTP_REPLY_LEN>(
+                stdio,
+                ota_writer,
+                firmware_key,
+                &files,
+                platform.scratch(),
+                &mut request_buffer,
+            )
+            .await?;
+        }
+        SessionType::Exec(ch, line, role) => {
+            info!("Running admin command: {line}");
+            #[cfg(feature = "plugins")]
+            session_started(SessionBehavior::Commands, role);
+            let chan_io = ssh_server.stdio(ch).await?;
+            let succeeded = {
+                let (stdin, mut stdout) = chan_io.split();
+                commands::run(&line, stdin, &mut stdout, uart_buff, config, platform, role).await?
+            };
+            chan_io.exit_status(u32::from(!succeeded)).await?;
+            // Dropping the last handle closes the channel.
+        }
+        SessionType::AdminShell(ch, role) => {
+            let fault = serial::bridge_fault().unwrap_or("unknown");
+            info!("Serial bridge disabled ({fault}), running the admin console");
+            #[cfg(feature = "plugins")]
+            session_started(SessionBehavior::Commands, role);
+            let limit = config.lock().await.session_limit.minutes_for(role);
+            let (chan_io, mut stderr) = ssh_server.stdio_stderr(ch).await?;
+            let (stdin, mut stdout) = chan_io.split();
+            let session =
+                commands::admin_shell(stdin, &mut stdout, uart_buff, config, platform, role, fault);
+            time_limited(session, limit, &mut stderr).await?;
+        }
+        SessionType::ConfigMenu(ch, role) => {
+            info!("Running the config menu");
+            #[cfg(feature = "plugins")]
+            session_started(SessionBehavior::Commands, role);
+            let limit = config.lock().await.session_limit.minutes_for(role);
+            let (chan_io, mut stderr) = ssh_server.stdio_stderr(ch).await?;
+            let (stdin, mut stdout) = chan_io.split();
+            let session =
+                config_menu::config_menu(stdin, &mut stdout, uart_buff, config, platform, role);
+            time_limited(session, limit, &mut stderr).await?;
         }
     }
     Ok(())
@@ -692,3 +1693,67 @@ where
 pub fn bridge_disable() {
     debug!("Bridge disabled: WIP");
 }
+
+#[cfg(test)]
+mod env_parser_tests {
+    use core::net::Ipv4Addr;
+
+    use super::env_parser::parse_subnet_list;
+    use crate::config::Ipv4Subnet;
+    use crate::settings::ACCESS_LIST_SLOTS;
+
+    fn subnet(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Ipv4Subnet {
+        Ipv4Subnet {
+            addr: Ipv4Addr::new(a, b, c, d),
+            prefix,
+        }
+    }
+
+    #[test]
+    fn subnet_list_entries() {
+        assert!(parse_subnet_list("").unwrap().is_empty());
+        assert_eq!(
+            parse_subnet_list("192.168.1.0/24,10.0.0.7")
+                .unwrap()
+                .as_slice(),
+            [subnet(192, 168, 1, 0, 24), subnet(10, 0, 0, 7, 32)]
+        );
+        assert_eq!(
+            parse_subnet_list("0.0.0.0/0").unwrap().as_slice(),
+            [subnet(0, 0, 0, 0, 0)]
+        );
+        assert_eq!(
+            parse_subnet_list("10.0.0.7/32").unwrap().as_slice(),
+            [subnet(10, 0, 0, 7, 32)]
+        );
+    }
+
+    #[test]
+    fn subnet_list_refuses_bad_entries() {
+        for bad in [
+            "10.0.0.0/33",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "256.0.0.1",
+            "::1/128",
+            "10.0.0.1,",
+            ",10.0.0.1",
+            "10.0.0.1, 10.0.0.2",
+        ] {
+            assert_eq!(parse_subnet_list(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn subnet_list_fills_the_slots() {
+        let full = parse_subnet_list("10.0.0.1,10.0.0.2,10.0.0.3/31,10.0.1.0/24").unwrap();
+        assert_eq!(full.len(), ACCESS_LIST_SLOTS);
+        assert_eq!(full[3], subnet(10, 0, 1, 0, 24));
+        assert_eq!(
+            parse_subnet_list("10.0.0.1,10.0.0.2,10.0.0.3/31,10.0.1.0/24,10.0.2.0/24"),
+            None
+        );
+    }
+}
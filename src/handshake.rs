@@ -0,0 +1,247 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Passive inspection of the SSH handshake.
+//!
+//! sunset does not report the client's identification string nor the
+//! algorithms it settled on, so the TCP halves handed to it are wrapped in
+//! [`Tap`]s that look at the plaintext start of each direction: the
+//! identification line and the first binary packet, which is always
+//! `SSH_MSG_KEXINIT` (RFC 4253, section 7.1). Applying the RFC's rule (the
+//! first client algorithm the server also offers) to both proposals yields
+//! what sunset negotiated. Once both proposals are seen the taps only pass
+//! data through.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::{String, Vec};
+
+use crate::sessions::{self, AlgorithmName, Algorithms};
+use crate::settings::{CLIENT_KEXINIT_LEN, CLIENT_VERSION_LEN, SERVER_KEXINIT_LEN};
+
+const SSH_MSG_KEXINIT: u8 = 20;
+/// Padding length, message number and cookie precede the name-lists.
+const KEXINIT_LISTS_OFFSET: usize = 1 + 1 + 16;
+/// kex, host key, cipher c2s/s2c and MAC c2s/s2c; compression and
+/// languages are not interesting.
+const NAME_LISTS: usize = 6;
+
+#[derive(Clone, Copy)]
+enum Stage {
+    Ident,
+    Length { have: u8, len: u32 },
+    Packet { remaining: u32 },
+    Done,
+}
+
+/// Follows one direction of the stream up to the end of its first packet.
+struct Sniffer<const N: usize> {
+    stage: Stage,
+    /// The line being read; the identification string once past it.
+    line: String<CLIENT_VERSION_LEN>,
+    packet: Vec<u8, N>,
+}
+
+impl<const N: usize> Sniffer<N> {
+    const fn new() -> Self {
+        Self {
+            stage: Stage::Ident,
+            line: String::new(),
+            packet: Vec::new(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.stage, Stage::Done)
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        for &b in data {
+            self.stage = match self.stage {
+                Stage::Ident if b == b'\n' => {
+                    // Lines before the identification string are allowed.
+                    if self.line.starts_with("SSH-") {
+                        Stage::Length { have: 0, len: 0 }
+                    } else {
+                        self.line.clear();
+                        Stage::Ident
+                    }
+                }
+                Stage::Ident => {
+                    // Overlong lines are kept truncated.
+                    if b != b'\r' {
+                        let _ = self.line.push(char::from(b));
+                    }
+                    Stage::Ident
+                }
+                Stage::Length { have: 3, len } => match (len << 8) | u32::from(b) {
+                    0 => Stage::Done,
+                    remaining => Stage::Packet { remaining },
+                },
+                Stage::Length { have, len } => Stage::Length {
+                    have: have + 1,
+                    len: (len << 8) | u32::from(b),
+                },
+                Stage::Packet { remaining } => {
+                    // The name-lists come first, only the tail is lost to a short buffer.
+                    let _ = self.packet.push(b);
+                    match remaining - 1 {
+                        0 => Stage::Done,
+                        remaining => Stage::Packet { remaining },
+                    }
+                }
+                Stage::Done => return,
+            };
+        }
+    }
+
+    /// The first [`NAME_LISTS`] name-lists of the `SSH_MSG_KEXINIT` seen.
+    fn name_lists(&self) -> Option<[&str; NAME_LISTS]> {
+        let p = &self.packet;
+        if p.get(1) != Some(&SSH_MSG_KEXINIT) {
+            return None;
+        }
+        let mut pos = KEXINIT_LISTS_OFFSET;
+        let mut lists = [""; NAME_LISTS];
+        for list in &mut lists {
+            let len = u32::from_be_bytes(p.get(pos..pos + 4)?.try_into().ok()?);
+            let len = usize::try_from(len).ok()?;
+            pos += 4;
+            *list = core::str::from_utf8(p.get(pos..pos + len)?).ok()?;
+            pos += len;
+        }
+        Some(lists)
+    }
+}
+
+/// The first algorithm in `client` that `server` also lists.
+fn negotiate(client: &str, server: &str) -> Option<AlgorithmName> {
+    let name = client
+        .split(',')
+        .find(|c| server.split(',').any(|s| s == *c))?;
+    AlgorithmName::try_from(name).ok()
+}
+
+struct State {
+    client: Sniffer<CLIENT_KEXINIT_LEN>,
+    server: Sniffer<SERVER_KEXINIT_LEN>,
+    reported: bool,
+}
+
+impl State {
+    /// Feeds `data` to one side; returns the result once, when both sides
+    /// are complete.
+    fn feed(
+        &mut self,
+        from_client: bool,
+        data: &[u8],
+    ) -> Option<(String<CLIENT_VERSION_LEN>, Option<Algorithms>)> {
+        if self.reported {
+            return None;
+        }
+        if from_client {
+            self.client.feed(data);
+        } else {
+            self.server.feed(data);
+        }
+        if !(self.client.is_done() && self.server.is_done()) {
+            return None;
+        }
+        self.reported = true;
+        let algorithms = match (self.client.name_lists(), self.server.name_lists()) {
+            (Some(c), Some(s)) => Some(Algorithms {
+                kex: negotiate(c[0], s[0]),
+                hostkey: negotiate(c[1], s[1]),
+                cipher_c2s: negotiate(c[2], s[2]),
+                cipher_s2c: negotiate(c[3], s[3]),
+                mac_c2s: negotiate(c[4], s[4]),
+                mac_s2c: negotiate(c[5], s[5]),
+            }),
+            _ => None,
+        };
+        Some((self.client.line.clone(), algorithms))
+    }
+}
+
+/// Handshake observations shared by the two [`Tap`]s of one connection.
+pub struct Handshake(Mutex<CriticalSectionRawMutex, RefCell<State>>);
+
+impl Handshake {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(Mutex::new(RefCell::new(State {
+            client: Sniffer::new(),
+            server: Sniffer::new(),
+            reported: false,
+        })))
+    }
+
+    /// Wraps the half of the socket that reads from the client.
+    pub fn client_tap<T: Read>(&self, inner: T) -> Tap<'_, T> {
+        Tap {
+            inner,
+            handshake: self,
+            from_client: true,
+        }
+    }
+
+    /// Wraps the half of the socket that writes to the client.
+    pub fn server_tap<T: Write>(&self, inner: T) -> Tap<'_, T> {
+        Tap {
+            inner,
+            handshake: self,
+            from_client: false,
+        }
+    }
+
+    fn inspect(&self, from_client: bool, data: &[u8]) {
+        let done = self
+            .0
+            .lock(|state| state.borrow_mut().feed(from_client, data));
+        // Reported outside the lock, logging may block on the console.
+        if let Some((client_version, algorithms)) = done {
+            sessions::record_handshake(&client_version, algorithms);
+        }
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A socket half that shows the data passing through to its [`Handshake`].
+pub struct Tap<'a, T> {
+    inner: T,
+    handshake: &'a Handshake,
+    from_client: bool,
+}
+
+impl<T: ErrorType> ErrorType for Tap<'_, T> {
+    type Error = T::Error;
+}
+
+impl<T: Read> Read for Tap<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.read(buf).await?;
+        self.handshake.inspect(self.from_client, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Tap<'_, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.write(buf).await?;
+        self.handshake.inspect(self.from_client, &buf[..n]);
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
+    }
+}
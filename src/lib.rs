@@ -37,13 +37,42 @@
 //!
 //! ## Key modules
 //!
-//! - [`app`] — entry points [`prepare_ap_config`] and [`run_app`]
+//! - [`app`] — entry points [`prepare_ap_config`] and [`run_app`], [`SshConsole`] builder for embedding
+//! - `auto_reset` — Arduino-style target resets through the pulse output (`rmt` feature)
 //! - [`handle`] — SSH event handlers (auth, channels, env vars)
+//! - [`commands`] — Admin commands run over SSH `exec`
+//! - [`registry`] — Table of admin commands: names, parameters, roles; line parsing and `help`
+//! - [`benchmark`] — UART and SSH throughput tests for `benchmark`
+//! - [`baud_ladder`] — Stepping through fallback baud rates while the UART keeps failing
 //! - [`serve`] — SSH connection loop
-//! - [`serial`] — UART bridge trait and bridge function
+//! - [`sessions`] — Recent SSH sessions: peer, client version, negotiated algorithms
+//! - [`clients`] — Persisted registry of clients that logged in, for `show clients`
+//! - [`handshake`] — Socket taps reading the plaintext SSH handshake for [`sessions`]
+//! - [`lockout`] — Refusing addresses, and pausing the access point, after repeated failed logins
+//! - [`serial`] — UART bridge trait, bridge function and receive error statistics
+//! - [`stats`] — Bytes and errors the bridge lost, per session and since boot, for `show stats`
+//! - [`redact`] — Masking configured byte patterns in the copies of target output passed to plugins and observers
+//! - [`console`] — Guessing the target's console (U-Boot, CFE, Linux login, `RouterOS`) from its output
 //! - [`config`] — [`SSHStampConfig`](crate::config::SSHStampConfig) struct and serialization
+//! - [`config_menu`] — Menu for the UART settings, the `config` SSH subsystem
 //! - [`store`] — Flash load/save/create
+//! - [`resets`] — Reset reason of this boot and persisted counts per reason
+//! - [`staging`] — Staged configuration changes and commit-confirmed rollback
+//! - [`ota_history`] — Persisted ring of firmware uploads and their outcome, for `show ota history`
+//! - [`ota_window`] — Confirmation window for new firmware, rolled back unless confirmed
+//! - `scripts` — Expect-like scripts stored on the device and run against the UART (`scripts` feature)
+//! - `observers` — Read-only SSH sessions watching the bridge next to the main connection (`observers` feature)
+//! - `raw_tcp` — Unauthenticated plain TCP access to the bridge for trusted networks (`raw-tcp` feature)
+//! - [`outbox`] — Fault resets, OTA results and other important events kept in flash until taken, for `show outbox`
+//! - `peers` — mDNS answers and browsing for other stamps, for `show peers` (`peers` feature)
+//! - [`telemetry`] — Event log and counters as JSON files for collection over SFTP
+//! - [`events`] — In-memory ring of noteworthy events (rejected connections, ...)
+//! - [`logging`] — `log` backend keeping recent output in RAM, console optional
+//! - `plugins` — Hooks for session starts, target output lines and events, for downstream forks (`plugins` feature)
 //! - [`platform`] — [`PlatformServices`](crate::platform::PlatformServices) trait (save config, reset, OTA)
+//! - [`version`] — Build metadata (git hash, build date, features) for `show version` and `show build`
+//! - [`provisioning`] — `WiFi` provisioning over non-SSH transports (BLE)
+//! - [`qr`] — Access point join QR code for `show qr`
 //!
 //! # Hacking
 //!
@@ -56,6 +85,28 @@
 //!   exclusive resource consumed by the serial bridge once SSH attaches.
 //! - **Dependency graph is acyclic:** `ssh-stamp-hal <- ssh-stamp <-
 //!   ssh-stamp-<port>`. `ssh-stamp` must not depend on any port crate.
+//! - **Interrupt-priority code takes no async locks.** The UART task may
+//!   preempt a thread-mode task holding the config or flash `SunsetMutex`
+//!   for a whole flash erase; waiting on either would stop reception until
+//!   the hardware FIFO overflows. It uses pipes, signals, atomics and short
+//!   blocking critical sections only, see [`serial::BufferedSerial`].
+//! - **Locks are taken config first, then flash**, never the other way
+//!   round, and never held across an unrelated await. Blocking
+//!   (critical section) mutexes guard short copies or updates: no logging
+//!   or hooks inside, since they mask interrupts.
+//!
+//! ## Embedding
+//!
+//! The same crate serves firmware that wants an SSH console next to its own
+//! tasks instead of the standalone binary. Depend on `ssh-stamp` and the port
+//! crate for your chip (for the [`PlatformServices`] and
+//! [`BufferedSerial`] impls, or write your own), then run an
+//! [`SshConsole`] on your embassy executor. The pieces it is built from are
+//! public too: [`serve::connection_loop`], [`serial::serial_bridge`] and, for
+//! `sftp-ota` builds, [`ota::run_ota_server`].
+//!
+//! [`PlatformServices`]: crate::platform::PlatformServices
+//! [`BufferedSerial`]: crate::serial::BufferedSerial
 //!
 //! ## Adding a new SSH env var handler
 //!
@@ -74,6 +125,19 @@
 //! and `SSH_STAMP_WIFI_PSK` env vars. Changes are persisted to flash and the
 //! device performs a software reset.
 //!
+//! ## Not yet possible: agent-forwarding authorization
+//!
+//! Gating admin operations (OTA uploads, `config commit`) on a challenge
+//! signed by a key in the client's forwarded SSH agent needs the server to
+//! accept `auth-agent-req@openssh.com` and open `auth-agent@openssh.com`
+//! channels back to the client. `sunset` raises no [`ServEvent`] for either,
+//! so there is nothing to build on yet. Once it does, the check belongs in
+//! [`handle::session_subsystem`] (SFTP) and [`staging::commit`], behind a
+//! cargo feature, with the approver keys stored in [`SSHStampConfig`].
+//!
+//! [`ServEvent`]: sunset::ServEvent
+//! [`SSHStampConfig`]: crate::config::SSHStampConfig
+//!
 //! ## Testing
 //!
 //! Host-side OTA TLV tests:
@@ -86,6 +150,7 @@
 //!
 //! [`prepare_ap_config`]: app::prepare_ap_config
 //! [`run_app`]: app::run_app
+//! [`SshConsole`]: app::SshConsole
 
 #![no_std]
 #![forbid(unsafe_code)]
@@ -96,11 +161,46 @@
 extern crate alloc;
 
 pub mod app;
+#[cfg(feature = "rmt")]
+pub mod auto_reset;
+pub mod baud_ladder;
+pub mod benchmark;
+pub mod clients;
+pub mod commands;
 pub mod config;
+pub mod config_menu;
+pub mod console;
 pub mod errors;
+pub mod events;
 pub mod handle;
+pub mod handshake;
+pub mod lockout;
+pub mod logging;
+#[cfg(feature = "observers")]
+pub mod observers;
+pub mod ota_history;
+pub mod ota_window;
+pub mod outbox;
+#[cfg(feature = "peers")]
+pub mod peers;
 pub mod platform;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+pub mod provisioning;
+pub mod qr;
+#[cfg(feature = "raw-tcp")]
+pub mod raw_tcp;
+pub mod redact;
+pub mod registry;
+pub mod resets;
+#[cfg(feature = "scripts")]
+pub mod scripts;
 pub mod serial;
 pub mod serve;
+pub mod sessions;
 pub mod settings;
+pub mod staging;
+pub mod stats;
 pub mod store;
+pub mod telemetry;
+pub mod version;
@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Backing off from clients that keep failing.
+//!
+//! A connection that ends without authenticating counts as a failure:
+//! rejected keys, broken handshakes, scanners that only grab the banner.
+//! After [`CLIENT_MAX_FAILURES`] from one address within
+//! [`FAILURE_WINDOW_SECS`], that address is refused for
+//! [`CLIENT_BLOCK_SECS`]. Scanners hopping between addresses are caught by
+//! a second count over all clients: past [`AP_PAUSE_FAILURES`] the platform
+//! is asked, through [`wait_ap_pause`], to take the access point off the air
+//! for [`AP_PAUSE_SECS`]. A successful login clears its address's count.
+
+use core::cell::RefCell;
+use core::net::IpAddr;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use heapless::Vec;
+use log::{debug, warn};
+
+use crate::events::{self, Event};
+use crate::settings::{
+    AP_PAUSE_FAILURES, AP_PAUSE_SECS, CLIENT_BLOCK_SECS, CLIENT_MAX_FAILURES, FAILURE_WINDOW_SECS,
+    LOCKOUT_SLOTS,
+};
+
+/// Failures from one address.
+struct Offender {
+    peer: IpAddr,
+    failures: u32,
+    /// Seconds since boot of the first failure in the current window.
+    window_start: u64,
+    /// Seconds since boot until which the address is refused.
+    blocked_until: Option<u64>,
+}
+
+/// Failures from all addresses.
+struct Total {
+    failures: u32,
+    window_start: u64,
+}
+
+struct Lockout {
+    offenders: Vec<Offender, LOCKOUT_SLOTS>,
+    total: Total,
+}
+
+static LOCKOUT: Mutex<CriticalSectionRawMutex, RefCell<Lockout>> =
+    Mutex::new(RefCell::new(Lockout {
+        offenders: Vec::new(),
+        total: Total {
+            failures: 0,
+            window_start: 0,
+        },
+    }));
+
+/// Seconds the access point should stay off the air.
+static AP_PAUSE: Signal<CriticalSectionRawMutex, u64> = Signal::new();
+
+/// Whether connections from `peer` are currently refused.
+#[must_use]
+pub fn is_blocked(peer: IpAddr) -> bool {
+    let now = Instant::now().as_secs();
+    LOCKOUT.lock(|lockout| {
+        lockout
+            .borrow()
+            .offenders
+            .iter()
+            .any(|o| o.peer == peer && o.blocked_until.is_some_and(|until| now < until))
+    })
+}
+
+/// Counts the end of a connection from `peer`, a failure unless it
+/// `authenticated`.
+pub fn record(peer: Option<IpAddr>, authenticated: bool) {
+    let now = Instant::now().as_secs();
+    let (blocked, pause) = LOCKOUT.lock(|lockout| {
+        let mut lockout = lockout.borrow_mut();
+        if authenticated {
+            if let Some(peer) = peer {
+                lockout.offenders.retain(|o| o.peer != peer);
+            }
+            return (None, false);
+        }
+        let blocked = peer.and_then(|peer| lockout.count_failure(peer, now));
+        let total = &mut lockout.total;
+        if now.saturating_sub(total.window_start) >= FAILURE_WINDOW_SECS {
+            total.failures = 0;
+            total.window_start = now;
+        }
+        total.failures += 1;
+        let pause = total.failures >= AP_PAUSE_FAILURES;
+        if pause {
+            total.failures = 0;
+        }
+        (blocked, pause)
+    });
+    if let Some(peer) = blocked {
+        warn!("Refusing {peer} for {CLIENT_BLOCK_SECS} s after repeated failures");
+        events::emit(Event::ClientBlocked {
+            peer,
+            secs: CLIENT_BLOCK_SECS,
+        });
+    }
+    if pause {
+        warn!("Pausing the access point for {AP_PAUSE_SECS} s after repeated failures");
+        events::emit(Event::AccessPointPaused {
+            secs: AP_PAUSE_SECS,
+        });
+        AP_PAUSE.signal(AP_PAUSE_SECS);
+    }
+}
+
+impl Lockout {
+    /// Counts a failure from `peer`; returns it if it is now blocked.
+    fn count_failure(&mut self, peer: IpAddr, now: u64) -> Option<IpAddr> {
+        let index = match self.offenders.iter().position(|o| o.peer == peer) {
+            Some(index) => index,
+            None => {
+                if self.offenders.is_full() {
+                    // Make room by forgetting the quietest address that is not
+                    // blocked; if all are, the new one goes uncounted.
+                    let evict = self
+                        .offenders
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, o)| o.blocked_until.is_none_or(|until| until <= now))
+                        .min_by_key(|(_, o)| o.window_start)
+                        .map(|(i, _)| i)?;
+                    self.offenders.swap_remove(evict);
+                }
+                let _ = self.offenders.push(Offender {
+                    peer,
+                    failures: 0,
+                    window_start: now,
+                    blocked_until: None,
+                });
+                self.offenders.len() - 1
+            }
+        };
+        let offender = &mut self.offenders[index];
+        if now.saturating_sub(offender.window_start) >= FAILURE_WINDOW_SECS {
+            offender.failures = 0;
+            offender.window_start = now;
+        }
+        offender.failures += 1;
+        debug!("{} failed connections from {peer}", offender.failures);
+        if offender.failures < CLIENT_MAX_FAILURES {
+            return None;
+        }
+        offender.failures = 0;
+        offender.blocked_until = Some(now + CLIENT_BLOCK_SECS);
+        Some(peer)
+    }
+}
+
+/// Waits until the access point should be taken off the air; returns for
+/// how many seconds. Platforms running a provisioning access point call
+/// this in their `WiFi` task.
+pub async fn wait_ap_pause() -> u64 {
+    AP_PAUSE.wait().await
+}
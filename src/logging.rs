@@ -0,0 +1,318 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Log routing.
+//!
+//! Every `log` record is kept in a small in-memory ring so it can be read
+//! back over SSH (`show log`). Copying records to the platform console is
+//! optional and can be switched off at runtime, which keeps log text off
+//! the console lines and removes the console's blocking writes from
+//! time-sensitive paths. The console UART itself stays configured on its
+//! pins; ports do not release them.
+//!
+//! A line identical to the one before it is held back and counted instead,
+//! so a storm of the same warning cannot push everything else out of the
+//! ring or stall on the console. The count is written as "last message
+//! repeated N times" once a different line comes in, and at most every
+//! [`LOG_REPEAT_SECS`] while the storm lasts.
+//!
+//! Production devices can have secrets (PSKs, config dumps) left out of the
+//! log, see [`Redacted`]. An admin lifts that for a while with
+//! `log verbose` when diagnosing a device.
+//!
+//! Ports install a [`RingLogger`] with their console writer instead of their
+//! usual console logger, and set the maximum level themselves.
+//!
+//! During development the bridged target output can be copied to the same
+//! console with `SSH_STAMP_CONSOLE_MIRROR`, to compare what the remote user
+//! sees with local instrumentation, see [`mirror`]. Ports that support it
+//! register a raw byte writer with [`set_mirror_writer`].
+
+use core::cell::{Cell, RefCell};
+use core::fmt::{self, Write as _};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+use log::{Level, Log, Metadata, Record};
+use portable_atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Bytes of formatted log text kept in RAM.
+pub const LOG_BUFFER_LEN: usize = 2048;
+/// Longest time a repeating line is held back before its count is written
+/// and the line let through again.
+pub const LOG_REPEAT_SECS: u64 = 10;
+
+struct LogRing {
+    bytes: Deque<u8, LOG_BUFFER_LEN>,
+    /// Total bytes ever written, used as a read cursor by [`read`].
+    written: u64,
+}
+
+impl fmt::Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if self.bytes.is_full() {
+                self.bytes.pop_front();
+            }
+            let _ = self.bytes.push_back(b);
+        }
+        self.written += s.len() as u64;
+        Ok(())
+    }
+}
+
+static RING: Mutex<CriticalSectionRawMutex, RefCell<LogRing>> = Mutex::new(RefCell::new(LogRing {
+    bytes: Deque::new(),
+    written: 0,
+}));
+
+/// The last line let through and how often it came again since.
+struct Repeats {
+    /// [`LineHash`] of the last line, `None` before the first.
+    last: Option<u32>,
+    level: Level,
+    count: u32,
+    since: Instant,
+}
+
+static REPEATS: Mutex<CriticalSectionRawMutex, RefCell<Repeats>> =
+    Mutex::new(RefCell::new(Repeats {
+        last: None,
+        level: Level::Info,
+        count: 0,
+        since: Instant::MIN,
+    }));
+
+/// FNV-1a over formatted text, to tell repeated lines apart without
+/// keeping a copy of the last one.
+struct LineHash(u32);
+
+impl fmt::Write for LineHash {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.0 = (self.0 ^ u32::from(b)).wrapping_mul(0x0100_0193);
+        }
+        Ok(())
+    }
+}
+
+/// Decides what to do with a line hashing to `hash`: returns the count of
+/// the previous line's repeats to write first, if any, and whether to let
+/// the line itself through.
+fn check_repeat(hash: u32, level: Level) -> (Option<(Level, u32)>, bool) {
+    let now = Instant::now();
+    REPEATS.lock(|repeats| {
+        let mut repeats = repeats.borrow_mut();
+        let same = repeats.last == Some(hash);
+        if same && now < repeats.since + Duration::from_secs(LOG_REPEAT_SECS) {
+            repeats.count = repeats.count.saturating_add(1);
+            return (None, false);
+        }
+        let summary = (repeats.count > 0).then_some((repeats.level, repeats.count));
+        *repeats = Repeats {
+            last: Some(hash),
+            level,
+            count: 0,
+            since: now,
+        };
+        (summary, true)
+    })
+}
+
+static CONSOLE: AtomicBool = AtomicBool::new(true);
+
+/// Enables or disables copying log records to the platform console.
+pub fn set_console(enabled: bool) {
+    CONSOLE.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether log records are currently copied to the platform console.
+#[must_use]
+pub fn console_enabled() -> bool {
+    CONSOLE.load(Ordering::Relaxed)
+}
+
+static MIRROR: AtomicBool = AtomicBool::new(false);
+static MIRROR_WRITER: Mutex<CriticalSectionRawMutex, Cell<Option<fn(&[u8])>>> =
+    Mutex::new(Cell::new(None));
+
+/// Registers `writer`, writing raw bytes to the platform console, for
+/// [`mirror`]. Without one, mirroring does nothing.
+pub fn set_mirror_writer(writer: fn(&[u8])) {
+    MIRROR_WRITER.lock(|w| w.set(Some(writer)));
+}
+
+/// Enables or disables copying bridged target output to the platform
+/// console.
+pub fn set_mirror(enabled: bool) {
+    MIRROR.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether bridged target output is currently copied to the platform
+/// console.
+#[must_use]
+pub fn mirror_enabled() -> bool {
+    MIRROR.load(Ordering::Relaxed)
+}
+
+/// Copies `bytes` read from the target to the platform console while
+/// mirroring is enabled. Called by the bridge with every chunk, as read and
+/// before any `SSH_STAMP_UART_REDACT` masking; log lines interleave with it.
+///
+/// The console write blocks, so a slow console slows the bridge down with
+/// it while mirroring is on.
+pub fn mirror(bytes: &[u8]) {
+    if !mirror_enabled() {
+        return;
+    }
+    if let Some(writer) = MIRROR_WRITER.lock(Cell::get) {
+        writer(bytes);
+    }
+}
+
+static REDACT: AtomicBool = AtomicBool::new(false);
+/// Milliseconds since boot until which `log verbose` lifts redaction.
+static VERBOSE_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+/// Enables or disables redaction of [`Redacted`] values.
+pub fn set_redact(enabled: bool) {
+    REDACT.store(enabled, Ordering::Relaxed);
+}
+
+/// Shows [`Redacted`] values in full for `duration`, even while redaction
+/// is enabled. Not persisted.
+pub fn verbose_for(duration: Duration) {
+    let until = Instant::now() + duration;
+    VERBOSE_UNTIL.store(until.as_millis(), Ordering::Relaxed);
+}
+
+/// When a `log verbose` period ends, if one is running.
+#[must_use]
+pub fn verbose_until() -> Option<Instant> {
+    let until = Instant::from_millis(VERBOSE_UNTIL.load(Ordering::Relaxed));
+    (until > Instant::now()).then_some(until)
+}
+
+/// Whether [`Redacted`] values are currently hidden.
+#[must_use]
+pub fn redacting() -> bool {
+    REDACT.load(Ordering::Relaxed) && verbose_until().is_none()
+}
+
+/// A value written as `<redacted>` while [`redacting`], for secrets in log
+/// messages: `info!("PSK: {}", Redacted(&psk))`.
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if redacting() {
+            f.write_str("<redacted>")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if redacting() {
+            f.write_str("<redacted>")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+/// Position just past the newest buffered byte, see [`read`].
+#[must_use]
+pub fn end() -> u64 {
+    RING.lock(|ring| ring.borrow().written)
+}
+
+/// Copies buffered log text starting at position `from` into `buf`.
+///
+/// Positions count bytes since boot. Returns the position of the first byte
+/// copied (later than `from` if that part was already overwritten) and the
+/// number of bytes copied; pass their sum as `from` to continue reading.
+pub fn read(from: u64, buf: &mut [u8]) -> (u64, usize) {
+    RING.lock(|ring| {
+        let ring = ring.borrow();
+        let oldest = ring.written - ring.bytes.len() as u64;
+        let start = from.clamp(oldest, ring.written);
+        // Bounded by LOG_BUFFER_LEN.
+        #[allow(clippy::cast_possible_truncation)]
+        let skip = (start - oldest) as usize;
+        let mut n = 0;
+        for (dst, &src) in buf.iter_mut().zip(ring.bytes.iter().skip(skip)) {
+            *dst = src;
+            n += 1;
+        }
+        (start, n)
+    })
+}
+
+/// A [`Log`] implementation that records into the in-memory ring and, while
+/// enabled, forwards to a platform console writer.
+///
+/// Records above [`log::max_level`] are dropped.
+pub struct RingLogger {
+    console: fn(&Record<'_>),
+}
+
+impl RingLogger {
+    /// Creates a logger with `console` writing a record to the platform
+    /// console.
+    #[must_use]
+    pub const fn new(console: fn(&Record<'_>)) -> Self {
+        Self { console }
+    }
+}
+
+impl RingLogger {
+    /// Writes `record` to the ring and, while enabled, the console.
+    fn write(&self, record: &Record<'_>) {
+        if console_enabled() {
+            (self.console)(record);
+        }
+        RING.lock(|ring| {
+            let _ = write!(
+                ring.borrow_mut(),
+                "{} - {}\r\n",
+                record.level(),
+                record.args()
+            );
+        });
+    }
+}
+
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut hash = LineHash(0x811c_9dc5);
+        let _ = write!(hash, "{}{}", record.level(), record.args());
+        let (summary, pass) = check_repeat(hash.0, record.level());
+        if let Some((level, count)) = summary {
+            self.write(
+                &Record::builder()
+                    .level(level)
+                    .target(record.target())
+                    .args(format_args!("last message repeated {count} times"))
+                    .build(),
+            );
+        }
+        if pass {
+            self.write(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,342 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Read-only SSH sessions watching the bridge (`observers` feature).
+//!
+//! The UART serves one SSH connection at a time: keystrokes from several
+//! clients would interleave on the target's console, and the per-connection
+//! state (the session record, staged settings, UART overrides) exists once.
+//! Builds with the `observers` feature let up to [`OBSERVER_SLOTS`] more
+//! clients watch instead. While a connection is up, [`serve`] listens on
+//! the same port of the primary stack, checks each client like the main
+//! listener does (access lists, lockout, login timeout) and lets in those
+//! with an installed admin key whose user has a bridge or monitor route.
+//!
+//! An observer's shell gets the recent target output and then whatever the
+//! main connection's bridge or monitor reads, through [`feed`]; nothing is
+//! shown while that connection runs admin commands. Its input is discarded,
+//! and it has no admin commands, environment variables or subsystems.
+//! Observers are disconnected when the main connection ends, so the next
+//! client always gets the UART.
+//!
+//! Each slot holds its own socket and SSH buffers, about 11 KiB of RAM, in
+//! a static taken on first use.
+
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::net::IpAddr;
+
+use embassy_futures::join::join_array;
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_net::{IpListenEndpoint, Stack, tcp::TcpSocket};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use embedded_io_async::{Read, Write};
+use heapless::Deque;
+use log::{debug, info, warn};
+use static_cell::ConstStaticCell;
+use sunset::packets::PubKey;
+use sunset::{ChanFail, ChanHandle, ServEvent};
+use sunset_async::{ProgressHolder, SSHServer, SunsetMutex};
+
+use crate::commands;
+use crate::config::SSHStampConfig;
+use crate::events::{self, Event};
+use crate::handle::{hostkeys, password_auth};
+use crate::lockout;
+use crate::serve;
+use crate::settings::{
+    BRIDGE_TO_SSH_CHUNK, BRIDGE_TO_UART_CHUNK, LOGIN_TIMEOUT_SECS, OBSERVER_POLL_MS,
+    OBSERVER_RING_LEN, OBSERVER_SLOTS, TCP_BUFFER_SIZE, UART_BUFFER_SIZE,
+};
+
+struct OutputRing {
+    bytes: Deque<u8, OBSERVER_RING_LEN>,
+    /// Total bytes ever fed, used as a read cursor by [`read`].
+    written: u64,
+}
+
+static RING: Mutex<CriticalSectionRawMutex, RefCell<OutputRing>> =
+    Mutex::new(RefCell::new(OutputRing {
+        bytes: Deque::new(),
+        written: 0,
+    }));
+
+/// The main connection's state, as last reported by [`main_connection`].
+static MAIN: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Socket and SSH buffers of one observer slot.
+struct ObserverBuffers {
+    tcp_rx: [u8; TCP_BUFFER_SIZE],
+    tcp_tx: [u8; TCP_BUFFER_SIZE],
+    ssh_in: [u8; UART_BUFFER_SIZE],
+    ssh_out: [u8; UART_BUFFER_SIZE],
+}
+
+impl ObserverBuffers {
+    const fn new() -> Self {
+        Self {
+            tcp_rx: [0; TCP_BUFFER_SIZE],
+            tcp_tx: [0; TCP_BUFFER_SIZE],
+            ssh_in: [0; UART_BUFFER_SIZE],
+            ssh_out: [0; UART_BUFFER_SIZE],
+        }
+    }
+}
+
+static OBSERVER_BUFFERS: ConstStaticCell<[ObserverBuffers; OBSERVER_SLOTS]> =
+    ConstStaticCell::new([const { ObserverBuffers::new() }; OBSERVER_SLOTS]);
+
+/// RAM held by the observer statics: the slot buffers and the output ring.
+pub const OBSERVER_STATIC_LEN: usize =
+    size_of::<[ObserverBuffers; OBSERVER_SLOTS]>() + size_of::<OutputRing>();
+
+/// Keeps target output for the observers. Called by the bridge with every
+/// chunk read from the UART.
+pub fn feed(bytes: &[u8]) {
+    RING.lock(|ring| {
+        let mut ring = ring.borrow_mut();
+        for &b in bytes {
+            if ring.bytes.is_full() {
+                ring.bytes.pop_front();
+            }
+            let _ = ring.bytes.push_back(b);
+        }
+        ring.written += bytes.len() as u64;
+    });
+}
+
+/// Copies target output starting at position `from` into `buf`, like
+/// [`crate::logging::read`]: returns the position of the first byte copied
+/// and the number of bytes copied.
+fn read(from: u64, buf: &mut [u8]) -> (u64, usize) {
+    RING.lock(|ring| {
+        let ring = ring.borrow();
+        let oldest = ring.written - ring.bytes.len() as u64;
+        let start = from.clamp(oldest, ring.written);
+        // Bounded by OBSERVER_RING_LEN.
+        #[allow(clippy::cast_possible_truncation)]
+        let skip = (start - oldest) as usize;
+        let mut n = 0;
+        for (dst, &src) in buf.iter_mut().zip(ring.bytes.iter().skip(skip)) {
+            *dst = src;
+            n += 1;
+        }
+        (start, n)
+    })
+}
+
+/// Reports whether the main SSH connection is up. Observers are only
+/// accepted while it is.
+pub fn main_connection(up: bool) {
+    MAIN.signal(up);
+}
+
+/// Accepts observers on `port` of `stack` while the main connection is up,
+/// see the module docs. Never returns.
+///
+/// # Panics
+///
+/// Panics if called more than once: the slots' buffers are taken here.
+pub async fn serve(
+    stack: Stack<'static>,
+    port: u16,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Infallible {
+    let buffers = OBSERVER_BUFFERS.take();
+    loop {
+        while !MAIN.wait().await {}
+        let slots = join_array(buffers.each_mut().map(|b| slot(stack, port, b, config)));
+        let closed = async { while MAIN.wait().await {} };
+        match select(slots, closed).await {
+            Either::First(never) => match never[0] {},
+            Either::Second(()) => debug!("Main connection closed, dropping observers"),
+        }
+    }
+}
+
+/// Serves one observer after another.
+async fn slot(
+    stack: Stack<'static>,
+    port: u16,
+    buffers: &mut ObserverBuffers,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Infallible {
+    let endpoint = IpListenEndpoint { addr: None, port };
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut buffers.tcp_rx, &mut buffers.tcp_tx);
+        if let Err(e) = socket.accept(endpoint).await {
+            debug!("Observer accept error: {e:?}");
+            continue;
+        }
+        let peer = socket.remote_endpoint().map(|e| IpAddr::from(e.addr));
+        if let Some(peer) = peer {
+            if !config.lock().await.access_lists.permits(peer) {
+                warn!("Rejecting observer {peer}: not permitted by access lists");
+                events::emit(Event::ConnectionRejected { peer });
+                socket.abort();
+                let _ = socket.flush().await;
+                continue;
+            }
+            if lockout::is_blocked(peer) {
+                debug!("Rejecting observer {peer}: too many failures");
+                socket.abort();
+                let _ = socket.flush().await;
+                continue;
+            }
+        }
+
+        let ssh_server =
+            serve::ssh_wait_for_initialisation(&mut buffers.ssh_in, &mut buffers.ssh_out);
+        let authenticated = Cell::new(false);
+        let shell = Signal::<NoopRawMutex, ChanHandle>::new();
+        let (mut rsock, mut wsock) = socket.split();
+        let server = ssh_server.run(&mut rsock, &mut wsock);
+        let connection = async {
+            let session = select(
+                observer_events(&ssh_server, config, &authenticated, &shell),
+                watch(&ssh_server, &shell),
+            );
+            match session.await {
+                Either::First(r) | Either::Second(r) => r,
+            }
+        };
+        let stalled = async {
+            Timer::after_secs(LOGIN_TIMEOUT_SECS).await;
+            if authenticated.get() {
+                core::future::pending::<()>().await;
+            }
+            warn!("Observer {peer:?} did not log in, closing it");
+        };
+        match select3(server, connection, stalled).await {
+            Either3::First(Err(e)) | Either3::Second(Err(e)) => {
+                debug!("Observer session ended: {e}");
+            }
+            _ => (),
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+        lockout::record(peer, authenticated.get());
+    }
+}
+
+/// Answers the observer's SSH events: login with an admin key, one session
+/// channel and a shell on it, handed to [`watch`] through `shell`.
+async fn observer_events(
+    serv: &SSHServer<'_>,
+    config: &SunsetMutex<SSHStampConfig>,
+    authenticated: &Cell<bool>,
+    shell: &Signal<NoopRawMutex, ChanHandle>,
+) -> Result<(), sunset::Error> {
+    let mut session = None;
+    loop {
+        let mut ph = ProgressHolder::new();
+        match serv.progress(&mut ph).await? {
+            ev @ ServEvent::Hostkeys(_) => hostkeys(ev, config).await?,
+            ev @ ServEvent::PasswordAuth(_) => password_auth(ev)?,
+            ServEvent::FirstAuth(mut a) => {
+                // No first-login provisioning here.
+                a.enable_password_auth(false)?;
+                a.enable_pubkey_auth(true)?;
+                a.reject()?;
+            }
+            ServEvent::PubkeyAuth(a) => {
+                let config = config.lock().await;
+                let user = a.username()?;
+                let known = match a.pubkey()? {
+                    PubKey::Ed25519(presented) => config.knows_key(&presented),
+                    PubKey::Unknown(_) => false,
+                };
+                let watches = config
+                    .route_for(user)
+                    .is_some_and(|route| route.behavior.bridges());
+                if known && watches {
+                    info!("Observer logged in as {user}");
+                    authenticated.set(true);
+                    a.allow()?;
+                } else {
+                    a.reject()?;
+                }
+            }
+            ServEvent::OpenSession(a) => {
+                if !authenticated.get() || session.is_some() {
+                    a.reject(ChanFail::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED)?;
+                } else {
+                    session = Some(a.accept()?);
+                }
+            }
+            ServEvent::SessionPty(a) => a.succeed()?,
+            ServEvent::SessionShell(a) => match session.take() {
+                Some(ch) => {
+                    a.succeed()?;
+                    shell.signal(ch);
+                }
+                None => a.fail()?,
+            },
+            ServEvent::SessionEnv(a) => a.fail()?,
+            ServEvent::SessionExec(a) => {
+                warn!("Observers cannot run admin commands");
+                a.fail()?;
+            }
+            ServEvent::SessionSubsystem(a) => a.fail()?,
+            ServEvent::Defunct => return Ok(()),
+            ServEvent::Authenticated | ServEvent::PollAgain => (),
+        }
+    }
+}
+
+/// Copies target output to the observer's shell once it has one,
+/// discarding its input.
+async fn watch(
+    serv: &SSHServer<'_>,
+    shell: &Signal<NoopRawMutex, ChanHandle>,
+) -> Result<(), sunset::Error> {
+    let ch = shell.wait().await;
+    let (mut stdin, mut stdout) = serv.stdio(ch).await?.split();
+    commands::print(
+        &mut stdout,
+        format_args!("ssh-stamp: observing the bridge, input is ignored\r\n"),
+    )
+    .await?;
+    let output = relay(&mut stdout);
+    let discard = async {
+        let mut buf = [0u8; BRIDGE_TO_UART_CHUNK];
+        loop {
+            if stdin.read(&mut buf).await? == 0 {
+                return Err::<(), _>(sunset::Error::ChannelEOF);
+            }
+        }
+    };
+    match select(output, discard).await {
+        Either::First(r) | Either::Second(r) => r,
+    }
+}
+
+/// Writes the buffered target output to `out` as it comes, starting with
+/// what is still buffered and noting what was lost to a slow client.
+async fn relay<W>(out: &mut W) -> Result<(), sunset::Error>
+where
+    W: Write<Error = sunset::Error>,
+{
+    let mut buf = [0u8; BRIDGE_TO_SSH_CHUNK];
+    let mut from = 0;
+    loop {
+        let (start, n) = read(from, &mut buf);
+        if start > from && from > 0 {
+            commands::print(
+                out,
+                format_args!("\r\nssh-stamp: {} bytes skipped\r\n", start - from),
+            )
+            .await?;
+        }
+        from = start + n as u64;
+        if n == 0 {
+            Timer::after_millis(OBSERVER_POLL_MS).await;
+        } else {
+            out.write_all(&buf[..n]).await?;
+        }
+    }
+}
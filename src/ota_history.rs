@@ -0,0 +1,337 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Firmware uploads and their outcome, for auditing what was flashed.
+//!
+//! Every upload the OTA server finishes or gives up on becomes an
+//! [`OtaAttempt`]: when it happened, the image's size, SHA-256 prefix and
+//! the start of its release notes from its TLV header, who uploaded it from
+//! where, and why it failed if it did. The header carries no version, so the
+//! SHA-256 prefix is what identifies an image; compare it with `sha256sum`
+//! of the `.otap`'s app binary. Release notes, if the packer was given any,
+//! tell the operator which release that was. Times are a boot number and seconds since that boot, like in
+//! [`crate::clients`].
+//!
+//! The last [`OTA_HISTORY_SLOTS`] attempts are kept in flash next to the
+//! config, written by [`crate::store::record_ota_write`] and
+//! [`crate::store::record_ota_failure`]. An upload abandoned without an
+//! error, by dropping the connection mid-transfer, is not recorded.
+//!
+//! Each attempt also records the firmware that was running, so the first
+//! boot of an installed image can say what it replaced: the platform calls
+//! [`booted_update`], which emits [`Event::FirmwareUpdated`] and leaves a
+//! notice for the first bridge session.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write as _};
+use core::net::IpAddr;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use log::{info, warn};
+use ssh_stamp_hal::{OtaFailure, OtaUpload};
+use sunset::sshwire::{SSHDecode, SSHEncode, SSHSink, SSHSource, WireError, WireResult};
+
+use crate::clients::{PEER_MAX_LEN, dec_peer, enc_peer};
+use crate::config::{dec_option, enc_option, wire_str};
+use crate::events::{self, Event};
+use crate::resets;
+use crate::sessions;
+use crate::settings::{
+    FIRMWARE_ID_LEN, OTA_DIGEST_PREFIX_LEN, OTA_HISTORY_SLOTS, OTA_NOTES_LEN, USER_NAME_LEN,
+};
+use crate::version::{FIRMWARE_VERSION, GIT_HASH};
+
+/// One upload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtaAttempt {
+    /// Boot number at the time, 0 if unknown.
+    pub boot: u32,
+    /// Seconds after that boot.
+    pub uptime_secs: u64,
+    /// Image size from the header, `None` if it did not get that far.
+    pub size: Option<u32>,
+    /// Leading bytes of the image's SHA-256 from the header.
+    pub sha256: Option<[u8; OTA_DIGEST_PREFIX_LEN]>,
+    /// Start of the image's release notes from the header, empty if none.
+    pub notes: String<OTA_NOTES_LEN>,
+    /// `None` if the image was installed.
+    pub failure: Option<OtaFailure>,
+    /// Username of the uploading session, empty if not known.
+    pub user: String<USER_NAME_LEN>,
+    pub peer: Option<IpAddr>,
+    /// Firmware running at the time, see [`running_firmware`].
+    pub replaced: String<FIRMWARE_ID_LEN>,
+}
+
+/// Describes `failure` for `show ota history`.
+#[must_use]
+pub fn failure_name(failure: OtaFailure) -> &'static str {
+    match failure {
+        OtaFailure::BadHeader => "bad header",
+        OtaFailure::Verification => "checksum mismatch",
+        OtaFailure::Signature => "bad signature",
+        OtaFailure::Write => "flash write error",
+        OtaFailure::Incomplete => "incomplete upload",
+        OtaFailure::Internal => "internal error",
+    }
+}
+
+impl fmt::Display for OtaAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "boot {} at {}s: ", self.boot, self.uptime_secs)?;
+        match self.failure {
+            None => f.write_str("installed")?,
+            Some(failure) => write!(f, "failed, {}", failure_name(failure))?,
+        }
+        if let Some(size) = self.size {
+            write!(f, ", {size} bytes")?;
+        }
+        if let Some(sha256) = self.sha256 {
+            f.write_str(", sha256 ")?;
+            for b in sha256 {
+                write!(f, "{b:02x}")?;
+            }
+        }
+        if !self.notes.is_empty() {
+            write!(f, ", notes \"{}\"", self.notes)?;
+        }
+        if !self.user.is_empty() {
+            write!(f, ", by {}", self.user)?;
+        }
+        if let Some(peer) = self.peer {
+            write!(f, " from {peer}")?;
+        }
+        if self.failure.is_none() && !self.replaced.is_empty() {
+            write!(f, ", replacing {}", self.replaced)?;
+        }
+        Ok(())
+    }
+}
+
+/// The persisted ring, oldest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OtaHistory(pub Vec<OtaAttempt, OTA_HISTORY_SLOTS>);
+
+impl OtaHistory {
+    /// Largest encoding of a full ring.
+    pub const MAX_ENCODED_LEN: usize = 1 + OTA_HISTORY_SLOTS
+        * (4 + 8
+            + (1 + 4)
+            + (1 + OTA_DIGEST_PREFIX_LEN)
+            + wire_str(OTA_NOTES_LEN)
+            + 1
+            + wire_str(USER_NAME_LEN)
+            + PEER_MAX_LEN
+            + wire_str(FIRMWARE_ID_LEN));
+}
+
+static HISTORY: Mutex<CriticalSectionRawMutex, RefCell<OtaHistory>> =
+    Mutex::new(RefCell::new(OtaHistory(Vec::new())));
+
+/// An attempt for `upload` by the session in progress, now. `failure` is
+/// `None` if the image was installed.
+#[must_use]
+pub fn attempt(upload: &OtaUpload, failure: Option<OtaFailure>) -> OtaAttempt {
+    OtaAttempt {
+        boot: resets::boot_number(),
+        uptime_secs: Instant::now().as_secs(),
+        size: upload.size,
+        sha256: upload.sha256.and_then(|digest| {
+            digest
+                .get(..OTA_DIGEST_PREFIX_LEN)
+                .and_then(|prefix| prefix.try_into().ok())
+        }),
+        notes: sessions::truncated(&upload.release_notes),
+        failure,
+        user: sessions::current_user().unwrap_or_default(),
+        peer: sessions::current_peer(),
+        replaced: running_firmware(),
+    }
+}
+
+/// The running firmware's version and commit, e.g. `0.3.1 (a1b2c3d)`.
+#[must_use]
+pub fn running_firmware() -> String<FIRMWARE_ID_LEN> {
+    let mut id: String<FIRMWARE_ID_LEN> = sessions::truncated(FIRMWARE_VERSION);
+    let _ = write!(id, " ({GIT_HASH})");
+    id
+}
+
+/// The first boot of an image installed over OTA, see [`booted_update`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirmwareUpdate {
+    /// Slot the device booted from before, as the platform names it, e.g.
+    /// `ota_0` or `factory`.
+    pub from_slot: &'static str,
+    /// Slot of the running image.
+    pub to_slot: &'static str,
+    /// Firmware the image replaced, `None` if its upload did not record it.
+    pub from: Option<String<FIRMWARE_ID_LEN>>,
+    /// The running firmware, see [`running_firmware`].
+    pub to: String<FIRMWARE_ID_LEN>,
+}
+
+impl fmt::Display for FirmwareUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "firmware updated to {} in {}, was ",
+            self.to, self.to_slot
+        )?;
+        match &self.from {
+            Some(from) => write!(f, "{from} in {}", self.from_slot),
+            None => write!(f, "unknown firmware in {}", self.from_slot),
+        }
+    }
+}
+
+/// See [`booted_update`].
+static UPDATE: Mutex<CriticalSectionRawMutex, RefCell<Option<FirmwareUpdate>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Reports that this boot is the first of an image installed over OTA,
+/// running from `to_slot` where the device booted from `from_slot` before:
+/// emits [`Event::FirmwareUpdated`] with the firmware the last installed
+/// upload replaced, and keeps the update for [`take_update`]. The platform
+/// calls this once the config is loaded, so the history is known.
+pub fn booted_update(from_slot: &'static str, to_slot: &'static str) {
+    let from = HISTORY.lock(|history| {
+        history
+            .borrow()
+            .0
+            .iter()
+            .rev()
+            .find(|a| a.failure.is_none())
+            .map(|a| a.replaced.clone())
+    });
+    let update = FirmwareUpdate {
+        from_slot,
+        to_slot,
+        from: from.filter(|from| !from.is_empty()),
+        to: running_firmware(),
+    };
+    UPDATE.lock(|u| *u.borrow_mut() = Some(update.clone()));
+    events::emit(Event::FirmwareUpdated(update));
+}
+
+/// The update [`booted_update`] reported, once: the first bridge session
+/// after it announces it.
+#[must_use]
+pub fn take_update() -> Option<FirmwareUpdate> {
+    UPDATE.lock(|u| u.borrow_mut().take())
+}
+
+/// Adds `attempt`, dropping the oldest once the ring is full. Called by
+/// [`crate::store`] between loading the config and saving it.
+pub(crate) fn push(attempt: OtaAttempt) {
+    match attempt.failure {
+        None => info!("OTA history: {attempt}"),
+        Some(_) => warn!("OTA history: {attempt}"),
+    }
+    HISTORY.lock(|history| {
+        let history = &mut history.borrow_mut().0;
+        if history.is_full() {
+            history.remove(0);
+        }
+        let _ = history.push(attempt);
+    });
+}
+
+/// A copy of the ring.
+#[must_use]
+pub fn snapshot() -> OtaHistory {
+    HISTORY.lock(|history| history.borrow().clone())
+}
+
+/// Replaces the ring with the one loaded from flash.
+pub(crate) fn restore(saved: OtaHistory) {
+    HISTORY.lock(|history| *history.borrow_mut() = saved);
+}
+
+// A private encoding, like the config's.
+fn enc_failure(v: Option<OtaFailure>, s: &mut dyn SSHSink) -> WireResult<()> {
+    let code: u8 = match v {
+        None => 0,
+        Some(OtaFailure::BadHeader) => 1,
+        Some(OtaFailure::Verification) => 2,
+        Some(OtaFailure::Write) => 3,
+        Some(OtaFailure::Incomplete) => 4,
+        Some(OtaFailure::Internal) => 5,
+        Some(OtaFailure::Signature) => 6,
+    };
+    code.enc(s)
+}
+
+fn dec_failure<'de, S>(s: &mut S) -> WireResult<Option<OtaFailure>>
+where
+    S: SSHSource<'de>,
+{
+    Ok(match u8::dec(s)? {
+        0 => None,
+        1 => Some(OtaFailure::BadHeader),
+        2 => Some(OtaFailure::Verification),
+        3 => Some(OtaFailure::Write),
+        4 => Some(OtaFailure::Incomplete),
+        5 => Some(OtaFailure::Internal),
+        6 => Some(OtaFailure::Signature),
+        _ => return Err(WireError::UnknownVariant),
+    })
+}
+
+impl SSHEncode for OtaHistory {
+    fn enc(&self, s: &mut dyn SSHSink) -> WireResult<()> {
+        u8::try_from(self.0.len())
+            .map_err(|_| WireError::PacketWrong)?
+            .enc(s)?;
+        for a in &self.0 {
+            a.boot.enc(s)?;
+            a.uptime_secs.enc(s)?;
+            enc_option(a.size.as_ref(), s)?;
+            enc_option(a.sha256.as_ref(), s)?;
+            a.notes.as_str().enc(s)?;
+            enc_failure(a.failure, s)?;
+            a.user.as_str().enc(s)?;
+            enc_peer(a.peer.as_ref(), s)?;
+            a.replaced.as_str().enc(s)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> SSHDecode<'de> for OtaHistory {
+    fn dec<S>(s: &mut S) -> WireResult<Self>
+    where
+        S: SSHSource<'de>,
+    {
+        let count: u8 = SSHDecode::dec(s)?;
+        let mut v = Vec::new();
+        for _ in 0..count {
+            let boot = SSHDecode::dec(s)?;
+            let uptime_secs = SSHDecode::dec(s)?;
+            let size = dec_option(s)?;
+            let sha256 = dec_option(s)?;
+            let notes: &str = SSHDecode::dec(s)?;
+            let failure = dec_failure(s)?;
+            let user: &str = SSHDecode::dec(s)?;
+            let peer = dec_peer(s)?;
+            let replaced: &str = SSHDecode::dec(s)?;
+            let attempt = OtaAttempt {
+                boot,
+                uptime_secs,
+                size,
+                sha256,
+                notes: String::try_from(notes).map_err(|_| WireError::BadString)?,
+                failure,
+                user: String::try_from(user).map_err(|_| WireError::BadString)?,
+                peer,
+                replaced: String::try_from(replaced).map_err(|_| WireError::BadString)?,
+            };
+            v.push(attempt).map_err(|_| WireError::PacketWrong)?;
+        }
+        Ok(Self(v))
+    }
+}
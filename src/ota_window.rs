@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Confirmation window for firmware installed over OTA.
+//!
+//! A new image boots unconfirmed. With a window configured
+//! (`SSH_STAMP_OTA_CONFIRM`, see [`OtaConfirm`]) an admin has to run
+//! `ota confirm` within [`OtaConfirm::minutes`] of each boot, and before the
+//! image has booted more than [`OtaConfirm::boots`] times. Otherwise the
+//! platform marks the image invalid and resets into the previous one, so an
+//! update that loses the network, or crashes before anyone can log in, undoes
+//! itself. Without a window the image is confirmed as soon as it boots.
+//!
+//! The platform counts the boots of an unconfirmed image in the
+//! [`FlashStats`](crate::store::FlashStats), asks [`check_boot`] what to do
+//! and starts the countdown with [`begin`]; [`watchdog`] runs alongside the
+//! SSH server and rolls back once the time is up.
+
+use core::cell::Cell;
+use core::convert::Infallible;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use log::{error, info, warn};
+use ssh_stamp_hal::{HalError, OtaActions};
+
+use crate::config::OtaConfirm;
+use crate::platform::PlatformServices;
+
+/// What to do with an unconfirmed image at boot, see [`check_boot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootCheck {
+    /// No window configured: confirm it now.
+    Confirm,
+    /// Keep it unconfirmed and start the countdown with [`begin`].
+    Wait,
+    /// Out of boots: roll back without waiting.
+    RollBack,
+}
+
+/// The running image, awaiting confirmation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pending {
+    /// Boots of this image so far, this one included.
+    pub boot: u8,
+    pub window: OtaConfirm,
+}
+
+impl Pending {
+    /// When the image is rolled back unless confirmed, `None` without a
+    /// time limit.
+    #[must_use]
+    pub fn deadline(&self) -> Option<Instant> {
+        (self.window.minutes > 0).then(|| Instant::from_secs(u64::from(self.window.minutes) * 60))
+    }
+
+    /// Time left before the rollback, `None` without a time limit.
+    #[must_use]
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+static PENDING: Mutex<CriticalSectionRawMutex, Cell<Option<Pending>>> = Mutex::new(Cell::new(None));
+
+/// Wakes [`watchdog`] when [`PENDING`] changes.
+static PENDING_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// What to do with an unconfirmed image on its `boot`th boot under
+/// `window`.
+#[must_use]
+pub fn check_boot(window: OtaConfirm, boot: u8) -> BootCheck {
+    if window.is_off() {
+        BootCheck::Confirm
+    } else if window.boots > 0 && boot > window.boots {
+        BootCheck::RollBack
+    } else {
+        BootCheck::Wait
+    }
+}
+
+/// Starts the countdown for an unconfirmed image on its `boot`th boot.
+pub fn begin(window: OtaConfirm, boot: u8) {
+    match window.minutes {
+        0 => warn!("Firmware not confirmed, boot {boot} of {}", window.boots),
+        minutes => warn!("Firmware not confirmed, rolling back in {minutes} minutes"),
+    }
+    PENDING.lock(|p| p.set(Some(Pending { boot, window })));
+    PENDING_CHANGED.signal(());
+}
+
+/// The running image if it awaits confirmation.
+#[must_use]
+pub fn pending() -> Option<Pending> {
+    PENDING.lock(Cell::get)
+}
+
+/// Confirms the running image, ending the window.
+///
+/// # Errors
+///
+/// Returns `HalError::Flash` if the image state cannot be written; the
+/// image stays unconfirmed.
+pub async fn confirm<O: OtaActions>() -> Result<(), HalError> {
+    O::try_validating_current_ota_partition().await?;
+    PENDING.lock(|p| p.set(None));
+    PENDING_CHANGED.signal(());
+    info!("Firmware confirmed");
+    Ok(())
+}
+
+/// Rolls back an image not confirmed in time, see the
+/// [module documentation](self). Runs alongside the SSH server.
+pub async fn watchdog<P: PlatformServices>(platform: &P) -> Infallible {
+    loop {
+        let Some(deadline) = pending().and_then(|p| p.deadline()) else {
+            PENDING_CHANGED.wait().await;
+            continue;
+        };
+        if let Either::Second(()) = select(Timer::at(deadline), PENDING_CHANGED.wait()).await {
+            continue;
+        }
+        if pending().is_none() {
+            continue;
+        }
+        warn!("Firmware not confirmed in time, rolling back. Rebooting...");
+        match P::OtaWriter::roll_back_current_ota_partition().await {
+            Ok(()) => platform.reset(),
+            Err(e) => error!("Cannot roll back the firmware: {e}"),
+        }
+        // Left unconfirmed; the boot limit, if any, still applies.
+        PENDING_CHANGED.wait().await;
+    }
+}
@@ -0,0 +1,289 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Important events kept in flash until someone has taken them.
+//!
+//! The [`crate::events`] log lives in RAM and is gone after a reset, which
+//! is exactly when a fault matters most. Events worth keeping are therefore
+//! also queued here: fault resets (panic, watchdog, brown-out), OTA results
+//! and the [`Event`]s for which [`Event::is_durable`] holds. The queue is
+//! stored in the config sector, written together with the reset count or
+//! OTA history entry that caused it, and otherwise with the next config
+//! save or at the end of the next SSH connection.
+//!
+//! Entries stay until they are taken: by a forwarder (e.g. a plugin) with
+//! [`take`], or by an operator who read them with `show outbox` and ran
+//! `outbox clear`. Each holds the event's text, truncated to
+//! [`OUTBOX_TEXT_LEN`] bytes.
+//!
+//! At most [`OUTBOX_SLOTS`] entries fit. A new entry evicts the oldest of
+//! the lowest [`Severity`] queued, as long as that is not above its own;
+//! otherwise the new entry is dropped. Either way the loss is counted and
+//! shown with the queue.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write as _};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use log::warn;
+use sunset::sshwire::{SSHDecode, SSHEncode, SSHSink, SSHSource, WireError, WireResult};
+
+use crate::config::wire_str;
+use crate::events::Event;
+use crate::resets;
+use crate::settings::{OUTBOX_SLOTS, OUTBOX_TEXT_LEN};
+
+/// How much an entry matters, for choosing what to drop when the queue is
+/// full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Worth knowing, e.g. an installed firmware image.
+    Notice,
+    /// Something went wrong and was dealt with.
+    Warning,
+    /// The device failed: a fault reset or a failed firmware upload.
+    Fault,
+}
+
+impl Severity {
+    /// Name shown by `show outbox`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Notice => "notice",
+            Severity::Warning => "warning",
+            Severity::Fault => "fault",
+        }
+    }
+}
+
+/// One queued event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    /// Boot number at the time, 0 if unknown.
+    pub boot: u32,
+    /// Seconds after that boot.
+    pub uptime_secs: u64,
+    pub severity: Severity,
+    pub text: String<OUTBOX_TEXT_LEN>,
+}
+
+impl fmt::Display for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "boot {} at {}s, {}: {}",
+            self.boot,
+            self.uptime_secs,
+            self.severity.name(),
+            self.text
+        )
+    }
+}
+
+/// The persisted queue, oldest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Outbox {
+    pub pending: Vec<Pending, OUTBOX_SLOTS>,
+    /// Entries lost to a full queue since it was last cleared.
+    pub dropped: u16,
+}
+
+impl Outbox {
+    /// Largest encoding of a full queue.
+    pub const MAX_ENCODED_LEN: usize =
+        1 + OUTBOX_SLOTS * (4 + 8 + 1 + wire_str(OUTBOX_TEXT_LEN)) + 4;
+}
+
+struct State {
+    outbox: Outbox,
+    /// Changed since the last save.
+    changed: bool,
+}
+
+static OUTBOX: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    outbox: Outbox {
+        pending: Vec::new(),
+        dropped: 0,
+    },
+    changed: false,
+}));
+
+/// Keeps what fits in [`OUTBOX_TEXT_LEN`] bytes of the text written, up to
+/// the first character that does not.
+#[derive(Default)]
+struct Truncating {
+    text: String<OUTBOX_TEXT_LEN>,
+    full: bool,
+}
+
+impl fmt::Write for Truncating {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.full || self.text.push(c).is_err() {
+                self.full = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Queues `text` as happening now, see the module docs for what is dropped
+/// when the queue is full.
+pub fn push(severity: Severity, text: impl fmt::Display) {
+    let mut truncating = Truncating::default();
+    let _ = write!(truncating, "{text}");
+    let entry = Pending {
+        boot: resets::boot_number(),
+        uptime_secs: Instant::now().as_secs(),
+        severity,
+        text: truncating.text,
+    };
+    let rejected = OUTBOX.lock(|state| {
+        let mut state = state.borrow_mut();
+        state.changed = true;
+        let outbox = &mut state.outbox;
+        if outbox.pending.is_full() {
+            outbox.dropped = outbox.dropped.saturating_add(1);
+            let victim = outbox
+                .pending
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| p.severity)
+                .filter(|(_, p)| p.severity <= severity)
+                .map(|(i, _)| i);
+            match victim {
+                Some(i) => {
+                    outbox.pending.remove(i);
+                }
+                None => return Some(entry),
+            }
+        }
+        let _ = outbox.pending.push(entry);
+        None
+    });
+    // Logged outside the critical section, see the crate's invariants.
+    if let Some(entry) = rejected {
+        warn!("Outbox full, dropped: {}", entry.text);
+    }
+}
+
+/// Whether the queue should be written to flash, i.e. it changed since it
+/// was last saved.
+#[must_use]
+pub fn needs_saving() -> bool {
+    OUTBOX.lock(|state| state.borrow().changed)
+}
+
+/// A copy of the queue.
+#[must_use]
+pub fn snapshot() -> Outbox {
+    OUTBOX.lock(|state| state.borrow().outbox.clone())
+}
+
+/// Empties the queue, returning what it held. The caller has forwarded or
+/// shown it; the empty queue is written with the next save.
+pub fn take() -> Outbox {
+    OUTBOX.lock(|state| {
+        let mut state = state.borrow_mut();
+        state.changed = true;
+        core::mem::take(&mut state.outbox)
+    })
+}
+
+/// Replaces the queue with the one loaded from flash.
+pub(crate) fn restore(saved: Outbox) {
+    OUTBOX.lock(|state| {
+        let mut state = state.borrow_mut();
+        state.outbox = saved;
+        state.changed = false;
+    });
+}
+
+/// Notes that `saved` was written to flash.
+pub(crate) fn saved(saved: &Outbox) {
+    OUTBOX.lock(|state| {
+        let mut state = state.borrow_mut();
+        // An event may have slipped in while flash was written.
+        if state.outbox == *saved {
+            state.changed = false;
+        }
+    });
+}
+
+/// Queues `event` if it is worth keeping across resets.
+pub(crate) fn event(event: &Event) {
+    if event.is_durable() {
+        push(Severity::Warning, event);
+    }
+}
+
+// A private encoding, like the config's.
+fn enc_severity(v: Severity, s: &mut dyn SSHSink) -> WireResult<()> {
+    let code: u8 = match v {
+        Severity::Notice => 0,
+        Severity::Warning => 1,
+        Severity::Fault => 2,
+    };
+    code.enc(s)
+}
+
+fn dec_severity<'de, S>(s: &mut S) -> WireResult<Severity>
+where
+    S: SSHSource<'de>,
+{
+    Ok(match u8::dec(s)? {
+        0 => Severity::Notice,
+        1 => Severity::Warning,
+        2 => Severity::Fault,
+        _ => return Err(WireError::UnknownVariant),
+    })
+}
+
+impl SSHEncode for Outbox {
+    fn enc(&self, s: &mut dyn SSHSink) -> WireResult<()> {
+        u8::try_from(self.pending.len())
+            .map_err(|_| WireError::PacketWrong)?
+            .enc(s)?;
+        for p in &self.pending {
+            p.boot.enc(s)?;
+            p.uptime_secs.enc(s)?;
+            enc_severity(p.severity, s)?;
+            p.text.as_str().enc(s)?;
+        }
+        u32::from(self.dropped).enc(s)
+    }
+}
+
+impl<'de> SSHDecode<'de> for Outbox {
+    fn dec<S>(s: &mut S) -> WireResult<Self>
+    where
+        S: SSHSource<'de>,
+    {
+        let count: u8 = SSHDecode::dec(s)?;
+        let mut pending = Vec::new();
+        for _ in 0..count {
+            let boot = SSHDecode::dec(s)?;
+            let uptime_secs = SSHDecode::dec(s)?;
+            let severity = dec_severity(s)?;
+            let text: &str = SSHDecode::dec(s)?;
+            let entry = Pending {
+                boot,
+                uptime_secs,
+                severity,
+                text: String::try_from(text).map_err(|_| WireError::BadString)?,
+            };
+            pending.push(entry).map_err(|_| WireError::PacketWrong)?;
+        }
+        let dropped: u32 = SSHDecode::dec(s)?;
+        Ok(Self {
+            pending,
+            dropped: u16::try_from(dropped).map_err(|_| WireError::PacketWrong)?,
+        })
+    }
+}
@@ -0,0 +1,315 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Finding the other stamps on the network, for `show peers`.
+//!
+//! Builds with the `peers` feature answer mDNS queries for
+//! `_ssh-stamp._tcp.local` with a PTR record naming the stamp by its access
+//! point SSID, and those for `_ssh._tcp.local` too, so `stamp-cli discover`
+//! finds them without a subnet scan. Every [`PEER_BROWSE_SECS`] they ask for
+//! `_ssh-stamp._tcp.local` themselves and remember who answered from which
+//! address, until [`PEER_EXPIRY_SECS`] pass without another answer.
+//!
+//! This is deliberately small: only PTR records are sent, without SRV, TXT
+//! or address records, and names are not probed for conflicts. A peer is
+//! the source address of its answer. Discovery runs on the primary stack,
+//! the station's network in AP+STA mode.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::net::{IpAddr, Ipv4Addr};
+
+use embassy_futures::select::{Either, select};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
+use log::{debug, info, warn};
+
+use crate::sessions::truncated;
+use crate::settings::{PEER_BROWSE_SECS, PEER_EXPIRY_SECS, PEER_NAME_LEN, PEER_SLOTS};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// Browsed for; only stamps answer it.
+const STAMP_SERVICE: &str = "_ssh-stamp._tcp.local";
+/// Answered as well, for `stamp-cli discover`.
+const SSH_SERVICE: &str = "_ssh._tcp.local";
+const TYPE_PTR: u16 = 12;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Seconds other hosts may cache an answer; RFC 6762 caps it at 10 for
+/// queries from other ports than 5353.
+const TTL_SECS: u32 = 120;
+const LEGACY_TTL_SECS: u32 = 10;
+/// Largest message sent or read.
+const MESSAGE_LEN: usize = 512;
+/// Longest name read: an instance label and the service.
+const NAME_LEN: usize = 128;
+/// Compression pointers followed in one name, so a loop cannot hang us.
+const MAX_POINTERS: usize = 8;
+
+/// Another stamp that answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// Its access point SSID.
+    pub name: String<PEER_NAME_LEN>,
+    pub addr: IpAddr,
+    /// Seconds after boot when it last answered.
+    pub seen_secs: u64,
+}
+
+static PEERS: Mutex<CriticalSectionRawMutex, RefCell<Vec<Peer, PEER_SLOTS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// The stamps heard from within [`PEER_EXPIRY_SECS`].
+#[must_use]
+pub fn snapshot() -> Vec<Peer, PEER_SLOTS> {
+    PEERS.lock(|peers| peers.borrow().clone())
+}
+
+fn remember(name: &str, addr: IpAddr, now: u64) {
+    let new = PEERS.lock(|peers| {
+        let mut peers = peers.borrow_mut();
+        if let Some(peer) = peers.iter_mut().find(|p| p.name == name) {
+            let moved = peer.addr != addr;
+            peer.addr = addr;
+            peer.seen_secs = now;
+            return moved;
+        }
+        if peers.is_full()
+            && let Some(oldest) = (0..peers.len()).min_by_key(|&i| peers[i].seen_secs)
+        {
+            peers.swap_remove(oldest);
+        }
+        let _ = peers.push(Peer {
+            name: truncated(name),
+            addr,
+            seen_secs: now,
+        });
+        true
+    });
+    if new {
+        info!("Peer {name} at {addr}");
+    }
+}
+
+fn forget_stale(now: u64) {
+    PEERS.lock(|peers| {
+        peers
+            .borrow_mut()
+            .retain(|p| now.saturating_sub(p.seen_secs) < PEER_EXPIRY_SECS);
+    });
+}
+
+/// Answers mDNS queries for this stamp, announced as `name`, and browses for
+/// the others. Never returns.
+pub async fn discover(stack: Stack<'_>, name: &str) -> Infallible {
+    if let Err(e) = stack.join_multicast_group(IpAddress::Ipv4(MDNS_GROUP)) {
+        warn!("Peer discovery: cannot join the mDNS group: {e:?}");
+    }
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 2 * MESSAGE_LEN];
+    let mut tx_buffer = [0u8; MESSAGE_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        warn!("Peer discovery: cannot bind port {MDNS_PORT}: {e:?}");
+        return core::future::pending().await;
+    }
+
+    let group = IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT);
+    let mut message = [0u8; MESSAGE_LEN];
+    let mut next_browse = Instant::now();
+    loop {
+        match select(socket.recv_from(&mut message), Timer::at(next_browse)).await {
+            Either::First(Ok((len, meta))) => {
+                let message = &message[..len];
+                if message.get(2).is_some_and(|flags| flags & 0x80 != 0) {
+                    let from = IpAddr::from(meta.endpoint.addr);
+                    let _ = for_each_peer(message, |peer| {
+                        if peer != name {
+                            remember(peer, from, Instant::now().as_secs());
+                        }
+                    });
+                } else if let Some((id, service)) = asked(message) {
+                    // Queries from other ports are answered to them alone.
+                    let legacy = meta.endpoint.port != MDNS_PORT;
+                    let to = if legacy { meta.endpoint } else { group };
+                    if let Some(reply) = response(id, service, name, legacy)
+                        && let Err(e) = socket.send_to(&reply, to).await
+                    {
+                        debug!("Peer discovery: cannot answer {to}: {e:?}");
+                    }
+                }
+            }
+            Either::First(Err(e)) => debug!("Peer discovery: receive error {e:?}"),
+            Either::Second(()) => {
+                forget_stale(Instant::now().as_secs());
+                if let Some(query) = query()
+                    && let Err(e) = socket.send_to(&query, group).await
+                {
+                    debug!("Peer discovery: cannot send query: {e:?}");
+                }
+                next_browse = Instant::now() + Duration::from_secs(PEER_BROWSE_SECS);
+            }
+        }
+    }
+}
+
+fn be16(message: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *message.get(pos)?,
+        *message.get(pos + 1)?,
+    ]))
+}
+
+fn put(out: &mut Vec<u8, MESSAGE_LEN>, bytes: &[u8]) -> Option<()> {
+    out.extend_from_slice(bytes).ok()
+}
+
+fn put_label(out: &mut Vec<u8, MESSAGE_LEN>, label: &str) -> Option<()> {
+    put(out, &[u8::try_from(label.len()).ok().filter(|&l| l <= 63)?])?;
+    put(out, label.as_bytes())
+}
+
+fn put_name(out: &mut Vec<u8, MESSAGE_LEN>, name: &str) -> Option<()> {
+    for label in name.split('.') {
+        put_label(out, label)?;
+    }
+    put(out, &[0])
+}
+
+/// Reads the name at `pos` as dotted labels into `name`, following
+/// compression pointers. Returns the position after it.
+fn read_name(message: &[u8], mut pos: usize, name: &mut String<NAME_LEN>) -> Option<usize> {
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(pos)?;
+        if len == 0 {
+            return Some(end.unwrap_or(pos + 1));
+        }
+        if len & 0xC0 == 0xC0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            end.get_or_insert(pos + 2);
+            pos = usize::from(be16(message, pos)? & 0x3FFF);
+            continue;
+        }
+        let label = message.get(pos + 1..pos + 1 + usize::from(len))?;
+        if !name.is_empty() {
+            name.push('.').ok()?;
+        }
+        name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+        pos += 1 + usize::from(len);
+    }
+}
+
+/// The id of query `message` and the first of our services it asks for.
+fn asked(message: &[u8]) -> Option<(u16, &'static str)> {
+    let questions = be16(message, 4)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        let mut name = String::new();
+        pos = read_name(message, pos, &mut name)?;
+        let qtype = be16(message, pos)?;
+        pos += 4;
+        if qtype != TYPE_PTR && qtype != TYPE_ANY {
+            continue;
+        }
+        if let Some(service) = [STAMP_SERVICE, SSH_SERVICE]
+            .into_iter()
+            .find(|s| name.eq_ignore_ascii_case(s))
+        {
+            return Some((be16(message, 0)?, service));
+        }
+    }
+    None
+}
+
+/// Calls `f` with the instance name of every PTR answer for
+/// [`STAMP_SERVICE`] in response `message`.
+fn for_each_peer(message: &[u8], mut f: impl FnMut(&str)) -> Option<()> {
+    let questions = be16(message, 4)?;
+    let answers = be16(message, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(message, pos, &mut String::new())? + 4;
+    }
+    for _ in 0..answers {
+        let mut owner = String::new();
+        pos = read_name(message, pos, &mut owner)?;
+        let rtype = be16(message, pos)?;
+        let rdata = pos + 10;
+        pos = rdata + usize::from(be16(message, pos + 8)?);
+        if rtype != TYPE_PTR || !owner.eq_ignore_ascii_case(STAMP_SERVICE) {
+            continue;
+        }
+        let mut target = String::new();
+        read_name(message, rdata, &mut target)?;
+        let split = target.len().checked_sub(STAMP_SERVICE.len() + 1)?;
+        if target
+            .get(split + 1..)
+            .is_some_and(|s| s.eq_ignore_ascii_case(STAMP_SERVICE))
+            && let Some(instance) = target.get(..split).filter(|i| !i.is_empty())
+        {
+            f(instance);
+        }
+    }
+    Some(())
+}
+
+/// A query for [`STAMP_SERVICE`].
+fn query() -> Option<Vec<u8, MESSAGE_LEN>> {
+    let mut out = Vec::new();
+    // Id 0, standard query, one question
+    put(&mut out, &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+    put_name(&mut out, STAMP_SERVICE)?;
+    put(&mut out, &TYPE_PTR.to_be_bytes())?;
+    put(&mut out, &CLASS_IN.to_be_bytes())?;
+    Some(out)
+}
+
+/// The answer to query `id` for `service`: a PTR record to `instance`. A
+/// legacy answer repeats the id and question.
+fn response(id: u16, service: &str, instance: &str, legacy: bool) -> Option<Vec<u8, MESSAGE_LEN>> {
+    let mut out = Vec::new();
+    let (id, questions, ttl) = if legacy {
+        (id, 1u16, LEGACY_TTL_SECS)
+    } else {
+        (0, 0, TTL_SECS)
+    };
+    put(&mut out, &id.to_be_bytes())?;
+    // Response, authoritative; one answer
+    put(&mut out, &[0x84, 0])?;
+    put(&mut out, &questions.to_be_bytes())?;
+    put(&mut out, &[0, 1, 0, 0, 0, 0])?;
+    if legacy {
+        put_name(&mut out, service)?;
+        put(&mut out, &TYPE_PTR.to_be_bytes())?;
+        put(&mut out, &CLASS_IN.to_be_bytes())?;
+    }
+    put_name(&mut out, service)?;
+    put(&mut out, &TYPE_PTR.to_be_bytes())?;
+    put(&mut out, &CLASS_IN.to_be_bytes())?;
+    put(&mut out, &ttl.to_be_bytes())?;
+    let rdlength = out.len();
+    put(&mut out, &[0, 0])?;
+    put_label(&mut out, instance)?;
+    put_name(&mut out, service)?;
+    let len = u16::try_from(out.len() - rdlength - 2).ok()?;
+    out[rdlength..rdlength + 2].copy_from_slice(&len.to_be_bytes());
+    Some(out)
+}
@@ -9,18 +9,28 @@
 //! app-layer state like [`SSHStampConfig`] or the serial bridge):
 //!
 //! * persisting the SSH-stamp config to non-volatile storage,
-//! * resetting the device,
-//! * minting an [`OtaActions`] writer for the SFTP OTA session,
+//! * resetting or powering off the device, after any flash write in
+//!   progress,
+//! * minting an [`OtaActions`] writer for the SFTP OTA session, and a
+//!   [`ScratchActions`] handle on the scratch partition it serves,
 //! * signalling the serial bridge that SSH is ready and the UART task
-//!   should wake up.
+//!   should wake up,
+//! * chain-booting into the recovery image,
+//! * driving the pulse output used by the `pulse` admin command (`rmt`
+//!   feature),
+//! * routing the bridge UART through an external mux (`mux` feature),
+//! * describing itself for `show version` and `show build`.
 //!
 //! Each platform crate provides one impl (for ESP32: `EspPlatform`).
 //! Consumers take `&impl PlatformServices` so the same app code runs on
 //! every MCU port.
 
+use core::fmt;
 use core::future::Future;
 
-use ssh_stamp_hal::{HalError, OtaActions};
+#[cfg(feature = "rmt")]
+use ssh_stamp_hal::Pulse;
+use ssh_stamp_hal::{HalError, OtaActions, ScratchActions};
 
 use crate::config::SSHStampConfig;
 
@@ -30,16 +40,25 @@ use crate::config::SSHStampConfig;
 ///
 /// * [`Self::save_config`] must be durable: after it returns `Ok(())` the
 ///   config must survive a reboot.
-/// * [`Self::reset`] must not return.
+/// * [`Self::reset`] and [`Self::power_off`] must not return.
+/// * Once [`Self::sync_flash`] returns, no flash write is in progress
+///   until the caller next yields.
 /// * [`Self::ota_writer`] may be called multiple times; each call yields
-///   a fresh writer suitable for a single OTA session.
+///   a fresh writer suitable for a single OTA session. So may
+///   [`Self::scratch`].
 /// * [`Self::activate_uart`] signals the platform's buffered UART task
 ///   (if any) that it is OK to start streaming. Idempotent.
+/// * [`Self::boot_recovery`] must only return on failure, leaving the
+///   current boot selection untouched.
 pub trait PlatformServices {
     /// OTA writer type this platform provides. Must live for the whole
     /// SFTP session, so `'static` is required.
     type OtaWriter: OtaActions + 'static;
 
+    /// Scratch partition access this platform provides, see
+    /// [`ota::scratch`].
+    type Scratch: ScratchActions + 'static;
+
     /// Persist the full config to non-volatile storage.
     ///
     /// # Errors
@@ -50,10 +69,73 @@ pub trait PlatformServices {
     /// Reset the device. Does not return.
     fn reset(&self) -> !;
 
+    /// Power the device down until it is power cycled or reset through its
+    /// reset pin. Does not return.
+    fn power_off(&self) -> !;
+
+    /// Wait for any flash write in progress (config save, OTA, recorded
+    /// reset) to finish, so a following [`Self::reset`] or
+    /// [`Self::power_off`] cannot cut it short.
+    fn sync_flash(&self) -> impl Future<Output = ()>;
+
     /// Construct a fresh OTA writer for a new SFTP OTA session.
     fn ota_writer(&self) -> Self::OtaWriter;
 
+    /// Access the scratch partition, for an SFTP session or an admin
+    /// command.
+    fn scratch(&self) -> Self::Scratch;
+
     /// Signal the platform's buffered UART task that SSH is ready and
     /// UART transfer may start. Idempotent.
     fn activate_uart(&self);
+
+    /// Select the recovery image (built with the `recovery` feature) for the
+    /// next boot and reset into it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `HalError::Flash` if no recovery image is installed or the
+    /// boot selection cannot be changed.
+    fn boot_recovery(&self) -> impl Future<Output = Result<(), HalError>>;
+
+    /// Emit a pulse train on the platform's pulse output pin.
+    ///
+    /// # Errors
+    ///
+    /// Returns `HalError::Pulse` if the sequence is rejected or the
+    /// platform has no pulse output.
+    #[cfg(feature = "rmt")]
+    fn send_pulses(&self, pulses: &[Pulse]) -> impl Future<Output = Result<(), HalError>>;
+
+    /// Drive the external UART mux select lines to `select`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `HalError::Mux` if `select` does not fit the select lines or
+    /// the platform has no mux outputs.
+    #[cfg(feature = "mux")]
+    fn select_mux(&self, select: u8) -> impl Future<Output = Result<(), HalError>>;
+
+    /// Describe the chip, HAL and image for [`crate::version`], as
+    /// `key: value` lines ending in `\n`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `out` does.
+    fn write_version(&self, out: &mut dyn fmt::Write) -> fmt::Result;
+
+    /// Describe the chip and port for [`crate::version::write_json`].
+    fn build_info(&self) -> BuildInfo;
+}
+
+/// What a platform tells host tooling about itself, see
+/// [`PlatformServices::build_info`].
+#[derive(Debug, Clone, Copy)]
+pub struct BuildInfo {
+    /// Chip the image was built for, e.g. `esp32c6`.
+    pub chip: &'static str,
+    /// Port crate features enabled, e.g. `ble-provisioning`.
+    pub features: &'static [&'static str],
+    /// Port buffer sizes in bytes, by name, e.g. the UART pipes.
+    pub buffers: &'static [(&'static str, usize)],
 }
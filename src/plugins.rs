@@ -0,0 +1,142 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Hooks for code linked in by downstream forks (`plugins` feature).
+//!
+//! A fork that wants site-specific behavior, e.g. reacting to a line the
+//! target prints or speaking a proprietary protocol on session start,
+//! implements [`Plugin`] in its own crate and registers a `static` instance
+//! with [`SshConsole::plugin`](crate::app::SshConsole::plugin), or with
+//! [`register`] before calling [`run_app`](crate::app::run_app). No core
+//! module needs patching.
+//!
+//! Hooks are called synchronously from the task that noticed what happened:
+//! the bridge for [`Plugin::on_uart_line`], the SSH connection for the
+//! others. They must return quickly and must not block; a plugin with
+//! longer work signals its own task, e.g. through an
+//! [`embassy_sync::signal::Signal`]. At most [`PLUGIN_SLOTS`] plugins are
+//! registered, each hook runs them in registration order.
+
+use core::cell::RefCell;
+use core::mem;
+use core::net::IpAddr;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use heapless::Vec;
+use log::{info, warn};
+
+use crate::config::{Role, SessionBehavior};
+use crate::events::Event;
+use crate::settings::{PLUGIN_LINE_LEN, PLUGIN_SLOTS};
+
+/// What a new session is about.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionStart<'a> {
+    /// Username the client logged in with, empty if not known.
+    pub user: &'a str,
+    pub peer: Option<IpAddr>,
+    pub behavior: SessionBehavior,
+    pub role: Role,
+}
+
+/// Behavior added by a downstream fork. Every hook does nothing unless
+/// implemented.
+pub trait Plugin: Sync {
+    /// Name for the log and `show version`.
+    fn name(&self) -> &'static str;
+
+    /// A session was handed its channel: a bridge, monitor, admin shell or
+    /// `exec` command is about to run. Not called for SFTP uploads.
+    fn on_session_start(&self, _session: &SessionStart<'_>) {}
+
+    /// The target printed `line`, without its line ending, while a bridge or
+    /// monitor session was running. Lines longer than [`PLUGIN_LINE_LEN`]
+    /// are split.
+    fn on_uart_line(&self, _line: &[u8]) {}
+
+    /// `event` was recorded in [`crate::events`].
+    fn on_event(&self, _event: &Event) {}
+}
+
+type Plugins = Vec<&'static dyn Plugin, PLUGIN_SLOTS>;
+
+static PLUGINS: Mutex<CriticalSectionRawMutex, RefCell<Plugins>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Target output since the last line ending.
+static LINE: Mutex<CriticalSectionRawMutex, RefCell<Vec<u8, PLUGIN_LINE_LEN>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Adds `plugin` to the ones the hooks call.
+///
+/// # Errors
+///
+/// Returns `plugin` back if [`PLUGIN_SLOTS`] are taken already.
+pub fn register(plugin: &'static dyn Plugin) -> Result<(), &'static dyn Plugin> {
+    PLUGINS.lock(|plugins| plugins.borrow_mut().push(plugin))?;
+    info!("Plugin registered: {}", plugin.name());
+    Ok(())
+}
+
+/// Registers `plugin`, logging a warning if there is no room for it.
+pub fn register_or_warn(plugin: &'static dyn Plugin) {
+    if register(plugin).is_err() {
+        warn!(
+            "Plugin {} not registered, all {PLUGIN_SLOTS} slots taken",
+            plugin.name()
+        );
+    }
+}
+
+/// The registered plugins, copied out so hooks run without the lock held.
+fn registered() -> Plugins {
+    PLUGINS.lock(|plugins| plugins.borrow().clone())
+}
+
+/// Names of the registered plugins, in registration order.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    registered().into_iter().map(|plugin| plugin.name())
+}
+
+/// Calls [`Plugin::on_session_start`].
+pub fn session_started(session: &SessionStart<'_>) {
+    for plugin in registered() {
+        plugin.on_session_start(session);
+    }
+}
+
+/// Calls [`Plugin::on_event`].
+pub fn event(event: &Event) {
+    for plugin in registered() {
+        plugin.on_event(event);
+    }
+}
+
+/// Splits target output into lines for [`Plugin::on_uart_line`]. Called by
+/// the bridge with every chunk read from the UART.
+pub fn observe(bytes: &[u8]) {
+    let plugins = registered();
+    if plugins.is_empty() {
+        return;
+    }
+    let mut line = LINE.lock(|line| mem::take(&mut *line.borrow_mut()));
+    for &b in bytes {
+        if b == b'\n' || b == b'\r' {
+            if !line.is_empty() {
+                for plugin in &plugins {
+                    plugin.on_uart_line(&line);
+                }
+                line.clear();
+            }
+        } else if line.push(b).is_err() {
+            for plugin in &plugins {
+                plugin.on_uart_line(&line);
+            }
+            line.clear();
+            let _ = line.push(b);
+        }
+    }
+    LINE.lock(|saved| *saved.borrow_mut() = line);
+}
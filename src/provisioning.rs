@@ -0,0 +1,211 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Out-of-band `WiFi` provisioning and factory provisioning bundles.
+//!
+//! Transports other than SSH (ESP32: BLE, `ble-provisioning` feature) hand
+//! the credentials they received to [`set_station_credentials`]. The same
+//! validation as for the `SSH_STAMP_WIFI_STA_*` env vars applies, and only
+//! an unprovisioned device (`first_login`) accepts them: once a public key
+//! has been installed, `WiFi` settings can only be changed over SSH.
+//!
+//! For mass provisioning, an unprovisioned device also takes a bundle
+//! signed by the vendor, see [`apply_bundle`]: `SSH_STAMP_*` settings, one
+//! `KEY=value` per line as for `config set`, and `SSH_STAMP_PUBKEY` lines
+//! for the admin keys. An optional `DEVICE=<mac>` line ties the bundle to
+//! one unit. The last line is `SIGNATURE=` and the base64 Ed25519
+//! signature of every byte before it, made with the key whose public half
+//! was given as `SSH_STAMP_VENDOR_KEY` at build time. The whole bundle
+//! applies or nothing does.
+
+use core::fmt;
+use core::str::FromStr;
+
+use base64ct::{Base64, Encoding};
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::{info, warn};
+use ssh_key::PublicKey;
+use ssh_key::public::KeyData;
+use sunset_async::SunsetMutex;
+
+use crate::config::{ConfigProblem, SSHStampConfig};
+use crate::handle::env_parser;
+use crate::platform::PlatformServices;
+use crate::staging;
+
+/// OpenSSH public key of the vendor signing provisioning bundles, from
+/// `SSH_STAMP_VENDOR_KEY` at build time. Without it bundles are refused.
+pub const VENDOR_KEY: Option<&str> = option_env!("SSH_STAMP_VENDOR_KEY");
+
+/// Why provisioned credentials were not applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProvisioningError {
+    /// The device already has an admin key.
+    AlreadyProvisioned,
+    /// The SSID is empty, too long or not printable ASCII.
+    InvalidSsid,
+    /// The PSK is not 8-63 printable ASCII characters.
+    InvalidPsk,
+    /// Persisting the config failed.
+    Save,
+    /// This build has no vendor key to check bundles with.
+    NoVendorKey,
+    /// The bundle is not UTF-8 text ending in a `SIGNATURE=` line.
+    Malformed,
+    /// The signature does not match the bundle and the vendor key.
+    BadSignature,
+    /// The bundle is for another device.
+    WrongDevice,
+    /// A setting is unknown or its value invalid; carries the line number.
+    InvalidLine(usize),
+    /// The bundle installs no admin key.
+    NoAdminKey,
+    /// The settings fail [`SSHStampConfig::validate`].
+    Invalid,
+}
+
+impl fmt::Display for ProvisioningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProvisioningError::AlreadyProvisioned => write!(f, "device already provisioned"),
+            ProvisioningError::InvalidSsid => write!(f, "invalid SSID"),
+            ProvisioningError::InvalidPsk => write!(f, "invalid PSK"),
+            ProvisioningError::Save => write!(f, "could not save config"),
+            ProvisioningError::NoVendorKey => write!(f, "no vendor key in this build"),
+            ProvisioningError::Malformed => write!(f, "not a provisioning bundle"),
+            ProvisioningError::BadSignature => write!(f, "bad signature"),
+            ProvisioningError::WrongDevice => write!(f, "bundle is for another device"),
+            ProvisioningError::InvalidLine(n) => write!(f, "line {n}: invalid setting"),
+            ProvisioningError::NoAdminKey => write!(f, "bundle has no SSH_STAMP_PUBKEY"),
+            ProvisioningError::Invalid => write!(f, "settings fail validation"),
+        }
+    }
+}
+
+/// Whether out-of-band provisioning should be offered at all.
+pub async fn is_open(config: &SunsetMutex<SSHStampConfig>) -> bool {
+    config.lock().await.first_login
+}
+
+/// Stores station-mode credentials and persists the config.
+///
+/// The caller resets the device afterwards so the station connection is
+/// brought up.
+///
+/// # Errors
+/// See [`ProvisioningError`].
+pub async fn set_station_credentials<P: PlatformServices>(
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    ssid: &str,
+    psk: &str,
+) -> Result<(), ProvisioningError> {
+    let mut config_guard = config.lock().await;
+    if !config_guard.first_login {
+        warn!("Out-of-band provisioning refused, device already provisioned");
+        return Err(ProvisioningError::AlreadyProvisioned);
+    }
+    let ssid = env_parser::parse_wifi_station_ssid(ssid)
+        .filter(|s| !s.is_empty())
+        .ok_or(ProvisioningError::InvalidSsid)?;
+    let psk = env_parser::parse_wifi_psk(psk).ok_or(ProvisioningError::InvalidPsk)?;
+
+    config_guard.wifi_sta_ssid = ssid;
+    config_guard.wifi_sta_pw = psk;
+    platform
+        .save_config(&config_guard)
+        .await
+        .map_err(|_| ProvisioningError::Save)?;
+    info!("Station credentials provisioned out-of-band");
+    Ok(())
+}
+
+/// The vendor key from [`VENDOR_KEY`], if it is a valid Ed25519 key.
+fn vendor_key() -> Option<VerifyingKey> {
+    let key = PublicKey::from_str(VENDOR_KEY?).ok()?;
+    match key.key_data() {
+        KeyData::Ed25519(k) => VerifyingKey::from_bytes(&k.0).ok(),
+        _ => None,
+    }
+}
+
+/// Checks `bundle` against the vendor key and applies it to an
+/// unprovisioned device, then persists the config. Settings are applied to
+/// a copy, so a bundle with any bad line changes nothing.
+///
+/// The caller resets the device afterwards so the settings take effect.
+///
+/// # Errors
+/// See [`ProvisioningError`].
+pub async fn apply_bundle<P: PlatformServices>(
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    bundle: &[u8],
+) -> Result<(), ProvisioningError> {
+    let vendor = vendor_key().ok_or(ProvisioningError::NoVendorKey)?;
+    let text = core::str::from_utf8(bundle).map_err(|_| ProvisioningError::Malformed)?;
+    let split = text
+        .rfind("SIGNATURE=")
+        .filter(|&at| at == 0 || text.as_bytes()[at - 1] == b'\n')
+        .ok_or(ProvisioningError::Malformed)?;
+    let (body, signature) = text.split_at(split);
+    let signature = signature["SIGNATURE=".len()..].trim();
+    let mut sig = [0u8; 64];
+    let decoded = Base64::decode(signature, &mut sig).map_err(|_| ProvisioningError::Malformed)?;
+    if decoded.len() != sig.len() {
+        return Err(ProvisioningError::Malformed);
+    }
+    vendor
+        .verify_strict(body.as_bytes(), &Signature::from_bytes(&sig))
+        .map_err(|_| ProvisioningError::BadSignature)?;
+
+    let mut config_guard = config.lock().await;
+    if !config_guard.first_login {
+        warn!("Provisioning bundle refused, device already provisioned");
+        return Err(ProvisioningError::AlreadyProvisioned);
+    }
+    let mut provisioned = config_guard.try_clone().ok_or(ProvisioningError::Save)?;
+    provisioned.pubkeys = Default::default();
+    for (n, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = ProvisioningError::InvalidLine(n + 1);
+        let (key, value) = line.split_once('=').ok_or(invalid)?;
+        match key {
+            "DEVICE" => {
+                let mac = env_parser::parse_mac_address(value).ok_or(invalid)?;
+                if mac != config_guard.mac {
+                    return Err(ProvisioningError::WrongDevice);
+                }
+            }
+            "SSH_STAMP_PUBKEY" => {
+                let pubkey = env_parser::parse_pubkey(value).ok_or(invalid)?;
+                provisioned.add_pubkey(pubkey).map_err(|_| invalid)?;
+            }
+            _ => {
+                staging::apply_key(&mut provisioned, key, value).ok_or(invalid)?;
+            }
+        }
+    }
+    if provisioned.pubkeys.iter().all(Option::is_none) {
+        return Err(ProvisioningError::NoAdminKey);
+    }
+    provisioned.first_login = false;
+    if provisioned
+        .validate(None)
+        .iter()
+        .any(ConfigProblem::is_fatal)
+    {
+        return Err(ProvisioningError::Invalid);
+    }
+    platform
+        .save_config(&provisioned)
+        .await
+        .map_err(|_| ProvisioningError::Save)?;
+    *config_guard = provisioned;
+    info!("Device provisioned from a signed bundle");
+    Ok(())
+}
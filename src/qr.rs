@@ -0,0 +1,98 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Provisioning QR code for `show qr`.
+//!
+//! The code carries the access point credentials in the `WIFI:` format that
+//! phone cameras understand, so joining the stamp's network is a scan away.
+//! The format has no room for anything else; the host key fingerprint to
+//! check on first connect is printed next to it.
+//!
+//! Terminals draw the code with half block characters, two module rows per
+//! line, light modules in the foreground colour: it scans on the usual dark
+//! background.
+
+use core::fmt::Write as _;
+
+use heapless::String;
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
+
+/// Largest symbol version used, 57 by 57 modules. At medium error
+/// correction it holds the longest escaped SSID and PSK.
+const MAX_VERSION: u8 = 10;
+/// Buffer size [`encode`] needs, twice.
+pub const QR_BUFFER_LEN: usize = Version::new(MAX_VERSION).buffer_len();
+
+/// `WIFI:T:WPA;S:;P:;;` with a 32 byte SSID and a 63 byte PSK, every
+/// character escaped.
+pub const WIFI_PAYLOAD_LEN: usize = 18 + 2 * 32 + 2 * 63;
+
+/// Light margin around the code, in modules. The standard asks for 4;
+/// the terminal background makes up the difference.
+const QUIET_ZONE: i32 = 2;
+/// One rendered line of the largest symbol with its quiet zone, three
+/// bytes per character.
+pub const QR_LINE_LEN: usize = 3 * (57 + 2 * 2);
+
+/// Join payload for a WPA2 network.
+#[must_use]
+pub fn wifi_payload(ssid: &str, psk: &str) -> String<WIFI_PAYLOAD_LEN> {
+    let mut payload = String::new();
+    let _ = payload.push_str("WIFI:T:WPA;S:");
+    push_escaped(&mut payload, ssid);
+    let _ = payload.push_str(";P:");
+    push_escaped(&mut payload, psk);
+    let _ = payload.push_str(";;");
+    payload
+}
+
+fn push_escaped(payload: &mut String<WIFI_PAYLOAD_LEN>, value: &str) {
+    for c in value.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            let _ = payload.push('\\');
+        }
+        let _ = payload.push(c);
+    }
+}
+
+/// Encodes `text`, or `None` if it does not fit [`MAX_VERSION`].
+pub fn encode<'a>(
+    text: &str,
+    temp: &mut [u8; QR_BUFFER_LEN],
+    out: &'a mut [u8; QR_BUFFER_LEN],
+) -> Option<QrCode<'a>> {
+    QrCode::encode_text(
+        text,
+        temp,
+        out,
+        QrCodeEcc::Medium,
+        Version::MIN,
+        Version::new(MAX_VERSION),
+        None,
+        true,
+    )
+    .ok()
+}
+
+/// The code as lines of text, top to bottom.
+pub fn lines<'q>(code: &'q QrCode<'_>) -> impl Iterator<Item = String<QR_LINE_LEN>> + 'q {
+    let span = code.size() + 2 * QUIET_ZONE;
+    (0..(span + 1) / 2).map(move |row| {
+        let top = 2 * row - QUIET_ZONE;
+        let mut line = String::new();
+        for x in -QUIET_ZONE..code.size() + QUIET_ZONE {
+            // Modules outside the symbol read as light: the quiet zone.
+            let light = |y: i32| !code.get_module(x, y);
+            let bottom_inside = top + 1 < code.size() + QUIET_ZONE;
+            let c = match (light(top), bottom_inside && light(top + 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            };
+            let _ = line.write_char(c);
+        }
+        line
+    })
+}
@@ -0,0 +1,194 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Plain TCP access to the bridge (`raw-tcp` feature), like ser2net's raw
+//! mode.
+//!
+//! Flash tools and other clients that speak raw sockets but not SSH can
+//! reach the target directly: when `SSH_STAMP_RAW_TCP` names a port,
+//! [`serve`] listens on it on the primary stack and copies bytes between one
+//! client at a time and the UART. There is no encryption and no login, so
+//! only turn it on for trusted networks; the access lists and the lockout
+//! apply as for SSH. Bytes pass unchanged both ways: no telnet option
+//! negotiation, no `ssh-stamp:` notices, no idle lock, and input waits for
+//! the target rather than being dropped. The interlock holds input as on
+//! the SSH bridge, see [`serial::hold_input`].
+//!
+//! SSH takes precedence. No raw client is accepted while an SSH connection
+//! is up, and one on the bridge is disconnected when an SSH connection
+//! arrives, so the UART never has two writers and the admin is never locked
+//! out by a forgotten raw client.
+//!
+//! The listener's socket buffers, about 3 KiB of RAM, are taken from a
+//! static when it starts.
+
+use core::convert::Infallible;
+use core::net::IpAddr;
+
+use embassy_futures::select::{Either, select};
+use embassy_net::tcp::{self, TcpReader, TcpSocket, TcpWriter};
+use embassy_net::{IpListenEndpoint, Stack};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embedded_io_async::{Read, Write};
+use log::{debug, info, warn};
+use static_cell::ConstStaticCell;
+use sunset_async::SunsetMutex;
+
+use crate::config::SSHStampConfig;
+use crate::console;
+use crate::events::{self, Event};
+use crate::lockout;
+use crate::logging;
+use crate::serial::{self, BufferedSerial};
+use crate::settings::{BRIDGE_TO_SSH_CHUNK, BRIDGE_TO_UART_CHUNK, TCP_BUFFER_SIZE};
+use crate::stats;
+
+/// The SSH connection's state, as last reported by [`main_connection`].
+static MAIN: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Socket buffers of the listener.
+struct RawBuffers {
+    tcp_rx: [u8; TCP_BUFFER_SIZE],
+    tcp_tx: [u8; TCP_BUFFER_SIZE],
+}
+
+static RAW_BUFFERS: ConstStaticCell<RawBuffers> = ConstStaticCell::new(RawBuffers {
+    tcp_rx: [0; TCP_BUFFER_SIZE],
+    tcp_tx: [0; TCP_BUFFER_SIZE],
+});
+
+/// RAM held by the listener's socket buffers.
+pub const RAW_STATIC_LEN: usize = size_of::<RawBuffers>();
+
+/// Reports whether an SSH connection is up. Raw clients are only accepted
+/// while none is.
+pub fn main_connection(up: bool) {
+    MAIN.signal(up);
+}
+
+/// Bridges raw TCP clients on the configured port of `stack` to `uart`,
+/// see the module docs. Never returns; does nothing if the port is 0 or
+/// `ssh_port`.
+///
+/// # Panics
+///
+/// Panics if called more than once: the listener's buffers are taken here.
+pub async fn serve<U: BufferedSerial>(
+    stack: Stack<'static>,
+    ssh_port: u16,
+    uart: &U,
+    config: &SunsetMutex<SSHStampConfig>,
+) -> Infallible {
+    let port = config.lock().await.raw_tcp_port;
+    if port == 0 {
+        return core::future::pending().await;
+    }
+    if port == ssh_port {
+        warn!("Raw TCP port {port} is the SSH port, not listening");
+        return core::future::pending().await;
+    }
+    info!("Raw TCP bridge on port {port}");
+    let buffers = RAW_BUFFERS.take();
+    let endpoint = IpListenEndpoint { addr: None, port };
+    let mut ssh_up = false;
+    loop {
+        while ssh_up {
+            ssh_up = MAIN.wait().await;
+        }
+        let mut socket = TcpSocket::new(stack, &mut buffers.tcp_rx, &mut buffers.tcp_tx);
+        match select(socket.accept(endpoint), MAIN.wait()).await {
+            Either::First(Ok(())) => (),
+            Either::First(Err(e)) => {
+                debug!("Raw TCP accept error: {e:?}");
+                continue;
+            }
+            Either::Second(up) => {
+                ssh_up = up;
+                continue;
+            }
+        }
+        let peer = socket.remote_endpoint().map(|e| IpAddr::from(e.addr));
+        if let Some(peer) = peer {
+            if !config.lock().await.access_lists.permits(peer) {
+                warn!("Rejecting raw TCP client {peer}: not permitted by access lists");
+                events::emit(Event::ConnectionRejected { peer });
+                socket.abort();
+                let _ = socket.flush().await;
+                continue;
+            }
+            if lockout::is_blocked(peer) {
+                debug!("Rejecting raw TCP client {peer}: too many failures");
+                socket.abort();
+                let _ = socket.flush().await;
+                continue;
+            }
+        }
+        info!("Raw TCP client {peer:?} on the bridge");
+        stats::session_started();
+        socket.set_nagle_enabled(!config.lock().await.tcp_nodelay);
+        {
+            let (rsock, wsock) = socket.split();
+            match select(bridge(rsock, wsock, uart), MAIN.wait()).await {
+                Either::First(r) => debug!("Raw TCP client left: {r:?}"),
+                Either::Second(up) => {
+                    ssh_up = up;
+                    if up {
+                        info!("SSH connection arrived, dropping the raw TCP client");
+                    }
+                }
+            }
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+        serial::end_connection(uart).await;
+    }
+}
+
+/// Copies bytes between the client and `uart` until either side fails or
+/// the client closes its end.
+async fn bridge<U: BufferedSerial>(
+    rsock: TcpReader<'_>,
+    wsock: TcpWriter<'_>,
+    uart: &U,
+) -> Result<(), tcp::Error> {
+    match select(uart_to_client(uart, wsock), client_to_uart(rsock, uart)).await {
+        Either::First(r) | Either::Second(r) => r,
+    }
+}
+
+async fn uart_to_client<U: BufferedSerial>(
+    uart: &U,
+    mut wsock: TcpWriter<'_>,
+) -> Result<(), tcp::Error> {
+    let mut uart_rx = uart.handle();
+    let mut buf = [0u8; BRIDGE_TO_SSH_CHUNK];
+    loop {
+        let dropped = uart.check_dropped_bytes();
+        if dropped > 0 {
+            warn!("UART RX dropped {dropped} bytes");
+            stats::record_rx_dropped(dropped);
+        }
+        let Ok(n) = uart_rx.read(&mut buf).await;
+        console::observe(&buf[..n]);
+        logging::mirror(&buf[..n]);
+        wsock.write_all(&buf[..n]).await?;
+    }
+}
+
+async fn client_to_uart<U: BufferedSerial>(
+    mut rsock: TcpReader<'_>,
+    uart: &U,
+) -> Result<(), tcp::Error> {
+    let mut uart_tx = uart.handle();
+    let mut buf = [0u8; BRIDGE_TO_UART_CHUNK];
+    loop {
+        let n = rsock.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        serial::input_released().await;
+        let Ok(()) = uart_tx.write_all(&buf[..n]).await;
+    }
+}
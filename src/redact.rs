@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Masking configured byte patterns in copies of the target's output.
+//!
+//! A target CLI may echo secrets, e.g. a password typed at a prompt that
+//! leaves echo on. With `SSH_STAMP_UART_REDACT` set, each occurrence of a
+//! configured pattern is replaced by as many `*` in the copies of bridge
+//! output the firmware passes on: the lines handed to `plugins`, what
+//! `observers` are shown and the output kept of `scripts` runs. The bridge itself, and so the operator's
+//! terminal, gets the output unchanged. Recordings built from those copies
+//! can then be shared, e.g. with a vendor, without the credentials in them.
+//!
+//! Matching is exact and case sensitive, and patterns are at least
+//! [`UART_REDACT_MIN_LEN`] bytes long. Output ending in the start of a
+//! pattern is held back until the next read shows whether it continues, so
+//! a pattern split across reads is still caught; what is held when the
+//! bridge ends is dropped. This is distinct from the log redaction of
+//! `SSH_STAMP_LOG_REDACT`, see [`crate::logging::Redacted`].
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use heapless::Vec;
+
+use crate::config::RedactPatterns;
+use crate::settings::UART_REDACT_LEN;
+#[cfg(doc)]
+use crate::settings::UART_REDACT_MIN_LEN;
+
+static PATTERNS: Mutex<CriticalSectionRawMutex, RefCell<RedactPatterns>> =
+    Mutex::new(RefCell::new(RedactPatterns::new()));
+
+/// Sets the patterns to mask, taking effect with the next read.
+pub fn set_patterns(patterns: &RedactPatterns) {
+    PATTERNS.lock(|current| current.borrow_mut().clone_from(patterns));
+}
+
+/// Masks the configured patterns in one stream of output, holding back a
+/// possible start of one between calls.
+pub struct Masker {
+    held: Vec<u8, UART_REDACT_LEN>,
+}
+
+impl Masker {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { held: Vec::new() }
+    }
+
+    /// Copies what was held back and then `bytes` into `out`, masks the
+    /// patterns and returns the part of `out` that is ready to pass on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `out` is shorter than `bytes` plus [`UART_REDACT_LEN`].
+    pub fn mask<'a>(&mut self, bytes: &[u8], out: &'a mut [u8]) -> &'a [u8] {
+        let patterns = PATTERNS.lock(|patterns| patterns.borrow().clone());
+        let total = self.held.len() + bytes.len();
+        let out = &mut out[..total];
+        let (held, rest) = out.split_at_mut(self.held.len());
+        held.copy_from_slice(&self.held);
+        rest.copy_from_slice(bytes);
+        self.held.clear();
+
+        for pattern in &patterns {
+            let pattern = pattern.as_bytes();
+            let mut i = 0;
+            while i + pattern.len() <= total {
+                if out[i..].starts_with(pattern) {
+                    out[i..i + pattern.len()].fill(b'*');
+                    i += pattern.len();
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        // The longest tail that could still grow into a pattern.
+        let keep = (1..UART_REDACT_LEN.min(total + 1))
+            .rev()
+            .find(|&n| {
+                patterns
+                    .iter()
+                    .any(|p| p.len() > n && p.as_bytes().starts_with(&out[total - n..]))
+            })
+            .unwrap_or(0);
+        // Shorter than the longest pattern, so it fits.
+        let _ = self.held.extend_from_slice(&out[total - keep..]);
+        &out[..total - keep]
+    }
+}
+
+impl Default for Masker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
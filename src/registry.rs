@@ -0,0 +1,784 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Admin command registry.
+//!
+//! Every admin command is one [`Command`] in [`COMMANDS`]: the words that
+//! name it, its parameters, the least [`Role`] that may run it and the
+//! [`Handler`] that [`crate::commands`] runs. Lines are matched against the
+//! table in one place, [`parse`], so every command rejects wrong arguments
+//! the same way and `help` is generated from the same entries. Parsing needs
+//! neither the platform nor an executor.
+//!
+//! A subsystem adds its commands by adding entries here, gated on the same
+//! feature as its code, and a [`Handler`] arm in [`crate::commands`].
+
+use core::fmt;
+use core::str::{FromStr, SplitAsciiWhitespace};
+
+use crate::config::Role;
+
+/// A parameter following a command's name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Param {
+    /// One word, which must be given.
+    Required(&'static str),
+    /// One word, which may be left out. Only followed by other optional
+    /// parameters.
+    Optional(&'static str),
+    /// One or more words; always last.
+    Repeated(&'static str),
+}
+
+/// What [`crate::commands`] runs for a [`Command`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    BenchmarkSsh,
+    BenchmarkUart,
+    ConfigAbort,
+    ConfigBackup,
+    ConfigCommit,
+    ConfigConfirm,
+    ConfigList,
+    ConfigRestore,
+    ConfigSet,
+    ConfigValidate,
+    Console,
+    ConsoleBreak,
+    Help,
+    Interlock,
+    InterlockOff,
+    InterlockOn,
+    LogVerbose,
+    ManifestCheck,
+    OtaConfirm,
+    OutboxClear,
+    Ping,
+    Provision,
+    #[cfg(feature = "rmt")]
+    Pulse,
+    Reboot,
+    #[cfg(feature = "sftp-ota")]
+    ScratchDelete,
+    #[cfg(feature = "sftp-ota")]
+    ScratchList,
+    #[cfg(feature = "scripts")]
+    ScriptDelete,
+    #[cfg(feature = "scripts")]
+    ScriptList,
+    #[cfg(feature = "scripts")]
+    ScriptResult,
+    #[cfg(feature = "scripts")]
+    ScriptRun,
+    #[cfg(feature = "scripts")]
+    ScriptSet,
+    Send,
+    ShowBuild,
+    ShowClients,
+    ShowEvents,
+    ShowFlash,
+    ShowLog,
+    ShowOta,
+    ShowOtaHistory,
+    ShowOutbox,
+    #[cfg(feature = "peers")]
+    ShowPeers,
+    ShowQr,
+    ShowResets,
+    ShowSessions,
+    ShowStats,
+    ShowUart,
+    ShowVersion,
+    Shutdown,
+    #[cfg(feature = "mux")]
+    Target,
+    #[cfg(feature = "test-services")]
+    TestChargen,
+    #[cfg(feature = "test-services")]
+    TestEcho,
+}
+
+/// An admin command.
+#[derive(Debug)]
+pub struct Command {
+    /// The words that select it, separated by single spaces. The first is
+    /// the command family `help <command>` lists.
+    pub name: &'static str,
+    pub params: &'static [Param],
+    /// The least role that may run it.
+    pub role: Role,
+    pub summary: &'static str,
+    pub handler: Handler,
+}
+
+impl Command {
+    /// The first word of [`name`](Self::name).
+    #[must_use]
+    pub fn family(&self) -> &'static str {
+        self.name.split(' ').next().unwrap_or(self.name)
+    }
+
+    /// The syntax, e.g. `config set <key> [<value>]`.
+    #[must_use]
+    pub fn usage(&self) -> Usage<'_> {
+        Usage(self)
+    }
+}
+
+/// See [`Command::usage`].
+#[derive(Debug)]
+pub struct Usage<'a>(&'a Command);
+
+impl fmt::Display for Usage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.name)?;
+        for param in self.0.params {
+            match param {
+                Param::Required(name) => write!(f, " <{name}>")?,
+                Param::Optional(name) => write!(f, " [<{name}>]")?,
+                Param::Repeated(name) => write!(f, " <{name}> [<{name}> ...]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+const fn cmd(
+    name: &'static str,
+    params: &'static [Param],
+    role: Role,
+    summary: &'static str,
+    handler: Handler,
+) -> Command {
+    Command {
+        name,
+        params,
+        role,
+        summary,
+        handler,
+    }
+}
+
+const NONE: &[Param] = &[];
+
+/// Version of the admin command set, reported by `show build` so host
+/// tooling can adapt. Bumped when a command is removed or renamed, or its
+/// output changes in a way a script parsing it would notice.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The commands compiled into this build, in `help` order.
+pub const COMMANDS: &[Command] = &[
+    cmd(
+        "benchmark ssh",
+        &[Param::Optional("seconds")],
+        Role::Admin,
+        "measure the link and SSH encryption without the UART",
+        Handler::BenchmarkSsh,
+    ),
+    cmd(
+        "benchmark uart",
+        &[Param::Optional("seconds")],
+        Role::Admin,
+        "push a test pattern through the UART, TX wired to RX",
+        Handler::BenchmarkUart,
+    ),
+    cmd(
+        "config",
+        NONE,
+        Role::Admin,
+        "list the staged settings",
+        Handler::ConfigList,
+    ),
+    cmd(
+        "config set",
+        &[Param::Required("key"), Param::Optional("value")],
+        Role::Admin,
+        "stage a setting, named as its SSH_STAMP_* variable",
+        Handler::ConfigSet,
+    ),
+    cmd(
+        "config validate",
+        NONE,
+        Role::Admin,
+        "check the staged settings as a whole",
+        Handler::ConfigValidate,
+    ),
+    cmd(
+        "config commit",
+        &[Param::Optional("minutes")],
+        Role::Admin,
+        "save and apply the staged settings",
+        Handler::ConfigCommit,
+    ),
+    cmd(
+        "config confirm",
+        NONE,
+        Role::Admin,
+        "keep committed network settings",
+        Handler::ConfigConfirm,
+    ),
+    cmd(
+        "config abort",
+        NONE,
+        Role::Admin,
+        "discard the staged settings",
+        Handler::ConfigAbort,
+    ),
+    cmd(
+        "config backup",
+        NONE,
+        Role::Admin,
+        "print the whole config, base64 encoded",
+        Handler::ConfigBackup,
+    ),
+    cmd(
+        "config restore",
+        NONE,
+        Role::Admin,
+        "read a backup from standard input and reset into it",
+        Handler::ConfigRestore,
+    ),
+    cmd(
+        "console",
+        NONE,
+        Role::Viewer,
+        "the target console guessed from its output",
+        Handler::Console,
+    ),
+    cmd(
+        "console break",
+        NONE,
+        Role::Operator,
+        "send the guessed console's break sequence to the UART",
+        Handler::ConsoleBreak,
+    ),
+    cmd(
+        "help",
+        &[Param::Optional("command")],
+        Role::Viewer,
+        "list the commands of this build",
+        Handler::Help,
+    ),
+    cmd(
+        "interlock",
+        NONE,
+        Role::Viewer,
+        "whether bridge input is held during a critical section",
+        Handler::Interlock,
+    ),
+    cmd(
+        "interlock on",
+        &[Param::Optional("minutes")],
+        Role::Operator,
+        "hold bridge input while the target flashes",
+        Handler::InterlockOn,
+    ),
+    cmd(
+        "interlock off",
+        NONE,
+        Role::Operator,
+        "deliver held bridge input again",
+        Handler::InterlockOff,
+    ),
+    cmd(
+        "log verbose",
+        &[Param::Optional("minutes")],
+        Role::Admin,
+        "show secrets in the log for a while despite SSH_STAMP_LOG_REDACT",
+        Handler::LogVerbose,
+    ),
+    cmd(
+        "manifest check",
+        NONE,
+        Role::Viewer,
+        "compare a build manifest from standard input with this firmware",
+        Handler::ManifestCheck,
+    ),
+    cmd(
+        "ota confirm",
+        NONE,
+        Role::Admin,
+        "keep firmware installed over OTA",
+        Handler::OtaConfirm,
+    ),
+    cmd(
+        "outbox clear",
+        NONE,
+        Role::Admin,
+        "drop the events kept in flash, once read",
+        Handler::OutboxClear,
+    ),
+    cmd(
+        "ping",
+        NONE,
+        Role::Viewer,
+        "answer at once with a counter and the uptime, as a liveness probe",
+        Handler::Ping,
+    ),
+    cmd(
+        "provision",
+        NONE,
+        Role::Admin,
+        "read a vendor-signed bundle from standard input, first boot only",
+        Handler::Provision,
+    ),
+    #[cfg(feature = "rmt")]
+    cmd(
+        "pulse",
+        &[Param::Required("high|low"), Param::Repeated("us")],
+        Role::Admin,
+        "send a pulse train on the pulse output",
+        Handler::Pulse,
+    ),
+    cmd(
+        "reboot",
+        NONE,
+        Role::Admin,
+        "let the UART drain and flash writes finish, then reset",
+        Handler::Reboot,
+    ),
+    #[cfg(feature = "sftp-ota")]
+    cmd(
+        "scratch delete",
+        &[Param::Required("name")],
+        Role::Admin,
+        "remove a file from the scratch area",
+        Handler::ScratchDelete,
+    ),
+    #[cfg(feature = "sftp-ota")]
+    cmd(
+        "scratch list",
+        NONE,
+        Role::Viewer,
+        "files in the scratch area and the space left",
+        Handler::ScratchList,
+    ),
+    #[cfg(feature = "scripts")]
+    cmd(
+        "script delete",
+        &[Param::Required("name")],
+        Role::Admin,
+        "remove a stored script",
+        Handler::ScriptDelete,
+    ),
+    #[cfg(feature = "scripts")]
+    cmd(
+        "script list",
+        NONE,
+        Role::Viewer,
+        "stored scripts and their size",
+        Handler::ScriptList,
+    ),
+    #[cfg(feature = "scripts")]
+    cmd(
+        "script result",
+        NONE,
+        Role::Viewer,
+        "outcome and target output of the last run",
+        Handler::ScriptResult,
+    ),
+    #[cfg(feature = "scripts")]
+    cmd(
+        "script run",
+        &[Param::Required("name")],
+        Role::Operator,
+        "run a stored script against the UART",
+        Handler::ScriptRun,
+    ),
+    #[cfg(feature = "scripts")]
+    cmd(
+        "script set",
+        &[Param::Required("name")],
+        Role::Admin,
+        "store a script read from standard input",
+        Handler::ScriptSet,
+    ),
+    cmd(
+        "send",
+        &[Param::Required("ms"), Param::Repeated("text")],
+        Role::Operator,
+        "write text to the UART, then print its output for <ms>",
+        Handler::Send,
+    ),
+    cmd(
+        "show build",
+        NONE,
+        Role::Viewer,
+        "features, buffer sizes and protocol versions as JSON",
+        Handler::ShowBuild,
+    ),
+    cmd(
+        "show clients",
+        NONE,
+        Role::Viewer,
+        "clients that logged in",
+        Handler::ShowClients,
+    ),
+    cmd(
+        "show events",
+        NONE,
+        Role::Viewer,
+        "noteworthy events since boot",
+        Handler::ShowEvents,
+    ),
+    cmd(
+        "show flash",
+        NONE,
+        Role::Viewer,
+        "config sector wear",
+        Handler::ShowFlash,
+    ),
+    cmd(
+        "show log",
+        NONE,
+        Role::Viewer,
+        "recent log output",
+        Handler::ShowLog,
+    ),
+    cmd(
+        "show ota",
+        NONE,
+        Role::Viewer,
+        "firmware confirmation state",
+        Handler::ShowOta,
+    ),
+    cmd(
+        "show ota history",
+        NONE,
+        Role::Viewer,
+        "recent firmware uploads and their outcome",
+        Handler::ShowOtaHistory,
+    ),
+    cmd(
+        "show outbox",
+        NONE,
+        Role::Viewer,
+        "fault resets, OTA results and other events kept in flash",
+        Handler::ShowOutbox,
+    ),
+    #[cfg(feature = "peers")]
+    cmd(
+        "show peers",
+        NONE,
+        Role::Viewer,
+        "other stamps found over mDNS",
+        Handler::ShowPeers,
+    ),
+    cmd(
+        "show qr",
+        NONE,
+        Role::Admin,
+        "code to join the access point, host key fingerprint",
+        Handler::ShowQr,
+    ),
+    cmd(
+        "show resets",
+        NONE,
+        Role::Viewer,
+        "reset causes",
+        Handler::ShowResets,
+    ),
+    cmd(
+        "show sessions",
+        NONE,
+        Role::Viewer,
+        "current and recent SSH sessions",
+        Handler::ShowSessions,
+    ),
+    cmd(
+        "show stats",
+        NONE,
+        Role::Viewer,
+        "bytes and errors the bridge lost, this session and since boot",
+        Handler::ShowStats,
+    ),
+    cmd(
+        "show uart",
+        NONE,
+        Role::Viewer,
+        "UART settings and receive errors",
+        Handler::ShowUart,
+    ),
+    cmd(
+        "show version",
+        NONE,
+        Role::Viewer,
+        "firmware, library and build details",
+        Handler::ShowVersion,
+    ),
+    cmd(
+        "shutdown",
+        NONE,
+        Role::Admin,
+        "like reboot, but power off",
+        Handler::Shutdown,
+    ),
+    #[cfg(feature = "mux")]
+    cmd(
+        "target",
+        &[Param::Optional("name")],
+        Role::Admin,
+        "list the UART mux targets, or select one",
+        Handler::Target,
+    ),
+    #[cfg(feature = "test-services")]
+    cmd(
+        "test chargen",
+        &[Param::Required("kib")],
+        Role::Admin,
+        "send KiB of test pattern and nothing else, for pv and the like",
+        Handler::TestChargen,
+    ),
+    #[cfg(feature = "test-services")]
+    cmd(
+        "test echo",
+        NONE,
+        Role::Admin,
+        "send back what the client sends until its input ends",
+        Handler::TestEcho,
+    ),
+];
+
+/// Why a line does not name a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// No command family by that name in this build.
+    Unknown,
+    /// Wrong arguments for the family with this name.
+    Usage(&'static str),
+}
+
+/// A line matched to a [`Command`], see [`parse`].
+#[derive(Debug, Clone)]
+pub struct Parsed<'a> {
+    pub command: &'static Command,
+    pub args: Args<'a>,
+}
+
+/// The words of a line after the command's name, checked against its
+/// [`Param`]s.
+#[derive(Debug, Clone)]
+pub struct Args<'a> {
+    words: SplitAsciiWhitespace<'a>,
+    family: &'static str,
+}
+
+impl<'a> Args<'a> {
+    /// The `n`th argument, `None` if an optional one was left out.
+    #[must_use]
+    pub fn get(&self, n: usize) -> Option<&'a str> {
+        self.words.clone().nth(n)
+    }
+
+    /// The arguments from the `n`th on.
+    #[must_use]
+    pub fn rest(&self, n: usize) -> core::iter::Skip<SplitAsciiWhitespace<'a>> {
+        self.words.clone().skip(n)
+    }
+
+    /// The `n`th argument as a number, `None` if left out.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ParseError::Usage`] if it is not a number.
+    pub fn number<T: FromStr>(&self, n: usize) -> Result<Option<T>, ParseError> {
+        self.get(n)
+            .map(|word| word.parse().map_err(|_| self.usage()))
+            .transpose()
+    }
+
+    /// The error for an argument the command rejects.
+    #[must_use]
+    pub fn usage(&self) -> ParseError {
+        ParseError::Usage(self.family)
+    }
+}
+
+/// Matches `line` to the entry of [`COMMANDS`] with the longest name it
+/// starts with, and checks the remaining words against its parameters.
+///
+/// # Errors
+///
+/// Returns [`ParseError::Unknown`] if no entry's family matches, and
+/// [`ParseError::Usage`] if the words after it fit no entry.
+pub fn parse(line: &str) -> Result<Parsed<'_>, ParseError> {
+    let family = line.split_ascii_whitespace().next();
+    let Some(family) = COMMANDS
+        .iter()
+        .map(Command::family)
+        .find(|f| Some(*f) == family)
+    else {
+        return Err(ParseError::Unknown);
+    };
+    COMMANDS
+        .iter()
+        .filter_map(|command| {
+            let mut words = line.split_ascii_whitespace();
+            command
+                .name
+                .split(' ')
+                .all(|name| words.next() == Some(name))
+                .then_some((command, words))
+        })
+        .max_by_key(|(command, _)| command.name.len())
+        .filter(|(command, words)| fits(command.params, words.clone().count()))
+        .map(|(command, words)| Parsed {
+            command,
+            args: Args { words, family },
+        })
+        .ok_or(ParseError::Usage(family))
+}
+
+/// Whether `count` arguments fit `params`.
+fn fits(params: &[Param], count: usize) -> bool {
+    let required = params
+        .iter()
+        .filter(|p| !matches!(p, Param::Optional(_)))
+        .count();
+    let repeated = params.iter().any(|p| matches!(p, Param::Repeated(_)));
+    count >= required && (repeated || count <= params.len())
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use super::*;
+
+    fn handler(line: &str) -> Result<Handler, ParseError> {
+        parse(line).map(|parsed| parsed.command.handler)
+    }
+
+    #[test]
+    fn unknown_family() {
+        assert_eq!(handler("frobnicate").unwrap_err(), ParseError::Unknown);
+        assert_eq!(handler("").unwrap_err(), ParseError::Unknown);
+        assert_eq!(handler("   ").unwrap_err(), ParseError::Unknown);
+        // Only whole words name a family.
+        assert_eq!(handler("shows flash").unwrap_err(), ParseError::Unknown);
+    }
+
+    #[test]
+    fn multi_word_names_take_the_longest_match() {
+        assert_eq!(handler("config"), Ok(Handler::ConfigList));
+        assert_eq!(handler("config commit"), Ok(Handler::ConfigCommit));
+        assert_eq!(handler("show ota"), Ok(Handler::ShowOta));
+        assert_eq!(handler("show ota history"), Ok(Handler::ShowOtaHistory));
+        assert_eq!(handler("  show \t flash  "), Ok(Handler::ShowFlash));
+    }
+
+    #[test]
+    fn unknown_subcommand_is_a_usage_error() {
+        assert_eq!(handler("show"), Err(ParseError::Usage("show")));
+        assert_eq!(handler("show nothing"), Err(ParseError::Usage("show")));
+        // Falls back to `config`, which takes no arguments.
+        assert_eq!(handler("config bogus"), Err(ParseError::Usage("config")));
+    }
+
+    #[test]
+    fn required_and_optional_params() {
+        assert_eq!(handler("config set"), Err(ParseError::Usage("config")));
+        let parsed = parse("config set SSH_STAMP_LOG_REDACT").unwrap();
+        assert_eq!(parsed.command.handler, Handler::ConfigSet);
+        assert_eq!(parsed.args.get(0), Some("SSH_STAMP_LOG_REDACT"));
+        assert_eq!(parsed.args.get(1), None);
+        let parsed = parse("config set SSH_STAMP_LOG_REDACT true").unwrap();
+        assert_eq!(parsed.args.get(1), Some("true"));
+        assert_eq!(
+            handler("config set SSH_STAMP_LOG_REDACT true false"),
+            Err(ParseError::Usage("config"))
+        );
+    }
+
+    #[test]
+    fn no_params() {
+        assert_eq!(handler("reboot"), Ok(Handler::Reboot));
+        assert_eq!(handler("reboot now"), Err(ParseError::Usage("reboot")));
+    }
+
+    #[test]
+    fn repeated_params() {
+        assert_eq!(handler("send"), Err(ParseError::Usage("send")));
+        assert_eq!(handler("send 100"), Err(ParseError::Usage("send")));
+        let parsed = parse("send 100 reset  now").unwrap();
+        assert_eq!(parsed.command.handler, Handler::Send);
+        assert_eq!(parsed.args.get(0), Some("100"));
+        let mut rest = parsed.args.rest(1);
+        assert_eq!(rest.next(), Some("reset"));
+        assert_eq!(rest.next(), Some("now"));
+        assert_eq!(rest.next(), None);
+    }
+
+    #[test]
+    fn numbers() {
+        let args = parse("interlock on 5").unwrap().args;
+        assert_eq!(args.number::<u64>(0), Ok(Some(5)));
+        assert_eq!(args.number::<u64>(1), Ok(None));
+        let args = parse("interlock on").unwrap().args;
+        assert_eq!(args.number::<u64>(0), Ok(None));
+        let args = parse("interlock on soon").unwrap().args;
+        assert_eq!(args.number::<u64>(0), Err(ParseError::Usage("interlock")));
+        let args = parse("interlock on -1").unwrap().args;
+        assert_eq!(args.number::<u64>(0), Err(ParseError::Usage("interlock")));
+    }
+
+    #[test]
+    fn fits_counts() {
+        let required = &[Param::Required("a")];
+        assert!(!fits(required, 0));
+        assert!(fits(required, 1));
+        assert!(!fits(required, 2));
+
+        let optional = &[Param::Required("a"), Param::Optional("b")];
+        assert!(!fits(optional, 0));
+        assert!(fits(optional, 1));
+        assert!(fits(optional, 2));
+        assert!(!fits(optional, 3));
+
+        let repeated = &[Param::Required("a"), Param::Repeated("b")];
+        assert!(!fits(repeated, 1));
+        assert!(fits(repeated, 2));
+        assert!(fits(repeated, 20));
+
+        assert!(fits(NONE, 0));
+        assert!(!fits(NONE, 1));
+    }
+
+    #[test]
+    fn roles() {
+        let role = |line| parse(line).unwrap().command.role;
+        assert!(Role::Viewer < Role::Operator && Role::Operator < Role::Admin);
+        assert_eq!(role("help"), Role::Viewer);
+        assert_eq!(role("show flash"), Role::Viewer);
+        assert_eq!(role("send 10 x"), Role::Operator);
+        assert_eq!(role("interlock"), Role::Viewer);
+        assert_eq!(role("interlock on"), Role::Operator);
+        assert_eq!(role("config set key"), Role::Admin);
+        assert_eq!(role("reboot"), Role::Admin);
+    }
+
+    #[test]
+    fn names_are_unique_and_well_formed() {
+        for (i, command) in COMMANDS.iter().enumerate() {
+            assert!(!command.name.is_empty());
+            assert!(
+                command.name.split(' ').all(|w| !w.is_empty()),
+                "{}",
+                command.name
+            );
+            assert!(
+                COMMANDS[i + 1..].iter().all(|c| c.name != command.name),
+                "{}",
+                command.name
+            );
+            let repeated = command
+                .params
+                .iter()
+                .position(|p| matches!(p, Param::Repeated(_)));
+            assert!(
+                repeated.is_none_or(|at| at == command.params.len() - 1),
+                "{}",
+                command.name
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Why the device last reset, and how often each kind of reset happened.
+//!
+//! The platform reads the chip's reset cause at boot and hands it to
+//! [`set_boot_reason`]. Telling a brown-out from a firmware crash is most of
+//! the work when diagnosing a stamp in the field, so the counts are kept in
+//! flash next to the [`FlashStats`](crate::store::FlashStats). A boot is
+//! only counted once it has lasted [`RESET_RECORD_DELAY_SECS`]: a device
+//! stuck in a crash loop would otherwise wear the config sector out.
+
+use core::cell::Cell;
+use core::fmt;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use log::{info, warn};
+use sunset_sshwire_derive::{SSHDecode, SSHEncode};
+
+use crate::store;
+
+/// Seconds a boot must last before its reset reason is written to flash.
+pub const RESET_RECORD_DELAY_SECS: u64 = 30;
+
+/// Cause of the last reset, as far as the platform can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    /// Power applied, or the chip's enable pin released.
+    PowerOn,
+    /// Reset requested by the firmware: config change, `reset` command,
+    /// recovery boot.
+    Software,
+    /// Software reset into a freshly written OTA image.
+    Ota,
+    /// The panic handler reset the chip.
+    Panic,
+    /// A watchdog timer expired.
+    Watchdog,
+    /// The supply voltage dropped below the brown-out threshold.
+    BrownOut,
+    /// Anything else: deep sleep wake-up, JTAG, eFuse errors, ...
+    Other,
+}
+
+impl ResetReason {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::PowerOn => "power-on",
+            Self::Software => "software",
+            Self::Ota => "ota",
+            Self::Panic => "panic",
+            Self::Watchdog => "watchdog",
+            Self::BrownOut => "brown-out",
+            Self::Other => "other",
+        }
+    }
+
+    /// Whether the reset points at a fault rather than a deliberate restart.
+    #[must_use]
+    pub const fn is_fault(self) -> bool {
+        matches!(self, Self::Panic | Self::Watchdog | Self::BrownOut)
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Recorded boots by reset reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq, SSHEncode, SSHDecode)]
+pub struct ResetCounts {
+    pub power_on: u32,
+    pub software: u32,
+    pub ota: u32,
+    pub panic: u32,
+    pub watchdog: u32,
+    pub brown_out: u32,
+    pub other: u32,
+}
+
+impl ResetCounts {
+    /// Length of the encoding: seven counts.
+    pub const ENCODED_LEN: usize = 7 * 4;
+
+    pub const ZERO: Self = Self {
+        power_on: 0,
+        software: 0,
+        ota: 0,
+        panic: 0,
+        watchdog: 0,
+        brown_out: 0,
+        other: 0,
+    };
+
+    /// Counts one more reset for `reason`.
+    pub fn add(&mut self, reason: ResetReason) {
+        let count = match reason {
+            ResetReason::PowerOn => &mut self.power_on,
+            ResetReason::Software => &mut self.software,
+            ResetReason::Ota => &mut self.ota,
+            ResetReason::Panic => &mut self.panic,
+            ResetReason::Watchdog => &mut self.watchdog,
+            ResetReason::BrownOut => &mut self.brown_out,
+            ResetReason::Other => &mut self.other,
+        };
+        *count = count.saturating_add(1);
+    }
+
+    /// Recorded boots of any kind.
+    #[must_use]
+    pub fn total(&self) -> u32 {
+        self.iter()
+            .into_iter()
+            .fold(0, |sum, (_, count)| sum.saturating_add(count))
+    }
+
+    /// Every reason with its count, in [`ResetReason`] order.
+    #[must_use]
+    pub fn iter(&self) -> [(ResetReason, u32); 7] {
+        [
+            (ResetReason::PowerOn, self.power_on),
+            (ResetReason::Software, self.software),
+            (ResetReason::Ota, self.ota),
+            (ResetReason::Panic, self.panic),
+            (ResetReason::Watchdog, self.watchdog),
+            (ResetReason::BrownOut, self.brown_out),
+            (ResetReason::Other, self.other),
+        ]
+    }
+}
+
+static BOOT_REASON: Mutex<CriticalSectionRawMutex, Cell<Option<ResetReason>>> =
+    Mutex::new(Cell::new(None));
+
+/// See [`boot_number`].
+static BOOT_NUMBER: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Records why this boot happened. Call once, after the config has been
+/// loaded: a software reset following a new OTA image, according to the
+/// [`FlashStats`](crate::store::FlashStats), is reported as
+/// [`ResetReason::Ota`]. Returns the reason as reported.
+pub fn set_boot_reason(reason: ResetReason) -> ResetReason {
+    let stats = store::usage().stats;
+    let reason = if reason == ResetReason::Software && stats.ota_writes != stats.boot_ota_writes {
+        ResetReason::Ota
+    } else {
+        reason
+    };
+    if reason.is_fault() {
+        warn!("Last reset: {reason}");
+    } else {
+        info!("Last reset: {reason}");
+    }
+    BOOT_REASON.lock(|r| r.set(Some(reason)));
+    BOOT_NUMBER.lock(|n| n.set(stats.resets.total().saturating_add(1)));
+    reason
+}
+
+/// Why this boot happened, if the platform said.
+#[must_use]
+pub fn boot_reason() -> Option<ResetReason> {
+    BOOT_REASON.lock(Cell::get)
+}
+
+/// This boot's number: one more than the boots recorded before it, or 0 if
+/// the platform did not call [`set_boot_reason`]. Boots shorter than
+/// [`RESET_RECORD_DELAY_SECS`] are not recorded, so the boots of a crash
+/// loop share a number.
+#[must_use]
+pub fn boot_number() -> u32 {
+    BOOT_NUMBER.lock(Cell::get)
+}
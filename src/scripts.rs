@@ -0,0 +1,572 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Expect-like scripts run against the UART (`scripts` feature).
+//!
+//! Repetitive procedures, e.g. logging in to the target and collecting its
+//! kernel log, can be stored on the device with `script set` and run with
+//! `script run` instead of being typed over a flaky link. A script is text,
+//! one step per line; blank lines and lines starting with `#` are skipped:
+//!
+//! - `expect <text>`: wait until the target prints `text`. The run fails if
+//!   it does not within the timeout.
+//! - `send <text>`: write `text` to the target.
+//! - `sendline [<text>]`: write `text` and a carriage return.
+//! - `timeout <secs>`: how long the following `expect` steps wait,
+//!   [`SCRIPT_TIMEOUT_SECS`] until set.
+//! - `sleep <ms>`: pause, still recording output.
+//!
+//! `text` is everything after the space following the step's name, up to
+//! [`SCRIPT_TEXT_LEN`] bytes, with `\r`, `\n`, `\t`, `\\` and `\xNN`
+//! escapes; a trailing space is written `\x20`. `expect` matches output
+//! printed since the previous match, so output that arrives while sending
+//! or sleeping counts too. A run ends at the first failing `expect`, and
+//! after [`SCRIPT_MAX_SECS`] at most.
+//!
+//! The end of the last run's target output, up to [`SCRIPT_RESULT_LEN`]
+//! bytes, is kept in RAM with `SSH_STAMP_UART_REDACT` patterns masked.
+//! `script result` prints it and `sftp-ota` builds serve it as
+//! [`RESULT_PATH`]. The running session sees the output unmasked, as it
+//! would on the bridge.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write as _};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, with_deadline};
+use embedded_io_async::Write;
+use heapless::{Deque, String, Vec};
+
+use crate::config::Script;
+use crate::redact::Masker;
+use crate::serial::{self, BufferedSerial, EscapeError};
+use crate::settings::{
+    SCRIPT_MAX_SECS, SCRIPT_NAME_LEN, SCRIPT_RESULT_LEN, SCRIPT_TEXT_LEN, SCRIPT_TIMEOUT_SECS,
+    UART_REDACT_LEN,
+};
+
+/// Path of the last run's result, served over SFTP.
+pub const RESULT_PATH: &str = "/scripts/result.txt";
+
+/// Longest first line of the result, naming the script and the outcome.
+const RESULT_HEADER_LEN: usize = 96;
+/// Bytes read from the UART at a time.
+const READ_CHUNK: usize = 64;
+
+/// Text of an `expect` or `send` step, escapes decoded.
+type Text = Vec<u8, SCRIPT_TEXT_LEN>;
+
+enum Step {
+    Expect(Text),
+    Send(Text),
+    Timeout(u64),
+    Sleep(u64),
+}
+
+/// What is wrong with a script line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepError {
+    Unknown,
+    MissingText,
+    TextTooLong,
+    BadEscape,
+    BadNumber,
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepError::Unknown => write!(f, "unknown step"),
+            StepError::MissingText => write!(f, "text missing"),
+            StepError::TextTooLong => write!(f, "text longer than {SCRIPT_TEXT_LEN} bytes"),
+            StepError::BadEscape => write!(f, "bad escape"),
+            StepError::BadNumber => write!(f, "number missing or out of range"),
+        }
+    }
+}
+
+/// Why a script was not stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptError {
+    /// The name is empty or too long.
+    BadName,
+    /// The text is longer than the config holds, or not UTF-8.
+    BadText,
+    /// A line does not parse; carries the line number.
+    Line(usize, StepError),
+    /// All script slots are taken.
+    NoSlot,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::BadName => {
+                write!(f, "script names are 1 to {SCRIPT_NAME_LEN} characters")
+            }
+            ScriptError::BadText => write!(f, "script too long or not UTF-8"),
+            ScriptError::Line(n, e) => write!(f, "line {n}: {e}"),
+            ScriptError::NoSlot => write!(f, "no room for another script"),
+        }
+    }
+}
+
+/// Parses one line, `None` for blank lines and comments.
+fn parse_step(line: &str) -> Result<Option<Step>, StepError> {
+    let line = line.strip_suffix('\r').unwrap_or(line).trim_start();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let step = match name {
+        "expect" | "send" if arg.is_empty() => return Err(StepError::MissingText),
+        "expect" => Step::Expect(text(arg)?),
+        "send" => Step::Send(text(arg)?),
+        "sendline" => {
+            let mut text = text(arg)?;
+            text.push(b'\r').map_err(|_| StepError::TextTooLong)?;
+            Step::Send(text)
+        }
+        "timeout" => match arg.trim().parse() {
+            Ok(secs @ 1..=SCRIPT_MAX_SECS) => Step::Timeout(secs),
+            _ => return Err(StepError::BadNumber),
+        },
+        "sleep" => match arg.trim().parse() {
+            Ok(ms) if ms <= SCRIPT_MAX_SECS * 1000 => Step::Sleep(ms),
+            _ => return Err(StepError::BadNumber),
+        },
+        _ => return Err(StepError::Unknown),
+    };
+    Ok(Some(step))
+}
+
+/// Decodes the escapes in `arg`.
+fn text(arg: &str) -> Result<Text, StepError> {
+    serial::unescape(arg).map_err(|e| match e {
+        EscapeError::Bad => StepError::BadEscape,
+        EscapeError::TooLong => StepError::TextTooLong,
+    })
+}
+
+/// Checks `name` and every line of `text`, and makes a [`Script`] of them.
+///
+/// # Errors
+///
+/// See [`ScriptError`].
+pub fn parse(name: &str, text: &[u8]) -> Result<Script, ScriptError> {
+    let name = String::try_from(name)
+        .ok()
+        .filter(|name| !name.is_empty())
+        .ok_or(ScriptError::BadName)?;
+    let text = core::str::from_utf8(text)
+        .ok()
+        .and_then(|text| String::try_from(text).ok())
+        .ok_or(ScriptError::BadText)?;
+    for (n, line) in text.split('\n').enumerate() {
+        parse_step(line).map_err(|e| ScriptError::Line(n + 1, e))?;
+    }
+    Ok(Script { name, text })
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every step ran.
+    Completed,
+    /// The `expect` on this line did not see its text in time.
+    NotSeen(usize),
+    /// The run reached [`SCRIPT_MAX_SECS`] at this line.
+    TimeLimit(usize),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Completed => write!(f, "completed"),
+            Outcome::NotSeen(line) => write!(f, "line {line}: expected text not seen in time"),
+            Outcome::TimeLimit(line) => {
+                write!(f, "line {line}: stopped after {SCRIPT_MAX_SECS}s")
+            }
+        }
+    }
+}
+
+/// The last run, see [`result_header`] and [`read_output`].
+struct LastRun {
+    /// Empty if no script ran since boot.
+    name: String<SCRIPT_NAME_LEN>,
+    /// `None` while running, or if the session ended first.
+    outcome: Option<Outcome>,
+    output: Deque<u8, SCRIPT_RESULT_LEN>,
+    /// Output bytes that did not fit, from the start.
+    skipped: u64,
+}
+
+static LAST_RUN: Mutex<CriticalSectionRawMutex, RefCell<LastRun>> =
+    Mutex::new(RefCell::new(LastRun {
+        name: String::new(),
+        outcome: None,
+        output: Deque::new(),
+        skipped: 0,
+    }));
+
+/// First line of the last run's result, `None` if no script ran since
+/// boot.
+#[must_use]
+pub fn result_header() -> Option<String<RESULT_HEADER_LEN>> {
+    LAST_RUN.lock(|run| {
+        let run = run.borrow();
+        if run.name.is_empty() {
+            return None;
+        }
+        let mut header = String::new();
+        // Fits, the name is short.
+        let _ = match run.outcome {
+            Some(outcome) => write!(header, "script {}: {outcome}", run.name),
+            None => write!(header, "script {}: interrupted", run.name),
+        };
+        if run.skipped > 0 {
+            let _ = write!(header, ", first {} bytes of output dropped", run.skipped);
+        }
+        Some(header)
+    })
+}
+
+/// Copies the last run's output starting at byte `from` into `buf`,
+/// returning the number of bytes copied.
+pub fn read_output(from: usize, buf: &mut [u8]) -> usize {
+    LAST_RUN.lock(|run| {
+        let run = run.borrow();
+        let mut n = 0;
+        for (dst, &src) in buf.iter_mut().zip(run.output.iter().skip(from)) {
+            *dst = src;
+            n += 1;
+        }
+        n
+    })
+}
+
+/// Runs `script` against `uart`, copying the target output to `out` as it
+/// comes.
+///
+/// # Errors
+///
+/// Returns an error if writing to `out` fails, which ends the run.
+pub async fn run<U, W>(script: &Script, uart: &U, out: &mut W) -> Result<Outcome, sunset::Error>
+where
+    U: BufferedSerial,
+    W: Write<Error = sunset::Error>,
+{
+    LAST_RUN.lock(|run| {
+        let mut run = run.borrow_mut();
+        run.name.clone_from(&script.name);
+        run.outcome = None;
+        run.output.clear();
+        run.skipped = 0;
+    });
+    let mut runner = Runner {
+        uart,
+        out,
+        window: Text::new(),
+        masker: Masker::new(),
+        masked: [0; READ_CHUNK + UART_REDACT_LEN],
+        timeout: Duration::from_secs(SCRIPT_TIMEOUT_SECS),
+        end: Instant::now() + Duration::from_secs(SCRIPT_MAX_SECS),
+    };
+    let outcome = runner.steps(&script.text).await?;
+    LAST_RUN.lock(|run| run.borrow_mut().outcome = Some(outcome));
+    Ok(outcome)
+}
+
+struct Runner<'a, U, W> {
+    uart: &'a U,
+    out: &'a mut W,
+    /// The end of the output since the last match.
+    window: Text,
+    masker: Masker,
+    masked: [u8; READ_CHUNK + UART_REDACT_LEN],
+    /// How long `expect` waits.
+    timeout: Duration,
+    /// When the run stops, see [`SCRIPT_MAX_SECS`].
+    end: Instant,
+}
+
+impl<U, W> Runner<'_, U, W>
+where
+    U: BufferedSerial,
+    W: Write<Error = sunset::Error>,
+{
+    async fn steps(&mut self, text: &str) -> Result<Outcome, sunset::Error> {
+        for (n, line) in text.split('\n').enumerate() {
+            // Checked when the script was stored.
+            let Ok(Some(step)) = parse_step(line) else {
+                continue;
+            };
+            let done = match step {
+                Step::Expect(pattern) => {
+                    let until = (Instant::now() + self.timeout).min(self.end);
+                    self.expect(&pattern, until).await?
+                }
+                Step::Send(text) => {
+                    let mut uart = self.uart.handle();
+                    with_deadline(self.end, uart.write_all(&text)).await.is_ok()
+                }
+                Step::Timeout(secs) => {
+                    self.timeout = Duration::from_secs(secs);
+                    true
+                }
+                Step::Sleep(ms) => {
+                    let until = (Instant::now() + Duration::from_millis(ms)).min(self.end);
+                    self.record_until(until).await?;
+                    true
+                }
+            };
+            if !done {
+                return Ok(if Instant::now() >= self.end {
+                    Outcome::TimeLimit(n + 1)
+                } else {
+                    Outcome::NotSeen(n + 1)
+                });
+            }
+        }
+        Ok(Outcome::Completed)
+    }
+
+    /// Reads until `pattern` shows up in the output since the last match,
+    /// or `until`. Returns whether it did.
+    async fn expect(&mut self, pattern: &[u8], until: Instant) -> Result<bool, sunset::Error> {
+        if let Some(at) = self
+            .window
+            .windows(pattern.len())
+            .position(|w| w == pattern)
+        {
+            let rest = Text::from_slice(&self.window[at + pattern.len()..]).unwrap_or_default();
+            self.window = rest;
+            return Ok(true);
+        }
+        let mut buf = [0u8; READ_CHUNK];
+        while let Ok(n) = with_deadline(until, self.uart.read(&mut buf)).await {
+            self.record(&buf[..n]).await?;
+            for (i, &b) in buf[..n].iter().enumerate() {
+                self.push(b);
+                if self.window.ends_with(pattern) {
+                    self.window.clear();
+                    for &b in &buf[i + 1..n] {
+                        self.push(b);
+                    }
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Records output until `until`.
+    async fn record_until(&mut self, until: Instant) -> Result<(), sunset::Error> {
+        let mut buf = [0u8; READ_CHUNK];
+        while let Ok(n) = with_deadline(until, self.uart.read(&mut buf)).await {
+            self.record(&buf[..n]).await?;
+            for &b in &buf[..n] {
+                self.push(b);
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, b: u8) {
+        if self.window.is_full() {
+            self.window.remove(0);
+        }
+        let _ = self.window.push(b);
+    }
+
+    /// Keeps `bytes`, masked, in the result and copies them to the session.
+    async fn record(&mut self, bytes: &[u8]) -> Result<(), sunset::Error> {
+        let masked = self.masker.mask(bytes, &mut self.masked);
+        LAST_RUN.lock(|run| {
+            let mut run = run.borrow_mut();
+            for &b in masked {
+                if run.output.is_full() {
+                    run.output.pop_front();
+                    run.skipped += 1;
+                }
+                let _ = run.output.push_back(b);
+            }
+        });
+        self.out.write_all(bytes).await
+    }
+}
+
+/// The result file served over SFTP, kept out of the connection future in a
+/// static by the caller.
+pub struct ResultFile {
+    bytes: Vec<u8, { RESULT_HEADER_LEN + 1 + SCRIPT_RESULT_LEN }>,
+}
+
+impl ResultFile {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    /// Copies the last run's result afresh: the header line and the output.
+    /// Empty if no script ran since boot.
+    pub fn refresh(&mut self) {
+        self.bytes.clear();
+        let Some(header) = result_header() else {
+            return;
+        };
+        // Sized for both.
+        let _ = self.bytes.extend_from_slice(header.as_bytes());
+        let _ = self.bytes.push(b'\n');
+        let start = self.bytes.len();
+        let _ = self.bytes.resize(self.bytes.capacity(), 0);
+        let n = read_output(0, &mut self.bytes[start..]);
+        self.bytes.truncate(start + n);
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Default for ResultFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod scripts_tests {
+    use super::*;
+    use crate::settings::SCRIPT_LEN;
+
+    /// The bytes a `send` or `sendline` line writes.
+    fn sent(line: &str) -> Text {
+        match parse_step(line) {
+            Ok(Some(Step::Send(text))) => text,
+            _ => panic!("`{line}` sends nothing"),
+        }
+    }
+
+    fn error(line: &str) -> StepError {
+        match parse_step(line) {
+            Err(e) => e,
+            Ok(_) => panic!("`{line}` parses"),
+        }
+    }
+
+    /// `step` followed by `len` times `text`.
+    fn repeated(step: &str, text: &str, len: usize) -> String<{ 2 * SCRIPT_LEN }> {
+        let mut line = String::try_from(step).unwrap();
+        for _ in 0..len {
+            line.push_str(text).unwrap();
+        }
+        line
+    }
+
+    #[test]
+    fn valid_script() {
+        let script = parse(
+            "login",
+            b"# log in\r\n\r\n  expect login: \r\nsendline root\ntimeout 30\nsleep 500\nsend \\x03\n",
+        )
+        .unwrap();
+        assert_eq!(script.name, "login");
+        assert!(script.text.starts_with("# log in"));
+
+        assert!(matches!(parse_step("   "), Ok(None)));
+        assert!(matches!(parse_step("  # send"), Ok(None)));
+        assert!(
+            matches!(parse_step("expect login: "), Ok(Some(Step::Expect(t))) if t.as_slice() == b"login: ")
+        );
+        assert!(matches!(
+            parse_step("timeout 600"),
+            Ok(Some(Step::Timeout(600)))
+        ));
+        assert!(matches!(parse_step("sleep 0"), Ok(Some(Step::Sleep(0)))));
+        assert!(matches!(
+            parse_step("sleep 600000\r"),
+            Ok(Some(Step::Sleep(600_000)))
+        ));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(sent(r"send \r\n\t\\").as_slice(), b"\r\n\t\\");
+        assert_eq!(sent(r"send \x41\x7f\xFF").as_slice(), b"A\x7f\xff");
+        assert_eq!(sent(r"sendline a\x20").as_slice(), b"a \r");
+        assert_eq!(sent("sendline").as_slice(), b"\r");
+        // Only the first space separates the text.
+        assert_eq!(sent("send  x").as_slice(), b" x");
+        for bad in [
+            r"send \q",
+            r"send \",
+            r"send \x4",
+            r"send \xZZ",
+            r"expect a\x",
+        ] {
+            assert_eq!(error(bad), StepError::BadEscape, "{bad}");
+        }
+    }
+
+    #[test]
+    fn invalid_steps() {
+        assert_eq!(error("frobnicate"), StepError::Unknown);
+        assert_eq!(error("Expect x"), StepError::Unknown);
+        assert_eq!(error("expect"), StepError::MissingText);
+        assert_eq!(error("send "), StepError::MissingText);
+        for bad in [
+            "timeout",
+            "timeout 0",
+            "timeout 601",
+            "timeout x",
+            "sleep",
+            "sleep -1",
+            "sleep 600001",
+        ] {
+            assert_eq!(error(bad), StepError::BadNumber, "{bad}");
+        }
+        assert_eq!(
+            parse("boot", b"expect a\nsend b\nbogus"),
+            Err(ScriptError::Line(3, StepError::Unknown))
+        );
+    }
+
+    #[test]
+    fn length_limits() {
+        assert_eq!(
+            sent(&repeated("send ", "a", SCRIPT_TEXT_LEN)).len(),
+            SCRIPT_TEXT_LEN
+        );
+        assert_eq!(
+            error(&repeated("send ", "a", SCRIPT_TEXT_LEN + 1)),
+            StepError::TextTooLong
+        );
+        // The limit counts decoded bytes, and the carriage return of `sendline`.
+        assert_eq!(
+            sent(&repeated("send ", r"\x41", SCRIPT_TEXT_LEN)).len(),
+            SCRIPT_TEXT_LEN
+        );
+        assert_eq!(
+            error(&repeated("sendline ", "a", SCRIPT_TEXT_LEN)),
+            StepError::TextTooLong
+        );
+
+        assert!(parse(&repeated("", "n", SCRIPT_NAME_LEN), b"").is_ok());
+        assert_eq!(
+            parse(&repeated("", "n", SCRIPT_NAME_LEN + 1), b""),
+            Err(ScriptError::BadName)
+        );
+        assert_eq!(parse("", b""), Err(ScriptError::BadName));
+        assert!(parse("long", repeated("", "#", SCRIPT_LEN).as_bytes()).is_ok());
+        assert_eq!(
+            parse("long", repeated("", "#", SCRIPT_LEN + 1).as_bytes()),
+            Err(ScriptError::BadText)
+        );
+        assert_eq!(parse("utf8", b"send \xff"), Err(ScriptError::BadText));
+    }
+}
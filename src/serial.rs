@@ -6,11 +6,42 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::fmt;
 use core::future::Future;
 
-use embassy_futures::select::select;
-use embedded_io_async::{Read, Write};
-use log::{debug, warn};
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use embedded_io_async::{ErrorType, Read, Write};
+use log::{debug, info, warn};
+use portable_atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use ssh_stamp_hal::UartConfig;
+
+use crate::commands;
+use crate::config::{BridgeEscape, TxFullPolicy};
+use crate::console::{self, ConsoleKind};
+use crate::events::{self, Event};
+use crate::logging;
+#[cfg(feature = "observers")]
+use crate::observers;
+#[cfg(feature = "plugins")]
+use crate::plugins;
+#[cfg(any(feature = "plugins", feature = "observers"))]
+use crate::redact::Masker;
+#[cfg(any(feature = "plugins", feature = "observers"))]
+use crate::settings::UART_REDACT_LEN;
+use crate::settings::{
+    BRIDGE_BAUD_RATES, BRIDGE_TO_SSH_CHUNK, BRIDGE_TO_UART_CHUNK, UART_ALERT_SLOTS,
+    UART_DRAIN_TIMEOUT_MS, UART_ERROR_ALERT_PER_MINUTE, UART_STUCK_ERRORS, UART_STUCK_MS,
+    UART_TX_STALL_MS,
+};
+use crate::stats;
 
 /// Platform-agnostic buffered serial bridge.
 ///
@@ -18,64 +49,1147 @@ use log::{debug, warn};
 /// channel and the target UART. Every platform provides a concrete type
 /// implementing this trait (ESP32: `ssh_stamp_esp32::BufferedUart`).
 ///
-/// `read`/`write` take `&self` (not `&mut self`) because the bridge splits
-/// each direction into its own future and runs them concurrently via
+/// `read`/`write_some` take `&self` (not `&mut self`) because the bridge
+/// splits each direction into its own future and runs them concurrently via
 /// [`embassy_futures::select::select`]. Implementations back this with
-/// internal pipes / interrupt-filled buffers.
+/// internal pipes / interrupt-filled buffers. Consumers take a
+/// [`SerialHandle`] per direction and use it through `embedded-io-async`.
+///
+/// The task feeding those buffers may run at interrupt priority (ESP32:
+/// an `InterruptExecutor`). Such a task must not await the config or flash
+/// locks, nor log while receiving; it reports through [`record_line_error`],
+/// [`record_discarded`] and the like, which only take a short critical
+/// section and leave logging to [`report_uart_alerts`].
 pub trait BufferedSerial: Sync {
     /// Read as many bytes as are available, up to `buf.len()`. Returns the
     /// number of bytes read. Awaits until at least one byte is available.
     fn read(&self, buf: &mut [u8]) -> impl Future<Output = usize>;
 
-    /// Queue bytes to be written. Completes once `buf` has been accepted
-    /// by the internal buffer (may still be in flight on the wire).
-    fn write(&self, buf: &[u8]) -> impl Future<Output = ()>;
+    /// Queue as much of `buf` as fits. Returns the number of bytes accepted,
+    /// awaiting until there is room for at least one. Accepted bytes may
+    /// still be in flight on the wire.
+    fn write_some(&self, buf: &[u8]) -> impl Future<Output = usize>;
+
+    /// Wait until everything queued by [`write_some`](Self::write_some)
+    /// has been taken for sending.
+    fn drain(&self) -> impl Future<Output = ()>;
+
+    /// Drop what is queued for sending and not yet taken. Returns the number
+    /// of bytes dropped.
+    fn discard_output(&self) -> usize;
 
     /// Return how many received bytes were dropped since the last call
     /// due to the internal buffer being full. Resets the counter.
     fn check_dropped_bytes(&self) -> usize;
+
+    /// Apply new line settings through the UART task and wait until it has.
+    /// Returns `false` if the UART rejected them and kept the previous ones,
+    /// or did not answer. Before the UART task runs they are queued for it
+    /// and `true` is returned. Settings that cannot change at runtime (pins)
+    /// are ignored.
+    fn reconfigure(&self, config: UartConfig) -> impl Future<Output = bool>;
+
+    /// Stop taking output and wait until what was queued has left the wire,
+    /// leaving TX idle. Later writes are discarded, so nothing reaches the
+    /// target until the device resets.
+    fn park(&self) -> impl Future<Output = ()>;
+
+    /// An `embedded-io-async` reader and writer for this UART. Handles are
+    /// cheap; take one for each direction to drive both at once.
+    fn handle(&self) -> SerialHandle<'_, Self>
+    where
+        Self: Sized,
+    {
+        SerialHandle(self)
+    }
+}
+
+/// [`Read`] and [`Write`] over a shared [`BufferedSerial`], see
+/// [`BufferedSerial::handle`]. Neither direction fails.
+pub struct SerialHandle<'a, U>(&'a U);
+
+impl<U> Clone for SerialHandle<'_, U> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<U> Copy for SerialHandle<'_, U> {}
+
+impl<U: BufferedSerial> ErrorType for SerialHandle<'_, U> {
+    type Error = Infallible;
+}
+
+impl<U: BufferedSerial> Read for SerialHandle<'_, U> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.0.read(buf).await)
+    }
+}
+
+impl<U: BufferedSerial> Write for SerialHandle<'_, U> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        Ok(self.0.write_some(buf).await)
+    }
+
+    /// Returns once the bytes are queued, not once they are on the wire;
+    /// [`BufferedSerial::park`] waits for that.
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
 }
 
 /// Forwards an incoming SSH connection to/from the local UART, until
-/// the connection drops.
+/// the connection drops, until the target stops taking input and
+/// `tx_full` is [`TxFullPolicy::Disconnect`], or until the client asks.
+///
+/// With an `escape`, the client reaches the bridge itself as with
+/// OpenSSH's `~`: the escape character at the start of a line, then within
+/// its timeout one of
+///
+/// - `.` to disconnect,
+/// - `e` to toggle local echo of the input, for targets that do not echo,
+/// - `+` or `-` to step the baud rate through [`BRIDGE_BAUD_RATES`] for the
+///   rest of the session,
+/// - `b` to send the break sequence of the console recognised from the
+///   target's output, see [`console`],
+/// - `s` to print what the bridge lost this session, see [`stats`],
+/// - `u` to unlock input, see below,
+/// - `?` to list these.
+///
+/// The escape character typed twice is sent once; followed by anything
+/// else, both are sent.
+///
+/// After `idle_lock` minutes without input (0 for never), input is dropped
+/// until unlocked, so a forgotten session does not send stray keystrokes to
+/// the target.
+///
+/// Bytes are never translated: no CR/LF conversion, no character set. What
+/// changes the streams is dropping input (`idle_lock`, [`TxFullPolicy::Drop`]),
+/// the escape and the `ssh-stamp:` notices written between target output.
+/// With [`TxFullPolicy::Block`], no idle lock and no escape, as
+/// `SSH_STAMP_SESSION_BINARY` sets up, none of these happens and the bridge
+/// is transparent to every byte value.
+///
 /// # Errors
 /// Returns an error if the SSH connection fails.
 pub async fn serial_bridge<U: BufferedSerial>(
     chan_read: impl Read<Error = sunset::Error>,
-    chan_write: impl Write<Error = sunset::Error>,
+    mut chan_write: impl Write<Error = sunset::Error>,
     uart: &U,
+    uart_config: UartConfig,
+    tx_full: TxFullPolicy,
+    idle_lock: u16,
+    escape: Option<BridgeEscape>,
 ) -> Result<(), sunset::Error> {
     debug!("Starting serial <--> SSH bridge");
-    select(uart_to_ssh(uart, chan_write), ssh_to_uart(chan_read, uart)).await;
+    let shared = Shared::new(uart_config.baud_rate);
+    let end = select(
+        uart_to_ssh(uart, &mut chan_write, &shared),
+        ssh_to_uart(
+            chan_read,
+            uart,
+            &uart_config,
+            tx_full,
+            IdleLock::new(idle_lock, escape.unwrap_or_default()),
+            escape.map(Escapes::new),
+            &shared,
+        ),
+    )
+    .await;
+    match end {
+        Either::Second(Ok(Ending::Stalled)) => {
+            warn!("UART TX stalled for {UART_TX_STALL_MS} ms, ending session");
+            commands::print(
+                &mut chan_write,
+                format_args!(
+                    "\r\nssh-stamp: target not taking input for {UART_TX_STALL_MS} ms, disconnecting\r\n"
+                ),
+            )
+            .await?;
+        }
+        Either::Second(Ok(Ending::Requested)) => {
+            info!("Bridge session ended by its escape");
+            commands::print(
+                &mut chan_write,
+                format_args!("\r\nssh-stamp: disconnecting\r\n"),
+            )
+            .await?;
+        }
+        _ => (),
+    }
+    if shared.baud_rate.get() != uart_config.baud_rate {
+        uart.reconfigure(uart_config).await;
+    }
     debug!("Stopping serial <--> SSH bridge");
     Ok(())
 }
 
+/// Puts the UART back in order after a connection, however it ended: the
+/// bridge may have been dropped halfway through queueing the client's
+/// input. Queued input gets [`UART_DRAIN_TIMEOUT_MS`] to be taken, then the
+/// rest is dropped rather than reaching the target during the next
+/// connection; the dropped receive byte count starts over.
+pub async fn end_connection<U: BufferedSerial>(uart: &U) {
+    let drain = Duration::from_millis(UART_DRAIN_TIMEOUT_MS);
+    if with_timeout(drain, uart.drain()).await.is_err() {
+        let dropped = uart.discard_output();
+        warn!("UART TX not draining, dropped {dropped} bytes of input");
+    }
+    let _ = uart.check_dropped_bytes();
+}
+
+/// Like [`serial_bridge`], but only forwards UART output; whatever the SSH
+/// client sends is discarded.
+///
+/// # Errors
+/// Returns an error if the SSH channel fails.
+pub async fn serial_monitor<U: BufferedSerial>(
+    mut chan_read: impl Read<Error = sunset::Error>,
+    chan_write: impl Write<Error = sunset::Error>,
+    uart: &U,
+) -> Result<(), sunset::Error> {
+    debug!("Starting serial --> SSH monitor");
+    let discard = async {
+        let mut buf = [0u8; BRIDGE_TO_UART_CHUNK];
+        loop {
+            if chan_read.read(&mut buf).await? == 0 {
+                return Err::<(), _>(sunset::Error::ChannelEOF);
+            }
+        }
+    };
+    // Nothing is written to the UART, so nothing to report.
+    let shared = Shared::new(0);
+    select(uart_to_ssh(uart, chan_write, &shared), discard).await;
+    debug!("Stopping serial --> SSH monitor");
+    Ok(())
+}
+
+/// Input dropping under [`TxFullPolicy::Drop`] or [`IdleLock`], reported to
+/// the client in line with the UART output.
+enum TxNotice {
+    /// The target stopped taking input.
+    Dropping,
+    /// It resumed after this many bytes were dropped.
+    Dropped(usize),
+    /// Input was locked after this many idle minutes, until this escape
+    /// unlocks it.
+    Locked(u16, BridgeEscape),
+    Unlocked,
+    /// Input is held by the interlock, see [`hold_input`].
+    Held,
+    /// The interlock was released; held input goes out now.
+    Released,
+    /// Local echo was turned on or off.
+    Echo(bool),
+    /// The baud rate for the rest of the session.
+    Baud(u32),
+    /// The UART rejected this baud rate and kept its own.
+    BaudRejected(u32),
+    /// The break sequence of this console was sent, or none is known.
+    Break(Option<ConsoleKind>),
+    /// What the bridge lost is asked for, see [`stats`].
+    Stats,
+    /// The escape commands are asked for.
+    Help(BridgeEscape),
+}
+
+/// What the two halves of a bridge session share.
+struct Shared {
+    /// For the client, in line with the UART output.
+    notices: Signal<NoopRawMutex, TxNotice>,
+    /// Input to echo to the client, see [`EscapeCommand::Echo`].
+    echo: Pipe<NoopRawMutex, BRIDGE_TO_UART_CHUNK>,
+    /// The UART's baud rate, see [`EscapeCommand::BaudUp`].
+    baud_rate: Cell<u32>,
+}
+
+impl Shared {
+    fn new(baud_rate: u32) -> Self {
+        Self {
+            notices: Signal::new(),
+            echo: Pipe::new(),
+            baud_rate: Cell::new(baud_rate),
+        }
+    }
+}
+
+/// Why [`ssh_to_uart`] ended a session.
+enum Ending {
+    /// The target stopped taking input, under [`TxFullPolicy::Disconnect`].
+    Stalled,
+    /// The client typed the escape and `.`.
+    Requested,
+}
+
+/// A command typed after the escape character, see [`serial_bridge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeCommand {
+    Disconnect,
+    Echo,
+    BaudUp,
+    BaudDown,
+    Break,
+    Stats,
+    Unlock,
+    Help,
+}
+
+impl EscapeCommand {
+    fn from_byte(b: u8) -> Option<Self> {
+        Some(match b {
+            b'.' => EscapeCommand::Disconnect,
+            b'e' => EscapeCommand::Echo,
+            b'+' => EscapeCommand::BaudUp,
+            b'-' => EscapeCommand::BaudDown,
+            b'b' => EscapeCommand::Break,
+            b's' => EscapeCommand::Stats,
+            b'u' => EscapeCommand::Unlock,
+            b'?' => EscapeCommand::Help,
+            _ => return None,
+        })
+    }
+}
+
+/// Where escape commands are matched in the client's input.
+#[derive(Clone, Copy)]
+enum Escape {
+    LineStart,
+    /// The escape character typed at the start of a line, at this time.
+    Typed(Instant),
+    Other,
+}
+
+/// A bridge session's input with escape commands taken out, at most one
+/// held back escape character longer than what was read.
+type Escaped = heapless::Vec<u8, { BRIDGE_TO_UART_CHUNK + 1 }>;
+
+/// Finds escape commands in the client's input, see [`serial_bridge`].
+struct Escapes {
+    escape: BridgeEscape,
+    state: Escape,
+}
+
+impl Escapes {
+    fn new(escape: BridgeEscape) -> Self {
+        Self {
+            escape,
+            state: Escape::LineStart,
+        }
+    }
+
+    /// Copies `input` to `out` up to the first escape command, which is
+    /// taken out and returned with the number of bytes used. An escape
+    /// character at the start of a line is held back until the next byte
+    /// tells whether a command follows.
+    fn scan(&mut self, input: &[u8], out: &mut Escaped) -> (usize, Option<EscapeCommand>) {
+        let timeout = Duration::from_millis(u64::from(self.escape.timeout_ms));
+        for (i, &b) in input.iter().enumerate() {
+            match self.state {
+                Escape::Typed(at) => {
+                    self.state = Escape::Other;
+                    if b == self.escape.character {
+                        let _ = out.push(b);
+                        continue;
+                    }
+                    if at.elapsed() <= timeout
+                        && let Some(command) = EscapeCommand::from_byte(b)
+                    {
+                        return (i + 1, Some(command));
+                    }
+                    let _ = out.push(self.escape.character);
+                }
+                Escape::LineStart if b == self.escape.character => {
+                    self.state = Escape::Typed(Instant::now());
+                    continue;
+                }
+                _ => (),
+            }
+            self.state = if matches!(b, b'\r' | b'\n') {
+                Escape::LineStart
+            } else {
+                Escape::Other
+            };
+            let _ = out.push(b);
+        }
+        (input.len(), None)
+    }
+}
+
+/// Locks a bridge session's input once it has been idle too long, see
+/// [`serial_bridge`].
+struct IdleLock {
+    minutes: u16,
+    last_input: Instant,
+    locked: bool,
+    /// Named in the notice, for unlocking.
+    escape: BridgeEscape,
+}
+
+impl IdleLock {
+    fn new(minutes: u16, escape: BridgeEscape) -> Self {
+        Self {
+            minutes,
+            last_input: Instant::now(),
+            locked: false,
+            escape,
+        }
+    }
+
+    /// Notes input from the client, locking if it had been idle too long.
+    fn input(&mut self, notices: &Signal<NoopRawMutex, TxNotice>) {
+        if self.minutes == 0 {
+            return;
+        }
+        let idle = self.last_input.elapsed();
+        self.last_input = Instant::now();
+        if !self.locked && idle.as_secs() >= u64::from(self.minutes) * 60 {
+            info!("Bridge input idle for {} s, locking it", idle.as_secs());
+            self.locked = true;
+            notices.signal(TxNotice::Locked(self.minutes, self.escape));
+        }
+    }
+
+    fn unlock(&mut self, notices: &Signal<NoopRawMutex, TxNotice>) {
+        if self.locked {
+            info!("Bridge input unlocked");
+            self.locked = false;
+            notices.signal(TxNotice::Unlocked);
+        }
+    }
+}
+
 async fn uart_to_ssh<U: BufferedSerial>(
     uart_buf: &U,
     mut chan_write: impl Write<Error = sunset::Error>,
+    shared: &Shared,
 ) -> Result<(), sunset::Error> {
-    let mut ssh_tx_buf = [0u8; 512];
+    let mut uart_rx = uart_buf.handle();
+    let mut ssh_tx_buf = [0u8; BRIDGE_TO_SSH_CHUNK];
+    let mut echo_buf = [0u8; BRIDGE_TO_UART_CHUNK];
+    // Copies for plugins and observers, with `SSH_STAMP_UART_REDACT`
+    // patterns masked; the channel gets the output as read.
+    #[cfg(any(feature = "plugins", feature = "observers"))]
+    let mut masker = Masker::new();
+    #[cfg(any(feature = "plugins", feature = "observers"))]
+    let mut masked = [0u8; BRIDGE_TO_SSH_CHUNK + UART_REDACT_LEN];
+    // Changes before this session were reported to nobody.
+    LINE_STATE.reset();
+    if line_stuck() {
+        print_line_state(&mut chan_write, true).await?;
+    }
     loop {
         let dropped = uart_buf.check_dropped_bytes();
         if dropped > 0 {
             warn!("UART RX dropped {dropped} bytes");
+            stats::record_rx_dropped(dropped);
         }
-        let n = uart_buf.read(&mut ssh_tx_buf).await;
-        chan_write.write_all(&ssh_tx_buf[..n]).await?;
+        let next = select4(
+            uart_rx.read(&mut ssh_tx_buf),
+            shared.notices.wait(),
+            LINE_STATE.wait(),
+            shared.echo.read(&mut echo_buf),
+        );
+        match next.await {
+            Either4::First(Ok(n)) => {
+                console::observe(&ssh_tx_buf[..n]);
+                logging::mirror(&ssh_tx_buf[..n]);
+                #[cfg(any(feature = "plugins", feature = "observers"))]
+                {
+                    let copy = masker.mask(&ssh_tx_buf[..n], &mut masked);
+                    #[cfg(feature = "plugins")]
+                    plugins::observe(copy);
+                    #[cfg(feature = "observers")]
+                    observers::feed(copy);
+                }
+                chan_write.write_all(&ssh_tx_buf[..n]).await?;
+            }
+            Either4::Second(TxNotice::Dropping) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: target not taking input, dropping it\r\n"),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Dropped(bytes)) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!(
+                        "\r\nssh-stamp: target resumed, {bytes} bytes of input dropped\r\n"
+                    ),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Locked(minutes, escape)) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!(
+                        "\r\nssh-stamp: no input for {minutes} min, input locked; type {escape}u at the start of a line to unlock\r\n"
+                    ),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Unlocked) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: input unlocked\r\n"),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Held) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!(
+                        "\r\nssh-stamp: target in a critical section, input held until `interlock off`\r\n"
+                    ),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Released) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: interlock released, sending held input\r\n"),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Echo(on)) => {
+                let state = if on { "on" } else { "off" };
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: local echo {state}\r\n"),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Baud(rate)) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: {rate} baud until the session ends\r\n"),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::BaudRejected(rate)) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: the UART cannot run at {rate} baud\r\n"),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Break(Some(kind))) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: sent the {kind} break sequence\r\n"),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Break(None)) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!(
+                        "\r\nssh-stamp: console not recognised, no break sequence to send\r\n"
+                    ),
+                )
+                .await?;
+            }
+            Either4::Second(TxNotice::Stats) => {
+                let snapshot = stats::snapshot();
+                let lost = snapshot.session.unwrap_or_default();
+                commands::print(
+                    &mut chan_write,
+                    format_args!("\r\nssh-stamp: this session {lost}\r\n"),
+                )
+                .await?;
+                if let Some(secs) = snapshot.last_loss_secs {
+                    commands::print(
+                        &mut chan_write,
+                        format_args!(
+                            "ssh-stamp: last loss at {secs}s, uptime now {}s\r\n",
+                            Instant::now().as_secs()
+                        ),
+                    )
+                    .await?;
+                }
+            }
+            Either4::Second(TxNotice::Help(escape)) => {
+                commands::print(
+                    &mut chan_write,
+                    format_args!(
+                        "\r\nssh-stamp: at the start of a line, {escape}. disconnect, {escape}e local echo, {escape}+ {escape}- baud rate, {escape}b break, {escape}s stats, {escape}u unlock, {escape}{escape} sends {escape}\r\n"
+                    ),
+                )
+                .await?;
+            }
+            Either4::Third(stuck) => print_line_state(&mut chan_write, stuck).await?,
+            Either4::Fourth(n) => chan_write.write_all(&echo_buf[..n]).await?,
+        }
+    }
+}
+
+/// Tells the client whether the RX line is stuck, see [`line_stuck`].
+async fn print_line_state(
+    out: &mut impl Write<Error = sunset::Error>,
+    stuck: bool,
+) -> Result<(), sunset::Error> {
+    if stuck {
+        commands::print(
+            out,
+            format_args!(
+                "\r\nssh-stamp: UART RX line stuck, target off or miswired? Waiting for it to recover\r\n"
+            ),
+        )
+        .await
+    } else {
+        commands::print(
+            out,
+            format_args!("\r\nssh-stamp: UART RX line recovered\r\n"),
+        )
+        .await
     }
 }
 
+/// Returns `Ok` only when the target stalled and `tx_full` is
+/// [`TxFullPolicy::Disconnect`], or the client asked to disconnect.
 async fn ssh_to_uart<U: BufferedSerial>(
     mut chan_read: impl Read<Error = sunset::Error>,
     uart_buf: &U,
-) -> Result<(), sunset::Error> {
-    let mut uart_tx_buf = [0u8; 64];
+    uart_config: &UartConfig,
+    tx_full: TxFullPolicy,
+    mut idle_lock: IdleLock,
+    mut escapes: Option<Escapes>,
+    shared: &Shared,
+) -> Result<Ending, sunset::Error> {
+    let mut uart_tx_buf = [0u8; BRIDGE_TO_UART_CHUNK];
+    let mut input = Input {
+        uart_tx: uart_buf.handle(),
+        tx_full,
+        dropping: None,
+        notices: &shared.notices,
+    };
+    let mut echo = false;
     loop {
         let n = chan_read.read(&mut uart_tx_buf).await?;
         if n == 0 {
             return Err(sunset::Error::ChannelEOF);
         }
-        uart_buf.write(&uart_tx_buf[..n]).await;
+        idle_lock.input(&shared.notices);
+        let mut rest = &uart_tx_buf[..n];
+        while !rest.is_empty() {
+            let mut forward = Escaped::new();
+            let command = match &mut escapes {
+                Some(escapes) => {
+                    let (used, command) = escapes.scan(rest, &mut forward);
+                    rest = &rest[used..];
+                    command
+                }
+                None => {
+                    let _ = forward.extend_from_slice(rest);
+                    rest = &[];
+                    None
+                }
+            };
+            if !forward.is_empty() && !idle_lock.locked {
+                if echo {
+                    // Best effort, the echo must not hold up the target.
+                    let _ = shared.echo.try_write(&forward);
+                }
+                if !input.write(&forward).await {
+                    return Ok(Ending::Stalled);
+                }
+            }
+            let Some(command) = command else {
+                continue;
+            };
+            debug!("Bridge escape command {command:?}");
+            match command {
+                EscapeCommand::Disconnect => return Ok(Ending::Requested),
+                EscapeCommand::Echo => {
+                    echo = !echo;
+                    shared.notices.signal(TxNotice::Echo(echo));
+                }
+                EscapeCommand::BaudUp | EscapeCommand::BaudDown => {
+                    let current = shared.baud_rate.get();
+                    let next = if command == EscapeCommand::BaudUp {
+                        BRIDGE_BAUD_RATES.iter().find(|&&rate| rate > current)
+                    } else {
+                        BRIDGE_BAUD_RATES.iter().rev().find(|&&rate| rate < current)
+                    };
+                    let notice = match next {
+                        Some(&rate) => {
+                            let wanted = UartConfig {
+                                baud_rate: rate,
+                                ..uart_config.clone()
+                            };
+                            if uart_buf.reconfigure(wanted).await {
+                                info!("Bridge baud rate {rate} for this session");
+                                shared.baud_rate.set(rate);
+                                TxNotice::Baud(rate)
+                            } else {
+                                warn!("The UART cannot run at {rate} baud");
+                                TxNotice::BaudRejected(rate)
+                            }
+                        }
+                        None => TxNotice::Baud(current),
+                    };
+                    shared.notices.signal(notice);
+                }
+                EscapeCommand::Break => {
+                    let kind = console::detected();
+                    if let Some(kind) = kind
+                        && !input.write(kind.break_sequence()).await
+                    {
+                        return Ok(Ending::Stalled);
+                    }
+                    shared.notices.signal(TxNotice::Break(kind));
+                }
+                EscapeCommand::Stats => shared.notices.signal(TxNotice::Stats),
+                EscapeCommand::Unlock => idle_lock.unlock(&shared.notices),
+                EscapeCommand::Help => {
+                    let escape = escapes
+                        .as_ref()
+                        .map_or_else(BridgeEscape::default, |e| e.escape);
+                    shared.notices.signal(TxNotice::Help(escape));
+                }
+            }
+        }
+    }
+}
+
+/// Writes the client's input to the UART, as `tx_full` says when the
+/// target stops taking it.
+struct Input<'a, U> {
+    uart_tx: SerialHandle<'a, U>,
+    tx_full: TxFullPolicy,
+    /// Bytes dropped since the target stopped taking input.
+    dropping: Option<usize>,
+    notices: &'a Signal<NoopRawMutex, TxNotice>,
+}
+
+impl<U: BufferedSerial> Input<'_, U> {
+    /// Writes `bytes` once the interlock lets it, see [`hold_input`].
+    /// Returns `false` if the target stalled and the session must end.
+    async fn write(&mut self, bytes: &[u8]) -> bool {
+        if input_held_until().is_some() {
+            self.notices.signal(TxNotice::Held);
+            input_released().await;
+            self.notices.signal(TxNotice::Released);
+        }
+        if self.tx_full == TxFullPolicy::Block {
+            let Ok(()) = self.uart_tx.write_all(bytes).await;
+            return true;
+        }
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            // While dropping, only take what fits right away.
+            let wait = if self.dropping.is_some() {
+                Duration::from_ticks(0)
+            } else {
+                Duration::from_millis(UART_TX_STALL_MS)
+            };
+            match with_timeout(wait, self.uart_tx.write(rest)).await {
+                Ok(Ok(written)) => {
+                    rest = &rest[written..];
+                    if let Some(bytes) = self.dropping.take() {
+                        info!("UART TX resumed, {bytes} bytes dropped");
+                        self.notices.signal(TxNotice::Dropped(bytes));
+                    }
+                }
+                Err(_) if self.tx_full == TxFullPolicy::Drop => {
+                    let bytes = self.dropping.get_or_insert_with(|| {
+                        warn!("UART TX stalled for {UART_TX_STALL_MS} ms, dropping input");
+                        self.notices.signal(TxNotice::Dropping);
+                        0
+                    });
+                    *bytes += rest.len();
+                    stats::record_tx_dropped(rest.len());
+                    break;
+                }
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Milliseconds since boot until which input is held, see [`hold_input`].
+static INTERLOCK_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+/// Raised when the interlock is set or released.
+static INTERLOCK_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Holds the keystrokes of bridge sessions (and raw TCP clients) for
+/// `duration`, while the target is in a critical section such as a flash
+/// write: what is typed is kept back, not delivered, until
+/// [`release_input`] or the time runs out, so input from someone who
+/// connects mid-flash cannot corrupt it. Scripts and `send` still reach
+/// the target. Not persisted.
+pub fn hold_input(duration: Duration) {
+    let until = Instant::now() + duration;
+    INTERLOCK_UNTIL.store(until.as_millis(), Ordering::Relaxed);
+    INTERLOCK_CHANGED.signal(());
+}
+
+/// Ends a [`hold_input`] period early.
+pub fn release_input() {
+    INTERLOCK_UNTIL.store(0, Ordering::Relaxed);
+    INTERLOCK_CHANGED.signal(());
+}
+
+/// When the interlock releases input by itself, if it holds it.
+#[must_use]
+pub fn input_held_until() -> Option<Instant> {
+    let until = Instant::from_millis(INTERLOCK_UNTIL.load(Ordering::Relaxed));
+    (until > Instant::now()).then_some(until)
+}
+
+/// Waits until input is no longer held. The bytes a bridge read before
+/// waiting stay in its buffer; the rest waits in the client's connection.
+pub async fn input_released() {
+    while let Some(until) = input_held_until() {
+        select(Timer::at(until), INTERLOCK_CHANGED.wait()).await;
     }
 }
+
+/// Why [`unescape`] refused a text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscapeError {
+    /// A `\` not followed by `r`, `n`, `t`, `\` or `x` and two hex digits.
+    Bad,
+    /// The decoded text does not fit.
+    TooLong,
+}
+
+/// Decodes the `\r`, `\n`, `\t`, `\\` and `\xNN` escapes in `text`, for
+/// bytes meant for the target typed on a command line.
+///
+/// # Errors
+///
+/// See [`EscapeError`].
+pub fn unescape<const N: usize>(text: &str) -> Result<heapless::Vec<u8, N>, EscapeError> {
+    let mut decoded = heapless::Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        let b = match b {
+            b'\\' => match bytes.next() {
+                Some(b'r') => b'\r',
+                Some(b'n') => b'\n',
+                Some(b't') => b'\t',
+                Some(b'\\') => b'\\',
+                Some(b'x') => {
+                    let digits = [bytes.next(), bytes.next()];
+                    let [Some(hi), Some(lo)] = digits else {
+                        return Err(EscapeError::Bad);
+                    };
+                    core::str::from_utf8(&[hi, lo])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or(EscapeError::Bad)?
+                }
+                _ => return Err(EscapeError::Bad),
+            },
+            b => b,
+        };
+        decoded.push(b).map_err(|_| EscapeError::TooLong)?;
+    }
+    Ok(decoded)
+}
+
+/// Why the bridge is out of service since boot, see [`disable_bridge`].
+static BRIDGE_FAULT: Mutex<CriticalSectionRawMutex, Cell<Option<&'static str>>> =
+    Mutex::new(Cell::new(None));
+
+/// Takes the bridge out of service for this boot because the UART could not
+/// be set up, e.g. its pins or line settings were rejected. Shell sessions
+/// then get the admin console instead, see [`crate::commands::admin_shell`],
+/// so the settings can be fixed remotely.
+pub fn disable_bridge(reason: &'static str) {
+    warn!("Serial bridge disabled: {reason}");
+    BRIDGE_FAULT.lock(|fault| fault.set(Some(reason)));
+}
+
+/// Why the bridge is out of service, `None` while it works.
+#[must_use]
+pub fn bridge_fault() -> Option<&'static str> {
+    BRIDGE_FAULT.lock(Cell::get)
+}
+
+/// What the idle UART pins did while a platform watched them before taking
+/// them over, with the `tx-sense` feature: TX with a pull-up, which only a
+/// transmitter on the other end pulls low, and RX with a pull-down, which
+/// the target's idle TX holds high.
+#[cfg(feature = "tx-sense")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PinSense {
+    pub samples: u32,
+    /// Samples with the TX pin low.
+    pub tx_low: u32,
+    /// Samples with the RX pin high.
+    pub rx_high: u32,
+}
+
+/// Set by [`record_pin_sense`]: whether RX and TX look swapped, if the TX
+/// pin is driven by the target.
+#[cfg(feature = "tx-sense")]
+static TX_CONTENTION: Mutex<CriticalSectionRawMutex, Cell<Option<bool>>> =
+    Mutex::new(Cell::new(None));
+
+/// Judges what the pins did before the UART took them. A TX pin pulled low
+/// means the target transmits on it, so both sides would drive the line
+/// once the bridge starts: an [`Event::UartTxContention`] reports that,
+/// adding whether RX and TX look swapped because nothing held RX high.
+#[cfg(feature = "tx-sense")]
+pub fn record_pin_sense(sense: PinSense) {
+    debug!(
+        "UART pins: TX low {}, RX high {} of {} samples",
+        sense.tx_low, sense.rx_high, sense.samples
+    );
+    if sense.tx_low == 0 {
+        return;
+    }
+    let swapped = sense.rx_high == 0;
+    TX_CONTENTION.lock(|contention| contention.set(Some(swapped)));
+    raise(Event::UartTxContention { swapped });
+}
+
+/// `Some` if the target was seen driving the TX pin, holding whether RX and
+/// TX look swapped; see [`record_pin_sense`].
+#[cfg(feature = "tx-sense")]
+#[must_use]
+pub fn tx_contention() -> Option<bool> {
+    TX_CONTENTION.lock(Cell::get)
+}
+
+/// A receive error reported by the UART hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineError {
+    /// A pulse too short to be a bit, usually noise.
+    Glitch,
+    /// No stop bit where one was expected.
+    Framing,
+    Parity,
+    /// The hardware FIFO filled before it was read.
+    Overflow,
+}
+
+impl LineError {
+    /// True for errors caused by the signal itself (noise, wrong baud rate
+    /// or format) rather than by the device falling behind.
+    #[must_use]
+    pub fn is_line_noise(self) -> bool {
+        !matches!(self, LineError::Overflow)
+    }
+}
+
+/// UART receive error counters since boot, see [`line_stats`].
+#[derive(Debug, Clone, Default)]
+pub struct LineStats {
+    pub glitches: u32,
+    pub framing: u32,
+    pub parity: u32,
+    pub overflows: u32,
+    /// Bytes thrown away while resynchronising after an error.
+    pub discarded_bytes: u32,
+    /// Line noise errors in the current minute.
+    pub last_minute: u32,
+}
+
+impl fmt::Display for LineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "glitch {}, framing {}, parity {}, overflow {}",
+            self.glitches, self.framing, self.parity, self.overflows
+        )
+    }
+}
+
+struct LineErrors {
+    stats: LineStats,
+    /// Start of the current one minute window, seconds since boot.
+    window_secs: u64,
+    /// Whether this window already produced an event.
+    alerted: bool,
+    /// Start of the current run of line noise errors with no byte received
+    /// cleanly in between, milliseconds since boot.
+    run_start_ms: u64,
+    /// Line noise errors in that run.
+    run_errors: u32,
+}
+
+static LINE_ERRORS: Mutex<CriticalSectionRawMutex, RefCell<LineErrors>> =
+    Mutex::new(RefCell::new(LineErrors {
+        stats: LineStats {
+            glitches: 0,
+            framing: 0,
+            parity: 0,
+            overflows: 0,
+            discarded_bytes: 0,
+            last_minute: 0,
+        },
+        window_secs: 0,
+        alerted: false,
+        run_start_ms: 0,
+        run_errors: 0,
+    }));
+
+/// Set by [`record_received`], taken by [`record_line_error`] to start a
+/// new run of errors.
+static RECEIVED_CLEANLY: AtomicBool = AtomicBool::new(true);
+
+/// Whether the RX line is stuck, see [`line_stuck`].
+static LINE_STUCK: AtomicBool = AtomicBool::new(false);
+
+/// Changes of [`line_stuck`], for the running bridge.
+static LINE_STATE: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Counts a receive error. Platforms call this after recovering from it,
+/// from the UART task; it only takes a short critical section.
+///
+/// Once more than [`UART_ERROR_ALERT_PER_MINUTE`] line noise errors arrive
+/// within a minute, an [`Event::UartLineErrors`] points at a likely baud
+/// rate mismatch; at most one per minute.
+///
+/// Line noise errors and not a byte received cleanly, at least
+/// [`UART_STUCK_ERRORS`] over [`UART_STUCK_MS`], mean the RX line is held
+/// low or in break, e.g. by a target that is off or a miswired pin. The
+/// line is then marked stuck and an [`Event::UartLineStuck`] replaces the
+/// per-minute alerts until a byte arrives cleanly again, see
+/// [`line_stuck`].
+pub fn record_line_error(error: LineError) {
+    let now_ms = Instant::now().as_millis();
+    let now = now_ms / 1000;
+    let new_run = RECEIVED_CLEANLY.swap(false, Ordering::Relaxed);
+    let alert = LINE_ERRORS.lock(|errors| {
+        let mut errors = errors.borrow_mut();
+        let counter = match error {
+            LineError::Glitch => &mut errors.stats.glitches,
+            LineError::Framing => &mut errors.stats.framing,
+            LineError::Parity => &mut errors.stats.parity,
+            LineError::Overflow => &mut errors.stats.overflows,
+        };
+        *counter = counter.saturating_add(1);
+        if !error.is_line_noise() {
+            return None;
+        }
+        if new_run {
+            errors.run_start_ms = now_ms;
+            errors.run_errors = 0;
+        }
+        errors.run_errors = errors.run_errors.saturating_add(1);
+        if LINE_STUCK.load(Ordering::Relaxed) {
+            return None;
+        }
+        if errors.run_errors >= UART_STUCK_ERRORS
+            && now_ms.saturating_sub(errors.run_start_ms) >= UART_STUCK_MS
+        {
+            LINE_STUCK.store(true, Ordering::Relaxed);
+            return Some(Event::UartLineStuck {
+                errors: errors.run_errors,
+            });
+        }
+        if now.saturating_sub(errors.window_secs) >= 60 {
+            errors.window_secs = now;
+            errors.stats.last_minute = 0;
+            errors.alerted = false;
+        }
+        errors.stats.last_minute += 1;
+        let exceeded = errors.stats.last_minute > UART_ERROR_ALERT_PER_MINUTE;
+        (exceeded && !core::mem::replace(&mut errors.alerted, true)).then_some(
+            Event::UartLineErrors {
+                per_minute: errors.stats.last_minute,
+            },
+        )
+    });
+    stats::record_line_error();
+    if let Some(event) = alert {
+        raise(event);
+    }
+}
+
+/// Whether the RX line is stuck: it keeps reporting errors and nothing
+/// arrives cleanly, see [`record_line_error`]. Platforms poll the UART
+/// only every [`UART_STUCK_POLL_MS`](crate::settings::UART_STUCK_POLL_MS)
+/// while it is, and the bridge tells its client when this changes.
+#[must_use]
+pub fn line_stuck() -> bool {
+    LINE_STUCK.load(Ordering::Relaxed)
+}
+
+/// Events raised by the UART task, waiting for [`report_uart_alerts`].
+static UART_ALERTS: Channel<CriticalSectionRawMutex, Event, UART_ALERT_SLOTS> = Channel::new();
+
+/// Queues `event` for [`report_uart_alerts`]; dropped if the queue is full,
+/// the counters behind it are kept regardless.
+fn raise(event: Event) {
+    let _ = UART_ALERTS.try_send(event);
+}
+
+/// Logs and emits the events raised by [`record_line_error`] and
+/// `record_pin_sense`. These are called from the UART task, which may run
+/// at interrupt priority, where logging and [`events::emit`] (with its
+/// outbox and plugin hooks) would hold up reception; this runs them in
+/// thread mode instead. [`SshConsole`](crate::app::SshConsole) runs it next
+/// to the server.
+pub async fn report_uart_alerts() -> Infallible {
+    loop {
+        let event = UART_ALERTS.receive().await;
+        match event {
+            Event::UartLineErrors { .. } => warn!("UART receive errors: {}", line_stats()),
+            Event::UartLineStuck { .. } => LINE_STATE.signal(true),
+            Event::UartLineRecovered => LINE_STATE.signal(false),
+            _ => (),
+        }
+        events::emit(event);
+    }
+}
+
+/// Bytes received from the UART since boot, wrapping.
+static RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+/// Counts bytes received without error. Platforms call this from the UART
+/// task with every chunk read; the first one after the line was stuck
+/// raises an [`Event::UartLineRecovered`].
+pub fn record_received(bytes: usize) {
+    // Wrapping, see `received_bytes`.
+    #[allow(clippy::cast_possible_truncation)]
+    RECEIVED.fetch_add(bytes as u32, Ordering::Relaxed);
+    if bytes > 0 {
+        RECEIVED_CLEANLY.store(true, Ordering::Relaxed);
+        if LINE_STUCK.swap(false, Ordering::Relaxed) {
+            raise(Event::UartLineRecovered);
+        }
+    }
+}
+
+/// Bytes received from the UART since boot. Wraps, so compare two readings
+/// with `wrapping_sub`.
+#[must_use]
+pub fn received_bytes() -> u32 {
+    RECEIVED.load(Ordering::Relaxed)
+}
+
+/// Counts bytes discarded while resynchronising after a receive error.
+pub fn record_discarded(bytes: usize) {
+    let bytes = u32::try_from(bytes).unwrap_or(u32::MAX);
+    LINE_ERRORS.lock(|errors| {
+        let mut errors = errors.borrow_mut();
+        errors.stats.discarded_bytes = errors.stats.discarded_bytes.saturating_add(bytes);
+    });
+}
+
+/// Returns a copy of the receive error counters.
+#[must_use]
+pub fn line_stats() -> LineStats {
+    let now = Instant::now().as_secs();
+    LINE_ERRORS.lock(|errors| {
+        let errors = errors.borrow();
+        let mut stats = errors.stats.clone();
+        if now.saturating_sub(errors.window_secs) >= 60 {
+            stats.last_minute = 0;
+        }
+        stats
+    })
+}
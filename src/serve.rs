@@ -11,16 +11,21 @@
 //!
 //! [`connection_loop`] processes `ServEvent` enums from the `sunset` SSH library
 //! and dispatches to the appropriate handler in [`handle`](crate::handle).
+//!
+//! While an event is held, the connection's channels wait for it, so
+//! keystrokes and UART output stall behind whatever its handler awaits.
+//! Events held longer than [`SLOW_EVENT_MS`] are logged.
 
-use log::trace;
+use embassy_time::{Duration, Instant};
+use log::{trace, warn};
 
-use crate::config::SSHStampConfig;
+use crate::config::{Route, SSHStampConfig, UartOverrides};
 use crate::handle::{
     EventContext, SessionType, defunct, first_auth, hostkeys, open_session, password_auth,
     pubkey_auth, session_env, session_exec, session_pty, session_shell, session_subsystem,
 };
 use crate::platform::PlatformServices;
-use crate::settings::UART_BUFFER_SIZE;
+use crate::settings::{SLOW_EVENT_MS, UART_BUFFER_SIZE};
 use sunset::{ChanHandle, ServEvent};
 use sunset_async::SunsetMutex;
 
@@ -30,6 +35,44 @@ use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::channel::Channel;
 use sunset_async::{ProgressHolder, SSHServer};
 
+/// What [`connection_loop`] keeps between events, lent to each handler as
+/// an [`EventContext`].
+struct ConnectionState {
+    session: Option<ChanHandle>,
+    auth_checked: bool,
+    config_changed: bool,
+    needs_reset: bool,
+    boot_recovery: bool,
+    uart_overrides: UartOverrides,
+    route: Route,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        Self {
+            session: None,
+            auth_checked: false,
+            config_changed: false,
+            needs_reset: false,
+            boot_recovery: false,
+            uart_overrides: UartOverrides::default(),
+            route: Route::DEFAULT,
+        }
+    }
+
+    fn context(&mut self) -> EventContext<'_> {
+        EventContext {
+            session: &mut self.session,
+            auth_checked: &mut self.auth_checked,
+            config_changed: &mut self.config_changed,
+            needs_reset: &mut self.needs_reset,
+            boot_recovery: &mut self.boot_recovery,
+            uart_overrides: &mut self.uart_overrides,
+            route: &mut self.route,
+        }
+    }
+}
+
 /// Handles the SSH connection loop, processing events from clients.
 ///
 /// # Errors
@@ -43,66 +86,97 @@ pub async fn connection_loop<P: PlatformServices>(
     config: &SunsetMutex<SSHStampConfig>,
     platform: &P,
 ) -> Result<(), sunset::Error> {
-    let mut session: Option<ChanHandle> = None;
-    let mut config_changed = false;
-    let mut needs_reset = false;
-    let mut auth_checked = false;
+    let mut state = ConnectionState::new();
 
     loop {
-        let mut ph = ProgressHolder::new();
-        let ev = serv.progress(&mut ph).await?;
-
-        trace!("{:?}", &ev);
+        {
+            // The event borrows the holder, which keeps the connection
+            // locked until both are dropped at the end of this block.
+            let mut ph = ProgressHolder::new();
+            let ev = serv.progress(&mut ph).await?;
 
-        let mut ctx = EventContext {
-            session: &mut session,
-            auth_checked: &mut auth_checked,
-            config_changed: &mut config_changed,
-            needs_reset: &mut needs_reset,
-        };
+            trace!("{:?}", &ev);
 
-        match ev {
-            ServEvent::SessionSubsystem(_) => {
-                #[cfg(feature = "sftp-ota")]
-                session_subsystem(ev, &mut ctx, chan_pipe)?;
-                #[cfg(not(feature = "sftp-ota"))]
-                session_subsystem(ev, &mut ctx)?;
+            let name = event_name(&ev);
+            let start = Instant::now();
+            dispatch(ev, &mut state.context(), config, chan_pipe, platform).await?;
+            let held = start.elapsed();
+            if held >= Duration::from_millis(SLOW_EVENT_MS) {
+                warn!("{name} held the SSH connection for {} ms", held.as_millis());
             }
-            ServEvent::SessionShell(_) => {
-                session_shell(ev, &mut ctx, config, chan_pipe, platform).await?;
-            }
-            ServEvent::FirstAuth(_) => {
-                first_auth(ev, config).await?;
-            }
-            ServEvent::Hostkeys(_) => {
-                hostkeys(ev, config).await?;
-            }
-            ServEvent::PasswordAuth(_) => {
-                password_auth(ev)?;
-            }
-            ServEvent::PubkeyAuth(_) => {
-                pubkey_auth(ev, &mut ctx, config).await?;
-            }
-            ServEvent::OpenSession(_) => {
-                open_session(ev, &mut ctx)?;
-            }
-            ServEvent::SessionEnv(_) => {
-                session_env(ev, &mut ctx, config).await?;
-            }
-            ServEvent::SessionPty(_) => {
-                session_pty(ev, &mut ctx, config).await?;
-            }
-            ServEvent::SessionExec(_) => {
-                session_exec(ev)?;
-            }
-            ServEvent::Defunct => {
-                defunct()?;
-            }
-            ServEvent::Authenticated | ServEvent::PollAgain => {}
         }
     }
 }
 
+/// Runs the handler for `ev`.
+///
+/// Channel requests `sunset` has no event for, such as `break` and
+/// `signal`, never get here; it refuses them itself.
+async fn dispatch<P: PlatformServices>(
+    ev: ServEvent<'_, '_>,
+    ctx: &mut EventContext<'_>,
+    config: &SunsetMutex<SSHStampConfig>,
+    chan_pipe: &Channel<NoopRawMutex, SessionType, 1>,
+    platform: &P,
+) -> Result<(), sunset::Error> {
+    match ev {
+        ServEvent::SessionSubsystem(_) => {
+            session_subsystem(ev, ctx, config, chan_pipe, platform).await?;
+        }
+        ServEvent::SessionShell(_) => {
+            session_shell(ev, ctx, config, chan_pipe, platform).await?;
+        }
+        ServEvent::FirstAuth(_) => {
+            first_auth(ev, config).await?;
+        }
+        ServEvent::Hostkeys(_) => {
+            hostkeys(ev, config).await?;
+        }
+        ServEvent::PasswordAuth(_) => {
+            password_auth(ev)?;
+        }
+        ServEvent::PubkeyAuth(_) => {
+            pubkey_auth(ev, ctx, config).await?;
+        }
+        ServEvent::OpenSession(_) => {
+            open_session(ev, ctx)?;
+        }
+        ServEvent::SessionEnv(_) => {
+            session_env(ev, ctx, config).await?;
+        }
+        ServEvent::SessionPty(_) => {
+            session_pty(ev, ctx, config).await?;
+        }
+        ServEvent::SessionExec(_) => {
+            session_exec(ev, ctx, config, chan_pipe).await?;
+        }
+        ServEvent::Defunct => {
+            defunct()?;
+        }
+        ServEvent::Authenticated | ServEvent::PollAgain => {}
+    }
+    Ok(())
+}
+
+/// Names `ev` for the slow event log.
+fn event_name(ev: &ServEvent<'_, '_>) -> &'static str {
+    match ev {
+        ServEvent::SessionSubsystem(_) => "SessionSubsystem",
+        ServEvent::SessionShell(_) => "SessionShell",
+        ServEvent::FirstAuth(_) => "FirstAuth",
+        ServEvent::Hostkeys(_) => "Hostkeys",
+        ServEvent::PasswordAuth(_) => "PasswordAuth",
+        ServEvent::PubkeyAuth(_) => "PubkeyAuth",
+        ServEvent::OpenSession(_) => "OpenSession",
+        ServEvent::SessionEnv(_) => "SessionEnv",
+        ServEvent::SessionPty(_) => "SessionPty",
+        ServEvent::SessionExec(_) => "SessionExec",
+        ServEvent::Defunct => "Defunct",
+        ServEvent::Authenticated => "Authenticated",
+        ServEvent::PollAgain => "PollAgain",
+    }
+}
+
 /// Creates a new [`SSHServer`] with the provided I/O buffers.
 pub fn ssh_wait_for_initialisation<'server>(
     inbuf: &'server mut [u8; UART_BUFFER_SIZE],
@@ -0,0 +1,294 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Records of recent SSH sessions.
+//!
+//! Every accepted connection gets a [`SessionRecord`] holding the peer, the
+//! interface it came in on, the client's identification string and the algorithms negotiated with it (see
+//! [`crate::handshake`]). The last [`SESSION_HISTORY_LEN`] records are kept,
+//! so a client that gave up during key exchange can still be inspected with
+//! `show sessions` after it disconnected.
+//!
+//! [`stalled`] bounds how long a connection may take to log in, so a client
+//! that stops halfway cannot hold the single connection slot.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::net::IpAddr;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, with_deadline};
+use heapless::{Deque, String};
+use log::{info, warn};
+
+use crate::config::NetInterface;
+use crate::events::{self, Event};
+use crate::settings::{
+    ALGORITHM_NAME_LEN, CLIENT_VERSION_LEN, HANDSHAKE_TIMEOUT_SECS, LOGIN_TIMEOUT_SECS,
+    SESSION_HISTORY_LEN, USER_NAME_LEN,
+};
+
+/// An SSH algorithm name such as `curve25519-sha256`.
+pub type AlgorithmName = String<ALGORITHM_NAME_LEN>;
+
+/// Algorithms negotiated for a session; `None` where client and server had
+/// nothing in common.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Algorithms {
+    pub kex: Option<AlgorithmName>,
+    pub hostkey: Option<AlgorithmName>,
+    pub cipher_c2s: Option<AlgorithmName>,
+    pub cipher_s2c: Option<AlgorithmName>,
+    pub mac_c2s: Option<AlgorithmName>,
+    pub mac_s2c: Option<AlgorithmName>,
+}
+
+/// Displays an optional algorithm, or `(none in common)`.
+pub struct Negotiated<'a>(pub &'a Option<AlgorithmName>);
+
+impl fmt::Display for Negotiated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(name) => f.write_str(name),
+            None => f.write_str("(none in common)"),
+        }
+    }
+}
+
+impl Algorithms {
+    /// True if `cipher` authenticates by itself, making the MAC irrelevant.
+    #[must_use]
+    pub fn is_aead(cipher: Option<&AlgorithmName>) -> bool {
+        cipher.is_some_and(|c| c.contains("poly1305") || c.contains("gcm"))
+    }
+}
+
+/// One SSH connection, current or past.
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    /// Counts up from 1 since boot.
+    pub id: u32,
+    pub peer: Option<IpAddr>,
+    /// Interface the connection came in on, `None` outside AP+STA mode.
+    pub interface: Option<NetInterface>,
+    /// Seconds since boot at accept time.
+    pub started_secs: u64,
+    /// Seconds since boot at disconnect, `None` while connected.
+    pub ended_secs: Option<u64>,
+    /// The client's identification string, e.g. `SSH-2.0-OpenSSH_9.6`.
+    pub client_version: String<CLIENT_VERSION_LEN>,
+    /// `None` until both key exchange proposals have been seen.
+    pub algorithms: Option<Algorithms>,
+    /// Whether the client logged in.
+    pub authenticated: bool,
+    /// The username it logged in as, empty if not known.
+    pub user: String<USER_NAME_LEN>,
+}
+
+struct Sessions {
+    records: Deque<SessionRecord, SESSION_HISTORY_LEN>,
+    next_id: u32,
+}
+
+static SESSIONS: Mutex<CriticalSectionRawMutex, RefCell<Sessions>> =
+    Mutex::new(RefCell::new(Sessions {
+        records: Deque::new(),
+        next_id: 1,
+    }));
+
+/// Signalled when the current session gets further towards logging in.
+static PROGRESS: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Where a connection that did not log in in time got stuck, see
+/// [`stalled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginPhase {
+    /// Before both key exchange proposals were seen.
+    KeyExchange,
+    /// After key exchange, before logging in.
+    Authentication,
+}
+
+impl fmt::Display for LoginPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LoginPhase::KeyExchange => "key exchange",
+            LoginPhase::Authentication => "authentication",
+        })
+    }
+}
+
+/// Starts a record for a newly accepted connection from `peer` on
+/// `interface`.
+pub fn begin(peer: Option<IpAddr>, interface: Option<NetInterface>) {
+    SESSIONS.lock(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let record = SessionRecord {
+            id: sessions.next_id,
+            peer,
+            interface,
+            started_secs: Instant::now().as_secs(),
+            ended_secs: None,
+            client_version: String::new(),
+            algorithms: None,
+            authenticated: false,
+            user: String::new(),
+        };
+        sessions.next_id = sessions.next_id.wrapping_add(1);
+        if sessions.records.is_full() {
+            sessions.records.pop_front();
+        }
+        let _ = sessions.records.push_back(record);
+    });
+    PROGRESS.reset();
+}
+
+/// Marks the current session as ended. Returns whether the client had
+/// logged in.
+pub fn end() -> bool {
+    SESSIONS.lock(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let Some(current) = sessions.records.back_mut() else {
+            return false;
+        };
+        current.ended_secs.get_or_insert(Instant::now().as_secs());
+        current.authenticated
+    })
+}
+
+/// Marks the current session as logged in as `user`, if known.
+pub(crate) fn authenticated(user: Option<&str>) {
+    SESSIONS.lock(|sessions| {
+        if let Some(current) = sessions.borrow_mut().records.back_mut() {
+            current.authenticated = true;
+            current.user = user.map(truncated).unwrap_or_default();
+        }
+    });
+    PROGRESS.signal(());
+}
+
+/// Stores the handshake details of the current session and reports them in
+/// the log and the event log. `algorithms` is `None` if the key exchange
+/// proposals could not be parsed.
+pub(crate) fn record_handshake(client_version: &str, algorithms: Option<Algorithms>) {
+    match &algorithms {
+        Some(a) => info!(
+            "SSH client {client_version}: kex {}, hostkey {}, cipher {}",
+            Negotiated(&a.kex),
+            Negotiated(&a.hostkey),
+            Negotiated(&a.cipher_c2s),
+        ),
+        None => warn!("SSH client {client_version}: could not parse key exchange proposals"),
+    }
+    let event = SESSIONS.lock(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let current = sessions.records.back_mut()?;
+        current.client_version = truncated(client_version);
+        let algorithms = algorithms?;
+        let event = Event::SessionNegotiated {
+            session: current.id,
+            client: truncated(
+                client_version
+                    .strip_prefix("SSH-2.0-")
+                    .unwrap_or(client_version),
+            ),
+            kex: algorithms.kex.clone(),
+            cipher: algorithms.cipher_c2s.clone(),
+        };
+        current.algorithms = Some(algorithms);
+        Some(event)
+    });
+    PROGRESS.signal(());
+    if let Some(event) = event {
+        events::emit(event);
+    }
+}
+
+/// Resolves when the session in progress has not seen both key exchange
+/// proposals within [`HANDSHAKE_TIMEOUT_SECS`], or not logged in within
+/// [`LOGIN_TIMEOUT_SECS`], of being accepted. The stall is logged and
+/// recorded as an event; the caller closes the connection. Never resolves
+/// once the client has logged in.
+pub async fn stalled() {
+    let started = Instant::now();
+    let (id, phase) = loop {
+        let (id, negotiated, authenticated) = SESSIONS.lock(|sessions| {
+            sessions
+                .borrow()
+                .records
+                .back()
+                .map_or((0, false, false), |r| {
+                    (r.id, !r.client_version.is_empty(), r.authenticated)
+                })
+        });
+        let (phase, secs) = match (negotiated, authenticated) {
+            (_, true) => return core::future::pending().await,
+            (true, false) => (LoginPhase::Authentication, LOGIN_TIMEOUT_SECS),
+            (false, false) => (LoginPhase::KeyExchange, HANDSHAKE_TIMEOUT_SECS),
+        };
+        let deadline = started + Duration::from_secs(secs);
+        if with_deadline(deadline, PROGRESS.wait()).await.is_err() {
+            break (id, phase);
+        }
+    };
+    warn!("Session {id} stalled in {phase}, closing it");
+    events::emit(Event::LoginStalled { session: id, phase });
+}
+
+/// Returns a copy of the `index`th stored session, oldest first.
+#[must_use]
+pub fn get(index: usize) -> Option<SessionRecord> {
+    SESSIONS.lock(|sessions| sessions.borrow().records.iter().nth(index).cloned())
+}
+
+/// The peer of the session in progress, if known.
+#[must_use]
+pub fn current_peer() -> Option<IpAddr> {
+    SESSIONS.lock(|sessions| {
+        sessions
+            .borrow()
+            .records
+            .back()
+            .filter(|r| r.ended_secs.is_none())
+            .and_then(|r| r.peer)
+    })
+}
+
+/// The username the session in progress logged in as, if known.
+#[must_use]
+pub fn current_user() -> Option<String<USER_NAME_LEN>> {
+    SESSIONS.lock(|sessions| {
+        sessions
+            .borrow()
+            .records
+            .back()
+            .filter(|r| r.ended_secs.is_none() && !r.user.is_empty())
+            .map(|r| r.user.clone())
+    })
+}
+
+/// The interface of the session in progress, `None` outside AP+STA mode.
+#[must_use]
+pub fn current_interface() -> Option<NetInterface> {
+    SESSIONS.lock(|sessions| {
+        sessions
+            .borrow()
+            .records
+            .back()
+            .filter(|r| r.ended_secs.is_none())
+            .and_then(|r| r.interface)
+    })
+}
+
+pub(crate) fn truncated<const N: usize>(s: &str) -> String<N> {
+    let mut out = String::new();
+    for c in s.chars() {
+        if out.push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
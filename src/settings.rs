@@ -11,18 +11,270 @@
 use core::net::Ipv4Addr;
 
 // SSH server settings
+/// Receive and transmit buffer of each listening TCP socket; one full
+/// Ethernet frame.
+pub const TCP_BUFFER_SIZE: usize = 1536;
 //pub(crate) const MTU: usize = 1536;
-//pub(crate) const PORT: u16 = 22;
+/// Port the SSH server listens on, unless [`SshConsole::port`](crate::app::SshConsole::port)
+/// says otherwise.
+pub const SSH_PORT: u16 = 22;
 pub(crate) const SSH_STAMP_IDENT: &str = env!("SSH_STAMP_IDENT");
 pub(crate) const KEY_SLOTS: usize = 1; // TODO: Document whether this a "reasonable default"? Justify why?
-pub const DEFAULT_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1); // TODO: Expose this setting via
-// SSH_STAMP env var?
+/// Default access point address, see `SSH_STAMP_WIFI_AP_ADDRESS`.
+pub const DEFAULT_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+/// Access point addresses tried in turn when the station's network in
+/// AP+STA mode overlaps the configured one, see
+/// [`crate::config::ap_address_beside`]. Picked from ranges home and office
+/// networks rarely use.
+pub const AP_FALLBACK_ADDRESSES: [Ipv4Addr; 3] = [
+    Ipv4Addr::new(10, 174, 77, 1),
+    Ipv4Addr::new(172, 29, 211, 1),
+    Ipv4Addr::new(192, 168, 213, 1),
+];
+/// Maximum number of subnets in each of the allow and deny lists.
+pub const ACCESS_LIST_SLOTS: usize = 4;
+/// Usernames in the routing table, see `SSH_STAMP_USER_ROUTES`.
+pub const USER_ROUTE_SLOTS: usize = 6;
+/// Longest routed username.
+pub const USER_NAME_LEN: usize = 16;
+
+// Known client registry, see `clients`
+/// Username and key pairs remembered.
+pub const KNOWN_CLIENT_SLOTS: usize = 4;
+/// Longest time a login's last-seen time waits to be saved, unless the
+/// config is saved sooner.
+pub const CLIENT_SAVE_INTERVAL_SECS: u64 = 3600;
+
+// OTA history, see `ota_history`
+/// Uploads remembered.
+pub const OTA_HISTORY_SLOTS: usize = 4;
+/// Bytes of an image's SHA-256 kept to identify it.
+pub const OTA_DIGEST_PREFIX_LEN: usize = 8;
+/// Bytes of an image's release notes kept.
+pub const OTA_NOTES_LEN: usize = 32;
+/// Bytes of a firmware version and commit kept, e.g. `0.3.1 (a1b2c3d)`.
+pub const FIRMWARE_ID_LEN: usize = 32;
+
+// Durable event queue, see `outbox`
+/// Events kept in flash until taken.
+pub const OUTBOX_SLOTS: usize = 4;
+/// Bytes of each event's text kept.
+pub const OUTBOX_TEXT_LEN: usize = 48;
+
+// Peer discovery, see `peers`
+/// Other stamps remembered; the one heard from longest ago makes room.
+pub const PEER_SLOTS: usize = 32;
+/// Longest peer name kept, an SSID.
+pub const PEER_NAME_LEN: usize = 32;
+/// Seconds between queries for other stamps.
+pub const PEER_BROWSE_SECS: u64 = 60;
+/// A stamp that has not answered for this long is forgotten.
+pub const PEER_EXPIRY_SECS: u64 = 3 * PEER_BROWSE_SECS + 30;
+
+// Downstream plugins, see `plugins`
+/// Plugins that can be registered.
+pub const PLUGIN_SLOTS: usize = 4;
+/// Longest target output line handed to plugins; longer ones are split.
+pub const PLUGIN_LINE_LEN: usize = 128;
+
+// SFTP files for fleet collection, see `telemetry`
+/// Longest event log file; 16 events take about 3 KiB.
+pub const EVENTS_JSONL_LEN: usize = 4096;
+/// Longest counters file.
+pub const METRICS_JSON_LEN: usize = 768;
+
+// SFTP sessions, `sftp-ota` builds
+/// SFTP sessions that can run at once. Each borrows one request buffer of
+/// `ota::SFTP_REQUEST_LEN` bytes from a static pool and returns it when it
+/// ends; a session finding none free is refused rather than queued.
+pub const SFTP_SESSION_SLOTS: usize = 1;
+/// SFTP reply buffer. Unlike the request buffer it lives in the session's
+/// future, so it adds to the connection's worst-case stack use.
+pub const SFTP_REPLY_LEN: usize = 512;
+
+// Read-only sessions, see `observers`
+/// Clients that may watch the bridge next to the main connection.
+pub const OBSERVER_SLOTS: usize = 2;
+/// Target output kept for observers; a slower client skips ahead.
+pub const OBSERVER_RING_LEN: usize = 1024;
+/// How often an idle observer looks for new target output.
+pub(crate) const OBSERVER_POLL_MS: u64 = 20;
+
+// Expect scripts, see `scripts`
+/// Scripts kept in the config.
+pub const SCRIPT_SLOTS: usize = 2;
+/// Longest script name.
+pub const SCRIPT_NAME_LEN: usize = 16;
+/// Longest script text; scripts share the config sector with everything
+/// else.
+pub const SCRIPT_LEN: usize = 256;
+/// Longest text of an `expect` or `send` step, once escapes are decoded.
+pub const SCRIPT_TEXT_LEN: usize = 64;
+/// How long `expect` waits until a `timeout` step says otherwise.
+pub const SCRIPT_TIMEOUT_SECS: u64 = 10;
+/// Longest run of a script; also bounds `timeout` and `sleep`.
+pub const SCRIPT_MAX_SECS: u64 = 600;
+/// Target output kept from the last run, for `script result` and SFTP.
+pub const SCRIPT_RESULT_LEN: usize = 2048;
+
+// Backing off from failing clients, see `lockout`
+/// Failed connections from one address, within [`FAILURE_WINDOW_SECS`],
+/// after which it is refused.
+pub const CLIENT_MAX_FAILURES: u32 = 5;
+/// How long an address is refused.
+pub const CLIENT_BLOCK_SECS: u64 = 300;
+/// Failed connections from all addresses, within [`FAILURE_WINDOW_SECS`],
+/// after which the access point is taken off the air.
+pub const AP_PAUSE_FAILURES: u32 = 20;
+/// How long the access point stays off the air.
+pub const AP_PAUSE_SECS: u64 = 120;
+/// Window over which failed connections are counted.
+pub const FAILURE_WINDOW_SECS: u64 = 60;
+/// Addresses whose failures are tracked at once.
+pub(crate) const LOCKOUT_SLOTS: usize = 4;
+
+// Stalled logins, see `sessions::stalled`
+/// Seconds from accepting a connection until both key exchange proposals
+/// must have been seen.
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+/// Seconds from accepting a connection until the client must have logged in.
+pub const LOGIN_TIMEOUT_SECS: u64 = 60;
 
 // WiFi SSID and password character set (alphanumeric)
 pub(crate) const WIFI_PASSWORD_CHARS: &[u8; 62] =
     b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// Minutes before an unconfirmed network change is rolled back, unless
+/// `config commit <minutes>` says otherwise.
+pub const ROLLBACK_MINUTES: u16 = 5;
+/// Boots new firmware gets to be confirmed when `SSH_STAMP_OTA_CONFIRM`
+/// gives only minutes.
+pub const OTA_CONFIRM_BOOTS: u8 = 3;
 // Wifi Station Mode Connection
 pub const STATION_MODE_MAX_RETRY_SECONDS: u8 = 10;
 
+// SSH session records
+/// Sessions listed by `show sessions`, the current one included.
+pub const SESSION_HISTORY_LEN: usize = 4;
+/// Longest client identification string kept, longer ones are truncated.
+pub(crate) const CLIENT_VERSION_LEN: usize = 64;
+/// Longest algorithm name kept; covers every name sunset implements.
+pub(crate) const ALGORITHM_NAME_LEN: usize = 32;
+/// Bytes of the client's `SSH_MSG_KEXINIT` inspected. `OpenSSH` sends ~1.5 KiB;
+/// the algorithm lists come first, so a longer packet loses only its tail.
+pub(crate) const CLIENT_KEXINIT_LEN: usize = 1536;
+/// Bytes of our own `SSH_MSG_KEXINIT` inspected.
+pub(crate) const SERVER_KEXINIT_LEN: usize = 512;
+
+/// An SSH event handled for longer than this is logged: the connection's
+/// channels wait for it, see [`crate::serve`].
+pub const SLOW_EVENT_MS: u64 = 20;
+
+/// Warning given before a session reaches its `SSH_STAMP_MAX_SESSION` limit.
+pub const SESSION_LIMIT_WARNING_SECS: u64 = 300;
+
 // UART settings
+/// Size of each of the SSH input and output buffers handed to sunset.
 pub const UART_BUFFER_SIZE: usize = 4096;
+/// UART output read per SSH channel write by the bridge.
+pub(crate) const BRIDGE_TO_SSH_CHUNK: usize = 512;
+/// SSH input read per UART write by the bridge.
+pub(crate) const BRIDGE_TO_UART_CHUNK: usize = 64;
+/// Baud rates the bridge's `+` and `-` escape commands step through.
+pub const BRIDGE_BAUD_RATES: [u32; 8] = [
+    9600, 19_200, 38_400, 57_600, 115_200, 230_400, 460_800, 921_600,
+];
+/// UART receive errors (glitch, framing, parity) per minute beyond which an
+/// event suggests a baud rate mismatch.
+pub const UART_ERROR_ALERT_PER_MINUTE: u32 = 10;
+/// Receive errors with no byte arriving cleanly, at least
+/// [`UART_STUCK_ERRORS`] over this long, after which the RX line counts as
+/// stuck, see `serial::line_stuck`.
+pub const UART_STUCK_MS: u64 = 2000;
+/// Fewest receive errors that make a stuck line.
+pub const UART_STUCK_ERRORS: u32 = 20;
+/// How often platforms look at a stuck RX line for recovery.
+pub const UART_STUCK_POLL_MS: u64 = 500;
+/// Baud rates a `SSH_STAMP_UART_BAUD_LADDER` may list.
+pub const BAUD_LADDER_SLOTS: usize = 6;
+/// Window over which the baud ladder judges the line, see
+/// [`crate::baud_ladder`].
+pub const BAUD_LADDER_WINDOW_SECS: u64 = 2;
+/// Receive errors within a window that make the baud ladder try the next
+/// rate.
+pub const BAUD_LADDER_ERRORS: u32 = 8;
+/// UART events queued for logging outside the UART task, see
+/// `serial::report_uart_alerts`.
+pub(crate) const UART_ALERT_SLOTS: usize = 4;
+/// After a receive error, input is discarded until the line has been idle
+/// this long, so the receiver restarts on a character boundary. 0 only
+/// flushes the FIFO.
+pub const UART_RESYNC_QUIET_MS: u64 = 2;
+/// Upper bound on the discarding, for a line that never goes quiet.
+pub const UART_RESYNC_MAX_MS: u64 = 50;
+/// How long the UART pins are watched at boot for a target driving TX
+/// (`tx-sense` feature).
+pub const UART_PIN_SENSE_MS: u64 = 200;
+/// Time between samples while watching; a start bit at 115200 baud lasts
+/// under 9 µs, so only some are caught, but a byte has several low bits.
+pub const UART_PIN_SENSE_INTERVAL_US: u64 = 20;
+/// How long UART output may make no progress before the configured
+/// [`TxFullPolicy`](crate::config::TxFullPolicy) applies.
+pub const UART_TX_STALL_MS: u64 = 2000;
+/// Longest wait for queued UART output to leave before `reboot` or
+/// `shutdown` goes ahead anyway.
+pub const UART_PARK_TIMEOUT_MS: u64 = 1000;
+/// Longest wait after a connection ends for the input it queued to be
+/// taken by the UART, before the rest is dropped.
+pub const UART_DRAIN_TIMEOUT_MS: u64 = 500;
+/// Longest wait for the UART task to take new line settings.
+pub const UART_RECONFIGURE_TIMEOUT_MS: u64 = 500;
+/// Length of the pulse that resets the target, see `auto_reset`.
+pub const AUTO_RESET_PULSE_MICROS: u16 = 20_000;
+/// Input silence before a programmer's sync command counts as it starting.
+pub const AUTO_RESET_QUIET_MS: u64 = 500;
+/// No further automatic reset for this long after one.
+pub const AUTO_RESET_HOLDOFF_SECS: u64 = 10;
+/// Named targets behind an external UART mux, see `SSH_STAMP_MUX_TARGETS`.
+pub const MUX_TARGET_SLOTS: usize = 4;
+/// Longest mux target name.
+pub const MUX_TARGET_NAME_LEN: usize = 16;
+/// Patterns a `SSH_STAMP_UART_REDACT` may list, see `redact`.
+pub const UART_REDACT_SLOTS: usize = 4;
+/// Longest redaction pattern.
+pub const UART_REDACT_LEN: usize = 32;
+/// Shortest redaction pattern, so that ordinary output is not masked.
+pub const UART_REDACT_MIN_LEN: usize = 4;
+
+// Flash
+/// Erase cycles a flash sector is rated for, the reference for `show flash`.
+pub const FLASH_RATED_ERASE_CYCLES: u32 = 100_000;
+/// Tries at saving the config to its sector before it is given up for the
+/// reserve sector, see [`crate::store`].
+pub const CONFIG_WRITE_ATTEMPTS: u8 = 3;
+
+// Admin commands
+/// Longest `exec` command line accepted, see [`crate::commands`].
+pub const COMMAND_LINE_LEN: usize = 128;
+/// Longest single line of command output; longer lines are truncated.
+pub(crate) const COMMAND_OUTPUT_LEN: usize = 160;
+/// Interval of the progress lines printed while a command waits, see
+/// `commands::with_progress`.
+pub(crate) const PROGRESS_INTERVAL_SECS: u64 = 2;
+/// Duration of a `benchmark` run, unless given.
+pub const BENCHMARK_SECS: u64 = 10;
+/// Longest `benchmark` run accepted.
+pub const BENCHMARK_MAX_SECS: u64 = 60;
+/// Minutes `log verbose` shows secrets in the log for, unless given.
+pub const LOG_VERBOSE_MINUTES: u64 = 30;
+/// Longest `log verbose` period accepted.
+pub const LOG_VERBOSE_MAX_MINUTES: u64 = 24 * 60;
+/// Longest wait for target output `send` accepts.
+pub const SEND_MAX_MS: u64 = 10_000;
+/// Minutes `interlock on` holds bridge input for, unless given.
+pub const INTERLOCK_MINUTES: u64 = 10;
+/// Longest `interlock on` period accepted.
+pub const INTERLOCK_MAX_MINUTES: u64 = 120;
+/// Largest build manifest `manifest check` reads.
+pub const MANIFEST_LEN: usize = 1024;
+/// Largest bundle `provision` accepts, see [`crate::provisioning`].
+pub const PROVISION_BUNDLE_LEN: usize = 2048;
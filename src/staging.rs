@@ -0,0 +1,482 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Staged configuration changes.
+//!
+//! The `config` admin command changes settings in a shadow copy of the
+//! configuration rather than the live one: `config set` accumulates changes,
+//! `config validate` runs the cross-field checks of
+//! [`SSHStampConfig::validate`], `config commit` persists and applies the
+//! whole set at once and `config abort` throws it away. A combination that
+//! would cut off network access (an AP PSK of the wrong length, access lists
+//! refusing the admin's own address, ...) is refused as a whole instead of
+//! being applied one environment variable at a time.
+//!
+//! Keys are the `SSH_STAMP_*` environment variable names, with or without
+//! the prefix, and take the same values.
+//!
+//! The staged copy lives in RAM only and is lost on reset. It is taken from
+//! the live configuration when the first change is staged; committing
+//! replaces the live configuration with it, so changes made through
+//! environment variables in the meantime are overwritten.
+//!
+//! # Commit confirmed
+//!
+//! A commit that touches network settings (`WiFi`, addresses, access lists,
+//! SSH interfaces) keeps the previous network settings in the saved config
+//! as a [`Rollback`]. Unless the admin reconnects and runs `config confirm`
+//! within [`ROLLBACK_MINUTES`] (or the minutes given to `config commit`),
+//! [`rollback_watchdog`] restores them and resets the device. The window
+//! restarts at every boot, so a commit whose reset leaves the device
+//! unreachable is still undone.
+
+use core::convert::Infallible;
+use core::fmt;
+use core::net::IpAddr;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use heapless::Vec;
+use log::{debug, error, info, warn};
+use ssh_stamp_hal::HalError;
+use sunset_async::SunsetMutex;
+
+use crate::config::{
+    ConfigProblem, MAX_CONFIG_PROBLEMS, NetworkSettings, Rollback, SSHStampConfig, TxFullPolicy,
+};
+use crate::handle::env_parser;
+use crate::logging;
+use crate::platform::PlatformServices;
+use crate::redact;
+#[cfg(doc)]
+use crate::settings::ROLLBACK_MINUTES;
+
+/// Distinct keys a staged change set can hold.
+pub const STAGED_KEYS: usize = 32;
+
+/// Problems found by [`validate`].
+pub type Problems = Vec<ConfigProblem, MAX_CONFIG_PROBLEMS>;
+
+/// Why a staging operation was refused.
+#[derive(Debug)]
+pub enum StagingError {
+    /// Not a key that can be staged.
+    UnknownKey,
+    /// The value does not parse for this key.
+    InvalidValue,
+    /// `validate`, `commit` or `abort` without staged changes.
+    NothingStaged,
+    /// The live configuration cannot be copied (agent-held host key).
+    CannotStage,
+    /// The staged configuration has fatal problems, see [`validate`].
+    Invalid,
+    /// `confirm` without a commit awaiting confirmation.
+    NothingToConfirm,
+    /// Saving the configuration failed.
+    Platform(HalError),
+}
+
+impl fmt::Display for StagingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StagingError::UnknownKey => write!(f, "unknown key"),
+            StagingError::InvalidValue => write!(f, "invalid value"),
+            StagingError::NothingStaged => write!(f, "nothing staged"),
+            StagingError::CannotStage => write!(f, "configuration cannot be staged"),
+            StagingError::Invalid => write!(f, "staged configuration is invalid"),
+            StagingError::NothingToConfirm => write!(f, "no commit awaiting confirmation"),
+            StagingError::Platform(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<HalError> for StagingError {
+    fn from(e: HalError) -> Self {
+        StagingError::Platform(e)
+    }
+}
+
+struct Staged {
+    config: SSHStampConfig,
+    /// Keys changed so far, in the order first set.
+    keys: Vec<&'static str, STAGED_KEYS>,
+    /// Some staged key only takes effect after a reset.
+    needs_reset: bool,
+    /// Some staged key is a network setting, see [`NetworkSettings`].
+    network: bool,
+}
+
+static STAGED: SunsetMutex<Option<Staged>> = SunsetMutex::new(None);
+
+/// Raised when a rollback is armed or confirmed.
+static ROLLBACK_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// A stageable key.
+#[derive(Clone, Copy)]
+struct Key {
+    /// Without the `SSH_STAMP_` prefix.
+    name: &'static str,
+    /// Takes effect after a reset.
+    needs_reset: bool,
+    /// Can make the device unreachable, so commits are rolled back unless
+    /// confirmed.
+    network: bool,
+}
+
+const fn key(name: &'static str, needs_reset: bool, network: bool) -> Key {
+    Key {
+        name,
+        needs_reset,
+        network,
+    }
+}
+
+const KEYS: &[Key] = &[
+    key("WIFI_AP_SSID", true, true),
+    key("WIFI_AP_PSK", true, true),
+    key("WIFI_STA_SSID", true, true),
+    key("WIFI_STA_PSK", true, true),
+    key("WIFI_MAC_ADDRESS", true, true),
+    key("WIFI_MAC_RANDOM", true, true),
+    key("WIFI_CONCURRENT", true, true),
+    key("WIFI_AP_ADDRESS", true, true),
+    key("DHCP_SERVER", true, true),
+    key("ALLOW_SUBNETS", false, true),
+    key("DENY_SUBNETS", false, true),
+    key("SSH_INTERFACES", false, true),
+    key("OTA_INTERFACES", false, false),
+    key("USER_ROUTES", false, true),
+    key("UART_BAUD", false, false),
+    key("UART_BAUD_LADDER", false, false),
+    key("UART_FRAME", false, false),
+    key("UART_REDACT", false, false),
+    key("UART_RX", false, false),
+    key("UART_PINS", true, false),
+    key("LOG_CONSOLE", false, false),
+    key("LOG_REDACT", false, false),
+    key("CONSOLE_MIRROR", false, false),
+    key("TCP_NODELAY", false, false),
+    key("UART_TX_FULL", false, false),
+    key("OTA_CONFIRM", false, false),
+    key("MAX_SESSION", false, false),
+    key("UART_IDLE_LOCK", false, false),
+    key("UART_ESCAPE", false, false),
+    #[cfg(feature = "mux")]
+    key("MUX_TARGETS", false, false),
+    #[cfg(feature = "rmt")]
+    key("AUTO_RESET", false, false),
+    #[cfg(feature = "raw-tcp")]
+    key("RAW_TCP", true, false),
+];
+
+const _: () = assert!(KEYS.len() <= STAGED_KEYS);
+
+/// Looks up `key`, accepting the `SSH_STAMP_` prefix and the
+/// `WIFI_STA_PW` spelling of the station PSK.
+fn lookup(key: &str) -> Option<Key> {
+    let key = key.strip_prefix("SSH_STAMP_").unwrap_or(key);
+    let key = if key == "WIFI_STA_PW" {
+        "WIFI_STA_PSK"
+    } else {
+        key
+    };
+    KEYS.iter().copied().find(|k| k.name == key)
+}
+
+/// Whether `key` names a stageable setting.
+pub(crate) fn is_key(key: &str) -> bool {
+    lookup(key).is_some()
+}
+
+/// Sets the setting named `key`, as for [`set`], in `config` directly.
+/// Returns whether the change takes effect only after a reset, or `None` for
+/// unknown keys and invalid values.
+pub(crate) fn apply_key(config: &mut SSHStampConfig, key: &str, value: &str) -> Option<bool> {
+    let key = lookup(key)?;
+    apply(config, key.name, value)?;
+    Some(key.needs_reset)
+}
+
+/// Applies the settings of `config` that take effect at once: logging and
+/// UART redaction.
+pub(crate) fn apply_live(config: &SSHStampConfig) {
+    logging::set_console(config.log_console);
+    logging::set_redact(config.log_redact);
+    logging::set_mirror(config.console_mirror);
+    redact::set_patterns(&config.uart_redact);
+}
+
+/// Sets `key` to `value` in `config`.
+fn apply(config: &mut SSHStampConfig, key: &str, value: &str) -> Option<()> {
+    match key {
+        "WIFI_AP_SSID" => config.wifi_ap_ssid = env_parser::parse_wifi_ap_ssid(value)?,
+        "WIFI_AP_PSK" => config.wifi_ap_pw = env_parser::parse_wifi_psk(value)?,
+        "WIFI_STA_SSID" => config.wifi_sta_ssid = env_parser::parse_wifi_station_ssid(value)?,
+        "WIFI_STA_PSK" => config.wifi_sta_pw = env_parser::parse_wifi_psk(value)?,
+        "WIFI_MAC_ADDRESS" => config.mac = env_parser::parse_mac_address(value)?,
+        "WIFI_MAC_RANDOM" => config.mac = [0xFF; 6],
+        "WIFI_CONCURRENT" => config.wifi_concurrent = env_parser::parse_flag(value)?,
+        "WIFI_AP_ADDRESS" => config.ap_address = env_parser::parse_ap_address(value)?,
+        "DHCP_SERVER" => config.dhcp_server = env_parser::parse_flag(value)?,
+        "ALLOW_SUBNETS" => config.access_lists.allow = env_parser::parse_subnet_list(value)?,
+        "DENY_SUBNETS" => config.access_lists.deny = env_parser::parse_subnet_list(value)?,
+        "SSH_INTERFACES" => {
+            (config.ssh_on_ap, config.ssh_on_sta) = env_parser::parse_interfaces(value)?;
+        }
+        "OTA_INTERFACES" => {
+            (config.ota_on_ap, config.ota_on_sta) = env_parser::parse_interfaces(value)?;
+        }
+        "UART_BAUD" => config.uart_baud = env_parser::parse_baud_rate(value)?,
+        "UART_BAUD_LADDER" => config.baud_ladder = env_parser::parse_baud_ladder(value)?,
+        "UART_FRAME" => config.uart_frame = env_parser::parse_uart_frame(value)?,
+        "UART_REDACT" => config.uart_redact = env_parser::parse_redact_patterns(value)?,
+        "UART_RX" => {
+            let profile = env_parser::parse_uart_rx(value)?;
+            config.uart_profile.rx_fifo_threshold = profile.rx_fifo_threshold;
+            config.uart_profile.rx_timeout = profile.rx_timeout;
+        }
+        "UART_PINS" => config.uart_pins = env_parser::parse_uart_pins(value)?,
+        "LOG_CONSOLE" => config.log_console = env_parser::parse_flag(value)?,
+        "LOG_REDACT" => config.log_redact = env_parser::parse_flag(value)?,
+        "CONSOLE_MIRROR" => config.console_mirror = env_parser::parse_flag(value)?,
+        "TCP_NODELAY" => config.tcp_nodelay = env_parser::parse_flag(value)?,
+        "UART_TX_FULL" => config.uart_tx_full = TxFullPolicy::from_name(value)?,
+        "OTA_CONFIRM" => config.ota_confirm = env_parser::parse_ota_confirm(value)?,
+        "UART_IDLE_LOCK" => config.uart_idle_lock = value.parse().ok()?,
+        "MAX_SESSION" => config.session_limit = env_parser::parse_session_limit(value)?,
+        #[cfg(feature = "rmt")]
+        "AUTO_RESET" => config.auto_reset = env_parser::parse_flag(value)?,
+        #[cfg(feature = "raw-tcp")]
+        "RAW_TCP" => config.raw_tcp_port = env_parser::parse_raw_tcp(value)?,
+        "UART_ESCAPE" => config.uart_escape = env_parser::parse_escape(value)?,
+        "USER_ROUTES" => config.user_routes = env_parser::parse_user_routes(value)?,
+        #[cfg(feature = "mux")]
+        "MUX_TARGETS" => {
+            let targets = env_parser::parse_mux_targets(value)?;
+            let selected = config
+                .selected_mux_target()
+                .and_then(|current| targets.iter().position(|t| t.name == current.name))
+                .and_then(|i| u8::try_from(i).ok());
+            config.mux_targets = targets;
+            config.mux_selected = selected;
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Stages `key = value`, starting a change set from the live `config` if
+/// none is open.
+///
+/// # Errors
+///
+/// Returns an error for unknown keys and invalid values; nothing is staged
+/// then.
+pub async fn set(
+    config: &SunsetMutex<SSHStampConfig>,
+    key: &str,
+    value: &str,
+) -> Result<(), StagingError> {
+    let key = lookup(key).ok_or(StagingError::UnknownKey)?;
+    let mut staged = STAGED.lock().await;
+    if staged.is_none() {
+        let copy = config
+            .lock()
+            .await
+            .try_clone()
+            .ok_or(StagingError::CannotStage)?;
+        *staged = Some(Staged {
+            config: copy,
+            keys: Vec::new(),
+            needs_reset: false,
+            network: false,
+        });
+    }
+    let staged = staged.as_mut().ok_or(StagingError::NothingStaged)?;
+    apply(&mut staged.config, key.name, value).ok_or(StagingError::InvalidValue)?;
+    if !staged.keys.contains(&key.name) {
+        // Every key fits, `STAGED_KEYS` exceeds the table.
+        let _ = staged.keys.push(key.name);
+    }
+    staged.needs_reset |= key.needs_reset;
+    staged.network |= key.network;
+    debug!("Staged {}", key.name);
+    Ok(())
+}
+
+/// Calls `f` on the staged configuration; `None` if no change set is open.
+pub async fn with_staged<R>(f: impl FnOnce(&SSHStampConfig) -> R) -> Option<R> {
+    STAGED.lock().await.as_ref().map(|s| f(&s.config))
+}
+
+/// The keys staged so far; empty if no change set is open.
+pub async fn keys() -> Vec<&'static str, STAGED_KEYS> {
+    STAGED
+        .lock()
+        .await
+        .as_ref()
+        .map(|s| s.keys.clone())
+        .unwrap_or_default()
+}
+
+/// Runs [`SSHStampConfig::validate`] on the staged configuration, `peer`
+/// being the admin's address.
+///
+/// # Errors
+///
+/// Returns [`StagingError::NothingStaged`] without an open change set.
+pub async fn validate(peer: Option<IpAddr>) -> Result<Problems, StagingError> {
+    STAGED
+        .lock()
+        .await
+        .as_ref()
+        .map(|s| s.config.validate(peer))
+        .ok_or(StagingError::NothingStaged)
+}
+
+/// The outcome of [`commit`].
+#[derive(Debug, Clone, Copy)]
+pub struct Committed {
+    /// The device must be reset for the changes to take effect; the caller
+    /// does that once it has reported back.
+    pub needs_reset: bool,
+    /// Minutes until the network settings are rolled back, unless
+    /// confirmed; `None` if the commit needs no confirmation.
+    pub rollback_minutes: Option<u16>,
+}
+
+/// Validates, saves and applies the staged configuration, closing the
+/// change set.
+///
+/// If network settings were staged and `rollback_minutes` is not zero, the
+/// commit must be confirmed with [`confirm`] within that many minutes.
+///
+/// # Errors
+///
+/// Returns [`StagingError::Invalid`] if validation finds fatal problems
+/// (the change set stays open), or an error if saving fails.
+pub async fn commit<P: PlatformServices>(
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+    peer: Option<IpAddr>,
+    rollback_minutes: u16,
+) -> Result<Committed, StagingError> {
+    let mut staged = STAGED.lock().await;
+    let pending = staged.as_mut().ok_or(StagingError::NothingStaged)?;
+    if pending
+        .config
+        .validate(peer)
+        .iter()
+        .any(ConfigProblem::is_fatal)
+    {
+        return Err(StagingError::Invalid);
+    }
+    let mut live = config.lock().await;
+    // An earlier unconfirmed commit stays pending, rolling back to the
+    // settings from before it.
+    pending.config.rollback = if pending.network && rollback_minutes > 0 {
+        Some(Rollback {
+            minutes: rollback_minutes,
+            previous: live
+                .rollback
+                .as_ref()
+                .map_or_else(|| NetworkSettings::of(&live), |r| r.previous.clone()),
+        })
+    } else {
+        live.rollback.clone()
+    };
+    platform.save_config(&pending.config).await?;
+    let Some(Staged {
+        config: new_config,
+        keys,
+        needs_reset,
+        ..
+    }) = staged.take()
+    else {
+        return Err(StagingError::NothingStaged);
+    };
+    apply_live(&new_config);
+    let committed = Committed {
+        needs_reset,
+        rollback_minutes: new_config.rollback.as_ref().map(|r| r.minutes),
+    };
+    *live = new_config;
+    drop(live);
+    ROLLBACK_CHANGED.signal(());
+    info!("Committed {} staged configuration changes", keys.len());
+    Ok(committed)
+}
+
+/// Confirms the last commit, cancelling its rollback.
+///
+/// # Errors
+///
+/// Returns [`StagingError::NothingToConfirm`] if no commit awaits
+/// confirmation, or an error if saving fails.
+pub async fn confirm<P: PlatformServices>(
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Result<(), StagingError> {
+    let mut config = config.lock().await;
+    let rollback = config
+        .rollback
+        .take()
+        .ok_or(StagingError::NothingToConfirm)?;
+    if let Err(e) = platform.save_config(&config).await {
+        config.rollback = Some(rollback);
+        return Err(e.into());
+    }
+    drop(config);
+    ROLLBACK_CHANGED.signal(());
+    info!("Configuration confirmed");
+    Ok(())
+}
+
+/// Rolls back unconfirmed commits once their time is up, see the
+/// [module documentation](self). Runs alongside the SSH server.
+pub async fn rollback_watchdog<P: PlatformServices>(
+    config: &SunsetMutex<SSHStampConfig>,
+    platform: &P,
+) -> Infallible {
+    loop {
+        let minutes = config.lock().await.rollback.as_ref().map(|r| r.minutes);
+        let Some(minutes) = minutes else {
+            ROLLBACK_CHANGED.wait().await;
+            continue;
+        };
+        warn!("Unconfirmed configuration, rolling back in {minutes} minutes");
+        let timeout = Timer::after_secs(u64::from(minutes) * 60);
+        if let Either::Second(()) = select(timeout, ROLLBACK_CHANGED.wait()).await {
+            continue;
+        }
+        let mut guard = config.lock().await;
+        let Some(rollback) = guard.rollback.take() else {
+            continue;
+        };
+        rollback.previous.restore(&mut guard);
+        if let Err(e) = platform.save_config(&guard).await {
+            // Left pending in flash; the next boot tries again.
+            error!("Cannot save rolled back configuration: {e}");
+        }
+        warn!("Configuration not confirmed, rolled back. Rebooting...");
+        platform.reset();
+    }
+}
+
+/// Discards the staged changes.
+///
+/// # Errors
+///
+/// Returns [`StagingError::NothingStaged`] without an open change set.
+pub async fn abort() -> Result<(), StagingError> {
+    STAGED
+        .lock()
+        .await
+        .take()
+        .ok_or(StagingError::NothingStaged)?;
+    debug!("Discarded staged configuration");
+    Ok(())
+}
@@ -0,0 +1,179 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! What the bridge lost, for the current session and since boot.
+//!
+//! Someone debugging a flaky capture needs to know whether bytes went
+//! missing on the device, and when. Counted are received bytes dropped
+//! because the UART receive buffer was full, input dropped because the
+//! target stopped taking it ([`TxFullPolicy::Drop`]), hardware FIFO
+//! overflows and line noise errors (from [`serial::line_stats`]), with the
+//! uptime of the last loss and the number of bridge sessions, so
+//! reconnects show too.
+//!
+//! `show stats` prints them; so does the `stats` subsystem, e.g.
+//! `ssh -s root@192.168.4.1 stats`, which needs no shell. Kept in RAM: a
+//! reboot starts over.
+//!
+//! [`TxFullPolicy::Drop`]: crate::config::TxFullPolicy::Drop
+
+use core::cell::RefCell;
+use core::fmt;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+
+use crate::serial;
+
+/// Loss counters, see the module docs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Losses {
+    /// Received bytes dropped, the receive buffer being full.
+    pub rx_dropped: u32,
+    /// Input bytes dropped, the target not taking them.
+    pub tx_dropped: u32,
+    /// Hardware receive FIFO overflows.
+    pub overflows: u32,
+    /// Glitch, framing and parity errors.
+    pub line_errors: u32,
+}
+
+impl Losses {
+    /// Whether anything was lost.
+    #[must_use]
+    pub fn any(&self) -> bool {
+        *self != Self::default()
+    }
+
+    fn since(&self, start: &Losses) -> Losses {
+        Losses {
+            rx_dropped: self.rx_dropped.wrapping_sub(start.rx_dropped),
+            tx_dropped: self.tx_dropped.wrapping_sub(start.tx_dropped),
+            overflows: self.overflows.wrapping_sub(start.overflows),
+            line_errors: self.line_errors.wrapping_sub(start.line_errors),
+        }
+    }
+}
+
+impl fmt::Display for Losses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rx dropped {} bytes, input dropped {} bytes, overflow {}, line errors {}",
+            self.rx_dropped, self.tx_dropped, self.overflows, self.line_errors
+        )
+    }
+}
+
+/// The counters at one moment, see [`snapshot`].
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub since_boot: Losses,
+    /// Since the current, or last, bridge session started; `None` before
+    /// the first.
+    pub session: Option<Losses>,
+    /// Uptime in seconds when that session started.
+    pub session_start_secs: u64,
+    /// Bridge sessions since boot, SSH and raw TCP.
+    pub sessions: u32,
+    /// Uptime in seconds of the last loss, if any.
+    pub last_loss_secs: Option<u64>,
+}
+
+struct Stats {
+    rx_dropped: u32,
+    tx_dropped: u32,
+    /// [`Stats::losses`] when the current session started.
+    at_session_start: Losses,
+    session_start_secs: u64,
+    sessions: u32,
+    last_loss_secs: Option<u64>,
+}
+
+impl Stats {
+    fn losses(&self) -> Losses {
+        let line = serial::line_stats();
+        Losses {
+            rx_dropped: self.rx_dropped,
+            tx_dropped: self.tx_dropped,
+            overflows: line.overflows,
+            line_errors: line
+                .glitches
+                .saturating_add(line.framing)
+                .saturating_add(line.parity),
+        }
+    }
+
+    fn loss(&mut self) {
+        self.last_loss_secs = Some(Instant::now().as_secs());
+    }
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<Stats>> = Mutex::new(RefCell::new(Stats {
+    rx_dropped: 0,
+    tx_dropped: 0,
+    at_session_start: Losses {
+        rx_dropped: 0,
+        tx_dropped: 0,
+        overflows: 0,
+        line_errors: 0,
+    },
+    session_start_secs: 0,
+    sessions: 0,
+    last_loss_secs: None,
+}));
+
+fn with_stats<R>(f: impl FnOnce(&mut Stats) -> R) -> R {
+    STATS.lock(|stats| f(&mut stats.borrow_mut()))
+}
+
+/// Starts the per-session counters, as a bridge session starts.
+pub fn session_started() {
+    with_stats(|stats| {
+        stats.at_session_start = stats.losses();
+        stats.session_start_secs = Instant::now().as_secs();
+        stats.sessions = stats.sessions.saturating_add(1);
+    });
+}
+
+/// Counts received bytes the bridge was told were dropped, see
+/// [`BufferedSerial::check_dropped_bytes`](serial::BufferedSerial::check_dropped_bytes).
+pub fn record_rx_dropped(bytes: usize) {
+    with_stats(|stats| {
+        let bytes = u32::try_from(bytes).unwrap_or(u32::MAX);
+        stats.rx_dropped = stats.rx_dropped.saturating_add(bytes);
+        stats.loss();
+    });
+}
+
+/// Counts input bytes dropped because the target did not take them.
+pub fn record_tx_dropped(bytes: usize) {
+    with_stats(|stats| {
+        let bytes = u32::try_from(bytes).unwrap_or(u32::MAX);
+        stats.tx_dropped = stats.tx_dropped.saturating_add(bytes);
+        stats.loss();
+    });
+}
+
+/// Notes the time of a receive error, counted by
+/// [`serial::record_line_error`].
+pub(crate) fn record_line_error() {
+    with_stats(Stats::loss);
+}
+
+/// The counters now.
+#[must_use]
+pub fn snapshot() -> Snapshot {
+    with_stats(|stats| {
+        let since_boot = stats.losses();
+        Snapshot {
+            since_boot,
+            session: (stats.sessions > 0).then(|| since_boot.since(&stats.at_session_start)),
+            session_start_secs: stats.session_start_secs,
+            sessions: stats.sessions,
+            last_loss_secs: stats.last_loss_secs,
+        }
+    })
+}
@@ -4,17 +4,40 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+//! The config in flash.
+//!
+//! The config, its hash, the flash wear counters, known clients, OTA
+//! history and the [`crate::outbox`] are kept together in one sector at [`CONFIG_OFFSET`] and
+//! rewritten as a whole on every save. Each save is read back. If the
+//! sector fails [`CONFIG_WRITE_ATTEMPTS`] times in a row, e.g. because it
+//! wore out, the config moves to the reserve sector at
+//! [`CONFIG_RESERVE_OFFSET`] for good and a [`Event::ConfigFailover`] is
+//! emitted. A marker at the end of the reserve sector, rewritten with
+//! every save there, tells the next boot which sector to load.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
 use embedded_storage::ReadStorage;
-use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 
 use pretty_hex::PrettyHex;
 use sha2::Digest;
 
-use log::{debug, error};
+use log::{debug, error, warn};
 
 use sunset::error::Error as SunsetError;
 
+use crate::clients::{self, KnownClients};
 use crate::config::{SSHStampConfig, UartPins};
+use crate::events::{self, Event};
+use crate::logging::Redacted;
+use crate::ota_history::{self, OtaAttempt, OtaHistory};
+use crate::outbox::{self, Outbox, Severity};
+use crate::resets::{ResetCounts, ResetReason};
+use crate::settings::CONFIG_WRITE_ATTEMPTS;
 
 use sunset::sshwire::{self, OwnOrBorrow};
 use sunset_sshwire_derive::{SSHDecode, SSHEncode};
@@ -24,6 +47,12 @@ pub const CONFIG_VERSION_SIZE: usize = 4;
 pub const CONFIG_HASH_SIZE: usize = 32;
 pub const CONFIG_AREA_SIZE: usize = 4096;
 pub const CONFIG_OFFSET: usize = 0x9000;
+/// The second sector of the `app_config` partition, used once the first
+/// keeps failing.
+pub const CONFIG_RESERVE_OFFSET: usize = CONFIG_OFFSET + CONFIG_AREA_SIZE;
+/// Written at the end of the reserve sector while it holds the config.
+const FAILOVER_MARK: [u8; 4] = *b"SSFO";
+const FAILOVER_MARK_OFFSET: usize = CONFIG_RESERVE_OFFSET + CONFIG_AREA_SIZE - FAILOVER_MARK.len();
 
 // SSHConfig::CURRENT_VERSION must be bumped if any of this struct
 #[derive(SSHEncode, SSHDecode)]
@@ -32,12 +61,88 @@ struct FlashConfig<'a> {
     config: OwnOrBorrow<'a, SSHStampConfig>,
     /// sha256 hash of config
     hash: [u8; 32],
+    stats: FlashStats,
+    clients: KnownClients,
+    ota_history: OtaHistory,
+    outbox: Outbox,
+}
+
+/// Flash wear counters, kept next to the config and carried over by every
+/// save. Lost if the config has to be recreated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, SSHEncode, SSHDecode)]
+pub struct FlashStats {
+    /// Times the config sector was erased, i.e. configs saved.
+    pub config_erases: u32,
+    /// Firmware images written to an OTA slot.
+    pub ota_writes: u32,
+    /// Recorded boots by reset reason, see [`record_reset`].
+    pub resets: ResetCounts,
+    /// `ota_writes` as of the last recorded boot; a difference means the
+    /// next software reset went into a new image.
+    pub boot_ota_writes: u32,
+    /// Boots of the last image written over OTA while it was unconfirmed,
+    /// see [`record_unconfirmed_boot`].
+    pub unconfirmed_boots: u8,
+}
+
+/// [`FlashStats`] plus figures for the current boot, see [`usage`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlashUsage {
+    pub stats: FlashStats,
+    /// Configs saved since boot.
+    pub saves_since_boot: u32,
+    /// Seconds since boot at the last save.
+    pub last_save_secs: Option<u64>,
+    /// The config lives in the reserve sector, the first one failed.
+    pub on_reserve: bool,
+}
+
+static USAGE: Mutex<CriticalSectionRawMutex, Cell<FlashUsage>> =
+    Mutex::new(Cell::new(FlashUsage {
+        stats: FlashStats {
+            config_erases: 0,
+            ota_writes: 0,
+            resets: ResetCounts::ZERO,
+            boot_ota_writes: 0,
+            unconfirmed_boots: 0,
+        },
+        saves_since_boot: 0,
+        last_save_secs: None,
+        on_reserve: false,
+    }));
+
+/// Flash wear counters as of the last load or save.
+#[must_use]
+pub fn usage() -> FlashUsage {
+    USAGE.lock(Cell::get)
 }
 
 impl FlashConfig<'_> {
-    const BUF_SIZE: usize = 460; // Must be enough to hold the whole config
+    /// All of the sector but the failover marker at its end.
+    const BUF_SIZE: usize = CONFIG_AREA_SIZE - FAILOVER_MARK.len();
+
+    /// Largest encoding, see [`SSHStampConfig::MAX_ENCODED_LEN`].
+    const MAX_ENCODED_LEN: usize = 1
+        + SSHStampConfig::MAX_ENCODED_LEN
+        + CONFIG_HASH_SIZE
+        + FlashStats::ENCODED_LEN
+        + KnownClients::MAX_ENCODED_LEN
+        + OtaHistory::MAX_ENCODED_LEN
+        + Outbox::MAX_ENCODED_LEN;
+}
+
+const _: () = assert!(
+    FlashConfig::MAX_ENCODED_LEN <= FlashConfig::BUF_SIZE,
+    "a full config does not fit its sector, see store.rs"
+);
+
+impl FlashStats {
+    const ENCODED_LEN: usize = 4 + 4 + ResetCounts::ENCODED_LEN + 4 + 1;
 }
 
+/// Largest backup [`write_backup`] produces, in bytes.
+pub const BACKUP_SIZE: usize = FlashConfig::MAX_ENCODED_LEN;
+
 fn config_hash(config: &SSHStampConfig) -> Result<[u8; 32], SunsetError> {
     let mut h = sha2::Sha256::new();
     sshwire::hash_ser(&mut h, config)?;
@@ -97,12 +202,13 @@ where
 {
     let c = SSHStampConfig::new(default_mac, default_uart_pins)?;
     save(flash, buf, &c)?;
-    debug!("Created new config: {:?}", &c);
+    debug!("Created new config: {:?}", Redacted(&c));
 
     Ok(c)
 }
 
-/// Loads `SSHStampConfig` from flash.
+/// Loads `SSHStampConfig` from flash, from the sector the failover marker
+/// names, or else from the other one.
 ///
 /// # Errors
 /// Returns an error if flash read fails, config is invalid, or hash mismatch.
@@ -110,44 +216,226 @@ pub fn load<F>(flash: &mut F, buf: &mut [u8]) -> Result<SSHStampConfig, SunsetEr
 where
     F: ReadStorage,
 {
-    // If at some point you target a 64bit arch these can truncate and cause
-    // corruption of the bootloader or the ota partition.
-    let offset =
-        u32::try_from(CONFIG_OFFSET).map_err(|_| SunsetError::msg("CONFIG_OFFSET overflow"))?;
+    let mut mark = [0u8; FAILOVER_MARK.len()];
+    let marked = flash_offset(FAILOVER_MARK_OFFSET)
+        .is_ok_and(|at| flash.read(at, &mut mark).is_ok() && mark == FAILOVER_MARK);
+    let (first, second) = if marked {
+        (CONFIG_RESERVE_OFFSET, CONFIG_OFFSET)
+    } else {
+        (CONFIG_OFFSET, CONFIG_RESERVE_OFFSET)
+    };
+    let (config, offset) = match load_from(flash, buf, first) {
+        Ok(config) => (config, first),
+        Err(e) => {
+            let config = load_from(flash, buf, second).map_err(|_| e)?;
+            warn!("Config at 0x{first:x} unreadable, loaded the one at 0x{second:x}");
+            (config, second)
+        }
+    };
+    USAGE.lock(|usage| {
+        usage.set(FlashUsage {
+            on_reserve: offset == CONFIG_RESERVE_OFFSET,
+            ..usage.get()
+        });
+    });
+    Ok(config)
+}
 
-    flash.read(offset, buf).map_err(|_e| {
-        error!("flash read error 0x{CONFIG_OFFSET:x}");
+// If at some point you target a 64bit arch these can truncate and cause
+// corruption of the bootloader or the ota partition.
+fn flash_offset(offset: usize) -> Result<u32, SunsetError> {
+    u32::try_from(offset).map_err(|_| SunsetError::msg("flash offset overflow"))
+}
+
+fn load_from<F>(flash: &mut F, buf: &mut [u8], offset: usize) -> Result<SSHStampConfig, SunsetError>
+where
+    F: ReadStorage,
+{
+    flash.read(flash_offset(offset)?, buf).map_err(|_e| {
+        error!("flash read error 0x{offset:x}");
         SunsetError::msg("flash error")
     })?;
 
     let flash_config: FlashConfig = sshwire::read_ssh(buf, None)
         .map_err(|_| SunsetError::msg("failed to decode flash config"))?;
+    let config = checked(flash_config.version, flash_config.config, flash_config.hash)?;
+
+    USAGE.lock(|usage| {
+        usage.set(FlashUsage {
+            stats: flash_config.stats,
+            ..usage.get()
+        });
+    });
+    clients::restore(flash_config.clients);
+    ota_history::restore(flash_config.ota_history);
+    outbox::restore(flash_config.outbox);
 
-    if flash_config.version != SSHStampConfig::CURRENT_VERSION {
-        error!("wrong config version on decode: {}", flash_config.version);
+    Ok(config)
+}
+
+/// The decoded config, if `version` is current and `hash` matches.
+fn checked(
+    version: u8,
+    config: OwnOrBorrow<'_, SSHStampConfig>,
+    hash: [u8; 32],
+) -> Result<SSHStampConfig, SunsetError> {
+    if version != SSHStampConfig::CURRENT_VERSION {
+        error!("wrong config version on decode: {version}");
         return Err(SunsetError::msg("wrong config version"));
     }
 
     // OwnOrBorrow::Own is the only variant that can be decoded from bytes
-    let config = match flash_config.config {
+    let config = match config {
         OwnOrBorrow::Own(c) => c,
         OwnOrBorrow::Borrow(_) => return Err(SunsetError::msg("unexpected borrowed config")),
     };
 
     let calc_hash = config_hash(&config)?;
 
-    if calc_hash != flash_config.hash {
+    if calc_hash != hash {
         return Err(SunsetError::msg("bad config hash"));
     }
 
     Ok(config)
 }
 
+/// Encodes `config` and the known clients into `buf` the way they are
+/// stored in flash, for `config backup`. Returns the length written.
+///
+/// # Errors
+/// Returns an error if `buf` is too small or serialization fails.
+pub fn write_backup(config: &SSHStampConfig, buf: &mut [u8]) -> Result<usize, SunsetError> {
+    let backup = FlashConfig {
+        version: SSHStampConfig::CURRENT_VERSION,
+        config: OwnOrBorrow::Borrow(config),
+        hash: config_hash(config)?,
+        stats: usage().stats,
+        clients: clients::snapshot(),
+        ota_history: ota_history::snapshot(),
+        outbox: outbox::snapshot(),
+    };
+    sshwire::write_ssh(buf, &backup)
+}
+
+/// Decodes a backup made by [`write_backup`], checking its version and
+/// hash. The flash wear counters, OTA history and outbox in it describe the
+/// device it came from and are dropped.
+///
+/// # Errors
+/// Returns an error if the backup is malformed, from another firmware
+/// version, or does not match its hash.
+pub fn read_backup(buf: &[u8]) -> Result<(SSHStampConfig, KnownClients), SunsetError> {
+    let backup: FlashConfig =
+        sshwire::read_ssh(buf, None).map_err(|_| SunsetError::msg("failed to decode backup"))?;
+    let config = checked(backup.version, backup.config, backup.hash)?;
+    Ok((config, backup.clients))
+}
+
 /// Saves `SSHStampConfig` to flash.
 ///
 /// # Errors
 /// Returns an error if flash write fails or config serialization fails.
 pub fn save<F>(flash: &mut F, buf: &mut [u8], config: &SSHStampConfig) -> Result<(), SunsetError>
+where
+    F: NorFlash,
+{
+    let mut stats = usage().stats;
+    stats.config_erases = stats.config_erases.saturating_add(1);
+    save_with_stats(flash, buf, config, stats)
+}
+
+/// Records a firmware image written to an OTA slot, rewriting the config in
+/// flash to persist the count and `attempt`, see [`crate::ota_history`].
+///
+/// # Errors
+/// Returns an error if the config cannot be loaded or saved.
+pub fn record_ota_write<F>(
+    flash: &mut F,
+    buf: &mut [u8],
+    attempt: OtaAttempt,
+) -> Result<(), SunsetError>
+where
+    F: NorFlash + ReadStorage,
+{
+    let config = load(flash, buf)?;
+    outbox::push(Severity::Notice, format_args!("OTA {attempt}"));
+    ota_history::push(attempt);
+    let mut stats = usage().stats;
+    stats.config_erases = stats.config_erases.saturating_add(1);
+    stats.ota_writes = stats.ota_writes.saturating_add(1);
+    stats.unconfirmed_boots = 0;
+    save_with_stats(flash, buf, &config, stats)
+}
+
+/// Records a failed firmware upload, rewriting the config in flash to
+/// persist `attempt`, see [`crate::ota_history`].
+///
+/// # Errors
+/// Returns an error if the config cannot be loaded or saved.
+pub fn record_ota_failure<F>(
+    flash: &mut F,
+    buf: &mut [u8],
+    attempt: OtaAttempt,
+) -> Result<(), SunsetError>
+where
+    F: NorFlash + ReadStorage,
+{
+    let config = load(flash, buf)?;
+    outbox::push(Severity::Fault, format_args!("OTA {attempt}"));
+    ota_history::push(attempt);
+    let mut stats = usage().stats;
+    stats.config_erases = stats.config_erases.saturating_add(1);
+    save_with_stats(flash, buf, &config, stats)
+}
+
+/// Counts a boot of a firmware image awaiting confirmation, rewriting the
+/// config in flash to persist it. Returns the boots counted, this one
+/// included. See [`crate::ota_window`].
+///
+/// # Errors
+/// Returns an error if the config cannot be loaded or saved.
+pub fn record_unconfirmed_boot<F>(flash: &mut F, buf: &mut [u8]) -> Result<u8, SunsetError>
+where
+    F: NorFlash + ReadStorage,
+{
+    let config = load(flash, buf)?;
+    let mut stats = usage().stats;
+    stats.config_erases = stats.config_erases.saturating_add(1);
+    stats.unconfirmed_boots = stats.unconfirmed_boots.saturating_add(1);
+    save_with_stats(flash, buf, &config, stats)?;
+    Ok(stats.unconfirmed_boots)
+}
+
+/// Counts this boot's reset `reason`, rewriting the config in flash to
+/// persist it. See [`crate::resets`] for when to call this.
+///
+/// # Errors
+/// Returns an error if the config cannot be loaded or saved.
+pub fn record_reset<F>(
+    flash: &mut F,
+    buf: &mut [u8],
+    reason: ResetReason,
+) -> Result<(), SunsetError>
+where
+    F: NorFlash + ReadStorage,
+{
+    let config = load(flash, buf)?;
+    if reason.is_fault() {
+        outbox::push(Severity::Fault, format_args!("reset: {reason}"));
+    }
+    let mut stats = usage().stats;
+    stats.config_erases = stats.config_erases.saturating_add(1);
+    stats.resets.add(reason);
+    stats.boot_ota_writes = stats.ota_writes;
+    save_with_stats(flash, buf, &config, stats)
+}
+
+fn save_with_stats<F>(
+    flash: &mut F,
+    buf: &mut [u8],
+    config: &SSHStampConfig,
+    stats: FlashStats,
+) -> Result<(), SunsetError>
 where
     F: NorFlash,
 {
@@ -155,36 +443,109 @@ where
         version: SSHStampConfig::CURRENT_VERSION,
         config: OwnOrBorrow::Borrow(config),
         hash: config_hash(config)?,
+        stats,
+        clients: clients::snapshot(),
+        ota_history: ota_history::snapshot(),
+        outbox: outbox::snapshot(),
     };
 
-    debug!("Before write_ssh, with hash: {}", &sc.hash.hex_dump());
+    debug!(
+        "Before write_ssh, with hash: {}",
+        Redacted(sc.hash.hex_dump())
+    );
     let l = sshwire::write_ssh(buf, &sc)?;
-    debug!("Saved flash (after write_ssh): {}", &buf[..l].hex_dump());
-
     debug!(
-        "CONFIG_OFFSET + FlashConfig::BUF_SIZE = {}",
-        CONFIG_OFFSET + FlashConfig::BUF_SIZE
+        "Saved flash (after write_ssh): {}",
+        Redacted(buf[..l].hex_dump())
     );
 
-    debug!("Erasing flash");
+    let on_reserve = usage().on_reserve;
+    let offset = if on_reserve {
+        CONFIG_RESERVE_OFFSET
+    } else {
+        CONFIG_OFFSET
+    };
+    // Not the whole buffer, which may span the sector and its marker.
+    let data = buf
+        .get(..FlashConfig::BUF_SIZE)
+        .filter(|_| l <= FlashConfig::BUF_SIZE)
+        .ok_or(SunsetError::msg("config too large"))?;
+    let mut written = Err(SunsetError::msg("flash write error"));
+    for attempt in 1..=CONFIG_WRITE_ATTEMPTS {
+        written = write_sector(flash, offset, data, on_reserve);
+        if written.is_ok() {
+            break;
+        }
+        warn!("Config save to 0x{offset:x} failed, attempt {attempt} of {CONFIG_WRITE_ATTEMPTS}");
+    }
+    let on_reserve = match written {
+        Ok(()) => on_reserve,
+        Err(e) if on_reserve => return Err(e),
+        Err(_) => {
+            write_sector(flash, CONFIG_RESERVE_OFFSET, data, true)?;
+            events::emit(Event::ConfigFailover {
+                from: CONFIG_OFFSET,
+                to: CONFIG_RESERVE_OFFSET,
+            });
+            true
+        }
+    };
 
-    const { assert!(CONFIG_AREA_SIZE > FlashConfig::BUF_SIZE) };
+    USAGE.lock(|usage| {
+        let current = usage.get();
+        usage.set(FlashUsage {
+            stats,
+            saves_since_boot: current.saves_since_boot.saturating_add(1),
+            last_save_secs: Some(Instant::now().as_secs()),
+            on_reserve,
+        });
+    });
+    clients::saved(&sc.clients);
+    outbox::saved(&sc.outbox);
 
-    let offset =
-        u32::try_from(CONFIG_OFFSET).map_err(|_| SunsetError::msg("CONFIG_OFFSET overflow"))?;
-    let area_size = u32::try_from(CONFIG_AREA_SIZE)
-        .map_err(|_| SunsetError::msg("CONFIG_AREA_SIZE overflow"))?;
+    debug!("flash save done");
+    Ok(())
+}
 
-    flash.erase(offset, offset + area_size).map_err(|_e| {
-        error!("flash erase error");
+/// Erases the config sector at `offset`, writes `data` to it and reads it
+/// back, as a worn sector may take a write without keeping it. `mark`
+/// also writes the failover marker.
+fn write_sector<F>(flash: &mut F, offset: usize, data: &[u8], mark: bool) -> Result<(), SunsetError>
+where
+    F: NorFlash,
+{
+    debug!("Erasing flash at 0x{offset:x}");
+    let start = flash_offset(offset)?;
+    let area_size = flash_offset(CONFIG_AREA_SIZE)?;
+    flash.erase(start, start + area_size).map_err(|_e| {
+        error!("flash erase error 0x{offset:x}");
         SunsetError::msg("flash erase error")
     })?;
 
-    flash.write(offset, buf).map_err(|_e| {
-        error!("flash write error");
+    flash.write(start, data).map_err(|_e| {
+        error!("flash write error 0x{offset:x}");
         SunsetError::msg("flash write error")
     })?;
+    if mark {
+        flash
+            .write(flash_offset(FAILOVER_MARK_OFFSET)?, &FAILOVER_MARK)
+            .map_err(|_e| {
+                error!("flash write error 0x{FAILOVER_MARK_OFFSET:x}");
+                SunsetError::msg("flash write error")
+            })?;
+    }
 
-    debug!("flash save done");
+    let mut check = [0u8; 64];
+    for (n, chunk) in data.chunks(check.len()).enumerate() {
+        let check = &mut check[..chunk.len()];
+        ReadNorFlash::read(flash, start + flash_offset(n * check.len())?, check).map_err(|_e| {
+            error!("flash read error 0x{offset:x}");
+            SunsetError::msg("flash error")
+        })?;
+        if check != chunk {
+            error!("flash verify error 0x{offset:x}");
+            return Err(SunsetError::msg("flash verify error"));
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,153 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The event log and counters as files for fleet collection over SFTP.
+//!
+//! Next to the version report, `sftp-ota` builds serve two read-only files
+//! that any SFTP client can fetch, so a collection script needs neither the
+//! admin commands nor their text output:
+//!
+//! - [`EVENTS_PATH`]: the [`crate::events`] log as JSON lines, oldest
+//!   first, see [`write_events`].
+//! - [`METRICS_PATH`]: uptime, reset and flash counts, UART statistics and
+//!   the outbox as one JSON object, see [`write_metrics`].
+//!
+//! Both are written when the SFTP session starts: fetching them again in
+//! the same session gives the same text, a new session fresh values. Keys
+//! are only ever added, so scripts should ignore those they do not know.
+
+use core::fmt::{self, Write as _};
+
+use embassy_time::Instant;
+use heapless::String;
+
+use crate::events;
+use crate::outbox;
+use crate::resets;
+use crate::serial;
+use crate::settings::{EVENTS_JSONL_LEN, METRICS_JSON_LEN};
+use crate::store;
+use crate::version::{JsonEscaped, json_str};
+
+/// Path of the event log.
+pub const EVENTS_PATH: &str = "/logs/events.jsonl";
+/// Path of the counters.
+pub const METRICS_PATH: &str = "/metrics.json";
+
+/// Writes one line per stored event, oldest first:
+/// `{"uptime_secs":..,"boot":..,"kind":"..","durable":..,"text":".."}`.
+/// `kind` is [`events::Event::name`], `text` what `show events` prints.
+///
+/// # Errors
+///
+/// Returns an error if `out` does not take all of it.
+pub fn write_events(out: &mut dyn fmt::Write) -> fmt::Result {
+    let boot = resets::boot_number();
+    let mut index = 0;
+    while let Some(record) = events::get(index) {
+        index += 1;
+        write!(
+            out,
+            "{{\"uptime_secs\":{},\"boot\":{boot},\"kind\":\"{}\",\"durable\":{},\"text\":\"",
+            record.uptime_secs,
+            record.event.name(),
+            record.event.is_durable()
+        )?;
+        write!(JsonEscaped(out), "{}", record.event)?;
+        out.write_str("\"}\n")?;
+    }
+    Ok(())
+}
+
+/// Writes the counters as one JSON object on one line:
+///
+/// - `uptime_secs`, `boot`, `last_reset` (`null` if unknown).
+/// - `resets`: recorded boots by reason, as in `show resets`.
+/// - `flash`: config erases and OTA writes over the device's life, saves
+///   since boot.
+/// - `uart`: receive errors by kind, bytes received and discarded.
+/// - `outbox`: queued and dropped entries, see [`crate::outbox`].
+///
+/// # Errors
+///
+/// Returns an error if `out` does not take all of it.
+pub fn write_metrics(out: &mut dyn fmt::Write) -> fmt::Result {
+    write!(
+        out,
+        "{{\"uptime_secs\":{},\"boot\":{},\"last_reset\":",
+        Instant::now().as_secs(),
+        resets::boot_number()
+    )?;
+    match resets::boot_reason() {
+        Some(reason) => json_str(out, reason.name())?,
+        None => out.write_str("null")?,
+    }
+    let usage = store::usage();
+    out.write_str(",\"resets\":")?;
+    for (i, (reason, count)) in usage.stats.resets.iter().into_iter().enumerate() {
+        out.write_str(if i == 0 { "{" } else { "," })?;
+        json_str(out, reason.name())?;
+        write!(out, ":{count}")?;
+    }
+    write!(
+        out,
+        "}},\"flash\":{{\"config_erases\":{},\"ota_writes\":{},\"saves_since_boot\":{}}}",
+        usage.stats.config_erases, usage.stats.ota_writes, usage.saves_since_boot
+    )?;
+    let stats = serial::line_stats();
+    write!(
+        out,
+        ",\"uart\":{{\"glitches\":{},\"framing\":{},\"parity\":{},\"overflows\":{},\
+         \"received_bytes\":{},\"discarded_bytes\":{}}}",
+        stats.glitches,
+        stats.framing,
+        stats.parity,
+        stats.overflows,
+        serial::received_bytes(),
+        stats.discarded_bytes
+    )?;
+    let outbox = outbox::snapshot();
+    writeln!(
+        out,
+        ",\"outbox\":{{\"pending\":{},\"dropped\":{}}}}}",
+        outbox.pending.len(),
+        outbox.dropped
+    )
+}
+
+/// The text of both files, kept out of the connection future in a static
+/// by the caller.
+pub struct Snapshot {
+    pub events: String<EVENTS_JSONL_LEN>,
+    pub metrics: String<METRICS_JSON_LEN>,
+}
+
+impl Snapshot {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            events: String::new(),
+            metrics: String::new(),
+        }
+    }
+
+    /// Writes both files afresh.
+    pub fn refresh(&mut self) {
+        self.events.clear();
+        self.metrics.clear();
+        // Truncated output is better than none; a truncated line is
+        // dropped so the rest still parses.
+        if write_events(&mut self.events).is_err() {
+            let whole = self.events.rfind('\n').map_or(0, |end| end + 1);
+            self.events.truncate(whole);
+        }
+        let _ = write_metrics(&mut self.metrics);
+    }
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,210 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Build metadata embedded in the image.
+//!
+//! Recorded by the build script and combined with the platform's own details
+//! (chip, HAL version, bootloader app descriptor) into the text shown by the
+//! `show version` admin command and served over SFTP as
+//! [`ota::VERSION_PATH`], so a remote unit's exact firmware can be
+//! identified.
+//!
+//! The same facts are kept as one [`BUILD_RECORD`] that build manifests are
+//! made from and checked against.
+//!
+//! `show build` gives host tooling the same facts as one JSON object, with
+//! the buffer sizes and protocol versions it may have to adapt to, see
+//! [`write_json`].
+
+use core::fmt::{self, Write as _};
+
+use heapless::String;
+
+use crate::config::SSHStampConfig;
+use crate::platform::PlatformServices;
+use crate::registry;
+use crate::settings::{COMMAND_LINE_LEN, TCP_BUFFER_SIZE, UART_BUFFER_SIZE};
+
+/// `ssh-stamp` crate version.
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Version of the `sunset` SSH library linked in.
+pub const SUNSET_VERSION: &str = env!("SSH_STAMP_SUNSET_VERSION");
+/// Abbreviated commit hash, `-dirty` if built with uncommitted changes.
+pub const GIT_HASH: &str = env!("SSH_STAMP_GIT_HASH");
+/// Build time in UTC (`SOURCE_DATE_EPOCH` if set).
+pub const BUILD_DATE: &str = env!("SSH_STAMP_BUILD_DATE");
+/// Enabled `ssh-stamp` cargo features, comma separated.
+pub const FEATURES: &str = env!("SSH_STAMP_FEATURES");
+
+/// The build facts above as `key: value` lines, kept in the image in one
+/// piece so `packer --manifest` can copy them from the app binary into the
+/// manifest, see [`ota::manifest`]. `manifest check` compares a manifest
+/// against them, see [`build_fact`].
+pub const BUILD_RECORD: &str = concat!(
+    "ssh-stamp build record\n",
+    "firmware: ",
+    env!("CARGO_PKG_VERSION"),
+    "\ngit: ",
+    env!("SSH_STAMP_GIT_HASH"),
+    "\nbuilt: ",
+    env!("SSH_STAMP_BUILD_DATE"),
+    "\nfeatures: ",
+    env!("SSH_STAMP_FEATURES"),
+    "\n\0"
+);
+
+const _: () = assert!(ota::manifest::is_build_record(BUILD_RECORD));
+
+/// The value of `key` in [`BUILD_RECORD`], if it records one.
+#[must_use]
+pub fn build_fact(key: &str) -> Option<&'static str> {
+    let lines = BUILD_RECORD
+        .strip_prefix(ota::manifest::BUILD_RECORD_MARKER)?
+        .trim_end_matches('\0');
+    ota::manifest::entries(lines).find_map(|(k, v)| (k == key).then_some(v))
+}
+
+/// Longest version text; the platform lines are truncated beyond it.
+pub const VERSION_TEXT_LEN: usize = 512;
+
+/// The version report, `key: value` lines ending in `\n`.
+pub type VersionText = String<VERSION_TEXT_LEN>;
+
+/// Writes the version report for `platform` to `out`.
+///
+/// # Errors
+///
+/// Returns an error if `out` does not take all of it.
+pub fn write<P: PlatformServices>(out: &mut dyn fmt::Write, platform: &P) -> fmt::Result {
+    writeln!(out, "ssh-stamp: {FIRMWARE_VERSION} ({GIT_HASH})")?;
+    writeln!(out, "built: {BUILD_DATE}")?;
+    writeln!(out, "features: {FEATURES}")?;
+    #[cfg(feature = "plugins")]
+    {
+        out.write_str("plugins:")?;
+        for name in crate::plugins::names() {
+            write!(out, " {name}")?;
+        }
+        out.write_char('\n')?;
+    }
+    writeln!(out, "sunset: {SUNSET_VERSION}")?;
+    platform.write_version(out)
+}
+
+/// The version report for `platform`.
+#[must_use]
+pub fn text<P: PlatformServices>(platform: &P) -> VersionText {
+    let mut text = VersionText::new();
+    // Truncated output is better than none.
+    let _ = write(&mut text, platform);
+    text
+}
+
+/// Longest `show build` output.
+pub const BUILD_INFO_LEN: usize = 1024;
+
+/// The `show build` JSON object.
+pub type BuildInfoText = String<BUILD_INFO_LEN>;
+
+/// Escapes the text written through it for the inside of a JSON string.
+pub(crate) struct JsonEscaped<'a>(pub(crate) &'a mut dyn fmt::Write);
+
+impl fmt::Write for JsonEscaped<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' | '\\' => write!(self.0, "\\{c}")?,
+                c if c.is_control() => write!(self.0, "\\u{:04x}", u32::from(c))?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a JSON string.
+pub(crate) fn json_str(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    JsonEscaped(out).write_str(s)?;
+    out.write_char('"')
+}
+
+/// Writes a JSON array of strings.
+fn json_list<'a>(out: &mut dyn fmt::Write, items: impl Iterator<Item = &'a str>) -> fmt::Result {
+    out.write_char('[')?;
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            out.write_char(',')?;
+        }
+        json_str(out, item)?;
+    }
+    out.write_char(']')
+}
+
+/// Writes the build details of `platform` as one JSON object on one line:
+///
+/// - `firmware`, `git`, `built`, `sunset`, `chip`: as in the version
+///   report.
+/// - `features`, `port_features`, `plugins`: arrays of names.
+/// - `buffers`: sizes in bytes by name, this crate's and the platform's.
+/// - `protocols`: the config schema, OTA image format and admin command
+///   versions, see [`SSHStampConfig::CURRENT_VERSION`],
+///   [`ota::tlv::OTA_FORMAT_VERSION`] and [`registry::PROTOCOL_VERSION`].
+///
+/// Keys are only ever added, so tools should ignore those they do not know.
+///
+/// # Errors
+///
+/// Returns an error if `out` does not take all of it.
+pub fn write_json<P: PlatformServices>(out: &mut dyn fmt::Write, platform: &P) -> fmt::Result {
+    let info = platform.build_info();
+    let strings = [
+        ("firmware", FIRMWARE_VERSION),
+        ("git", GIT_HASH),
+        ("built", BUILD_DATE),
+        ("sunset", SUNSET_VERSION),
+        ("chip", info.chip),
+    ];
+    for (i, (key, value)) in strings.into_iter().enumerate() {
+        out.write_str(if i == 0 { "{" } else { "," })?;
+        write!(out, "\"{key}\":")?;
+        json_str(out, value)?;
+    }
+    out.write_str(",\"features\":")?;
+    json_list(out, FEATURES.split(',').filter(|f| !f.is_empty()))?;
+    out.write_str(",\"port_features\":")?;
+    json_list(out, info.features.iter().copied())?;
+    out.write_str(",\"plugins\":")?;
+    #[cfg(feature = "plugins")]
+    json_list(out, crate::plugins::names())?;
+    #[cfg(not(feature = "plugins"))]
+    json_list(out, core::iter::empty())?;
+    let buffers = [
+        ("tcp", TCP_BUFFER_SIZE),
+        ("ssh_channel", UART_BUFFER_SIZE),
+        ("command_line", COMMAND_LINE_LEN),
+    ];
+    out.write_str(",\"buffers\":")?;
+    for (i, (name, size)) in buffers.iter().chain(info.buffers).enumerate() {
+        out.write_str(if i == 0 { "{" } else { "," })?;
+        json_str(out, name)?;
+        write!(out, ":{size}")?;
+    }
+    write!(
+        out,
+        "}},\"protocols\":{{\"config\":{},\"ota_format\":{},\"admin\":{}}}}}",
+        SSHStampConfig::CURRENT_VERSION,
+        ota::tlv::OTA_FORMAT_VERSION,
+        registry::PROTOCOL_VERSION
+    )
+}
+
+/// The `show build` JSON object for `platform`.
+#[must_use]
+pub fn build_info<P: PlatformServices>(platform: &P) -> BuildInfoText {
+    let mut text = BuildInfoText::new();
+    // Truncated output is better than none.
+    let _ = write_json(&mut text, platform);
+    text
+}
@@ -5,4 +5,21 @@
 
 fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
+
+    // Reported by `show version`.
+    println!("cargo:rerun-if-changed=../Cargo.lock");
+    let lock_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../Cargo.lock");
+    let lock = std::fs::read_to_string(&lock_path).unwrap_or_default();
+    let esp_hal_ver = lock
+        .split("[[package]]")
+        .find(|s| s.contains("name = \"esp-hal\""))
+        .and_then(|s| {
+            s.lines().find_map(|l| {
+                l.trim()
+                    .strip_prefix("version = ")
+                    .map(|v| v.trim_matches('"'))
+            })
+        })
+        .unwrap_or("unknown");
+    println!("cargo::rustc-env=ESP_HAL_VERSION={esp_hal_ver}");
 }
@@ -22,9 +22,38 @@
 //! | ESP32    | 13  | 14  |                                            |
 //! | ESP32-C2 | 18  | 19  | GPIO9 is a strapping pin; 18/19 avoid it   |
 //! | ESP32-C3 | 20  | 21  |                                            |
-//! | ESP32-C6 | 10  | 11  | Default (also used for S2/S3)              |
-//! | ESP32-S2 | 10  | 11  |                                            |
-//! | ESP32-S3 | 10  | 11  |                                            |
+//! | ESP32-C6 | 10  | 11  |                                            |
+//! | ESP32-S2 | 18  | 17  | The chip's default UART1 pins              |
+//! | ESP32-S3 | 18  | 17  | The chip's default UART1 pins              |
+//!
+//! These are the defaults for a new configuration. `SSH_STAMP_UART_PINS`
+//! moves the bridge to other GPIOs from the next boot; the choice is checked
+//! against the chip's pin table ([`ssh_stamp_esp32::pins`]) and against the
+//! pins below, and the defaults are used if it fails. Pins wired to the SPI
+//! flash or PSRAM, strapping pins and (for TX) input-only pins are refused,
+//! the defaults at build time.
+//!
+//! With the `rmt` feature, GPIO4 additionally carries the pulse output of the
+//! `pulse` admin command on every target except the ESP32-C2, which has no
+//! RMT peripheral.
+//!
+//! With the `mux` feature, three GPIOs drive the select lines of an external
+//! UART mux (line 0 first): GPIO25/26/27 on the ESP32, GPIO5/6/7 elsewhere.
+//!
+//! With the `uart-flow-control` feature, the UART also uses RTS/CTS hardware
+//! flow control, so a target that supports it pauses instead of overflowing
+//! the RX FIFO at high baud rates. Wire the target's RTS to CTS below and
+//! its CTS to RTS:
+//!
+//! | Target      | CTS | RTS |
+//! |-------------|-----|-----|
+//! | ESP32       | 32  | 33  |
+//! | ESP32-C2/C3 | 0   | 1   |
+//! | ESP32-C6    | 2   | 3   |
+//! | ESP32-S2/S3 | 15  | 16  |
+//!
+//! A target that does not drive its RTS line leaves CTS floating, so the
+//! stamp may never send; build without the feature for such targets.
 //!
 //! These are the only source of truth for pin numbers. Port binaries for
 //! other MCUs define their own assignments; no other file in this repository
@@ -36,19 +65,22 @@
 extern crate alloc;
 
 use embassy_executor::Spawner;
+use esp_hal::gpio::{AnyPin, Pin};
 use esp_hal::interrupt::{Priority, software::SoftwareInterruptControl};
 use esp_hal::rng::{Trng, TrngSource};
-use esp_println::logger;
 use esp_rtos::embassy::InterruptExecutor;
 use heapless::String;
-use log::{debug, error, warn};
+use log::{LevelFilter, Record, debug, error, info, warn};
+use ssh_stamp::app;
 use ssh_stamp::config::{SSHStampConfig, UartPins};
+use ssh_stamp::logging::{self, RingLogger};
 use ssh_stamp::platform::PlatformServices;
+use ssh_stamp::redact;
+use ssh_stamp::resets;
 use ssh_stamp::store;
-use ssh_stamp::{app, settings::DEFAULT_IP};
 use ssh_stamp_esp32::{
-    BufferedUart, EspPlatform, EspUartPins, EspWifi, UART_BUF, flash, mac_address,
-    register_custom_rng, uart_task,
+    BufferedUart, EspPlatform, EspUartPins, EspWifi, UART_BUF, flash, mac_address, memory, pins,
+    register_custom_rng, reset, uart_task,
 };
 use ssh_stamp_hal::{HalError, WifiError};
 use ssh_stamp_hal::{NetworkProviderHal, WifiHal};
@@ -61,21 +93,57 @@ cfg_if::cfg_if! {
     }
 }
 
+esp_bootloader_esp_idf::esp_app_desc!();
+
 static INT_EXECUTOR: StaticCell<InterruptExecutor<1>> = StaticCell::new(); // 0 is used for esp_rtos
 
+static LOGGER: RingLogger = RingLogger::new(console_log);
+
+fn console_log(record: &Record<'_>) {
+    esp_println::println!("{} - {}", record.level(), record.args());
+}
+
+/// `pin` as a flow control line in `uart-flow-control` builds, otherwise
+/// left alone.
+fn flow_pin<'a>(pin: impl Into<AnyPin<'a>>) -> Option<AnyPin<'a>> {
+    cfg!(feature = "uart-flow-control").then(|| pin.into())
+}
+
+fn console_mirror(bytes: &[u8]) {
+    esp_println::Printer::write_bytes(bytes);
+}
+
+/// Installs [`LOGGER`] at the `ESP_LOG` level given at build time.
+///
+/// Only a plain level is understood; per-module filters fall back to `info`.
+fn init_logger() {
+    let level = option_env!("ESP_LOG")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    // The safe setters need atomic CAS, which the ESP32-C2/C3 lack.
+    // SAFETY: called once at the top of `main`, before the scheduler or any
+    // interrupt handler that could log is running.
+    unsafe {
+        let _ = log::set_logger_racy(&LOGGER);
+        log::set_max_level_racy(level);
+    }
+}
+
 #[esp_rtos::main]
 async fn main(spawner: Spawner) -> ! {
+    // Sizes and the RAM budget they are checked against: see `memory.rs`.
     cfg_if::cfg_if!(
         if #[cfg(feature = "esp32s2")] {
-            // TODO: This heap size will crash at runtime (only for the ESP32S2);
+            // The reclaimed bootloader RAM is too small for the whole heap,
             // see https://github.com/brainstorm/ssh-stamp/pull/41#issuecomment-2964775170
-            esp_alloc::heap_allocator!(#[esp_hal::ram(reclaimed)] size: 72 * 1024);
+            esp_alloc::heap_allocator!(#[esp_hal::ram(reclaimed)] size: memory::RECLAIMED_HEAP_SIZE);
+            esp_alloc::heap_allocator!(size: memory::HEAP_SIZE);
         } else {
-            esp_alloc::heap_allocator!(size: 72 * 1024);
+            esp_alloc::heap_allocator!(size: memory::HEAP_SIZE);
         }
     );
-    esp_bootloader_esp_idf::esp_app_desc!();
-    logger::init_logger_from_env();
+    init_logger();
+    ssh_stamp_esp32::set_app_desc(&ESP_APP_DESC);
     debug!("HSM: initialising peripherals");
 
     let peripherals = esp_hal::init(esp_hal::Config::default());
@@ -94,47 +162,59 @@ async fn main(spawner: Spawner) -> ! {
 
     debug!("Initialising flash");
     flash::init(peripherals.FLASH);
+    reset::init(peripherals.LPWR);
 
-    #[cfg(feature = "sftp-ota")]
+    // GPIOs handed to other functions, which the bridge UART pins from the
+    // config must stay clear of.
+    let mut in_use: heapless::Vec<u8, 6> = heapless::Vec::new();
+
+    #[cfg(feature = "rmt")]
     {
-        use ssh_stamp_hal::OtaActions;
-        ssh_stamp_esp32::EspOtaWriter::try_validating_current_ota_partition()
-            .await
-            .expect("Failed to validate the current ota partition");
+        let pin: AnyPin<'static> = peripherals.GPIO4.into();
+        let _ = in_use.push(pin.number());
+        ssh_stamp_esp32::pulse::init(peripherals.RMT, pin);
     }
 
     // UART pin assignment — single source of truth for all ESP32 targets.
-    // The `cfg_if!` block selects per-target GPIO numbers that are used both
-    // for the hardware UART pins (EspUartPins) and for the config record
-    // (UartPins). No other file in the repository defines UART pin numbers.
+    // The `cfg_if!` block selects the per-target default GPIO numbers for a
+    // new config record (UartPins) and the flow control pins. No other file
+    // in the repository defines UART pin numbers.
     cfg_if::cfg_if!(
         if #[cfg(feature = "esp32")] {
-            let uart_pins = UartPins { rx: 13, tx: 14 };
-            let pins = EspUartPins {
-                rx: peripherals.GPIO13.into(),
-                tx: peripherals.GPIO14.into(),
-            };
+            const UART_PINS: UartPins = UartPins { rx: 13, tx: 14 };
+            let cts = flow_pin(peripherals.GPIO32);
+            let rts = flow_pin(peripherals.GPIO33);
         } else if #[cfg(feature = "esp32c2")] {
             // GPIO9 is a strapping pin - use GPIO18/19 instead to avoid boot interference
-            let uart_pins = UartPins { rx: 18, tx: 19 };
-            let pins = EspUartPins {
-                rx: peripherals.GPIO18.into(),
-                tx: peripherals.GPIO19.into(),
-            };
+            const UART_PINS: UartPins = UartPins { rx: 18, tx: 19 };
+            let cts = flow_pin(peripherals.GPIO0);
+            let rts = flow_pin(peripherals.GPIO1);
         } else if #[cfg(feature = "esp32c3")] {
-            let uart_pins = UartPins { rx: 20, tx: 21 };
-            let pins = EspUartPins {
-                rx: peripherals.GPIO20.into(),
-                tx: peripherals.GPIO21.into(),
-            };
+            const UART_PINS: UartPins = UartPins { rx: 20, tx: 21 };
+            let cts = flow_pin(peripherals.GPIO0);
+            let rts = flow_pin(peripherals.GPIO1);
+        } else if #[cfg(any(feature = "esp32s2", feature = "esp32s3"))] {
+            // The chip's default UART1 pins, clear of the flash and PSRAM
+            // lines of every module variant.
+            const UART_PINS: UartPins = UartPins { rx: 18, tx: 17 };
+            let cts = flow_pin(peripherals.GPIO15);
+            let rts = flow_pin(peripherals.GPIO16);
         } else {
-            let uart_pins = UartPins { rx: 10, tx: 11 };
-            let pins = EspUartPins {
-                rx: peripherals.GPIO10.into(),
-                tx: peripherals.GPIO11.into(),
-            };
+            const UART_PINS: UartPins = UartPins { rx: 10, tx: 11 };
+            let cts = flow_pin(peripherals.GPIO2);
+            let rts = flow_pin(peripherals.GPIO3);
         }
     );
+    const {
+        assert!(
+            pins::check_pin(UART_PINS.rx, false).is_ok()
+                && pins::check_pin(UART_PINS.tx, true).is_ok(),
+            "Default UART pins unusable on this chip"
+        );
+    };
+    for pin in [&cts, &rts].into_iter().flatten() {
+        let _ = in_use.push(pin.number());
+    }
 
     debug!("Loading config");
     let flash_config = {
@@ -143,9 +223,69 @@ async fn main(spawner: Spawner) -> ! {
         };
         let mut fb = flash_storage_guard.lock().await;
         let (flash_storage, buf) = fb.split_ref_mut();
-        store::load_or_create(flash_storage, buf, mac_address(), uart_pins)
+        store::load_or_create(flash_storage, buf, mac_address(), UART_PINS)
     }
     .expect("Could not load or create SSHStampConfig");
+    // After loading, so an update's reset can be told from others.
+    let reset_reason = resets::set_boot_reason(reset::reset_reason());
+    spawner.spawn(reset::record_reset(reset_reason).expect("record_reset spawn failed"));
+    #[cfg(feature = "sftp-ota")]
+    flash::check_ota_image(flash_config.ota_confirm)
+        .await
+        .expect("Failed to check the current ota partition");
+    let uart_config = flash_config.uart_config();
+    #[cfg(feature = "mux")]
+    {
+        let select = flash_config.selected_mux_target().map_or(0, |t| t.select);
+        cfg_if::cfg_if!(
+            if #[cfg(feature = "esp32")] {
+                let mux_pins: [AnyPin<'static>; 3] = [
+                    peripherals.GPIO25.into(),
+                    peripherals.GPIO26.into(),
+                    peripherals.GPIO27.into(),
+                ];
+            } else {
+                let mux_pins: [AnyPin<'static>; 3] = [
+                    peripherals.GPIO5.into(),
+                    peripherals.GPIO6.into(),
+                    peripherals.GPIO7.into(),
+                ];
+            }
+        );
+        for pin in &mux_pins {
+            let _ = in_use.push(pin.number());
+        }
+        ssh_stamp_esp32::mux::init(mux_pins, select);
+    }
+    let uart_pins = match pins::check_uart_pins(&flash_config.uart_pins, &in_use) {
+        Ok(()) => flash_config.uart_pins.clone(),
+        Err((gpio, problem)) => {
+            // Bad `SSH_STAMP_UART_PINS`, or a config restored from another
+            // board.
+            warn!(
+                "Config UART pin GPIO{gpio} {problem}, using RX {} TX {}",
+                UART_PINS.rx, UART_PINS.tx
+            );
+            UART_PINS
+        }
+    };
+    // SAFETY: the pin table vouches for both GPIOs, and nothing else takes
+    // them: they are not in `in_use` and the `peripherals` fields of these
+    // numbers are left alone.
+    let pins = EspUartPins {
+        rx: unsafe { AnyPin::steal(uart_pins.rx) },
+        tx: unsafe { AnyPin::steal(uart_pins.tx) },
+        cts,
+        rts,
+    };
+    if !flash_config.log_console {
+        info!("Log console disabled by config, use `show log` over SSH");
+    }
+    logging::set_console(flash_config.log_console);
+    logging::set_redact(flash_config.log_redact);
+    logging::set_mirror_writer(console_mirror);
+    logging::set_mirror(flash_config.console_mirror);
+    redact::set_patterns(&flash_config.uart_redact);
 
     static CONFIG: StaticCell<SunsetMutex<SSHStampConfig>> = StaticCell::new();
     let config: &'static SunsetMutex<SSHStampConfig> = CONFIG.init(SunsetMutex::new(flash_config));
@@ -179,17 +319,28 @@ async fn main(spawner: Spawner) -> ! {
             let interrupt_spawner = interrupt_executor.start(Priority::Priority10);
         }
     }
-    interrupt_spawner
-        .spawn(uart_task(uart_buf, peripherals.UART1, pins).expect("uart_task spawn failed"));
+    // The recovery image never bridges, leave the UART pins untouched.
+    #[cfg(not(feature = "recovery"))]
+    interrupt_spawner.spawn(
+        uart_task(uart_buf, peripherals.UART1, pins, uart_config).expect("uart_task spawn failed"),
+    );
+    #[cfg(feature = "recovery")]
+    let _ = (interrupt_spawner, pins, uart_config);
 
     debug!("Initialising radio");
 
+    #[cfg(feature = "ble-provisioning")]
+    spawner.spawn(
+        ssh_stamp_esp32::ble_provisioning(peripherals.BT, config)
+            .expect("ble_provisioning spawn failed"),
+    );
+
     let platform = EspPlatform::new();
     let ap_config = app::prepare_ap_config(config, &platform)
         .await
         .expect("Failed to prepare AP config");
 
-    let mut wifi = EspWifi::new(spawner, peripherals.WIFI, rng, DEFAULT_IP);
+    let mut wifi = EspWifi::new(spawner, peripherals.WIFI, rng);
     wifi.configure_ap(ap_config)
         .expect("Failed to configure AP");
 
@@ -208,7 +359,11 @@ async fn main(spawner: Spawner) -> ! {
         }
     }
 
-    if let Err(e) = app::run_app(stack.unwrap(), uart_buf, config, &platform).await {
+    let stacks = app::NetStacks {
+        primary: stack.unwrap(),
+        access_point: wifi.concurrent_ap_stack(),
+    };
+    if let Err(e) = app::run_app(stacks, uart_buf, config, &platform).await {
         error!("run_app exited with error: {e}");
     }
 
@@ -217,6 +372,6 @@ async fn main(spawner: Spawner) -> ! {
 }
 
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    loop {}
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    reset::reset_after_panic(info)
 }
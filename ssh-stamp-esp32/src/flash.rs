@@ -7,21 +7,34 @@
 
 //! Flash storage and OTA implementation for ESP32 family
 //!
-//! Provides access to flash storage for configuration persistence and firmware updates.
+//! Provides access to flash storage for configuration persistence, firmware updates and
+//! the scratch partition.
 
-use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 use esp_bootloader_esp_idf::ota::OtaImageState;
 use esp_bootloader_esp_idf::ota_updater::OtaUpdater;
-use esp_bootloader_esp_idf::partitions::PARTITION_TABLE_MAX_LEN;
+use esp_bootloader_esp_idf::partitions::{
+    self, AppPartitionSubType, DataPartitionSubType, FlashRegion, PARTITION_TABLE_MAX_LEN,
+    PartitionType,
+};
 use esp_hal::peripherals::FLASH;
 use esp_storage::FlashStorage;
-use log::{debug, error};
+use log::{debug, error, warn};
 use once_cell::sync::OnceCell;
-use ssh_stamp_hal::{FlashError, HalError, OtaActions};
+use ssh_stamp::config::OtaConfirm;
+use ssh_stamp::ota_history;
+use ssh_stamp::ota_window::{self, BootCheck};
+use ssh_stamp::store;
+use ssh_stamp_hal::{
+    ChipTarget, FlashError, HalError, OtaActions, OtaFailure, OtaUpload, ScratchActions,
+};
 use sunset_async::SunsetMutex;
 
 const FLASH_BUF_SIZE: usize = FlashStorage::SECTOR_SIZE as usize;
 
+/// First byte of an ESP application image.
+const APP_IMAGE_MAGIC: u8 = 0xE9;
+
 /// Flash storage singleton
 static FLASH_STORAGE: OnceCell<SunsetMutex<FlashBuffer<'static>>> = OnceCell::new();
 
@@ -62,6 +75,100 @@ pub fn get_flash_n_buffer() -> Option<&'static SunsetMutex<FlashBuffer<'static>>
     FLASH_STORAGE.get()
 }
 
+/// Erase `otadata` so the bootloader falls back to the factory (recovery)
+/// image on the next boot.
+///
+/// # Errors
+/// Refuses with `FlashError::PartitionNotFound` when the partition table has
+/// no factory app, so the device is never left without a bootable image.
+pub async fn select_factory_image() -> Result<(), HalError> {
+    let Some(fb) = get_flash_n_buffer() else {
+        error!("Flash storage not initialized");
+        return Err(HalError::Flash(FlashError::InternalError));
+    };
+    let mut fb = fb.lock().await;
+    let (storage, _) = fb.split_ref_mut();
+    erase_otadata(storage)
+}
+
+fn erase_otadata(storage: &mut FlashStorage<'_>) -> Result<(), HalError> {
+    let mut buff_table = [0u8; PARTITION_TABLE_MAX_LEN];
+
+    let table = partitions::read_partition_table(storage, &mut buff_table)
+        .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+
+    let factory = table
+        .find_partition(PartitionType::App(AppPartitionSubType::Factory))
+        .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+    if factory.is_none() {
+        error!("No factory partition holding a recovery image");
+        return Err(HalError::Flash(FlashError::PartitionNotFound));
+    }
+
+    let otadata = table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Ota))
+        .map_err(|_| HalError::Flash(FlashError::InternalError))?
+        .ok_or(HalError::Flash(FlashError::PartitionNotFound))?;
+
+    let len = otadata.len();
+    let mut region = otadata.as_embedded_storage(storage);
+    debug!("Erasing otadata ({len} bytes) to boot the factory image");
+    NorFlash::erase(&mut region, 0, len).map_err(|_| HalError::Flash(FlashError::Erase))?;
+
+    Ok(())
+}
+
+/// Deal with a firmware image booting unconfirmed, see
+/// [`ssh_stamp::ota_window`]: report its first boot, confirm it right away
+/// without a `window`, roll back once it is out of boots, otherwise start
+/// the countdown. Call after the config is loaded.
+///
+/// # Errors
+/// Returns an error if the image state cannot be read or written.
+pub async fn check_ota_image(window: OtaConfirm) -> Result<(), HalError> {
+    if !EspOtaWriter::is_current_ota_partition_pending().await? {
+        return Ok(());
+    }
+    // Written since the last recorded boot, so this is its first.
+    let stats = store::usage().stats;
+    if stats.ota_writes != stats.boot_ota_writes {
+        match EspOtaWriter::booted_slots().await {
+            Ok((from, to)) => ota_history::booted_update(from, to),
+            Err(e) => warn!("Cannot tell the OTA slots apart: {e}"),
+        }
+    }
+    let boot = if window.is_off() {
+        0
+    } else {
+        let Some(fb) = get_flash_n_buffer() else {
+            error!("Flash storage not initialized");
+            return Err(HalError::Flash(FlashError::InternalError));
+        };
+        let mut fb = fb.lock().await;
+        let (storage, buf) = fb.split_ref_mut();
+        store::record_unconfirmed_boot(storage, buf).unwrap_or_else(|e| {
+            // Counted in RAM only; a reset before the next save loses it.
+            warn!("Cannot record the unconfirmed boot: {e}");
+            store::usage().stats.unconfirmed_boots.saturating_add(1)
+        })
+    };
+    match ota_window::check_boot(window, boot) {
+        BootCheck::Confirm => EspOtaWriter::try_validating_current_ota_partition().await,
+        BootCheck::Wait => {
+            ota_window::begin(window, boot);
+            Ok(())
+        }
+        BootCheck::RollBack => {
+            warn!(
+                "Firmware not confirmed within {} boots, rolling back",
+                window.boots
+            );
+            EspOtaWriter::roll_back_current_ota_partition().await?;
+            esp_hal::system::software_reset()
+        }
+    }
+}
+
 /// OTA writer for ESP32
 #[derive(Debug, Copy, Clone)]
 pub struct EspOtaWriter {}
@@ -121,7 +228,64 @@ impl EspOtaWriter {
         Ok(())
     }
 
-    async fn activate_next_ota_slot() -> Result<(), HalError> {
+    async fn erase_target(offset: u32, len: u32) -> Result<(), HalError> {
+        let Some(fb) = get_flash_n_buffer() else {
+            error!("Flash storage not initialized");
+            return Err(HalError::Flash(FlashError::InternalError));
+        };
+        let mut fb = fb.lock().await;
+
+        let (storage, _) = fb.split_ref_mut();
+        let mut buff_ota = [0u8; PARTITION_TABLE_MAX_LEN];
+
+        let mut ota = OtaUpdater::new(storage, &mut buff_ota)
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+        let (mut target_partition, _) = ota
+            .next_partition()
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+
+        debug!("Erasing target_partition at offset {offset}, with len {len}");
+        NorFlash::erase(&mut target_partition, offset, offset + len)
+            .map_err(|_| HalError::Flash(FlashError::Erase))?;
+
+        Ok(())
+    }
+
+    /// The slot the device booted from before the running image and the
+    /// running image's slot, named as in the partition table.
+    async fn booted_slots() -> Result<(&'static str, &'static str), HalError> {
+        let Some(fb) = get_flash_n_buffer() else {
+            error!("Flash storage not initialized");
+            return Err(HalError::Flash(FlashError::InternalError));
+        };
+        let mut fb = fb.lock().await;
+
+        let (storage, _) = fb.split_ref_mut();
+        let mut buff_ota = [0u8; PARTITION_TABLE_MAX_LEN];
+
+        let mut ota = OtaUpdater::new(storage, &mut buff_ota)
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+        let running = ota
+            .selected_partition()
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+
+        // As for a rollback, the other slot holds the previous image unless
+        // this is the first update since the factory image was flashed.
+        let mut magic = [0u8; 4];
+        let (mut other, other_slot) = ota
+            .next_partition()
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+        ReadNorFlash::read(&mut other, 0, &mut magic)
+            .map_err(|_| HalError::Flash(FlashError::Read))?;
+        let previous = if magic[0] == APP_IMAGE_MAGIC {
+            slot_name(other_slot)
+        } else {
+            slot_name(AppPartitionSubType::Factory)
+        };
+        Ok((previous, slot_name(running)))
+    }
+
+    async fn activate_next_ota_slot(upload: &OtaUpload) -> Result<(), HalError> {
         let Some(fb) = get_flash_n_buffer() else {
             error!("Flash storage not initialized");
             return Err(HalError::Flash(FlashError::InternalError));
@@ -139,17 +303,61 @@ impl EspOtaWriter {
         ota.set_current_ota_state(OtaImageState::New)
             .map_err(|_| HalError::Flash(FlashError::Write))?;
 
+        let (storage, buf) = fb.split_ref_mut();
+        let attempt = ota_history::attempt(upload, None);
+        if let Err(e) = store::record_ota_write(storage, buf, attempt) {
+            // Only the wear counter and history are lost, the update itself
+            // went through.
+            warn!("Cannot record the OTA write: {e}");
+        }
+
         Ok(())
     }
 }
 
+/// The partition table's name for an app slot.
+fn slot_name(slot: AppPartitionSubType) -> &'static str {
+    match slot {
+        AppPartitionSubType::Factory => "factory",
+        AppPartitionSubType::Ota0 => "ota_0",
+        AppPartitionSubType::Ota1 => "ota_1",
+        _ => "ota",
+    }
+}
+
 impl Default for EspOtaWriter {
     fn default() -> Self {
         Self::new()
     }
 }
 
+// ESP-IDF image chip ID of the chip built for, see `ChipTarget`.
+cfg_if::cfg_if! {
+    if #[cfg(feature = "esp32")] {
+        const CHIP_FAMILY: u16 = 0;
+    } else if #[cfg(feature = "esp32c2")] {
+        const CHIP_FAMILY: u16 = 12;
+    } else if #[cfg(feature = "esp32c3")] {
+        const CHIP_FAMILY: u16 = 5;
+    } else if #[cfg(feature = "esp32s2")] {
+        const CHIP_FAMILY: u16 = 2;
+    } else if #[cfg(feature = "esp32s3")] {
+        const CHIP_FAMILY: u16 = 9;
+    } else {
+        const CHIP_FAMILY: u16 = 13;
+    }
+}
+
 impl OtaActions for EspOtaWriter {
+    fn running_chip() -> Option<ChipTarget> {
+        let major = u16::from(esp_hal::efuse::Efuse::major_chip_version());
+        let minor = u16::from(esp_hal::efuse::Efuse::minor_chip_version());
+        Some(ChipTarget {
+            family: CHIP_FAMILY,
+            revision: major * 100 + minor,
+        })
+    }
+
     async fn try_validating_current_ota_partition() -> Result<(), HalError> {
         let Some(fb) = get_flash_n_buffer() else {
             error!("Flash storage not initialized");
@@ -180,19 +388,161 @@ impl OtaActions for EspOtaWriter {
         Ok(())
     }
 
+    async fn is_current_ota_partition_pending() -> Result<bool, HalError> {
+        let Some(fb) = get_flash_n_buffer() else {
+            error!("Flash storage not initialized");
+            return Err(HalError::Flash(FlashError::InternalError));
+        };
+        let mut fb = fb.lock().await;
+
+        let (storage, _) = fb.split_ref_mut();
+        let mut buff_ota = [0u8; PARTITION_TABLE_MAX_LEN];
+
+        let mut ota = OtaUpdater::new(storage, &mut buff_ota)
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+        let state = ota
+            .current_ota_state()
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+        Ok(state == OtaImageState::New || state == OtaImageState::PendingVerify)
+    }
+
+    async fn roll_back_current_ota_partition() -> Result<(), HalError> {
+        let Some(fb) = get_flash_n_buffer() else {
+            error!("Flash storage not initialized");
+            return Err(HalError::Flash(FlashError::InternalError));
+        };
+        let mut fb = fb.lock().await;
+
+        let (storage, _) = fb.split_ref_mut();
+        let mut buff_ota = [0u8; PARTITION_TABLE_MAX_LEN];
+
+        let mut ota = OtaUpdater::new(storage, &mut buff_ota)
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+        ota.set_current_ota_state(OtaImageState::Invalid)
+            .map_err(|_| HalError::Flash(FlashError::Write))?;
+
+        // The other slot holds the previous image, unless this is the first
+        // update since the factory image was flashed.
+        let mut magic = [0u8; 4];
+        {
+            let (mut previous, _) = ota
+                .next_partition()
+                .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+            ReadNorFlash::read(&mut previous, 0, &mut magic)
+                .map_err(|_| HalError::Flash(FlashError::Read))?;
+        }
+        if magic[0] == APP_IMAGE_MAGIC {
+            debug!("Rolling back to the other OTA slot");
+            ota.activate_next_partition()
+                .map_err(|_| HalError::Flash(FlashError::Write))?;
+            ota.set_current_ota_state(OtaImageState::Valid)
+                .map_err(|_| HalError::Flash(FlashError::Write))?;
+            return Ok(());
+        }
+        drop(ota);
+
+        debug!("Other OTA slot empty, rolling back to the factory image");
+        let (storage, _) = fb.split_ref_mut();
+        erase_otadata(storage)
+    }
+
     async fn get_ota_partition_size() -> Result<u32, HalError> {
         Self::next_ota_size().await
     }
 
+    const ERASE_SIZE: u32 = FlashStorage::SECTOR_SIZE;
+
+    async fn erase_ota_data(&self, offset: u32, len: u32) -> Result<(), HalError> {
+        Self::erase_target(offset, len).await
+    }
+
     async fn write_ota_data(&self, offset: u32, data: &[u8]) -> Result<(), HalError> {
         Self::write_to_target(offset, data).await
     }
 
-    async fn finalize_ota_update(&mut self) -> Result<(), HalError> {
-        Self::activate_next_ota_slot().await
+    async fn finalize_ota_update(&mut self, upload: &OtaUpload) -> Result<(), HalError> {
+        Self::activate_next_ota_slot(upload).await
+    }
+
+    async fn record_failed_upload(&mut self, upload: &OtaUpload, failure: OtaFailure) {
+        let Some(fb) = get_flash_n_buffer() else {
+            error!("Flash storage not initialized");
+            return;
+        };
+        let mut fb = fb.lock().await;
+
+        let (storage, buf) = fb.split_ref_mut();
+        let attempt = ota_history::attempt(upload, Some(failure));
+        if let Err(e) = store::record_ota_failure(storage, buf, attempt) {
+            warn!("Cannot record the failed OTA upload: {e}");
+        }
     }
 
     fn reset_device(&self) -> ! {
         esp_hal::system::software_reset()
     }
 }
+
+/// The scratch partition, the `undefined` data partition of the partition table
+#[derive(Debug, Copy, Clone, Default)]
+pub struct EspScratch;
+
+impl EspScratch {
+    /// Run `f` on the scratch partition, holding the flash for the whole call.
+    async fn with_partition<R>(
+        f: impl FnOnce(&mut FlashRegion<'_, FlashStorage<'static>>) -> Result<R, HalError>,
+    ) -> Result<R, HalError> {
+        let Some(fb) = get_flash_n_buffer() else {
+            error!("Flash storage not initialized");
+            return Err(HalError::Flash(FlashError::InternalError));
+        };
+        let mut fb = fb.lock().await;
+
+        let (storage, _) = fb.split_ref_mut();
+        let mut buff_table = [0u8; PARTITION_TABLE_MAX_LEN];
+
+        let table = partitions::read_partition_table(storage, &mut buff_table)
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?;
+        let scratch = table
+            .find_partition(PartitionType::Data(DataPartitionSubType::Undefined))
+            .map_err(|_| HalError::Flash(FlashError::InternalError))?
+            .ok_or(HalError::Flash(FlashError::PartitionNotFound))?;
+
+        f(&mut scratch.as_embedded_storage(storage))
+    }
+}
+
+impl ScratchActions for EspScratch {
+    const ERASE_SIZE: u32 = FlashStorage::SECTOR_SIZE;
+
+    async fn scratch_size(&self) -> Result<u32, HalError> {
+        Self::with_partition(|region| {
+            u32::try_from(region.partition_size())
+                .map_err(|_| HalError::Flash(FlashError::InternalError))
+        })
+        .await
+    }
+
+    async fn read_scratch(&self, offset: u32, buf: &mut [u8]) -> Result<(), HalError> {
+        Self::with_partition(|region| {
+            ReadNorFlash::read(region, offset, buf).map_err(|_| HalError::Flash(FlashError::Read))
+        })
+        .await
+    }
+
+    async fn erase_scratch(&self, offset: u32, len: u32) -> Result<(), HalError> {
+        debug!("Erasing the scratch partition at offset {offset}, with len {len}");
+        Self::with_partition(|region| {
+            NorFlash::erase(region, offset, offset + len)
+                .map_err(|_| HalError::Flash(FlashError::Erase))
+        })
+        .await
+    }
+
+    async fn write_scratch(&self, offset: u32, data: &[u8]) -> Result<(), HalError> {
+        Self::with_partition(|region| {
+            NorFlash::write(region, offset, data).map_err(|_| HalError::Flash(FlashError::Write))
+        })
+        .await
+    }
+}
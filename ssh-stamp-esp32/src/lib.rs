@@ -16,18 +16,41 @@
 
 extern crate alloc;
 
+#[cfg(all(
+    feature = "ble-provisioning",
+    not(any(feature = "esp32c3", feature = "esp32c6", feature = "esp32s3"))
+))]
+compile_error!("The `ble-provisioning` feature needs an ESP32-C3, ESP32-C6 or ESP32-S3");
+
+#[cfg(all(feature = "rmt", feature = "esp32c2"))]
+compile_error!("The `rmt` feature is not available on the ESP32-C2 (no RMT peripheral)");
+
 pub mod flash;
 mod hash;
+pub mod memory;
+#[cfg(feature = "mux")]
+pub mod mux;
 mod network;
+pub mod pins;
 mod platform;
+#[cfg(feature = "rmt")]
+pub mod pulse;
+pub mod reset;
 mod rng;
 mod timer;
 mod uart;
 
-pub use flash::{EspOtaWriter, FlashBuffer, get_flash_n_buffer, init as flash_init};
+pub use flash::{EspOtaWriter, EspScratch, FlashBuffer, get_flash_n_buffer, init as flash_init};
 pub use hash::EspHmac;
+#[cfg(feature = "mux")]
+pub use mux::EspMux;
+#[cfg(feature = "ble-provisioning")]
+pub use network::ble_provisioning;
 pub use network::{EspWifi, accept_requests, dhcp_server, net_up, wifi_up};
-pub use platform::EspPlatform;
+pub use pins::is_reserved_pin;
+pub use platform::{EspPlatform, set_app_desc};
+#[cfg(feature = "rmt")]
+pub use pulse::EspPulse;
 pub use rng::{EspRng, register_custom_rng};
 pub use timer::EspTimer;
 pub use uart::{BufferedUart, EspUartPins, UART_BUF, UART_SIGNAL, uart_task};
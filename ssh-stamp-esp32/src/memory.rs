@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Per-chip RAM budget.
+//!
+//! The heap handed to `esp-alloc` (`WiFi` driver, sunset) and the large
+//! static buffers share the data RAM left over by the ROM, the radio
+//! libraries, the caches and the task stacks. [`RAM_BUDGET`] is the share
+//! this firmware allows itself on each chip; the build fails if the heap
+//! and the buffers below outgrow it, rather than the device crashing at
+//! boot. Buffer sizes that differ between chips or build profiles are
+//! chosen here too.
+
+use core::mem::size_of;
+
+use ssh_stamp::app::ConnectionBuffers;
+use ssh_stamp::logging::LOG_BUFFER_LEN;
+#[cfg(feature = "sftp-ota")]
+use ssh_stamp::settings::SFTP_SESSION_SLOTS;
+
+use crate::flash::FlashBuffer;
+use crate::uart::BufferedUart;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "esp32s2")] {
+        /// 320 KiB of SRAM, part of it taken by the caches.
+        pub const RAM_BUDGET: usize = 112 * 1024;
+        /// Heap placed in the RAM the second-stage bootloader used. Only
+        /// part of the heap fits there; a single 72 KiB region overran it.
+        pub const RECLAIMED_HEAP_SIZE: usize = 32 * 1024;
+        pub const HEAP_SIZE: usize = 40 * 1024;
+    } else if #[cfg(feature = "esp32c2")] {
+        /// 272 KiB of SRAM, 16 KiB of it cache.
+        pub const RAM_BUDGET: usize = 128 * 1024;
+        pub const RECLAIMED_HEAP_SIZE: usize = 0;
+        pub const HEAP_SIZE: usize = 72 * 1024;
+    } else if #[cfg(feature = "esp32")] {
+        /// 320 KiB of data RAM, much of it claimed by the radio.
+        pub const RAM_BUDGET: usize = 144 * 1024;
+        pub const RECLAIMED_HEAP_SIZE: usize = 0;
+        pub const HEAP_SIZE: usize = 72 * 1024;
+    } else if #[cfg(feature = "esp32c3")] {
+        /// 400 KiB of SRAM, 16 KiB of it cache.
+        pub const RAM_BUDGET: usize = 192 * 1024;
+        pub const RECLAIMED_HEAP_SIZE: usize = 0;
+        pub const HEAP_SIZE: usize = 72 * 1024;
+    } else {
+        /// ESP32-C6 and ESP32-S3: 512 KiB of SRAM.
+        pub const RAM_BUDGET: usize = 256 * 1024;
+        pub const RECLAIMED_HEAP_SIZE: usize = 0;
+        pub const HEAP_SIZE: usize = 72 * 1024;
+    }
+}
+
+// Bridge buffers of [`BufferedUart`]: UART to SSH (inward), SSH to UART
+// (outward), and the chunk moved per hardware transfer. Recovery images
+// have no bridge; small chips trade catch-up room for heap.
+cfg_if::cfg_if! {
+    if #[cfg(feature = "recovery")] {
+        pub const INWARD_BUF_SZ: usize = 64;
+        pub const OUTWARD_BUF_SZ: usize = 64;
+    } else if #[cfg(any(feature = "esp32c2", feature = "esp32s2"))] {
+        pub const INWARD_BUF_SZ: usize = 256;
+        pub const OUTWARD_BUF_SZ: usize = 128;
+    } else if #[cfg(any(feature = "esp32c6", feature = "esp32s3"))] {
+        pub const INWARD_BUF_SZ: usize = 1024;
+        pub const OUTWARD_BUF_SZ: usize = 256;
+    } else {
+        pub const INWARD_BUF_SZ: usize = 512;
+        pub const OUTWARD_BUF_SZ: usize = 256;
+    }
+}
+/// Bytes moved per UART driver call; half the smallest hardware FIFO.
+pub const UART_BUF_SZ: usize = 64;
+
+// A full chunk must fit into an empty pipe, or the RX path cannot make
+// room for it.
+const _: () = assert!(UART_BUF_SZ <= INWARD_BUF_SZ && UART_BUF_SZ <= OUTWARD_BUF_SZ);
+
+#[cfg(feature = "sftp-ota")]
+const SFTP_BUFFERS: usize = ota::SFTP_REQUEST_LEN * SFTP_SESSION_SLOTS;
+#[cfg(not(feature = "sftp-ota"))]
+const SFTP_BUFFERS: usize = 0;
+
+#[cfg(feature = "observers")]
+const OBSERVER_BUFFERS: usize = ssh_stamp::observers::OBSERVER_STATIC_LEN;
+#[cfg(not(feature = "observers"))]
+const OBSERVER_BUFFERS: usize = 0;
+
+#[cfg(feature = "raw-tcp")]
+const RAW_TCP_BUFFERS: usize = ssh_stamp::raw_tcp::RAW_STATIC_LEN;
+#[cfg(not(feature = "raw-tcp"))]
+const RAW_TCP_BUFFERS: usize = 0;
+
+/// Statically allocated buffers large enough to matter.
+pub const STATIC_BUFFERS: usize = size_of::<ConnectionBuffers>()
+    + SFTP_BUFFERS
+    + OBSERVER_BUFFERS
+    + RAW_TCP_BUFFERS
+    + size_of::<BufferedUart>()
+    + size_of::<FlashBuffer<'static>>()
+    + LOG_BUFFER_LEN;
+
+const _: () = assert!(
+    RECLAIMED_HEAP_SIZE + HEAP_SIZE + STATIC_BUFFERS <= RAM_BUDGET,
+    "heap and static buffers exceed this chip's RAM budget, see memory.rs"
+);
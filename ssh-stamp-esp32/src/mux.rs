@@ -0,0 +1,60 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! External UART mux select lines for ESP32 family, plain GPIO outputs.
+
+use esp_hal::gpio::{AnyPin, Level, Output, OutputConfig};
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
+use ssh_stamp_hal::{HalError, MUX_SELECT_LINES, MuxHal};
+use sunset_async::SunsetMutex;
+
+/// Mux select lines singleton, set up by [`init`].
+static MUX_OUT: OnceCell<SunsetMutex<EspMux>> = OnceCell::new();
+
+/// GPIO outputs driving the mux select lines, line 0 first.
+pub struct EspMux {
+    lines: [Output<'static>; MUX_SELECT_LINES],
+}
+
+impl EspMux {
+    /// Configure `pins` as outputs driven to `select`.
+    #[must_use]
+    pub fn new(pins: [AnyPin<'static>; MUX_SELECT_LINES], select: u8) -> Self {
+        let mut line = 0;
+        let lines = pins.map(|pin| {
+            let level = Level::from(select & (1 << line) != 0);
+            line += 1;
+            Output::new(pin, level, OutputConfig::default())
+        });
+        Self { lines }
+    }
+}
+
+impl MuxHal for EspMux {
+    fn select(&mut self, select: u8) -> Result<(), HalError> {
+        if select >> MUX_SELECT_LINES != 0 {
+            return Err(HalError::Mux);
+        }
+        debug!("Mux: select lines {select:#05b}");
+        for (n, line) in self.lines.iter_mut().enumerate() {
+            line.set_level(Level::from(select & (1 << n) != 0));
+        }
+        Ok(())
+    }
+}
+
+/// Initialize the mux select lines on `pins`, driven to `select` so the
+/// persisted target is routed before the bridge starts.
+pub fn init(pins: [AnyPin<'static>; MUX_SELECT_LINES], select: u8) {
+    let Ok(()) = MUX_OUT.set(SunsetMutex::new(EspMux::new(pins, select))) else {
+        warn!("Mux select lines already initialized");
+        return;
+    };
+}
+
+/// Get the mux select lines, if [`init`] has run.
+pub fn get_mux_out() -> Option<&'static SunsetMutex<EspMux>> {
+    MUX_OUT.get()
+}
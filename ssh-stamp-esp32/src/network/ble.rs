@@ -0,0 +1,185 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! BLE `WiFi` provisioning for ESP32 chips with Bluetooth (C3, C6, S3).
+//!
+//! A minimal custom GATT service, advertised under the access point SSID
+//! while the device is unprovisioned:
+//!
+//! | Characteristic | Access       | Content                                   |
+//! |----------------|--------------|-------------------------------------------|
+//! | SSID           | write        | station SSID, UTF-8, up to 32 bytes       |
+//! | PSK            | write        | station PSK, 8-63 bytes                   |
+//! | Apply          | write        | any value: validate, save and reboot      |
+//! | Status         | read, notify | 0 idle, 1 applied, 2 rejected             |
+//!
+//! The credentials go through [`ssh_stamp::provisioning`], which refuses
+//! them once an admin key has been installed over SSH.
+
+use embassy_futures::join::join;
+use embassy_time::{Duration, Timer};
+use esp_hal::peripherals::BT;
+use esp_radio::ble::controller::BleConnector;
+use log::{debug, info, warn};
+use ssh_stamp::config::SSHStampConfig;
+use ssh_stamp::platform::PlatformServices;
+use ssh_stamp::provisioning;
+use sunset_async::SunsetMutex;
+use trouble_host::prelude::*;
+
+use crate::EspPlatform;
+
+const STATUS_IDLE: u8 = 0;
+const STATUS_APPLIED: u8 = 1;
+const STATUS_REJECTED: u8 = 2;
+
+#[gatt_server]
+struct Server {
+    provisioning: ProvisioningService,
+}
+
+#[gatt_service(uuid = "5353480a-0000-4000-8000-73736873746d")]
+struct ProvisioningService {
+    #[characteristic(uuid = "5353480a-0001-4000-8000-73736873746d", write)]
+    ssid: heapless::Vec<u8, 32>,
+    #[characteristic(uuid = "5353480a-0002-4000-8000-73736873746d", write)]
+    psk: heapless::Vec<u8, 63>,
+    #[characteristic(uuid = "5353480a-0003-4000-8000-73736873746d", write)]
+    apply: u8,
+    #[characteristic(uuid = "5353480a-0004-4000-8000-73736873746d", read, notify, value = STATUS_IDLE)]
+    status: u8,
+}
+
+/// Advertises the provisioning service until credentials are applied, then
+/// resets into Station Mode. Returns at once on a provisioned device.
+#[embassy_executor::task]
+pub async fn ble_provisioning(bt: BT<'static>, config: &'static SunsetMutex<SSHStampConfig>) {
+    if !provisioning::is_open(config).await {
+        debug!("Device provisioned, BLE provisioning disabled");
+        return;
+    }
+    let name = config.lock().await.wifi_ap_ssid.clone();
+
+    let connector = match BleConnector::new(bt, Default::default()) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("BLE unavailable: {e:?}");
+            return;
+        }
+    };
+    let controller: ExternalController<_, 20> = ExternalController::new(connector);
+    let mut resources: HostResources<DefaultPacketPool, 1, 2> = HostResources::new();
+    let stack = trouble_host::new(controller, &mut resources);
+    let Host {
+        mut peripheral,
+        mut runner,
+        ..
+    } = stack.build();
+
+    let Ok(server) = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: name.as_str(),
+        appearance: &appearance::UNKNOWN,
+    })) else {
+        warn!("BLE GATT server setup failed");
+        return;
+    };
+
+    info!("BLE provisioning advertised as `{name}`");
+    let _ = join(runner.run(), async {
+        loop {
+            match advertise(name.as_str(), &mut peripheral, &server).await {
+                Ok(conn) => serve(&server, &conn, config).await,
+                Err(e) => {
+                    warn!("BLE advertising failed: {e:?}");
+                    Timer::after(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    })
+    .await;
+}
+
+async fn advertise<'v, 's, C: Controller>(
+    name: &'v str,
+    peripheral: &mut Peripheral<'v, C, DefaultPacketPool>,
+    server: &'s Server<'v>,
+) -> Result<GattConnection<'v, 's, DefaultPacketPool>, BleHostError<C::Error>> {
+    let mut adv_data = [0u8; 31];
+    let len = AdStructure::encode_slice(
+        &[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::CompleteLocalName(name.as_bytes()),
+        ],
+        &mut adv_data[..],
+    )?;
+    let advertiser = peripheral
+        .advertise(
+            &AdvertisementParameters::default(),
+            Advertisement::ConnectableScannableUndirected {
+                adv_data: &adv_data[..len],
+                scan_data: &[],
+            },
+        )
+        .await?;
+    let conn = advertiser.accept().await?.with_attribute_server(server)?;
+    Ok(conn)
+}
+
+async fn serve(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    config: &SunsetMutex<SSHStampConfig>,
+) {
+    let service = &server.provisioning;
+    let mut ssid = heapless::String::<32>::new();
+    let mut psk = heapless::String::<63>::new();
+
+    loop {
+        match conn.next().await {
+            GattConnectionEvent::Disconnected { .. } => {
+                debug!("BLE client disconnected");
+                return;
+            }
+            GattConnectionEvent::Gatt { event } => {
+                let mut applied = None;
+                if let GattEvent::Write(write) = &event {
+                    let data = core::str::from_utf8(write.data()).unwrap_or_default();
+                    if write.handle() == service.ssid.handle {
+                        ssid.clear();
+                        let _ = ssid.push_str(data);
+                    } else if write.handle() == service.psk.handle {
+                        psk.clear();
+                        let _ = psk.push_str(data);
+                    } else if write.handle() == service.apply.handle {
+                        let platform = EspPlatform::new();
+                        applied = Some(
+                            provisioning::set_station_credentials(config, &platform, &ssid, &psk)
+                                .await,
+                        );
+                    }
+                }
+                if let Ok(reply) = event.accept() {
+                    reply.send().await;
+                }
+                if let Some(result) = applied {
+                    let status = match result {
+                        Ok(()) => STATUS_APPLIED,
+                        Err(e) => {
+                            warn!("BLE provisioning rejected: {e}");
+                            STATUS_REJECTED
+                        }
+                    };
+                    let _ = service.status.notify(conn, &status).await;
+                    if status == STATUS_APPLIED {
+                        info!("Rebooting into Station Mode...");
+                        // Give the notification a chance to go out.
+                        Timer::after(Duration::from_millis(500)).await;
+                        EspPlatform::new().reset();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
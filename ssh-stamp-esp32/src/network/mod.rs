@@ -8,6 +8,10 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+#[cfg(feature = "ble-provisioning")]
+mod ble;
 mod wifi;
 
+#[cfg(feature = "ble-provisioning")]
+pub use ble::ble_provisioning;
 pub use wifi::{EspWifi, accept_requests, dhcp_server, net_up, wifi_up};
@@ -10,7 +10,7 @@
 
 //! `WiFi` implementation for ESP32 family.
 //!
-//! Wraps `esp-radio` AP-mode `WiFi` behind the generic [`NetworkProviderHal`]
+//! Wraps `esp-radio` `WiFi` (AP, STA or both at once) behind the generic [`NetworkProviderHal`]
 //! and [`WifiHal`] traits so the app layer never names ESP-specific types.
 
 use core::net::Ipv4Addr;
@@ -23,6 +23,7 @@ use edge_dhcp::server::{Server, ServerOptions};
 use edge_nal::UdpBind;
 use edge_nal_embassy::{Udp, UdpBuffers};
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_net::DhcpConfig;
 use embassy_net::tcp::TcpSocket;
 use embassy_net::{IpListenEndpoint, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
@@ -35,6 +36,9 @@ use esp_radio::wifi::{
 };
 use log::info;
 use log::{debug, error, warn};
+use ssh_stamp::config::{Ipv4Subnet, ap_address_beside};
+use ssh_stamp::events::{self, Event};
+use ssh_stamp::lockout;
 use ssh_stamp::settings::STATION_MODE_MAX_RETRY_SECONDS;
 use ssh_stamp_hal::{HalError, NetworkProviderHal, WifiApConfigStatic, WifiError, WifiHal};
 use static_cell::StaticCell;
@@ -52,22 +56,21 @@ pub struct EspWifi {
     wifi_peri: Option<WIFI<'static>>,
     rng: Rng,
     ap_config: Option<WifiApConfigStatic>,
-    gateway: Ipv4Addr,
+    concurrent_ap_stack: Option<Stack<'static>>,
 }
 
 impl EspWifi {
     /// Create a new uninitialised ESP32 `WiFi` handle.
     ///
-    /// `gateway` is the static IPv4 address the device will serve as the
-    /// access-point gateway (and DHCP server).
+    /// The access point address comes from [`WifiApConfigStatic::ap_address`].
     #[must_use]
-    pub fn new(spawner: Spawner, wifi_peri: WIFI<'static>, rng: Rng, gateway: Ipv4Addr) -> Self {
+    pub fn new(spawner: Spawner, wifi_peri: WIFI<'static>, rng: Rng) -> Self {
         Self {
             spawner,
             wifi_peri: Some(wifi_peri),
             rng,
             ap_config: None,
-            gateway,
+            concurrent_ap_stack: None,
         }
     }
 }
@@ -77,11 +80,72 @@ impl WifiHal for EspWifi {
         self.ap_config = Some(config);
         Ok(())
     }
+
+    fn concurrent_ap_stack(&self) -> Option<Stack<'static>> {
+        self.concurrent_ap_stack
+    }
+}
+
+fn access_point_config(config: &WifiApConfigStatic) -> AccessPointConfig {
+    AccessPointConfig::default()
+        .with_ssid(AllocString::from(config.ap_ssid.as_str()))
+        .with_auth_method(AuthenticationMethod::Wpa2Wpa3Personal)
+        .with_password(AllocString::from(config.ap_password.as_str()))
+}
+
+fn station_config(config: &WifiApConfigStatic) -> StationConfig {
+    StationConfig::default()
+        .with_ssid(AllocString::from(config.sta_ssid.as_str()))
+        .with_password(AllocString::from(config.sta_password.as_str()))
+}
+
+/// The access point address to use beside the station network `stack` is
+/// on, see [`ap_address_beside`]. Moving the access point is logged and
+/// recorded as an event.
+fn ap_address_beside_station(stack: Stack<'_>, configured: Ipv4Addr) -> Ipv4Addr {
+    let Some(v4) = stack.config_v4() else {
+        return configured;
+    };
+    let station = Ipv4Subnet {
+        addr: v4.address.address(),
+        prefix: v4.address.prefix_len(),
+    };
+    match ap_address_beside(configured, station) {
+        Some(address) if address == configured => configured,
+        Some(address) => {
+            warn!(
+                "Station network {} overlaps the access point at {configured}, using {address}",
+                v4.address
+            );
+            events::emit(Event::ApAddressMoved {
+                from: configured,
+                to: address,
+            });
+            address
+        }
+        None => {
+            warn!(
+                "Station network {} overlaps the access point and every fallback address",
+                v4.address
+            );
+            configured
+        }
+    }
+}
+
+fn access_point_net_config(gateway: Ipv4Addr) -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(gateway, 24),
+        gateway: Some(gateway),
+        // The embassy-net heapless version is different so `Default::default()` must be used here.
+        dns_servers: Default::default(),
+    })
 }
 
 impl NetworkProviderHal for EspWifi {
     async fn bring_up(&mut self) -> Result<Stack<'static>, HalError> {
         static RESOURCES_CELL: StaticCell<StackResources<3>> = StaticCell::new();
+        static AP_RESOURCES_CELL: StaticCell<StackResources<3>> = StaticCell::new();
         static STA_SSID_CELL: StaticCell<heapless::String<32>> = StaticCell::new();
 
         let ap_config = self
@@ -98,34 +162,27 @@ impl NetworkProviderHal for EspWifi {
             .map_err(|_| HalError::Wifi(WifiError::Initialization))?;
 
         let sta_ssid_static: &'static str = STA_SSID_CELL.init(ap_config.sta_ssid.clone()).as_str();
+        let concurrent = ap_config.concurrent && !sta_ssid_static.is_empty();
         let ap_radio_config;
         let net_config;
         let wifi_interface;
 
         if sta_ssid_static.is_empty() {
             info!("Wifi configuring Access Point Mode");
-            let password = AllocString::from(ap_config.ap_password.as_str());
-            ap_radio_config = RadioConfig::AccessPoint(
-                AccessPointConfig::default()
-                    .with_ssid(AllocString::from(ap_config.ap_ssid.as_str()))
-                    .with_auth_method(AuthenticationMethod::Wpa2Wpa3Personal)
-                    .with_password(password.clone()),
-            );
-            net_config = embassy_net::Config::ipv4_static(StaticConfigV4 {
-                address: Ipv4Cidr::new(self.gateway, 24),
-                gateway: Some(self.gateway),
-                // The embassy-net heapless version is different so `Default::default()` must be used here.
-                dns_servers: Default::default(),
-            });
+            ap_radio_config = RadioConfig::AccessPoint(access_point_config(&ap_config));
+            net_config = access_point_net_config(ap_config.ap_address);
             wifi_interface = Interface::access_point();
+        } else if concurrent {
+            info!("Wifi configuring Access Point + Station Mode");
+            ap_radio_config = RadioConfig::AccessPointStation(
+                station_config(&ap_config),
+                access_point_config(&ap_config),
+            );
+            net_config = embassy_net::Config::dhcpv4(DhcpConfig::default());
+            wifi_interface = Interface::station();
         } else {
             info!("Wifi configuring Station Mode");
-            let password = AllocString::from(ap_config.sta_password.as_str());
-            ap_radio_config = RadioConfig::Station(
-                StationConfig::default()
-                    .with_ssid(AllocString::from(ap_config.sta_ssid.as_str()))
-                    .with_password(password.clone()),
-            );
+            ap_radio_config = RadioConfig::Station(station_config(&ap_config));
             net_config = embassy_net::Config::dhcpv4(DhcpConfig::default());
             wifi_interface = Interface::station();
         }
@@ -151,10 +208,14 @@ impl NetworkProviderHal for EspWifi {
             .spawn(net_up(runner).map_err(|_| HalError::Wifi(WifiError::Initialization))?);
 
         if sta_ssid_static.is_empty() {
-            self.spawner.spawn(
-                dhcp_server(ap_stack, self.gateway)
-                    .map_err(|_| HalError::Wifi(WifiError::Initialization))?,
-            );
+            if ap_config.dhcp_server {
+                self.spawner.spawn(
+                    dhcp_server(ap_stack, ap_config.ap_address)
+                        .map_err(|_| HalError::Wifi(WifiError::Initialization))?,
+                );
+            } else {
+                info!("DHCP server disabled, clients need a static address");
+            }
             loop {
                 debug!("Checking if link is up");
                 if ap_stack.is_link_up() {
@@ -184,12 +245,48 @@ impl NetworkProviderHal for EspWifi {
                 }
                 retry_count += 1;
                 if retry_count > STATION_MODE_MAX_RETRY_SECONDS {
-                    return Err(HalError::Wifi(WifiError::StationMode));
+                    if !concurrent {
+                        return Err(HalError::Wifi(WifiError::StationMode));
+                    }
+                    // Keep the access point reachable for provisioning; the
+                    // station goes on trying in the background.
+                    warn!(
+                        "Station `{sta_ssid_static}` has no address yet, bringing the access point up anyway"
+                    );
+                    break;
                 }
                 Timer::after(Duration::from_millis(1000)).await;
             }
         }
 
+        if concurrent {
+            // The access point gets its own stack so both networks can reach
+            // the SSH server; see `WifiHal::concurrent_ap_stack`. It waits
+            // for the station's address so the two networks can be kept
+            // apart, and takes the configured address if none came.
+            let ap_address = ap_address_beside_station(ap_stack, ap_config.ap_address);
+            let (concurrent_ap_stack, ap_runner) = embassy_net::new(
+                Interface::access_point(),
+                access_point_net_config(ap_address),
+                AP_RESOURCES_CELL.init(StackResources::<3>::new()),
+                seed.wrapping_add(1),
+            );
+            self.spawner
+                .spawn(net_up(ap_runner).map_err(|_| HalError::Wifi(WifiError::Initialization))?);
+            if ap_config.dhcp_server {
+                self.spawner.spawn(
+                    dhcp_server(concurrent_ap_stack, ap_address)
+                        .map_err(|_| HalError::Wifi(WifiError::Initialization))?,
+                );
+            }
+            info!(
+                "Access point `{}` kept up alongside Station Mode with IP {}",
+                ap_config.ap_ssid.as_str(),
+                ap_address,
+            );
+            self.concurrent_ap_stack = Some(concurrent_ap_stack);
+        }
+
         Ok(ap_stack)
     }
 }
@@ -234,17 +331,23 @@ pub async fn wifi_up(mut wifi_controller: WifiController<'static>, sta_ssid: &'s
         // If the radio ever goes down (e.g. hardware fault), esp-radio
         // currently has no public event API to detect it.
         loop {
-            let ev = wifi_controller
-                .wait_for_access_point_connected_event_async()
-                .await;
+            let ev = select(
+                wifi_controller.wait_for_access_point_connected_event_async(),
+                lockout::wait_ap_pause(),
+            )
+            .await;
             match ev {
-                Ok(EventInfo::Connected(info)) => {
+                Either::First(Ok(EventInfo::Connected(info))) => {
                     info!("Station connected: {info:?}");
                 }
-                Ok(EventInfo::Disconnected(info)) => {
+                Either::First(Ok(EventInfo::Disconnected(info))) => {
                     info!("Station disconnected: {info:?}");
                 }
-                _ => (),
+                Either::First(_) => (),
+                Either::Second(secs) => {
+                    pause_access_point(&mut wifi_controller, secs).await;
+                    continue;
+                }
             }
             Timer::after(Duration::from_millis(5000)).await;
         }
@@ -271,8 +374,26 @@ pub async fn wifi_up(mut wifi_controller: WifiController<'static>, sta_ssid: &'s
     }
 }
 
+/// Takes the access point off the air for `secs`, see
+/// [`ssh_stamp::lockout`]. Only done in Access Point Mode: stopping the
+/// controller would take the station link down too.
+async fn pause_access_point(wifi_controller: &mut WifiController<'static>, secs: u64) {
+    if let Err(e) = wifi_controller.stop_async().await {
+        warn!("Cannot stop the access point: {e:?}");
+        return;
+    }
+    Timer::after_secs(secs).await;
+    if let Err(e) = wifi_controller.start_async().await {
+        error!("Cannot restart the access point: {e:?}");
+    } else {
+        info!("Access point back on the air");
+    }
+}
+
 /// Network task for Embassy executor.
-#[embassy_executor::task]
+///
+/// Two instances run in AP+STA mode, one per interface.
+#[embassy_executor::task(pool_size = 2)]
 pub async fn net_up(mut runner: Runner<'static, Interface>) {
     debug!("Bringing up network stack...");
     runner.run().await;
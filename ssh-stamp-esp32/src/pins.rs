@@ -0,0 +1,163 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! GPIO table of the ESP32 family.
+//!
+//! Tells which GPIO numbers exist on the target chip and which of them the
+//! bridge UART must keep away from, so the pins named in the configuration
+//! (`SSH_STAMP_UART_PINS`) can be checked at boot before they are taken.
+
+use core::fmt;
+
+use ssh_stamp::config::UartPins;
+
+/// Why a GPIO cannot carry a UART line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinProblem {
+    /// The chip has no such GPIO.
+    NoSuchPin,
+    /// Wired to the SPI flash or PSRAM, see [`is_reserved_pin`].
+    Reserved,
+    /// Sampled at reset to select the boot mode, see [`is_strapping_pin`].
+    Strapping,
+    /// Cannot drive an output, see [`is_input_only`].
+    InputOnly,
+    /// Already taken by another function of this build, or by the other
+    /// UART line.
+    InUse,
+}
+
+impl fmt::Display for PinProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PinProblem::NoSuchPin => "does not exist on this chip",
+            PinProblem::Reserved => "is wired to the flash or PSRAM",
+            PinProblem::Strapping => "is a strapping pin",
+            PinProblem::InputOnly => "is input only",
+            PinProblem::InUse => "is already in use",
+        })
+    }
+}
+
+/// Whether this chip has a GPIO numbered `gpio`.
+#[must_use]
+pub const fn is_gpio(gpio: u8) -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "esp32")] {
+            let exists = matches!(gpio, 0..=19 | 21..=23 | 25..=27 | 32..=39);
+        } else if #[cfg(feature = "esp32c2")] {
+            let exists = matches!(gpio, 0..=20);
+        } else if #[cfg(feature = "esp32c3")] {
+            let exists = matches!(gpio, 0..=21);
+        } else if #[cfg(feature = "esp32c6")] {
+            let exists = matches!(gpio, 0..=30);
+        } else if #[cfg(feature = "esp32s2")] {
+            let exists = matches!(gpio, 0..=21 | 26..=46);
+        } else {
+            let exists = matches!(gpio, 0..=21 | 26..=48);
+        }
+    }
+    exists
+}
+
+/// Whether `gpio` is wired to the SPI flash or PSRAM on this chip's
+/// modules. The bridge UART must not use these: taking one over stalls the
+/// next flash or PSRAM access and the chip resets.
+#[must_use]
+pub const fn is_reserved_pin(gpio: u8) -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "esp32")] {
+            // Flash on 6-11, PSRAM of WROVER modules on 16/17.
+            let reserved = matches!(gpio, 6..=11 | 16 | 17);
+        } else if #[cfg(any(feature = "esp32c2", feature = "esp32c3"))] {
+            let reserved = matches!(gpio, 12..=17);
+        } else if #[cfg(feature = "esp32c6")] {
+            let reserved = matches!(gpio, 24..=30);
+        } else if #[cfg(feature = "esp32s2")] {
+            let reserved = matches!(gpio, 26..=32);
+        } else {
+            // Octal flash and PSRAM take 33-37 as well.
+            let reserved = matches!(gpio, 26..=37);
+        }
+    }
+    reserved
+}
+
+/// Whether `gpio` is sampled at reset to select the boot mode or flash
+/// voltage. A target driving it while the stamp resets can keep the stamp
+/// in its ROM download mode.
+#[must_use]
+pub const fn is_strapping_pin(gpio: u8) -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "esp32")] {
+            let strapping = matches!(gpio, 0 | 2 | 5 | 12 | 15);
+        } else if #[cfg(feature = "esp32c2")] {
+            let strapping = matches!(gpio, 8 | 9);
+        } else if #[cfg(feature = "esp32c3")] {
+            let strapping = matches!(gpio, 2 | 8 | 9);
+        } else if #[cfg(feature = "esp32c6")] {
+            let strapping = matches!(gpio, 8 | 9 | 15);
+        } else if #[cfg(feature = "esp32s2")] {
+            let strapping = matches!(gpio, 0 | 45 | 46);
+        } else {
+            let strapping = matches!(gpio, 0 | 3 | 45 | 46);
+        }
+    }
+    strapping
+}
+
+/// Whether `gpio` has no output driver, so it can receive but not send.
+#[must_use]
+pub const fn is_input_only(gpio: u8) -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "esp32")] {
+            let input_only = matches!(gpio, 34..=39);
+        } else if #[cfg(feature = "esp32s2")] {
+            let input_only = gpio == 46;
+        } else {
+            let input_only = false;
+        }
+    }
+    input_only
+}
+
+/// Checks `gpio` for a UART line, an `output` one if the stamp drives it.
+///
+/// # Errors
+///
+/// Returns why the pin cannot be used.
+pub const fn check_pin(gpio: u8, output: bool) -> Result<(), PinProblem> {
+    if !is_gpio(gpio) {
+        Err(PinProblem::NoSuchPin)
+    } else if is_reserved_pin(gpio) {
+        Err(PinProblem::Reserved)
+    } else if is_strapping_pin(gpio) {
+        Err(PinProblem::Strapping)
+    } else if output && is_input_only(gpio) {
+        Err(PinProblem::InputOnly)
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks the bridge UART pins from the configuration. `in_use` lists the
+/// GPIOs this build has handed to other functions (flow control, mux
+/// select lines, pulse output).
+///
+/// # Errors
+///
+/// Returns the first unusable pin and why.
+pub fn check_uart_pins(pins: &UartPins, in_use: &[u8]) -> Result<(), (u8, PinProblem)> {
+    check_pin(pins.rx, false).map_err(|p| (pins.rx, p))?;
+    check_pin(pins.tx, true).map_err(|p| (pins.tx, p))?;
+    for gpio in [pins.rx, pins.tx] {
+        if in_use.contains(&gpio) {
+            return Err((gpio, PinProblem::InUse));
+        }
+    }
+    if pins.rx == pins.tx {
+        return Err((pins.tx, PinProblem::InUse));
+    }
+    Ok(())
+}
@@ -4,19 +4,71 @@
 
 //! ESP32 implementation of [`PlatformServices`].
 //!
-//! Wires the app layer's persistence, reset, OTA, and UART-activation hooks
-//! through to ESP-specific helpers (`flash::*`, `esp_hal::system`, the
-//! `UART_SIGNAL`).
+//! Wires the app layer's persistence, reset, power-off, OTA, scratch partition,
+//! UART-activation, pulse output and mux hooks through to ESP-specific helpers (`flash::*`,
+//! `esp_hal::system`, `reset::*`, the `UART_SIGNAL`, `pulse::*`, `mux::*`), and
+//! describes the chip and image for `show version` and `show build`.
 
+use core::fmt::{self, Write as _};
+
+use esp_bootloader_esp_idf::EspAppDesc;
+use once_cell::sync::OnceCell;
 use ssh_stamp::config::SSHStampConfig;
-use ssh_stamp::platform::PlatformServices;
+use ssh_stamp::platform::{BuildInfo, PlatformServices};
 use ssh_stamp::store;
+#[cfg(feature = "mux")]
+use ssh_stamp_hal::MuxHal;
 use ssh_stamp_hal::{FlashError, HalError};
+#[cfg(feature = "rmt")]
+use ssh_stamp_hal::{Pulse, PulseHal};
 
 use crate::EspOtaWriter;
+use crate::EspScratch;
 use crate::flash;
+use crate::memory::{HEAP_SIZE, INWARD_BUF_SZ, OUTWARD_BUF_SZ, UART_BUF_SZ};
+#[cfg(feature = "mux")]
+use crate::mux;
+#[cfg(feature = "rmt")]
+use crate::pulse;
+use crate::reset;
 use crate::uart::UART_SIGNAL;
 
+cfg_if::cfg_if! {
+    if #[cfg(feature = "esp32")] {
+        const CHIP: &str = "esp32";
+    } else if #[cfg(feature = "esp32c2")] {
+        const CHIP: &str = "esp32c2";
+    } else if #[cfg(feature = "esp32c3")] {
+        const CHIP: &str = "esp32c3";
+    } else if #[cfg(feature = "esp32s2")] {
+        const CHIP: &str = "esp32s2";
+    } else if #[cfg(feature = "esp32s3")] {
+        const CHIP: &str = "esp32s3";
+    } else {
+        const CHIP: &str = "esp32c6";
+    }
+}
+
+/// Features of this crate reported with the version.
+const PORT_FEATURES: &[&str] = match (
+    cfg!(feature = "ble-provisioning"),
+    cfg!(feature = "uart-flow-control"),
+) {
+    (true, true) => &["ble-provisioning", "uart-flow-control"],
+    (true, false) => &["ble-provisioning"],
+    (false, true) => &["uart-flow-control"],
+    (false, false) => &[],
+};
+
+/// Application descriptor of the running image, set by [`set_app_desc`].
+static APP_DESC: OnceCell<&'static EspAppDesc> = OnceCell::new();
+
+/// Register the image's application descriptor (the `ESP_APP_DESC` static
+/// from `esp_app_desc!`) so `show version` can report it.
+pub fn set_app_desc(desc: &'static EspAppDesc) {
+    let _ = APP_DESC.set(desc);
+}
+
 /// Zero-sized handle through which the app layer reaches ESP-only services.
 ///
 /// Construct once on the embassy executor and pass `&EspPlatform` to
@@ -38,6 +90,7 @@ impl Default for EspPlatform {
 
 impl PlatformServices for EspPlatform {
     type OtaWriter = EspOtaWriter;
+    type Scratch = EspScratch;
 
     async fn save_config(&self, config: &SSHStampConfig) -> Result<(), HalError> {
         let Some(flash_guard) = flash::get_flash_n_buffer() else {
@@ -52,11 +105,83 @@ impl PlatformServices for EspPlatform {
         esp_hal::system::software_reset()
     }
 
+    fn power_off(&self) -> ! {
+        reset::power_off()
+    }
+
+    async fn sync_flash(&self) {
+        // Writers hold the lock for the whole write.
+        if let Some(flash_guard) = flash::get_flash_n_buffer() {
+            drop(flash_guard.lock().await);
+        }
+    }
+
     fn ota_writer(&self) -> Self::OtaWriter {
         EspOtaWriter::new()
     }
 
+    fn scratch(&self) -> Self::Scratch {
+        EspScratch
+    }
+
     fn activate_uart(&self) {
         UART_SIGNAL.signal(1);
     }
+
+    async fn boot_recovery(&self) -> Result<(), HalError> {
+        flash::select_factory_image().await?;
+        esp_hal::system::software_reset()
+    }
+
+    #[cfg(feature = "rmt")]
+    async fn send_pulses(&self, pulses: &[Pulse]) -> Result<(), HalError> {
+        let Some(out) = pulse::get_pulse_out() else {
+            return Err(HalError::Pulse);
+        };
+        out.lock().await.transmit(pulses).await
+    }
+
+    #[cfg(feature = "mux")]
+    async fn select_mux(&self, select: u8) -> Result<(), HalError> {
+        let Some(out) = mux::get_mux_out() else {
+            return Err(HalError::Mux);
+        };
+        out.lock().await.select(select)
+    }
+
+    fn write_version(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "chip: {CHIP}")?;
+        writeln!(out, "esp-hal: {}", env!("ESP_HAL_VERSION"))?;
+        if let [first, rest @ ..] = PORT_FEATURES {
+            write!(out, "port features: {first}")?;
+            for feature in rest {
+                write!(out, ",{feature}")?;
+            }
+            writeln!(out)?;
+        }
+        if let Some(desc) = APP_DESC.get() {
+            writeln!(out, "app: {} {}", desc.project_name(), desc.version())?;
+            writeln!(out, "app date: {} {}", desc.date(), desc.time())?;
+            writeln!(out, "esp-idf: {}", desc.idf_ver())?;
+            write!(out, "elf sha256: ")?;
+            for b in desc.app_elf_sha256() {
+                write!(out, "{b:02x}")?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn build_info(&self) -> BuildInfo {
+        BuildInfo {
+            chip: CHIP,
+            features: PORT_FEATURES,
+            buffers: &[
+                ("uart_inward", INWARD_BUF_SZ),
+                ("uart_outward", OUTWARD_BUF_SZ),
+                ("uart_chunk", UART_BUF_SZ),
+                ("heap", HEAP_SIZE),
+            ],
+        }
+    }
 }
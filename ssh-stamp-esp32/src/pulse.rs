@@ -0,0 +1,103 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Pulse output for ESP32 family, backed by the RMT peripheral.
+//!
+//! RMT channel 0 runs at 1 MHz (80 MHz source, divider 80), so each
+//! [`Pulse`] maps one-to-one onto an RMT half-code in microseconds.
+
+use esp_hal::Async;
+use esp_hal::gpio::{AnyPin, Level};
+use esp_hal::peripherals::RMT;
+use esp_hal::rmt::{Channel, PulseCode, Rmt, Tx, TxChannelConfig, TxChannelCreator};
+use esp_hal::time::Rate;
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
+use ssh_stamp_hal::{HalError, MAX_PULSE_MICROS, MAX_PULSES, Pulse, PulseHal};
+use sunset_async::SunsetMutex;
+
+/// Pulse output singleton, set up by [`init`].
+static PULSE_OUT: OnceCell<SunsetMutex<EspPulse>> = OnceCell::new();
+
+/// RMT transmit channel driving the pulse output pin.
+pub struct EspPulse {
+    channel: Channel<'static, Async, Tx>,
+}
+
+impl EspPulse {
+    /// Configure RMT channel 0 as a transmitter on `pin`, idling low.
+    ///
+    /// # Errors
+    /// Returns `HalError::Pulse` if the RMT peripheral cannot be configured.
+    pub fn new(rmt: RMT<'static>, pin: AnyPin<'static>) -> Result<Self, HalError> {
+        let rmt = Rmt::new(rmt, Rate::from_mhz(80))
+            .map_err(|_| HalError::Pulse)?
+            .into_async();
+        let config = TxChannelConfig::default()
+            .with_clk_divider(80)
+            .with_idle_output(true)
+            .with_idle_output_level(Level::Low);
+        let channel = rmt
+            .channel0
+            .configure_tx(pin, config)
+            .map_err(|_| HalError::Pulse)?;
+        Ok(Self { channel })
+    }
+}
+
+fn level(high: bool) -> Level {
+    if high { Level::High } else { Level::Low }
+}
+
+impl PulseHal for EspPulse {
+    async fn transmit(&mut self, pulses: &[Pulse]) -> Result<(), HalError> {
+        if pulses.is_empty()
+            || pulses.len() > MAX_PULSES
+            || pulses
+                .iter()
+                .any(|p| p.micros == 0 || p.micros > MAX_PULSE_MICROS)
+        {
+            return Err(HalError::Pulse);
+        }
+
+        // Two pulses per code, plus the end marker.
+        let mut codes = heapless::Vec::<PulseCode, { MAX_PULSES / 2 + 1 }>::new();
+        for pair in pulses.chunks(2) {
+            let code = match pair {
+                [a, b] => PulseCode::new(level(a.high), a.micros, level(b.high), b.micros),
+                // A zero length second half doubles as the end marker.
+                [a] => PulseCode::new(level(a.high), a.micros, Level::Low, 0),
+                _ => unreachable!(),
+            };
+            let _ = codes.push(code);
+        }
+        if pulses.len() % 2 == 0 {
+            let _ = codes.push(PulseCode::end_marker());
+        }
+
+        debug!("RMT: sending {} pulses", pulses.len());
+        self.channel.transmit(&codes).await.map_err(|e| {
+            warn!("RMT transmit failed: {e:?}");
+            HalError::Pulse
+        })
+    }
+}
+
+/// Initialize the pulse output on `pin`.
+pub fn init(rmt: RMT<'static>, pin: AnyPin<'static>) {
+    match EspPulse::new(rmt, pin) {
+        Ok(pulse) => {
+            let Ok(()) = PULSE_OUT.set(SunsetMutex::new(pulse)) else {
+                warn!("Pulse output already initialized");
+                return;
+            };
+        }
+        Err(e) => warn!("Pulse output unavailable: {e}"),
+    }
+}
+
+/// Get the pulse output, if [`init`] succeeded.
+pub fn get_pulse_out() -> Option<&'static SunsetMutex<EspPulse>> {
+    PULSE_OUT.get()
+}
@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Reset cause from the RTC controller, see [`ssh_stamp::resets`], and
+//! powering off for the `shutdown` command.
+//!
+//! Deliberate resets and the panic handler both go through
+//! `software_reset`. The panic handler leaves a marker in RTC fast memory
+//! first, which survives the reset, so the two can be told apart on the
+//! next boot.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Timer;
+use esp_hal::peripherals::LPWR;
+use esp_hal::ram;
+use esp_hal::rtc_cntl::Rtc;
+use log::warn;
+use ssh_stamp::resets::{RESET_RECORD_DELAY_SECS, ResetReason};
+use ssh_stamp::store;
+
+use crate::flash;
+
+/// Set to [`PANICKED`] by [`reset_after_panic`], read and cleared by
+/// [`reset_reason`]. Zeroed at power-on only.
+#[ram(unstable(rtc_fast, persistent))]
+static mut PANIC_MARKER: u32 = 0;
+
+const PANICKED: u32 = 0x5041_4e43;
+
+/// RTC controller handed over by [`init`], taken by [`power_off`].
+static RTC: Mutex<CriticalSectionRawMutex, RefCell<Option<Rtc<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Keep the RTC controller for [`power_off`].
+pub fn init(lpwr: LPWR<'static>) {
+    RTC.lock(|rtc| rtc.replace(Some(Rtc::new(lpwr))));
+}
+
+/// The chip's reset cause, mapped to a [`ResetReason`]. A software reset
+/// is a [`ResetReason::Panic`] if [`reset_after_panic`] left its marker.
+/// Clears the marker, so call it once per boot.
+///
+/// Matched on the raw cause codes, which are shared across the family while
+/// the `SocResetReason` variant names are not.
+#[must_use]
+pub fn reset_reason() -> ResetReason {
+    // SAFETY: only touched here and in the panic handler, never at once.
+    let panicked = unsafe {
+        let marker = (&raw mut PANIC_MARKER).read_volatile();
+        (&raw mut PANIC_MARKER).write_volatile(0);
+        marker == PANICKED
+    };
+    let Some(reason) = esp_hal::system::reset_reason() else {
+        return ResetReason::Other;
+    };
+    match reason as u32 {
+        0x01 => ResetReason::PowerOn,
+        0x03 | 0x0C if panicked => ResetReason::Panic,
+        0x03 | 0x0C => ResetReason::Software,
+        // Main system, RTC and super watchdogs, at core, CPU or system level
+        0x07..=0x09 | 0x0B | 0x0D | 0x10..=0x12 => ResetReason::Watchdog,
+        // Brown-out, and the power glitch detector of the C3 and S3
+        0x0F | 0x17 => ResetReason::BrownOut,
+        _ => ResetReason::Other,
+    }
+}
+
+/// Prints `info` to the console, marks the reset as a panic and resets,
+/// see the module docs.
+pub fn reset_after_panic(info: &core::panic::PanicInfo) -> ! {
+    esp_println::println!("{info}");
+    // SAFETY: single writer, the panicking core; see `reset_reason`.
+    unsafe { (&raw mut PANIC_MARKER).write_volatile(PANICKED) };
+    esp_hal::system::software_reset()
+}
+
+/// Enters deep sleep with no wake-up source: the chip stays off until it is
+/// power cycled or its enable pin is pulled low. Without [`init`], halts
+/// the CPU instead.
+pub fn power_off() -> ! {
+    if let Some(mut rtc) = RTC.lock(RefCell::take) {
+        rtc.sleep_deep(&[]);
+    }
+    warn!("RTC controller not initialized, halting instead of powering off");
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Counts `reason` in flash once the boot has lasted
+/// [`RESET_RECORD_DELAY_SECS`].
+#[embassy_executor::task]
+pub async fn record_reset(reason: ResetReason) {
+    Timer::after_secs(RESET_RECORD_DELAY_SECS).await;
+    let Some(fb) = flash::get_flash_n_buffer() else {
+        warn!("Flash storage not initialized, reset not recorded");
+        return;
+    };
+    let mut fb = fb.lock().await;
+    let (storage, buf) = fb.split_ref_mut();
+    if let Err(e) = store::record_reset(storage, buf, reason) {
+        warn!("Cannot record the reset reason: {e}");
+    }
+}
@@ -15,28 +15,56 @@
 //! same UART from two futures (TX and RX) concurrently because both sides
 //! take `&self`.
 
-use core::future::Future;
-
+use embassy_futures::select::{Either, select};
 use embassy_sync::pipe::TryWriteError;
 use embassy_sync::signal::Signal;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
-use esp_hal::Async;
-use esp_hal::gpio::AnyPin;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_hal::gpio::{AnyPin, Pin};
+#[cfg(feature = "tx-sense")]
+use esp_hal::gpio::{Input, InputConfig, Pull};
 use esp_hal::peripherals::UART1;
-use esp_hal::uart::{Config, RxConfig, Uart};
-use portable_atomic::{AtomicUsize, Ordering};
-use ssh_stamp::serial::BufferedSerial;
+use esp_hal::uart::{
+    Config, ConfigError, CtsConfig, DataBits, HwFlowControl, Parity, RtsConfig, RxConfig, RxError,
+    StopBits, Uart, UartRx,
+};
+use esp_hal::{Async, Blocking};
+use log::{debug, error, warn};
+use portable_atomic::{AtomicBool, AtomicUsize, Ordering};
+use ssh_stamp::serial::{self, BufferedSerial, LineError};
+#[cfg(feature = "tx-sense")]
+use ssh_stamp::settings::{UART_PIN_SENSE_INTERVAL_US, UART_PIN_SENSE_MS};
+use ssh_stamp::settings::{
+    UART_RECONFIGURE_TIMEOUT_MS, UART_RESYNC_MAX_MS, UART_RESYNC_QUIET_MS, UART_STUCK_POLL_MS,
+};
+use ssh_stamp_hal::{UartConfig, UartFrame};
 use static_cell::StaticCell;
 
-const INWARD_BUF_SZ: usize = 512;
-const OUTWARD_BUF_SZ: usize = 256;
-const UART_BUF_SZ: usize = 64;
+use crate::memory::{INWARD_BUF_SZ, OUTWARD_BUF_SZ, UART_BUF_SZ};
+use crate::pins::is_reserved_pin;
+
+/// RX FIFO fill level at which RTS tells the target to pause, with
+/// hardware flow control. Above the highest RX FIFO threshold a profile
+/// can set, so the FIFO is read before the target is held off.
+const RTS_FIFO_THRESHOLD: u8 = 120;
 
 /// Bidirectional pipe buffer for UART communications.
 pub struct BufferedUart {
     outward: Pipe<CriticalSectionRawMutex, OUTWARD_BUF_SZ>,
     inward: Pipe<CriticalSectionRawMutex, INWARD_BUF_SZ>,
     dropped_rx_bytes: AtomicUsize,
+    /// Settings queued by [`BufferedSerial::reconfigure`]...
+    reconfigure: Signal<CriticalSectionRawMutex, UartConfig>,
+    /// ...and whether the UART took them.
+    applied: Signal<CriticalSectionRawMutex, bool>,
+    /// [`run`](Self::run) has started and answers [`Self::reconfigure`].
+    running: AtomicBool,
+    /// Set by [`BufferedSerial::park`]: output is discarded.
+    parked: AtomicBool,
+    /// Asks the TX side to drain and flush...
+    park: Signal<CriticalSectionRawMutex, ()>,
+    /// ...and tells [`BufferedSerial::park`] it did.
+    drained: Signal<CriticalSectionRawMutex, ()>,
 }
 
 impl BufferedUart {
@@ -46,6 +74,12 @@ impl BufferedUart {
             outward: Pipe::new(),
             inward: Pipe::new(),
             dropped_rx_bytes: AtomicUsize::from(0),
+            reconfigure: Signal::new(),
+            applied: Signal::new(),
+            running: AtomicBool::new(false),
+            parked: AtomicBool::new(false),
+            park: Signal::new(),
+            drained: Signal::new(),
         }
     }
 
@@ -53,20 +87,105 @@ impl BufferedUart {
     ///
     /// This should be awaited from an Embassy task run in an `InterruptExecutor`
     /// for lower latency.
-    pub async fn run(&self, uart: Uart<'_, Async>) {
-        let (mut uart_rx, mut uart_tx) = uart.split();
+    ///
+    /// At that priority it preempts every thread-mode task, including one
+    /// holding the config or flash `SunsetMutex` through a flash erase, so
+    /// it must never await either: it shares only the pipes and signals
+    /// above, atomics, and the short critical sections behind
+    /// [`serial::record_line_error`] and friends. New settings arrive as a
+    /// copy through [`BufferedSerial::reconfigure`] rather than by reading
+    /// the config. `flow` is kept across them: it follows the pins wired.
+    ///
+    /// RX tuning is applied to the running receiver. A new baud rate or
+    /// frame needs the whole driver, which esp-hal only reconfigures before
+    /// it is split, so the driver is rebuilt on the same peripheral and
+    /// pins; a chunk being sent at that moment is cut short. `config` was
+    /// accepted by the driver already, see [`uart_task`].
+    pub async fn run(
+        &self,
+        mut uart1: UART1<'_>,
+        pins: &mut EspUartPins<'_>,
+        mut config: UartConfig,
+        flow: HwFlowControl,
+    ) {
         let mut rx_buf = [0u8; UART_BUF_SZ];
         let mut tx_buf = [0u8; UART_BUF_SZ];
+        let mut queued: Option<UartConfig> = None;
+        self.running.store(true, Ordering::Relaxed);
 
         loop {
-            use embassy_futures::select::select;
+            if let Some(wanted) = queued.take() {
+                debug!(
+                    "UART line settings: {} baud {}",
+                    wanted.baud_rate, wanted.frame
+                );
+                // The driver checks settings when it is built.
+                let accepted = match Uart::new(uart1.reborrow(), esp_config(&wanted, flow)) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        warn!("UART reconfiguration rejected: {e:?}");
+                        false
+                    }
+                };
+                if accepted {
+                    config = wanted;
+                }
+                self.applied.signal(accepted);
+            }
+            let uart = match Uart::new(uart1.reborrow(), esp_config(&config, flow)) {
+                Ok(uart) => uart,
+                Err(e) => {
+                    error!("UART config error: {e}");
+                    serial::disable_bridge("UART settings rejected");
+                    return;
+                }
+            };
+            let (mut uart_rx, mut uart_tx) = connect(uart, pins).into_async().split();
 
             let rd_from = async {
                 loop {
-                    let Ok(n) = uart_rx.read_async(&mut rx_buf).await else {
-                        continue;
+                    let read =
+                        select(uart_rx.read_async(&mut rx_buf), self.reconfigure.wait()).await;
+                    let n = match read {
+                        Either::First(Ok(n)) => n,
+                        Either::First(Err(e)) => {
+                            let error = line_error(e);
+                            let discarded = resync(&mut uart_rx, &mut rx_buf).await;
+                            // No logging here: a console write at this
+                            // priority would stall reception during the very
+                            // bursts that cause errors. `show uart` has them.
+                            serial::record_line_error(error);
+                            serial::record_discarded(discarded);
+                            if serial::line_stuck() {
+                                // A held line only brings more errors; look
+                                // again now and then, dropping what piled up.
+                                Timer::after(Duration::from_millis(UART_STUCK_POLL_MS)).await;
+                                let discarded = resync(&mut uart_rx, &mut rx_buf).await;
+                                serial::record_discarded(discarded);
+                            }
+                            continue;
+                        }
+                        Either::Second(wanted)
+                            if wanted.baud_rate != config.baud_rate
+                                || wanted.frame != config.frame =>
+                        {
+                            break wanted;
+                        }
+                        Either::Second(wanted) => {
+                            debug!(
+                                "UART RX tuning: threshold {} timeout {}",
+                                wanted.rx_fifo_threshold, wanted.rx_timeout
+                            );
+                            let applied = uart_rx.apply_config(&esp_config(&wanted, flow));
+                            if let Err(e) = applied {
+                                warn!("UART reconfiguration rejected: {e:?}");
+                            }
+                            self.applied.signal(applied.is_ok());
+                            continue;
+                        }
                     };
 
+                    serial::record_received(n);
                     let mut rx_slice = &rx_buf[..n];
 
                     while !rx_slice.is_empty() {
@@ -92,27 +211,42 @@ impl BufferedUart {
 
             let rd_to = async {
                 loop {
-                    let n = self.outward.read(&mut tx_buf).await;
-                    let _ = uart_tx.write_async(&tx_buf[..n]).await;
+                    match select(self.outward.read(&mut tx_buf), self.park.wait()).await {
+                        Either::First(n) => {
+                            let _ = uart_tx.write_async(&tx_buf[..n]).await;
+                        }
+                        Either::Second(()) => {
+                            // Send what was queued before parking, then wait
+                            // for the FIFO to empty so the last byte is whole.
+                            while let Ok(n) = self.outward.try_read(&mut tx_buf) {
+                                let _ = uart_tx.write_async(&tx_buf[..n]).await;
+                            }
+                            let _ = uart_tx.flush_async().await;
+                            self.drained.signal(());
+                        }
+                    }
                 }
             };
 
-            select(rd_from, rd_to).await;
+            if let Either::First(wanted) = select(rd_from, rd_to).await {
+                // Let what the FIFO holds leave at the old settings.
+                let _ = uart_tx.flush_async().await;
+                queued = Some(wanted);
+            }
         }
     }
+}
 
-    pub async fn read(&self, buf: &mut [u8]) -> usize {
-        self.inward.read(buf).await
+/// Attaches `pins` to `uart`, with flow control where wired.
+fn connect<'d>(uart: Uart<'d, Blocking>, pins: &'d mut EspUartPins<'_>) -> Uart<'d, Blocking> {
+    let mut uart = uart.with_rx(pins.rx.reborrow()).with_tx(pins.tx.reborrow());
+    if let Some(cts) = &mut pins.cts {
+        uart = uart.with_cts(cts.reborrow());
     }
-
-    pub async fn write(&self, buf: &[u8]) {
-        self.outward.write_all(buf).await;
-    }
-
-    /// Number of bytes the RX side dropped since the last call. Resets the counter.
-    pub fn check_dropped_bytes(&self) -> usize {
-        self.dropped_rx_bytes.swap(0, Ordering::Relaxed)
+    if let Some(rts) = &mut pins.rts {
+        uart = uart.with_rts(rts.reborrow());
     }
+    uart
 }
 
 impl Default for BufferedUart {
@@ -122,16 +256,120 @@ impl Default for BufferedUart {
 }
 
 impl BufferedSerial for BufferedUart {
-    fn read(&self, buf: &mut [u8]) -> impl Future<Output = usize> {
-        BufferedUart::read(self, buf)
+    async fn read(&self, buf: &mut [u8]) -> usize {
+        self.inward.read(buf).await
+    }
+
+    async fn write_some(&self, buf: &[u8]) -> usize {
+        if self.parked.load(Ordering::Relaxed) {
+            return buf.len();
+        }
+        self.outward.write(buf).await
     }
 
-    fn write(&self, buf: &[u8]) -> impl Future<Output = ()> {
-        BufferedUart::write(self, buf)
+    /// Polls, the pipe cannot be waited on until empty.
+    async fn drain(&self) {
+        while !self.outward.is_empty() {
+            Timer::after(Duration::from_millis(5)).await;
+        }
+    }
+
+    fn discard_output(&self) -> usize {
+        let queued = self.outward.len();
+        self.outward.clear();
+        queued
     }
 
     fn check_dropped_bytes(&self) -> usize {
-        BufferedUart::check_dropped_bytes(self)
+        self.dropped_rx_bytes.swap(0, Ordering::Relaxed)
+    }
+
+    /// Waits at most [`UART_RECONFIGURE_TIMEOUT_MS`] for the UART task.
+    async fn reconfigure(&self, config: UartConfig) -> bool {
+        self.applied.reset();
+        self.reconfigure.signal(config);
+        if !self.running.load(Ordering::Relaxed) {
+            // Taken up when `run` starts.
+            return true;
+        }
+        let timeout = Duration::from_millis(UART_RECONFIGURE_TIMEOUT_MS);
+        with_timeout(timeout, self.applied.wait())
+            .await
+            .unwrap_or(false)
+    }
+
+    /// The bridge UART has no modem control lines, so parking leaves TX
+    /// idling high. Waits forever if [`uart_task`] has not started.
+    async fn park(&self) {
+        self.parked.store(true, Ordering::Relaxed);
+        self.park.signal(());
+        self.drained.wait().await;
+    }
+}
+
+fn line_error(e: RxError) -> LineError {
+    match e {
+        RxError::FifoOverflowed => LineError::Overflow,
+        RxError::GlitchOccurred => LineError::Glitch,
+        RxError::ParityMismatch => LineError::Parity,
+        _ => LineError::Framing,
+    }
+}
+
+/// Recovers from a receive error: flushes whatever the FIFO still holds,
+/// which may be garbled, then discards input until the line has been quiet
+/// for [`UART_RESYNC_QUIET_MS`] (at most [`UART_RESYNC_MAX_MS`]), so the
+/// next read starts on a character boundary. Returns the bytes discarded.
+async fn resync(uart_rx: &mut UartRx<'_, Async>, buf: &mut [u8]) -> usize {
+    let mut discarded = 0;
+    while let Ok(n @ 1..) = uart_rx.read_buffered(buf) {
+        discarded += n;
+    }
+    if UART_RESYNC_QUIET_MS == 0 {
+        return discarded;
+    }
+    let deadline = Instant::now() + Duration::from_millis(UART_RESYNC_MAX_MS);
+    let quiet = Duration::from_millis(UART_RESYNC_QUIET_MS);
+    while Instant::now() < deadline {
+        match with_timeout(quiet, uart_rx.read_async(buf)).await {
+            Ok(Ok(n)) => discarded += n,
+            // Further errors are part of the same burst.
+            Ok(Err(_)) => {}
+            Err(_) => break,
+        }
+    }
+    discarded
+}
+
+/// Map the portable [`UartConfig`] onto the esp-hal driver configuration.
+fn esp_config(config: &UartConfig, flow: HwFlowControl) -> Config {
+    Config::default()
+        .with_baudrate(config.baud_rate)
+        .with_data_bits(data_bits(config.frame))
+        .with_parity(match config.frame.parity {
+            ssh_stamp_hal::Parity::None => Parity::None,
+            ssh_stamp_hal::Parity::Even => Parity::Even,
+            ssh_stamp_hal::Parity::Odd => Parity::Odd,
+        })
+        .with_stop_bits(if config.frame.stop_bits == 2 {
+            StopBits::_2
+        } else {
+            StopBits::_1
+        })
+        .with_hw_flow_ctrl(flow)
+        .with_rx(
+            RxConfig::default()
+                .with_fifo_full_threshold(config.rx_fifo_threshold)
+                .with_timeout(config.rx_timeout),
+        )
+}
+
+fn data_bits(frame: UartFrame) -> DataBits {
+    match frame.data_bits {
+        5 => DataBits::_5,
+        6 => DataBits::_6,
+        7 => DataBits::_7,
+        _ => DataBits::_8,
     }
 }
 
@@ -142,6 +380,50 @@ impl BufferedSerial for BufferedUart {
 pub struct EspUartPins<'a> {
     pub rx: AnyPin<'a>,
     pub tx: AnyPin<'a>,
+    /// Input the target pulls low while it can take data, if wired.
+    pub cts: Option<AnyPin<'a>>,
+    /// Output held low while the RX FIFO has room, if wired.
+    pub rts: Option<AnyPin<'a>>,
+}
+
+impl EspUartPins<'_> {
+    /// Hardware flow control in each direction whose pin is wired.
+    fn flow_control(&self) -> HwFlowControl {
+        HwFlowControl {
+            cts: match self.cts {
+                Some(_) => CtsConfig::Enabled,
+                None => CtsConfig::Disabled,
+            },
+            rts: match self.rts {
+                Some(_) => RtsConfig::Enabled(RTS_FIFO_THRESHOLD),
+                None => RtsConfig::Disabled,
+            },
+        }
+    }
+}
+
+/// Samples the pins for [`UART_PIN_SENSE_MS`] before the UART takes them,
+/// see [`serial::record_pin_sense`]. The pull-up keeps the TX pin at the
+/// line's idle level, so a correctly wired target sees nothing.
+#[cfg(feature = "tx-sense")]
+async fn sense_pins(pins: &mut EspUartPins<'static>) {
+    let tx = Input::new(
+        pins.tx.reborrow(),
+        InputConfig::default().with_pull(Pull::Up),
+    );
+    let rx = Input::new(
+        pins.rx.reborrow(),
+        InputConfig::default().with_pull(Pull::Down),
+    );
+    let mut sense = serial::PinSense::default();
+    let end = Instant::now() + Duration::from_millis(UART_PIN_SENSE_MS);
+    while Instant::now() < end {
+        sense.samples += 1;
+        sense.tx_low += u32::from(tx.is_low());
+        sense.rx_high += u32::from(rx.is_high());
+        Timer::after_micros(UART_PIN_SENSE_INTERVAL_US).await;
+    }
+    serial::record_pin_sense(sense);
 }
 
 /// Static storage for the buffered UART singleton.
@@ -154,22 +436,49 @@ pub static UART_SIGNAL: Signal<CriticalSectionRawMutex, u8> = Signal::new();
 /// Embassy task that owns the hardware UART and pumps it through
 /// [`BufferedUart::run`]. Spawn from a higher-priority `InterruptExecutor`
 /// for lower latency.
+///
+/// `config` is the persisted profile; sessions may change the line
+/// settings later through [`BufferedSerial::reconfigure`].
 #[embassy_executor::task]
 pub async fn uart_task(
     uart_buf: &'static BufferedUart,
-    uart1: UART1<'static>,
-    pins: EspUartPins<'static>,
+    mut uart1: UART1<'static>,
+    mut pins: EspUartPins<'static>,
+    config: UartConfig,
 ) {
-    UART_SIGNAL.wait().await;
-
-    let uart_config = Config::default().with_rx(
-        RxConfig::default()
-            .with_fifo_full_threshold(16)
-            .with_timeout(1),
-    );
+    // Checked at boot rather than on first use, so a bad setup leaves the
+    // device reachable over SSH to fix it, see `serial::disable_bridge`.
+    let (rx, tx) = (pins.rx.number(), pins.tx.number());
+    let [cts, rts] = [&pins.cts, &pins.rts].map(|pin| pin.as_ref().map(Pin::number));
+    let numbers = [Some(rx), Some(tx), cts, rts];
+    let collide = numbers.iter().enumerate().any(|(i, &a)| {
+        a.is_some_and(|a| is_reserved_pin(a) || numbers[i + 1..].contains(&Some(a)))
+    });
+    if collide {
+        error!(
+            "UART pins RX {rx} TX {tx} CTS {cts:?} RTS {rts:?} collide with each other, the flash or PSRAM"
+        );
+        serial::disable_bridge("UART pins unusable");
+        return;
+    }
+    let flow = pins.flow_control();
+    #[cfg(feature = "tx-sense")]
+    sense_pins(&mut pins).await;
+    if let Err(e) = Uart::new(uart1.reborrow(), esp_config(&config, flow)) {
+        error!("UART config error: {e}");
+        serial::disable_bridge(match e {
+            ConfigError::BaudrateNotSupported => "baud rate not supported",
+            ConfigError::TimeoutTooLong => "UART RX timeout too long",
+            ConfigError::RxFifoThresholdNotSupported => "UART RX FIFO threshold too high",
+            _ => "UART settings rejected",
+        });
+        return;
+    }
 
-    let uart = Uart::new(uart1, uart_config).expect("UART config error");
-    let uart = uart.with_rx(pins.rx).with_tx(pins.tx).into_async();
+    UART_SIGNAL.wait().await;
 
-    uart_buf.run(uart).await;
+    if flow != HwFlowControl::default() {
+        debug!("UART hardware flow control: {flow:?}");
+    }
+    uart_buf.run(uart1, &mut pins, config, flow).await;
 }
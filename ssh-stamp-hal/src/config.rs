@@ -6,6 +6,9 @@
 
 //! Hardware configuration types.
 
+use core::fmt;
+use core::net::Ipv4Addr;
+
 use heapless::String;
 
 /// UART peripheral configuration.
@@ -21,6 +24,13 @@ pub struct UartConfig {
     pub cts_pin: Option<u8>,
     pub rts_pin: Option<u8>,
     pub baud_rate: u32,
+    /// RX FIFO fill level at which received bytes are handed over. Lower
+    /// values favour latency, higher values throughput.
+    pub rx_fifo_threshold: u16,
+    /// Line idle time, in symbol times, after which a partially filled RX
+    /// FIFO is handed over anyway.
+    pub rx_timeout: u8,
+    pub frame: UartFrame,
 }
 
 impl Default for UartConfig {
@@ -31,10 +41,59 @@ impl Default for UartConfig {
             cts_pin: None,
             rts_pin: None,
             baud_rate: 115_200,
+            rx_fifo_threshold: 16,
+            rx_timeout: 1,
+            frame: UartFrame::DEFAULT,
         }
     }
 }
 
+/// Parity bit of each UART character.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Parity {
+    #[default]
+    None,
+    Even,
+    Odd,
+}
+
+/// Framing of each UART character, written the usual way: data bits,
+/// parity and stop bits, e.g. `8N1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UartFrame {
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+}
+
+impl UartFrame {
+    /// `8N1`, what nearly every target console uses.
+    pub const DEFAULT: Self = Self {
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+    };
+}
+
+impl Default for UartFrame {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl fmt::Display for UartFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Even => 'E',
+            Parity::Odd => 'O',
+        };
+        write!(f, "{}{parity}{}", self.data_bits, self.stop_bits)
+    }
+}
+
 /// `WiFi` access point configuration.
 ///
 /// Contains settings for running the device as a `WiFi` access point.
@@ -53,6 +112,14 @@ pub struct WifiApConfigStatic {
     pub channel: u8,
     /// MAC address for the access point interface.
     pub mac: [u8; 6],
+    /// Keep the access point up while connected as a station (AP+STA).
+    /// Ignored when `sta_ssid` is empty.
+    pub concurrent: bool,
+    /// Address of the device on the access point network (a `/24`).
+    pub ap_address: Ipv4Addr,
+    /// Hand out addresses on the access point network. Disable when another
+    /// DHCP server is bridged into it.
+    pub dhcp_server: bool,
 }
 
 impl Default for WifiApConfigStatic {
@@ -64,6 +131,9 @@ impl Default for WifiApConfigStatic {
             sta_password: String::new(),
             channel: 1,
             mac: [0; 6],
+            concurrent: false,
+            ap_address: Ipv4Addr::new(192, 168, 4, 1),
+            dhcp_server: true,
         }
     }
 }
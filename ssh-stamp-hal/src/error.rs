@@ -30,6 +30,10 @@ pub enum HalError {
     Hash(HashError),
     /// Timer error.
     Timer,
+    /// Pulse output error.
+    Pulse,
+    /// UART multiplexer error.
+    Mux,
 }
 
 /// UART-specific errors.
@@ -106,6 +110,8 @@ impl fmt::Display for HalError {
             HalError::Rng => write!(f, "RNG error"),
             HalError::Hash(e) => write!(f, "Hash error: {e:?}"),
             HalError::Timer => write!(f, "Timer error"),
+            HalError::Pulse => write!(f, "Pulse output error"),
+            HalError::Mux => write!(f, "UART mux error"),
         }
     }
 }
@@ -17,7 +17,8 @@
 //! ## Overview
 //!
 //! - Peripheral traits: [`WifiHal`], [`NetworkProviderHal`], [`RngHal`],
-//!   [`HashHal`], [`TimerHal`], [`OtaActions`]
+//!   [`HashHal`], [`TimerHal`], [`OtaActions`], [`ScratchActions`], [`PulseHal`],
+//!   [`MuxHal`]
 //! - Configuration: [`WifiApConfigStatic`]
 //! - Error handling: [`HalError`] with variants per peripheral type
 //!
@@ -33,6 +34,9 @@
 //! | [`WifiHal`]              | `WiFi` ports | `EspWifi`         |
 //! | `BufferedSerial`          | always       | `BufferedUart`    |
 //! | [`OtaActions`]           | sftp-ota     | `EspOtaWriter`      |
+//! | [`ScratchActions`]       | sftp-ota     | `EspScratch`        |
+//! | [`PulseHal`]             | rmt          | `EspPulse`          |
+//! | [`MuxHal`]               | mux          | `EspMux`            |
 //! | `PlatformServices`        | always       | `EspPlatform`      |
 //!
 //! [`WifiHal`] is required only for WiFi-based ports. Ethernet ports would
@@ -66,6 +70,6 @@ pub mod config;
 pub mod error;
 pub mod traits;
 
-pub use config::{UartConfig, WifiApConfigStatic};
+pub use config::{Parity, UartConfig, UartFrame, WifiApConfigStatic};
 pub use error::{FlashError, HalError, HashError, UartError, WifiError};
 pub use traits::*;
@@ -5,18 +5,65 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-//! OTA update traits.
+//! OTA update and scratch partition traits.
 //!
 //! Flash storage operations should use `embedded_storage_async::nor_flash::NorFlash`
 //! from the embedded-hal ecosystem rather than a custom trait.
 //!
 //! OTA update actions are kept here because they are application-specific
 //! (partition management, firmware validation) and not covered by embedded-hal.
+//! So is the scratch partition, which has to be found in the partition table.
 
 use core::future::Future;
 
 use crate::HalError;
 
+/// Longest release notes an OTA header can carry, one TLV value.
+pub const OTA_RELEASE_NOTES_LEN: usize = 255;
+
+/// What the OTA server learned about an upload from its header, passed along
+/// with its outcome.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OtaUpload {
+    /// Firmware image size, `None` if the header did not get that far.
+    pub size: Option<u32>,
+    /// SHA-256 of the firmware image, from the header.
+    pub sha256: Option<[u8; 32]>,
+    /// Release notes from the header, empty if it carried none.
+    pub release_notes: heapless::String<OTA_RELEASE_NOTES_LEN>,
+}
+
+/// A chip as OTA images name it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipTarget {
+    /// Chip family, as the chip ID of ESP-IDF app image headers, e.g. 5 for
+    /// the ESP32-C3 and 9 for the ESP32-S3.
+    pub family: u16,
+    /// Chip revision as `major * 100 + minor`. In an OTA header, the oldest
+    /// revision the image runs on.
+    pub revision: u16,
+}
+
+/// Why an upload was not installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtaFailure {
+    /// The TLV header was malformed, the image does not fit or is for
+    /// another chip.
+    BadHeader,
+    /// A CRC-32 segment checksum or the SHA-256 did not match.
+    Verification,
+    /// The firmware requires signed images and this one was unsigned or
+    /// signed with another key.
+    Signature,
+    /// Erasing or writing the OTA partition failed.
+    Write,
+    /// The upload ended, or arrived too far out of order, before the whole
+    /// image was received.
+    Incomplete,
+    /// Anything else, including marking the new image for boot.
+    Internal,
+}
+
 /// OTA update operations.
 ///
 /// # Errors
@@ -26,19 +73,105 @@ pub trait OtaActions {
     /// Validate the current OTA partition.
     fn try_validating_current_ota_partition() -> impl Future<Output = Result<(), HalError>> + Send;
 
+    /// Whether the current OTA partition holds a new image not validated
+    /// yet.
+    fn is_current_ota_partition_pending() -> impl Future<Output = Result<bool, HalError>> + Send;
+
+    /// Mark the current OTA partition invalid and select the previous image
+    /// for the next boot.
+    fn roll_back_current_ota_partition() -> impl Future<Output = Result<(), HalError>> + Send;
+
     /// Get size of OTA partition in bytes.
     fn get_ota_partition_size() -> impl Future<Output = Result<u32, HalError>> + Send;
 
-    /// Write data to OTA partition at offset.
+    /// The chip this firmware runs on, checked against the target an OTA
+    /// header names before anything is written. `None`, the default, skips
+    /// the check.
+    fn running_chip() -> Option<ChipTarget> {
+        None
+    }
+
+    /// Erase unit of the OTA partition in bytes.
+    const ERASE_SIZE: u32;
+
+    /// Erase `len` bytes of the OTA partition starting at `offset`. Both are
+    /// multiples of [`Self::ERASE_SIZE`].
+    fn erase_ota_data(
+        &self,
+        offset: u32,
+        len: u32,
+    ) -> impl Future<Output = Result<(), HalError>> + Send;
+
+    /// Write data to OTA partition at offset. The range has already been
+    /// erased with [`Self::erase_ota_data`].
     fn write_ota_data(
         &self,
         offset: u32,
         data: &[u8],
     ) -> impl Future<Output = Result<(), HalError>> + Send;
 
-    /// Finalize OTA update and mark for boot.
-    fn finalize_ota_update(&mut self) -> impl Future<Output = Result<(), HalError>> + Send;
+    /// Finalize OTA update and mark for boot. `upload` describes the
+    /// verified image, for the platform's OTA history.
+    fn finalize_ota_update(
+        &mut self,
+        upload: &OtaUpload,
+    ) -> impl Future<Output = Result<(), HalError>> + Send;
+
+    /// Note an upload that failed with `failure`, for the platform's OTA
+    /// history. Does nothing by default.
+    fn record_failed_upload(
+        &mut self,
+        upload: &OtaUpload,
+        failure: OtaFailure,
+    ) -> impl Future<Output = ()> + Send {
+        let _ = (upload, failure);
+        async {}
+    }
 
     /// Reset device to boot into new partition.
     fn reset_device(&self) -> !;
 }
+
+/// Alignment of scratch partition reads and writes in bytes, see [`ScratchActions`].
+pub const SCRATCH_ALIGN: u32 = 4;
+
+/// Access to the scratch partition, a small flash area holding the operator's own files.
+/// The `ota` crate lays the files out and serves them over SFTP.
+///
+/// Offsets are from the start of the partition.
+///
+/// # Errors
+///
+/// All methods return `HalError` on failure, `FlashError::PartitionNotFound` if the
+/// partition table has no scratch partition.
+pub trait ScratchActions {
+    /// Erase unit of the scratch partition in bytes.
+    const ERASE_SIZE: u32;
+
+    /// Size of the scratch partition in bytes.
+    fn scratch_size(&self) -> impl Future<Output = Result<u32, HalError>> + Send;
+
+    /// Read `buf.len()` bytes from `offset`, both multiples of [`SCRATCH_ALIGN`].
+    fn read_scratch(
+        &self,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<(), HalError>> + Send;
+
+    /// Erase `len` bytes starting at `offset`. Both are multiples of
+    /// [`Self::ERASE_SIZE`].
+    fn erase_scratch(
+        &self,
+        offset: u32,
+        len: u32,
+    ) -> impl Future<Output = Result<(), HalError>> + Send;
+
+    /// Write `data` at `offset`, both multiples of [`SCRATCH_ALIGN`].
+    /// Writing only clears bits: data written over data that was not erased
+    /// is ANDed with it.
+    fn write_scratch(
+        &self,
+        offset: u32,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), HalError>> + Send;
+}
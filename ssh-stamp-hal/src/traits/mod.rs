@@ -12,14 +12,21 @@
 
 mod flash;
 mod hash;
+mod mux;
 mod network;
+mod pulse;
 mod rng;
 mod timer;
 mod uart;
 
-pub use flash::OtaActions;
+pub use flash::{
+    ChipTarget, OTA_RELEASE_NOTES_LEN, OtaActions, OtaFailure, OtaUpload, SCRATCH_ALIGN,
+    ScratchActions,
+};
 pub use hash::HashHal;
+pub use mux::{MUX_SELECT_LINES, MuxHal};
 pub use network::{NetworkProviderHal, WifiHal};
+pub use pulse::{MAX_PULSE_MICROS, MAX_PULSES, Pulse, PulseHal};
 pub use rng::RngHal;
 pub use timer::TimerHal;
 pub use uart::UartHal;
@@ -0,0 +1,26 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! External UART multiplexer trait.
+
+use crate::HalError;
+
+/// Number of select lines a [`MuxHal`] drives, so up to
+/// `1 << MUX_SELECT_LINES` targets can share the bridge UART.
+pub const MUX_SELECT_LINES: usize = 3;
+
+/// GPIO select lines of an external analog mux (e.g. a 74HC4052) that
+/// routes the bridge UART to one of several targets.
+///
+/// The lines are plain push-pull outputs holding their level until the next
+/// [`MuxHal::select`].
+pub trait MuxHal {
+    /// Drive the select lines: bit `n` of `select` sets line `n` high.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HalError::Mux`] if `select` has bits set at or above
+    /// [`MUX_SELECT_LINES`].
+    fn select(&mut self, select: u8) -> Result<(), HalError>;
+}
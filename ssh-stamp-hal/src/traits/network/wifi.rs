@@ -10,6 +10,8 @@
 
 //! `WiFi` hardware abstraction trait.
 
+use embassy_net::Stack;
+
 use crate::{HalError, NetworkProviderHal, WifiApConfigStatic};
 
 /// `WiFi`-specific extension of [`NetworkProviderHal`].
@@ -50,4 +52,14 @@ pub trait WifiHal: NetworkProviderHal {
     /// Returns [`HalError::Wifi`] if the configuration is rejected by the
     /// driver (e.g. SSID too long after encoding, unsupported channel).
     fn configure_ap(&mut self, config: WifiApConfigStatic) -> Result<(), HalError>;
+
+    /// The access-point stack when running AP and station concurrently.
+    ///
+    /// In that mode [`NetworkProviderHal::bring_up`] returns the station
+    /// stack and the access point is reachable through this one. `None` in
+    /// single-interface modes, before `bring_up`, or on radios that cannot
+    /// run both at once.
+    fn concurrent_ap_stack(&self) -> Option<Stack<'static>> {
+        None
+    }
 }
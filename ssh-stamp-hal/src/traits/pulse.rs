@@ -0,0 +1,51 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Pulse-train output trait.
+
+use core::future::Future;
+
+use crate::HalError;
+
+/// Longest pulse sequence a [`PulseHal::transmit`] call must accept.
+pub const MAX_PULSES: usize = 32;
+
+/// Longest single pulse, in microseconds.
+pub const MAX_PULSE_MICROS: u16 = 0x7FFF;
+
+/// One output level held for a fixed time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pulse {
+    /// Drive the pin high (`true`) or low (`false`).
+    pub high: bool,
+    /// Duration in microseconds, `1..=MAX_PULSE_MICROS`.
+    pub micros: u16,
+}
+
+/// Hardware-timed pulse generator on a dedicated output pin.
+///
+/// Used for board bring-up waveforms the UART cannot produce: reset or
+/// recovery strobes, 1-wire resets, simple custom protocols. The timing is
+/// done by a peripheral (ESP32: RMT), not by software delays, so it is
+/// unaffected by Wi-Fi or SSH load.
+///
+/// The pin returns to low once the sequence has been sent.
+///
+/// # Example
+///
+/// ```ignore
+/// async fn reset_target<P: PulseHal>(out: &mut P) -> Result<(), HalError> {
+///     out.transmit(&[Pulse { high: false, micros: 500 }, Pulse { high: true, micros: 10 }]).await
+/// }
+/// ```
+pub trait PulseHal {
+    /// Emit `pulses` in order and wait until the last one has finished.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`HalError::Pulse`] if the sequence is empty, longer than
+    /// [`MAX_PULSES`], contains a zero or overlong duration, or the
+    /// peripheral reports a failure.
+    fn transmit(&mut self, pulses: &[Pulse]) -> impl Future<Output = Result<(), HalError>>;
+}
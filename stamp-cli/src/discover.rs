@@ -0,0 +1,214 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Finding stamps on the local network.
+//!
+//! An mDNS query for `_ssh._tcp.local` collects every host that advertises
+//! SSH; each one, and every address of an optional subnet scan, is then
+//! confirmed by reading its SSH identification string, which names
+//! `ssh-stamp` and its version (see the firmware's `build.rs`).
+
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MDNS_GROUP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+const SERVICE: &str = "_ssh._tcp.local";
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_CLASS_IN: u16 = 1;
+
+/// Largest subnet scanned, so a typo cannot start a sweep of a /8.
+pub const MAX_SCAN_PREFIX: u8 = 20;
+
+/// A stamp that answered.
+pub struct Found {
+    pub addr: IpAddr,
+    /// e.g. `SSH-2.0-Sunset-0.5.0-ssh-stamp-0.3.0`
+    pub ident: String,
+}
+
+fn query() -> Vec<u8> {
+    // Header: id 0, standard query, one question
+    let mut q = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in SERVICE.split('.') {
+        q.push(u8::try_from(label.len()).expect("short label"));
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&DNS_TYPE_PTR.to_be_bytes());
+    q.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    q
+}
+
+/// Whether the DNS message `reply` is a response (QR bit) with at least
+/// one answer.
+fn is_answer(reply: &[u8]) -> bool {
+    reply.len() >= 12 && reply[2] & 0x80 != 0 && reply[6..8] != [0, 0]
+}
+
+/// Addresses of the hosts answering an mDNS query for SSH services within
+/// `wait`.
+///
+/// The query is sent from an ephemeral port, so responders answer by
+/// unicast and no multicast membership is needed.
+///
+/// # Errors
+///
+/// Fails if the query cannot be sent.
+pub fn mdns(wait: Duration) -> std::io::Result<Vec<IpAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(&query(), MDNS_GROUP)?;
+    let deadline = Instant::now() + wait;
+    let mut hosts = Vec::new();
+    let mut buf = [0u8; 1500];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if left.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(left))?;
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            break;
+        };
+        if is_answer(&buf[..len]) && !hosts.contains(&from.ip()) {
+            hosts.push(from.ip());
+        }
+    }
+    Ok(hosts)
+}
+
+/// Every host address of an IPv4 `a.b.c.d/prefix` subnet.
+///
+/// # Errors
+///
+/// Fails on malformed input or a prefix shorter than [`MAX_SCAN_PREFIX`].
+pub fn subnet(cidr: &str) -> Result<Vec<IpAddr>, String> {
+    let (addr, prefix) = cidr
+        .split_once('/')
+        .ok_or_else(|| format!("'{cidr}' is not <address>/<prefix>"))?;
+    let addr: Ipv4Addr = addr
+        .parse()
+        .map_err(|_| format!("'{addr}' is not an IPv4 address"))?;
+    let prefix: u8 = prefix
+        .parse()
+        .ok()
+        .filter(|p| *p <= 32)
+        .ok_or_else(|| format!("'{prefix}' is not a prefix length"))?;
+    if prefix < MAX_SCAN_PREFIX {
+        return Err(format!("refusing to scan more than a /{MAX_SCAN_PREFIX}"));
+    }
+    let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+    let network = u32::from(addr) & mask;
+    let broadcast = network | !mask;
+    let hosts = if prefix >= 31 {
+        network..=broadcast
+    } else {
+        network + 1..=broadcast - 1
+    };
+    Ok(hosts.map(|a| IpAddr::V4(Ipv4Addr::from(a))).collect())
+}
+
+/// The SSH identification string of `addr`, if it is a stamp.
+fn probe(addr: IpAddr, port: u16, timeout: Duration) -> Option<Found> {
+    let stream = TcpStream::connect_timeout(&SocketAddr::new(addr, port), timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    let mut ident = String::new();
+    BufReader::new(stream).read_line(&mut ident).ok()?;
+    let ident = ident.trim_end().to_owned();
+    ident.contains("ssh-stamp").then_some(Found { addr, ident })
+}
+
+/// Probes all of `hosts` in parallel, returning the stamps in input order.
+#[must_use]
+pub fn confirm(hosts: &[IpAddr], port: u16, timeout: Duration) -> Vec<Found> {
+    thread::scope(|s| {
+        let probes: Vec<_> = hosts
+            .iter()
+            .map(|&addr| s.spawn(move || probe(addr, port, timeout)))
+            .collect();
+        probes
+            .into_iter()
+            .filter_map(|p| p.join().ok().flatten())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod discover_tests {
+    use super::*;
+
+    fn header(flags: u8, answers: u16) -> Vec<u8> {
+        let mut h = vec![0, 0, flags, 0, 0, 0];
+        h.extend_from_slice(&answers.to_be_bytes());
+        h.extend_from_slice(&[0, 0, 0, 0]);
+        h
+    }
+
+    #[test]
+    fn query_asks_for_ssh_ptr_records() {
+        let q = query();
+        // One question, not a response
+        assert_eq!(&q[..12], &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&q[12..17], b"\x04_ssh");
+        assert_eq!(&q[17..22], b"\x04_tcp");
+        assert_eq!(&q[22..28], b"\x05local");
+        assert_eq!(&q[28..], &[0, 0, 12, 0, 1]);
+        assert!(!is_answer(&q));
+    }
+
+    #[test]
+    fn response_with_answers_is_an_answer() {
+        assert!(is_answer(&header(0x84, 1)));
+        assert!(is_answer(&header(0x80, 0x0100)));
+    }
+
+    #[test]
+    fn response_without_answers_is_not() {
+        assert!(!is_answer(&header(0x84, 0)));
+    }
+
+    #[test]
+    fn query_echo_is_not_an_answer() {
+        assert!(!is_answer(&header(0x00, 1)));
+    }
+
+    #[test]
+    fn short_reply_is_not_an_answer() {
+        assert!(!is_answer(&header(0x84, 1)[..11]));
+        assert!(!is_answer(&[]));
+    }
+
+    #[test]
+    fn subnet_skips_network_and_broadcast() {
+        let hosts = subnet("192.168.4.77/30").unwrap();
+        assert_eq!(
+            hosts,
+            [
+                IpAddr::V4(Ipv4Addr::new(192, 168, 4, 77)),
+                IpAddr::V4(Ipv4Addr::new(192, 168, 4, 78)),
+            ]
+        );
+        assert_eq!(subnet("10.0.0.0/24").unwrap().len(), 254);
+    }
+
+    #[test]
+    fn subnet_point_to_point_and_single_host() {
+        assert_eq!(subnet("10.0.0.1/31").unwrap().len(), 2);
+        assert_eq!(
+            subnet("10.0.0.1/32").unwrap(),
+            [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]
+        );
+    }
+
+    #[test]
+    fn subnet_rejects_bad_input() {
+        assert!(subnet("10.0.0.0").is_err());
+        assert!(subnet("10.0.0/24").is_err());
+        assert!(subnet("10.0.0.0/33").is_err());
+        assert!(subnet("10.0.0.0/x").is_err());
+        assert!(subnet("10.0.0.0/8").is_err());
+        assert!(subnet(&format!("10.0.0.0/{}", MAX_SCAN_PREFIX - 1)).is_err());
+        assert!(subnet(&format!("10.0.0.0/{MAX_SCAN_PREFIX}")).is_ok());
+    }
+}
@@ -0,0 +1,520 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Host-side companion for SSH-Stamp: wraps the admin commands (`exec`
+//! requests) and the SFTP subsystem behind a few common workflows.
+
+mod discover;
+mod sftp;
+mod ssh;
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use clap::{ArgMatches, Command, arg, value_parser};
+
+use crate::sftp::CHUNK_LEN;
+use crate::ssh::Target;
+
+const STAMP_CLI_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const OK: i32 = 0;
+const USAGE: i32 = 1;
+const NOT_FOUND: i32 = 2;
+const READ_FAILED: i32 = 3;
+const WRITE_FAILED: i32 = 4;
+const COMMAND_FAILED: i32 = 5;
+const TRANSFER_FAILED: i32 = 6;
+const INVALID_CONFIG: i32 = 7;
+
+/// Remote file served by `sftp-ota` builds with the `show version` report.
+const VERSION_PATH: &str = "/info/version";
+
+/// Remote file served by `sftp-ota` builds with the OTA partition size.
+const OTA_INFO_PATH: &str = "/info/ota";
+
+/// Remote name for firmware uploads; any writable path other than
+/// [`VERSION_PATH`] and the other read-only files is taken as the image.
+const OTA_PATH: &str = "/ssh-stamp.ota";
+
+/// Upper bound on the TLV header `cargo packer` puts before the image:
+/// type, SHA-256, up to 256 bytes of CRC-32 segments and the blob size.
+const OTA_HEADER_MAX_LEN: u64 = 1024;
+
+fn target_args(cmd: Command) -> Command {
+    cmd.arg(arg!(<HOST> "Stamp address or ssh_config host alias"))
+        .arg(arg!(-l --user <USER> "Username").default_value("root"))
+        .arg(arg!(-p --port <PORT> "SSH port").value_parser(value_parser!(u16)))
+        .arg(arg!(-i --identity <FILE> "Private key passed to ssh"))
+}
+
+fn target(m: &ArgMatches) -> Target {
+    Target {
+        host: m.get_one::<String>("HOST").cloned().unwrap_or_default(),
+        user: m.get_one::<String>("user").cloned().unwrap_or_default(),
+        port: m.get_one::<u16>("port").copied(),
+        identity: m.get_one::<String>("identity").cloned(),
+    }
+}
+
+fn main() {
+    let matches = Command::new("stamp-cli")
+        .about(format!(
+            "SSH-Stamp host utility {STAMP_CLI_VERSION}: discovery, status, configuration and firmware updates."
+        ))
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("discover")
+                .about("Find stamps via mDNS (and optionally a subnet scan)")
+                .arg(arg!(-s --subnet <CIDR> "Also probe every address of an IPv4 subnet, e.g. 192.168.1.0/24"))
+                .arg(
+                    arg!(-w --wait <MS> "How long to collect mDNS answers and wait for each probe")
+                        .value_parser(value_parser!(u64))
+                        .default_value("1500"),
+                )
+                .arg(
+                    arg!(-p --port <PORT> "SSH port to probe")
+                        .value_parser(value_parser!(u16))
+                        .default_value("22"),
+                ),
+        )
+        .subcommand(target_args(
+            Command::new("status").about("Print the firmware version and flash wear counters"),
+        ))
+        .subcommand(target_args(
+            Command::new("build-info")
+                .about("Print features, buffer sizes and protocol versions as JSON (show build)"),
+        ))
+        .subcommand(target_args(
+            Command::new("push-config")
+                .about("Stage KEY=VALUE lines from a file, validate and commit them")
+                .arg(arg!(<FILE> "Settings, one SSH_STAMP_* variable per line; # starts a comment"))
+                .arg(
+                    arg!(-r --rollback <MINUTES> "Network changes roll back unless confirmed within this time (0: never)")
+                        .value_parser(value_parser!(u16)),
+                )
+                .arg(arg!(-n --"dry-run" "Validate only, then discard the staged settings")),
+        ))
+        .subcommand(target_args(
+            Command::new("confirm").about("Keep committed network settings (config confirm)"),
+        ))
+        .subcommand(target_args(
+            Command::new("ota")
+                .about("Upload a packed .ota image over SFTP; the stamp resets into it")
+                .arg(arg!(<FILE> "Image made with `cargo packer`")),
+        ))
+        .subcommand(target_args(
+            Command::new("transparency")
+                .about("Check that the bridge passes every byte value unchanged; needs UART TX wired to RX")
+                .arg(
+                    arg!(-r --rounds <N> "Times to send all 256 byte values")
+                        .value_parser(value_parser!(usize))
+                        .default_value("16"),
+                )
+                .arg(
+                    arg!(-w --wait <MS> "How long to wait for more echoed bytes")
+                        .value_parser(value_parser!(u64))
+                        .default_value("2000"),
+                ),
+        ))
+        .subcommand(target_args(
+            Command::new("fetch")
+                .about("Download a file from the stamp's SFTP server")
+                .arg(arg!([REMOTE] "Remote path").default_value(VERSION_PATH))
+                .arg(arg!([LOCAL] "Local file, standard output if omitted")),
+        ))
+        .get_matches();
+
+    let code = match matches.subcommand() {
+        Some(("discover", m)) => discover(m),
+        Some(("status", m)) => status(&target(m)),
+        Some(("build-info", m)) => exec_and_print(&target(m), "show build"),
+        Some(("push-config", m)) => push_config(&target(m), m),
+        Some(("confirm", m)) => exec_and_print(&target(m), "config confirm"),
+        Some(("ota", m)) => ota(&target(m), m),
+        Some(("transparency", m)) => transparency(&target(m), m),
+        Some(("fetch", m)) => fetch(&target(m), m),
+        _ => USAGE,
+    };
+    std::process::exit(code);
+}
+
+fn discover(m: &ArgMatches) -> i32 {
+    let wait = Duration::from_millis(m.get_one::<u64>("wait").copied().unwrap_or(1500));
+    let port = m.get_one::<u16>("port").copied().unwrap_or(22);
+    let mut hosts = match discover::mdns(wait) {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            eprintln!("Warning: mDNS query failed: {e}");
+            Vec::new()
+        }
+    };
+    if let Some(cidr) = m.get_one::<String>("subnet") {
+        match discover::subnet(cidr) {
+            Ok(scan) => {
+                for addr in scan {
+                    if !hosts.contains(&addr) {
+                        hosts.push(addr);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {e}");
+                return USAGE;
+            }
+        }
+    }
+    let found = discover::confirm(&hosts, port, wait);
+    if found.is_empty() {
+        eprintln!("No stamps found");
+        return NOT_FOUND;
+    }
+    for stamp in found {
+        println!("{}\t{}", stamp.addr, stamp.ident);
+    }
+    OK
+}
+
+fn exec_and_print(target: &Target, line: &str) -> i32 {
+    match target.exec(line) {
+        Ok(lines) => {
+            for line in lines {
+                println!("{line}");
+            }
+            OK
+        }
+        Err(e) => {
+            eprintln!("Error: {line}: {e}");
+            COMMAND_FAILED
+        }
+    }
+}
+
+fn status(target: &Target) -> i32 {
+    let code = exec_and_print(target, "show version");
+    if code != OK {
+        return code;
+    }
+    println!();
+    exec_and_print(target, "show flash")
+}
+
+/// Parses `KEY=VALUE` lines. Admin commands are split on whitespace, so
+/// values containing any cannot be staged this way.
+fn parse_settings(text: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut settings = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected KEY=VALUE", n + 1));
+        };
+        let (key, value) = (key.trim(), value.trim().trim_matches('"'));
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("line {}: bad key '{key}'", n + 1));
+        }
+        if value.contains(char::is_whitespace) {
+            return Err(format!(
+                "line {}: {key}: values with whitespace cannot be staged, send it with SendEnv instead",
+                n + 1
+            ));
+        }
+        settings.push((key, value));
+    }
+    Ok(settings)
+}
+
+fn push_config(target: &Target, m: &ArgMatches) -> i32 {
+    let path = m.get_one::<String>("FILE").cloned().unwrap_or_default();
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Error: Could not read '{path}': {e}");
+            return READ_FAILED;
+        }
+    };
+    let settings = match parse_settings(&text) {
+        Ok(settings) if settings.is_empty() => {
+            eprintln!("Error: '{path}' has no settings");
+            return USAGE;
+        }
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Error: {path}: {e}");
+            return USAGE;
+        }
+    };
+
+    for (key, value) in settings {
+        if let Err(e) = target.exec(&format!("config set {key} {value}")) {
+            eprintln!("Error: {key}: {e}");
+            let _ = target.exec("config abort");
+            return COMMAND_FAILED;
+        }
+        println!("staged {key}");
+    }
+
+    let problems = match target.run("config validate") {
+        Ok(lines) => lines,
+        Err(e) => {
+            eprintln!("Error: config validate: {e}");
+            return COMMAND_FAILED;
+        }
+    };
+    for line in &problems {
+        println!("{line}");
+    }
+    let invalid = problems.iter().any(|l| l.starts_with("error:"));
+    if invalid || m.get_flag("dry-run") {
+        let _ = target.exec("config abort");
+        println!("Staged settings discarded");
+        return if invalid { INVALID_CONFIG } else { OK };
+    }
+
+    let commit = match m.get_one::<u16>("rollback") {
+        Some(minutes) => format!("config commit {minutes}"),
+        None => "config commit".to_owned(),
+    };
+    match target.run(&commit) {
+        Ok(lines) => {
+            for line in &lines {
+                println!("{line}");
+            }
+            if lines.iter().any(|l| l.starts_with("error:")) {
+                COMMAND_FAILED
+            } else {
+                OK
+            }
+        }
+        // A commit that resets the stamp may cut the connection before ssh
+        // sees the reply.
+        Err(e) => {
+            eprintln!("Warning: {commit}: {e}");
+            eprintln!(
+                "The stamp may be resetting; reconnect and run `stamp-cli confirm` if network settings changed"
+            );
+            COMMAND_FAILED
+        }
+    }
+}
+
+/// Prints `done` of `total` bytes on one refreshed stderr line.
+fn progress(done: u64, total: u64) {
+    let percent = (done * 100).checked_div(total).unwrap_or(100);
+    eprint!("\r{done}/{total} bytes ({percent}%)");
+    let _ = io::stderr().flush();
+}
+
+fn ota(target: &Target, m: &ArgMatches) -> i32 {
+    let path = m.get_one::<String>("FILE").cloned().unwrap_or_default();
+    let mut file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Error: Could not open '{path}': {e}");
+            return READ_FAILED;
+        }
+    };
+    if Path::new(&path).extension().is_none_or(|e| e != "ota") {
+        eprintln!("Warning: '{path}' does not end in .ota, was it packed with `cargo packer`?");
+    }
+    let total = file.metadata().map_or(0, |md| md.len());
+
+    let mut session = match target.sftp().and_then(sftp::Session::start) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Error: Could not start SFTP: {e}");
+            return TRANSFER_FAILED;
+        }
+    };
+    // The stamp checks the exact image size against its OTA partition once
+    // the header arrives; this only spares uploading a far too large file.
+    match ota_capacity(&mut session) {
+        Ok(Some(capacity)) if total > capacity + OTA_HEADER_MAX_LEN => {
+            eprintln!(
+                "Error: '{path}' is {total} bytes, the stamp's OTA partition holds {capacity}"
+            );
+            return TRANSFER_FAILED;
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Warning: Could not check the OTA partition size: {e}"),
+    }
+    let handle = match session.create(OTA_PATH) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Error: Could not open {OTA_PATH}: {e}");
+            return TRANSFER_FAILED;
+        }
+    };
+
+    let mut buf = vec![0u8; CHUNK_LEN];
+    let mut offset = 0u64;
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("\nError: Could not read '{path}': {e}");
+                return READ_FAILED;
+            }
+        };
+        if let Err(e) = session.write(&handle, offset, &buf[..n]) {
+            eprintln!("\nError: Upload failed at byte {offset}: {e}");
+            return TRANSFER_FAILED;
+        }
+        offset += n as u64;
+        progress(offset, total);
+    }
+    eprintln!();
+
+    // The stamp validates the image on close and resets into it, which may
+    // drop the connection before the reply.
+    match session.close(&handle) {
+        Ok(()) => println!("Image accepted, the stamp is resetting"),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            println!("Connection closed after upload, the stamp is probably resetting");
+        }
+        Err(e) => {
+            eprintln!("Error: Image rejected: {e}");
+            return TRANSFER_FAILED;
+        }
+    }
+    session.finish();
+    OK
+}
+
+/// Streams every byte value through a binary bridge session and checks
+/// that the same bytes come back in order, which takes the UART's TX wired
+/// to its RX (or a target echoing them untouched). Covers both directions
+/// of the bridge at once.
+fn transparency(target: &Target, m: &ArgMatches) -> i32 {
+    let rounds = m.get_one::<usize>("rounds").copied().unwrap_or(16);
+    let wait = Duration::from_millis(m.get_one::<u64>("wait").copied().unwrap_or(2000));
+    let pattern: Vec<u8> = (0..rounds).flat_map(|_| 0..=u8::MAX).collect();
+    let total = pattern.len();
+
+    let mut child = match target.serial(&[("SSH_STAMP_SESSION_BINARY", "1")]) {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Error: Could not start the serial session: {e}");
+            return TRANSFER_FAILED;
+        }
+    };
+    let (Some(mut stdin), Some(mut stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        eprintln!("Error: ssh has no pipes");
+        return TRANSFER_FAILED;
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        while let Ok(n @ 1..) = stdout.read(&mut buf) {
+            if tx.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    // Killing ssh below ends a write still blocked on a lost echo.
+    let writer = thread::spawn(move || stdin.write_all(&pattern).and_then(|()| stdin.flush()));
+
+    let mut echoed = Vec::with_capacity(total);
+    while echoed.len() < total {
+        match rx.recv_timeout(wait) {
+            Ok(chunk) => echoed.extend_from_slice(&chunk),
+            Err(_) => break,
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = writer.join();
+
+    let expected = (0..rounds).flat_map(|_| 0..=u8::MAX);
+    if let Some((offset, (got, sent))) = echoed
+        .iter()
+        .zip(expected)
+        .enumerate()
+        .find(|(_, (got, sent))| **got != *sent)
+    {
+        eprintln!("Error: Byte {offset} came back as {got:#04x}, sent {sent:#04x}");
+        return TRANSFER_FAILED;
+    }
+    if echoed.len() < total {
+        eprintln!(
+            "Error: {} of {} bytes came back, check the loopback and baud rate",
+            echoed.len(),
+            total
+        );
+        return TRANSFER_FAILED;
+    }
+    println!(
+        "All 256 byte values passed unchanged, {} bytes",
+        echoed.len()
+    );
+    OK
+}
+
+/// The OTA partition size the stamp reports in [`OTA_INFO_PATH`], `None` if
+/// the file does not give one.
+fn ota_capacity(session: &mut sftp::Session) -> io::Result<Option<u64>> {
+    let handle = session.open_read(OTA_INFO_PATH)?;
+    let mut text = Vec::new();
+    loop {
+        let chunk = session.read(&handle, text.len() as u64)?;
+        if chunk.is_empty() {
+            break;
+        }
+        text.extend_from_slice(&chunk);
+    }
+    session.close(&handle)?;
+    Ok(String::from_utf8_lossy(&text)
+        .lines()
+        .find_map(|line| line.strip_prefix("capacity:"))
+        .and_then(|capacity| capacity.trim().parse().ok()))
+}
+
+fn fetch(target: &Target, m: &ArgMatches) -> i32 {
+    let remote = m
+        .get_one::<String>("REMOTE")
+        .map_or(VERSION_PATH, String::as_str);
+    let mut session = match target.sftp().and_then(sftp::Session::start) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Error: Could not start SFTP: {e}");
+            return TRANSFER_FAILED;
+        }
+    };
+    let handle = match session.open_read(remote) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Error: Could not open {remote}: {e}");
+            return TRANSFER_FAILED;
+        }
+    };
+    let mut data = Vec::new();
+    loop {
+        match session.read(&handle, data.len() as u64) {
+            Ok(chunk) if chunk.is_empty() => break,
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(e) => {
+                eprintln!("Error: Download failed at byte {}: {e}", data.len());
+                return TRANSFER_FAILED;
+            }
+        }
+    }
+    let _ = session.close(&handle);
+    session.finish();
+
+    let written = match m.get_one::<String>("LOCAL") {
+        Some(local) => std::fs::write(local, &data),
+        None => io::stdout().write_all(&data),
+    };
+    if let Err(e) = written {
+        eprintln!("Error: Could not write output: {e}");
+        return WRITE_FAILED;
+    }
+    OK
+}
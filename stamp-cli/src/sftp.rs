@@ -0,0 +1,331 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Just enough of an SFTP version 3 client for the stamp's subsystem: open,
+//! sequential reads and writes, close.
+//!
+//! The firmware treats every file opened for writing, other than
+//! `/info/version`, as a firmware image and resets once it is closed, so the
+//! reply to the final close may never arrive.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout};
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+
+/// Bytes per read or write request.
+pub const CHUNK_LEN: usize = 8192;
+
+/// Replies larger than this are refused rather than allocated.
+const MAX_PACKET_LEN: usize = 256 * 1024;
+
+/// An SFTP session over a child process' stdio.
+pub struct Session {
+    child: Child,
+    tx: ChildStdin,
+    rx: ChildStdout,
+    next_id: u32,
+}
+
+/// An open remote file.
+pub struct Handle(Vec<u8>);
+
+fn protocol_error(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("SFTP: {what}"))
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &[u8]) {
+    let len = u32::try_from(s.len()).expect("SFTP strings are short");
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+/// Reads from a reply payload.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(protocol_error("truncated reply"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Frames `payload` as an SFTP packet of type `kind`.
+fn packet(kind: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let len = u32::try_from(payload.len() + 1).map_err(|_| protocol_error("packet too long"))?;
+    let mut packet = Vec::with_capacity(payload.len() + 5);
+    packet.extend_from_slice(&len.to_be_bytes());
+    packet.push(kind);
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+impl Session {
+    /// Takes over `child`'s piped stdio and negotiates version 3.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pipes are missing or the server does not answer `INIT`.
+    pub fn start(mut child: Child) -> io::Result<Self> {
+        let (Some(tx), Some(rx)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(io::Error::other("ssh stdio is not piped"));
+        };
+        let mut session = Self {
+            child,
+            tx,
+            rx,
+            next_id: 0,
+        };
+        session.send(SSH_FXP_INIT, &3u32.to_be_bytes())?;
+        let (kind, _) = session.recv()?;
+        if kind != SSH_FXP_VERSION {
+            return Err(protocol_error("no VERSION reply"));
+        }
+        Ok(session)
+    }
+
+    fn send(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        self.tx.write_all(&packet(kind, payload)?)?;
+        self.tx.flush()
+    }
+
+    fn recv(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut len = [0u8; 4];
+        self.rx.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > MAX_PACKET_LEN {
+            return Err(protocol_error("bad packet length"));
+        }
+        let mut packet = vec![0u8; len];
+        self.rx.read_exact(&mut packet)?;
+        let kind = packet.remove(0);
+        Ok((kind, packet))
+    }
+
+    /// Sends a request with a fresh id and returns the matching reply.
+    fn request(&mut self, kind: u8, body: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut payload = id.to_be_bytes().to_vec();
+        payload.extend_from_slice(body);
+        self.send(kind, &payload)?;
+        let (reply, payload) = self.recv()?;
+        let mut r = Reader(&payload);
+        if r.u32()? != id {
+            return Err(protocol_error("reply id mismatch"));
+        }
+        Ok((reply, r.0.to_vec()))
+    }
+
+    /// Turns a `STATUS` reply into `Ok` for `SSH_FX_OK`, the status code
+    /// otherwise; other reply types are protocol errors.
+    fn status(reply: u8, payload: &[u8]) -> io::Result<Result<(), u32>> {
+        if reply != SSH_FXP_STATUS {
+            return Err(protocol_error("unexpected reply"));
+        }
+        let mut r = Reader(payload);
+        let code = r.u32()?;
+        Ok(if code == SSH_FX_OK { Ok(()) } else { Err(code) })
+    }
+
+    fn failed(code: u32, payload: &[u8]) -> io::Error {
+        let mut r = Reader(payload);
+        let message = r
+            .u32()
+            .and_then(|_| r.string())
+            .map(|m| String::from_utf8_lossy(m).into_owned())
+            .unwrap_or_default();
+        io::Error::other(format!("SFTP status {code}: {message}"))
+    }
+
+    fn open(&mut self, path: &str, flags: u32) -> io::Result<Handle> {
+        let mut body = Vec::new();
+        put_string(&mut body, path.as_bytes());
+        body.extend_from_slice(&flags.to_be_bytes());
+        // Empty ATTRS
+        body.extend_from_slice(&0u32.to_be_bytes());
+        let (reply, payload) = self.request(SSH_FXP_OPEN, &body)?;
+        if reply == SSH_FXP_HANDLE {
+            return Ok(Handle(Reader(&payload).string()?.to_vec()));
+        }
+        match Self::status(reply, &payload)? {
+            Ok(()) => Err(protocol_error("OPEN returned no handle")),
+            Err(code) => Err(Self::failed(code, &payload)),
+        }
+    }
+
+    /// Opens `path` for writing.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server refuses or the connection breaks.
+    pub fn create(&mut self, path: &str) -> io::Result<Handle> {
+        self.open(path, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC)
+    }
+
+    /// Opens `path` for reading.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server refuses or the connection breaks.
+    pub fn open_read(&mut self, path: &str) -> io::Result<Handle> {
+        self.open(path, SSH_FXF_READ)
+    }
+
+    /// Writes `data` at `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server refuses or the connection breaks.
+    pub fn write(&mut self, handle: &Handle, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(data.len() + handle.0.len() + 16);
+        put_string(&mut body, &handle.0);
+        body.extend_from_slice(&offset.to_be_bytes());
+        put_string(&mut body, data);
+        let (reply, payload) = self.request(SSH_FXP_WRITE, &body)?;
+        Self::status(reply, &payload)?.map_err(|code| Self::failed(code, &payload))
+    }
+
+    /// Reads up to [`CHUNK_LEN`] bytes at `offset`; empty at end of file.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server refuses or the connection breaks.
+    pub fn read(&mut self, handle: &Handle, offset: u64) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        put_string(&mut body, &handle.0);
+        body.extend_from_slice(&offset.to_be_bytes());
+        body.extend_from_slice(&u32::try_from(CHUNK_LEN).unwrap_or(u32::MAX).to_be_bytes());
+        let (reply, payload) = self.request(SSH_FXP_READ, &body)?;
+        if reply == SSH_FXP_DATA {
+            return Ok(Reader(&payload).string()?.to_vec());
+        }
+        match Self::status(reply, &payload)? {
+            Err(SSH_FX_EOF) => Ok(Vec::new()),
+            Ok(()) => Err(protocol_error("READ returned no data")),
+            Err(code) => Err(Self::failed(code, &payload)),
+        }
+    }
+
+    /// Closes `handle`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the server refuses or the connection breaks.
+    pub fn close(&mut self, handle: &Handle) -> io::Result<()> {
+        let mut body = Vec::new();
+        put_string(&mut body, &handle.0);
+        let (reply, payload) = self.request(SSH_FXP_CLOSE, &body)?;
+        Self::status(reply, &payload)?.map_err(|code| Self::failed(code, &payload))
+    }
+
+    /// Ends the session and waits for `ssh` to exit.
+    pub fn finish(self) {
+        let Self { mut child, tx, .. } = self;
+        drop(tx);
+        let _ = child.wait();
+    }
+}
+
+#[cfg(test)]
+mod sftp_tests {
+    use super::*;
+
+    #[test]
+    fn packet_frames_length_and_type() {
+        let p = packet(SSH_FXP_INIT, &3u32.to_be_bytes()).unwrap();
+        assert_eq!(p, [0, 0, 0, 5, SSH_FXP_INIT, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn empty_packet_counts_the_type_byte() {
+        assert_eq!(
+            packet(SSH_FXP_CLOSE, &[]).unwrap(),
+            [0, 0, 0, 1, SSH_FXP_CLOSE]
+        );
+    }
+
+    #[test]
+    fn put_string_prefixes_the_length() {
+        let mut buf = vec![0xaa];
+        put_string(&mut buf, b"/ota");
+        assert_eq!(buf, [0xaa, 0, 0, 0, 4, b'/', b'o', b't', b'a']);
+    }
+
+    #[test]
+    fn reader_decodes_what_put_string_wrote() {
+        let mut buf = 7u32.to_be_bytes().to_vec();
+        put_string(&mut buf, b"handle");
+        let mut r = Reader(&buf);
+        assert_eq!(r.u32().unwrap(), 7);
+        assert_eq!(r.string().unwrap(), b"handle");
+        assert!(r.0.is_empty());
+    }
+
+    #[test]
+    fn reader_empty_string() {
+        let buf = 0u32.to_be_bytes();
+        let mut r = Reader(&buf);
+        assert_eq!(r.string().unwrap(), b"");
+    }
+
+    #[test]
+    fn reader_rejects_truncated_input() {
+        assert!(Reader(&[0, 0, 1]).u32().is_err());
+        // Length says 5, only 2 bytes follow
+        let mut r = Reader(&[0, 0, 0, 5, b'a', b'b']);
+        let err = r.string().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn status_maps_codes() {
+        let ok = SSH_FX_OK.to_be_bytes();
+        assert_eq!(Session::status(SSH_FXP_STATUS, &ok).unwrap(), Ok(()));
+        let eof = SSH_FX_EOF.to_be_bytes();
+        assert_eq!(
+            Session::status(SSH_FXP_STATUS, &eof).unwrap(),
+            Err(SSH_FX_EOF)
+        );
+        assert!(Session::status(SSH_FXP_DATA, &ok).is_err());
+    }
+
+    #[test]
+    fn failed_carries_the_server_message() {
+        let mut payload = 4u32.to_be_bytes().to_vec();
+        put_string(&mut payload, b"no space");
+        let err = Session::failed(4, &payload);
+        assert_eq!(err.to_string(), "SFTP status 4: no space");
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-FileCopyrightText: 2026 Roman Valls Guimera <brainstorm@nopcode.org>
+//
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Talking to a stamp through the system `ssh` client.
+//!
+//! Reusing OpenSSH keeps host key checking, agents and `~/.ssh/config` working
+//! as they do for interactive use. Admin commands go over `exec` requests and
+//! print `error: ...` and exit with status 1 on failure; the SFTP
+//! and `serial` subsystems are reached with `ssh -s`.
+
+use std::io;
+use std::process::{Child, Command, Stdio};
+
+/// Where and as whom to connect.
+pub struct Target {
+    pub host: String,
+    pub user: String,
+    pub port: Option<u16>,
+    pub identity: Option<String>,
+}
+
+impl Target {
+    fn command(&self) -> Command {
+        let mut cmd = Command::new("ssh");
+        // Never fall back to prompting on a terminal we don't own.
+        cmd.args(["-o", "BatchMode=yes"]);
+        if let Some(port) = self.port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity {
+            cmd.arg("-i").arg(identity);
+        }
+        cmd.arg("-l").arg(&self.user);
+        cmd
+    }
+
+    /// Runs one admin command and returns all of its output lines, including
+    /// any `error:` lines.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `ssh`'s stderr if it cannot be run or exits with an error
+    /// other than the failed command's status 1.
+    pub fn run(&self, line: &str) -> Result<Vec<String>, String> {
+        let output = self
+            .command()
+            .arg(&self.host)
+            .arg(line)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("could not run ssh: {e}"))?;
+        if !matches!(output.status.code(), Some(0 | 1)) {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("ssh {}: {}", output.status, stderr.trim()));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout
+            .lines()
+            .map(|l| l.trim_end_matches('\r').to_owned())
+            .collect())
+    }
+
+    /// Runs one admin command and returns its output lines.
+    ///
+    /// # Errors
+    ///
+    /// As [`Self::run`], and with the message of the first `error:` line if
+    /// the command failed.
+    pub fn exec(&self, line: &str) -> Result<Vec<String>, String> {
+        let lines = self.run(line)?;
+        match lines.iter().find_map(|l| l.strip_prefix("error: ")) {
+            Some(e) => Err(e.to_owned()),
+            None => Ok(lines),
+        }
+    }
+
+    /// Starts the SFTP subsystem with piped stdin and stdout.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `ssh` cannot be run.
+    pub fn sftp(&self) -> io::Result<Child> {
+        self.command()
+            .args(["-s", &self.host, "sftp"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+    }
+
+    /// Starts the `serial` subsystem, the bridge without a shell, with piped
+    /// stdin and stdout. `env` is set for `ssh` and sent with `SendEnv`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `ssh` cannot be run.
+    pub fn serial(&self, env: &[(&str, &str)]) -> io::Result<Child> {
+        let mut cmd = self.command();
+        for (name, value) in env {
+            cmd.arg("-o")
+                .arg(format!("SendEnv={name}"))
+                .env(name, value);
+        }
+        cmd.args(["-s", &self.host, "serial"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+    }
+}